@@ -0,0 +1,122 @@
+//! Throughput of encoding and decoding each kind of event in each format a trace can be read
+//! back from, through `cannonball_tools` and through the C API
+
+mod common;
+
+use cannonball_client::{
+    cannonball_client_reader_close, cannonball_client_reader_next, cannonball_client_reader_open,
+    CannonballEvent,
+};
+use cannonball_tools::{output::OutputFormat, trace::TraceReader};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use std::{
+    env::temp_dir,
+    ffi::CString,
+    fs::{remove_file, write},
+    io,
+    mem::zeroed,
+    process,
+};
+
+use common::{client_format, encode, events, name, EVENTS, KINDS};
+
+/// The formats `TraceReader` can read back
+pub const FORMATS: [OutputFormat; 5] = [
+    OutputFormat::Json,
+    OutputFormat::Cbor,
+    OutputFormat::Msgpack,
+    OutputFormat::Binary,
+    OutputFormat::Compact,
+];
+
+fn encoding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    group.throughput(Throughput::Elements(EVENTS as u64));
+
+    for (kind, event) in KINDS {
+        let events = events(event);
+
+        for format in FORMATS {
+            group.bench_with_input(
+                BenchmarkId::new(kind, name(format)),
+                &events,
+                |b, events| {
+                    b.iter(|| {
+                        let mut writer = format.writer(io::sink()).unwrap();
+                        for event in events {
+                            writer.write(event).unwrap();
+                        }
+                        writer.finish().unwrap();
+                    })
+                },
+            );
+        }
+    }
+
+    group.finish();
+}
+
+fn decoding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(EVENTS as u64));
+
+    for (kind, event) in KINDS {
+        let events = events(event);
+
+        for format in FORMATS {
+            let (trace, _) = encode(&events, format);
+
+            group.bench_with_input(BenchmarkId::new(kind, name(format)), &trace, |b, trace| {
+                b.iter(|| {
+                    let reader = TraceReader::new(trace.as_slice(), format).unwrap();
+                    for event in reader {
+                        black_box(event.unwrap());
+                    }
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn client(c: &mut Criterion) {
+    let mut group = c.benchmark_group("client");
+    group.throughput(Throughput::Elements(EVENTS as u64));
+
+    for (kind, event) in KINDS {
+        let events = events(event);
+
+        for format in FORMATS {
+            let path = temp_dir().join(format!(
+                "cannonball-bench-{}-{}-{}",
+                process::id(),
+                kind,
+                name(format)
+            ));
+            write(&path, encode(&events, format).0).unwrap();
+            let cpath = CString::new(path.to_str().unwrap()).unwrap();
+
+            group.bench_function(BenchmarkId::new(kind, name(format)), |b| {
+                b.iter(|| unsafe {
+                    let reader =
+                        cannonball_client_reader_open(cpath.as_ptr(), client_format(format));
+                    let mut event: CannonballEvent = zeroed();
+
+                    while cannonball_client_reader_next(reader, &mut event) == 1 {
+                        black_box(&event);
+                    }
+                    cannonball_client_reader_close(reader);
+                })
+            });
+
+            let _ = remove_file(path);
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, encoding, decoding, client);
+criterion_main!(benches);
@@ -0,0 +1,140 @@
+//! Events shared by the benchmarks
+
+use cannonball_client::CannonballFormat;
+use cannonball_tools::output::OutputFormat;
+use serde_json::{json, Value};
+
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+/// The number of events in each benchmarked trace
+pub const EVENTS: usize = 4096;
+
+/// Generates the `i`th event of a trace
+pub type Generator = fn(u64) -> Value;
+
+/// The event kinds benchmarked, with a function generating the `i`th event of a trace of them.
+/// Addresses move forward like a program running sequentially would
+pub const KINDS: [(&str, Generator); 4] = [
+    ("insn", insn),
+    ("tb", tb),
+    ("mem", mem),
+    ("syscall", syscall),
+];
+
+/// An instruction event
+fn insn(i: u64) -> Value {
+    json!({
+        "vcpu_idx": 0,
+        "vaddr": 0x401000 + i * 4,
+        "opcode": [0x48, 0x89, 0xe5, (i % 256) as u8],
+        "branch": i % 8 == 7,
+    })
+}
+
+/// A translation block event
+fn tb(i: u64) -> Value {
+    json!({
+        "vcpu_idx": 0,
+        "vaddr": 0x401000 + i * 32,
+        "size": 32,
+        "n_insns": 8,
+    })
+}
+
+/// A memory access event
+fn mem(i: u64) -> Value {
+    json!({
+        "vaddr": 0x7ffff7dd0000_u64 + i * 8,
+        "is_sext": false,
+        "is_be": false,
+        "is_store": i % 2 == 1,
+        "size_shift": 3,
+        "size": 8,
+        "value": null,
+        "insn": insn(i),
+    })
+}
+
+/// A system call event
+fn syscall(i: u64) -> Value {
+    json!({
+        "num": 1,
+        "rv": 14,
+        "args": [1, 0x402000 + i * 16, 14, 0, 0, 0, 0, 0],
+    })
+}
+
+/// A trace of `EVENTS` events of one kind
+///
+/// # Arguments
+///
+/// * `event` - The function generating its events
+pub fn events(event: Generator) -> Vec<Value> {
+    (0..EVENTS as u64).map(event).collect()
+}
+
+/// A buffer that stays readable after the writer writing to it is dropped
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Encode events in a format, returning the encoded events and the offset each of them ends
+/// at
+///
+/// # Arguments
+///
+/// * `events` - The events
+/// * `format` - The format to encode them in
+pub fn encode(events: &[Value], format: OutputFormat) -> (Vec<u8>, Vec<usize>) {
+    let out = Shared::default();
+    let mut writer = format.writer(out.clone()).unwrap();
+    let mut ends = Vec::new();
+
+    for event in events {
+        writer.write(event).unwrap();
+        ends.push(out.0.lock().unwrap().len());
+    }
+    writer.finish().unwrap();
+    drop(writer);
+
+    let trace = out.0.lock().unwrap().split_off(0);
+    (trace, ends)
+}
+
+/// The name of a format, as it is passed to `--format`
+///
+/// # Arguments
+///
+/// * `format` - The format
+pub fn name(format: OutputFormat) -> String {
+    format!("{:?}", format).to_lowercase()
+}
+
+/// The `CannonballFormat` of a format, as passed to the C API
+///
+/// # Arguments
+///
+/// * `format` - The format
+pub fn client_format(format: OutputFormat) -> u32 {
+    (match format {
+        OutputFormat::Json => CannonballFormat::Json,
+        OutputFormat::Cbor => CannonballFormat::Cbor,
+        OutputFormat::Msgpack => CannonballFormat::Msgpack,
+        OutputFormat::Binary => CannonballFormat::Binary,
+        OutputFormat::Compact => CannonballFormat::Compact,
+        _ => unreachable!("{:?} traces cannot be read back", format),
+    }) as u32
+}
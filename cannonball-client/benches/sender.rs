@@ -0,0 +1,91 @@
+//! Throughput of sending a full instruction trace to a consumer from a plugin: with a
+//! `Sender` writing each event from the callback, a `ThreadedSender` writing them from a
+//! thread, and, with the `io-uring` feature, one writing them with io_uring in batches
+
+use cannonball_client::{sender::Sender, threaded::ThreadedSender};
+use cannonball_events::{Event, InsnEvent};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use std::{
+    env::temp_dir,
+    fs::remove_file,
+    io::{copy, sink},
+    os::unix::net::UnixListener,
+    process,
+    thread::scope,
+};
+
+/// The number of instructions in the trace
+const EVENTS: usize = 65536;
+
+/// The number of events the threaded senders buffer
+const CAPACITY: usize = 4096;
+
+/// The backends events are sent with, by name
+const BACKENDS: [&str; 3] = ["sender", "threaded", "io-uring"];
+
+/// Send events to a consumer with a backend
+///
+/// # Arguments
+///
+/// * `backend` - The name of the backend
+/// * `sender` - A sender connected to the consumer
+/// * `events` - The events
+fn send(backend: &str, mut sender: Sender, events: &[Event]) {
+    let mut threaded = match backend {
+        "threaded" => ThreadedSender::spawn(sender, CAPACITY).unwrap(),
+        #[cfg(feature = "io-uring")]
+        "io-uring" => ThreadedSender::spawn_uring(sender, CAPACITY).unwrap(),
+        _ => {
+            for event in events {
+                sender.send(event).unwrap();
+            }
+            sender.shutdown().unwrap();
+            return;
+        }
+    };
+
+    for event in events {
+        threaded.send(event).unwrap();
+    }
+    threaded.shutdown().unwrap();
+}
+
+fn sending(c: &mut Criterion) {
+    let mut group = c.benchmark_group("send");
+    group.throughput(Throughput::Elements(EVENTS as u64));
+
+    // Addresses move forward like a program running sequentially would
+    let events: Vec<Event> = (0..EVENTS as u64)
+        .map(|i| Event::Insn(InsnEvent::new(Some(0), 0x401000 + i * 4, None, i % 8 == 7)))
+        .collect();
+
+    let path = temp_dir().join(format!("cannonball-bench-send-{}.sock", process::id()));
+    let _ = remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+
+    for backend in BACKENDS {
+        if backend == "io-uring" && cfg!(not(feature = "io-uring")) {
+            continue;
+        }
+
+        group.bench_function(backend, |b| {
+            b.iter(|| {
+                scope(|scope| {
+                    scope.spawn(|| {
+                        let (mut stream, _) = listener.accept().unwrap();
+                        copy(&mut stream, &mut sink()).unwrap();
+                    });
+
+                    send(backend, Sender::connect(&path).unwrap(), &events);
+                })
+            })
+        });
+    }
+
+    let _ = remove_file(path);
+    group.finish();
+}
+
+criterion_group!(benches, sending);
+criterion_main!(benches);
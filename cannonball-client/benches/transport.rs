@@ -0,0 +1,94 @@
+//! Throughput of streaming events over a UNIX socket to a reader connected through the C API,
+//! with the writer sending them in batches of various sizes
+
+mod common;
+
+use cannonball_client::{
+    cannonball_client_reader_close, cannonball_client_reader_connect,
+    cannonball_client_reader_next, CannonballEvent,
+};
+use cannonball_tools::output::OutputFormat;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use std::{
+    env::temp_dir, ffi::CString, fs::remove_file, io::Write, mem::zeroed,
+    os::unix::net::UnixListener, process, thread::scope,
+};
+
+use common::{client_format, encode, events, name, EVENTS, KINDS};
+
+/// The number of events written to the socket at once
+const BATCH_SIZES: [usize; 4] = [1, 16, 256, 4096];
+
+/// The formats events are streamed in: the plugin writes JSON, and compact is the smallest
+const FORMATS: [OutputFormat; 2] = [OutputFormat::Json, OutputFormat::Compact];
+
+fn socket(c: &mut Criterion) {
+    let mut group = c.benchmark_group("socket");
+    group.throughput(Throughput::Elements(EVENTS as u64));
+
+    let (_, insn) = KINDS[0];
+    let events = events(insn);
+
+    for format in FORMATS {
+        // Compact events depend on the ones before them, so the stream is encoded at once and
+        // split into batches where events end
+        let (stream, ends) = encode(&events, format);
+
+        for batch_size in BATCH_SIZES {
+            let mut start = 0;
+            let batches: Vec<Vec<u8>> = ends
+                .chunks(batch_size)
+                .map(|chunk| {
+                    let end = *chunk.last().unwrap();
+                    let batch = stream[start..end].to_vec();
+                    start = end;
+                    batch
+                })
+                .collect();
+            let path = temp_dir().join(format!(
+                "cannonball-bench-{}-{}-{}.sock",
+                process::id(),
+                name(format),
+                batch_size
+            ));
+            let _ = remove_file(&path);
+            let listener = UnixListener::bind(&path).unwrap();
+            let cpath = CString::new(path.to_str().unwrap()).unwrap();
+
+            group.bench_function(BenchmarkId::new(name(format), batch_size), |b| {
+                b.iter(|| {
+                    scope(|scope| {
+                        // Readers of compact traces read the header when they connect
+                        scope.spawn(|| {
+                            let (mut stream, _) = listener.accept().unwrap();
+                            for batch in &batches {
+                                stream.write_all(batch).unwrap();
+                            }
+                        });
+
+                        unsafe {
+                            let reader = cannonball_client_reader_connect(
+                                cpath.as_ptr(),
+                                client_format(format),
+                            );
+                            let mut event: CannonballEvent = zeroed();
+
+                            while cannonball_client_reader_next(reader, &mut event) == 1 {
+                                black_box(&event);
+                            }
+                            cannonball_client_reader_close(reader);
+                        }
+                    })
+                })
+            });
+
+            let _ = remove_file(path);
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, socket);
+criterion_main!(benches);
@@ -0,0 +1,352 @@
+#ifndef CANNONBALL_CLIENT_H
+#define CANNONBALL_CLIENT_H
+
+/* Generated with cbindgen:0.26.0 */
+
+/* This file was generated by cbindgen. Don't modify this manually. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/// The version of the layout of `CannonballEvent`
+#define CANNONBALL_CLIENT_ABI_VERSION 1
+
+/// The most opcode bytes a `CannonballEvent` holds
+#define CANNONBALL_EVENT_OPCODE_MAX 16
+
+/// `pid` is set
+#define CANNONBALL_EVENT_HAS_PID (1 << 0)
+
+/// `vcpu` is set
+#define CANNONBALL_EVENT_HAS_VCPU (1 << 1)
+
+/// `pc` is set
+#define CANNONBALL_EVENT_HAS_PC (1 << 2)
+
+/// `addr` is set
+#define CANNONBALL_EVENT_HAS_ADDR (1 << 3)
+
+/// `size` is set
+#define CANNONBALL_EVENT_HAS_SIZE (1 << 4)
+
+/// The instruction is a branch
+#define CANNONBALL_EVENT_BRANCH (1 << 5)
+
+/// The memory access is a store
+#define CANNONBALL_EVENT_IS_STORE (1 << 6)
+
+/// The version of the replay log format this library reads
+#define CANNONBALL_REPLAY_VERSION 1
+
+/// The number of arguments recorded for each system call
+#define CANNONBALL_REPLAY_ARGS 8
+
+/// Header flag set if the buffers system calls wrote to were recorded
+#define CANNONBALL_REPLAY_BUFFERS (1 << 0)
+
+/// The kinds of events, as stored in `CannonballEvent::kind`
+enum CannonballEventKind {
+    CANNONBALL_EVENT_KIND_UNKNOWN = 0,
+    CANNONBALL_EVENT_KIND_HEADER = 1,
+    CANNONBALL_EVENT_KIND_RUN_END = 2,
+    CANNONBALL_EVENT_KIND_STATS = 3,
+    CANNONBALL_EVENT_KIND_FORK = 4,
+    CANNONBALL_EVENT_KIND_EXEC = 5,
+    CANNONBALL_EVENT_KIND_TRIGGER = 6,
+    CANNONBALL_EVENT_KIND_INSN = 7,
+    CANNONBALL_EVENT_KIND_TB = 8,
+    CANNONBALL_EVENT_KIND_BLOCK_HITS = 9,
+    CANNONBALL_EVENT_KIND_EDGE = 10,
+    CANNONBALL_EVENT_KIND_CALL = 11,
+    CANNONBALL_EVENT_KIND_RETURN = 12,
+    CANNONBALL_EVENT_KIND_FUNCTION_ENTER = 13,
+    CANNONBALL_EVENT_KIND_FUNCTION_EXIT = 14,
+    CANNONBALL_EVENT_KIND_MEM = 15,
+    CANNONBALL_EVENT_KIND_SYS_MEM = 16,
+    CANNONBALL_EVENT_KIND_SYSCALL = 17,
+    CANNONBALL_EVENT_KIND_BREAKPOINT = 18,
+    CANNONBALL_EVENT_KIND_INSN_MIX = 19,
+    CANNONBALL_EVENT_KIND_DISCON = 20,
+    CANNONBALL_EVENT_KIND_VCPU = 21,
+    CANNONBALL_EVENT_KIND_SIGNAL = 22,
+    CANNONBALL_EVENT_KIND_FINAL = 23,
+    CANNONBALL_EVENT_KIND_MAP = 24,
+    CANNONBALL_EVENT_KIND_UNMAP = 25,
+    CANNONBALL_EVENT_KIND_PROTECT = 26,
+};
+typedef uint32_t CannonballEventKind;
+
+/// The formats traces can be read in
+enum CannonballFormat {
+    /// Newline-delimited JSON
+    CANNONBALL_FORMAT_JSON = 0,
+    /// A sequence of CBOR items
+    CANNONBALL_FORMAT_CBOR = 1,
+    /// A sequence of MessagePack maps
+    CANNONBALL_FORMAT_MSGPACK = 2,
+    /// Length-prefixed CBOR items
+    CANNONBALL_FORMAT_BINARY = 3,
+    /// Delta-encoded instruction and block events
+    CANNONBALL_FORMAT_COMPACT = 4,
+};
+typedef uint32_t CannonballFormat;
+
+/// Reads events from a trace file or a socket
+typedef struct CannonballReader CannonballReader;
+
+/// Reads the system calls of a replay log file
+typedef struct CannonballReplayLog CannonballReplayLog;
+
+/// Names the functions of a program addresses are in
+typedef struct CannonballSymbolizer CannonballSymbolizer;
+
+/// An event, with the fields most events have in common decoded. Fields an event does not
+/// have are zero, and their `CANNONBALL_EVENT_HAS_*` flag is clear
+typedef struct CannonballEvent {
+    /// `CANNONBALL_CLIENT_ABI_VERSION`
+    uint32_t version;
+    /// The kind of the event, one of `CannonballEventKind`
+    uint32_t kind;
+    /// The position of the event in the trace
+    uint64_t seq;
+    /// `CANNONBALL_EVENT_*` flags
+    uint32_t flags;
+    /// The process that logged the event
+    uint32_t pid;
+    /// The VCPU that logged the event
+    uint32_t vcpu;
+    /// The number of bytes of `opcode` that are set
+    uint32_t opcode_len;
+    /// The address of the instruction, block, function entry or exit, edge source, or where an
+    /// interrupt, exception or signal was taken
+    uint64_t pc;
+    /// The address accessed by a memory access, the target of an edge or call, or where an
+    /// interrupt, exception or signal continued
+    uint64_t addr;
+    /// The size of a memory access or block
+    uint64_t size;
+    /// The bytes of an instruction
+    uint8_t opcode[CANNONBALL_EVENT_OPCODE_MAX];
+    /// The whole event as a NUL-terminated JSON object, valid until the next call on the
+    /// reader it was read from
+    const char *json;
+    /// The length of `json`, without its NUL terminator
+    size_t json_len;
+} CannonballEvent;
+
+/// A system call, as read by `cannonball_client_replay_next`
+typedef struct CannonballSyscall {
+    /// The process that made it
+    uint32_t pid;
+    /// The VCPU that made it
+    uint32_t vcpu;
+    /// The system call number
+    int64_t num;
+    /// The return value
+    int64_t rv;
+    /// The arguments
+    uint64_t args[CANNONBALL_REPLAY_ARGS];
+    /// The number of buffers it wrote to, read with `cannonball_client_replay_buffer`
+    uint32_t buffers;
+} CannonballSyscall;
+
+/// A buffer a system call wrote to, as read by `cannonball_client_replay_buffer`
+typedef struct CannonballSyscallBuffer {
+    /// The argument of the system call pointing to the buffer
+    uint32_t arg;
+    /// The address of the buffer
+    uint64_t addr;
+    /// The number of bytes the system call wrote to it
+    uint64_t size;
+    /// The first bytes it wrote, valid until the next call on the log it was read from
+    const uint8_t *data;
+    /// The number of bytes of `data`, at most `size`
+    size_t len;
+} CannonballSyscallBuffer;
+
+/// The version of the layout of `CannonballEvent` this library fills in. Callers built against
+/// a different `CANNONBALL_CLIENT_ABI_VERSION` should not use it
+uint32_t cannonball_client_reader_abi_version(void);
+
+/// Open a trace file written in `format`, decompressing it if it is compressed with gzip.
+/// Returns NULL and sets `errno` if it cannot be opened
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string
+struct CannonballReader *cannonball_client_reader_open(const char *path,
+                                                       uint32_t format);
+
+/// Read a trace already in memory, written in `format`, decompressing it if it is compressed
+/// with gzip. The trace is copied, so `data` may be freed once this returns. Returns NULL and
+/// sets `errno` if `format` is invalid, or if the header of a compact trace cannot be read
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes
+struct CannonballReader *cannonball_client_reader_bytes(const uint8_t *data,
+                                                        size_t len,
+                                                        uint32_t format);
+
+/// Connect to a UNIX socket events are written to in `format`. Returns NULL and sets `errno`
+/// if it cannot be connected to
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string
+struct CannonballReader *cannonball_client_reader_connect(const char *path,
+                                                          uint32_t format);
+
+/// Read events written to stdin in `format`, like the framed binary stream of a plugin sink at
+/// the end of a shell pipeline. Returns NULL and sets `errno` if `format` is invalid, or if the
+/// header of a compact trace cannot be read
+struct CannonballReader *cannonball_client_reader_stdin(uint32_t format);
+
+/// Listen on a vsock port for the plugin to connect to from a virtual machine, with
+/// `sink=vsock:<cid>:<port>`, and read the events it writes in `format`. Blocks until the
+/// plugin connects. Returns NULL and sets `errno` if `format` is invalid or the port cannot be
+/// listened on
+struct CannonballReader *cannonball_client_reader_vsock(uint32_t port, uint32_t format);
+
+/// Read the next event into `event`. Returns 1 if an event was read, 0 at the end of the
+/// trace, and -1 if it could not be read, see `cannonball_client_reader_error`
+///
+/// # Safety
+///
+/// `reader` must have been returned by `cannonball_client_reader_open`,
+/// `cannonball_client_reader_bytes`, `cannonball_client_reader_connect`,
+/// `cannonball_client_reader_stdin` or `cannonball_client_reader_vsock` and not closed, and
+/// `event` must point to a `CannonballEvent`
+int32_t cannonball_client_reader_next(struct CannonballReader *reader,
+                                      struct CannonballEvent *event);
+
+/// The last error of a reader, as a NUL-terminated string valid until the next call on it, or
+/// an empty string if there was none. NULL if `reader` is NULL
+///
+/// # Safety
+///
+/// `reader` must be NULL, or have been returned by `cannonball_client_reader_open`,
+/// `cannonball_client_reader_bytes`, `cannonball_client_reader_connect`,
+/// `cannonball_client_reader_stdin` or `cannonball_client_reader_vsock` and not closed
+const char *cannonball_client_reader_error(const struct CannonballReader *reader);
+
+/// Close a reader, freeing it
+///
+/// # Safety
+///
+/// `reader` must be NULL, or have been returned by `cannonball_client_reader_open`,
+/// `cannonball_client_reader_bytes`, `cannonball_client_reader_connect`,
+/// `cannonball_client_reader_stdin` or `cannonball_client_reader_vsock` and not closed
+void cannonball_client_reader_close(struct CannonballReader *reader);
+
+/// Open a replay log file. Returns NULL and sets `errno` if it cannot be opened or is not a
+/// replay log
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string
+struct CannonballReplayLog *cannonball_client_replay_open(const char *path);
+
+/// The `CANNONBALL_REPLAY_*` flags of a replay log, or 0 if `log` is NULL
+///
+/// # Safety
+///
+/// `log` must be NULL, or have been returned by `cannonball_client_replay_open` and not closed
+uint32_t cannonball_client_replay_flags(const struct CannonballReplayLog *log);
+
+/// The QEMU target of a replay log, like `x86_64`, as a NUL-terminated string valid until it is
+/// closed. NULL if `log` is NULL
+///
+/// # Safety
+///
+/// `log` must be NULL, or have been returned by `cannonball_client_replay_open` and not closed
+const char *cannonball_client_replay_target(const struct CannonballReplayLog *log);
+
+/// Read the next system call into `syscall`. Returns 1 if one was read, 0 at the end of the
+/// log, and -1 if it could not be read, see `cannonball_client_replay_error`
+///
+/// # Safety
+///
+/// `log` must have been returned by `cannonball_client_replay_open` and not closed, and
+/// `syscall` must point to a `CannonballSyscall`
+int32_t cannonball_client_replay_next(struct CannonballReplayLog *log,
+                                      struct CannonballSyscall *syscall);
+
+/// Read a buffer the last system call read wrote to into `buffer`. Returns 1 if it was read,
+/// and 0 if the system call wrote to fewer buffers
+///
+/// # Safety
+///
+/// `log` must have been returned by `cannonball_client_replay_open` and not closed, and
+/// `buffer` must point to a `CannonballSyscallBuffer`
+int32_t cannonball_client_replay_buffer(const struct CannonballReplayLog *log,
+                                        uint32_t index,
+                                        struct CannonballSyscallBuffer *buffer);
+
+/// The last error of a replay log, as a NUL-terminated string valid until the next call on it,
+/// or an empty string if there was none. NULL if `log` is NULL
+///
+/// # Safety
+///
+/// `log` must be NULL, or have been returned by `cannonball_client_replay_open` and not closed
+const char *cannonball_client_replay_error(const struct CannonballReplayLog *log);
+
+/// Close a replay log, freeing it
+///
+/// # Safety
+///
+/// `log` must be NULL, or have been returned by `cannonball_client_replay_open` and not closed
+void cannonball_client_replay_close(struct CannonballReplayLog *log);
+
+/// Read the function symbols of an ELF program. Returns NULL and sets `errno` if it cannot be
+/// read or is not an ELF file
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string
+struct CannonballSymbolizer *cannonball_client_symbolizer_open(const char *path);
+
+/// Read the function symbols of an ELF program already in memory. Nothing is kept of `data`,
+/// so it may be freed once this returns. Returns NULL and sets `errno` if it is not an ELF file
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes
+struct CannonballSymbolizer *cannonball_client_symbolizer_parse(const uint8_t *data,
+                                                                size_t len);
+
+/// Symbolize addresses of the program loaded at `bias`, relative to its symbol table, like
+/// a position independent executable
+///
+/// # Safety
+///
+/// `symbolizer` must be NULL, or have been returned by `cannonball_client_symbolizer_open` or
+/// `cannonball_client_symbolizer_parse` and not closed
+void cannonball_client_symbolizer_set_bias(struct CannonballSymbolizer *symbolizer,
+                                           uint64_t bias);
+
+/// The name of the function `addr` is in, as a NUL-terminated string valid until the next
+/// call on `symbolizer`, with the offset of `addr` in it stored in `offset` if it is not NULL.
+/// NULL if the address is in no known function
+///
+/// # Safety
+///
+/// `symbolizer` must be NULL, or have been returned by `cannonball_client_symbolizer_open` or
+/// `cannonball_client_symbolizer_parse` and not closed, and `offset` must be NULL or point to a
+/// `uint64_t`
+const char *cannonball_client_symbolize(struct CannonballSymbolizer *symbolizer,
+                                        uint64_t addr,
+                                        uint64_t *offset);
+
+/// Close a symbolizer, freeing it
+///
+/// # Safety
+///
+/// `symbolizer` must be NULL, or have been returned by `cannonball_client_symbolizer_open` or
+/// `cannonball_client_symbolizer_parse` and not closed
+void cannonball_client_symbolizer_close(struct CannonballSymbolizer *symbolizer);
+
+#endif /* CANNONBALL_CLIENT_H */
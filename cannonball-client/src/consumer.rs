@@ -0,0 +1,228 @@
+//! Consuming events asynchronously
+//!
+//! An `EventStream` listens on a UNIX socket for QEMU instances running the Mons Meg plugin,
+//! with `socket_path=<path>`, and yields the events they write as a `Stream`, tagged with the
+//! instance that logged them. Any number of instances may connect, one after the other or at
+//! the same time, like the processes of a program that forks and execs more of itself, and
+//! their events are interleaved as they arrive.
+//!
+//! ```no_run
+//! use cannonball_client::consumer::EventStream;
+//! use tokio_stream::StreamExt;
+//!
+//! # async fn consume() -> std::io::Result<()> {
+//! let mut events = EventStream::bind("/tmp/qemu.sock")?.max_connections(1);
+//!
+//! while let Some(event) = events.next().await {
+//!     let (peer, event) = event?;
+//!     println!("{}: {:?}", peer.id, event);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The stream ends once `max_connections` instances connected and disconnected, or once it is
+//! shut down, with `EventStream::shutdown` or the token given to
+//! `EventStream::shutdown_token`. Shutting down stops accepting instances and reading from
+//! those connected, and the events already read are still yielded before the stream ends.
+//! Dropping the stream shuts it down and removes its socket.
+//!
+//! The stream must be created and polled inside a tokio runtime.
+
+use cannonball_events::{codec::EventCodec, Event};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    select, spawn,
+    sync::mpsc::{channel, Receiver, Sender},
+};
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::{codec::FramedRead, sync::CancellationToken};
+
+use std::{
+    fs::remove_file,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The number of events read ahead of the consumer before connections wait for it
+const CAPACITY: usize = 4096;
+
+/// The events of a stream, with the instance that logged each
+type Item = io::Result<(Peer, Event)>;
+
+/// A QEMU instance connected to an `EventStream`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Peer {
+    /// The order the instance connected in, from 0
+    pub id: u64,
+    /// The process ID of the instance, if the socket reports it
+    pub pid: Option<i32>,
+}
+
+/// The events of the QEMU instances connecting to a UNIX socket
+pub struct EventStream {
+    /// The socket
+    path: PathBuf,
+    /// The listener and the sender of connections, until the stream is first polled and starts
+    /// accepting instances
+    start: Option<(UnixListener, Sender<Item>)>,
+    /// The events read from every connection
+    receiver: Receiver<Item>,
+    /// Shuts the stream down
+    shutdown: CancellationToken,
+    /// The number of instances to accept, if it is limited
+    max_connections: Option<u64>,
+}
+
+impl EventStream {
+    /// Listen on a UNIX socket, replacing any file at its path
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The socket, given to the plugin as `socket_path`
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let _ = remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        let (sender, receiver) = channel(CAPACITY);
+
+        Ok(Self {
+            path,
+            start: Some((listener, sender)),
+            receiver,
+            shutdown: CancellationToken::new(),
+            max_connections: None,
+        })
+    }
+
+    /// Shut the stream down when a token is cancelled, like the token of a whole program.
+    /// Dropping the stream does not cancel it
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token
+    pub fn shutdown_token(mut self, token: &CancellationToken) -> Self {
+        self.shutdown = token.child_token();
+        self
+    }
+
+    /// Stop accepting instances after some have connected, so the stream ends once they
+    /// disconnect
+    ///
+    /// # Arguments
+    ///
+    /// * `max_connections` - The number of instances to accept
+    pub fn max_connections(mut self, max_connections: u64) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// The socket the stream listens on
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stop accepting instances and reading their events. The events already read are still
+    /// yielded
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Item>> {
+        if let Some((listener, sender)) = self.start.take() {
+            spawn(accept(
+                listener,
+                sender,
+                self.shutdown.clone(),
+                self.max_connections,
+            ));
+        }
+
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// Accept instances, reading the events of each on a task of its own
+///
+/// # Arguments
+///
+/// * `listener` - The socket
+/// * `sender` - Where to send events
+/// * `shutdown` - Stops accepting instances once cancelled
+/// * `max_connections` - The number of instances to accept, if it is limited
+async fn accept(
+    listener: UnixListener,
+    sender: Sender<Item>,
+    shutdown: CancellationToken,
+    max_connections: Option<u64>,
+) {
+    let mut id = 0;
+
+    while max_connections.is_none_or(|max| id < max) {
+        let accepted = select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => accepted,
+        };
+
+        match accepted {
+            Ok((stream, _)) => {
+                let peer = Peer {
+                    id,
+                    pid: stream.peer_cred().ok().and_then(|cred| cred.pid()),
+                };
+                id += 1;
+                spawn(read(peer, stream, sender.clone(), shutdown.clone()));
+            }
+            Err(e) => {
+                if sender.send(Err(e)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Read the events of an instance until it disconnects, or an event cannot be decoded
+///
+/// # Arguments
+///
+/// * `peer` - The instance
+/// * `stream` - Its connection
+/// * `sender` - Where to send events
+/// * `shutdown` - Stops reading once cancelled
+async fn read(peer: Peer, stream: UnixStream, sender: Sender<Item>, shutdown: CancellationToken) {
+    let mut events = FramedRead::new(stream, EventCodec);
+
+    loop {
+        let event = select! {
+            _ = shutdown.cancelled() => break,
+            event = events.next() => event,
+        };
+
+        let Some(event) = event else {
+            break;
+        };
+
+        let failed = event.is_err();
+        let event = event
+            .map(|event| (peer, event))
+            .map_err(|e| io::Error::new(e.kind(), format!("Connection {}: {}", peer.id, e)));
+
+        if sender.send(event).await.is_err() || failed {
+            break;
+        }
+    }
+}
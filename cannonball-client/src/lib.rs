@@ -0,0 +1,587 @@
+//! C API for reading traces
+//!
+//! C and C++ tools can read traces recorded by the `cannonball` tool, events streamed over a
+//! UNIX socket or from a virtual machine over vsock, or events piped to their stdin from a
+//! plugin sink, through the `cannonball_client_reader_*` functions declared in
+//! `include/cannonball_client.h`. Events are decoded by `cannonball_tools` and handed back one
+//! at a time in a `CannonballEvent`, whose layout is fixed for a given
+//! `CANNONBALL_CLIENT_ABI_VERSION`: fields are only ever added at its end, along with a bump
+//! of the version.
+//!
+//! ```c
+//! CannonballReader *reader = cannonball_client_reader_open("ls.bin", CANNONBALL_FORMAT_BINARY);
+//! CannonballEvent event;
+//!
+//! while (cannonball_client_reader_next(reader, &event) == 1) {
+//!     if (event.kind == CANNONBALL_EVENT_KIND_INSN) {
+//!         printf("%lx\n", event.pc);
+//!     }
+//! }
+//!
+//! cannonball_client_reader_close(reader);
+//! ```
+//!
+//! Replay logs recorded by the Jaivana plugin, holding the results of the system calls of a
+//! run, are read with the `cannonball_client_replay_*` functions (see `replay`), and the
+//! addresses of events are named with the `cannonball_client_symbolizer_*` functions (see
+//! `symbols`).
+//!
+//! Reading from sockets, vsock and stdin needs the default `transport` feature. Without it,
+//! the library only decodes traces from files and from memory with
+//! `cannonball_client_reader_bytes`, and builds for `wasm32-unknown-unknown`, for a trace
+//! viewer running in a browser (see `wasm`).
+//!
+//! Rust tools consuming the events of the Mons Meg plugin live, rather than from a trace,
+//! get them as an async `Stream` with the `consumer` feature (see `consumer`). Plugins write
+//! them to a consumer with the `sender` feature, which returns a `ClientError` rather than
+//! panicking when the consumer goes away (see `sender`), or keep the last of them in memory
+//! to dump to a file when the program crashes (see `recorder`). With the `sync-client`
+//! feature, they write them from a thread of their own instead of QEMU's callbacks, without an
+//! async runtime (see `threaded`), and with the `io-uring` feature from that thread with
+//! io_uring, in batches (see `uring`).
+//!
+//! The header is generated with `cbindgen --config cbindgen.toml -o include/cannonball_client.h`.
+
+#[cfg(feature = "consumer")]
+pub mod consumer;
+#[cfg(feature = "sender")]
+pub mod recorder;
+pub mod replay;
+#[cfg(feature = "sender")]
+pub mod sender;
+pub mod symbols;
+#[cfg(feature = "sync-client")]
+pub mod threaded;
+#[cfg(feature = "io-uring")]
+mod uring;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[cfg(feature = "transport")]
+use cannonball_tools::vsock::VsockListener;
+use cannonball_tools::{
+    output::OutputFormat,
+    trace::{EventKind, TraceReader},
+};
+use serde_json::Value;
+
+use std::{
+    ffi::{c_char, CStr, CString},
+    io::{self, BufRead, Cursor},
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::Path,
+    ptr::{null, null_mut},
+    slice,
+};
+#[cfg(feature = "transport")]
+use std::{
+    io::{stdin, BufReader},
+    os::unix::net::UnixStream,
+};
+
+/// The version of the layout of `CannonballEvent`
+pub const CANNONBALL_CLIENT_ABI_VERSION: u32 = 1;
+
+/// The most opcode bytes a `CannonballEvent` holds
+pub const CANNONBALL_EVENT_OPCODE_MAX: usize = 16;
+
+/// `pid` is set
+pub const CANNONBALL_EVENT_HAS_PID: u32 = 1 << 0;
+/// `vcpu` is set
+pub const CANNONBALL_EVENT_HAS_VCPU: u32 = 1 << 1;
+/// `pc` is set
+pub const CANNONBALL_EVENT_HAS_PC: u32 = 1 << 2;
+/// `addr` is set
+pub const CANNONBALL_EVENT_HAS_ADDR: u32 = 1 << 3;
+/// `size` is set
+pub const CANNONBALL_EVENT_HAS_SIZE: u32 = 1 << 4;
+/// The instruction is a branch
+pub const CANNONBALL_EVENT_BRANCH: u32 = 1 << 5;
+/// The memory access is a store
+pub const CANNONBALL_EVENT_IS_STORE: u32 = 1 << 6;
+
+/// The formats traces can be read in
+#[repr(u32)]
+pub enum CannonballFormat {
+    /// Newline-delimited JSON
+    Json = 0,
+    /// A sequence of CBOR items
+    Cbor = 1,
+    /// A sequence of MessagePack maps
+    Msgpack = 2,
+    /// Length-prefixed CBOR items
+    Binary = 3,
+    /// Delta-encoded instruction and block events
+    Compact = 4,
+}
+
+/// The kinds of events, as stored in `CannonballEvent::kind`
+#[repr(u32)]
+pub enum CannonballEventKind {
+    Unknown = 0,
+    Header = 1,
+    RunEnd = 2,
+    Stats = 3,
+    Fork = 4,
+    Exec = 5,
+    Trigger = 6,
+    Insn = 7,
+    Tb = 8,
+    BlockHits = 9,
+    Edge = 10,
+    Call = 11,
+    Return = 12,
+    FunctionEnter = 13,
+    FunctionExit = 14,
+    Mem = 15,
+    SysMem = 16,
+    Syscall = 17,
+    Breakpoint = 18,
+    InsnMix = 19,
+    Discon = 20,
+    Vcpu = 21,
+    Signal = 22,
+    Final = 23,
+    Map = 24,
+    Unmap = 25,
+    Protect = 26,
+}
+
+impl From<Option<EventKind>> for CannonballEventKind {
+    fn from(kind: Option<EventKind>) -> Self {
+        match kind {
+            None => Self::Unknown,
+            Some(EventKind::Header) => Self::Header,
+            Some(EventKind::Final) => Self::Final,
+            Some(EventKind::RunEnd) => Self::RunEnd,
+            Some(EventKind::Stats) => Self::Stats,
+            Some(EventKind::InsnMix) => Self::InsnMix,
+            Some(EventKind::Fork) => Self::Fork,
+            Some(EventKind::Exec) => Self::Exec,
+            Some(EventKind::Trigger) => Self::Trigger,
+            Some(EventKind::Breakpoint) => Self::Breakpoint,
+            Some(EventKind::Discon) => Self::Discon,
+            Some(EventKind::Vcpu) => Self::Vcpu,
+            Some(EventKind::Signal) => Self::Signal,
+            Some(EventKind::Map) => Self::Map,
+            Some(EventKind::Unmap) => Self::Unmap,
+            Some(EventKind::Protect) => Self::Protect,
+            Some(EventKind::Insn) => Self::Insn,
+            Some(EventKind::TB) => Self::Tb,
+            Some(EventKind::BlockHits) => Self::BlockHits,
+            Some(EventKind::Edge) => Self::Edge,
+            Some(EventKind::Call) => Self::Call,
+            Some(EventKind::Return) => Self::Return,
+            Some(EventKind::FunctionEnter) => Self::FunctionEnter,
+            Some(EventKind::FunctionExit) => Self::FunctionExit,
+            Some(EventKind::Mem) => Self::Mem,
+            Some(EventKind::SysMem) => Self::SysMem,
+            Some(EventKind::Syscall) => Self::Syscall,
+        }
+    }
+}
+
+/// An event, with the fields most events have in common decoded. Fields an event does not
+/// have are zero, and their `CANNONBALL_EVENT_HAS_*` flag is clear
+#[repr(C)]
+pub struct CannonballEvent {
+    /// `CANNONBALL_CLIENT_ABI_VERSION`
+    pub version: u32,
+    /// The kind of the event, one of `CannonballEventKind`
+    pub kind: u32,
+    /// The position of the event in the trace
+    pub seq: u64,
+    /// `CANNONBALL_EVENT_*` flags
+    pub flags: u32,
+    /// The process that logged the event
+    pub pid: u32,
+    /// The VCPU that logged the event
+    pub vcpu: u32,
+    /// The number of bytes of `opcode` that are set
+    pub opcode_len: u32,
+    /// The address of the instruction, block, function entry or exit, edge source, or where an
+    /// interrupt, exception or signal was taken
+    pub pc: u64,
+    /// The address accessed by a memory access, the target of an edge or call, or where an
+    /// interrupt, exception or signal continued
+    pub addr: u64,
+    /// The size of a memory access or block
+    pub size: u64,
+    /// The bytes of an instruction
+    pub opcode: [u8; CANNONBALL_EVENT_OPCODE_MAX],
+    /// The whole event as a NUL-terminated JSON object, valid until the next call on the
+    /// reader it was read from
+    pub json: *const c_char,
+    /// The length of `json`, without its NUL terminator
+    pub json_len: usize,
+}
+
+/// Reads events from a trace file or a socket
+pub struct CannonballReader {
+    /// The events
+    events: TraceReader<Box<dyn BufRead + Send>>,
+    /// The number of events read so far
+    seq: u64,
+    /// The JSON of the last event read
+    json: CString,
+    /// The last error
+    error: CString,
+}
+
+impl CannonballReader {
+    /// Decode an event into `out`
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    /// * `out` - Where to decode it
+    fn decode(&mut self, event: &Value, out: &mut CannonballEvent) {
+        let kind = EventKind::of(event);
+        let u64_of = |event: &Value, name: &str| event.get(name).and_then(Value::as_u64);
+        let bool_of = |event: &Value, name: &str| event.get(name).and_then(Value::as_bool);
+
+        // Accesses are nested in the physical access, and instructions in the access
+        let mem = event.get("mem").unwrap_or(event);
+        let insn = mem.get("insn").unwrap_or(event);
+
+        let (pc, addr) = match kind {
+            Some(EventKind::Mem) | Some(EventKind::SysMem) => {
+                (u64_of(insn, "vaddr"), u64_of(mem, "vaddr"))
+            }
+            Some(EventKind::Edge) => (u64_of(event, "src"), u64_of(event, "dst")),
+            Some(EventKind::Call) => (u64_of(event, "callsite"), u64_of(event, "target")),
+            Some(EventKind::Return) => (u64_of(event, "site"), u64_of(event, "target")),
+            Some(EventKind::FunctionEnter) => (u64_of(event, "entry"), None),
+            Some(EventKind::FunctionExit) => (u64_of(event, "exit"), None),
+            Some(EventKind::Trigger) | Some(EventKind::Breakpoint) => (u64_of(event, "pc"), None),
+            Some(EventKind::Discon) => (u64_of(event, "from_pc"), u64_of(event, "to_pc")),
+            Some(EventKind::Signal) => (u64_of(event, "pc"), u64_of(event, "handler")),
+            Some(EventKind::Map) | Some(EventKind::Unmap) | Some(EventKind::Protect) => {
+                (None, u64_of(event, "addr"))
+            }
+            _ => (u64_of(event, "vaddr"), None),
+        };
+
+        let mut flags = 0;
+        let mut set = |flag: u32, value: Option<u64>| {
+            if value.is_some() {
+                flags |= flag;
+            }
+            value.unwrap_or_default()
+        };
+
+        out.version = CANNONBALL_CLIENT_ABI_VERSION;
+        out.kind = CannonballEventKind::from(kind) as u32;
+        out.seq = self.seq;
+        out.pid = set(CANNONBALL_EVENT_HAS_PID, u64_of(event, "pid")) as u32;
+        out.vcpu = set(CANNONBALL_EVENT_HAS_VCPU, u64_of(insn, "vcpu_idx")) as u32;
+        out.pc = set(CANNONBALL_EVENT_HAS_PC, pc);
+        out.addr = set(CANNONBALL_EVENT_HAS_ADDR, addr);
+        out.size = set(CANNONBALL_EVENT_HAS_SIZE, u64_of(mem, "size"));
+
+        if bool_of(insn, "branch") == Some(true) {
+            flags |= CANNONBALL_EVENT_BRANCH;
+        }
+
+        if bool_of(mem, "is_store") == Some(true) {
+            flags |= CANNONBALL_EVENT_IS_STORE;
+        }
+
+        out.flags = flags;
+
+        out.opcode = [0; CANNONBALL_EVENT_OPCODE_MAX];
+        out.opcode_len = 0;
+
+        if let Some(bytes) = insn.get("opcode").and_then(Value::as_array) {
+            for (byte, value) in out.opcode.iter_mut().zip(bytes) {
+                *byte = value.as_u64().unwrap_or_default() as u8;
+                out.opcode_len += 1;
+            }
+        }
+
+        // JSON strings escape NUL, so the event has none
+        self.json = CString::new(event.to_string()).unwrap_or_default();
+        out.json = self.json.as_ptr();
+        out.json_len = self.json.as_bytes().len();
+
+        self.seq += 1;
+    }
+}
+
+/// The format a `CannonballFormat` stands for
+///
+/// # Arguments
+///
+/// * `format` - One of `CannonballFormat`
+pub(crate) fn output_format(format: u32) -> Option<OutputFormat> {
+    match format {
+        f if f == CannonballFormat::Json as u32 => Some(OutputFormat::Json),
+        f if f == CannonballFormat::Cbor as u32 => Some(OutputFormat::Cbor),
+        f if f == CannonballFormat::Msgpack as u32 => Some(OutputFormat::Msgpack),
+        f if f == CannonballFormat::Binary as u32 => Some(OutputFormat::Binary),
+        f if f == CannonballFormat::Compact as u32 => Some(OutputFormat::Compact),
+        _ => None,
+    }
+}
+
+/// Open a reader with `open`, setting `errno` and returning NULL if it fails
+///
+/// # Arguments
+///
+/// * `path` - The path to open
+/// * `format` - One of `CannonballFormat`
+/// * `open` - Opens a reader of a path
+///
+/// # Safety
+///
+/// `path` must be NULL or a NUL-terminated string
+unsafe fn open_with(
+    path: *const c_char,
+    format: u32,
+    open: impl FnOnce(&Path, OutputFormat) -> io::Result<TraceReader<Box<dyn BufRead + Send>>>,
+) -> *mut CannonballReader {
+    let (Some(format), false) = (output_format(format), path.is_null()) else {
+        set_errno(None);
+        return null_mut();
+    };
+
+    let Ok(path) = CStr::from_ptr(path).to_str() else {
+        set_errno(None);
+        return null_mut();
+    };
+
+    reader(open(Path::new(path), format))
+}
+
+/// A reader of events, or NULL with `errno` set if they could not be opened
+///
+/// # Arguments
+///
+/// * `events` - The events, or why they could not be opened
+fn reader(events: io::Result<TraceReader<Box<dyn BufRead + Send>>>) -> *mut CannonballReader {
+    match events {
+        Ok(events) => Box::into_raw(Box::new(CannonballReader {
+            events,
+            seq: 0,
+            json: CString::default(),
+            error: CString::default(),
+        })),
+        Err(e) => {
+            set_errno(Some(&e));
+            null_mut()
+        }
+    }
+}
+
+/// Set `errno` to the error of a call that failed, or to `EINVAL` for invalid arguments.
+/// Without the `transport` feature, like in WebAssembly, there is no `errno` to set
+///
+/// # Arguments
+///
+/// * `error` - Why the call failed, if not because of its arguments
+pub(crate) fn set_errno(error: Option<&io::Error>) {
+    #[cfg(feature = "transport")]
+    unsafe {
+        *libc::__errno_location() = error
+            .and_then(io::Error::raw_os_error)
+            .unwrap_or(libc::EINVAL)
+    };
+
+    #[cfg(not(feature = "transport"))]
+    let _ = error;
+}
+
+/// Run the body of a C API function, catching a panic so it does not unwind into the caller.
+/// A panic fails the call like any other error, with `errno` set to `EIO`
+///
+/// # Arguments
+///
+/// * `failed` - What the function returns when it fails
+/// * `f` - The body of the function
+pub(crate) fn guard<R>(failed: R, f: impl FnOnce() -> R) -> R {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(_) => {
+            #[cfg(feature = "transport")]
+            set_errno(Some(&io::Error::from_raw_os_error(libc::EIO)));
+            failed
+        }
+    }
+}
+
+/// The version of the layout of `CannonballEvent` this library fills in. Callers built against
+/// a different `CANNONBALL_CLIENT_ABI_VERSION` should not use it
+#[no_mangle]
+pub extern "C" fn cannonball_client_reader_abi_version() -> u32 {
+    CANNONBALL_CLIENT_ABI_VERSION
+}
+
+/// Open a trace file written in `format`, decompressing it if it is compressed with gzip.
+/// Returns NULL and sets `errno` if it cannot be opened
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_reader_open(
+    path: *const c_char,
+    format: u32,
+) -> *mut CannonballReader {
+    guard(null_mut(), || {
+        open_with(path, format, |path, format| TraceReader::open(path, format))
+    })
+}
+
+/// Read a trace already in memory, written in `format`, decompressing it if it is compressed
+/// with gzip. The trace is copied, so `data` may be freed once this returns. Returns NULL and
+/// sets `errno` if `format` is invalid, or if the header of a compact trace cannot be read
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_reader_bytes(
+    data: *const u8,
+    len: usize,
+    format: u32,
+) -> *mut CannonballReader {
+    guard(null_mut(), || {
+        let (Some(format), false) = (output_format(format), data.is_null() && len != 0) else {
+            set_errno(None);
+            return null_mut();
+        };
+
+        let data = if len == 0 {
+            Vec::new()
+        } else {
+            slice::from_raw_parts(data, len).to_vec()
+        };
+
+        reader(TraceReader::decompress(Cursor::new(data), format))
+    })
+}
+
+/// Connect to a UNIX socket events are written to in `format`. Returns NULL and sets `errno`
+/// if it cannot be connected to
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string
+#[cfg(feature = "transport")]
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_reader_connect(
+    path: *const c_char,
+    format: u32,
+) -> *mut CannonballReader {
+    guard(null_mut(), || {
+        open_with(path, format, |path, format| {
+            let stream: Box<dyn BufRead + Send> =
+                Box::new(BufReader::new(UnixStream::connect(path)?));
+            TraceReader::new(stream, format)
+        })
+    })
+}
+
+/// Read events written to stdin in `format`, like the framed binary stream of a plugin sink at
+/// the end of a shell pipeline. Returns NULL and sets `errno` if `format` is invalid, or if the
+/// header of a compact trace cannot be read
+#[cfg(feature = "transport")]
+#[no_mangle]
+pub extern "C" fn cannonball_client_reader_stdin(format: u32) -> *mut CannonballReader {
+    guard(null_mut(), || {
+        let Some(format) = output_format(format) else {
+            set_errno(None);
+            return null_mut();
+        };
+
+        let stream: Box<dyn BufRead + Send> = Box::new(BufReader::new(stdin()));
+        reader(TraceReader::new(stream, format))
+    })
+}
+
+/// Listen on a vsock port for the plugin to connect to from a virtual machine, with
+/// `sink=vsock:<cid>:<port>`, and read the events it writes in `format`. Blocks until the
+/// plugin connects. Returns NULL and sets `errno` if `format` is invalid or the port cannot be
+/// listened on
+#[cfg(feature = "transport")]
+#[no_mangle]
+pub extern "C" fn cannonball_client_reader_vsock(port: u32, format: u32) -> *mut CannonballReader {
+    guard(null_mut(), || {
+        let Some(format) = output_format(format) else {
+            set_errno(None);
+            return null_mut();
+        };
+
+        reader(VsockListener::bind(port).and_then(|listener| {
+            let stream: Box<dyn BufRead + Send> = Box::new(BufReader::new(listener.accept()?.0));
+            TraceReader::new(stream, format)
+        }))
+    })
+}
+
+/// Read the next event into `event`. Returns 1 if an event was read, 0 at the end of the
+/// trace, and -1 if it could not be read, see `cannonball_client_reader_error`
+///
+/// # Safety
+///
+/// `reader` must have been returned by `cannonball_client_reader_open`,
+/// `cannonball_client_reader_bytes`, `cannonball_client_reader_connect`,
+/// `cannonball_client_reader_stdin` or `cannonball_client_reader_vsock` and not closed, and
+/// `event` must point to a `CannonballEvent`
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_reader_next(
+    reader: *mut CannonballReader,
+    event: *mut CannonballEvent,
+) -> i32 {
+    guard(-1, || {
+        let (Some(reader), Some(out)) = (reader.as_mut(), event.as_mut()) else {
+            return -1;
+        };
+
+        match reader.events.next() {
+            Some(Ok(event)) => {
+                reader.decode(&event, out);
+                1
+            }
+            Some(Err(e)) => {
+                reader.error = CString::new(e.to_string()).unwrap_or_default();
+                -1
+            }
+            None => 0,
+        }
+    })
+}
+
+/// The last error of a reader, as a NUL-terminated string valid until the next call on it, or
+/// an empty string if there was none. NULL if `reader` is NULL
+///
+/// # Safety
+///
+/// `reader` must be NULL, or have been returned by `cannonball_client_reader_open`,
+/// `cannonball_client_reader_bytes`, `cannonball_client_reader_connect`,
+/// `cannonball_client_reader_stdin` or `cannonball_client_reader_vsock` and not closed
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_reader_error(
+    reader: *const CannonballReader,
+) -> *const c_char {
+    guard(null(), || match reader.as_ref() {
+        Some(reader) => reader.error.as_ptr(),
+        None => null(),
+    })
+}
+
+/// Close a reader, freeing it
+///
+/// # Safety
+///
+/// `reader` must be NULL, or have been returned by `cannonball_client_reader_open`,
+/// `cannonball_client_reader_bytes`, `cannonball_client_reader_connect`,
+/// `cannonball_client_reader_stdin` or `cannonball_client_reader_vsock` and not closed
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_reader_close(reader: *mut CannonballReader) {
+    guard((), || {
+        if !reader.is_null() {
+            drop(Box::from_raw(reader));
+        }
+    })
+}
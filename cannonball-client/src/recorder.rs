@@ -0,0 +1,126 @@
+//! Recording the last events
+//!
+//! Sending every event to a consumer costs a write per event, for a trace of which only the
+//! end matters when the question is why a program crashed. A `FlightRecorder` keeps the last
+//! events of a plugin in memory instead, overwriting the oldest ones, and writes them to a
+//! file when asked, like when the guest is killed by a signal (see `cannonball::exit`).
+//! Events are encoded into buffers the recorder reuses, so once it is full, recording an
+//! event allocates nothing.
+//!
+//! A dump holds the events oldest first, encoded as on the socket of a `Sender`, so it is read
+//! back with `cannonball_events::codec::read_events`:
+//!
+//! ```no_run
+//! use cannonball_client::{recorder::FlightRecorder, sender::ClientError};
+//! use cannonball_events::{codec::read_events, Event, InsnEvent};
+//!
+//! use std::fs::File;
+//!
+//! # fn record() -> Result<(), ClientError> {
+//! let mut recorder = FlightRecorder::new(2);
+//!
+//! for pc in [0x401000, 0x401004, 0x401008] {
+//!     recorder.record(&Event::Insn(InsnEvent::new(Some(0), pc, None, false)))?;
+//! }
+//!
+//! // The first event was overwritten
+//! assert_eq!(recorder.dump("crash.dump")?, 2);
+//!
+//! for event in read_events(File::open("crash.dump")?) {
+//!     println!("{:?}", event?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use cannonball_events::{codec::encode, Event};
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::sender::ClientError;
+
+/// Keeps the last events recorded, to write them to a file
+#[derive(Debug)]
+pub struct FlightRecorder {
+    /// The most events kept
+    capacity: usize,
+    /// The encodings of the events kept, oldest first
+    events: VecDeque<Vec<u8>>,
+    /// The number of events recorded, including those overwritten
+    recorded: u64,
+}
+
+impl FlightRecorder {
+    /// Instantiate a recorder keeping the last events
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - The number of events to keep, at least 1
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+
+        Self {
+            capacity,
+            events: VecDeque::with_capacity(capacity),
+            recorded: 0,
+        }
+    }
+
+    /// Record an event, overwriting the oldest one if the recorder is full. Fails if the event
+    /// cannot be encoded, in which case it is not recorded, but the oldest one is still dropped
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn record(&mut self, event: &Event) -> Result<(), ClientError> {
+        let mut buf = if self.events.len() == self.capacity {
+            self.events.pop_front().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+
+        buf.clear();
+
+        encode(event, &mut buf)?;
+        self.events.push_back(buf);
+        self.recorded += 1;
+        Ok(())
+    }
+
+    /// Write the events kept to a file, oldest first, replacing it if it exists. Returns the
+    /// number of events written. The events are kept, so a later dump holds them too
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file
+    pub fn dump(&self, path: impl AsRef<Path>) -> Result<usize, ClientError> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        for event in &self.events {
+            file.write_all(event)?;
+        }
+
+        file.flush()?;
+        Ok(self.events.len())
+    }
+
+    /// The number of events kept
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Whether no event was recorded
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// The number of events recorded so far, including those overwritten since
+    pub fn recorded(&self) -> u64 {
+        self.recorded
+    }
+}
@@ -0,0 +1,470 @@
+//! Reading replay logs
+//!
+//! With `replay_log=<path>`, the Jaivana plugin records the result of every system call a
+//! program makes, so a replay harness can feed a later run the same results. `ReplayLog` reads
+//! these logs from Rust, and the `cannonball_client_replay_*` functions from C.
+//!
+//! A replay log is a header followed by one record per system call, in the order they returned.
+//! Integers are little endian:
+//!
+//! ```text
+//! header: magic "CBREPLAY" | version u32 | flags u32 | max_bytes u32 | target_len u32
+//!         | target [target_len]
+//! record: pid u32 | vcpu u32 | num i64 | rv i64 | args [u64; 8] | buffers u32
+//!         | buffer [buffers]
+//! buffer: arg u32 | addr u64 | size u64 | len u32 | data [len]
+//! ```
+//!
+//! `target` is the QEMU target, like `x86_64`, which system call numbers depend on. Bit 0 of
+//! `flags` is set if the buffers system calls wrote to were recorded, which needs the plugin to
+//! be built with `plugin-api-v4`. Each buffer is the `size` bytes at `addr` the system call
+//! wrote to through its argument `arg`, of which the first `len` were recorded: at most
+//! `max_bytes`.
+//!
+//! ```c
+//! CannonballReplayLog *log = cannonball_client_replay_open("ls.replay");
+//! CannonballSyscall syscall;
+//! CannonballSyscallBuffer buffer;
+//!
+//! while (cannonball_client_replay_next(log, &syscall) == 1) {
+//!     for (uint32_t i = 0; cannonball_client_replay_buffer(log, i, &buffer) == 1; i++) {
+//!         printf("%ld wrote %zu bytes at %lx\n", syscall.num, buffer.len, buffer.addr);
+//!     }
+//! }
+//!
+//! cannonball_client_replay_close(log);
+//! ```
+
+use std::{
+    ffi::{c_char, CStr, CString},
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+    ptr::{null, null_mut},
+};
+
+use crate::{guard, set_errno};
+
+/// The version of the replay log format this library reads
+pub const CANNONBALL_REPLAY_VERSION: u32 = 1;
+
+/// The number of arguments recorded for each system call
+pub const CANNONBALL_REPLAY_ARGS: usize = 8;
+
+/// Header flag set if the buffers system calls wrote to were recorded
+pub const CANNONBALL_REPLAY_BUFFERS: u32 = 1 << 0;
+
+/// The magic bytes a replay log starts with
+const MAGIC: &[u8; 8] = b"CBREPLAY";
+
+/// The header of a replay log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayHeader {
+    /// The version of the format
+    pub version: u32,
+    /// `CANNONBALL_REPLAY_*` flags
+    pub flags: u32,
+    /// The most bytes recorded of each buffer
+    pub max_bytes: u32,
+    /// The QEMU target, like `x86_64`
+    pub target: String,
+}
+
+/// A buffer a system call wrote to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyscallBuffer {
+    /// The argument of the system call pointing to the buffer
+    pub arg: u32,
+    /// The address of the buffer
+    pub addr: u64,
+    /// The number of bytes the system call wrote to it
+    pub size: u64,
+    /// The first bytes it wrote, at most `max_bytes` of the header
+    pub data: Vec<u8>,
+}
+
+/// A system call and its results
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyscallRecord {
+    /// The process that made it
+    pub pid: u32,
+    /// The VCPU that made it
+    pub vcpu: u32,
+    /// The system call number
+    pub num: i64,
+    /// The return value
+    pub rv: i64,
+    /// The arguments
+    pub args: [u64; CANNONBALL_REPLAY_ARGS],
+    /// The buffers it wrote to
+    pub buffers: Vec<SyscallBuffer>,
+}
+
+/// Read a little endian `u32`
+///
+/// # Arguments
+///
+/// * `reader` - Where to read it from
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Read a little endian `u64`
+///
+/// # Arguments
+///
+/// * `reader` - Where to read it from
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Read `len` bytes
+///
+/// # Arguments
+///
+/// * `reader` - Where to read them from
+/// * `len` - The number of bytes
+fn read_bytes(reader: &mut impl Read, len: u32) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut bytes)?;
+
+    if bytes.len() != len as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(bytes)
+}
+
+/// Reads the system calls of a replay log
+pub struct ReplayLog<R: Read> {
+    reader: R,
+    header: ReplayHeader,
+}
+
+impl ReplayLog<BufReader<File>> {
+    /// Open a replay log file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The replay log
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> ReplayLog<R> {
+    /// Read a replay log, starting with its header
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The replay log
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+
+        if &magic != MAGIC {
+            return Err(invalid("Not a replay log"));
+        }
+
+        let version = read_u32(&mut reader)?;
+
+        if version != CANNONBALL_REPLAY_VERSION {
+            return Err(invalid("Unsupported replay log version"));
+        }
+
+        let flags = read_u32(&mut reader)?;
+        let max_bytes = read_u32(&mut reader)?;
+        let target_len = read_u32(&mut reader)?;
+        let target = String::from_utf8(read_bytes(&mut reader, target_len)?)
+            .map_err(|_| invalid("The target is not UTF-8"))?;
+
+        Ok(Self {
+            reader,
+            header: ReplayHeader {
+                version,
+                flags,
+                max_bytes,
+                target,
+            },
+        })
+    }
+
+    /// The header of the log
+    pub fn header(&self) -> &ReplayHeader {
+        &self.header
+    }
+
+    /// Read the next record, or `None` at the end of the log
+    fn record(&mut self) -> io::Result<Option<SyscallRecord>> {
+        let mut pid = [0; 4];
+
+        // The log may end between records, but not inside one
+        match self.reader.read(&mut pid[..1])? {
+            0 => return Ok(None),
+            _ => self.reader.read_exact(&mut pid[1..])?,
+        }
+
+        let reader = &mut self.reader;
+        let vcpu = read_u32(reader)?;
+        let num = read_u64(reader)? as i64;
+        let rv = read_u64(reader)? as i64;
+        let mut args = [0; CANNONBALL_REPLAY_ARGS];
+
+        for arg in &mut args {
+            *arg = read_u64(reader)?;
+        }
+
+        let buffers = (0..read_u32(reader)?)
+            .map(|_| {
+                let arg = read_u32(reader)?;
+                let addr = read_u64(reader)?;
+                let size = read_u64(reader)?;
+                let len = read_u32(reader)?;
+
+                Ok(SyscallBuffer {
+                    arg,
+                    addr,
+                    size,
+                    data: read_bytes(reader, len)?,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Some(SyscallRecord {
+            pid: u32::from_le_bytes(pid),
+            vcpu,
+            num,
+            rv,
+            args,
+            buffers,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for ReplayLog<R> {
+    type Item = io::Result<SyscallRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.record().transpose()
+    }
+}
+
+/// A system call, as read by `cannonball_client_replay_next`
+#[repr(C)]
+pub struct CannonballSyscall {
+    /// The process that made it
+    pub pid: u32,
+    /// The VCPU that made it
+    pub vcpu: u32,
+    /// The system call number
+    pub num: i64,
+    /// The return value
+    pub rv: i64,
+    /// The arguments
+    pub args: [u64; CANNONBALL_REPLAY_ARGS],
+    /// The number of buffers it wrote to, read with `cannonball_client_replay_buffer`
+    pub buffers: u32,
+}
+
+/// A buffer a system call wrote to, as read by `cannonball_client_replay_buffer`
+#[repr(C)]
+pub struct CannonballSyscallBuffer {
+    /// The argument of the system call pointing to the buffer
+    pub arg: u32,
+    /// The address of the buffer
+    pub addr: u64,
+    /// The number of bytes the system call wrote to it
+    pub size: u64,
+    /// The first bytes it wrote, valid until the next call on the log it was read from
+    pub data: *const u8,
+    /// The number of bytes of `data`, at most `size`
+    pub len: usize,
+}
+
+/// Reads the system calls of a replay log file
+pub struct CannonballReplayLog {
+    /// The log
+    log: ReplayLog<BufReader<File>>,
+    /// The target of the log, NUL-terminated
+    target: CString,
+    /// The last system call read
+    record: Option<SyscallRecord>,
+    /// The last error
+    error: CString,
+}
+
+/// Open a replay log file. Returns NULL and sets `errno` if it cannot be opened or is not a
+/// replay log
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_replay_open(
+    path: *const c_char,
+) -> *mut CannonballReplayLog {
+    guard(null_mut(), || {
+        let path = if path.is_null() {
+            None
+        } else {
+            CStr::from_ptr(path).to_str().ok()
+        };
+
+        let Some(path) = path else {
+            set_errno(None);
+            return null_mut();
+        };
+
+        match ReplayLog::open(path) {
+            Ok(log) => Box::into_raw(Box::new(CannonballReplayLog {
+                target: CString::new(log.header().target.clone()).unwrap_or_default(),
+                log,
+                record: None,
+                error: CString::default(),
+            })),
+            Err(e) => {
+                set_errno(Some(&e));
+                null_mut()
+            }
+        }
+    })
+}
+
+/// The `CANNONBALL_REPLAY_*` flags of a replay log, or 0 if `log` is NULL
+///
+/// # Safety
+///
+/// `log` must be NULL, or have been returned by `cannonball_client_replay_open` and not closed
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_replay_flags(log: *const CannonballReplayLog) -> u32 {
+    guard(0, || log.as_ref().map_or(0, |log| log.log.header().flags))
+}
+
+/// The QEMU target of a replay log, like `x86_64`, as a NUL-terminated string valid until it is
+/// closed. NULL if `log` is NULL
+///
+/// # Safety
+///
+/// `log` must be NULL, or have been returned by `cannonball_client_replay_open` and not closed
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_replay_target(
+    log: *const CannonballReplayLog,
+) -> *const c_char {
+    guard(null(), || match log.as_ref() {
+        Some(log) => log.target.as_ptr(),
+        None => null(),
+    })
+}
+
+/// Read the next system call into `syscall`. Returns 1 if one was read, 0 at the end of the
+/// log, and -1 if it could not be read, see `cannonball_client_replay_error`
+///
+/// # Safety
+///
+/// `log` must have been returned by `cannonball_client_replay_open` and not closed, and
+/// `syscall` must point to a `CannonballSyscall`
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_replay_next(
+    log: *mut CannonballReplayLog,
+    syscall: *mut CannonballSyscall,
+) -> i32 {
+    guard(-1, || {
+        let (Some(log), Some(out)) = (log.as_mut(), syscall.as_mut()) else {
+            return -1;
+        };
+
+        match log.log.next() {
+            Some(Ok(record)) => {
+                out.pid = record.pid;
+                out.vcpu = record.vcpu;
+                out.num = record.num;
+                out.rv = record.rv;
+                out.args = record.args;
+                out.buffers = record.buffers.len() as u32;
+                log.record = Some(record);
+                1
+            }
+            Some(Err(e)) => {
+                log.record = None;
+                log.error = CString::new(e.to_string()).unwrap_or_default();
+                -1
+            }
+            None => {
+                log.record = None;
+                0
+            }
+        }
+    })
+}
+
+/// Read a buffer the last system call read wrote to into `buffer`. Returns 1 if it was read,
+/// and 0 if the system call wrote to fewer buffers
+///
+/// # Safety
+///
+/// `log` must have been returned by `cannonball_client_replay_open` and not closed, and
+/// `buffer` must point to a `CannonballSyscallBuffer`
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_replay_buffer(
+    log: *const CannonballReplayLog,
+    index: u32,
+    buffer: *mut CannonballSyscallBuffer,
+) -> i32 {
+    guard(-1, || {
+        let (Some(log), Some(out)) = (log.as_ref(), buffer.as_mut()) else {
+            return -1;
+        };
+
+        match log
+            .record
+            .as_ref()
+            .and_then(|record| record.buffers.get(index as usize))
+        {
+            Some(buffer) => {
+                out.arg = buffer.arg;
+                out.addr = buffer.addr;
+                out.size = buffer.size;
+                out.data = buffer.data.as_ptr();
+                out.len = buffer.data.len();
+                1
+            }
+            None => 0,
+        }
+    })
+}
+
+/// The last error of a replay log, as a NUL-terminated string valid until the next call on it,
+/// or an empty string if there was none. NULL if `log` is NULL
+///
+/// # Safety
+///
+/// `log` must be NULL, or have been returned by `cannonball_client_replay_open` and not closed
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_replay_error(
+    log: *const CannonballReplayLog,
+) -> *const c_char {
+    guard(null(), || match log.as_ref() {
+        Some(log) => log.error.as_ptr(),
+        None => null(),
+    })
+}
+
+/// Close a replay log, freeing it
+///
+/// # Safety
+///
+/// `log` must be NULL, or have been returned by `cannonball_client_replay_open` and not closed
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_replay_close(log: *mut CannonballReplayLog) {
+    guard((), || {
+        if !log.is_null() {
+            drop(Box::from_raw(log));
+        }
+    })
+}
@@ -0,0 +1,326 @@
+//! Sending events
+//!
+//! A `Sender` is the end of an `EventStream` (see `consumer`) that lives inside a plugin: it
+//! connects to the UNIX socket of a consumer and writes events to it. Writing runs inside the
+//! guest process, on the callbacks of QEMU, so failures are never panics. Each is returned as
+//! a `ClientError`, after the sender handled it as its `OnError` policy says: trying to
+//! reconnect, dropping the events the consumer missed, or shutting down.
+//!
+//! ```no_run
+//! use cannonball_client::sender::{ClientError, OnError, Sender};
+//! use cannonball_events::{Event, InsnEvent};
+//!
+//! # fn send() -> Result<(), ClientError> {
+//! let mut sender = Sender::connect("/tmp/qemu.sock")?.on_error("retry:3".parse()?);
+//!
+//! sender.send(&Event::Insn(InsnEvent::new(Some(0), 0x401000, None, false)))?;
+//! sender.shutdown()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use cannonball_events::{codec::encode, Event};
+
+#[cfg(feature = "io-uring")]
+use std::os::unix::io::RawFd;
+use std::{
+    error::Error,
+    fmt,
+    io::{self, ErrorKind},
+    net::Shutdown,
+    os::unix::{io::AsRawFd, net::UnixStream},
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread::sleep,
+    time::Duration,
+};
+
+/// How long a retrying sender waits between attempts to reconnect, unless it is given
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An error sending events
+#[derive(Debug)]
+pub enum ClientError {
+    /// The consumer could not be connected to or written to
+    Transport(io::Error),
+    /// An event could not be encoded. The sender can still send other events
+    Encode(cannonball_events::codec::Error),
+    /// The sender was shut down, by `Sender::shutdown` or by its `OnError` policy
+    Shutdown,
+    /// An `OnError` policy could not be parsed
+    Policy(String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Transport(e) => write!(f, "Could not send events: {}", e),
+            Self::Encode(e) => write!(f, "Could not encode event: {}", e),
+            Self::Shutdown => write!(f, "The sender is shut down"),
+            Self::Policy(policy) => write!(
+                f,
+                "Unknown error policy {}, expected retry, retry:N, drop or terminate",
+                policy
+            ),
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Transport(e) => Some(e),
+            Self::Encode(e) => Some(e),
+            Self::Shutdown | Self::Policy(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        Self::Transport(e)
+    }
+}
+
+impl From<cannonball_events::codec::Error> for ClientError {
+    fn from(e: cannonball_events::codec::Error) -> Self {
+        Self::Encode(e)
+    }
+}
+
+/// What a `Sender` does when the consumer cannot be written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    /// Reconnect to the consumer and send the event again, up to `attempts` times
+    /// `interval` apart, and shut down if it never comes back
+    Retry { attempts: u32, interval: Duration },
+    /// Drop the event, and every later one while the consumer cannot be written to, counting
+    /// them in `Sender::dropped`. Sending succeeds, so the plugin keeps running without a
+    /// consumer
+    Drop,
+    /// Shut down, so every later event fails with `ClientError::Shutdown`
+    Terminate,
+}
+
+impl Default for OnError {
+    /// Shut down on the first error
+    fn default() -> Self {
+        Self::Terminate
+    }
+}
+
+impl FromStr for OnError {
+    type Err = ClientError;
+
+    /// Parse a policy as given to a plugin: `retry`, which tries 3 times, `retry:N`, `drop`
+    /// or `terminate`
+    fn from_str(policy: &str) -> Result<Self, ClientError> {
+        match policy.split_once(':') {
+            None if policy == "retry" => Ok(Self::Retry {
+                attempts: 3,
+                interval: RETRY_INTERVAL,
+            }),
+            None if policy == "drop" => Ok(Self::Drop),
+            None if policy == "terminate" => Ok(Self::Terminate),
+            Some(("retry", attempts)) => Ok(Self::Retry {
+                attempts: attempts
+                    .parse()
+                    .map_err(|_| ClientError::Policy(policy.to_string()))?,
+                interval: RETRY_INTERVAL,
+            }),
+            _ => Err(ClientError::Policy(policy.to_string())),
+        }
+    }
+}
+
+/// Writes events to a consumer listening on a UNIX socket
+#[derive(Debug)]
+pub struct Sender {
+    /// The socket of the consumer, to reconnect to
+    path: PathBuf,
+    /// The connection, until the sender is shut down
+    stream: Option<UnixStream>,
+    /// What to do when the consumer cannot be written to
+    on_error: OnError,
+    /// The encoding of the event being sent
+    buf: Vec<u8>,
+    /// The number of events dropped
+    dropped: u64,
+}
+
+impl Sender {
+    /// Connect to a consumer
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The socket the consumer listens on
+    pub fn connect(path: impl AsRef<Path>) -> Result<Self, ClientError> {
+        let path = path.as_ref().to_path_buf();
+        let stream = UnixStream::connect(&path)?;
+
+        Ok(Self {
+            path,
+            stream: Some(stream),
+            on_error: OnError::default(),
+            buf: Vec::new(),
+            dropped: 0,
+        })
+    }
+
+    /// Handle the errors writing to the consumer with a policy other than shutting down
+    ///
+    /// # Arguments
+    ///
+    /// * `on_error` - The policy
+    pub fn on_error(mut self, on_error: OnError) -> Self {
+        self.on_error = on_error;
+        self
+    }
+
+    /// Send an event. Fails if the sender is shut down, if the event cannot be encoded, or if
+    /// the consumer cannot be written to and the `OnError` policy did not recover
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn send(&mut self, event: &Event) -> Result<(), ClientError> {
+        let stream = self.stream.as_ref().ok_or(ClientError::Shutdown)?;
+
+        self.buf.clear();
+        encode(event, &mut self.buf)?;
+
+        match send_all(stream, &self.buf) {
+            Ok(()) => Ok(()),
+            Err(e) => self.failed(e),
+        }
+    }
+
+    /// Send an event encoded already, like by a `ThreadedSender`. Fails like `send`
+    ///
+    /// # Arguments
+    ///
+    /// * `encoded` - The encoding of the event
+    #[cfg(feature = "sync-client")]
+    pub(crate) fn send_encoded(&mut self, encoded: &[u8]) -> Result<(), ClientError> {
+        self.send_rest(encoded, 0)
+    }
+
+    /// Send the rest of an event encoded already, whose first bytes were written to the
+    /// connection already, like by a short write. Fails like `send`. Only the rest is written
+    /// to the connection, but a retrying sender sends the whole event to a new connection
+    ///
+    /// # Arguments
+    ///
+    /// * `encoded` - The encoding of the event
+    /// * `sent` - The number of bytes of the encoding written already
+    #[cfg(feature = "sync-client")]
+    pub(crate) fn send_rest(&mut self, encoded: &[u8], sent: usize) -> Result<(), ClientError> {
+        let stream = self.stream.as_ref().ok_or(ClientError::Shutdown)?;
+
+        match send_all(stream, &encoded[sent..]) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                // The policy sends the event again from `buf`
+                self.buf.clear();
+                self.buf.extend_from_slice(encoded);
+                self.failed(e)
+            }
+        }
+    }
+
+    /// Close the connection, so the consumer sees the end of the events. Later events fail
+    /// with `ClientError::Shutdown`
+    pub fn shutdown(&mut self) -> Result<(), ClientError> {
+        match self.stream.take() {
+            Some(stream) => Ok(stream.shutdown(Shutdown::Both)?),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the sender is shut down
+    pub fn is_shutdown(&self) -> bool {
+        self.stream.is_none()
+    }
+
+    /// The number of events dropped by the `OnError::Drop` policy
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// The file descriptor of the connection, for writing to it other than with `send`, unless
+    /// the sender is shut down
+    #[cfg(feature = "io-uring")]
+    pub(crate) fn as_raw_fd(&self) -> Option<RawFd> {
+        self.stream.as_ref().map(AsRawFd::as_raw_fd)
+    }
+
+    /// Handle an error writing the event in `buf`, as the policy says
+    ///
+    /// # Arguments
+    ///
+    /// * `e` - The error
+    fn failed(&mut self, e: io::Error) -> Result<(), ClientError> {
+        match self.on_error {
+            OnError::Drop => {
+                self.dropped += 1;
+                Ok(())
+            }
+            OnError::Terminate => {
+                self.stream = None;
+                Err(e.into())
+            }
+            OnError::Retry { attempts, interval } => {
+                let mut error = e;
+
+                for _ in 0..attempts {
+                    sleep(interval);
+
+                    match UnixStream::connect(&self.path)
+                        .and_then(|stream| send_all(&stream, &self.buf).map(|_| stream))
+                    {
+                        Ok(stream) => {
+                            self.stream = Some(stream);
+                            return Ok(());
+                        }
+                        Err(e) => error = e,
+                    }
+                }
+
+                self.stream = None;
+                Err(error.into())
+            }
+        }
+    }
+}
+
+/// Write a whole buffer to a socket. The consumer going away fails the write rather than
+/// raising `SIGPIPE`, which would kill QEMU
+///
+/// # Arguments
+///
+/// * `stream` - The socket
+/// * `buf` - The buffer
+fn send_all(stream: &UnixStream, mut buf: &[u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        let sent = unsafe {
+            libc::send(
+                stream.as_raw_fd(),
+                buf.as_ptr().cast(),
+                buf.len(),
+                libc::MSG_NOSIGNAL,
+            )
+        };
+
+        if sent < 0 {
+            let e = io::Error::last_os_error();
+
+            if e.kind() != ErrorKind::Interrupted {
+                return Err(e);
+            }
+        } else {
+            buf = &buf[sent as usize..];
+        }
+    }
+
+    Ok(())
+}
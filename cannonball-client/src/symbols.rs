@@ -0,0 +1,176 @@
+//! Symbolizing addresses
+//!
+//! Events log addresses, not names. The `cannonball_client_symbolizer_*` functions name the
+//! function an address is in from the symbol table of the traced program, read from a file or
+//! from memory, like a program a trace viewer was handed along with its trace.
+//!
+//! ```c
+//! CannonballSymbolizer *symbolizer = cannonball_client_symbolizer_open("/bin/ls");
+//! uint64_t offset;
+//! const char *name = cannonball_client_symbolize(symbolizer, event.pc, &offset);
+//!
+//! if (name) {
+//!     printf("%s+%lx\n", name, offset);
+//! }
+//!
+//! cannonball_client_symbolizer_close(symbolizer);
+//! ```
+
+use cannonball_tools::symbols::Symbolizer;
+
+use std::{
+    ffi::{c_char, CStr, CString},
+    io,
+    ptr::{null, null_mut},
+    slice,
+};
+
+use crate::{guard, set_errno};
+
+/// Names the functions of a program addresses are in
+pub struct CannonballSymbolizer {
+    /// The symbols of the program
+    symbolizer: Symbolizer,
+    /// The address the program was loaded at, relative to its symbol table
+    bias: u64,
+    /// The name of the last function found
+    name: CString,
+}
+
+/// A symbolizer of a program, or NULL with `errno` set if its symbols could not be read
+///
+/// # Arguments
+///
+/// * `symbolizer` - The symbols, or why they could not be read
+fn symbolizer(symbolizer: io::Result<Symbolizer>) -> *mut CannonballSymbolizer {
+    match symbolizer {
+        Ok(symbolizer) => Box::into_raw(Box::new(CannonballSymbolizer {
+            symbolizer,
+            bias: 0,
+            name: CString::default(),
+        })),
+        Err(e) => {
+            set_errno(Some(&e));
+            null_mut()
+        }
+    }
+}
+
+/// Read the function symbols of an ELF program. Returns NULL and sets `errno` if it cannot be
+/// read or is not an ELF file
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_symbolizer_open(
+    path: *const c_char,
+) -> *mut CannonballSymbolizer {
+    guard(null_mut(), || {
+        let path = if path.is_null() {
+            None
+        } else {
+            CStr::from_ptr(path).to_str().ok()
+        };
+
+        let Some(path) = path else {
+            set_errno(None);
+            return null_mut();
+        };
+
+        symbolizer(Symbolizer::load(path))
+    })
+}
+
+/// Read the function symbols of an ELF program already in memory. Nothing is kept of `data`,
+/// so it may be freed once this returns. Returns NULL and sets `errno` if it is not an ELF file
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_symbolizer_parse(
+    data: *const u8,
+    len: usize,
+) -> *mut CannonballSymbolizer {
+    guard(null_mut(), || {
+        if data.is_null() {
+            set_errno(None);
+            return null_mut();
+        }
+
+        symbolizer(Symbolizer::parse(slice::from_raw_parts(data, len)))
+    })
+}
+
+/// Symbolize addresses of the program loaded at `bias`, relative to its symbol table, like
+/// a position independent executable
+///
+/// # Safety
+///
+/// `symbolizer` must be NULL, or have been returned by `cannonball_client_symbolizer_open` or
+/// `cannonball_client_symbolizer_parse` and not closed
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_symbolizer_set_bias(
+    symbolizer: *mut CannonballSymbolizer,
+    bias: u64,
+) {
+    guard((), || {
+        if let Some(symbolizer) = symbolizer.as_mut() {
+            symbolizer.bias = bias;
+        }
+    })
+}
+
+/// The name of the function `addr` is in, as a NUL-terminated string valid until the next
+/// call on `symbolizer`, with the offset of `addr` in it stored in `offset` if it is not NULL.
+/// NULL if the address is in no known function
+///
+/// # Safety
+///
+/// `symbolizer` must be NULL, or have been returned by `cannonball_client_symbolizer_open` or
+/// `cannonball_client_symbolizer_parse` and not closed, and `offset` must be NULL or point to a
+/// `uint64_t`
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_symbolize(
+    symbolizer: *mut CannonballSymbolizer,
+    addr: u64,
+    offset: *mut u64,
+) -> *const c_char {
+    guard(null(), || {
+        let Some(symbolizer) = symbolizer.as_mut() else {
+            return null();
+        };
+
+        let Some((name, found)) = addr
+            .checked_sub(symbolizer.bias)
+            .and_then(|addr| symbolizer.symbolizer.symbolize(addr))
+        else {
+            return null();
+        };
+
+        // Symbol names come from a NUL-terminated string table, so they have none
+        symbolizer.name = CString::new(name).unwrap_or_default();
+
+        if let Some(offset) = offset.as_mut() {
+            *offset = found;
+        }
+
+        symbolizer.name.as_ptr()
+    })
+}
+
+/// Close a symbolizer, freeing it
+///
+/// # Safety
+///
+/// `symbolizer` must be NULL, or have been returned by `cannonball_client_symbolizer_open` or
+/// `cannonball_client_symbolizer_parse` and not closed
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_client_symbolizer_close(symbolizer: *mut CannonballSymbolizer) {
+    guard((), || {
+        if !symbolizer.is_null() {
+            drop(Box::from_raw(symbolizer));
+        }
+    })
+}
@@ -0,0 +1,269 @@
+//! Sending events from a thread
+//!
+//! A `Sender` writes each event to the socket from the callback that logged it, so QEMU waits
+//! on every write, and on every retry of its `OnError` policy. A `ThreadedSender` moves the
+//! `Sender` to an OS thread of its own instead. Callbacks only encode events and queue them in
+//! a bounded buffer, and the thread writes them out in order with plain blocking writes. No
+//! async runtime is involved, so plugins using it stay small and link statically, like
+//! against musl.
+//!
+//! Events are encoded into slots the thread hands back once it wrote them, so after the first
+//! few, sending an event allocates nothing and only encodes it, however many are sent. Slots
+//! go back over a bounded channel, whose room is allocated once like that of the buffer, so
+//! handing them back allocates nothing either.
+//!
+//! When the buffer is full, sending waits for the thread to catch up, so a slow consumer
+//! slows the program down rather than losing events. Errors of the thread are returned by the
+//! next `send`, and the thread stops once its `Sender` shut down as its policy says:
+//!
+//! ```no_run
+//! use cannonball_client::{
+//!     sender::{ClientError, OnError, Sender},
+//!     threaded::ThreadedSender,
+//! };
+//! use cannonball_events::{Event, InsnEvent};
+//!
+//! # fn send() -> Result<(), ClientError> {
+//! let sender = Sender::connect("/tmp/qemu.sock")?.on_error(OnError::Drop);
+//! let mut sender = ThreadedSender::spawn(sender, 4096)?;
+//!
+//! sender.send(&Event::Insn(InsnEvent::new(Some(0), 0x401000, None, false)))?;
+//!
+//! // Waits for the queued events to be written
+//! sender.shutdown()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! With the `io-uring` feature, `ThreadedSender::spawn_uring` starts a thread writing the
+//! events with io_uring instead, in batches from buffers registered with the kernel (see
+//! `uring`).
+
+use cannonball_events::{codec::encode, Event};
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    thread::{Builder, JoinHandle},
+};
+
+use crate::sender::{ClientError, Sender};
+#[cfg(feature = "io-uring")]
+use crate::uring::Ring;
+
+/// What the thread of a `ThreadedSender` shares with it
+#[derive(Debug, Default)]
+pub(crate) struct Shared {
+    /// The last error writing events that was not returned yet
+    error: Mutex<Option<ClientError>>,
+    /// The number of events dropped by the `OnError::Drop` policy
+    dropped: AtomicU64,
+    /// Whether the `Sender` shut down
+    shutdown: AtomicBool,
+}
+
+impl Shared {
+    /// Record the result of writing an event with a sender, returning whether the sender shut
+    /// down, so the thread stops
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The sender
+    /// * `result` - The result of writing the event
+    pub(crate) fn record(&self, sender: &Sender, result: Result<(), ClientError>) -> bool {
+        self.dropped.store(sender.dropped(), Ordering::Relaxed);
+
+        if let Err(e) = result {
+            if let Ok(mut error) = self.error.lock() {
+                *error = Some(e);
+            }
+        }
+
+        if sender.is_shutdown() {
+            self.shutdown.store(true, Ordering::Relaxed);
+        }
+
+        sender.is_shutdown()
+    }
+}
+
+/// Writes events to a consumer from a thread of its own
+#[derive(Debug)]
+pub struct ThreadedSender {
+    /// The buffer of encoded events, until the sender is shut down
+    events: Option<SyncSender<Vec<u8>>>,
+    /// The slots the thread is done with, to encode later events in
+    slots: Receiver<Vec<u8>>,
+    /// The thread writing the events, handing the `Sender` back when done
+    thread: Option<JoinHandle<Sender>>,
+    /// What the thread shares
+    shared: Arc<Shared>,
+}
+
+impl ThreadedSender {
+    /// Move a sender to a new thread, writing the events sent with a buffer of a number of
+    /// events. Fails if the thread cannot be spawned
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The sender, connected to the consumer
+    /// * `capacity` - The most events buffered before sending waits for the thread
+    pub fn spawn(sender: Sender, capacity: usize) -> Result<Self, ClientError> {
+        Self::spawn_with(capacity, move |events, slots, shared| {
+            write_events(sender, events, slots, shared)
+        })
+    }
+
+    /// Move a sender to a new thread writing the events sent with io_uring, in batches, with a
+    /// buffer of a number of events. Fails if io_uring is not available, like on kernels older
+    /// than 5.1 or in containers that filter it out, or if the thread cannot be spawned
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The sender, connected to the consumer
+    /// * `capacity` - The most events buffered before sending waits for the thread
+    #[cfg(feature = "io-uring")]
+    pub fn spawn_uring(sender: Sender, capacity: usize) -> Result<Self, ClientError> {
+        // Set up before spawning, so a kernel without io_uring fails here rather than on the
+        // thread
+        let ring = Ring::new()?;
+
+        Self::spawn_with(capacity, move |events, slots, shared| {
+            ring.write_events(sender, events, slots, shared)
+        })
+    }
+
+    /// Spawn the thread writing the events sent, with a buffer of a number of events
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - The most events buffered before sending waits for the thread
+    /// * `write` - Writes the events of the buffer until it is dropped or the sender shuts
+    ///   down, handing back the slots of the events it wrote, and hands the sender back
+    fn spawn_with<F>(capacity: usize, write: F) -> Result<Self, ClientError>
+    where
+        F: FnOnce(Receiver<Vec<u8>>, SyncSender<Vec<u8>>, &Shared) -> Sender + Send + 'static,
+    {
+        let (events, receiver) = sync_channel(capacity);
+        // Room for every slot in the buffer and the one being written
+        let (free, slots) = sync_channel(capacity + 1);
+        let shared = Arc::new(Shared::default());
+        let thread = Builder::new()
+            .name("cannonball-sender".to_string())
+            .spawn({
+                let shared = shared.clone();
+                move || write(receiver, free, &shared)
+            })?;
+
+        Ok(Self {
+            events: Some(events),
+            slots,
+            thread: Some(thread),
+            shared,
+        })
+    }
+
+    /// Queue an event to send. Fails if the sender is shut down, if the event cannot be
+    /// encoded, or with the last error the thread had writing earlier events
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn send(&mut self, event: &Event) -> Result<(), ClientError> {
+        if let Some(e) = self.take_error() {
+            return Err(e);
+        }
+
+        let events = self.events.as_ref().ok_or(ClientError::Shutdown)?;
+        let mut encoded = self.slots.try_recv().unwrap_or_default();
+        encoded.clear();
+        encode(event, &mut encoded)?;
+
+        // The thread only stops once the sender shut down
+        events
+            .send(encoded)
+            .map_err(|_| self.take_error().unwrap_or(ClientError::Shutdown))
+    }
+
+    /// Wait for the queued events to be written, and close the connection, so the consumer
+    /// sees the end of the events. Fails with the last error the thread had writing them.
+    /// Later events fail with `ClientError::Shutdown`
+    pub fn shutdown(&mut self) -> Result<(), ClientError> {
+        // Dropping the buffer ends the thread once it wrote what is left in it
+        self.events = None;
+
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+
+        let mut sender = thread
+            .join()
+            .map_err(|_| ClientError::Transport(io::Error::other("Sender thread panicked")))?;
+        let closed = sender.shutdown();
+
+        match self.take_error() {
+            Some(e) => Err(e),
+            None => closed,
+        }
+    }
+
+    /// Whether the sender is shut down, by `shutdown` or by the `OnError` policy of its
+    /// `Sender`
+    pub fn is_shutdown(&self) -> bool {
+        self.events.is_none() || self.shared.shutdown.load(Ordering::Relaxed)
+    }
+
+    /// The number of events dropped by the `OnError::Drop` policy so far
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// The last error the thread had writing events, if it was not returned yet
+    fn take_error(&self) -> Option<ClientError> {
+        self.shared
+            .error
+            .lock()
+            .ok()
+            .and_then(|mut error| error.take())
+    }
+}
+
+impl Drop for ThreadedSender {
+    /// Write the queued events before the plugin goes away
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+/// Write the events of a `ThreadedSender` with its `Sender` until the buffer is dropped or the
+/// sender shuts down, and hand the sender back
+///
+/// # Arguments
+///
+/// * `sender` - The sender
+/// * `events` - The buffer of encoded events
+/// * `slots` - Where the slots of the events written go back to
+/// * `shared` - What the thread shares with the `ThreadedSender`
+fn write_events(
+    mut sender: Sender,
+    events: Receiver<Vec<u8>>,
+    slots: SyncSender<Vec<u8>>,
+    shared: &Shared,
+) -> Sender {
+    for encoded in events {
+        let result = sender.send_encoded(&encoded);
+        // Only fails once the `ThreadedSender` is gone, or with more slots than room, which
+        // are freed
+        let _ = slots.try_send(encoded);
+
+        if shared.record(&sender, result) {
+            break;
+        }
+    }
+
+    sender
+}
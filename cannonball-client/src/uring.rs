@@ -0,0 +1,335 @@
+//! Writing events with io_uring
+//!
+//! The thread of a `ThreadedSender` spawned with `ThreadedSender::spawn_uring` writes events
+//! with io_uring rather than with a `send` per event. It copies the events queued for it into
+//! buffers registered with the kernel, which are mapped once rather than on every write, and
+//! submits one write per buffer rather than per event. While one buffer is written, the next
+//! one is filled with the events queued meanwhile, so with full instruction traces, whose
+//! events are a few bytes each, a system call writes thousands of them.
+//!
+//! Writes that fail hand the events that were not written to the `Sender` one at a time, so
+//! its `OnError` policy handles them like it does with the other backends: a retrying sender
+//! reconnects and the ring writes to the new connection. An event a short write left partly
+//! written is finished on the connection it was started on, so the consumer never reads its
+//! first bytes twice, and only sent whole to a new connection.
+
+use io_uring::{opcode::WriteFixed, types::Fd, IoUring};
+
+use std::{
+    io, mem,
+    ptr::null_mut,
+    sync::mpsc::{Receiver, SyncSender},
+};
+
+use crate::{sender::Sender, threaded::Shared};
+
+/// The number of registered buffers: one filled while the other is written
+const BUFFERS: usize = 2;
+
+/// The size of a registered buffer. Events larger than it are written without the ring
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Events copied to a registered buffer
+#[derive(Debug, Default)]
+struct Batch {
+    /// The number of bytes of the buffer filled
+    len: usize,
+    /// The offset each event in the buffer ends at
+    ends: Vec<usize>,
+}
+
+/// An io_uring ring with the buffers registered with it
+pub(crate) struct Ring {
+    /// The ring
+    ring: IoUring,
+    /// The buffers, whose addresses the kernel holds on to as long as the ring lives
+    buffers: Vec<Box<[u8]>>,
+}
+
+impl Ring {
+    /// Set up a ring and register its buffers. Fails if the kernel does not support io_uring
+    pub(crate) fn new() -> io::Result<Self> {
+        let ring = IoUring::new(BUFFERS as u32)?;
+        let mut buffers: Vec<Box<[u8]>> = (0..BUFFERS)
+            .map(|_| vec![0; BUFFER_SIZE].into_boxed_slice())
+            .collect();
+        let iovecs: Vec<libc::iovec> = buffers
+            .iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr().cast(),
+                iov_len: buffer.len(),
+            })
+            .collect();
+
+        // The buffers live on the heap as long as the ring, so moving it keeps them in place
+        unsafe { ring.submitter().register_buffers(&iovecs)? };
+
+        Ok(Self { ring, buffers })
+    }
+
+    /// Write the events of a `ThreadedSender` to the connection of its `Sender` until the
+    /// buffer is dropped or the sender shuts down, and hand the sender back
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The sender
+    /// * `events` - The buffer of encoded events
+    /// * `slots` - Where the slots of the events copied or written go back to
+    /// * `shared` - What the thread shares with the `ThreadedSender`
+    pub(crate) fn write_events(
+        mut self,
+        mut sender: Sender,
+        events: Receiver<Vec<u8>>,
+        slots: SyncSender<Vec<u8>>,
+        shared: &Shared,
+    ) -> Sender {
+        block_sigpipe();
+
+        let mut batches: [Batch; BUFFERS] = Default::default();
+        let mut filling = 0;
+        let mut writing: Option<usize> = None;
+        // An event that did not fit in the buffer being filled
+        let mut pending: Option<Vec<u8>> = None;
+
+        loop {
+            // Wait for events only with nothing else to do, and take the ones queued otherwise
+            if pending.is_none() && writing.is_none() && batches[filling].len == 0 {
+                match events.recv() {
+                    Ok(encoded) => pending = Some(encoded),
+                    Err(_) => break,
+                }
+            }
+
+            loop {
+                if let Some(encoded) = pending.take() {
+                    if !self.push(&mut batches[filling], filling, &encoded) {
+                        pending = Some(encoded);
+                        break;
+                    }
+
+                    // Only fails once the `ThreadedSender` is gone, or with more slots than
+                    // room, which are freed
+                    let _ = slots.try_send(encoded);
+                }
+
+                // Once the buffer is dropped, waiting for events above ends the thread
+                match events.try_recv() {
+                    Ok(encoded) => pending = Some(encoded),
+                    Err(_) => break,
+                }
+            }
+
+            if let Some(index) = writing.take() {
+                if self.finish(&mut sender, &mut batches[index], index, shared) {
+                    return sender;
+                }
+            }
+
+            if batches[filling].len > 0 {
+                match self.submit(&sender, &batches[filling], filling, 0) {
+                    Ok(()) => {
+                        writing = Some(filling);
+                        filling = (filling + 1) % BUFFERS;
+                    }
+                    Err(_) => {
+                        if self.failed(&mut sender, &mut batches[filling], filling, 0, shared) {
+                            return sender;
+                        }
+                    }
+                }
+            } else if let Some(encoded) = pending.take() {
+                // Too large for a buffer, and everything before it is written
+                let result = sender.send_encoded(&encoded);
+                let _ = slots.try_send(encoded);
+
+                if shared.record(&sender, result) {
+                    return sender;
+                }
+            }
+        }
+
+        sender
+    }
+
+    /// Copy an event to a buffer, returning whether it fit
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - The events in the buffer
+    /// * `index` - The index of the buffer
+    /// * `encoded` - The encoding of the event
+    fn push(&mut self, batch: &mut Batch, index: usize, encoded: &[u8]) -> bool {
+        let end = batch.len + encoded.len();
+
+        if end > BUFFER_SIZE {
+            return false;
+        }
+
+        self.buffers[index][batch.len..end].copy_from_slice(encoded);
+        batch.len = end;
+        batch.ends.push(end);
+        true
+    }
+
+    /// Submit a write of the rest of a buffer to the connection of a sender
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The sender
+    /// * `batch` - The events in the buffer
+    /// * `index` - The index of the buffer
+    /// * `written` - The number of bytes of the buffer written already
+    fn submit(
+        &mut self,
+        sender: &Sender,
+        batch: &Batch,
+        index: usize,
+        written: usize,
+    ) -> io::Result<()> {
+        let fd = sender
+            .as_raw_fd()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        let write = WriteFixed::new(
+            Fd(fd),
+            self.buffers[index][written..].as_ptr(),
+            (batch.len - written) as u32,
+            index as u16,
+        )
+        .build()
+        .user_data(index as u64);
+
+        // The buffer is not touched until the write completes, in `finish`
+        unsafe {
+            self.ring
+                .submission()
+                .push(&write)
+                .map_err(|_| io::Error::other("The submission queue is full"))?;
+        }
+        self.ring.submit()?;
+
+        Ok(())
+    }
+
+    /// Wait for the write of a buffer to complete, writing what a short write left, and empty
+    /// the buffer. Returns whether the sender shut down
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The sender
+    /// * `batch` - The events in the buffer
+    /// * `index` - The index of the buffer
+    /// * `shared` - What the thread shares with the `ThreadedSender`
+    fn finish(
+        &mut self,
+        sender: &mut Sender,
+        batch: &mut Batch,
+        index: usize,
+        shared: &Shared,
+    ) -> bool {
+        let mut written = 0;
+
+        loop {
+            let result = match self.complete() {
+                Ok(sent) => {
+                    written += sent;
+                    Ok(())
+                }
+                // Nothing was written, so the rest is submitted again
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+                Err(e) => Err(e),
+            }
+            .and_then(|()| {
+                if written < batch.len {
+                    self.submit(sender, batch, index, written)?;
+                }
+
+                Ok(())
+            });
+
+            if result.is_err() {
+                return self.failed(sender, batch, index, written, shared);
+            }
+
+            if written == batch.len {
+                break;
+            }
+        }
+
+        *batch = Batch::default();
+        false
+    }
+
+    /// Wait for the write in flight to complete, returning the number of bytes it wrote
+    fn complete(&mut self) -> io::Result<usize> {
+        loop {
+            if let Some(completion) = self.ring.completion().next() {
+                let result = completion.result();
+
+                return match result {
+                    sent if sent > 0 => Ok(sent as usize),
+                    0 => Err(io::Error::from(io::ErrorKind::WriteZero)),
+                    e if -e == libc::EINTR => Err(io::Error::from(io::ErrorKind::Interrupted)),
+                    e => Err(io::Error::from_raw_os_error(-e)),
+                };
+            }
+
+            match self.ring.submit_and_wait(1) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => result?,
+            };
+        }
+    }
+
+    /// Hand the events of a buffer that were not written to the sender one at a time, after
+    /// writing it failed, so the sender fails to write them too and its `OnError` policy
+    /// handles the error, and empty the buffer. Returns whether the sender shut down
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The sender
+    /// * `batch` - The events in the buffer
+    /// * `index` - The index of the buffer
+    /// * `written` - The number of bytes of the buffer written
+    /// * `shared` - What the thread shares with the `ThreadedSender`
+    fn failed(
+        &mut self,
+        sender: &mut Sender,
+        batch: &mut Batch,
+        index: usize,
+        written: usize,
+        shared: &Shared,
+    ) -> bool {
+        let batch = mem::take(batch);
+        let mut start = 0;
+
+        for end in batch.ends {
+            // Only the rest of an event partly written is sent, after the bytes the consumer
+            // read already
+            if end > written {
+                let result = sender.send_rest(
+                    &self.buffers[index][start..end],
+                    written.saturating_sub(start),
+                );
+
+                if shared.record(sender, result) {
+                    return true;
+                }
+            }
+
+            start = end;
+        }
+
+        false
+    }
+}
+
+/// Block `SIGPIPE` on the calling thread, so writes the ring makes on it to a consumer that
+/// went away fail rather than kill QEMU. Writes cannot be given `MSG_NOSIGNAL` like `send`
+fn block_sigpipe() {
+    unsafe {
+        let mut set = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGPIPE);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, null_mut());
+    }
+}
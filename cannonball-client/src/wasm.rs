@@ -0,0 +1,135 @@
+//! WebAssembly helpers
+//!
+//! Built for `wasm32-unknown-unknown` without the `transport` feature, the library decodes
+//! traces in a browser, so a trace viewer needs no server:
+//!
+//! ```text
+//! $ cargo build -p cannonball-client --target wasm32-unknown-unknown --release \
+//!     --no-default-features --features wasm
+//! ```
+//!
+//! The module exports the same functions as the C library, which take pointers into its
+//! memory. A host copies a trace file into memory it got from `cannonball_wasm_alloc`, opens a
+//! reader on it with `cannonball_client_reader_bytes`, and reads each event into a
+//! `CannonballEvent` of `cannonball_wasm_event_size` bytes with
+//! `cannonball_client_reader_next`, whose `json` field points to the whole event:
+//!
+//! ```text
+//! const { memory, cannonball_wasm_alloc, cannonball_client_reader_bytes } = instance.exports;
+//! const data = new Uint8Array(await file.arrayBuffer());
+//! const ptr = cannonball_wasm_alloc(data.length);
+//! new Uint8Array(memory.buffer, ptr, data.length).set(data);
+//! const reader = cannonball_client_reader_bytes(ptr, data.length, 3);
+//! ```
+//!
+//! The analyzers of `cannonball_tools::analyze` run over a trace in memory with
+//! `cannonball_wasm_analyze`, and addresses are named with `cannonball_client_symbolizer_parse`
+//! on the program in memory.
+
+use cannonball_tools::{
+    analyze::{Analyzers, Registry},
+    trace::TraceReader,
+};
+use serde_json::json;
+
+use std::{
+    ffi::{c_char, CStr, CString},
+    io::Cursor,
+    mem::size_of,
+    ptr::{null_mut, slice_from_raw_parts_mut},
+    slice,
+};
+
+use crate::{output_format, CannonballEvent};
+
+/// Allocate `len` bytes for the host to write to, like a trace. Returns NULL if `len` is 0
+#[no_mangle]
+pub extern "C" fn cannonball_wasm_alloc(len: usize) -> *mut u8 {
+    if len == 0 {
+        return null_mut();
+    }
+
+    Box::into_raw(vec![0u8; len].into_boxed_slice()) as *mut u8
+}
+
+/// Free bytes allocated with `cannonball_wasm_alloc`
+///
+/// # Safety
+///
+/// `ptr` must be NULL, or have been returned by `cannonball_wasm_alloc` with the same `len`
+/// and not freed
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_wasm_free(ptr: *mut u8, len: usize) {
+    if !ptr.is_null() {
+        drop(Box::from_raw(slice_from_raw_parts_mut(ptr, len)));
+    }
+}
+
+/// The size of a `CannonballEvent`, to allocate one for `cannonball_client_reader_next`
+#[no_mangle]
+pub extern "C" fn cannonball_wasm_event_size() -> usize {
+    size_of::<CannonballEvent>()
+}
+
+/// Run an analyzer over a trace in memory, written in `format`, and return its report as a
+/// NUL-terminated JSON object, with the text of the report in `text` and its findings in
+/// `data`, or why it failed in `error`. The string is freed with
+/// `cannonball_wasm_string_free`
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes, and `analyzer` must be a NUL-terminated string
+/// naming an analyzer, like `cover`
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_wasm_analyze(
+    data: *const u8,
+    len: usize,
+    format: u32,
+    analyzer: *const c_char,
+) -> *mut c_char {
+    let report = (|| {
+        let format = output_format(format).ok_or("Invalid format")?;
+
+        if data.is_null() || analyzer.is_null() {
+            return Err("Invalid argument".to_string());
+        }
+
+        let spec = CStr::from_ptr(analyzer).to_string_lossy();
+        let mut analyzers = Analyzers::new();
+        analyzers.add(
+            spec.to_string(),
+            Registry::default()
+                .create(&spec)
+                .map_err(|e| e.to_string())?,
+        );
+
+        let data = slice::from_raw_parts(data, len).to_vec();
+
+        for event in
+            TraceReader::decompress(Cursor::new(data), format).map_err(|e| e.to_string())?
+        {
+            analyzers.on_event(&event.map_err(|e| e.to_string())?);
+        }
+
+        let (_, report) = analyzers.finish().pop().ok_or("No report")?;
+        Ok(json!({"text": report.text, "data": report.data}))
+    })()
+    .unwrap_or_else(|error: String| json!({ "error": error }));
+
+    // JSON strings escape NUL, so the report has none
+    CString::new(report.to_string())
+        .unwrap_or_default()
+        .into_raw()
+}
+
+/// Free a string returned by `cannonball_wasm_analyze`
+///
+/// # Safety
+///
+/// `string` must be NULL, or have been returned by `cannonball_wasm_analyze` and not freed
+#[no_mangle]
+pub unsafe extern "C" fn cannonball_wasm_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
@@ -0,0 +1,137 @@
+//! Encoding events
+//!
+//! Events are written as a sequence of CBOR items, one per event, with nothing between them,
+//! which is how the plugin writes them to its socket. `encode` and `decode` work on buffers
+//! and only need an allocator. With the `std` feature, `write_event` and `read_events` work on
+//! readers and writers, and with the `tokio` feature, `EventCodec` frames events on async
+//! streams:
+//!
+//! ```ignore
+//! use cannonball_events::codec::EventCodec;
+//! use tokio_util::codec::FramedRead;
+//!
+//! let (stream, _) = listener.accept().await?;
+//! let mut events = FramedRead::new(stream, EventCodec);
+//!
+//! while let Some(event) = events.next().await {
+//!     println!("{:?}", event?);
+//! }
+//! ```
+//!
+//! The encoding is the same whatever the byte order of the host or of the target, so a
+//! consumer on any machine decodes the events of a big endian guest like s390x: integers are
+//! CBOR integers, which are big endian, and memory values wider than 64 bits are their little
+//! endian bytes. Only opcodes are in the byte order of the target, as bytes copied from the
+//! guest, and `MemEvent::is_be` tells which byte order a memory access used.
+
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+use serde_cbor::{Deserializer, Serializer};
+
+pub use serde_cbor::Error;
+
+use crate::Event;
+
+/// Append the encoding of an event to a buffer
+///
+/// # Arguments
+///
+/// * `event` - The event
+/// * `buf` - The buffer
+pub fn encode(event: &Event, buf: &mut Vec<u8>) -> Result<(), Error> {
+    event.serialize(&mut Serializer::new(buf))
+}
+
+/// Decode the first event of a buffer. Returns the event and the number of bytes it was
+/// encoded in, or `None` if the buffer only holds the start of an event
+///
+/// # Arguments
+///
+/// * `buf` - The buffer
+pub fn decode(buf: &[u8]) -> Result<Option<(Event, usize)>, Error> {
+    let mut deserializer = Deserializer::from_slice(buf);
+
+    match Event::deserialize(&mut deserializer) {
+        Ok(event) => Ok(Some((event, deserializer.byte_offset()))),
+        Err(e) if e.is_eof() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Write an event
+///
+/// # Arguments
+///
+/// * `writer` - Where to write the event
+/// * `event` - The event
+#[cfg(feature = "std")]
+pub fn write_event(writer: impl std::io::Write, event: &Event) -> std::io::Result<()> {
+    serde_cbor::to_writer(writer, event).map_err(invalid_data)
+}
+
+/// The events of a reader, until its end
+///
+/// # Arguments
+///
+/// * `reader` - Where to read the events from
+#[cfg(feature = "std")]
+pub fn read_events(reader: impl std::io::Read) -> impl Iterator<Item = std::io::Result<Event>> {
+    Deserializer::from_reader(reader)
+        .into_iter::<Event>()
+        .map(|event| event.map_err(invalid_data))
+}
+
+/// Convert an error encoding or decoding an event to an IO error
+///
+/// # Arguments
+///
+/// * `e` - The error
+#[cfg(feature = "std")]
+fn invalid_data(e: Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(feature = "tokio")]
+pub use self::framed::EventCodec;
+
+#[cfg(feature = "tokio")]
+mod framed {
+    use bytes::{Buf, BytesMut};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use alloc::vec::Vec;
+    use std::io;
+
+    use super::{decode, encode, invalid_data};
+    use crate::Event;
+
+    /// Frames events on async streams, with `tokio_util::codec::FramedRead` and `FramedWrite`
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct EventCodec;
+
+    impl Decoder for EventCodec {
+        type Item = Event;
+        type Error = io::Error;
+
+        fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Event>> {
+            match decode(src).map_err(invalid_data)? {
+                Some((event, len)) => {
+                    src.advance(len);
+                    Ok(Some(event))
+                }
+                None => Ok(None),
+            }
+        }
+    }
+
+    impl Encoder<Event> for EventCodec {
+        type Error = io::Error;
+
+        fn encode(&mut self, event: Event, dst: &mut BytesMut) -> io::Result<()> {
+            let mut buf = Vec::new();
+            encode(&event, &mut buf).map_err(invalid_data)?;
+            dst.extend_from_slice(&buf);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Random events
+//!
+//! Every event type can be sampled from `rand`'s `Standard` distribution, to test and
+//! benchmark consumers and the codec with plausible events rather than by running a program:
+//!
+//! ```
+//! use cannonball_events::{codec::encode, Event};
+//! use rand::{rngs::StdRng, Rng, SeedableRng};
+//!
+//! let mut rng = StdRng::seed_from_u64(0);
+//! let mut buf = Vec::new();
+//!
+//! for _ in 0..1000 {
+//!     encode(&rng.gen::<Event>(), &mut buf).unwrap();
+//! }
+//! ```
+//!
+//! Values are only plausible, not consistent with each other: memory accesses are not made by
+//! instructions that access memory, and system call numbers are not those of any particular
+//! target.
+
+use alloc::vec::Vec;
+use rand::{
+    distributions::{Distribution, Standard},
+    Rng,
+};
+
+use crate::{Event, InsnEvent, MemEvent, SysMemEvent, SyscallEvent};
+
+/// The most VCPUs events are sampled from
+const MAX_VCPUS: u32 = 8;
+/// The longest opcode sampled, that of the longest x86 instruction
+const MAX_OPCODE: usize = 15;
+/// The largest system call number sampled
+const MAX_SYSCALL: i64 = 450;
+/// The number of arguments sampled for each system call
+const SYSCALL_ARGS: usize = 6;
+
+impl Distribution<InsnEvent> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> InsnEvent {
+        let vcpu_idx = rng.gen_bool(0.9).then(|| rng.gen_range(0..MAX_VCPUS));
+        let opcode = rng.gen::<bool>().then(|| {
+            let len = rng.gen_range(1..=MAX_OPCODE);
+            (0..len).map(|_| rng.gen()).collect()
+        });
+
+        InsnEvent::new(vcpu_idx, rng.gen(), opcode, rng.gen_bool(0.2))
+    }
+}
+
+impl Distribution<MemEvent> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> MemEvent {
+        let size_shift = rng.gen_range(0..=4);
+        // Values are at most 16 bytes, the largest access
+        let value = rng
+            .gen::<bool>()
+            .then(|| rng.gen::<u128>() >> (128 - (8 << size_shift)));
+
+        MemEvent::new(
+            rng.gen(),
+            rng.gen(),
+            rng.gen(),
+            rng.gen(),
+            size_shift,
+            value,
+            rng.gen(),
+        )
+    }
+}
+
+impl Distribution<SysMemEvent> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> SysMemEvent {
+        SysMemEvent::new(rng.gen(), rng.gen_bool(0.1), rng.gen())
+    }
+}
+
+impl Distribution<SyscallEvent> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> SyscallEvent {
+        let args: Vec<u64> = (0..SYSCALL_ARGS).map(|_| rng.gen()).collect();
+        let rv = rng.gen::<bool>().then(|| rng.gen());
+
+        SyscallEvent::new(rng.gen_range(0..=MAX_SYSCALL), rv, args)
+    }
+}
+
+impl Distribution<Event> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Event {
+        match rng.gen_range(0..4) {
+            0 => Event::Insn(rng.gen()),
+            1 => Event::Mem(rng.gen()),
+            2 => Event::SysMem(rng.gen()),
+            _ => Event::Syscall(rng.gen()),
+        }
+    }
+}
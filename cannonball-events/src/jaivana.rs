@@ -0,0 +1,839 @@
+//! Jaivana events
+//!
+//! The events the Jaivana plugin logs, as JSON lines on stdout or as framed CBOR items on a
+//! sink. Events are not tagged with their type, so consumers tell them apart by their fields.
+//!
+//! ```
+//! use cannonball_events::jaivana::SyscallEvent;
+//!
+//! let event = SyscallEvent::new(60, None, vec![0]);
+//! let item = serde_cbor::to_vec(&event).unwrap();
+//! assert_eq!(serde_cbor::from_slice::<SyscallEvent>(&item).unwrap(), event);
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    env::{args, vars_os},
+    format,
+    string::String,
+    time::Duration,
+    vec::Vec,
+};
+
+/// How much every buffer of the plugin has written out so far
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Written {
+    /// The number of events
+    pub events: u64,
+    /// The number of bytes
+    pub bytes: u64,
+    /// The time spent writing them, in nanoseconds
+    pub write_nanos: u64,
+    /// The most events any buffer held when it was flushed
+    pub high_water: u64,
+    /// The number of events discarded without being written out
+    pub discarded: u64,
+}
+
+/// A trigger opening or closing the window when the instruction at its PC executes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Trigger {
+    /// Opens the window
+    Start,
+    /// Closes the window
+    Stop,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Invocation {
+    pub command_line: Vec<String>,
+    pub environment: Option<Vec<String>>,
+}
+
+impl Invocation {
+    /// Capture how QEMU was invoked. In user mode, the command line ends with the command line
+    /// of the guest program, and the guest inherits the environment of QEMU
+    ///
+    /// # Arguments
+    ///
+    /// * `system_emulation` - Whether QEMU is emulating a whole system, in which case the
+    ///   environment is not captured because it has nothing to do with the guest
+    pub fn current(system_emulation: bool) -> Self {
+        Self {
+            command_line: args().collect(),
+            environment: if system_emulation {
+                None
+            } else {
+                Some(
+                    vars_os()
+                        .map(|(key, value)| {
+                            format!("{}={}", key.to_string_lossy(), value.to_string_lossy())
+                        })
+                        .collect(),
+                )
+            },
+        }
+    }
+}
+
+/// The byte order of the target, which register values and memory read from the guest are in
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HeaderEvent {
+    pub target_name: Option<String>,
+    /// Missing from traces logged before it was added, which were all of little endian targets
+    #[serde(default)]
+    pub endianness: Option<Endianness>,
+    /// The width of the addresses of the target in bits, 32 or 64. System call arguments of
+    /// 32-bit targets are sign extended, so consumers truncate the ones that are addresses
+    #[serde(default)]
+    pub address_bits: Option<u32>,
+    pub api_version: Option<i32>,
+    pub min_api_version: Option<i32>,
+    pub plugin_api_version: i32,
+    pub system_emulation: Option<bool>,
+    pub sample_rate: u64,
+    pub plugin_args: Vec<String>,
+    /// The only system calls logged, by name, if not all of them
+    #[serde(default)]
+    pub syscall_filter: Option<Vec<String>>,
+    /// The system calls never logged, by name
+    #[serde(default)]
+    pub syscall_exclude: Vec<String>,
+    #[serde(flatten)]
+    pub invocation: Invocation,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ForkEvent {
+    pub vcpu_idx: Option<u32>,
+    pub parent: u32,
+    pub child: u32,
+}
+
+impl ForkEvent {
+    /// Instantiate a new `ForkEvent`, logged by the child of a fork before any of its other
+    /// events
+    ///
+    /// # Arguments
+    ///
+    /// * `parent` - The PID of the process that forked
+    /// * `child` - The PID of the new process
+    pub fn new(vcpu_idx: Option<u32>, parent: u32, child: u32) -> Self {
+        Self {
+            vcpu_idx,
+            parent,
+            child,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ExecEvent {
+    pub vcpu_idx: Option<u32>,
+    pub num: i64,
+    pub pathname: u64,
+    pub argv: u64,
+}
+
+impl ExecEvent {
+    /// Instantiate a new `ExecEvent`, logged when the process is about to replace its image
+    ///
+    /// # Arguments
+    ///
+    /// * `num` - The number of the system call (`execve` or `execveat`)
+    /// * `pathname` - The guest virtual address of the path of the new image
+    /// * `argv` - The guest virtual address of the argument vector of the new image
+    pub fn new(vcpu_idx: Option<u32>, num: i64, pathname: u64, argv: u64) -> Self {
+        Self {
+            vcpu_idx,
+            num,
+            pathname,
+            argv,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RunEndEvent {
+    pub run: u64,
+    pub child: u32,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+impl RunEndEvent {
+    /// Instantiate a new `RunEndEvent`, logged by the fork server once a run has exited
+    ///
+    /// # Arguments
+    ///
+    /// * `run` - The number of the run, counting from 1
+    /// * `child` - The PID of the process that ran
+    /// * `exit_code` - The exit code of the run, if it exited normally
+    /// * `signal` - The signal that killed the run, if any
+    pub fn new(run: u64, child: u32, exit_code: Option<i32>, signal: Option<i32>) -> Self {
+        Self {
+            run,
+            child,
+            exit_code,
+            signal,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct StatsEvent {
+    pub elapsed_ms: u64,
+    pub events: u64,
+    pub bytes: u64,
+    pub events_per_sec: f64,
+    pub write_ms: u64,
+    pub high_water: u64,
+    pub discarded: u64,
+    pub kinds: HashMap<String, u64>,
+    pub insn_callbacks: u64,
+}
+
+impl StatsEvent {
+    /// Instantiate a new `StatsEvent` reporting on the trace so far
+    ///
+    /// # Arguments
+    ///
+    /// * `elapsed` - The time since the plugin was set up
+    /// * `written` - How much has been written out so far
+    /// * `kinds` - The number of events logged so far by type
+    /// * `insn_callbacks` - The number of instruction callbacks registered so far to log
+    ///   instruction events
+    pub fn new(
+        elapsed: Duration,
+        written: Written,
+        kinds: HashMap<String, u64>,
+        insn_callbacks: u64,
+    ) -> Self {
+        Self {
+            elapsed_ms: elapsed.as_millis() as u64,
+            events: written.events,
+            bytes: written.bytes,
+            events_per_sec: written.events as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+            write_ms: written.write_nanos / 1_000_000,
+            high_water: written.high_water,
+            discarded: written.discarded,
+            kinds,
+            insn_callbacks,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct InsnMixEvent {
+    pub insns: u64,
+    pub classes: BTreeMap<String, u64>,
+}
+
+impl InsnMixEvent {
+    /// Instantiate a new `InsnMixEvent` reporting the instructions executed so far by class
+    ///
+    /// # Arguments
+    ///
+    /// * `classes` - The number of instructions executed in each class
+    pub fn new(classes: BTreeMap<String, u64>) -> Self {
+        Self {
+            insns: classes.values().sum(),
+            classes,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MapEvent {
+    pub vcpu_idx: Option<u32>,
+    pub addr: u64,
+    pub len: u64,
+    pub prot: u64,
+    pub flags: u64,
+    pub fd: i32,
+    pub offset: u64,
+}
+
+impl MapEvent {
+    /// Instantiate a new `MapEvent`, marking memory the guest mapped with `mmap`
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address the memory was mapped at
+    /// * `len` - The length of the mapping in bytes, rounded up to a page
+    /// * `prot` - The protection of the mapping, as the `PROT_*` bits
+    /// * `flags` - The `MAP_*` flags of the mapping
+    /// * `fd` - The file mapped, or -1 for anonymous memory
+    /// * `offset` - The offset in the file mapped, in bytes
+    pub fn new(
+        vcpu_idx: Option<u32>,
+        addr: u64,
+        len: u64,
+        prot: u64,
+        flags: u64,
+        fd: i32,
+        offset: u64,
+    ) -> Self {
+        Self {
+            vcpu_idx,
+            addr,
+            len,
+            prot,
+            flags,
+            fd,
+            offset,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UnmapEvent {
+    pub vcpu_idx: Option<u32>,
+    pub addr: u64,
+    pub len: u64,
+}
+
+impl UnmapEvent {
+    /// Instantiate a new `UnmapEvent`, marking memory the guest unmapped with `munmap`
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The first address unmapped
+    /// * `len` - The length of the memory unmapped in bytes, rounded up to a page
+    pub fn new(vcpu_idx: Option<u32>, addr: u64, len: u64) -> Self {
+        Self {
+            vcpu_idx,
+            addr,
+            len,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ProtectEvent {
+    pub vcpu_idx: Option<u32>,
+    pub addr: u64,
+    pub len: u64,
+    pub prot: u64,
+}
+
+impl ProtectEvent {
+    /// Instantiate a new `ProtectEvent`, marking memory the guest changed the protection of
+    /// with `mprotect`
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The first address changed
+    /// * `len` - The length of the memory changed in bytes, rounded up to a page
+    /// * `prot` - The new protection, as the `PROT_*` bits
+    pub fn new(vcpu_idx: Option<u32>, addr: u64, len: u64, prot: u64) -> Self {
+        Self {
+            vcpu_idx,
+            addr,
+            len,
+            prot,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FinalEvent {
+    pub exit_code: Option<i32>,
+    pub signaled: bool,
+    pub signal: Option<i32>,
+    pub signal_name: Option<String>,
+    pub insns: u64,
+    pub events: u64,
+    pub kinds: HashMap<String, u64>,
+}
+
+impl FinalEvent {
+    /// Instantiate a new `FinalEvent`, logged last when QEMU exits, telling how the guest
+    /// exited and how much it executed and logged
+    ///
+    /// # Arguments
+    ///
+    /// * `exit_code` - The exit code of the guest, if it exited
+    /// * `signaled` - Whether the guest was killed by a signal, even if which is not known
+    /// * `signal` - The signal that killed the guest, if it is known
+    /// * `signal_name` - The name of that signal, like `SIGSEGV`, if it is a standard signal
+    /// * `insns` - The number of instructions the guest executed
+    /// * `events` - The number of events logged before this one
+    /// * `kinds` - The number of events logged before this one by type
+    pub fn new(
+        exit_code: Option<i32>,
+        signaled: bool,
+        signal: Option<i32>,
+        signal_name: Option<String>,
+        insns: u64,
+        events: u64,
+        kinds: HashMap<String, u64>,
+    ) -> Self {
+        Self {
+            exit_code,
+            signaled,
+            signal,
+            signal_name,
+            insns,
+            events,
+            kinds,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TriggerEvent {
+    pub vcpu_idx: Option<u32>,
+    pub pc: u64,
+    pub trigger: Trigger,
+}
+
+impl TriggerEvent {
+    /// Instantiate a new `TriggerEvent`, marking where tracing started or stopped
+    ///
+    /// # Arguments
+    ///
+    /// * `pc` - The virtual address of the trigger instruction
+    /// * `trigger` - Whether the trigger started or stopped tracing
+    pub fn new(vcpu_idx: Option<u32>, pc: u64, trigger: Trigger) -> Self {
+        Self {
+            vcpu_idx,
+            pc,
+            trigger,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BreakpointEvent {
+    pub vcpu_idx: Option<u32>,
+    pub pc: u64,
+    pub paused: bool,
+}
+
+impl BreakpointEvent {
+    /// Instantiate a new `BreakpointEvent`, marking where a breakpoint was hit
+    ///
+    /// # Arguments
+    ///
+    /// * `pc` - The virtual address of the breakpoint
+    /// * `paused` - Whether the VCPU is held until the driver continues it
+    pub fn new(vcpu_idx: Option<u32>, pc: u64, paused: bool) -> Self {
+        Self {
+            vcpu_idx,
+            pc,
+            paused,
+        }
+    }
+}
+
+/// A change in the lifecycle or power state of a VCPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VcpuState {
+    /// The VCPU was created. In user mode, this also happens for each new guest thread
+    Init,
+    /// The VCPU was destroyed, like when its guest thread exits
+    Exit,
+    /// The VCPU went idle, waiting for an interrupt. Only in system mode
+    Idle,
+    /// The VCPU resumed from idle. Only in system mode
+    Resume,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct VcpuEvent {
+    pub vcpu_idx: Option<u32>,
+    pub vcpu: VcpuState,
+}
+
+impl VcpuEvent {
+    /// Instantiate a new `VcpuEvent`, marking a change in the lifecycle or power state of a VCPU
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu` - The new state of the VCPU
+    pub fn new(vcpu_idx: Option<u32>, vcpu: VcpuState) -> Self {
+        Self { vcpu_idx, vcpu }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SignalEvent {
+    pub vcpu_idx: Option<u32>,
+    pub signal: Option<i32>,
+    pub name: Option<String>,
+    pub pc: Option<u64>,
+    pub handler: Option<u64>,
+    pub fatal: bool,
+}
+
+impl SignalEvent {
+    /// Instantiate a new `SignalEvent`, marking the delivery of a signal to the guest, either
+    /// to one of its handlers or fatally
+    ///
+    /// # Arguments
+    ///
+    /// * `signal` - The signal number, if it is known
+    /// * `name` - The name of the signal, like `SIGSEGV`, if it is a standard signal
+    /// * `pc` - The virtual address of the last instruction executed before the delivery, which
+    ///   for a signal raised by a fault is the faulting instruction, if it is known
+    /// * `handler` - The virtual address of the handler the signal was delivered to, if any
+    /// * `fatal` - Whether the signal killed the guest
+    pub fn new(
+        vcpu_idx: Option<u32>,
+        signal: Option<i32>,
+        name: Option<String>,
+        pc: Option<u64>,
+        handler: Option<u64>,
+        fatal: bool,
+    ) -> Self {
+        Self {
+            vcpu_idx,
+            signal,
+            name,
+            pc,
+            handler,
+            fatal,
+        }
+    }
+}
+
+/// The ways a VCPU can leave the code it was executing other than by a branch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Discon {
+    /// An asynchronous interrupt, like a timer or device interrupt
+    Interrupt,
+    /// A synchronous exception, like a page fault or a system call trap
+    Exception,
+    /// A call handled by the host, like semihosting
+    Hostcall,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DisconEvent {
+    pub vcpu_idx: Option<u32>,
+    pub discon: Discon,
+    pub from_pc: u64,
+    pub to_pc: u64,
+}
+
+impl DisconEvent {
+    /// Instantiate a new `DisconEvent`, marking where a VCPU took an interrupt or exception
+    ///
+    /// # Arguments
+    ///
+    /// * `discon` - How the VCPU left the code it was executing
+    /// * `from_pc` - The virtual address of the instruction it happened at
+    /// * `to_pc` - The virtual address execution continues at, like an interrupt handler
+    pub fn new(vcpu_idx: Option<u32>, discon: Discon, from_pc: u64, to_pc: u64) -> Self {
+        Self {
+            vcpu_idx,
+            discon,
+            from_pc,
+            to_pc,
+        }
+    }
+}
+
+/// The instruction set an instruction was translated in, on targets with several
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IsaMode {
+    Arm,
+    Thumb,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct InsnEvent {
+    pub vcpu_idx: Option<u32>,
+    pub vaddr: u64,
+    pub opcode: Option<Vec<u8>>,
+    pub branch: bool,
+    /// Only logged on targets with several instruction sets, where the opcode cannot be
+    /// disassembled without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub isa_mode: Option<IsaMode>,
+}
+
+impl InsnEvent {
+    /// Instantiate a new `InsnEvent` from the raw arguments passed to the plugin
+    ///
+    /// # Arguments
+    ///
+    /// * `vaddr` - The virtual address of the instruction
+    /// * `opcode` - The opcode of the instruction, optional
+    /// * `branch` - Whether or not the instruction is a branch (in this case, `branch`
+    ///   is a bit of a misnomer -- it actually just means "last insn in the basic
+    ///   block" not exclusively *conditional* branches)
+    pub fn new(vcpu_idx: Option<u32>, vaddr: u64, opcode: Option<Vec<u8>>, branch: bool) -> Self {
+        Self {
+            vcpu_idx,
+            vaddr,
+            opcode,
+            branch,
+            isa_mode: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TBEvent {
+    pub vcpu_idx: Option<u32>,
+    pub vaddr: u64,
+    pub size: usize,
+    pub n_insns: usize,
+}
+
+impl TBEvent {
+    /// Instantiate a new `TBEvent` for the execution of a translation block
+    ///
+    /// # Arguments
+    ///
+    /// * `vaddr` - The virtual address of the first instruction of the translation block
+    /// * `size` - The size of the translation block in bytes
+    /// * `n_insns` - The number of instructions in the translation block
+    pub fn new(vcpu_idx: Option<u32>, vaddr: u64, size: usize, n_insns: usize) -> Self {
+        Self {
+            vcpu_idx,
+            vaddr,
+            size,
+            n_insns,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BlockHitsEvent {
+    pub vaddr: u64,
+    pub size: usize,
+    pub n_insns: usize,
+    pub hits: u64,
+}
+
+impl BlockHitsEvent {
+    /// Instantiate a new `BlockHitsEvent` summarizing the executions of a translation block
+    ///
+    /// # Arguments
+    ///
+    /// * `vaddr` - The virtual address of the first instruction of the translation block
+    /// * `size` - The size of the translation block in bytes
+    /// * `n_insns` - The number of instructions in the translation block
+    /// * `hits` - The number of times the translation block was executed, on every VCPU
+    pub fn new(vaddr: u64, size: usize, n_insns: usize, hits: u64) -> Self {
+        Self {
+            vaddr,
+            size,
+            n_insns,
+            hits,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EdgeEvent {
+    pub vcpu_idx: u32,
+    pub src: u64,
+    pub dst: u64,
+}
+
+impl EdgeEvent {
+    /// Instantiate a new `EdgeEvent` for a transfer of control between translation blocks
+    ///
+    /// # Arguments
+    ///
+    /// * `src` - The virtual address of the last instruction of the previous translation block
+    /// * `dst` - The virtual address of the first instruction of the next translation block
+    pub fn new(vcpu_idx: u32, src: u64, dst: u64) -> Self {
+        Self { vcpu_idx, src, dst }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CallEvent {
+    pub vcpu_idx: u32,
+    pub callsite: u64,
+    pub target: u64,
+    pub depth: i64,
+}
+
+impl CallEvent {
+    /// Instantiate a new `CallEvent`
+    ///
+    /// # Arguments
+    ///
+    /// * `callsite` - The virtual address of the call instruction
+    /// * `target` - The virtual address the call transferred control to
+    /// * `depth` - The call stack depth after the call, relative to the start of the trace
+    pub fn new(vcpu_idx: u32, callsite: u64, target: u64, depth: i64) -> Self {
+        Self {
+            vcpu_idx,
+            callsite,
+            target,
+            depth,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ReturnEvent {
+    pub vcpu_idx: u32,
+    pub site: u64,
+    pub target: u64,
+    pub depth: i64,
+}
+
+impl ReturnEvent {
+    /// Instantiate a new `ReturnEvent`
+    ///
+    /// # Arguments
+    ///
+    /// * `site` - The virtual address of the return instruction
+    /// * `target` - The virtual address the return transferred control to
+    /// * `depth` - The call stack depth after the return, relative to the start of the trace.
+    ///   This is negative when returning from functions entered before the trace started
+    pub fn new(vcpu_idx: u32, site: u64, target: u64, depth: i64) -> Self {
+        Self {
+            vcpu_idx,
+            site,
+            target,
+            depth,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FunctionEnterEvent {
+    pub vcpu_idx: Option<u32>,
+    pub function: String,
+    pub entry: u64,
+}
+
+impl FunctionEnterEvent {
+    /// Instantiate a new `FunctionEnterEvent`
+    ///
+    /// # Arguments
+    ///
+    /// * `function` - The name of the function
+    /// * `entry` - The virtual address of the entry point of the function
+    pub fn new(vcpu_idx: Option<u32>, function: String, entry: u64) -> Self {
+        Self {
+            vcpu_idx,
+            function,
+            entry,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FunctionExitEvent {
+    pub vcpu_idx: Option<u32>,
+    pub function: String,
+    pub exit: u64,
+}
+
+impl FunctionExitEvent {
+    /// Instantiate a new `FunctionExitEvent`
+    ///
+    /// # Arguments
+    ///
+    /// * `function` - The name of the function
+    /// * `exit` - The virtual address of the return instruction leaving the function
+    pub fn new(vcpu_idx: Option<u32>, function: String, exit: u64) -> Self {
+        Self {
+            vcpu_idx,
+            function,
+            exit,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MemEvent {
+    pub vaddr: u64,
+    pub is_sext: bool,
+    pub is_be: bool,
+    pub is_store: bool,
+    pub size_shift: u32,
+    pub size: usize,
+    /// The value loaded or stored, if it was captured. Values wider than 64 bits, which CBOR
+    /// has no integers for, are written as their 16 little endian bytes
+    #[serde(with = "super::wide")]
+    pub value: Option<u128>,
+    pub insn: InsnEvent,
+}
+
+impl MemEvent {
+    /// Instantiate a new `MemEvent` from the raw arguments passed to the plugin
+    ///
+    /// # Arguments
+    ///
+    /// * `vaddr` - The virtual address of the memory access
+    /// * `is_sext` - Whether or not the memory access is sign extended
+    /// * `is_be` - Whether or not the memory access is big endian
+    /// * `is_store` - Whether or not the memory access is a store
+    /// * `size_shift` - The size of the memory access, as a power of 2
+    /// * `value` - The value loaded or stored by the memory access, if it was captured
+    /// * `insn` - The instruction that caused the memory access
+    pub fn new(
+        vaddr: u64,
+        is_sext: bool,
+        is_be: bool,
+        is_store: bool,
+        size_shift: u32,
+        value: Option<u128>,
+        insn: InsnEvent,
+    ) -> Self {
+        Self {
+            vaddr,
+            is_sext,
+            is_be,
+            is_store,
+            size_shift,
+            size: 1 << size_shift,
+            value,
+            insn,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SysMemEvent {
+    pub paddr: u64,
+    pub is_io: bool,
+    pub mem: MemEvent,
+}
+
+impl SysMemEvent {
+    /// Instantiate a new `SysMemEvent` for a memory access in system emulation
+    ///
+    /// # Arguments
+    ///
+    /// * `paddr` - The physical address of the memory access
+    /// * `is_io` - Whether or not the memory access targeted device memory (MMIO)
+    /// * `mem` - The memory access, including its virtual address
+    pub fn new(paddr: u64, is_io: bool, mem: MemEvent) -> Self {
+        Self { paddr, is_io, mem }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SyscallEvent {
+    pub num: i64,
+    pub rv: Option<i64>,
+    pub args: Vec<u64>,
+}
+
+impl SyscallEvent {
+    pub fn new(num: i64, rv: Option<i64>, args: Vec<u64>) -> Self {
+        Self { num, rv, args }
+    }
+}
@@ -0,0 +1,274 @@
+//! Cannonball events
+//!
+//! The events the Mons Meg plugin logs, and the CBOR codec it writes them to its driver with,
+//! shared by both ends so they cannot drift apart. The crate is `no_std` and only needs an
+//! allocator, so consumers embedded elsewhere, like in a firmware analysis, get the event types
+//! and the codec without the rest of the tools.
+//!
+//! ```
+//! use cannonball_events::{
+//!     codec::{decode, encode},
+//!     Event, InsnEvent,
+//! };
+//!
+//! let event = Event::Insn(InsnEvent::new(Some(0), 0x401000, Some(vec![0x90]), false));
+//! let mut buf = Vec::new();
+//! encode(&event, &mut buf).unwrap();
+//!
+//! let (decoded, len) = decode(&buf).unwrap().unwrap();
+//! assert_eq!(decoded, event);
+//! assert_eq!(len, buf.len());
+//! ```
+//!
+//! Optional features add what needs more than an allocator:
+//!
+//! * `std` - Reading and writing events with `std::io`, and the events the Jaivana plugin
+//!   logs in [`jaivana`]
+//! * `tokio` - A `tokio_util` codec, to read and write events on async streams
+//! * `rand` - Random events, to test and benchmark consumers without running a program
+
+#![no_std]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod codec;
+#[cfg(feature = "rand")]
+pub mod generate;
+#[cfg(feature = "std")]
+pub mod jaivana;
+
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+/// An executed instruction
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct InsnEvent {
+    /// The VCPU that executed the instruction, if it is known
+    pub vcpu_idx: Option<u32>,
+    /// The virtual address of the instruction
+    pub vaddr: u64,
+    /// The bytes of the instruction, if they were logged
+    pub opcode: Option<Vec<u8>>,
+    /// Whether the instruction is the last of its basic block
+    pub branch: bool,
+}
+
+impl InsnEvent {
+    /// Instantiate a new `InsnEvent` from the raw arguments passed to the plugin
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_idx` - The VCPU that executed the instruction, if it is known
+    /// * `vaddr` - The virtual address of the instruction
+    /// * `opcode` - The opcode of the instruction, optional
+    /// * `branch` - Whether or not the instruction is a branch (in this case, `branch`
+    ///   is a bit of a misnomer -- it actually just means "last insn in the basic
+    ///   block" not exclusively *conditional* branches)
+    pub fn new(vcpu_idx: Option<u32>, vaddr: u64, opcode: Option<Vec<u8>>, branch: bool) -> Self {
+        Self {
+            vcpu_idx,
+            vaddr,
+            opcode,
+            branch,
+        }
+    }
+}
+
+/// A memory access
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct MemEvent {
+    /// The virtual address accessed
+    pub vaddr: u64,
+    /// Whether the access is sign extended
+    pub is_sext: bool,
+    /// Whether the access is big endian
+    pub is_be: bool,
+    /// Whether the access is a store
+    pub is_store: bool,
+    /// The size of the access, as a power of 2
+    pub size_shift: u32,
+    /// The size of the access in bytes
+    pub size: usize,
+    /// The value loaded or stored, if it was captured
+    #[serde(with = "wide")]
+    pub value: Option<u128>,
+    /// The instruction that made the access
+    pub insn: InsnEvent,
+}
+
+impl MemEvent {
+    /// Instantiate a new `MemEvent` from the raw arguments passed to the plugin
+    ///
+    /// # Arguments
+    ///
+    /// * `vaddr` - The virtual address of the memory access
+    /// * `is_sext` - Whether or not the memory access is sign extended
+    /// * `is_be` - Whether or not the memory access is big endian
+    /// * `is_store` - Whether or not the memory access is a store
+    /// * `size_shift` - The size of the memory access, as a power of 2
+    /// * `value` - The value loaded or stored by the memory access, if it was captured
+    /// * `insn` - The instruction that caused the memory access
+    pub fn new(
+        vaddr: u64,
+        is_sext: bool,
+        is_be: bool,
+        is_store: bool,
+        size_shift: u32,
+        value: Option<u128>,
+        insn: InsnEvent,
+    ) -> Self {
+        Self {
+            vaddr,
+            is_sext,
+            is_be,
+            is_store,
+            size_shift,
+            size: 1 << size_shift,
+            value,
+            insn,
+        }
+    }
+}
+
+/// A memory access in system emulation
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SysMemEvent {
+    /// The physical address accessed
+    pub paddr: u64,
+    /// Whether the access targeted device memory (MMIO)
+    pub is_io: bool,
+    /// The access, with its virtual address
+    pub mem: MemEvent,
+}
+
+impl SysMemEvent {
+    /// Instantiate a new `SysMemEvent` for a memory access in system emulation
+    ///
+    /// # Arguments
+    ///
+    /// * `paddr` - The physical address of the memory access
+    /// * `is_io` - Whether or not the memory access targeted device memory (MMIO)
+    /// * `mem` - The memory access, including its virtual address
+    pub fn new(paddr: u64, is_io: bool, mem: MemEvent) -> Self {
+        Self { paddr, is_io, mem }
+    }
+}
+
+/// A system call
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SyscallEvent {
+    /// The system call number
+    pub num: i64,
+    /// The return value, once the system call returned
+    pub rv: Option<i64>,
+    /// The arguments
+    pub args: Vec<u64>,
+}
+
+impl SyscallEvent {
+    /// Instantiate a new `SyscallEvent`
+    ///
+    /// # Arguments
+    ///
+    /// * `num` - The system call number
+    /// * `rv` - The return value, once the system call returned
+    /// * `args` - The arguments
+    pub fn new(num: i64, rv: Option<i64>, args: Vec<u64>) -> Self {
+        Self { num, rv, args }
+    }
+}
+
+/// An event, as written to the wire
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum Event {
+    Insn(InsnEvent),
+    Mem(MemEvent),
+    SysMem(SysMemEvent),
+    Syscall(SyscallEvent),
+}
+
+/// Encodes the values of memory accesses, which may be wider than the 64 bit integers CBOR
+/// holds. Values that fit are written as integers, and wider values as their 16 little endian
+/// bytes
+mod wide {
+    use core::fmt;
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    /// A value wider than 64 bits, written as bytes
+    struct Bytes([u8; 16]);
+
+    impl Serialize for Bytes {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+
+    /// A value, written either way
+    struct Wide(u128);
+
+    impl<'de> Deserialize<'de> for Wide {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_any(WideVisitor)
+        }
+    }
+
+    /// Reads a value written either way
+    struct WideVisitor;
+
+    impl<'de> de::Visitor<'de> for WideVisitor {
+        type Value = Wide;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("an integer or 16 bytes")
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<Wide, E> {
+            Ok(Wide(value.into()))
+        }
+
+        fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Wide, E> {
+            let bytes = value
+                .try_into()
+                .map_err(|_| E::invalid_length(value.len(), &self))?;
+            Ok(Wide(u128::from_le_bytes(bytes)))
+        }
+
+        // Formats without a byte string type, like JSON, write bytes as a sequence
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Wide, A::Error> {
+            let mut bytes = [0; 16];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+            }
+            if seq.next_element::<u8>()?.is_some() {
+                return Err(de::Error::invalid_length(17, &self));
+            }
+            Ok(Wide(u128::from_le_bytes(bytes)))
+        }
+    }
+
+    /// Write a value
+    pub fn serialize<S: Serializer>(
+        value: &Option<u128>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            None => serializer.serialize_none(),
+            Some(value) => match u64::try_from(*value) {
+                Ok(value) => serializer.serialize_some(&value),
+                Err(_) => serializer.serialize_some(&Bytes(value.to_le_bytes())),
+            },
+        }
+    }
+
+    /// Read a value
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u128>, D::Error> {
+        Ok(Option::<Wide>::deserialize(deserializer)?.map(|Wide(value)| value))
+    }
+}
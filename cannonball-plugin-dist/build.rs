@@ -0,0 +1,104 @@
+use xxhash_rust::xxh3::xxh3_64;
+
+use std::{
+    env::{var, vars_os},
+    fs::{copy, read},
+    path::PathBuf,
+    process::Command,
+};
+
+/// The name cargo gives the Jaivana library for a target, like `cannonball_plugin_dist::file_name`
+///
+/// # Arguments
+///
+/// * `target` - The target triple
+fn file_name(target: &str) -> &'static str {
+    if target.contains("-windows") {
+        "jaivana.dll"
+    } else if target.contains("-apple-") {
+        "libjaivana.dylib"
+    } else {
+        "libjaivana.so"
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=CANNONBALL_PLUGIN");
+
+    if var("CARGO_FEATURE_EMBEDDED").is_err() {
+        return;
+    }
+
+    let out_dir = PathBuf::from(var("OUT_DIR").unwrap());
+    let plugin = out_dir.join("libjaivana.so");
+
+    // A plugin built elsewhere can be supplied with `CANNONBALL_PLUGIN`, like one built with
+    // other features, or when the Jaivana sources are not next to this crate
+    if let Ok(prebuilt) = var("CANNONBALL_PLUGIN") {
+        copy(prebuilt, &plugin).expect("Failed to copy CANNONBALL_PLUGIN");
+    } else {
+        let manifest_dir = PathBuf::from(var("CARGO_MANIFEST_DIR").unwrap());
+        let jaivana = manifest_dir.join("../examples/jaivana");
+        let cannonball = manifest_dir.join("../cannonball");
+
+        println!("cargo:rerun-if-changed={}", jaivana.display());
+        println!("cargo:rerun-if-changed={}", cannonball.display());
+
+        // The plugin gets a target directory of its own, because cargo holds the lock on the
+        // one building this crate
+        let target_dir = out_dir.join("target");
+        let profile = var("PROFILE").unwrap();
+        let target = var("TARGET").unwrap();
+        let cross = target != var("HOST").unwrap();
+
+        let mut cargo = Command::new(var("CARGO").unwrap());
+        cargo
+            .arg("build")
+            .arg("--manifest-path")
+            .arg(jaivana.join("Cargo.toml"))
+            .arg("--lib")
+            .arg("--target-dir")
+            .arg(&target_dir);
+
+        if profile == "release" {
+            cargo.arg("--release");
+        }
+
+        // Cross-compiling this crate embeds a plugin for the same target. The linker and
+        // sysroot for it are configured in the environment cargo passes down, like
+        // `CARGO_TARGET_<TRIPLE>_LINKER`
+        if cross {
+            cargo.arg("--target").arg(&target);
+        }
+
+        // The plugin is built like a dependency of this crate. Build scripts of its own
+        // dependencies, like this one, would otherwise see the features of this build and
+        // build the plugin again, and `cargo clippy` would lint it
+        for (key, _) in vars_os() {
+            if key.to_string_lossy().starts_with("CARGO_FEATURE_") {
+                cargo.env_remove(key);
+            }
+        }
+
+        cargo.env_remove("RUSTC_WORKSPACE_WRAPPER");
+        cargo.env_remove("CLIPPY_ARGS");
+
+        let status = cargo.status().expect("Failed to run cargo");
+
+        if !status.success() {
+            panic!("Failed to build the Jaivana plugin. Set CANNONBALL_PLUGIN to the path of a built plugin to embed it instead");
+        }
+
+        let dir = if cross {
+            target_dir.join(&target)
+        } else {
+            target_dir
+        };
+
+        copy(dir.join(&profile).join(file_name(&target)), &plugin)
+            .expect("Failed to copy the Jaivana plugin");
+    }
+
+    let hash = xxh3_64(&read(&plugin).expect("Failed to read the Jaivana plugin"));
+    println!("cargo:rustc-env=CANNONBALL_PLUGIN_HASH={:016x}", hash);
+}
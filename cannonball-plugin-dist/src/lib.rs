@@ -0,0 +1,180 @@
+//! Cannonball plugin distribution
+//!
+//! QEMU loads plugins from files, so the drivers of cannonball plugins need to know where their
+//! plugin is. This crate answers that without paths into a target directory baked in at
+//! compile time:
+//!
+//! * `find` looks for a plugin next to the running executable, which is where cargo puts the
+//!   plugins of a workspace when building it along with their drivers
+//! * `file_name` and `target_path` tell where cargo puts a plugin built for a target, for
+//!   plugins cross-compiled with `--target`, like an aarch64 plugin built on an x86_64 host
+//! * `extract` writes a plugin in memory to the cache directory, named after its version and
+//!   the hash of its contents, so it is written once and every process using it shares it
+//! * `plugin_path`, with the `embedded` feature, extracts the Jaivana plugin this crate was
+//!   built with. The build script builds it, or embeds the plugin at the path in the
+//!   `CANNONBALL_PLUGIN` environment variable, so tools that depend on this crate work after
+//!   `cargo install` too
+//!
+//! ```no_run
+//! use std::process::Command;
+//!
+//! let plugin = cannonball_plugin_dist::plugin_path().unwrap();
+//!
+//! Command::new("qemu-x86_64")
+//!     .arg("-plugin")
+//!     .arg(format!("{},log_syscall=true", plugin.display()))
+//!     .arg("/bin/ls")
+//!     .status()
+//!     .unwrap();
+//! ```
+
+use xxhash_rust::xxh3::xxh3_64;
+
+use std::{
+    env::{
+        consts::{DLL_PREFIX, DLL_SUFFIX},
+        current_exe, var_os,
+    },
+    fs::{create_dir_all, rename, write},
+    io,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// The version of this crate, in the names of the plugins it extracts
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The Jaivana plugin, built along with this crate
+#[cfg(feature = "embedded")]
+pub static PLUGIN: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/libjaivana.so"));
+
+/// The hash of `PLUGIN`, as 16 hex digits
+#[cfg(feature = "embedded")]
+pub const PLUGIN_HASH: &str = env!("CANNONBALL_PLUGIN_HASH");
+
+/// The directory plugins are extracted to: `cannonball` in `$XDG_CACHE_HOME`, or in
+/// `$HOME/.cache` if it is not set
+pub fn cache_dir() -> io::Result<PathBuf> {
+    // The XDG base directory specification says relative paths are to be ignored
+    let cache = var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|cache| cache.is_absolute())
+        .or_else(|| var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "Neither XDG_CACHE_HOME nor HOME is set",
+            )
+        })?;
+
+    Ok(cache.join("cannonball"))
+}
+
+/// The name cargo gives the library of a plugin built for a target: `lib<name>.so` on Linux
+/// and other ELF targets, `lib<name>.dylib` on Apple targets and `<name>.dll` on Windows
+///
+/// # Arguments
+///
+/// * `name` - The name of the plugin, like `jaivana`
+/// * `target` - The target triple, like `aarch64-unknown-linux-gnu`
+pub fn file_name(name: &str, target: &str) -> String {
+    if target.contains("-windows") {
+        format!("{}.dll", name)
+    } else if target.contains("-apple-") {
+        format!("lib{}.dylib", name)
+    } else {
+        format!("lib{}.so", name)
+    }
+}
+
+/// The path cargo writes the library of a plugin to: `<target_dir>/<profile>` when building
+/// for the host, and `<target_dir>/<target>/<profile>` when building with `--target`
+///
+/// # Arguments
+///
+/// * `target_dir` - The target directory, like `target`
+/// * `target` - The target triple passed to `--target`, if any
+/// * `profile` - The directory of the profile, like `debug` or `release`
+/// * `name` - The name of the plugin
+pub fn target_path(target_dir: &Path, target: Option<&str>, profile: &str, name: &str) -> PathBuf {
+    match target {
+        Some(target) => target_dir
+            .join(target)
+            .join(profile)
+            .join(file_name(name, target)),
+        None => target_dir
+            .join(profile)
+            .join(format!("{}{}{}", DLL_PREFIX, name, DLL_SUFFIX)),
+    }
+}
+
+/// Write a plugin to the cache directory as `lib<name>-<version>-<hash>.so`, or the names
+/// libraries have on the running platform, unless it is already there, and return its path
+///
+/// # Arguments
+///
+/// * `name` - The name of the plugin, like `jaivana`
+/// * `plugin` - The plugin
+pub fn extract(name: &str, plugin: &[u8]) -> io::Result<PathBuf> {
+    extract_hashed(name, plugin, &format!("{:016x}", xxh3_64(plugin)))
+}
+
+/// Write a plugin whose hash is known to the cache directory, unless it is already there, and
+/// return its path
+///
+/// # Arguments
+///
+/// * `name` - The name of the plugin
+/// * `plugin` - The plugin
+/// * `hash` - The hash of the plugin
+fn extract_hashed(name: &str, plugin: &[u8], hash: &str) -> io::Result<PathBuf> {
+    let dir = cache_dir()?;
+    let file = format!("{}{}-{}-{}{}", DLL_PREFIX, name, VERSION, hash, DLL_SUFFIX);
+    let path = dir.join(&file);
+
+    // The name is unique to the contents, so a file with it holds this plugin already
+    if path.is_file() {
+        return Ok(path);
+    }
+
+    create_dir_all(&dir)?;
+
+    // Written to a file of its own and renamed into place, so processes extracting the same
+    // plugin at once never load a partly written one
+    let temp = dir.join(format!(".{}.{}", file, process::id()));
+    write(&temp, plugin)?;
+    rename(&temp, &path)?;
+
+    Ok(path)
+}
+
+/// The path of the Jaivana plugin this crate was built with, extracting it to the cache
+/// directory the first time
+#[cfg(feature = "embedded")]
+pub fn plugin_path() -> io::Result<PathBuf> {
+    extract_hashed("jaivana", PLUGIN, PLUGIN_HASH)
+}
+
+/// Find a plugin built in the same workspace as the running executable, in the directory of
+/// the executable or the one above it, for tests and examples cargo puts in `deps` and
+/// `examples`
+///
+/// # Arguments
+///
+/// * `name` - The name of the plugin, like `jaivana` for `libjaivana.so`
+pub fn find(name: &str) -> io::Result<PathBuf> {
+    let exe = current_exe()?;
+    let file = format!("{}{}{}", DLL_PREFIX, name, DLL_SUFFIX);
+
+    exe.ancestors()
+        .skip(1)
+        .take(2)
+        .map(|dir| dir.join(&file))
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No {} next to {}", file, exe.display()),
+            )
+        })
+}
@@ -0,0 +1,186 @@
+//! Python bindings for reading traces
+//!
+//! Traces are decoded by `cannonball_tools` and handed to Python one event at a time, so
+//! recorded traces can be analyzed from Python without decoding them there:
+//!
+//! ```python
+//! from cannonball_py import Symbolizer, TraceReader
+//!
+//! symbolizer = Symbolizer("/bin/ls")
+//!
+//! for event in TraceReader("ls.bin.gz", format="binary"):
+//!     if event.kind == "Insn":
+//!         print(symbolizer.symbolize(event["vaddr"]))
+//! ```
+
+use cannonball_tools::{
+    output::OutputFormat,
+    symbols,
+    trace::{self, EventKind},
+};
+use clap::ValueEnum;
+use pyo3::{
+    exceptions::{PyKeyError, PyValueError},
+    prelude::*,
+    types::{PyDict, PyList},
+};
+use serde_json::Value;
+
+use std::{io::BufRead, path::PathBuf};
+
+/// Convert a JSON value to the Python object it corresponds to
+///
+/// # Arguments
+///
+/// * `py` - The GIL
+/// * `value` - The value
+fn to_python(py: Python<'_>, value: &Value) -> PyResult<PyObject> {
+    Ok(match value {
+        Value::Null => py.None(),
+        Value::Bool(b) => b.into_py(py),
+        Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(n), _) => n.into_py(py),
+            (_, Some(n)) => n.into_py(py),
+            _ => n.as_f64().unwrap_or_default().into_py(py),
+        },
+        Value::String(s) => s.into_py(py),
+        Value::Array(values) => PyList::new(
+            py,
+            values
+                .iter()
+                .map(|value| to_python(py, value))
+                .collect::<PyResult<Vec<PyObject>>>()?,
+        )
+        .into_py(py),
+        Value::Object(fields) => {
+            let dict = PyDict::new(py);
+
+            for (name, value) in fields {
+                dict.set_item(name, to_python(py, value)?)?;
+            }
+
+            dict.into_py(py)
+        }
+    })
+}
+
+/// An event of a trace
+#[pyclass(module = "cannonball_py")]
+struct Event {
+    /// The position of the event in the trace
+    #[pyo3(get)]
+    seq: u64,
+    /// The kind of the event, like `Insn`, or `None` if unknown
+    #[pyo3(get)]
+    kind: Option<String>,
+    /// The process that logged the event, if events are tagged with PIDs
+    #[pyo3(get)]
+    pid: Option<u64>,
+    /// The VCPU that logged the event, if known
+    #[pyo3(get)]
+    vcpu: Option<u64>,
+    /// The fields of the event, as a dict
+    #[pyo3(get)]
+    data: Py<PyDict>,
+}
+
+#[pymethods]
+impl Event {
+    /// A field of the event
+    fn __getitem__(&self, py: Python<'_>, name: &str) -> PyResult<PyObject> {
+        match self.data.as_ref(py).get_item(name) {
+            Some(value) => Ok(value.into_py(py)),
+            None => Err(PyKeyError::new_err(name.to_string())),
+        }
+    }
+
+    fn __repr__(&self, py: Python<'_>) -> PyResult<String> {
+        Ok(format!(
+            "Event(seq={}, kind={}, data={})",
+            self.seq,
+            self.kind.as_deref().unwrap_or("None"),
+            self.data.as_ref(py).repr()?
+        ))
+    }
+}
+
+/// Iterates over the events of a trace file, which may be compressed with gzip
+#[pyclass(module = "cannonball_py")]
+struct TraceReader {
+    /// The events of the trace
+    events: trace::TraceReader<Box<dyn BufRead + Send>>,
+    /// The number of events read so far
+    seq: u64,
+}
+
+#[pymethods]
+impl TraceReader {
+    /// Open a trace written in `format`: `json`, `cbor`, `msgpack`, `binary` or `compact`
+    #[new]
+    #[pyo3(signature = (path, format = "json"))]
+    fn new(path: PathBuf, format: &str) -> PyResult<Self> {
+        let format = OutputFormat::from_str(format, true).map_err(PyValueError::new_err)?;
+
+        Ok(Self {
+            events: trace::TraceReader::open(path, format)?,
+            seq: 0,
+        })
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<Event>> {
+        let event = match self.events.next() {
+            Some(event) => event?,
+            None => return Ok(None),
+        };
+        let field = |name: &str| event.get(name).and_then(Value::as_u64);
+
+        let seq = self.seq;
+        self.seq += 1;
+
+        Ok(Some(Event {
+            seq,
+            kind: EventKind::of(&event).map(|kind| format!("{:?}", kind)),
+            pid: field("pid"),
+            vcpu: field("vcpu_idx"),
+            data: to_python(py, &event)?.extract(py)?,
+        }))
+    }
+}
+
+/// Names the functions of a program addresses are in
+#[pyclass(module = "cannonball_py")]
+struct Symbolizer {
+    symbolizer: symbols::Symbolizer,
+}
+
+#[pymethods]
+impl Symbolizer {
+    /// Load the symbols of a program, loaded at `bias` if it is position independent
+    #[new]
+    #[pyo3(signature = (path, bias = 0))]
+    fn new(path: PathBuf, bias: u64) -> PyResult<Self> {
+        Ok(Self {
+            symbolizer: symbols::Symbolizer::load(path)?.with_bias(bias),
+        })
+    }
+
+    /// The name of the function an address is in and the offset of the address in it, or
+    /// `None` if it is in no known function
+    fn symbolize(&self, addr: u64) -> Option<(String, u64)> {
+        self.symbolizer
+            .symbolize(addr)
+            .map(|(name, offset)| (name.to_string(), offset))
+    }
+}
+
+#[pymodule]
+fn cannonball_py(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Event>()?;
+    m.add_class::<TraceReader>()?;
+    m.add_class::<Symbolizer>()?;
+    Ok(())
+}
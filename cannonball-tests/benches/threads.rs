@@ -0,0 +1,45 @@
+//! Throughput of tracing a program looping on several threads at once, each running on a VCPU
+//! of its own, with the plugin logging every instruction and memory access, and every
+//! translation block
+
+use cannonball_tests::Fixture;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+/// The number of iterations of the loop of each thread
+const ITERATIONS: u32 = 100_000;
+
+/// The number of instructions in the loop
+const LOOP_INSNS: u64 = 3;
+
+/// The number of threads the program runs, the first one included
+const THREADS: [u32; 3] = [2, 4, 8];
+
+/// The arguments the plugin is run with, by name
+const ARGS: [(&str, &[&str]); 2] = [
+    ("insns", &["log_pc=true", "log_mem=true"]),
+    ("tbs", &["trace_tb=true"]),
+];
+
+fn tracing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("threads");
+    // Every run starts QEMU, so there are fewer samples than usual
+    group.sample_size(10);
+
+    for threads in THREADS {
+        let fixture = Fixture::threaded(threads, ITERATIONS).unwrap();
+        group.throughput(Throughput::Elements(
+            threads as u64 * ITERATIONS as u64 * LOOP_INSNS,
+        ));
+
+        for (name, args) in ARGS {
+            group.bench_with_input(BenchmarkId::new(name, threads), &fixture, |b, fixture| {
+                b.iter(|| assert_eq!(fixture.run(args).unwrap(), Some(0)))
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, tracing);
+criterion_main!(benches);
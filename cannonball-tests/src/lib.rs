@@ -0,0 +1,842 @@
+//! End-to-end tests of the Jaivana plugin
+//!
+//! The tests in `tests/` run small programs under QEMU with the Jaivana plugin and check the
+//! events it logs. The programs are static ELF executables generated from a few hand-assembled
+//! instructions, so no toolchain for the guest architectures is needed and the tests run
+//! wherever QEMU does. Every program counts down a loop of `LOOP` iterations, writes "hi\n" to
+//! stdout and exits.
+//!
+//! The plugin is built with cargo the first time it is needed, with the profile the tests are
+//! built with. x86_64 programs are always tested, aarch64 programs with the `aarch64`
+//! feature, which embeds `qemu-aarch64`, 32-bit ARM programs, one in the ARM instruction
+//! set and one in Thumb, with the `arm` feature, which embeds `qemu-arm`, i386 programs with
+//! the `i386` feature, which embeds `qemu-i386`, and s390x programs, the only big endian
+//! ones, with the `s390x` feature, which embeds `qemu-s390x`:
+//!
+//! ```text
+//! $ cargo test -p cannonball-tests --features aarch64,arm,i386,s390x
+//! ```
+//!
+//! With the `plugin-api-v2` feature, the plugin is built with the feature of the same name, to
+//! test what needs it, which needs the embedded QEMU to be 9.0 or later. Without it, those
+//! tests check the plugin refuses to be installed instead.
+//!
+//! The benchmarks in `benches/` run an x86_64 program looping on several threads at once, to
+//! measure how the plugin scales with the number of VCPUs.
+
+use cannonball_plugin_dist::target_path;
+use cannonball_tools::trace::EventKind;
+use memfd_exec::{MemFdExecutable, Stdio};
+use once_cell::sync::OnceCell;
+#[cfg(feature = "aarch64")]
+use qemu::qemu_aarch64;
+#[cfg(feature = "arm")]
+use qemu::qemu_arm;
+#[cfg(feature = "i386")]
+use qemu::qemu_i386;
+#[cfg(feature = "s390x")]
+use qemu::qemu_s390x;
+use qemu::qemu_x86_64;
+use serde_json::{from_str, Value};
+
+use std::{
+    env::{current_exe, temp_dir},
+    fs::{remove_file, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    process::{self, Command},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The address the programs are loaded at
+pub const BASE: u64 = 0x400000;
+
+/// The number of iterations of the loop of the programs
+pub const LOOP: usize = 10;
+
+/// The program for x86_64
+///
+/// ```text
+///     mov ecx, 10
+/// loop:
+///     dec ecx
+///     jnz loop
+///     mov eax, 1          ; write
+///     mov edi, 1
+///     lea rsi, [rip + msg]
+///     mov edx, 3
+///     syscall
+///     mov eax, 231        ; exit_group
+///     xor edi, edi
+///     syscall
+/// msg:
+///     .ascii "hi\n"
+/// ```
+const X86_64_CODE: &[u8] = &[
+    0xb9, 0x0a, 0x00, 0x00, 0x00, // mov ecx, 10
+    0xff, 0xc9, // dec ecx
+    0x75, 0xfc, // jnz loop
+    0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+    0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+    0x48, 0x8d, 0x35, 0x10, 0x00, 0x00, 0x00, // lea rsi, [rip + 16]
+    0xba, 0x03, 0x00, 0x00, 0x00, // mov edx, 3
+    0x0f, 0x05, // syscall
+    0xb8, 0xe7, 0x00, 0x00, 0x00, // mov eax, 231
+    0x31, 0xff, // xor edi, edi
+    0x0f, 0x05, // syscall
+    b'h', b'i', b'\n',
+];
+
+/// The program for aarch64
+///
+/// ```text
+///     mov x1, #10
+/// loop:
+///     subs x1, x1, #1
+///     b.ne loop
+///     mov x0, #1
+///     adr x1, msg
+///     mov x2, #3
+///     mov x8, #64         // write
+///     svc #0
+///     mov x0, #0
+///     mov x8, #94         // exit_group
+///     svc #0
+/// msg:
+///     .ascii "hi\n"
+/// ```
+#[cfg(feature = "aarch64")]
+const AARCH64_CODE: &[u8] = &[
+    0x41, 0x01, 0x80, 0xd2, // mov x1, #10
+    0x21, 0x04, 0x00, 0xf1, // subs x1, x1, #1
+    0xe1, 0xff, 0xff, 0x54, // b.ne loop
+    0x20, 0x00, 0x80, 0xd2, // mov x0, #1
+    0xe1, 0x00, 0x00, 0x10, // adr x1, #28
+    0x62, 0x00, 0x80, 0xd2, // mov x2, #3
+    0x08, 0x08, 0x80, 0xd2, // mov x8, #64
+    0x01, 0x00, 0x00, 0xd4, // svc #0
+    0x00, 0x00, 0x80, 0xd2, // mov x0, #0
+    0xc8, 0x0b, 0x80, 0xd2, // mov x8, #94
+    0x01, 0x00, 0x00, 0xd4, // svc #0
+    b'h', b'i', b'\n',
+];
+
+/// The program for 32-bit ARM, in the ARM instruction set
+///
+/// ```text
+///     mov r1, #10
+/// loop:
+///     subs r1, r1, #1
+///     bne loop
+///     mov r0, #1
+///     adr r1, msg
+///     mov r2, #3
+///     mov r7, #4          @ write
+///     svc #0
+///     mov r0, #0
+///     mov r7, #248        @ exit_group
+///     svc #0
+/// msg:
+///     .ascii "hi\n"
+/// ```
+#[cfg(feature = "arm")]
+const ARM_CODE: &[u8] = &[
+    0x0a, 0x10, 0xa0, 0xe3, // mov r1, #10
+    0x01, 0x10, 0x51, 0xe2, // subs r1, r1, #1
+    0xfd, 0xff, 0xff, 0x1a, // bne loop
+    0x01, 0x00, 0xa0, 0xe3, // mov r0, #1
+    0x14, 0x10, 0x8f, 0xe2, // add r1, pc, #20
+    0x03, 0x20, 0xa0, 0xe3, // mov r2, #3
+    0x04, 0x70, 0xa0, 0xe3, // mov r7, #4
+    0x00, 0x00, 0x00, 0xef, // svc #0
+    0x00, 0x00, 0xa0, 0xe3, // mov r0, #0
+    0xf8, 0x70, 0xa0, 0xe3, // mov r7, #248
+    0x00, 0x00, 0x00, 0xef, // svc #0
+    b'h', b'i', b'\n',
+];
+
+/// The program for 32-bit ARM, in the Thumb instruction set. The block after the first
+/// `svc` only has 4 byte instructions on 4 byte boundaries, which could as well be ARM ones
+///
+/// ```text
+///     movs r1, #10
+/// loop:
+///     subs r1, #1
+///     bne loop
+///     movs r0, #1
+///     adr r1, msg
+///     movs r2, #3
+///     movs r7, #4         @ write
+///     svc #0
+///     mov.w r0, #0
+///     b.w exit
+/// exit:
+///     movs r7, #248       @ exit_group
+///     svc #0
+/// msg:
+///     .ascii "hi\n"
+/// ```
+#[cfg(feature = "arm")]
+const THUMB_CODE: &[u8] = &[
+    0x0a, 0x21, // movs r1, #10
+    0x01, 0x39, // subs r1, #1
+    0xfd, 0xd1, // bne loop
+    0x01, 0x20, // movs r0, #1
+    0x04, 0xa1, // adr r1, #16
+    0x03, 0x22, // movs r2, #3
+    0x04, 0x27, // movs r7, #4
+    0x00, 0xdf, // svc #0
+    0x4f, 0xf0, 0x00, 0x00, // mov.w r0, #0
+    0x00, 0xf0, 0x00, 0xb8, // b.w exit
+    0xf8, 0x27, // movs r7, #248
+    0x00, 0xdf, // svc #0
+    b'h', b'i', b'\n',
+];
+
+/// The program for i386. It has no PC-relative addressing, so the message is addressed where
+/// the program is loaded
+///
+/// ```text
+///     mov ecx, 10
+/// loop:
+///     dec ecx
+///     jnz loop
+///     mov eax, 4          ; write
+///     mov ebx, 1
+///     mov ecx, msg
+///     mov edx, 3
+///     int 0x80
+///     mov eax, 252        ; exit_group
+///     xor ebx, ebx
+///     int 0x80
+/// msg:
+///     .ascii "hi\n"
+/// ```
+#[cfg(feature = "i386")]
+const I386_CODE: &[u8] = &[
+    0xb9, 0x0a, 0x00, 0x00, 0x00, // mov ecx, 10
+    0x49, // dec ecx
+    0x75, 0xfd, // jnz loop
+    0xb8, 0x04, 0x00, 0x00, 0x00, // mov eax, 4
+    0xbb, 0x01, 0x00, 0x00, 0x00, // mov ebx, 1
+    0xb9, 0x7b, 0x00, 0x40, 0x00, // mov ecx, 0x40007b
+    0xba, 0x03, 0x00, 0x00, 0x00, // mov edx, 3
+    0xcd, 0x80, // int 0x80
+    0xb8, 0xfc, 0x00, 0x00, 0x00, // mov eax, 252
+    0x31, 0xdb, // xor ebx, ebx
+    0xcd, 0x80, // int 0x80
+    b'h', b'i', b'\n',
+];
+
+/// The program for s390x
+///
+/// ```text
+///     lghi %r1, 10
+/// loop:
+///     aghi %r1, -1
+///     jne loop
+///     lghi %r2, 1
+///     larl %r3, msg
+///     lghi %r4, 3
+///     svc 4               # write
+///     lghi %r2, 0
+///     svc 248             # exit_group
+/// msg:
+///     .ascii "hi\n"
+/// ```
+#[cfg(feature = "s390x")]
+const S390X_CODE: &[u8] = &[
+    0xa7, 0x19, 0x00, 0x0a, // lghi %r1, 10
+    0xa7, 0x1b, 0xff, 0xff, // aghi %r1, -1
+    0xa7, 0x74, 0xff, 0xfe, // jne loop
+    0xa7, 0x29, 0x00, 0x01, // lghi %r2, 1
+    0xc0, 0x30, 0x00, 0x00, 0x00, 0x09, // larl %r3, msg
+    0xa7, 0x49, 0x00, 0x03, // lghi %r4, 3
+    0x0a, 0x04, // svc 4
+    0xa7, 0x29, 0x00, 0x00, // lghi %r2, 0
+    0x0a, 0xf8, // svc 248
+    b'h', b'i', b'\n',
+];
+
+/// The multithreaded program for x86_64. Every thread, the first one included, loads from the
+/// stack in a loop, then the first one waits for the others to exit. The threads share the
+/// stack, which they do not push to, and count how many are left below it. The number of
+/// threads and iterations are patched in by `threaded_code`
+///
+/// ```text
+///     xor r13d, r13d      ; 0 on the first thread
+///     mov r12d, THREADS - 1
+///     mov qword [rsp - 8], THREADS - 1
+/// spawn:
+///     mov eax, 56         ; clone
+///     mov edi, 0x50f00    ; CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND |
+///                         ; CLONE_THREAD | CLONE_SYSVSEM
+///     xor esi, esi        ; on the same stack
+///     xor edx, edx
+///     xor r10d, r10d
+///     xor r8d, r8d
+///     syscall
+///     test eax, eax
+///     jz thread
+///     dec r12d
+///     jnz spawn
+///     jmp work
+/// thread:
+///     mov r13d, 1
+/// work:
+///     mov ecx, ITERATIONS
+/// loop:
+///     mov rax, [rsp - 16]
+///     dec ecx
+///     jnz loop
+///     test r13d, r13d
+///     jz wait
+///     lock dec qword [rsp - 8]
+///     mov eax, 60         ; exit
+///     xor edi, edi
+///     syscall
+/// wait:
+///     mov eax, 24         ; sched_yield
+///     syscall
+///     cmp qword [rsp - 8], 0
+///     jne wait
+///     mov eax, 231        ; exit_group
+///     xor edi, edi
+///     syscall
+/// ```
+const X86_64_THREADED_CODE: &[u8] = &[
+    0x45, 0x31, 0xed, // xor r13d, r13d
+    0x41, 0xbc, 0x00, 0x00, 0x00, 0x00, // mov r12d, THREADS - 1
+    0x48, 0xc7, 0x44, 0x24, 0xf8, 0x00, 0x00, 0x00, 0x00, // mov qword [rsp - 8], THREADS - 1
+    0xb8, 0x38, 0x00, 0x00, 0x00, // mov eax, 56
+    0xbf, 0x00, 0x0f, 0x05, 0x00, // mov edi, 0x50f00
+    0x31, 0xf6, // xor esi, esi
+    0x31, 0xd2, // xor edx, edx
+    0x45, 0x31, 0xd2, // xor r10d, r10d
+    0x45, 0x31, 0xc0, // xor r8d, r8d
+    0x0f, 0x05, // syscall
+    0x85, 0xc0, // test eax, eax
+    0x74, 0x07, // jz thread
+    0x41, 0xff, 0xcc, // dec r12d
+    0x75, 0xe1, // jnz spawn
+    0xeb, 0x06, // jmp work
+    0x41, 0xbd, 0x01, 0x00, 0x00, 0x00, // mov r13d, 1
+    0xb9, 0x00, 0x00, 0x00, 0x00, // mov ecx, ITERATIONS
+    0x48, 0x8b, 0x44, 0x24, 0xf0, // mov rax, [rsp - 16]
+    0xff, 0xc9, // dec ecx
+    0x75, 0xf7, // jnz loop
+    0x45, 0x85, 0xed, // test r13d, r13d
+    0x74, 0x0f, // jz wait
+    0xf0, 0x48, 0xff, 0x4c, 0x24, 0xf8, // lock dec qword [rsp - 8]
+    0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, 60
+    0x31, 0xff, // xor edi, edi
+    0x0f, 0x05, // syscall
+    0xb8, 0x18, 0x00, 0x00, 0x00, // mov eax, 24
+    0x0f, 0x05, // syscall
+    0x48, 0x83, 0x7c, 0x24, 0xf8, 0x00, // cmp qword [rsp - 8], 0
+    0x75, 0xf1, // jne wait
+    0xb8, 0xe7, 0x00, 0x00, 0x00, // mov eax, 231
+    0x31, 0xff, // xor edi, edi
+    0x0f, 0x05, // syscall
+];
+
+/// The multithreaded program for x86_64, with its number of threads and of iterations of the
+/// loop of each thread
+///
+/// # Arguments
+///
+/// * `threads` - The number of threads, the first one included. At least 2
+/// * `iterations` - The number of iterations of the loop of each thread
+fn threaded_code(threads: u32, iterations: u32) -> Vec<u8> {
+    assert!(threads >= 2, "The program runs at least 2 threads");
+
+    let mut code = X86_64_THREADED_CODE.to_vec();
+    code[5..9].copy_from_slice(&(threads - 1).to_le_bytes());
+    code[14..18].copy_from_slice(&(threads - 1).to_le_bytes());
+    code[58..62].copy_from_slice(&iterations.to_le_bytes());
+    code
+}
+
+/// The architectures programs are tested on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    #[cfg(feature = "aarch64")]
+    Aarch64,
+    /// 32-bit ARM, running a program in the ARM instruction set
+    #[cfg(feature = "arm")]
+    Arm,
+    /// 32-bit ARM, running a program in the Thumb instruction set
+    #[cfg(feature = "arm")]
+    Thumb,
+    #[cfg(feature = "i386")]
+    I386,
+    /// s390x, a big endian architecture
+    #[cfg(feature = "s390x")]
+    S390x,
+}
+
+impl Arch {
+    /// The name of the architecture, as QEMU names it
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::X86_64 => "x86_64",
+            #[cfg(feature = "aarch64")]
+            Self::Aarch64 => "aarch64",
+            #[cfg(feature = "arm")]
+            Self::Arm => "arm",
+            #[cfg(feature = "arm")]
+            Self::Thumb => "thumb",
+            #[cfg(feature = "i386")]
+            Self::I386 => "i386",
+            #[cfg(feature = "s390x")]
+            Self::S390x => "s390x",
+        }
+    }
+
+    /// Whether programs for the architecture are 64-bit ELF files
+    fn is_64(self) -> bool {
+        match self {
+            Self::X86_64 => true,
+            #[cfg(feature = "aarch64")]
+            Self::Aarch64 => true,
+            #[cfg(feature = "arm")]
+            Self::Arm | Self::Thumb => false,
+            #[cfg(feature = "i386")]
+            Self::I386 => false,
+            #[cfg(feature = "s390x")]
+            Self::S390x => true,
+        }
+    }
+
+    /// The width of the addresses of the architecture in bits, as the plugin logs it
+    pub fn address_bits(self) -> u32 {
+        if self.is_64() {
+            64
+        } else {
+            32
+        }
+    }
+
+    /// The byte order of the architecture, as the plugin logs it
+    pub fn endianness(self) -> &'static str {
+        match self {
+            Self::X86_64 => "little",
+            #[cfg(feature = "aarch64")]
+            Self::Aarch64 => "little",
+            #[cfg(feature = "arm")]
+            Self::Arm | Self::Thumb => "little",
+            #[cfg(feature = "i386")]
+            Self::I386 => "little",
+            #[cfg(feature = "s390x")]
+            Self::S390x => "big",
+        }
+    }
+
+    /// Append an integer field to an ELF file, in the byte order of the architecture
+    ///
+    /// # Arguments
+    ///
+    /// * `elf` - The ELF file
+    /// * `value` - The value of the field
+    /// * `size` - The size of the field in bytes
+    fn put(self, elf: &mut Vec<u8>, value: u64, size: usize) {
+        if self.endianness() == "big" {
+            elf.extend(&value.to_be_bytes()[8 - size..]);
+        } else {
+            elf.extend(&value.to_le_bytes()[..size]);
+        }
+    }
+
+    /// The size of the ELF header and the program header before the code of a program
+    fn headers(self) -> u64 {
+        if self.is_64() {
+            64 + 56
+        } else {
+            52 + 32
+        }
+    }
+
+    /// The `e_machine` of ELF files for the architecture
+    fn machine(self) -> u16 {
+        match self {
+            Self::X86_64 => 62,
+            #[cfg(feature = "aarch64")]
+            Self::Aarch64 => 183,
+            #[cfg(feature = "arm")]
+            Self::Arm | Self::Thumb => 40,
+            #[cfg(feature = "i386")]
+            Self::I386 => 3,
+            #[cfg(feature = "s390x")]
+            Self::S390x => 22,
+        }
+    }
+
+    /// The `e_flags` of ELF files for the architecture
+    fn flags(self) -> u64 {
+        match self {
+            Self::X86_64 => 0,
+            #[cfg(feature = "aarch64")]
+            Self::Aarch64 => 0,
+            // The EABI version 5
+            #[cfg(feature = "arm")]
+            Self::Arm | Self::Thumb => 0x0500_0000,
+            #[cfg(feature = "i386")]
+            Self::I386 => 0,
+            #[cfg(feature = "s390x")]
+            Self::S390x => 0,
+        }
+    }
+
+    /// The code of the program for the architecture
+    fn code(self) -> &'static [u8] {
+        match self {
+            Self::X86_64 => X86_64_CODE,
+            #[cfg(feature = "aarch64")]
+            Self::Aarch64 => AARCH64_CODE,
+            #[cfg(feature = "arm")]
+            Self::Arm => ARM_CODE,
+            #[cfg(feature = "arm")]
+            Self::Thumb => THUMB_CODE,
+            #[cfg(feature = "i386")]
+            Self::I386 => I386_CODE,
+            #[cfg(feature = "s390x")]
+            Self::S390x => S390X_CODE,
+        }
+    }
+
+    /// The offset of the conditional branch closing the loop in the code of the program
+    fn branch_offset(self) -> u64 {
+        match self {
+            Self::X86_64 => 7,
+            #[cfg(feature = "aarch64")]
+            Self::Aarch64 => 8,
+            #[cfg(feature = "arm")]
+            Self::Arm => 8,
+            #[cfg(feature = "arm")]
+            Self::Thumb => 4,
+            #[cfg(feature = "i386")]
+            Self::I386 => 6,
+            #[cfg(feature = "s390x")]
+            Self::S390x => 8,
+        }
+    }
+
+    /// The number of the `write` system call
+    pub fn write_syscall(self) -> i64 {
+        match self {
+            Self::X86_64 => 1,
+            #[cfg(feature = "aarch64")]
+            Self::Aarch64 => 64,
+            #[cfg(feature = "arm")]
+            Self::Arm | Self::Thumb => 4,
+            #[cfg(feature = "i386")]
+            Self::I386 => 4,
+            #[cfg(feature = "s390x")]
+            Self::S390x => 4,
+        }
+    }
+
+    /// The instruction set the instructions of the program are logged in, if the
+    /// architecture has several
+    pub fn isa_mode(self) -> Option<&'static str> {
+        match self {
+            Self::X86_64 => None,
+            #[cfg(feature = "aarch64")]
+            Self::Aarch64 => None,
+            #[cfg(feature = "arm")]
+            Self::Arm => Some("arm"),
+            #[cfg(feature = "arm")]
+            Self::Thumb => Some("thumb"),
+            #[cfg(feature = "i386")]
+            Self::I386 => None,
+            #[cfg(feature = "s390x")]
+            Self::S390x => None,
+        }
+    }
+
+    /// The QEMU user mode emulator for the architecture
+    fn qemu(self) -> MemFdExecutable<'static> {
+        match self {
+            Self::X86_64 => MemFdExecutable::new("qemu-x86_64", qemu_x86_64()),
+            #[cfg(feature = "aarch64")]
+            Self::Aarch64 => MemFdExecutable::new("qemu-aarch64", qemu_aarch64()),
+            #[cfg(feature = "arm")]
+            Self::Arm | Self::Thumb => MemFdExecutable::new("qemu-arm", qemu_arm()),
+            #[cfg(feature = "i386")]
+            Self::I386 => MemFdExecutable::new("qemu-i386", qemu_i386()),
+            #[cfg(feature = "s390x")]
+            Self::S390x => MemFdExecutable::new("qemu-s390x", qemu_s390x()),
+        }
+    }
+}
+
+/// The Jaivana plugin, built the first time it is needed
+pub fn plugin() -> io::Result<PathBuf> {
+    static PLUGIN: OnceCell<PathBuf> = OnceCell::new();
+
+    PLUGIN.get_or_try_init(|| build_plugin(None)).cloned()
+}
+
+/// Build the Jaivana plugin with cargo, in the target directory and with the profile the tests
+/// are built with, and return its path. With the `plugin-api-v2` feature, the plugin is built
+/// with it too
+///
+/// # Arguments
+///
+/// * `target` - The target triple to cross-compile the plugin for, like
+///   `aarch64-unknown-linux-gnu`. If not set, the plugin is built for the host
+pub fn build_plugin(target: Option<&str>) -> io::Result<PathBuf> {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("../Cargo.toml");
+    let profile = if cfg!(debug_assertions) {
+        "debug"
+    } else {
+        "release"
+    };
+
+    // Tests run from the deps directory of the profile, in the target directory
+    let exe = current_exe()?;
+    let target_dir = exe
+        .ancestors()
+        .nth(3)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No target directory"))?;
+
+    let mut cargo = Command::new(env!("CARGO"));
+    cargo
+        .arg("build")
+        .arg("--manifest-path")
+        .arg(manifest)
+        .arg("--target-dir")
+        .arg(target_dir)
+        .args(["-p", "jaivana", "--lib"]);
+
+    if cfg!(feature = "plugin-api-v2") {
+        cargo.args(["--features", "plugin-api-v2"]);
+    }
+
+    if profile == "release" {
+        cargo.arg("--release");
+    }
+
+    if let Some(target) = target {
+        cargo.arg("--target").arg(target);
+    }
+
+    if !cargo.status()?.success() {
+        return Err(io::Error::other("Failed to build the plugin"));
+    }
+
+    Ok(target_path(target_dir, target, profile, "jaivana"))
+}
+
+/// A program written to a temporary file, removed when dropped
+pub struct Fixture {
+    /// The architecture of the program
+    pub arch: Arch,
+    /// The program
+    pub path: PathBuf,
+}
+
+impl Fixture {
+    /// Write the program for an architecture
+    ///
+    /// # Arguments
+    ///
+    /// * `arch` - The architecture
+    pub fn new(arch: Arch) -> io::Result<Self> {
+        Self::with_code(arch, arch.code())
+    }
+
+    /// Write the multithreaded program for x86_64. Only `entry` of the addresses in the
+    /// program is meaningful for it
+    ///
+    /// # Arguments
+    ///
+    /// * `threads` - The number of threads, the first one included. At least 2
+    /// * `iterations` - The number of iterations of the loop of each thread
+    pub fn threaded(threads: u32, iterations: u32) -> io::Result<Self> {
+        Self::with_code(Arch::X86_64, &threaded_code(threads, iterations))
+    }
+
+    /// Write a program for an architecture
+    ///
+    /// # Arguments
+    ///
+    /// * `arch` - The architecture
+    /// * `code` - The code of the program
+    fn with_code(arch: Arch, code: &[u8]) -> io::Result<Self> {
+        // Tests run in parallel, so every fixture gets a file of its own
+        static FIXTURES: AtomicUsize = AtomicUsize::new(0);
+
+        let path = temp_dir().join(format!(
+            "cannonball-tests-{}-{}-{}",
+            process::id(),
+            arch.name(),
+            FIXTURES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let headers = arch.headers();
+        let size = headers + code.len() as u64;
+        let entry = BASE + headers;
+
+        let mut elf = Vec::new();
+        let data = if arch.endianness() == "big" { 2 } else { 1 };
+
+        if arch.is_64() {
+            // ELF header: 64-bit, in the byte order of the architecture, a static executable
+            elf.extend([0x7f, b'E', b'L', b'F', 2, data, 1, 0]);
+            elf.extend([0; 8]);
+            arch.put(&mut elf, 2, 2);
+            arch.put(&mut elf, arch.machine().into(), 2);
+            arch.put(&mut elf, 1, 4);
+            arch.put(&mut elf, entry, 8);
+            arch.put(&mut elf, 64, 8);
+            arch.put(&mut elf, 0, 8);
+            arch.put(&mut elf, 0, 4);
+            for field in [64, 56, 1, 64, 0, 0] {
+                arch.put(&mut elf, field, 2);
+            }
+            // One readable and executable segment, mapping the whole file at `BASE`
+            arch.put(&mut elf, 1, 4);
+            arch.put(&mut elf, 5, 4);
+            for field in [0, BASE, BASE, size, size, 0x1000] {
+                arch.put(&mut elf, field, 8);
+            }
+        } else {
+            // Thumb programs are entered at an odd address, which switches to Thumb
+            #[cfg(feature = "arm")]
+            let entry = entry | (arch == Arch::Thumb) as u64;
+
+            // ELF header: 32-bit, in the byte order of the architecture, a static executable
+            elf.extend([0x7f, b'E', b'L', b'F', 1, data, 1, 0]);
+            elf.extend([0; 8]);
+            arch.put(&mut elf, 2, 2);
+            arch.put(&mut elf, arch.machine().into(), 2);
+            arch.put(&mut elf, 1, 4);
+            arch.put(&mut elf, entry, 4);
+            arch.put(&mut elf, 52, 4);
+            arch.put(&mut elf, 0, 4);
+            arch.put(&mut elf, arch.flags(), 4);
+            for field in [52, 32, 1, 40, 0, 0] {
+                arch.put(&mut elf, field, 2);
+            }
+            // One readable and executable segment, mapping the whole file at `BASE`
+            for field in [1, 0, BASE, BASE, size, size, 5, 0x1000] {
+                arch.put(&mut elf, field, 4);
+            }
+        }
+
+        elf.extend(code);
+
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o755)
+            .open(&path)?
+            .write_all(&elf)?;
+
+        Ok(Self { arch, path })
+    }
+
+    /// The address execution starts at
+    pub fn entry(&self) -> u64 {
+        BASE + self.arch.headers()
+    }
+
+    /// The address of the message the program writes, its last 3 bytes
+    pub fn message(&self) -> u64 {
+        BASE + self.arch.headers() + self.arch.code().len() as u64 - 3
+    }
+
+    /// The address of the conditional branch closing the loop
+    pub fn branch(&self) -> u64 {
+        self.entry() + self.arch.branch_offset()
+    }
+
+    /// QEMU, set up to run the program with the plugin
+    ///
+    /// # Arguments
+    ///
+    /// * `plugin_args` - The arguments to pass to the plugin, like `log_pc=true`
+    fn qemu(&self, plugin_args: &[&str]) -> io::Result<MemFdExecutable<'static>> {
+        let mut plugin = plugin()?.canonicalize()?.to_string_lossy().to_string();
+
+        for arg in plugin_args {
+            plugin.push(',');
+            plugin.push_str(arg);
+        }
+
+        let mut exe = self.arch.qemu();
+        exe.arg("-plugin").arg(plugin).arg("--").arg(&self.path);
+
+        Ok(exe)
+    }
+
+    /// Run the program under QEMU with the plugin, and return its exit code if it exited
+    /// normally. The events and the output of the program are discarded
+    ///
+    /// # Arguments
+    ///
+    /// * `plugin_args` - The arguments to pass to the plugin, like `log_pc=true`
+    pub fn run(&self, plugin_args: &[&str]) -> io::Result<Option<i32>> {
+        let mut child = self.qemu(plugin_args)?.stdout(Stdio::null()).spawn()?;
+
+        Ok(child.wait()?.code())
+    }
+
+    /// Run the program under QEMU with the plugin
+    ///
+    /// # Arguments
+    ///
+    /// * `plugin_args` - The arguments to pass to the plugin, like `log_pc=true`
+    pub fn trace(&self, plugin_args: &[&str]) -> io::Result<Trace> {
+        let mut child = self.qemu(plugin_args)?.stdout(Stdio::piped()).spawn()?;
+
+        let mut trace = Trace::default();
+
+        // Events and the output of the program share stdout, like in `Driver::run`
+        for line in BufReader::new(child.stdout.take().expect("Failed to get stdout")).lines() {
+            let line = line?;
+
+            match from_str::<Value>(&line) {
+                Ok(event @ Value::Object(_)) => trace.events.push(event),
+                _ => trace.output.push(line),
+            }
+        }
+
+        trace.code = child.wait()?.code();
+
+        Ok(trace)
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// The events logged running a program, and how it ran
+#[derive(Debug, Default)]
+pub struct Trace {
+    /// The events, in order
+    pub events: Vec<Value>,
+    /// The lines the program wrote to stdout
+    pub output: Vec<String>,
+    /// The exit code of the program, if it exited normally
+    pub code: Option<i32>,
+}
+
+impl Trace {
+    /// The events of a kind, in order
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - The kind of the events
+    pub fn events_of(&self, kind: EventKind) -> impl Iterator<Item = &Value> {
+        self.events
+            .iter()
+            .filter(move |event| EventKind::of(event) == Some(kind))
+    }
+}
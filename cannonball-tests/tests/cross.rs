@@ -0,0 +1,51 @@
+//! Cross-compiling the plugin for other targets than the host, with the `cross` feature
+
+#![cfg(feature = "cross")]
+
+use cannonball_tests::build_plugin;
+use goblin::elf::{
+    header::{EM_AARCH64, EM_RISCV, ET_DYN},
+    Elf,
+};
+
+use std::fs::read;
+
+/// The symbols QEMU looks up in a plugin when loading it
+const EXPORTS: &[&str] = &["qemu_plugin_install", "qemu_plugin_version"];
+
+/// Build the plugin for a target and check it is a shared library for the target's machine,
+/// that QEMU can load
+///
+/// # Arguments
+///
+/// * `target` - The target triple
+/// * `machine` - The ELF machine of the target
+fn plugin_cross_compiles(target: &str, machine: u16) {
+    let plugin = build_plugin(Some(target)).unwrap();
+    let bytes = read(&plugin).unwrap();
+    let elf = Elf::parse(&bytes).unwrap();
+
+    assert_eq!(elf.header.e_type, ET_DYN);
+    assert_eq!(elf.header.e_machine, machine);
+
+    for export in EXPORTS {
+        assert!(
+            elf.dynsyms
+                .iter()
+                .any(|sym| sym.st_value != 0 && elf.dynstrtab.get_at(sym.st_name) == Some(export)),
+            "{} does not export {}",
+            plugin.display(),
+            export
+        );
+    }
+}
+
+#[test]
+fn aarch64_plugin_cross_compiles() {
+    plugin_cross_compiles("aarch64-unknown-linux-gnu", EM_AARCH64);
+}
+
+#[test]
+fn riscv64_plugin_cross_compiles() {
+    plugin_cross_compiles("riscv64gc-unknown-linux-gnu", EM_RISCV);
+}
@@ -0,0 +1,385 @@
+//! The events the plugin logs survive being written out and read back by a consumer, both as
+//! JSON lines on stdout and as the framed CBOR items written to sinks, whatever their fields
+//! hold
+
+use cannonball_events::jaivana::{
+    BlockHitsEvent, BreakpointEvent, CallEvent, Discon, DisconEvent, EdgeEvent, Endianness,
+    ExecEvent, FinalEvent, ForkEvent, FunctionEnterEvent, FunctionExitEvent, HeaderEvent,
+    InsnEvent, InsnMixEvent, Invocation, IsaMode, MapEvent, MemEvent, ProtectEvent, ReturnEvent,
+    RunEndEvent, SignalEvent, StatsEvent, SysMemEvent, SyscallEvent, TBEvent, Trigger,
+    TriggerEvent, UnmapEvent, VcpuEvent, VcpuState, Written,
+};
+use proptest::{
+    collection::{btree_map, hash_map, vec},
+    option,
+    prelude::*,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use std::{collections::HashMap, fmt::Debug};
+
+/// Write an event out like the plugin does and read it back, as a JSON line and as a framed
+/// CBOR item, and check both read back equal to the event
+///
+/// # Arguments
+///
+/// * `event` - The event
+fn round_trips<T: Serialize + DeserializeOwned + PartialEq + Debug>(
+    event: T,
+) -> Result<(), TestCaseError> {
+    let mut line = serde_json::to_vec(&event).unwrap();
+    line.push(b'\n');
+    prop_assert_eq!(&serde_json::from_slice::<T>(&line).unwrap(), &event);
+
+    // Each item is preceded by its length as a little endian u32
+    let mut frame = vec![0; 4];
+    serde_cbor::to_writer(&mut frame, &event).unwrap();
+    let len = (frame.len() - 4) as u32;
+    frame[..4].copy_from_slice(&len.to_le_bytes());
+
+    let (len, item) = frame.split_at(4);
+    prop_assert_eq!(
+        u32::from_le_bytes(len.try_into().unwrap()) as usize,
+        item.len()
+    );
+    prop_assert_eq!(serde_cbor::from_slice::<T>(item).unwrap(), event);
+    Ok(())
+}
+
+/// Names, like those of functions, signals and event types
+fn name() -> impl Strategy<Value = String> {
+    any::<String>()
+}
+
+/// Lists of strings, like command lines and plugin arguments
+fn names() -> impl Strategy<Value = Vec<String>> {
+    vec(name(), 0..8)
+}
+
+/// The number of events logged by type, as in `StatsEvent` and `FinalEvent`
+fn kinds() -> impl Strategy<Value = HashMap<String, u64>> {
+    hash_map(name(), any::<u64>(), 0..8)
+}
+
+/// Values loaded or stored, both those that fit a CBOR integer and the wider ones that do not
+fn value() -> impl Strategy<Value = Option<u128>> {
+    option::of(prop_oneof![
+        any::<u64>().prop_map(u128::from),
+        any::<u128>()
+    ])
+}
+
+/// Instruction events, with or without an opcode and an instruction set
+fn insn() -> impl Strategy<Value = InsnEvent> {
+    (
+        any::<Option<u32>>(),
+        any::<u64>(),
+        option::of(vec(any::<u8>(), 0..16)),
+        any::<bool>(),
+        option::of(prop_oneof![Just(IsaMode::Arm), Just(IsaMode::Thumb)]),
+    )
+        .prop_map(|(vcpu_idx, vaddr, opcode, branch, isa_mode)| {
+            let mut insn = InsnEvent::new(vcpu_idx, vaddr, opcode, branch);
+            insn.isa_mode = isa_mode;
+            insn
+        })
+}
+
+/// Memory accesses of every size, by any instruction
+fn mem() -> impl Strategy<Value = MemEvent> {
+    (
+        any::<u64>(),
+        any::<bool>(),
+        any::<bool>(),
+        any::<bool>(),
+        0u32..5,
+        value(),
+        insn(),
+    )
+        .prop_map(
+            |(vaddr, is_sext, is_be, is_store, size_shift, value, insn)| {
+                MemEvent::new(vaddr, is_sext, is_be, is_store, size_shift, value, insn)
+            },
+        )
+}
+
+/// Headers, of any target and plugin arguments
+fn header() -> impl Strategy<Value = HeaderEvent> {
+    (
+        (
+            option::of(name()),
+            option::of(prop_oneof![Just(Endianness::Little), Just(Endianness::Big)]),
+            any::<Option<u32>>(),
+            any::<Option<i32>>(),
+            any::<Option<i32>>(),
+            any::<i32>(),
+            any::<Option<bool>>(),
+        ),
+        (
+            any::<u64>(),
+            names(),
+            option::of(names()),
+            names(),
+            names(),
+            option::of(names()),
+        ),
+    )
+        .prop_map(
+            |(
+                (
+                    target_name,
+                    endianness,
+                    address_bits,
+                    api_version,
+                    min_api_version,
+                    plugin_api_version,
+                    system_emulation,
+                ),
+                (
+                    sample_rate,
+                    plugin_args,
+                    syscall_filter,
+                    syscall_exclude,
+                    command_line,
+                    environment,
+                ),
+            )| HeaderEvent {
+                target_name,
+                endianness,
+                address_bits,
+                api_version,
+                min_api_version,
+                plugin_api_version,
+                system_emulation,
+                sample_rate,
+                plugin_args,
+                syscall_filter,
+                syscall_exclude,
+                invocation: Invocation {
+                    command_line,
+                    environment,
+                },
+            },
+        )
+}
+
+/// How much a plugin wrote out
+fn written() -> impl Strategy<Value = Written> {
+    any::<[u64; 5]>().prop_map(
+        |[events, bytes, write_nanos, high_water, discarded]| Written {
+            events,
+            bytes,
+            write_nanos,
+            high_water,
+            discarded,
+        },
+    )
+}
+
+/// Statistics of a trace so far
+fn stats() -> impl Strategy<Value = StatsEvent> {
+    (
+        any::<[u64; 6]>(),
+        // Rates are written with as many digits as it takes to read them back exactly, but
+        // serde_json only reads integral ones back exactly without its float_roundtrip feature
+        any::<u32>().prop_map(f64::from),
+        kinds(),
+        any::<u64>(),
+    )
+        .prop_map(
+            |(
+                [elapsed_ms, events, bytes, write_ms, high_water, discarded],
+                events_per_sec,
+                kinds,
+                insn_callbacks,
+            )| StatsEvent {
+                elapsed_ms,
+                events,
+                bytes,
+                events_per_sec,
+                write_ms,
+                high_water,
+                discarded,
+                kinds,
+                insn_callbacks,
+            },
+        )
+}
+
+proptest! {
+    #[test]
+    fn header_round_trips(header in header()) {
+        round_trips(header)?;
+    }
+
+    #[test]
+    fn insn_round_trips(insn in insn()) {
+        round_trips(insn)?;
+    }
+
+    #[test]
+    fn tb_round_trips(vcpu_idx: Option<u32>, vaddr: u64, size: usize, n_insns: usize) {
+        round_trips(TBEvent::new(vcpu_idx, vaddr, size, n_insns))?;
+    }
+
+    #[test]
+    fn block_hits_round_trip(vaddr: u64, size: usize, n_insns: usize, hits: u64) {
+        round_trips(BlockHitsEvent::new(vaddr, size, n_insns, hits))?;
+    }
+
+    #[test]
+    fn edge_round_trips(vcpu_idx: u32, src: u64, dst: u64) {
+        round_trips(EdgeEvent::new(vcpu_idx, src, dst))?;
+    }
+
+    #[test]
+    fn call_and_return_round_trip(vcpu_idx: u32, site: u64, target: u64, depth: i64) {
+        round_trips(CallEvent::new(vcpu_idx, site, target, depth))?;
+        round_trips(ReturnEvent::new(vcpu_idx, site, target, depth))?;
+    }
+
+    #[test]
+    fn function_events_round_trip(vcpu_idx: Option<u32>, function in name(), addr: u64) {
+        round_trips(FunctionEnterEvent::new(vcpu_idx, function.clone(), addr))?;
+        round_trips(FunctionExitEvent::new(vcpu_idx, function, addr))?;
+    }
+
+    #[test]
+    fn trigger_round_trips(
+        vcpu_idx: Option<u32>,
+        pc: u64,
+        trigger in prop_oneof![Just(Trigger::Start), Just(Trigger::Stop)],
+    ) {
+        round_trips(TriggerEvent::new(vcpu_idx, pc, trigger))?;
+    }
+
+    #[test]
+    fn breakpoint_round_trips(vcpu_idx: Option<u32>, pc: u64, paused: bool) {
+        round_trips(BreakpointEvent::new(vcpu_idx, pc, paused))?;
+    }
+
+    #[test]
+    fn discon_round_trips(
+        vcpu_idx: Option<u32>,
+        discon in prop_oneof![
+            Just(Discon::Interrupt),
+            Just(Discon::Exception),
+            Just(Discon::Hostcall)
+        ],
+        from_pc: u64,
+        to_pc: u64,
+    ) {
+        round_trips(DisconEvent::new(vcpu_idx, discon, from_pc, to_pc))?;
+    }
+
+    #[test]
+    fn mem_round_trips(mem in mem(), paddr: u64, is_io: bool) {
+        round_trips(mem.clone())?;
+        round_trips(SysMemEvent::new(paddr, is_io, mem))?;
+    }
+
+    #[test]
+    fn map_events_round_trip(
+        vcpu_idx: Option<u32>,
+        addr: u64,
+        len: u64,
+        prot: u64,
+        flags: u64,
+        fd: i32,
+        offset: u64,
+    ) {
+        round_trips(MapEvent::new(vcpu_idx, addr, len, prot, flags, fd, offset))?;
+        round_trips(UnmapEvent::new(vcpu_idx, addr, len))?;
+        round_trips(ProtectEvent::new(vcpu_idx, addr, len, prot))?;
+    }
+
+    #[test]
+    fn syscall_round_trips(num: i64, rv: Option<i64>, args in vec(any::<u64>(), 0..8)) {
+        round_trips(SyscallEvent::new(num, rv, args))?;
+    }
+
+    #[test]
+    fn process_events_round_trip(
+        vcpu_idx: Option<u32>,
+        parent: u32,
+        child: u32,
+        num: i64,
+        pathname: u64,
+        argv: u64,
+        run: u64,
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+    ) {
+        round_trips(ForkEvent::new(vcpu_idx, parent, child))?;
+        round_trips(ExecEvent::new(vcpu_idx, num, pathname, argv))?;
+        round_trips(RunEndEvent::new(run, child, exit_code, signal))?;
+    }
+
+    #[test]
+    fn signal_round_trips(
+        vcpu_idx: Option<u32>,
+        signal: Option<i32>,
+        name in option::of(name()),
+        pc: Option<u64>,
+        handler: Option<u64>,
+        fatal: bool,
+    ) {
+        round_trips(SignalEvent::new(vcpu_idx, signal, name, pc, handler, fatal))?;
+    }
+
+    #[test]
+    fn vcpu_round_trips(
+        vcpu_idx: Option<u32>,
+        vcpu in prop_oneof![
+            Just(VcpuState::Init),
+            Just(VcpuState::Exit),
+            Just(VcpuState::Idle),
+            Just(VcpuState::Resume)
+        ],
+    ) {
+        round_trips(VcpuEvent::new(vcpu_idx, vcpu))?;
+    }
+
+    #[test]
+    fn final_round_trips(
+        exit_code: Option<i32>,
+        signaled: bool,
+        signal: Option<i32>,
+        signal_name in option::of(name()),
+        insns: u64,
+        events: u64,
+        kinds in kinds(),
+    ) {
+        round_trips(FinalEvent::new(
+            exit_code,
+            signaled,
+            signal,
+            signal_name,
+            insns,
+            events,
+            kinds,
+        ))?;
+    }
+
+    #[test]
+    fn report_events_round_trip(
+        written in written(),
+        stats in stats(),
+        classes in btree_map(name(), any::<u32>().prop_map(u64::from), 0..12),
+    ) {
+        round_trips(written)?;
+        round_trips(stats)?;
+        round_trips(InsnMixEvent::new(classes))?;
+    }
+}
+
+#[test]
+fn header_without_newer_fields_reads() {
+    let line = r#"{"target_name":"x86_64","api_version":null,"min_api_version":null,"plugin_api_version":1,"system_emulation":null,"sample_rate":1,"plugin_args":[],"command_line":["qemu-x86_64"],"environment":null}"#;
+    let header: HeaderEvent = serde_json::from_str(line).unwrap();
+
+    assert_eq!(header.endianness, None);
+    assert_eq!(header.address_bits, None);
+    assert_eq!(header.syscall_filter, None);
+    assert!(header.syscall_exclude.is_empty());
+}
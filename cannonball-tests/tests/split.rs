@@ -0,0 +1,96 @@
+//! Traces of a program that forked split into a trace per process, each of which reads back on
+//! its own
+
+use cannonball_tools::{
+    output::{EventWriter, OutputFormat},
+    split::PidSplitter,
+    trace::{EventKind, TraceReader},
+};
+use serde_json::{json, Value};
+
+use std::{
+    env::temp_dir,
+    fs::{remove_file, File},
+    io::{self, BufReader},
+    path::Path,
+    process,
+};
+
+/// The PID of the traced program
+const PARENT: u32 = 100;
+
+/// The PID of the child it forked
+const CHILD: u32 = 200;
+
+/// The events of a program that forked, tagged with their PIDs like with `tag_pids=on`, and
+/// one that is not
+fn forked() -> Vec<Value> {
+    vec![
+        json!({"target_name": "x86_64", "plugin_api_version": 1, "sample_rate": 1, "plugin_args": ["tag_pids=true"], "pid": PARENT}),
+        json!({"vcpu_idx": 0, "vaddr": 0x401000, "branch": false, "pid": PARENT}),
+        json!({"vcpu_idx": 0, "parent": PARENT, "child": CHILD, "pid": CHILD}),
+        json!({"vcpu_idx": 0, "vaddr": 0x401010, "branch": true, "pid": CHILD}),
+        json!({"vcpu_idx": 0, "vaddr": 0x401004, "branch": false}),
+        json!({"vcpu_idx": 0, "vaddr": 0x401008, "branch": true, "pid": PARENT}),
+    ]
+}
+
+/// Read a trace back
+///
+/// # Arguments
+///
+/// * `path` - The trace
+/// * `format` - Its format
+fn read(path: &Path, format: OutputFormat) -> Vec<Value> {
+    TraceReader::new(BufReader::new(File::open(path).unwrap()), format)
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+#[test]
+fn trace_splits_per_process() {
+    let events = forked();
+
+    for format in [OutputFormat::Json, OutputFormat::Binary] {
+        let path = temp_dir().join(format!(
+            "cannonball-tests-{}-split-{:?}",
+            process::id(),
+            format
+        ));
+        let mut splitter = PidSplitter::new(&path, format, None);
+
+        for event in &events {
+            splitter.write(event).unwrap();
+        }
+        splitter.finish().unwrap();
+
+        assert_eq!(splitter.pids().collect::<Vec<_>>(), [PARENT, CHILD]);
+
+        let parent = read(&PidSplitter::path_of(&path, PARENT), format);
+        let child = read(&PidSplitter::path_of(&path, CHILD), format);
+
+        // The event without a PID is the first process'
+        assert_eq!(parent, [0, 1, 4, 5].map(|i| events[i].clone()));
+        assert_eq!(child, [0, 2, 3].map(|i| events[i].clone()));
+        assert_eq!(EventKind::of(&child[0]), Some(EventKind::Header));
+        assert_eq!(EventKind::of(&child[1]), Some(EventKind::Fork));
+
+        for pid in [PARENT, CHILD] {
+            remove_file(PidSplitter::path_of(&path, pid)).unwrap();
+        }
+    }
+}
+
+#[test]
+fn untagged_trace_does_not_split() {
+    let path = temp_dir().join(format!("cannonball-tests-{}-untagged", process::id()));
+    let mut splitter = PidSplitter::new(&path, OutputFormat::Json, None);
+
+    let error = splitter
+        .write(&json!({"vcpu_idx": 0, "vaddr": 0x401000, "branch": false}))
+        .unwrap_err();
+
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(splitter.pids().count(), 0);
+}
@@ -0,0 +1,441 @@
+//! Properties of the events logged running the fixture programs on each architecture
+
+use cannonball_tests::{Arch, Fixture, Trace, LOOP};
+use cannonball_tools::{
+    analyze::Analyzer,
+    merge::merge,
+    metadata::RunMetadata,
+    output::OutputFormat,
+    strace::Strace,
+    trace::{rotated_files, EventKind, TraceReader},
+};
+use serde_json::{json, Value};
+
+use std::{env::temp_dir, fs::remove_file, process};
+
+/// The arguments the fixtures are traced with
+const PLUGIN_ARGS: &[&str] = &["log_pc=true", "log_branch=true", "log_syscall=true"];
+
+/// Trace the fixture for an architecture
+///
+/// # Arguments
+///
+/// * `arch` - The architecture
+fn trace(arch: Arch) -> (Fixture, Trace) {
+    let fixture = Fixture::new(arch).unwrap();
+    let trace = fixture.trace(PLUGIN_ARGS).unwrap();
+    (fixture, trace)
+}
+
+fn program_runs(arch: Arch) {
+    let (_, trace) = trace(arch);
+
+    assert_eq!(trace.code, Some(0));
+    assert!(trace.output.iter().any(|line| line == "hi"));
+    assert_eq!(trace.events_of(EventKind::Header).count(), 1);
+}
+
+fn entry_pc_is_traced(arch: Arch) {
+    let (fixture, trace) = trace(arch);
+    let first = trace.events_of(EventKind::Insn).next().unwrap();
+
+    assert_eq!(first["vaddr"].as_u64(), Some(fixture.entry()));
+}
+
+fn write_syscall_is_traced(arch: Arch) {
+    let (_, trace) = trace(arch);
+    let write = trace
+        .events_of(EventKind::Syscall)
+        .find(|event| event["num"].as_i64() == Some(arch.write_syscall()))
+        .unwrap();
+
+    assert_eq!(write["args"][0].as_u64(), Some(1));
+    assert_eq!(write["args"][2].as_u64(), Some(3));
+    assert_eq!(write["rv"].as_i64(), Some(3));
+}
+
+fn branches_are_traced(arch: Arch) {
+    let (fixture, trace) = trace(arch);
+    let branches = trace
+        .events_of(EventKind::Insn)
+        .filter(|event| event["branch"].as_bool() == Some(true))
+        .count();
+    let loop_branches = trace
+        .events_of(EventKind::Insn)
+        .filter(|event| event["vaddr"].as_u64() == Some(fixture.branch()))
+        .count();
+
+    assert_ne!(branches, 0);
+    assert_eq!(loop_branches, LOOP);
+}
+
+fn isa_mode_is_traced(arch: Arch) {
+    let (_, trace) = trace(arch);
+
+    assert!(trace
+        .events_of(EventKind::Insn)
+        .all(|event| event.get("isa_mode").and_then(|mode| mode.as_str()) == arch.isa_mode()));
+}
+
+fn endianness_is_traced(arch: Arch) {
+    let (_, trace) = trace(arch);
+    let header = trace.events_of(EventKind::Header).next().unwrap();
+
+    assert_eq!(header["endianness"].as_str(), Some(arch.endianness()));
+}
+
+fn address_bits_are_traced(arch: Arch) {
+    let (fixture, trace) = trace(arch);
+    let header = trace.events_of(EventKind::Header).next().unwrap();
+    let write = trace
+        .events_of(EventKind::Syscall)
+        .find(|event| event["num"].as_i64() == Some(arch.write_syscall()))
+        .unwrap();
+
+    assert_eq!(
+        header["address_bits"].as_u64(),
+        Some(arch.address_bits().into())
+    );
+    assert_eq!(write["args"][1].as_u64(), Some(fixture.message()));
+}
+
+fn syscall_args_fit_address_bits(arch: Arch) {
+    let (_, trace) = trace(arch);
+    let mut strace = Strace::new();
+
+    for event in &trace.events {
+        strace.on_event(event);
+    }
+
+    assert!(!strace.lines.is_empty());
+
+    for line in &strace.lines {
+        let (_, args) = line.split_once('(').unwrap();
+        let (args, _) = args.split_once(')').unwrap();
+
+        for arg in args.split(", ") {
+            let arg = u64::from_str_radix(arg.trim_start_matches("0x"), 16).unwrap();
+            assert!(arg <= u64::MAX >> (64 - arch.address_bits()), "{}", line);
+        }
+    }
+}
+
+fn traces_merge(arch: Arch) {
+    let fixture = Fixture::new(arch).unwrap();
+    let args = [PLUGIN_ARGS, &["tag_time=true"]].concat();
+    let traces = [fixture.trace(&args).unwrap(), fixture.trace(&args).unwrap()];
+    let merged = merge(traces.iter().map(|trace| trace.events.clone()));
+    let times = merged
+        .iter()
+        .skip(2)
+        .map(|event| event["time_ns"].as_u64().unwrap())
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        merged.len(),
+        traces[0].events.len() + traces[1].events.len()
+    );
+    assert_eq!(merged[0]["stream"].as_u64(), Some(0));
+    assert_eq!(merged[1]["stream"].as_u64(), Some(1));
+    assert!(merged[..2]
+        .iter()
+        .all(|event| EventKind::of(event) == Some(EventKind::Header)));
+    assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+}
+
+fn trace_round_trips(arch: Arch) {
+    let (_, trace) = trace(arch);
+    let metadata = RunMetadata::new(
+        arch.name().to_string(),
+        Vec::new(),
+        PLUGIN_ARGS.iter().map(|arg| arg.to_string()).collect(),
+        None,
+        None,
+        None,
+        None,
+    );
+
+    // The binary formats frame events and encode addresses on their own, whatever the byte
+    // order of the guest
+    for format in [
+        OutputFormat::Cbor,
+        OutputFormat::Binary,
+        OutputFormat::Compact,
+        OutputFormat::CompactRepeat,
+    ] {
+        let path = temp_dir().join(format!(
+            "cannonball-tests-{}-{}-{:?}",
+            process::id(),
+            arch.name(),
+            format
+        ));
+        let mut writer = format.create_with_metadata(&path, &metadata).unwrap();
+
+        for event in &trace.events {
+            writer.write(event).unwrap();
+        }
+
+        writer.finish().unwrap();
+        drop(writer);
+
+        let reader = TraceReader::open(&path, format).unwrap();
+        let read_metadata = reader.metadata().cloned();
+        let events = reader.collect::<Result<Vec<_>, _>>();
+        let _ = remove_file(&path);
+
+        assert_eq!(read_metadata.as_ref(), Some(&metadata), "{:?}", format);
+        assert_eq!(events.unwrap(), trace.events, "{:?}", format);
+    }
+}
+
+fn sink_rotates(arch: Arch) {
+    let (fixture, trace) = trace(arch);
+    let path = temp_dir().join(format!(
+        "cannonball-tests-{}-{}-rotated",
+        process::id(),
+        arch.name()
+    ));
+    let sink = format!("sink=file:{}", path.to_string_lossy());
+    // Every batch of a single event starts a new file
+    let args = [PLUGIN_ARGS, &[&sink, "sink_rotate_size=1", "batch_size=1"]].concat();
+    let sunk = fixture.trace(&args).unwrap();
+    let files = rotated_files(&path).unwrap();
+    let last = TraceReader::open(files.last().unwrap(), OutputFormat::Binary)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+    let events = TraceReader::open_rotated(&path)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>();
+
+    for file in &files {
+        let _ = remove_file(file);
+    }
+
+    let not_header = |event: &&Value| EventKind::of(event) != Some(EventKind::Header);
+    let events = events.unwrap();
+
+    assert!(sunk.events.is_empty());
+    assert!(files.len() > 1);
+    assert_eq!(EventKind::of(&last), Some(EventKind::Header));
+    assert_eq!(EventKind::of(&events[0]), Some(EventKind::Header));
+    assert!(events
+        .iter()
+        .filter(not_header)
+        .eq(trace.events.iter().filter(not_header)));
+}
+
+/// The number of instruction callbacks the plugin registered to log instruction events,
+/// reported by the statistics logged when QEMU exits
+///
+/// # Arguments
+///
+/// * `fixture` - The fixture
+/// * `plugin_args` - The arguments selecting the events logged
+fn insn_callbacks(fixture: &Fixture, plugin_args: &[&str]) -> (Trace, u64) {
+    let trace = fixture
+        .trace(&[plugin_args, &["stats=on"]].concat())
+        .unwrap();
+    let callbacks = trace
+        .events_of(EventKind::Stats)
+        .last()
+        .and_then(|stats| stats["insn_callbacks"].as_u64())
+        .unwrap();
+
+    (trace, callbacks)
+}
+
+fn event_flags_select_callbacks(arch: Arch) {
+    let fixture = Fixture::new(arch).unwrap();
+    let (none, none_callbacks) = insn_callbacks(&fixture, &[]);
+    let (mem, mem_callbacks) = insn_callbacks(&fixture, &["log_mem=on"]);
+    let (branch, branch_callbacks) = insn_callbacks(&fixture, &["log_branch=on"]);
+    let (pc, pc_callbacks) = insn_callbacks(&fixture, &["log_pc=on"]);
+
+    // Without instruction events, no instruction gets a callback logging them
+    assert_eq!(none_callbacks, 0);
+    assert_eq!(mem_callbacks, 0);
+    assert_eq!(none.events_of(EventKind::Insn).count(), 0);
+    assert_eq!(mem.events_of(EventKind::Insn).count(), 0);
+
+    // Only the last instruction of each block gets one when logging branches
+    assert!(branch_callbacks > 0);
+    assert!(branch_callbacks < pc_callbacks);
+    assert!(branch
+        .events_of(EventKind::Insn)
+        .all(|event| event["branch"].as_bool() == Some(true)));
+    assert!(pc.events_of(EventKind::Insn).count() > branch.events_of(EventKind::Insn).count());
+}
+
+fn other_modules_are_not_traced(arch: Arch) {
+    let fixture = Fixture::new(arch).unwrap();
+    let trace = fixture
+        .trace(&[PLUGIN_ARGS, &["trace_modules=libfoo.so"]].concat())
+        .unwrap();
+
+    // The static fixture maps no library, so none of its code is instrumented
+    assert_eq!(trace.code, Some(0));
+    assert!(trace.output.iter().any(|line| line == "hi"));
+    assert_eq!(trace.events_of(EventKind::Insn).count(), 0);
+    assert!(trace
+        .events_of(EventKind::Syscall)
+        .any(|event| event["num"].as_i64() == Some(arch.write_syscall())));
+}
+
+fn main_module_is_traced(arch: Arch) {
+    let fixture = Fixture::new(arch).unwrap();
+    let trace = fixture
+        .trace(&[PLUGIN_ARGS, &["trace_modules=main"]].concat())
+        .unwrap();
+
+    // Where the program is loaded is only known with plugin API version 2
+    if cfg!(not(feature = "plugin-api-v2")) {
+        assert_ne!(trace.code, Some(0));
+        assert!(trace.events.is_empty());
+        assert!(trace.output.is_empty());
+        return;
+    }
+
+    // Every instruction the fixture executes is in the program, and none is in a library like
+    // the C library or in the dynamic loader
+    let program = fixture.entry()..fixture.message() + 3;
+    assert_eq!(trace.code, Some(0));
+    assert!(trace.output.iter().any(|line| line == "hi"));
+    assert!(trace.events_of(EventKind::Insn).count() > 0);
+    assert!(trace
+        .events_of(EventKind::Insn)
+        .all(|event| program.contains(&event["vaddr"].as_u64().unwrap())));
+}
+
+fn syscalls_are_filtered(arch: Arch) {
+    let fixture = Fixture::new(arch).unwrap();
+    let excluded = fixture
+        .trace(&[PLUGIN_ARGS, &["syscall_exclude=write"]].concat())
+        .unwrap();
+    let filtered = fixture
+        .trace(&[PLUGIN_ARGS, &["syscall_filter=write"]].concat())
+        .unwrap();
+
+    // There is no table of the system calls of s390x to look their names up in, so selecting
+    // them by name fails the installation of the plugin
+    if arch.name() == "s390x" {
+        for trace in [excluded, filtered] {
+            assert_ne!(trace.code, Some(0));
+            assert!(trace.events.is_empty());
+            assert!(trace.output.is_empty());
+        }
+        return;
+    }
+    let is_write = |event: &Value| event["num"].as_i64() == Some(arch.write_syscall());
+
+    assert_eq!(excluded.code, Some(0));
+    assert!(excluded.output.iter().any(|line| line == "hi"));
+    assert!(!excluded.events_of(EventKind::Syscall).any(is_write));
+    assert!(filtered.events_of(EventKind::Syscall).any(is_write));
+    assert!(filtered.events_of(EventKind::Syscall).all(is_write));
+
+    // The header lists the system calls selected
+    let header = excluded.events_of(EventKind::Header).next().unwrap();
+    assert_eq!(header["syscall_exclude"], json!(["write"]));
+    assert!(header["syscall_filter"].is_null());
+
+    let header = filtered.events_of(EventKind::Header).next().unwrap();
+    assert_eq!(header["syscall_filter"], json!(["write"]));
+}
+
+/// The tests for an architecture, in a module of its own
+macro_rules! arch_tests {
+    ($name:ident, $arch:expr) => {
+        mod $name {
+            use super::*;
+
+            #[test]
+            fn program_runs() {
+                super::program_runs($arch);
+            }
+
+            #[test]
+            fn entry_pc_is_traced() {
+                super::entry_pc_is_traced($arch);
+            }
+
+            #[test]
+            fn write_syscall_is_traced() {
+                super::write_syscall_is_traced($arch);
+            }
+
+            #[test]
+            fn branches_are_traced() {
+                super::branches_are_traced($arch);
+            }
+
+            #[test]
+            fn isa_mode_is_traced() {
+                super::isa_mode_is_traced($arch);
+            }
+
+            #[test]
+            fn endianness_is_traced() {
+                super::endianness_is_traced($arch);
+            }
+
+            #[test]
+            fn address_bits_are_traced() {
+                super::address_bits_are_traced($arch);
+            }
+
+            #[test]
+            fn syscall_args_fit_address_bits() {
+                super::syscall_args_fit_address_bits($arch);
+            }
+
+            #[test]
+            fn traces_merge() {
+                super::traces_merge($arch);
+            }
+
+            #[test]
+            fn trace_round_trips() {
+                super::trace_round_trips($arch);
+            }
+
+            #[test]
+            fn sink_rotates() {
+                super::sink_rotates($arch);
+            }
+
+            #[test]
+            fn event_flags_select_callbacks() {
+                super::event_flags_select_callbacks($arch);
+            }
+
+            #[test]
+            fn other_modules_are_not_traced() {
+                super::other_modules_are_not_traced($arch);
+            }
+
+            #[test]
+            fn main_module_is_traced() {
+                super::main_module_is_traced($arch);
+            }
+
+            #[test]
+            fn syscalls_are_filtered() {
+                super::syscalls_are_filtered($arch);
+            }
+        }
+    };
+}
+
+arch_tests!(x86_64, Arch::X86_64);
+#[cfg(feature = "aarch64")]
+arch_tests!(aarch64, Arch::Aarch64);
+#[cfg(feature = "arm")]
+arch_tests!(arm, Arch::Arm);
+#[cfg(feature = "arm")]
+arch_tests!(thumb, Arch::Thumb);
+#[cfg(feature = "i386")]
+arch_tests!(i386, Arch::I386);
+#[cfg(feature = "s390x")]
+arch_tests!(s390x, Arch::S390x);
@@ -0,0 +1,532 @@
+//! Analyzers
+//!
+//! An `Analyzer` consumes the events of a trace one at a time and reports on them at the end,
+//! like the analyses of `cover`, `profile`, `strace` and `diff`, which all implement it. Each
+//! analyzer gets the header of the trace first, to learn how it was recorded, then every
+//! other event in order, and finally produces a `Report`: text for people, and the same
+//! findings as a value for tools.
+//!
+//! Analyzers are created by name from a `Registry`, which is how the `analyze` command finds
+//! them. Analyzers written outside of this crate are added to it from a shared library. Rust
+//! types are not passed between the library and the `cannonball` binary, since their layout
+//! changes from one compiler to the next, so the library only talks to it over the C ABI: it
+//! exports `cannonball_analyzer_plugin`, an `extern "C"` function returning an
+//! `AnalyzerPlugin`, which lists the `AnalyzerVTable` of each of its analyzers. Events and
+//! reports cross as JSON. The plugin tells the version of these types it was built for, and a
+//! library built for another version than `PLUGIN_VERSION` is refused. The library may be
+//! written in any language, or in Rust with any compiler:
+//!
+//! ```no_run
+//! use cannonball_tools::analyze::{AnalyzerPlugin, AnalyzerVTable, ReportFn, PLUGIN_VERSION};
+//!
+//! use std::ffi::{c_char, c_void};
+//!
+//! unsafe extern "C" fn create(_arg: *const c_char) -> *mut c_void {
+//!     Box::into_raw(Box::new(0u64)).cast()
+//! }
+//!
+//! unsafe extern "C" fn on_start(_count: *mut c_void, _header: *const u8, _len: usize) {}
+//!
+//! unsafe extern "C" fn on_event(count: *mut c_void, _event: *const u8, _len: usize) {
+//!     *count.cast::<u64>() += 1;
+//! }
+//!
+//! unsafe extern "C" fn on_end(count: *mut c_void, report: ReportFn, context: *mut c_void) {
+//!     let count = *count.cast::<u64>();
+//!     let (text, data) = (format!("{} events", count), count.to_string());
+//!     report(context, text.as_ptr(), text.len(), data.as_ptr(), data.len());
+//! }
+//!
+//! unsafe extern "C" fn destroy(count: *mut c_void) {
+//!     drop(Box::from_raw(count.cast::<u64>()));
+//! }
+//!
+//! static ANALYZERS: [AnalyzerVTable; 1] = [AnalyzerVTable {
+//!     name: c"count".as_ptr(),
+//!     description: c"Count the events of a trace".as_ptr(),
+//!     create,
+//!     on_start,
+//!     on_event,
+//!     on_end,
+//!     destroy,
+//! }];
+//!
+//! static PLUGIN: AnalyzerPlugin = AnalyzerPlugin {
+//!     version: PLUGIN_VERSION,
+//!     analyzers: ANALYZERS.as_ptr(),
+//!     count: ANALYZERS.len(),
+//! };
+//!
+//! #[no_mangle]
+//! pub extern "C" fn cannonball_analyzer_plugin() -> *const AnalyzerPlugin {
+//!     &PLUGIN
+//! }
+//! ```
+//!
+//! Built as a `cdylib`, it is loaded with `cannonball analyze --load libcount.so -a count`.
+
+use serde_json::Value;
+
+use std::{
+    collections::BTreeMap,
+    ffi::{c_char, c_void},
+    fs::File,
+    io::{self, BufReader, ErrorKind},
+};
+
+use crate::{
+    cover::Coverage,
+    diff::Diff,
+    maps::Wx,
+    profile::Profile,
+    strace::Strace,
+    trace::{events, EventKind},
+};
+
+/// The function a shared library of analyzers exports, returning its `AnalyzerPlugin`
+pub const PLUGIN_SYMBOL: &str = "cannonball_analyzer_plugin";
+
+/// The version of `AnalyzerPlugin` and `AnalyzerVTable`, which changes whenever their layout
+/// or the meaning of their fields does
+pub const PLUGIN_VERSION: u32 = 1;
+
+/// Hands the report of an analyzer of a shared library to the `cannonball` binary, which copies
+/// it. Called with the context `on_end` got, the text of the report in UTF-8 and its data as
+/// JSON, each as a pointer and a length
+pub type ReportFn = unsafe extern "C" fn(
+    context: *mut c_void,
+    text: *const u8,
+    text_len: usize,
+    data: *const u8,
+    data_len: usize,
+);
+
+/// An analyzer of a shared library, as functions over the state of each analyzer it creates.
+/// Events are passed as JSON, as a pointer and a length, and only for the duration of the call
+#[repr(C)]
+pub struct AnalyzerVTable {
+    /// The name to create the analyzer with, NUL terminated
+    pub name: *const c_char,
+    /// What the analyzer reports, NUL terminated
+    pub description: *const c_char,
+    /// Create the state of an analyzer, given the argument of its name, NUL terminated, or null
+    /// if it has none. Returns null if the argument is invalid
+    pub create: unsafe extern "C" fn(arg: *const c_char) -> *mut c_void,
+    /// Called once before any event with the header of the trace, or null if it has none
+    pub on_start: unsafe extern "C" fn(analyzer: *mut c_void, header: *const u8, len: usize),
+    /// Called with each event of the trace but its header, in order
+    pub on_event: unsafe extern "C" fn(analyzer: *mut c_void, event: *const u8, len: usize),
+    /// Called once after every event, to call `report` with the report and `context`
+    pub on_end: unsafe extern "C" fn(analyzer: *mut c_void, report: ReportFn, context: *mut c_void),
+    /// Free the state of an analyzer, after `on_end`
+    pub destroy: unsafe extern "C" fn(analyzer: *mut c_void),
+}
+
+// The pointers are to static strings, so tables can be statics of the library
+unsafe impl Sync for AnalyzerVTable {}
+
+/// The analyzers of a shared library, returned by the function it exports as `PLUGIN_SYMBOL`
+#[repr(C)]
+pub struct AnalyzerPlugin {
+    /// The `PLUGIN_VERSION` the library was built for
+    pub version: u32,
+    /// The analyzers, an array of `count` tables
+    pub analyzers: *const AnalyzerVTable,
+    /// The number of analyzers
+    pub count: usize,
+}
+
+// The pointer is to a static array, so plugins can be statics of the library
+unsafe impl Sync for AnalyzerPlugin {}
+
+/// The items at a pointer, or none if it is null
+///
+/// # Arguments
+///
+/// * `data` - The pointer
+/// * `len` - The number of items
+#[cfg(feature = "driver")]
+unsafe fn items<'a, T>(data: *const T, len: usize) -> &'a [T] {
+    if data.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data, len)
+    }
+}
+
+/// An analyzer created by a shared library
+#[cfg(feature = "driver")]
+struct PluginAnalyzer {
+    /// The functions of the analyzer, in the library, which stays loaded
+    vtable: &'static AnalyzerVTable,
+    /// The state of the analyzer
+    analyzer: *mut c_void,
+}
+
+#[cfg(feature = "driver")]
+impl Analyzer for PluginAnalyzer {
+    fn on_start(&mut self, header: Option<&Value>) {
+        let header = header.map(|header| header.to_string());
+        let (data, len) = header.as_ref().map_or((std::ptr::null(), 0), |header| {
+            (header.as_ptr(), header.len())
+        });
+        unsafe { (self.vtable.on_start)(self.analyzer, data, len) };
+    }
+
+    fn on_event(&mut self, event: &Value) {
+        let event = event.to_string();
+        unsafe { (self.vtable.on_event)(self.analyzer, event.as_ptr(), event.len()) };
+    }
+
+    fn on_end(&mut self) -> Report {
+        unsafe extern "C" fn receive(
+            context: *mut c_void,
+            text: *const u8,
+            text_len: usize,
+            data: *const u8,
+            data_len: usize,
+        ) {
+            let report = &mut *context.cast::<Report>();
+            report.text = String::from_utf8_lossy(items(text, text_len)).to_string();
+            // Data that is not JSON is reported as text rather than lost
+            let data = items(data, data_len);
+            report.data = serde_json::from_slice(data)
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(data).to_string()));
+        }
+
+        let mut report = Report::new(String::new(), Value::Null);
+        unsafe {
+            (self.vtable.on_end)(self.analyzer, receive, (&mut report as *mut Report).cast())
+        };
+        report
+    }
+}
+
+#[cfg(feature = "driver")]
+impl Drop for PluginAnalyzer {
+    fn drop(&mut self) {
+        unsafe { (self.vtable.destroy)(self.analyzer) };
+    }
+}
+
+/// What an analyzer found in a trace
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    /// The findings, formatted for people
+    pub text: String,
+    /// The findings, for tools
+    pub data: Value,
+}
+
+impl Report {
+    /// Instantiate a new `Report`
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The findings, formatted for people
+    /// * `data` - The findings, for tools
+    pub fn new(text: impl Into<String>, data: Value) -> Self {
+        Self {
+            text: text.into(),
+            data,
+        }
+    }
+}
+
+/// Consumes the events of a trace and reports on them
+pub trait Analyzer {
+    /// Called once before any event
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - The header of the trace, if it has one
+    fn on_start(&mut self, header: Option<&Value>) {
+        let _ = header;
+    }
+
+    /// Called with each event of the trace but its header, in order
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    fn on_event(&mut self, event: &Value);
+
+    /// Called once after every event, to report on them
+    fn on_end(&mut self) -> Report;
+}
+
+/// Creates an analyzer, given the argument of its name, like the `other.trace` of
+/// `diff=other.trace`
+pub type Factory = Box<dyn Fn(Option<&str>) -> io::Result<Box<dyn Analyzer>>>;
+
+/// Analyzers by name
+pub struct Registry {
+    /// The description and factory of each analyzer
+    analyzers: BTreeMap<String, (String, Factory)>,
+}
+
+impl Default for Registry {
+    /// The analyzers of this crate
+    fn default() -> Self {
+        let mut registry = Self::new();
+
+        registry.register("cover", "The translation blocks executed", |_| {
+            Ok(Box::new(Coverage::new()))
+        });
+        registry.register(
+            "profile",
+            "The functions and translation blocks the most instructions were spent in",
+            |_| Ok(Box::new(Profile::new())),
+        );
+        registry.register("strace", "The system calls made", |_| {
+            Ok(Box::new(Strace::new()))
+        });
+        registry.register(
+            "diff",
+            "The first point where the trace executed different code than the one given, as diff=<trace>",
+            |reference| {
+                let reference = reference.ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidInput, "diff needs a trace, as diff=<trace>")
+                })?;
+                let reference = events(BufReader::new(File::open(reference)?))
+                    .collect::<io::Result<Vec<_>>>()?;
+                Ok(Box::new(Diff::new(reference)))
+            },
+        );
+        registry.register(
+            "wx",
+            "Memory mapped writable and executable, and code executed from writable memory, with --maps",
+            |_| Ok(Box::new(Wx::new())),
+        );
+
+        registry
+    }
+}
+
+impl Registry {
+    /// Instantiate a new `Registry` without analyzers. `Registry::default` has those of this
+    /// crate
+    pub fn new() -> Self {
+        Self {
+            analyzers: BTreeMap::new(),
+        }
+    }
+
+    /// Add an analyzer, replacing any with the same name
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name to create the analyzer with
+    /// * `description` - What the analyzer reports
+    /// * `factory` - Creates the analyzer
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        factory: impl Fn(Option<&str>) -> io::Result<Box<dyn Analyzer>> + 'static,
+    ) {
+        self.analyzers
+            .insert(name.into(), (description.into(), Box::new(factory)));
+    }
+
+    /// Add the analyzers of a shared library, listed by the `AnalyzerPlugin` the function it
+    /// exports as `PLUGIN_SYMBOL` returns. The library stays loaded for the rest of the
+    /// process. Loading needs the `driver` feature
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The library
+    ///
+    /// # Safety
+    ///
+    /// The function must take no arguments and return a pointer to an `AnalyzerPlugin` that
+    /// lives as long as the library, and the functions of its analyzers must behave as
+    /// `AnalyzerVTable` says, because none of this can be checked. Only the version of the
+    /// plugin is
+    #[cfg(feature = "driver")]
+    pub unsafe fn load(&mut self, path: impl AsRef<std::path::Path>) -> io::Result<()> {
+        use libc::{dlerror, dlopen, dlsym, RTLD_NOW};
+        use std::{
+            ffi::{CStr, CString},
+            mem::transmute,
+            os::unix::ffi::OsStrExt,
+            ptr::null,
+        };
+
+        let error = || {
+            let message = dlerror();
+            let message = if message.is_null() {
+                "Unknown error".to_string()
+            } else {
+                CStr::from_ptr(message).to_string_lossy().to_string()
+            };
+            io::Error::other(message)
+        };
+
+        let path = CString::new(path.as_ref().as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        let symbol = CString::new(PLUGIN_SYMBOL).expect("Symbol name contains a NUL");
+
+        let library = dlopen(path.as_ptr(), RTLD_NOW);
+
+        if library.is_null() {
+            return Err(error());
+        }
+
+        let entry = dlsym(library, symbol.as_ptr());
+
+        if entry.is_null() {
+            return Err(error());
+        }
+
+        let entry: unsafe extern "C" fn() -> *const AnalyzerPlugin = transmute(entry);
+        let plugin = entry().as_ref().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{} returned no plugin", PLUGIN_SYMBOL),
+            )
+        })?;
+
+        if plugin.version != PLUGIN_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} was built for version {} of the analyzer plugin interface, not {}",
+                    path.to_string_lossy(),
+                    plugin.version,
+                    PLUGIN_VERSION
+                ),
+            ));
+        }
+
+        for vtable in items(plugin.analyzers, plugin.count) {
+            let name = CStr::from_ptr(vtable.name).to_string_lossy().to_string();
+            let description = CStr::from_ptr(vtable.description)
+                .to_string_lossy()
+                .to_string();
+            let error = format!("{} could not be created", name);
+
+            self.register(name, description, move |arg| {
+                let arg = arg
+                    .map(CString::new)
+                    .transpose()
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+                let analyzer =
+                    unsafe { (vtable.create)(arg.as_ref().map_or(null(), |arg| arg.as_ptr())) };
+
+                if analyzer.is_null() {
+                    return Err(io::Error::new(ErrorKind::InvalidInput, error.clone()));
+                }
+
+                Ok(Box::new(PluginAnalyzer { vtable, analyzer }))
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Create an analyzer
+    ///
+    /// # Arguments
+    ///
+    /// * `spec` - The name of the analyzer, optionally followed by `=` and its argument, like
+    ///   `diff=other.trace`
+    pub fn create(&self, spec: &str) -> io::Result<Box<dyn Analyzer>> {
+        let (name, arg) = match spec.split_once('=') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (spec, None),
+        };
+
+        let (_, factory) = self.analyzers.get(name).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "No analyzer named {}, try one of {}",
+                    name,
+                    self.analyzers
+                        .keys()
+                        .map(String::as_str)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            )
+        })?;
+
+        factory(arg)
+    }
+
+    /// The name and description of every analyzer, by name
+    pub fn analyzers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.analyzers
+            .iter()
+            .map(|(name, (description, _))| (name.as_str(), description.as_str()))
+    }
+}
+
+/// Runs several analyzers over the same trace
+#[derive(Default)]
+pub struct Analyzers {
+    /// The analyzers, by the name they report under
+    analyzers: Vec<(String, Box<dyn Analyzer>)>,
+    /// Whether the analyzers were started
+    started: bool,
+}
+
+impl Analyzers {
+    /// Instantiate a new `Analyzers` without analyzers
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an analyzer. Analyzers must be added before the first event
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name to report under
+    /// * `analyzer` - The analyzer
+    pub fn add(&mut self, name: impl Into<String>, analyzer: Box<dyn Analyzer>) {
+        self.analyzers.push((name.into(), analyzer));
+    }
+
+    /// Hand the next event of the trace to every analyzer. The first event starts them, with
+    /// it as the header if it is one
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn on_event(&mut self, event: &Value) {
+        if !self.started {
+            self.started = true;
+            let header = (EventKind::of(event) == Some(EventKind::Header)).then_some(event);
+
+            for (_, analyzer) in &mut self.analyzers {
+                analyzer.on_start(header);
+            }
+
+            if header.is_some() {
+                return;
+            }
+        }
+
+        for (_, analyzer) in &mut self.analyzers {
+            analyzer.on_event(event);
+        }
+    }
+
+    /// Report on the trace, by the name of each analyzer, in the order they were added
+    pub fn finish(mut self) -> Vec<(String, Report)> {
+        if !self.started {
+            for (_, analyzer) in &mut self.analyzers {
+                analyzer.on_start(None);
+            }
+        }
+
+        self.analyzers
+            .into_iter()
+            .map(|(name, mut analyzer)| {
+                let report = analyzer.on_end();
+                (name, report)
+            })
+            .collect()
+    }
+}
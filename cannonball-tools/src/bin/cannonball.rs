@@ -0,0 +1,1481 @@
+//! Cannonball command line interface
+//!
+//! One binary for running programs under the Jaivana plugin and working with their traces.
+//! Each subcommand is a thin layer over the `cannonball_tools` library.
+
+#[cfg(feature = "disasm")]
+use cannonball_tools::disasm::Disassembler;
+#[cfg(feature = "noise")]
+use cannonball_tools::noise::{read_psk, NoiseStream};
+#[cfg(feature = "grpc")]
+use cannonball_tools::serve::EventServer;
+use cannonball_tools::{
+    analyze::{Analyzers, Registry},
+    cfg::{ControlFlow, GraphFormat},
+    cover::Coverage,
+    diff::first_divergence,
+    driver::{LimitOptions, Qemu, StdioOptions, TraceOptions},
+    filter::Filter,
+    gdbserver::GdbServer,
+    merge::merge,
+    metadata::RunMetadata,
+    model::{BranchPredictor, Cache, Model},
+    output::{EventWriter, OutputFormat},
+    profile::{Disassemble, Profile},
+    session::{TraceResult, TraceStats},
+    split::PidSplitter,
+    strace,
+    symbols::Symbolizer,
+    trace::{
+        address_bits, events, exit_status, guest_command, plugin_args, EventKind, TraceReader,
+    },
+    triage::{Buckets, Recorder},
+    verify::Verifier,
+    vsock::VsockListener,
+    TracePool, TraceSession,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde_json::{json, to_string_pretty, Value};
+
+use std::{
+    cmp::Reverse,
+    fs::{create_dir_all, read_dir, File},
+    io::{self, stdout, BufReader, Read, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{self, exit, Stdio},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Parser, Debug)]
+/// Trace programs with the Jaivana QEMU plugin and analyze their traces
+struct Cli {
+    #[clap(flatten)]
+    pub emulator: Emulator,
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Args, Debug)]
+/// The QEMU and plugin to trace programs with
+struct Emulator {
+    /// The plugin to trace with. If not set, the Jaivana plugin built alongside this binary, or embedded in it with the embedded-plugin feature, is used.
+    #[clap(long, global = true)]
+    pub plugin: Option<PathBuf>,
+    /// The QEMU user mode binary to run programs under, e.g. /usr/bin/qemu-x86_64. If not set, the one in the QEMU environment variable is used, and otherwise the QEMU built into this binary.
+    #[clap(long, global = true)]
+    pub qemu_path: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+/// The program to trace
+struct Target {
+    /// An input file to feed to the program. If not set, the program will take input via this driver's stdin.
+    #[clap(short = 'I', long)]
+    pub input_file: Option<PathBuf>,
+    #[clap(flatten)]
+    pub stdio: StdioOptions,
+    #[clap(flatten)]
+    pub limits: LimitOptions,
+    /// Whether to print statistics about the trace to stderr once the program exits, like the number of events of each kind and how fast they were logged.
+    #[clap(long)]
+    pub stats: bool,
+    /// Whether to also run the program once without QEMU, to report the overhead of tracing it along with the statistics.
+    #[clap(long, requires = "stats")]
+    pub baseline: bool,
+    /// Whether to count the instructions the program executes by class, and print the histogram to stderr once the program exits. Instructions are counted inline in the translated code, so this is cheap. Only supported for x86 programs.
+    #[clap(long)]
+    pub insn_mix: bool,
+    /// Also write the trace to a file in a format, given as FORMAT:PATH, like binary:ls.trace, on a thread of its own. May be given multiple times, and the time the trace waited for each file is printed with --stats.
+    #[clap(long, value_parser = parse_tee)]
+    pub tee: Vec<(OutputFormat, PathBuf)>,
+    /// The program to run
+    #[clap()]
+    pub program: PathBuf,
+    /// The arguments to the program
+    #[clap(num_args = 1.., last = true)]
+    pub args: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+/// The events to keep
+struct FilterArgs {
+    /// Only keep the events matching this filter, e.g. 'pc in 0x400000..0x500000 && type == syscall && syscall.num == 1'. Fields are compared with ==, !=, <, <=, >, >= and in, and combined with &&, || and !.
+    #[clap(long)]
+    pub filter: Option<Filter>,
+}
+
+#[derive(Args, Debug)]
+/// Whether to split the trace per process
+struct SplitArgs {
+    /// Write the events of each process to a file of its own, named after --output with the PID appended, like ls.trace.1234. Events must be tagged with the PID of their process, with --tag-pids. A single process is kept with --filter 'pid == 1234' instead.
+    #[clap(long, requires = "output")]
+    pub split_pids: bool,
+}
+
+#[derive(Args, Debug)]
+/// Inputs to trace the program with, one run each
+struct Corpus {
+    /// A directory of inputs. If set, the program is traced once for each file in it, with the file fed to it on stdin.
+    #[clap(long, conflicts_with = "input_file")]
+    pub input_dir: Option<PathBuf>,
+    /// The number of inputs to trace at once. If 0, one per CPU.
+    #[clap(short, long, default_value_t = 1, requires = "input_dir")]
+    pub jobs: usize,
+    /// A directory to write one result file per input to, named after the input.
+    #[clap(long, requires = "input_dir")]
+    pub output_dir: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+/// The caches and branch predictor to model
+struct ModelOptions {
+    /// The size of the instruction cache in bytes.
+    #[clap(long, default_value_t = 16384)]
+    pub icache_size: usize,
+    /// The associativity of the instruction cache.
+    #[clap(long, default_value_t = 8)]
+    pub icache_ways: usize,
+    /// The line size of the instruction cache in bytes.
+    #[clap(long, default_value_t = 64)]
+    pub icache_line: usize,
+    /// The size of the data cache in bytes.
+    #[clap(long, default_value_t = 16384)]
+    pub dcache_size: usize,
+    /// The associativity of the data cache.
+    #[clap(long, default_value_t = 8)]
+    pub dcache_ways: usize,
+    /// The line size of the data cache in bytes.
+    #[clap(long, default_value_t = 64)]
+    pub dcache_line: usize,
+    /// The number of two bit counters of the branch predictor.
+    #[clap(long, default_value_t = 4096)]
+    pub predictor_entries: usize,
+    /// The number of branch outcomes the branch predictor hashes into its index. If 0, it is a bimodal predictor.
+    #[clap(long, default_value_t = 8)]
+    pub history_bits: u32,
+    /// The address the program was loaded at, relative to its symbol table, e.g. 0x555555554000 for a position independent executable.
+    #[clap(long, value_parser = parse_hex)]
+    pub bias: Option<u64>,
+    /// The number of functions to print.
+    #[clap(long, default_value_t = 20)]
+    pub limit: usize,
+}
+
+/// Parse a hex address, with or without a 0x prefix
+///
+/// # Arguments
+///
+/// * `value` - The address
+fn parse_hex(value: &str) -> Result<u64, String> {
+    u64::from_str_radix(value.trim_start_matches("0x"), 16).map_err(|e| e.to_string())
+}
+
+/// Parse a file to write a trace to, as its format and path separated by a colon
+///
+/// # Arguments
+///
+/// * `value` - The format and path, like `binary:ls.trace`
+fn parse_tee(value: &str) -> Result<(OutputFormat, PathBuf), String> {
+    let (format, path) = value
+        .split_once(':')
+        .ok_or_else(|| format!("Expected FORMAT:PATH, not {}", value))?;
+
+    Ok((OutputFormat::from_str(format, true)?, PathBuf::from(path)))
+}
+
+#[derive(Subcommand, Debug)]
+// Parsed once, so the size of the largest variant does not matter
+#[allow(clippy::large_enum_variant)]
+enum Command {
+    /// Trace a program, writing its events as JSON
+    Run {
+        #[clap(flatten)]
+        options: TraceOptions,
+        /// A file to write the trace to. If not set, events are written to stdout along with the output of the program.
+        #[clap(short = 'T', long, conflicts_with = "input_dir")]
+        trace: Option<PathBuf>,
+        /// The format to write the trace in.
+        #[clap(long, value_enum, default_value_t)]
+        output_format: OutputFormat,
+        #[clap(flatten)]
+        filter: FilterArgs,
+        /// Also publish events live over gRPC on this address, e.g. grpc://0.0.0.0:50051. Tracing starts once a client subscribes.
+        #[clap(long, conflicts_with = "input_dir")]
+        serve: Option<String>,
+        /// Whether to check the trace against the program's ELF program headers and the memory it maps executable, printing the problems found to stderr and exiting with 1 if there are any. System calls are logged too, to learn where libraries are mapped.
+        #[clap(long, conflicts_with = "input_dir")]
+        verify: bool,
+        #[clap(flatten)]
+        corpus: Corpus,
+        #[clap(flatten)]
+        target: Target,
+    },
+    /// Trace a program while debugging it with gdb, which connects once the program stops at its entry point
+    Gdbserver {
+        #[clap(flatten)]
+        options: TraceOptions,
+        /// The address to wait for gdb on.
+        #[clap(short, long, default_value = "127.0.0.1:1234")]
+        listen: String,
+        /// A file to write the trace to. If not set, events are written to stdout along with the output of the program.
+        #[clap(short = 'T', long)]
+        trace: Option<PathBuf>,
+        /// The format to write the trace in.
+        #[clap(long, value_enum, default_value_t)]
+        output_format: OutputFormat,
+        #[clap(flatten)]
+        filter: FilterArgs,
+        #[clap(flatten)]
+        target: Target,
+    },
+    /// Convert a trace to another format, like a Chrome trace for ui.perfetto.dev
+    Export {
+        /// The trace to convert
+        trace: PathBuf,
+        /// The format to convert it to.
+        #[clap(long, value_enum)]
+        output_format: OutputFormat,
+        /// A file to write the converted trace to. If not set, it is written to stdout.
+        #[clap(short = 'T', long)]
+        output: Option<PathBuf>,
+        #[clap(flatten)]
+        filter: FilterArgs,
+        #[clap(flatten)]
+        split: SplitArgs,
+    },
+    /// Receive the events of a plugin running in a virtual machine with `--sink vsock:CID:PORT`, or on another machine with `--sink tcp:HOST:PORT`, and write them as a trace
+    Receive {
+        /// The vsock port to listen on for the plugin.
+        #[clap(long, required_unless_present = "tcp", conflicts_with = "tcp")]
+        vsock: Option<u32>,
+        /// The TCP address to listen on for the plugin, e.g. 0.0.0.0:5000.
+        #[clap(long)]
+        tcp: Option<String>,
+        /// A file holding the hex-encoded 32 byte key the plugin encrypts its events with, given to it with `--sink-psk-file`. Connections from plugins without it are refused.
+        #[clap(long)]
+        psk_file: Option<PathBuf>,
+        /// A file to write the trace to. If not set, it is written to stdout.
+        #[clap(short = 'T', long)]
+        output: Option<PathBuf>,
+        /// The format to write the trace in.
+        #[clap(long, value_enum, default_value_t)]
+        output_format: OutputFormat,
+        #[clap(flatten)]
+        filter: FilterArgs,
+        #[clap(flatten)]
+        split: SplitArgs,
+    },
+    /// Print the events of a trace as indented JSON, followed by a footer telling how the program exited, if the trace recorded it
+    Json {
+        /// The trace to print
+        trace: PathBuf,
+        /// Only print events of these kinds, e.g. Syscall. May be given multiple times.
+        #[clap(short, long)]
+        kind: Vec<String>,
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
+    /// Trace a program and print the translation blocks it executed
+    Cover {
+        #[clap(flatten)]
+        corpus: Corpus,
+        #[clap(flatten)]
+        target: Target,
+    },
+    /// Trace a program and print the functions and translation blocks it spent the most instructions in
+    Profile {
+        /// The address the program was loaded at, relative to its symbol table, e.g. 0x555555554000 for a position independent executable.
+        #[clap(long, value_parser = parse_hex)]
+        bias: Option<u64>,
+        /// The number of functions and blocks to print.
+        #[clap(long, default_value_t = 20)]
+        limit: usize,
+        /// Whether to list the instructions of each block. Needs the disasm feature.
+        #[clap(short, long)]
+        disassemble: bool,
+        #[clap(flatten)]
+        target: Target,
+    },
+    /// Trace a program and write the control flow graph of each function it executed, with the number of times each block and edge executed
+    Cfg {
+        /// The format to write the graphs in.
+        #[clap(long, value_enum, default_value_t)]
+        format: GraphFormat,
+        /// A file to write the graphs to. If not set, they are written to stdout.
+        #[clap(short = 'T', long)]
+        output: Option<PathBuf>,
+        /// Only write the graph of this function. May be given multiple times.
+        #[clap(long)]
+        function: Vec<String>,
+        /// The address the program was loaded at, relative to its symbol table, e.g. 0x555555554000 for a position independent executable.
+        #[clap(long, value_parser = parse_hex)]
+        bias: Option<u64>,
+        /// Whether to list the instructions of each block. Needs the disasm feature.
+        #[clap(short, long)]
+        disassemble: bool,
+        #[clap(flatten)]
+        target: Target,
+    },
+    /// Trace a program and print the system calls it made
+    Strace {
+        #[clap(flatten)]
+        target: Target,
+    },
+    /// Trace a program through models of a CPU's caches and branch predictor, and print their miss rates by function
+    Model {
+        #[clap(flatten)]
+        model: ModelOptions,
+        #[clap(flatten)]
+        target: Target,
+    },
+    /// Trace a program once for each input of a directory of crashing inputs, and group the inputs into buckets of those that crashed it alike, by the signal and the last blocks executed
+    Triage {
+        /// The number of blocks executed before the crash that tell crashes apart.
+        #[clap(long, default_value_t = 16)]
+        blocks: usize,
+        /// The address the program was loaded at, relative to its symbol table, e.g. 0x555555554000 for a position independent executable.
+        #[clap(long, value_parser = parse_hex)]
+        bias: Option<u64>,
+        /// Whether to print the buckets as JSON.
+        #[clap(long)]
+        json: bool,
+        #[clap(flatten)]
+        corpus: Corpus,
+        #[clap(flatten)]
+        target: Target,
+    },
+    /// Find the first point where two traces executed different code
+    Diff {
+        /// The first trace
+        left: PathBuf,
+        /// The second trace
+        right: PathBuf,
+    },
+    /// Merge the traces of programs traced separately, like a client and a server, into a single timeline, ordered by the time their events were logged at with --tag-time
+    Merge {
+        /// The traces to merge. Events are tagged with the index of their trace among these, as `stream`.
+        #[clap(required = true)]
+        traces: Vec<PathBuf>,
+        /// A file to write the merged trace to. If not set, it is written to stdout.
+        #[clap(short = 'T', long)]
+        output: Option<PathBuf>,
+        /// The format to write the merged trace in.
+        #[clap(long, value_enum, default_value_t)]
+        output_format: OutputFormat,
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
+    /// Run analyzers over a trace and print their reports, including analyzers loaded from shared libraries
+    Analyze {
+        /// The trace to analyze
+        #[clap(required_unless_present = "list")]
+        trace: Option<PathBuf>,
+        /// An analyzer to run, by name, followed by its argument after = if it takes one, e.g. diff=other.trace. May be given multiple times.
+        #[clap(short, long, required_unless_present = "list")]
+        analyzer: Vec<String>,
+        /// A shared library of analyzers to load, which exports `cannonball_analyzer_plugin`, a C function returning the table of its analyzers. It may be built with any compiler, but for the same version of the analyzer plugin interface. May be given multiple times.
+        #[clap(long)]
+        load: Vec<PathBuf>,
+        /// Whether to print the reports as JSON, one line per analyzer.
+        #[clap(long)]
+        json: bool,
+        /// Whether to list the analyzers instead, with the ones loaded from libraries.
+        #[clap(long)]
+        list: bool,
+    },
+    /// Trace a program again with the command line and plugin arguments recorded in a trace
+    Replay {
+        /// The trace to replay
+        trace: PathBuf,
+        /// A file to write the new trace to. If not set, events are written to stdout along with the output of the program.
+        #[clap(short = 'T', long)]
+        output: Option<PathBuf>,
+        /// The format to write the new trace in.
+        #[clap(long, value_enum, default_value_t)]
+        output_format: OutputFormat,
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
+}
+
+/// Read the events of a trace file
+///
+/// # Arguments
+///
+/// * `path` - The trace file
+fn read_trace(path: &Path) -> io::Result<Vec<Value>> {
+    events(BufReader::new(File::open(path)?)).collect()
+}
+
+/// A session tracing a program under the plugin
+///
+/// # Arguments
+///
+/// * `emulator` - The QEMU and plugin to trace with
+/// * `plugin_args` - The arguments to pass to the plugin
+/// * `target` - The program to run
+fn session(emulator: &Emulator, plugin_args: Vec<String>, target: &Target) -> TraceSession {
+    let mut session = TraceSession::new(&target.program)
+        .args(target.args.iter().cloned())
+        .plugin_args(plugin_args)
+        .stdio(target.stdio.clone())
+        .limits(target.limits.clone());
+
+    if let Some(plugin) = &emulator.plugin {
+        session = session.plugin(plugin);
+    }
+
+    if let Some(qemu_path) = &emulator.qemu_path {
+        session = session.qemu(Qemu::Path(qemu_path.clone()));
+    }
+
+    if let Some(input_file) = &target.input_file {
+        session = session.input_file(input_file);
+    }
+
+    session
+}
+
+/// Run a program under the plugin, writing its output to stdout
+///
+/// # Arguments
+///
+/// * `emulator` - The QEMU and plugin to trace with
+/// * `plugin_args` - The arguments to pass to the plugin
+/// * `target` - The program to run
+/// * `on_event` - Called with each event
+fn trace(
+    emulator: &Emulator,
+    mut plugin_args: Vec<String>,
+    target: &Target,
+    on_event: impl FnMut(Value),
+) -> io::Result<Option<i32>> {
+    if target.stats {
+        plugin_args.push("stats=true".to_string());
+    }
+
+    if target.insn_mix {
+        plugin_args.push("stats=insn_mix".to_string());
+    }
+
+    let mut session = session(emulator, plugin_args, target);
+
+    if !target.tee.is_empty() {
+        let metadata = session.metadata()?;
+
+        for (format, path) in &target.tee {
+            session = session.sink_writer(
+                path.to_string_lossy(),
+                format.create_with_metadata(path, &metadata)?,
+            );
+        }
+    }
+
+    let result = session
+        .on_output(|line| println!("{}", line))
+        .run(on_event)?;
+
+    for sink in &result.stats.sinks {
+        if let Some(error) = &sink.error {
+            eprintln!("Could not write {}: {}", sink.name, error);
+        }
+    }
+
+    if result.timed_out {
+        eprintln!("{} timed out", target.program.to_string_lossy());
+    } else if let Some(crash) = crash(&result) {
+        eprintln!(
+            "{} crashed with {}",
+            target.program.to_string_lossy(),
+            crash
+        );
+    }
+
+    if target.stats {
+        let baseline = if target.baseline {
+            Some(baseline(target)?)
+        } else {
+            None
+        };
+        print_stats(&result.stats, baseline);
+    }
+
+    if target.insn_mix {
+        print_insn_mix(&result.stats);
+    }
+
+    Ok(result.exit_code)
+}
+
+/// Run a program without QEMU, with its output discarded, and return how long it ran for
+///
+/// # Arguments
+///
+/// * `target` - The program to run
+fn baseline(target: &Target) -> io::Result<Duration> {
+    let stdin = match &target.input_file {
+        Some(input_file) => Stdio::from(File::open(input_file)?),
+        None => Stdio::null(),
+    };
+
+    let start = Instant::now();
+    process::Command::new(&target.program)
+        .args(&target.args)
+        .stdin(stdin)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+
+    Ok(start.elapsed())
+}
+
+/// How a traced program crashed, like `SIGSEGV at pc=0x401000`, if it was killed by a signal.
+/// The signal and where it was raised come from the plugin when it logged signals, and the
+/// signal from the exit status of QEMU otherwise
+///
+/// # Arguments
+///
+/// * `result` - The outcome of the trace
+fn crash(result: &TraceResult) -> Option<String> {
+    let event = result.stats.crash.as_ref();
+
+    if event.is_none() && result.signal.is_none() {
+        return None;
+    }
+
+    let signal = event
+        .and_then(|event| event.get("signal"))
+        .and_then(Value::as_i64)
+        .or(result.signal.map(i64::from));
+    let name = event
+        .and_then(|event| event.get("name"))
+        .or_else(|| result.stats.end.as_ref()?.get("signal_name"))
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .or_else(|| signal.map(|signal| format!("signal {}", signal)))
+        .unwrap_or_else(|| "a signal".to_string());
+
+    Some(
+        match event
+            .and_then(|event| event.get("pc"))
+            .and_then(Value::as_u64)
+        {
+            Some(pc) => format!("{} at pc={:#x}", name, pc),
+            None => name,
+        },
+    )
+}
+
+/// Print statistics about a trace to stderr
+///
+/// # Arguments
+///
+/// * `stats` - The statistics
+/// * `baseline` - How long the program ran for without QEMU, if known
+fn print_stats(stats: &TraceStats, baseline: Option<Duration>) {
+    let seconds = stats.duration.as_secs_f64();
+
+    eprintln!("Duration: {:.3}s", seconds);
+    eprintln!(
+        "Events: {} ({:.0}/s)",
+        stats.events,
+        stats.events as f64 / seconds.max(f64::EPSILON)
+    );
+
+    let mut kinds = stats.by_kind.iter().collect::<Vec<_>>();
+    kinds.sort_by(|a, b| b.1.cmp(a.1));
+
+    for (kind, count) in kinds {
+        eprintln!("  {:?}: {}", kind, count);
+    }
+
+    if let Some(plugin) = &stats.plugin {
+        let field = |name: &str| plugin.get(name).and_then(Value::as_u64).unwrap_or(0);
+
+        eprintln!("Bytes written: {}", field("bytes"));
+        eprintln!("Time spent writing: {}ms", field("write_ms"));
+        eprintln!("Largest batch: {} events", field("high_water"));
+        eprintln!("Dropped events: {}", field("discarded"));
+    }
+
+    for sink in &stats.sinks {
+        eprintln!(
+            "Sink {}: {} events, waited {} times for {:.3}s",
+            sink.name,
+            sink.events,
+            sink.stalls,
+            sink.stalled.as_secs_f64()
+        );
+    }
+
+    if let Some(baseline) = baseline {
+        eprintln!(
+            "Overhead: {:.1}x ({:.3}s without QEMU)",
+            seconds / baseline.as_secs_f64().max(f64::EPSILON),
+            baseline.as_secs_f64()
+        );
+    }
+}
+
+/// Print the instruction mix of a trace to stderr, most executed classes first
+///
+/// # Arguments
+///
+/// * `stats` - The statistics, holding the instruction mix the plugin reported
+fn print_insn_mix(stats: &TraceStats) {
+    let Some(mix) = &stats.insn_mix else {
+        eprintln!("The plugin did not report an instruction mix");
+        return;
+    };
+
+    let insns = mix.get("insns").and_then(Value::as_u64).unwrap_or(0);
+    let mut classes = mix
+        .get("classes")
+        .and_then(Value::as_object)
+        .map(|classes| {
+            classes
+                .iter()
+                .map(|(class, count)| (class.as_str(), count.as_u64().unwrap_or(0)))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    classes.sort_by_key(|(_, count)| Reverse(*count));
+
+    eprintln!("Instructions: {}", insns);
+
+    for (class, count) in classes {
+        eprintln!(
+            "  {:<8} {:>6.2}% {}",
+            class,
+            count as f64 * 100.0 / insns.max(1) as f64,
+            count
+        );
+    }
+}
+
+/// Print the problems found verifying a trace to stderr
+///
+/// # Arguments
+///
+/// * `verifier` - The verifier the trace was checked with
+fn print_problems(verifier: &Verifier) {
+    eprintln!("Found {} problems in the trace:", verifier.count());
+
+    for problem in verifier.problems() {
+        eprintln!("  {}", problem);
+    }
+
+    let omitted = verifier.count() - verifier.problems().len() as u64;
+
+    if omitted > 0 {
+        eprintln!("  and {} more", omitted);
+    }
+}
+
+/// Trace a program once for each input of a corpus, in parallel, and report how each run went
+/// on stderr. The output of the program is discarded. Returns whether every input was traced
+///
+/// # Arguments
+///
+/// * `emulator` - The QEMU and plugin to trace with
+/// * `plugin_args` - The arguments to pass to the plugin
+/// * `target` - The program to run
+/// * `corpus` - The inputs
+/// * `job` - Called with the name of each input and its session, to run it
+fn trace_corpus(
+    emulator: &Emulator,
+    plugin_args: Vec<String>,
+    target: &Target,
+    corpus: &Corpus,
+    job: impl Fn(&str, TraceSession) -> io::Result<TraceResult> + Sync,
+) -> io::Result<bool> {
+    let input_dir = corpus.input_dir.as_ref().expect("No input directory");
+
+    if !target.tee.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--tee writes a single trace, so it cannot be used with --input-dir",
+        ));
+    }
+
+    if let Some(output_dir) = &corpus.output_dir {
+        create_dir_all(output_dir)?;
+    }
+
+    let mut inputs = read_dir(input_dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    inputs.retain(|input| input.is_file());
+    inputs.sort();
+
+    let names = inputs
+        .iter()
+        .map(|input| {
+            input
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect::<Vec<_>>();
+    let sessions = inputs
+        .iter()
+        .map(|input| session(emulator, plugin_args.clone(), target).input_file(input))
+        .collect();
+
+    let results =
+        TracePool::new(corpus.jobs).run(sessions, |index, session| job(&names[index], session));
+    let mut traced = true;
+
+    for (name, result) in names.iter().zip(results) {
+        match result {
+            Ok(result) => eprintln!(
+                "{}: exit code {}, {} events{}",
+                name,
+                result
+                    .exit_code
+                    .map_or("none".to_string(), |code| code.to_string()),
+                result.stats.events,
+                if result.timed_out { ", timed out" } else { "" }
+            ),
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                traced = false;
+            }
+        }
+    }
+
+    Ok(traced)
+}
+
+/// Write events in a format
+///
+/// # Arguments
+///
+/// * `out` - Where to write the events
+/// * `format` - The format to write them in
+/// * `metadata` - The metadata of the run the events are of, written first to a file
+fn event_writer(
+    out: Option<&PathBuf>,
+    format: OutputFormat,
+    metadata: Option<&RunMetadata>,
+) -> io::Result<impl FnMut(Value)> {
+    let mut writer = match (out, metadata) {
+        (Some(path), Some(metadata)) => format.create_with_metadata(path, metadata)?,
+        (Some(path), None) => format.create(path)?,
+        (None, _) => format.writer(stdout())?,
+    };
+
+    Ok(move |event: Value| {
+        writer.write(&event).expect("Could not write event!");
+    })
+}
+
+/// Write the events of each process of a trace to a file of its own, and print the files
+/// written to stderr
+///
+/// # Arguments
+///
+/// * `events` - The events of the trace
+/// * `output` - The path of the trace, which the PIDs are appended to
+/// * `format` - The format to write the files in
+/// * `metadata` - The run the trace was recorded for, if it is known
+/// * `filter` - Only the events matching it are written, if set
+fn split(
+    events: impl IntoIterator<Item = io::Result<Value>>,
+    output: &Path,
+    format: OutputFormat,
+    metadata: Option<&RunMetadata>,
+    filter: Option<Filter>,
+) -> io::Result<()> {
+    let mut splitter = PidSplitter::new(output, format, metadata);
+
+    for event in events {
+        let event = event?;
+        if filter.as_ref().is_none_or(|filter| filter.matches(&event)) {
+            splitter.write(&event)?;
+        }
+    }
+
+    splitter.finish()?;
+
+    for pid in splitter.pids() {
+        eprintln!(
+            "Wrote the events of {} to {}",
+            pid,
+            PidSplitter::path_of(output, pid).display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Only pass on the events matching a filter, if there is one
+///
+/// # Arguments
+///
+/// * `filter` - The filter
+/// * `write` - Writes events
+fn filtering(filter: Option<Filter>, mut write: impl FnMut(Value)) -> impl FnMut(Value) {
+    move |event: Value| {
+        if filter.as_ref().is_none_or(|filter| filter.matches(&event)) {
+            write(event);
+        }
+    }
+}
+
+/// Also publish events over gRPC, if asked to. Waits for a client to subscribe first
+///
+/// # Arguments
+///
+/// * `serve` - The address to serve events on, like `grpc://0.0.0.0:50051`
+/// * `write` - Writes events
+fn serving(
+    serve: Option<&str>,
+    mut write: impl FnMut(Value) + 'static,
+) -> io::Result<Box<dyn FnMut(Value)>> {
+    let Some(serve) = serve else {
+        return Ok(Box::new(write));
+    };
+
+    #[cfg(feature = "grpc")]
+    {
+        let addr = serve
+            .strip_prefix("grpc://")
+            .unwrap_or(serve)
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut server = EventServer::start(addr)?;
+
+        eprintln!("Serving events on {}, waiting for a subscriber", serve);
+        server.wait_for_subscriber();
+
+        Ok(Box::new(move |event: Value| {
+            server.publish(&event);
+            write(event);
+        }))
+    }
+
+    #[cfg(not(feature = "grpc"))]
+    {
+        let _ = &mut write;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Serving events on {} needs the grpc feature", serve),
+        ))
+    }
+}
+
+/// Decrypt the events of a plugin encrypting them with a pre-shared key, if one is given
+///
+/// # Arguments
+///
+/// * `stream` - The connection from the plugin
+/// * `psk_file` - The file holding the pre-shared key
+fn decrypting(
+    stream: impl Read + Write + 'static,
+    psk_file: Option<&Path>,
+) -> io::Result<Box<dyn Read>> {
+    let Some(psk_file) = psk_file else {
+        return Ok(Box::new(stream));
+    };
+
+    #[cfg(feature = "noise")]
+    {
+        let psk = read_psk(psk_file)?;
+        Ok(Box::new(NoiseStream::accept(stream, &psk)?))
+    }
+
+    #[cfg(not(feature = "noise"))]
+    {
+        let _ = (stream, psk_file);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Decrypting events needs the noise feature",
+        ))
+    }
+}
+
+/// A function disassembling the code of a program, given an address and a size
+///
+/// # Arguments
+///
+/// * `program` - The program
+/// * `bias` - The address the program was loaded at, relative to its segments
+fn disassembler(program: &Path, bias: u64) -> io::Result<Box<Disassemble<'static>>> {
+    #[cfg(feature = "disasm")]
+    {
+        let disassembler = Disassembler::load(program)?.with_bias(bias);
+        Ok(Box::new(move |addr, size| {
+            disassembler.disassemble(addr, size)
+        }))
+    }
+
+    #[cfg(not(feature = "disasm"))]
+    {
+        let _ = (program, bias);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Disassembling needs the disasm feature",
+        ))
+    }
+}
+
+fn main() -> io::Result<()> {
+    let cli = Cli::parse();
+
+    let code = match cli.command {
+        Command::Run {
+            options,
+            trace: _,
+            output_format,
+            filter: FilterArgs { filter },
+            serve: _,
+            verify: _,
+            corpus,
+            target,
+        } if corpus.input_dir.is_some() => {
+            let output_dir = corpus
+                .output_dir
+                .as_ref()
+                .expect("Tracing a corpus needs an output directory!");
+            let plugin_args = options.plugin_args(&target.program);
+            let traced = trace_corpus(
+                &cli.emulator,
+                plugin_args,
+                &target,
+                &corpus,
+                |name, session| {
+                    let out = output_dir.join(format!("{}.trace", name));
+                    let metadata = session.metadata()?;
+                    session.run(filtering(
+                        filter.clone(),
+                        event_writer(Some(&out), output_format, Some(&metadata))?,
+                    ))
+                },
+            )?;
+            Some(if traced { 0 } else { 1 })
+        }
+        Command::Run {
+            mut options,
+            trace: out,
+            output_format,
+            filter: FilterArgs { filter },
+            serve,
+            verify,
+            corpus: _,
+            target,
+        } => {
+            let mut verifier = if verify {
+                options.syscalls = true;
+                Some(Verifier::load(&target.program)?)
+            } else {
+                None
+            };
+
+            let plugin_args = options.plugin_args(&target.program);
+            let metadata = session(&cli.emulator, plugin_args.clone(), &target).metadata()?;
+            // The verifier sees every event, the filter only decides which are written
+            let mut write = filtering(
+                filter,
+                serving(
+                    serve.as_deref(),
+                    event_writer(out.as_ref(), output_format, Some(&metadata))?,
+                )?,
+            );
+            let code = trace(&cli.emulator, plugin_args, &target, |event| {
+                if let Some(verifier) = &mut verifier {
+                    verifier.verify(&event);
+                }
+                write(event)
+            })?;
+
+            match verifier {
+                Some(verifier) if verifier.count() > 0 => {
+                    print_problems(&verifier);
+                    Some(1)
+                }
+                _ => code,
+            }
+        }
+        Command::Gdbserver {
+            mut options,
+            listen,
+            trace: out,
+            output_format,
+            filter: FilterArgs { filter },
+            target,
+        } => {
+            options.break_pause = true;
+            options.break_entry = true;
+            let mut plugin_args = options.plugin_args(&target.program);
+
+            if target.stats {
+                plugin_args.push("stats=true".to_string());
+            }
+
+            if target.insn_mix {
+                plugin_args.push("stats=insn_mix".to_string());
+            }
+
+            let server = GdbServer::bind(&listen)?;
+            eprintln!(
+                "Waiting for gdb, connect with: target remote {}",
+                server.local_addr()?
+            );
+
+            let session = session(&cli.emulator, plugin_args, &target);
+            let metadata = session.metadata()?;
+            let result = server.serve(
+                session.on_output(|line| println!("{}", line)),
+                filtering(
+                    filter,
+                    event_writer(out.as_ref(), output_format, Some(&metadata))?,
+                ),
+            )?;
+
+            if target.stats {
+                print_stats(&result.stats, None);
+            }
+
+            if target.insn_mix {
+                print_insn_mix(&result.stats);
+            }
+
+            result.exit_code
+        }
+        Command::Export {
+            trace,
+            output_format,
+            output,
+            filter: FilterArgs { filter },
+            split: SplitArgs { split_pids },
+        } => {
+            // The converted trace keeps the metadata of the run it was written for
+            let reader = TraceReader::new(BufReader::new(File::open(trace)?), OutputFormat::Json)?;
+
+            if let (true, Some(output)) = (split_pids, &output) {
+                let metadata = reader.metadata().cloned();
+                split(reader, output, output_format, metadata.as_ref(), filter)?;
+            } else {
+                let mut write = filtering(
+                    filter,
+                    event_writer(output.as_ref(), output_format, reader.metadata())?,
+                );
+
+                for event in reader {
+                    write(event?);
+                }
+            }
+
+            Some(0)
+        }
+        Command::Receive {
+            vsock,
+            tcp,
+            psk_file,
+            output,
+            output_format,
+            filter: FilterArgs { filter },
+            split: SplitArgs { split_pids },
+        } => {
+            let stream: Box<dyn Read> = match (vsock, tcp) {
+                (Some(vsock), _) => {
+                    let listener = VsockListener::bind(vsock)?;
+                    eprintln!("Waiting for the plugin on vsock port {}", vsock);
+
+                    let (stream, peer) = listener.accept()?;
+                    eprintln!("Receiving events from {}", peer);
+                    decrypting(stream, psk_file.as_deref())?
+                }
+                (None, Some(tcp)) => {
+                    let listener = TcpListener::bind(&tcp)?;
+                    eprintln!("Waiting for the plugin on {}", tcp);
+
+                    let (stream, peer) = listener.accept()?;
+                    eprintln!("Receiving events from {}", peer);
+                    decrypting(stream, psk_file.as_deref())?
+                }
+                (None, None) => unreachable!("clap requires --vsock or --tcp"),
+            };
+
+            // Sinks write the binary format
+            let reader = TraceReader::new(BufReader::new(stream), OutputFormat::Binary)?;
+
+            if let (true, Some(output)) = (split_pids, &output) {
+                split(reader, output, output_format, None, filter)?;
+            } else {
+                let mut write =
+                    filtering(filter, event_writer(output.as_ref(), output_format, None)?);
+
+                for event in reader {
+                    write(event?);
+                }
+            }
+
+            Some(0)
+        }
+        Command::Json {
+            trace,
+            kind,
+            filter: FilterArgs { filter },
+        } => {
+            let mut last = None;
+            let mut shown = 0;
+
+            for event in read_trace(&trace)? {
+                if EventKind::of(&event) == Some(EventKind::Final) {
+                    last = Some(event.clone());
+                }
+
+                if (kind.is_empty()
+                    || EventKind::of(&event)
+                        .map(|k| kind.contains(&format!("{:?}", k)))
+                        .unwrap_or(false))
+                    && filter.as_ref().is_none_or(|filter| filter.matches(&event))
+                {
+                    println!("{}", to_string_pretty(&event)?);
+                    shown += 1;
+                }
+            }
+
+            // Summarizes the whole trace, whatever was shown of it
+            if let Some(last) = last {
+                let field = |name: &str| last.get(name).cloned().unwrap_or(Value::Null);
+                let footer = json!({
+                    "footer": {
+                        "status": exit_status(&last),
+                        "exit_code": field("exit_code"),
+                        "signal": field("signal"),
+                        "insns": field("insns"),
+                        "events": field("events"),
+                        "shown": shown,
+                    }
+                });
+                println!("{}", to_string_pretty(&footer)?);
+            }
+
+            Some(0)
+        }
+        Command::Cover { corpus, target } if corpus.input_dir.is_some() => {
+            let plugin_args = vec!["trace_tb=true".to_string(), "dedup=true".to_string()];
+            let merged = Mutex::new(Coverage::new());
+            let traced = trace_corpus(
+                &cli.emulator,
+                plugin_args,
+                &target,
+                &corpus,
+                |name, session| {
+                    let mut coverage = Coverage::new();
+                    let result = session.run(|event| coverage.add(&event))?;
+
+                    if let Some(output_dir) = &corpus.output_dir {
+                        coverage.write(File::create(output_dir.join(format!("{}.cov", name)))?)?;
+                    }
+
+                    merged
+                        .lock()
+                        .expect("Failed to lock coverage")
+                        .merge(&coverage);
+                    Ok(result)
+                },
+            )?;
+            merged
+                .into_inner()
+                .expect("Failed to lock coverage")
+                .write(stdout())?;
+            Some(if traced { 0 } else { 1 })
+        }
+        Command::Cover { corpus: _, target } => {
+            let plugin_args = vec!["trace_tb=true".to_string(), "dedup=true".to_string()];
+            let mut coverage = Coverage::new();
+            let code = trace(&cli.emulator, plugin_args, &target, |event| {
+                coverage.add(&event)
+            })?;
+            coverage.write(stdout())?;
+            code
+        }
+        Command::Profile {
+            bias,
+            limit,
+            disassemble,
+            target,
+        } => {
+            let plugin_args = vec!["trace_tb=true".to_string(), "dedup=true".to_string()];
+            // Checked before tracing, so a long trace is not wasted on a missing feature
+            let disassemble = if disassemble {
+                Some(disassembler(&target.program, bias.unwrap_or_default())?)
+            } else {
+                None
+            };
+            let mut profile = Profile::new();
+            let code = trace(&cli.emulator, plugin_args, &target, |event| {
+                profile.add(&event)
+            })?;
+            // Programs without a symbol table are still profiled, just not by function
+            let symbolizer = Symbolizer::load(&target.program)
+                .ok()
+                .map(|symbolizer| symbolizer.with_bias(bias.unwrap_or_default()));
+            profile.write(symbolizer.as_ref(), disassemble.as_deref(), limit, stdout())?;
+            code
+        }
+        Command::Cfg {
+            format,
+            output,
+            function,
+            bias,
+            disassemble,
+            target,
+        } => {
+            let plugin_args = vec![
+                "trace_tb=true".to_string(),
+                "dedup=true".to_string(),
+                "log_edges=true".to_string(),
+            ];
+            // Checked before tracing, so a long trace is not wasted on a missing feature
+            let disassemble = if disassemble {
+                Some(disassembler(&target.program, bias.unwrap_or_default())?)
+            } else {
+                None
+            };
+            let mut cfg = ControlFlow::new();
+            let code = trace(&cli.emulator, plugin_args, &target, |event| cfg.add(&event))?;
+            // Programs without a symbol table still have a graph, just not one per function
+            let symbolizer = Symbolizer::load(&target.program)
+                .ok()
+                .map(|symbolizer| symbolizer.with_bias(bias.unwrap_or_default()));
+            let mut graphs = cfg.graphs(symbolizer.as_ref());
+
+            if !function.is_empty() {
+                graphs.retain(|graph| {
+                    graph
+                        .name
+                        .as_ref()
+                        .is_some_and(|name| function.contains(name))
+                });
+            }
+
+            let mut out: Box<dyn Write> = match output {
+                Some(path) => Box::new(File::create(path)?),
+                None => Box::new(stdout()),
+            };
+
+            match format {
+                GraphFormat::Dot => cfg.write_dot(&graphs, disassemble.as_deref(), &mut out)?,
+                GraphFormat::Json => writeln!(
+                    out,
+                    "{}",
+                    to_string_pretty(&cfg.to_json(&graphs, disassemble.as_deref()))?
+                )?,
+            }
+
+            code
+        }
+        Command::Strace { target } => {
+            let plugin_args = vec!["log_syscall=true".to_string()];
+            let mut bits = 64;
+            trace(&cli.emulator, plugin_args, &target, |event| {
+                if EventKind::of(&event) == Some(EventKind::Header) {
+                    bits = address_bits(&event);
+                }
+
+                if let Some(line) = strace::format(&event, bits) {
+                    eprintln!("{}", line);
+                }
+            })?
+        }
+        Command::Model { model, target } => {
+            let plugin_args = vec![
+                "log_pc=true".to_string(),
+                "log_opcode=true".to_string(),
+                "log_mem=true".to_string(),
+            ];
+            let mut cpu = Model::new(
+                Cache::new(model.icache_size, model.icache_ways, model.icache_line),
+                Cache::new(model.dcache_size, model.dcache_ways, model.dcache_line),
+                BranchPredictor::new(model.predictor_entries, model.history_bits),
+            );
+            let code = trace(&cli.emulator, plugin_args, &target, |event| cpu.add(&event))?;
+            // Programs without a symbol table are still modeled, just not by function
+            let symbolizer = Symbolizer::load(&target.program)
+                .ok()
+                .map(|symbolizer| symbolizer.with_bias(model.bias.unwrap_or_default()));
+            cpu.write(symbolizer.as_ref(), model.limit, stdout())?;
+            code
+        }
+        Command::Triage {
+            blocks,
+            bias,
+            json,
+            corpus,
+            target,
+        } => {
+            if corpus.input_dir.is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "triage needs a directory of crashing inputs, given with --input-dir",
+                ));
+            }
+
+            let plugin_args = vec!["trace_tb=true".to_string(), "log_signals=true".to_string()];
+            let buckets = Mutex::new(Buckets::new());
+            let traced = trace_corpus(
+                &cli.emulator,
+                plugin_args,
+                &target,
+                &corpus,
+                |name, session| {
+                    let mut recorder = Recorder::new(blocks);
+                    let result = session.run(|event| recorder.add(&event))?;
+                    let crash = recorder.crash(result.signal);
+
+                    if let (Some(output_dir), Some(crash)) = (&corpus.output_dir, &crash) {
+                        let path = output_dir.join(format!("{}.crash", name));
+                        writeln!(File::create(path)?, "{}", crash.to_json())?;
+                    }
+
+                    buckets
+                        .lock()
+                        .expect("Failed to lock buckets")
+                        .add(name, crash);
+                    Ok(result)
+                },
+            )?;
+            let buckets = buckets.into_inner().expect("Failed to lock buckets");
+
+            if json {
+                println!("{}", to_string_pretty(&buckets.to_json())?);
+            } else {
+                // Programs without a symbol table are still triaged, just not symbolized
+                let symbolizer = Symbolizer::load(&target.program)
+                    .ok()
+                    .map(|symbolizer| symbolizer.with_bias(bias.unwrap_or_default()));
+                buckets.write(symbolizer.as_ref(), stdout())?;
+            }
+
+            Some(if traced { 0 } else { 1 })
+        }
+        Command::Diff { left, right } => {
+            match first_divergence(read_trace(&left)?, read_trace(&right)?) {
+                Some(divergence) => {
+                    let show = |pc: Option<u64>| match pc {
+                        Some(pc) => format!("{:#x}", pc),
+                        None => "end of trace".to_string(),
+                    };
+                    println!(
+                        "Traces diverge after {} common events: {} != {}",
+                        divergence.index,
+                        show(divergence.left),
+                        show(divergence.right)
+                    );
+                    Some(1)
+                }
+                None => {
+                    println!("Traces executed the same code");
+                    Some(0)
+                }
+            }
+        }
+        Command::Merge {
+            traces,
+            output,
+            output_format,
+            filter: FilterArgs { filter },
+        } => {
+            let traces = traces
+                .iter()
+                .map(|path| read_trace(path))
+                .collect::<io::Result<Vec<_>>>()?;
+            let mut write = filtering(filter, event_writer(output.as_ref(), output_format, None)?);
+
+            for event in merge(traces) {
+                write(event);
+            }
+
+            Some(0)
+        }
+        Command::Analyze {
+            trace,
+            analyzer,
+            load,
+            json,
+            list,
+        } => {
+            let mut registry = Registry::default();
+
+            for library in &load {
+                // The library is trusted like any code the user asks to run
+                unsafe { registry.load(library)? };
+            }
+
+            if list {
+                for (name, description) in registry.analyzers() {
+                    println!("{:<10} {}", name, description);
+                }
+            } else {
+                let mut analyzers = Analyzers::new();
+
+                for spec in analyzer {
+                    let created = registry.create(&spec)?;
+                    analyzers.add(spec, created);
+                }
+
+                let trace = trace.expect("No trace to analyze");
+
+                for event in events(BufReader::new(File::open(trace)?)) {
+                    analyzers.on_event(&event?);
+                }
+
+                for (name, report) in analyzers.finish() {
+                    if json {
+                        println!("{}", json!({"analyzer": name, "report": report.data}));
+                    } else {
+                        println!("{}:", name);
+                        println!("{}", report.text.trim_end());
+                    }
+                }
+            }
+
+            Some(0)
+        }
+        Command::Replay {
+            trace: path,
+            output,
+            output_format,
+            filter: FilterArgs { filter },
+        } => {
+            let header = read_trace(&path)?
+                .into_iter()
+                .find(|event| EventKind::of(event) == Some(EventKind::Header))
+                .expect("The trace has no header!");
+            let (program, args) =
+                guest_command(&header).expect("The header has no guest command line!");
+            let plugin_args = plugin_args(&header).expect("The header has no plugin arguments!");
+            let target = Target {
+                input_file: None,
+                stdio: StdioOptions::default(),
+                limits: LimitOptions::default(),
+                stats: false,
+                baseline: false,
+                insn_mix: false,
+                tee: Vec::new(),
+                program,
+                args,
+            };
+            let metadata = session(&cli.emulator, plugin_args.clone(), &target).metadata()?;
+            trace(
+                &cli.emulator,
+                plugin_args,
+                &target,
+                filtering(
+                    filter,
+                    event_writer(output.as_ref(), output_format, Some(&metadata))?,
+                ),
+            )?
+        }
+    };
+
+    exit(code.unwrap_or(1));
+}
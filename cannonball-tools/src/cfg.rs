@@ -0,0 +1,281 @@
+//! Control flow graphs
+//!
+//! A `ControlFlow` rebuilds the control flow graph of each function a program executed from a
+//! trace of its translation blocks and control flow edges (`trace_tb` and `log_edges`, cheapest
+//! with `dedup`). Blocks are grouped into functions with a `Symbolizer`, like in a profile, and
+//! each block and edge carries the number of times it executed. Edges into the first block of
+//! another function are kept as calls, and other edges between functions, like returns, are
+//! left out. Only the code that executed is in the graphs, and blocks are QEMU's translation
+//! blocks, so a jump into the middle of a block makes a block of its own that overlaps it.
+//!
+//! The graphs are written as Graphviz DOT, with a cluster per function, or as JSON, with the
+//! instructions of each block if a disassembler is given.
+//!
+//! ```no_run
+//! use cannonball_tools::{cfg::ControlFlow, symbols::Symbolizer, trace::events};
+//! use std::{fs::File, io::{stdout, BufReader}};
+//!
+//! let mut cfg = ControlFlow::new();
+//!
+//! for event in events(BufReader::new(File::open("ls.trace").unwrap())) {
+//!     cfg.add(&event.unwrap());
+//! }
+//!
+//! let symbolizer = Symbolizer::load("/bin/ls").unwrap();
+//! cfg.write_dot(&cfg.graphs(Some(&symbolizer)), None, stdout()).unwrap();
+//! ```
+
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+};
+
+use crate::{
+    profile::{Block, Disassemble, Profile},
+    symbols::Symbolizer,
+    trace::EventKind,
+};
+
+/// The formats control flow graphs are written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz DOT, to render with `dot -Tsvg`
+    #[default]
+    Dot,
+    /// A JSON object with the blocks, edges and calls of each function
+    Json,
+}
+
+/// The control flow graph of a function
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Graph {
+    /// The name of the function, if the symbolizer knows it
+    pub name: Option<String>,
+    /// The blocks of the function that executed, by address
+    pub blocks: BTreeMap<u64, Block>,
+    /// The edges between its blocks that executed, by source and destination block, with the
+    /// number of times they did
+    pub edges: BTreeMap<(u64, u64), u64>,
+    /// The calls from its blocks to the first block of other functions, by source and
+    /// destination block, with the number of times they were made
+    pub calls: BTreeMap<(u64, u64), u64>,
+}
+
+/// The blocks and edges a program executed, to build control flow graphs from
+#[derive(Debug, Clone, Default)]
+pub struct ControlFlow {
+    /// Every executed block
+    pub profile: Profile,
+    /// Every edge taken, by the address of the last instruction of its source block and the
+    /// address of its destination block, with the number of times it was
+    pub edges: BTreeMap<(u64, u64), u64>,
+}
+
+impl ControlFlow {
+    /// Instantiate a new empty `ControlFlow`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Account for an event. Events other than translation block and edge events are ignored
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn add(&mut self, event: &Value) {
+        if EventKind::of(event) != Some(EventKind::Edge) {
+            self.profile.add(event);
+            return;
+        }
+
+        let field = |name: &str| event.get(name).and_then(Value::as_u64);
+        let (Some(src), Some(dst)) = (field("src"), field("dst")) else {
+            return;
+        };
+
+        *self.edges.entry((src, dst)).or_default() += 1;
+    }
+
+    /// The address of the block an instruction is in, if it executed
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the instruction
+    fn block_of(&self, addr: u64) -> Option<u64> {
+        let (&vaddr, block) = self.profile.blocks.range(..=addr).next_back()?;
+        (addr - vaddr < block.size).then_some(vaddr)
+    }
+
+    /// The control flow graph of each function, in order of the address of their first block.
+    /// Blocks outside of the functions the symbolizer knows are grouped together, with no name
+    ///
+    /// # Arguments
+    ///
+    /// * `symbolizer` - Names the functions blocks are in. If not set, every block is in a
+    ///   single graph
+    pub fn graphs(&self, symbolizer: Option<&Symbolizer>) -> Vec<Graph> {
+        let symbolize = |vaddr: u64| symbolizer.and_then(|symbolizer| symbolizer.symbolize(vaddr));
+
+        let mut graphs = Vec::<Graph>::new();
+        let mut indices = HashMap::<Option<&str>, usize>::new();
+        let mut functions = HashMap::<u64, usize>::new();
+
+        for (&vaddr, block) in &self.profile.blocks {
+            let name = symbolize(vaddr).map(|(name, _)| name);
+            let index = *indices.entry(name).or_insert_with(|| {
+                graphs.push(Graph {
+                    name: name.map(|name| name.to_string()),
+                    ..Default::default()
+                });
+                graphs.len() - 1
+            });
+
+            graphs[index].blocks.insert(vaddr, *block);
+            functions.insert(vaddr, index);
+        }
+
+        for (&(src, dst), &hits) in &self.edges {
+            let Some(src) = self.block_of(src) else {
+                continue;
+            };
+            let (Some(&from), Some(&to)) = (functions.get(&src), functions.get(&dst)) else {
+                continue;
+            };
+
+            if from == to {
+                *graphs[from].edges.entry((src, dst)).or_default() += hits;
+            } else if symbolize(dst).is_some_and(|(_, offset)| offset == 0) {
+                *graphs[from].calls.entry((src, dst)).or_default() += hits;
+            }
+        }
+
+        graphs
+    }
+
+    /// Write control flow graphs as a Graphviz DOT digraph, with a cluster per function. Each
+    /// block is labeled with its address and hit count, and its instructions if a disassembler
+    /// is given, and each edge with its hit count. Calls are dashed
+    ///
+    /// # Arguments
+    ///
+    /// * `graphs` - The graphs, from `graphs`
+    /// * `disassemble` - Disassembles a block given its address and size
+    /// * `out` - Where to write the graphs
+    pub fn write_dot(
+        &self,
+        graphs: &[Graph],
+        disassemble: Option<&Disassemble<'_>>,
+        mut out: impl Write,
+    ) -> io::Result<()> {
+        writeln!(out, "digraph cfg {{")?;
+        writeln!(out, "    node [shape=box, fontname=monospace];")?;
+
+        for (index, graph) in graphs.iter().enumerate() {
+            writeln!(out, "    subgraph cluster_{} {{", index)?;
+            writeln!(
+                out,
+                "        label=\"{}\";",
+                escape(graph.name.as_deref().unwrap_or("??"))
+            )?;
+
+            for (vaddr, block) in &graph.blocks {
+                let mut label = format!("{:#x} ({} hits)\\l", vaddr, block.hits);
+
+                if let Some(disassemble) = disassemble {
+                    for (addr, insn) in disassemble(*vaddr, block.size) {
+                        label.push_str(&escape(&format!("{:#x}: {}", addr, insn)));
+                        label.push_str("\\l");
+                    }
+                }
+
+                writeln!(out, "        \"{:#x}\" [label=\"{}\"];", vaddr, label)?;
+            }
+
+            writeln!(out, "    }}")?;
+        }
+
+        for graph in graphs {
+            for ((src, dst), hits) in &graph.edges {
+                writeln!(
+                    out,
+                    "    \"{:#x}\" -> \"{:#x}\" [label=\"{}\"];",
+                    src, dst, hits
+                )?;
+            }
+
+            for ((src, dst), hits) in &graph.calls {
+                writeln!(
+                    out,
+                    "    \"{:#x}\" -> \"{:#x}\" [label=\"{}\", style=dashed];",
+                    src, dst, hits
+                )?;
+            }
+        }
+
+        writeln!(out, "}}")
+    }
+
+    /// Control flow graphs as JSON: the blocks, edges and calls of each function, with the
+    /// instructions of each block if a disassembler is given
+    ///
+    /// # Arguments
+    ///
+    /// * `graphs` - The graphs, from `graphs`
+    /// * `disassemble` - Disassembles a block given its address and size
+    pub fn to_json(&self, graphs: &[Graph], disassemble: Option<&Disassemble<'_>>) -> Value {
+        let edges = |edges: &BTreeMap<(u64, u64), u64>| {
+            edges
+                .iter()
+                .map(|((src, dst), hits)| json!({"src": src, "dst": dst, "hits": hits}))
+                .collect::<Vec<_>>()
+        };
+
+        let functions = graphs
+            .iter()
+            .map(|graph| {
+                let blocks = graph
+                    .blocks
+                    .iter()
+                    .map(|(vaddr, block)| {
+                        let mut value = json!({
+                            "vaddr": vaddr,
+                            "size": block.size,
+                            "n_insns": block.n_insns,
+                            "hits": block.hits,
+                        });
+
+                        if let Some(disassemble) = disassemble {
+                            value["insns"] = disassemble(*vaddr, block.size)
+                                .into_iter()
+                                .map(|(addr, insn)| json!({"addr": addr, "insn": insn}))
+                                .collect();
+                        }
+
+                        value
+                    })
+                    .collect::<Vec<_>>();
+
+                json!({
+                    "name": graph.name,
+                    "blocks": blocks,
+                    "edges": edges(&graph.edges),
+                    "calls": edges(&graph.calls),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json!({ "functions": functions })
+    }
+}
+
+/// Escape text to put in a quoted DOT string
+///
+/// # Arguments
+///
+/// * `text` - The text
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,221 @@
+//! Chrome trace export
+//!
+//! `ChromeWriter` converts a trace to the Chrome `trace_event` JSON format, which
+//! ui.perfetto.dev and `chrome://tracing` open directly. Each VCPU of each process is a track:
+//!
+//! * Function entries and exits, and calls and returns, are nested slices
+//! * System calls are slices of their own
+//! * Execs, forks, triggers, breakpoints and the ends of fork server runs are instants
+//!
+//! Events carry no timestamps, so time in the exported trace is the position of an event in
+//! the trace, one microsecond per event. Slices are as long as the number of events logged
+//! during them. Instruction, block, memory and edge events only advance time, since there
+//! would be far too many of them to show.
+
+use serde_json::{json, to_writer, Value};
+
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+};
+
+use crate::{output::EventWriter, trace::EventKind};
+
+/// Writes events as a Chrome trace
+pub struct ChromeWriter<W: Write> {
+    /// Where the Chrome trace is written
+    out: W,
+    /// The number of events written so far, which is also the current time
+    seq: u64,
+    /// The tracks named so far, by process and VCPU
+    tracks: HashSet<(u64, u64)>,
+    /// Whether any trace event has been written, so the next one needs a separator
+    started: bool,
+    /// Whether the trace has been closed
+    finished: bool,
+}
+
+impl<W: Write> ChromeWriter<W> {
+    /// Instantiate a new `ChromeWriter`
+    ///
+    /// # Arguments
+    ///
+    /// * `out` - Where to write the Chrome trace
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            seq: 0,
+            tracks: HashSet::new(),
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// Write a trace event
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The trace event
+    fn emit(&mut self, event: Value) -> io::Result<()> {
+        self.out.write_all(if self.started {
+            b",\n"
+        } else {
+            b"{\"traceEvents\":[\n"
+        })?;
+        self.started = true;
+        to_writer(&mut self.out, &event)?;
+        Ok(())
+    }
+
+    /// Name the track of a VCPU the first time it is seen
+    ///
+    /// # Arguments
+    ///
+    /// * `pid` - The process the VCPU belongs to
+    /// * `tid` - The VCPU
+    fn track(&mut self, pid: u64, tid: u64) -> io::Result<()> {
+        if self.tracks.insert((pid, tid)) {
+            self.emit(json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": pid,
+                "tid": tid,
+                "args": { "name": format!("vcpu {}", tid) },
+            }))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> EventWriter for ChromeWriter<W> {
+    fn write(&mut self, event: &Value) -> io::Result<()> {
+        let ts = self.seq;
+        self.seq += 1;
+
+        let Some(kind) = EventKind::of(event) else {
+            return Ok(());
+        };
+
+        let field = |name: &str| event.get(name).and_then(Value::as_u64);
+        let pid = field("pid").unwrap_or(0);
+        let tid = field("vcpu_idx").unwrap_or(0);
+        let hex = |name: &str| field(name).map(|value| format!("{:#x}", value));
+
+        let (name, ph, cat) = match kind {
+            EventKind::Header => {
+                let name = event
+                    .get("target_name")
+                    .and_then(Value::as_str)
+                    .unwrap_or("target");
+
+                return self.emit(json!({
+                    "name": "process_name",
+                    "ph": "M",
+                    "pid": pid,
+                    "args": { "name": name },
+                }));
+            }
+            EventKind::FunctionEnter | EventKind::FunctionExit => (
+                event
+                    .get("function")
+                    .and_then(Value::as_str)
+                    .unwrap_or("?")
+                    .to_string(),
+                if kind == EventKind::FunctionEnter {
+                    "B"
+                } else {
+                    "E"
+                },
+                "function",
+            ),
+            EventKind::Call => (hex("target").unwrap_or_default(), "B", "call"),
+            EventKind::Return => (String::new(), "E", "call"),
+            EventKind::Syscall => {
+                let num = event.get("num").and_then(Value::as_i64).unwrap_or(-1);
+                self.track(pid, tid)?;
+
+                return self.emit(json!({
+                    "name": format!("syscall_{}", num),
+                    "ph": "X",
+                    "cat": "syscall",
+                    "ts": ts,
+                    "dur": 1,
+                    "pid": pid,
+                    "tid": tid,
+                    "args": { "args": event.get("args"), "rv": event.get("rv") },
+                }));
+            }
+            EventKind::Exec => ("exec".to_string(), "i", "process"),
+            EventKind::Fork => ("fork".to_string(), "i", "process"),
+            EventKind::Trigger => ("trigger".to_string(), "i", "window"),
+            EventKind::Breakpoint => ("breakpoint".to_string(), "i", "breakpoint"),
+            EventKind::Discon => (
+                event
+                    .get("discon")
+                    .and_then(Value::as_str)
+                    .unwrap_or("discon")
+                    .to_lowercase(),
+                "i",
+                "discon",
+            ),
+            EventKind::Vcpu => (
+                event
+                    .get("vcpu")
+                    .and_then(Value::as_str)
+                    .unwrap_or("vcpu")
+                    .to_lowercase(),
+                "i",
+                "vcpu",
+            ),
+            EventKind::Signal => (
+                event
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .unwrap_or("signal")
+                    .to_string(),
+                "i",
+                "signal",
+            ),
+            EventKind::Map => ("mmap".to_string(), "i", "memory"),
+            EventKind::Unmap => ("munmap".to_string(), "i", "memory"),
+            EventKind::Protect => ("mprotect".to_string(), "i", "memory"),
+            EventKind::Final => ("exit".to_string(), "i", "process"),
+            EventKind::RunEnd => ("run end".to_string(), "i", "forkserver"),
+            _ => return Ok(()),
+        };
+
+        self.track(pid, tid)?;
+        self.emit(json!({
+            "name": name,
+            "ph": ph,
+            "cat": cat,
+            "ts": ts,
+            "pid": pid,
+            "tid": tid,
+            "args": event,
+        }))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.finished {
+            self.finished = true;
+
+            if !self.started {
+                self.out.write_all(b"{\"traceEvents\":[")?;
+            }
+
+            self.out.write_all(b"\n]}\n")?;
+        }
+
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Drop for ChromeWriter<W> {
+    fn drop(&mut self) {
+        // Like `BufWriter`, a writer that was not finished is finished when dropped, without
+        // reporting errors
+        let _ = self.finish();
+    }
+}
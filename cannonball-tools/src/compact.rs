@@ -0,0 +1,561 @@
+//! Compact traces
+//!
+//! Instruction traces are made of instruction events, most of which are a few bytes past the
+//! instruction executed before them on the same VCPU. The `compact` format writes instruction
+//! and translation block events as a tag byte followed by their fields as variable-length
+//! integers (LEB128), with the address encoded as its distance from the previous address of
+//! the same VCPU, so a sequential instruction takes a handful of bytes instead of a JSON line.
+//! Every other event is written as a length-prefixed CBOR item, like in `binary` traces.
+//!
+//! A compact trace starts with `MAGIC` and the version of the encoding. `TraceReader` checks
+//! them, and `TraceReader::open` reads a trace starting with them as a compact trace whatever
+//! format it is given, so compact traces are decoded transparently.
+//!
+//! Tight loops execute the same instructions over and over, and a loop of one instruction,
+//! like a spin on a lock, logs the same event millions of times in a row. The `compact-repeat`
+//! format, a `CompactWriter` built with `coalescing`, writes a run of identical events as the
+//! first of them followed by a repeat record holding how many more there were. It says so in
+//! its header, as version 2 of the encoding followed by a byte of flags, so readers that
+//! predate repeat records refuse the trace rather than misread it, and traces written without
+//! them stay version 1. `TraceReader` expands the runs back, so readers see every event.
+//!
+//! ```no_run
+//! use cannonball_tools::{output::OutputFormat, trace::TraceReader};
+//!
+//! for event in TraceReader::open("ls.compact", OutputFormat::Compact).unwrap() {
+//!     println!("{}", event.unwrap());
+//! }
+//! ```
+
+use serde_json::{json, Map, Value};
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read, Write},
+};
+
+use crate::output::EventWriter;
+
+/// The first bytes of a compact trace
+pub const MAGIC: [u8; 4] = *b"CBCT";
+
+/// The version of the encoding, written after `MAGIC`
+const VERSION: u8 = 1;
+/// The version of the encoding followed by a byte of flags, written by coalescing writers
+const VERSION_FLAGS: u8 = 2;
+
+/// The trace may hold repeat records, in the flags of its header
+const HEADER_REPEATS: u8 = 1 << 0;
+
+/// An event of any kind, as a length-prefixed CBOR item
+const TAG_EVENT: u8 = 0;
+/// An instruction event
+const TAG_INSN: u8 = 1;
+/// A translation block event
+const TAG_TB: u8 = 2;
+/// The event before, repeated a number of times
+const TAG_REPEAT: u8 = 3;
+
+/// The event has a VCPU index
+const FLAG_VCPU: u8 = 1 << 0;
+/// The instruction event has an opcode
+const FLAG_OPCODE: u8 = 1 << 1;
+/// The instruction ends its translation block
+const FLAG_BRANCH: u8 = 1 << 2;
+
+/// The fields of an instruction event
+struct Insn {
+    vcpu_idx: Option<u64>,
+    vaddr: u64,
+    opcode: Option<Vec<u8>>,
+    branch: bool,
+}
+
+impl Insn {
+    /// The fields of an instruction event, if the event has exactly these and can be encoded
+    /// compactly
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    fn of(event: &Map<String, Value>) -> Option<Self> {
+        if event.len() != 4 {
+            return None;
+        }
+
+        let opcode = match event.get("opcode")? {
+            Value::Null => None,
+            Value::Array(bytes) => Some(
+                bytes
+                    .iter()
+                    .map(|byte| byte.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+                    .collect::<Option<Vec<u8>>>()?,
+            ),
+            _ => return None,
+        };
+
+        Some(Self {
+            vcpu_idx: vcpu_idx(event)?,
+            vaddr: event.get("vaddr")?.as_u64()?,
+            opcode,
+            branch: event.get("branch")?.as_bool()?,
+        })
+    }
+}
+
+/// The fields of a translation block event
+struct Block {
+    vcpu_idx: Option<u64>,
+    vaddr: u64,
+    size: u64,
+    n_insns: u64,
+}
+
+impl Block {
+    /// The fields of a translation block event, if the event has exactly these
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    fn of(event: &Map<String, Value>) -> Option<Self> {
+        if event.len() != 4 {
+            return None;
+        }
+
+        Some(Self {
+            vcpu_idx: vcpu_idx(event)?,
+            vaddr: event.get("vaddr")?.as_u64()?,
+            size: event.get("size")?.as_u64()?,
+            n_insns: event.get("n_insns")?.as_u64()?,
+        })
+    }
+}
+
+/// The VCPU index of an event, which is null for events not logged by a VCPU. Returns `None`
+/// if the event has no or an invalid index
+///
+/// # Arguments
+///
+/// * `event` - The event
+fn vcpu_idx(event: &Map<String, Value>) -> Option<Option<u64>> {
+    match event.get("vcpu_idx")? {
+        Value::Null => Some(None),
+        vcpu_idx => Some(Some(vcpu_idx.as_u64()?)),
+    }
+}
+
+/// The last address of each VCPU, which the addresses of its next events are encoded
+/// relative to
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Deltas {
+    last: HashMap<Option<u64>, u64>,
+}
+
+impl Deltas {
+    /// The distance of an address from the last address of a VCPU, zigzag encoded so that
+    /// short jumps backwards are small too
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_idx` - The VCPU
+    /// * `vaddr` - The address
+    fn encode(&mut self, vcpu_idx: Option<u64>, vaddr: u64) -> u64 {
+        let last = self.last.insert(vcpu_idx, vaddr).unwrap_or_default();
+        let delta = vaddr.wrapping_sub(last) as i64;
+        ((delta << 1) ^ (delta >> 63)) as u64
+    }
+
+    /// The address at a distance from the last address of a VCPU
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_idx` - The VCPU
+    /// * `zigzag` - The distance, as returned by `encode`
+    fn decode(&mut self, vcpu_idx: Option<u64>, zigzag: u64) -> u64 {
+        let delta = ((zigzag >> 1) as i64) ^ -((zigzag & 1) as i64);
+        let last = self.last.entry(vcpu_idx).or_default();
+        *last = last.wrapping_add(delta as u64);
+        *last
+    }
+}
+
+/// The runs of identical events of a trace with repeat records, expanded back as it is read
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Repeats {
+    /// Whether the header of the trace allows repeat records
+    enabled: bool,
+    /// The last event read
+    last: Option<Value>,
+    /// The number of times the last event is left to be repeated
+    left: u64,
+}
+
+impl Repeats {
+    /// The next repetition of the last event, if its run is not over
+    pub(crate) fn next(&mut self) -> Option<Value> {
+        if self.left == 0 {
+            return None;
+        }
+
+        self.left -= 1;
+        self.last.clone()
+    }
+}
+
+/// Append an integer as LEB128
+///
+/// # Arguments
+///
+/// * `buf` - Where to append it
+/// * `value` - The integer
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Read a byte
+///
+/// # Arguments
+///
+/// * `reader` - Where to read it from
+fn get_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// Read an integer written as LEB128
+///
+/// # Arguments
+///
+/// * `reader` - Where to read it from
+fn get_varint(reader: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0;
+
+    for shift in (0..64).step_by(7) {
+        let byte = get_u8(reader)?;
+        value |= ((byte & 0x7f) as u64) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(io::Error::new(ErrorKind::InvalidData, "Integer too long"))
+}
+
+/// Writes events as a compact trace
+pub struct CompactWriter<W: Write> {
+    out: W,
+    /// Whether `MAGIC` and the version have been written
+    started: bool,
+    deltas: Deltas,
+    /// The event being encoded
+    buf: Vec<u8>,
+    /// Whether runs of identical events are written as repeat records
+    coalesce: bool,
+    /// The last event written, when coalescing
+    last: Option<Value>,
+    /// The number of times the last event was repeated since it was written
+    repeats: u64,
+}
+
+impl<W: Write> CompactWriter<W> {
+    /// Instantiate a new `CompactWriter`
+    ///
+    /// # Arguments
+    ///
+    /// * `out` - Where to write the trace
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            started: false,
+            deltas: Deltas::default(),
+            buf: Vec::new(),
+            coalesce: false,
+            last: None,
+            repeats: 0,
+        }
+    }
+
+    /// Write runs of identical events as the first of them and a repeat record. The last run
+    /// is only written when another event ends it or on `finish`
+    ///
+    /// ```
+    /// use cannonball_tools::{
+    ///     compact::CompactWriter,
+    ///     output::{EventWriter, OutputFormat},
+    ///     trace::TraceReader,
+    /// };
+    /// use serde_json::json;
+    ///
+    /// let spin = json!({"vcpu_idx": 0, "vaddr": 0x401000, "opcode": null, "branch": true});
+    /// let mut trace = Vec::new();
+    /// let mut writer = CompactWriter::new(&mut trace).coalescing();
+    ///
+    /// for _ in 0..1000 {
+    ///     writer.write(&spin).unwrap();
+    /// }
+    /// writer.finish().unwrap();
+    /// drop(writer);
+    ///
+    /// // The header, the event and a repeat record
+    /// assert!(trace.len() < 20);
+    ///
+    /// let events = TraceReader::new(trace.as_slice(), OutputFormat::Compact).unwrap();
+    /// assert_eq!(events.map(Result::unwrap).filter(|event| *event == spin).count(), 1000);
+    /// ```
+    pub fn coalescing(mut self) -> Self {
+        self.coalesce = true;
+        self
+    }
+
+    /// Write `MAGIC` and the version, if they have not been yet
+    fn start(&mut self) -> io::Result<()> {
+        if !self.started {
+            self.out.write_all(&MAGIC)?;
+
+            if self.coalesce {
+                self.out.write_all(&[VERSION_FLAGS, HEADER_REPEATS])?;
+            } else {
+                self.out.write_all(&[VERSION])?;
+            }
+
+            self.started = true;
+        }
+
+        Ok(())
+    }
+
+    /// Write the repeat record of the run of the last event, if it repeated
+    fn end_run(&mut self) -> io::Result<()> {
+        if self.repeats == 0 {
+            return Ok(());
+        }
+
+        self.buf.clear();
+        self.buf.push(TAG_REPEAT);
+        put_varint(&mut self.buf, self.repeats);
+        self.repeats = 0;
+
+        self.out.write_all(&self.buf)
+    }
+
+    /// Encode an event into `buf`
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    fn encode(&mut self, event: &Value) -> io::Result<()> {
+        let object = event.as_object();
+        let buf = &mut self.buf;
+
+        if let Some(insn) = object.and_then(Insn::of) {
+            let mut flags = 0;
+
+            if insn.vcpu_idx.is_some() {
+                flags |= FLAG_VCPU;
+            }
+            if insn.opcode.is_some() {
+                flags |= FLAG_OPCODE;
+            }
+            if insn.branch {
+                flags |= FLAG_BRANCH;
+            }
+
+            buf.extend([TAG_INSN, flags]);
+            if let Some(vcpu_idx) = insn.vcpu_idx {
+                put_varint(buf, vcpu_idx);
+            }
+            put_varint(buf, self.deltas.encode(insn.vcpu_idx, insn.vaddr));
+            if let Some(opcode) = insn.opcode {
+                put_varint(buf, opcode.len() as u64);
+                buf.extend(opcode);
+            }
+        } else if let Some(block) = object.and_then(Block::of) {
+            let flags = if block.vcpu_idx.is_some() {
+                FLAG_VCPU
+            } else {
+                0
+            };
+
+            buf.extend([TAG_TB, flags]);
+            if let Some(vcpu_idx) = block.vcpu_idx {
+                put_varint(buf, vcpu_idx);
+            }
+            put_varint(buf, self.deltas.encode(block.vcpu_idx, block.vaddr));
+            put_varint(buf, block.size);
+            put_varint(buf, block.n_insns);
+        } else {
+            let item = serde_cbor::to_vec(event).map_err(io::Error::other)?;
+
+            buf.push(TAG_EVENT);
+            put_varint(buf, item.len() as u64);
+            buf.extend(item);
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> EventWriter for CompactWriter<W> {
+    fn write(&mut self, event: &Value) -> io::Result<()> {
+        self.start()?;
+
+        if self.coalesce {
+            if self.last.as_ref() == Some(event) {
+                self.repeats += 1;
+                return Ok(());
+            }
+
+            self.end_run()?;
+            self.last = Some(event.clone());
+        }
+
+        self.buf.clear();
+        self.encode(event)?;
+        self.out.write_all(&self.buf)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        // A trace with no events still starts with its header
+        self.start()?;
+        self.end_run()?;
+        self.out.flush()
+    }
+}
+
+/// Read and check the header of a compact trace, returning how to expand its runs of events
+///
+/// # Arguments
+///
+/// * `reader` - The trace
+pub(crate) fn read_header(reader: &mut impl Read) -> io::Result<Repeats> {
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+
+    if magic != MAGIC {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Not a compact trace",
+        ));
+    }
+
+    match get_u8(reader)? {
+        VERSION => Ok(Repeats::default()),
+        VERSION_FLAGS => {
+            let flags = get_u8(reader)?;
+
+            if flags & !HEADER_REPEATS != 0 {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unsupported compact trace flags {:#x}", flags),
+                ));
+            }
+
+            Ok(Repeats {
+                enabled: flags & HEADER_REPEATS != 0,
+                ..Default::default()
+            })
+        }
+        version => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Unsupported compact trace version {}", version),
+        )),
+    }
+}
+
+/// Read the next event of a compact trace, after its header, and after the repetitions of
+/// the event before it are taken from `repeats`
+///
+/// # Arguments
+///
+/// * `reader` - The trace
+/// * `deltas` - The last address of each VCPU, shared by every event of the trace
+/// * `repeats` - The runs of events, shared by every event of the trace
+pub(crate) fn read_event(
+    reader: &mut impl Read,
+    deltas: &mut Deltas,
+    repeats: &mut Repeats,
+) -> io::Result<Value> {
+    let tag = get_u8(reader)?;
+
+    if !repeats.enabled {
+        return read_tagged(reader, tag, deltas);
+    }
+
+    if tag == TAG_REPEAT {
+        let count = get_varint(reader)?;
+
+        return match &repeats.last {
+            Some(last) if count > 0 => {
+                repeats.left = count - 1;
+                Ok(last.clone())
+            }
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Repeat record without an event to repeat",
+            )),
+        };
+    }
+
+    let event = read_tagged(reader, tag, deltas)?;
+    repeats.last = Some(event.clone());
+    Ok(event)
+}
+
+/// Read the rest of an event of a compact trace, after its tag
+///
+/// # Arguments
+///
+/// * `reader` - The trace
+/// * `tag` - The tag of the event
+/// * `deltas` - The last address of each VCPU, shared by every event of the trace
+fn read_tagged(reader: &mut impl Read, tag: u8, deltas: &mut Deltas) -> io::Result<Value> {
+    if tag == TAG_EVENT {
+        let mut item = vec![0; get_varint(reader)? as usize];
+        reader.read_exact(&mut item)?;
+
+        return serde_cbor::from_slice(&item).map_err(io::Error::other);
+    }
+
+    let flags = get_u8(reader)?;
+    let vcpu_idx = if flags & FLAG_VCPU != 0 {
+        Some(get_varint(reader)?)
+    } else {
+        None
+    };
+    let vaddr = deltas.decode(vcpu_idx, get_varint(reader)?);
+
+    match tag {
+        TAG_INSN => {
+            let opcode = if flags & FLAG_OPCODE != 0 {
+                let mut opcode = vec![0; get_varint(reader)? as usize];
+                reader.read_exact(&mut opcode)?;
+                Some(opcode)
+            } else {
+                None
+            };
+
+            Ok(json!({
+                "vcpu_idx": vcpu_idx,
+                "vaddr": vaddr,
+                "opcode": opcode,
+                "branch": flags & FLAG_BRANCH != 0,
+            }))
+        }
+        TAG_TB => Ok(json!({
+            "vcpu_idx": vcpu_idx,
+            "vaddr": vaddr,
+            "size": get_varint(reader)?,
+            "n_insns": get_varint(reader)?,
+        })),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Unknown event tag {}", tag),
+        )),
+    }
+}
@@ -0,0 +1,317 @@
+//! Reconfiguring a trace while it runs
+//!
+//! Some events are only worth logging for part of a run, like memory accesses once the
+//! program reaches the code of interest. A `ControlHandle` from `TraceSession::control` tells
+//! the Jaivana plugin which events to log while the program runs, over a Unix socket. It can
+//! be cloned and used from another thread while the session runs.
+//!
+//! It also sets breakpoints. With `TraceOptions::break_pause`, a VCPU that hits one waits
+//! until `resume` is called, after the `BreakpointEvent` reached the session. Meanwhile its
+//! registers and memory can be read with `registers` and `read_memory`, if the plugin was
+//! built for a plugin API version that can read them.
+//!
+//! ```no_run
+//! use cannonball_tools::TraceSession;
+//!
+//! let mut session = TraceSession::new("/bin/ls");
+//! let control = session.control().unwrap();
+//!
+//! std::thread::spawn(move || {
+//!     std::thread::sleep(std::time::Duration::from_millis(100));
+//!     control.enable("log_syscall").unwrap();
+//! });
+//!
+//! session.run(|event| println!("{}", event)).unwrap();
+//! ```
+
+use serde_json::{from_str, Value};
+
+use std::{
+    env::temp_dir,
+    fs::remove_file,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/// How long a command waits for the plugin to connect, which it does as QEMU starts
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a command checks whether the plugin has connected
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The number of control sockets created by this process so far
+static CONTROL_SOCKETS: AtomicUsize = AtomicUsize::new(0);
+
+/// The socket shared by the clones of a handle
+struct Channel {
+    /// The socket the plugin connects to
+    path: PathBuf,
+    /// Accepts the plugin's connection
+    listener: UnixListener,
+    /// The connection to the plugin, once it has connected
+    stream: Mutex<Option<BufReader<UnixStream>>>,
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// A register of a VCPU held at a breakpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Register {
+    /// The name QEMU's gdbstub gives the register, like `rip`
+    pub name: String,
+    /// The gdbstub feature the register belongs to, like `org.gnu.gdb.i386.core`
+    pub feature: String,
+    /// The value of the register in the guest's byte order, empty if it could not be read
+    pub value: Vec<u8>,
+}
+
+/// Read a list of bytes from a reply of the plugin
+///
+/// # Arguments
+///
+/// * `value` - The list, if any
+fn bytes(value: Option<&Value>) -> Vec<u8> {
+    value
+        .and_then(Value::as_array)
+        .map(|bytes| {
+            bytes
+                .iter()
+                .filter_map(Value::as_u64)
+                .map(|byte| byte as u8)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Changes what a running trace logs
+#[derive(Clone)]
+pub struct ControlHandle {
+    channel: Arc<Channel>,
+}
+
+impl ControlHandle {
+    /// Listen for the plugin on a new socket
+    pub(crate) fn listen() -> io::Result<Self> {
+        let path = temp_dir().join(format!(
+            "cannonball-{}-control-{}.sock",
+            process::id(),
+            CONTROL_SOCKETS.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let _ = remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            channel: Arc::new(Channel {
+                path,
+                listener,
+                stream: Mutex::new(None),
+            }),
+        })
+    }
+
+    /// The socket the plugin connects to
+    pub(crate) fn path(&self) -> &Path {
+        &self.channel.path
+    }
+
+    /// Set a boolean plugin argument selecting events, like `log_mem`. Every translation
+    /// block is instrumented again, so this is not free
+    ///
+    /// # Arguments
+    ///
+    /// * `arg` - The argument, one of `log_pc`, `log_opcode`, `log_branch`, `log_mem`,
+    ///   `log_reads`, `log_writes`, `log_syscall`, `log_vcpu`, `log_signals`, `trace_tb`, `dedup`,
+    ///   `log_edges` and `log_calls`
+    /// * `value` - Its new value
+    pub fn set(&self, arg: &str, value: bool) -> io::Result<()> {
+        self.command(&format!("set {} {}", arg, value)).map(|_| ())
+    }
+
+    /// Start logging the events selected by a plugin argument, like `log_mem`
+    ///
+    /// # Arguments
+    ///
+    /// * `arg` - The argument, as for `set`
+    pub fn enable(&self, arg: &str) -> io::Result<()> {
+        self.set(arg, true)
+    }
+
+    /// Stop logging the events selected by a plugin argument, like `log_mem`
+    ///
+    /// # Arguments
+    ///
+    /// * `arg` - The argument, as for `set`
+    pub fn disable(&self, arg: &str) -> io::Result<()> {
+        self.set(arg, false)
+    }
+
+    /// Log only one in every `sample_rate` instruction events on each VCPU
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The new sample rate, at least 1
+    pub fn set_sample_rate(&self, sample_rate: u64) -> io::Result<()> {
+        self.command(&format!("set sample_rate {}", sample_rate))
+            .map(|_| ())
+    }
+
+    /// Log a `BreakpointEvent` each time the instruction at `pc` executes, holding the VCPU
+    /// until `resume` if breakpoints pause
+    ///
+    /// # Arguments
+    ///
+    /// * `pc` - The address of the instruction
+    pub fn add_breakpoint(&self, pc: u64) -> io::Result<()> {
+        self.command(&format!("break {:#x}", pc)).map(|_| ())
+    }
+
+    /// Remove the breakpoint at `pc`
+    ///
+    /// # Arguments
+    ///
+    /// * `pc` - The address of the breakpoint
+    pub fn remove_breakpoint(&self, pc: u64) -> io::Result<()> {
+        self.command(&format!("delete {:#x}", pc)).map(|_| ())
+    }
+
+    /// Set whether breakpoints hold the VCPUs that hit them until `resume`
+    ///
+    /// # Arguments
+    ///
+    /// * `pause` - Whether VCPUs are held
+    pub fn set_break_pause(&self, pause: bool) -> io::Result<()> {
+        self.command(&format!("set break_pause {}", pause))
+            .map(|_| ())
+    }
+
+    /// Continue every VCPU held at a breakpoint, returning how many there were
+    pub fn resume(&self) -> io::Result<usize> {
+        let reply = self.command("continue")?;
+        Ok(reply
+            .get("resumed")
+            .and_then(Value::as_u64)
+            .unwrap_or_default() as usize)
+    }
+
+    /// The registers of a VCPU held at a breakpoint, in the order of the register numbers of
+    /// QEMU's gdbstub. This needs the plugin to be built with `plugin-api-v2` (QEMU 9.0)
+    pub fn registers(&self) -> io::Result<Vec<Register>> {
+        let reply = self.command("regs")?;
+        let field = |register: &Value, name: &str| {
+            register
+                .get(name)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        Ok(reply
+            .get("registers")
+            .and_then(Value::as_array)
+            .map(|registers| {
+                registers
+                    .iter()
+                    .map(|register| Register {
+                        name: field(register, "name"),
+                        feature: field(register, "feature"),
+                        value: bytes(register.get("value")),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Read the memory of a VCPU held at a breakpoint. This needs the plugin to be built with
+    /// `plugin-api-v4` (QEMU 9.2)
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The first address to read
+    /// * `len` - The number of bytes to read
+    pub fn read_memory(&self, addr: u64, len: usize) -> io::Result<Vec<u8>> {
+        let reply = self.command(&format!("read {:#x} {}", addr, len))?;
+        Ok(bytes(reply.get("bytes")))
+    }
+
+    /// Write out the events buffered by the plugin now, instead of once its buffers fill up
+    pub fn flush(&self) -> io::Result<()> {
+        self.command("flush").map(|_| ())
+    }
+
+    /// The number of events and bytes the plugin has written out so far, as `events` and
+    /// `bytes`
+    pub fn stats(&self) -> io::Result<Value> {
+        self.command("stats")
+    }
+
+    /// Send a command to the plugin, waiting for it to connect first, and return its reply
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command, without its newline
+    fn command(&self, command: &str) -> io::Result<Value> {
+        let mut stream = self.channel.stream.lock().expect("Failed to lock stream");
+
+        if stream.is_none() {
+            *stream = Some(BufReader::new(self.accept()?));
+        }
+
+        let stream = stream.as_mut().expect("No stream");
+        writeln!(stream.get_mut(), "{}", command)?;
+
+        let mut reply = String::new();
+
+        if stream.read_line(&mut reply)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The plugin closed the control channel",
+            ));
+        }
+
+        let reply = from_str::<Value>(&reply)?;
+
+        match reply.get("error").and_then(Value::as_str) {
+            Some(error) => Err(io::Error::new(io::ErrorKind::InvalidInput, error)),
+            None => Ok(reply),
+        }
+    }
+
+    /// Wait for the plugin to connect
+    fn accept(&self) -> io::Result<UnixStream> {
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+
+        loop {
+            match self.channel.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    return Ok(stream);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "The plugin did not connect to the control channel",
+                ));
+            }
+
+            sleep(POLL_INTERVAL);
+        }
+    }
+}
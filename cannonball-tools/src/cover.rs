@@ -0,0 +1,186 @@
+//! Block coverage
+//!
+//! Coverage is collected from translation block events. A trace recorded with deduplication
+//! (`dedup=on`) logs each block the first time it executes and its hit count on exit, which is
+//! all coverage needs, but a trace of every block execution works too.
+//!
+//! Fuzzers want coverage in a different shape: `EdgeMap` counts control flow edges (`log_edges`)
+//! in a fixed-size map of saturating hit counts, like the coverage map of AFL.
+
+use serde_json::{json, Value};
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+use crate::{
+    analyze::{Analyzer, Report},
+    trace::EventKind,
+};
+
+/// A covered translation block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    /// The size of the block in bytes
+    pub size: u64,
+    /// The number of times the block executed
+    pub hits: u64,
+}
+
+/// The translation blocks executed in a trace, by address
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    /// Every covered block
+    pub blocks: BTreeMap<u64, Block>,
+}
+
+impl Coverage {
+    /// Instantiate a new empty `Coverage`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Account for an event. Events other than translation block events are ignored
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn add(&mut self, event: &Value) {
+        let kind = EventKind::of(event);
+
+        if !matches!(kind, Some(EventKind::TB) | Some(EventKind::BlockHits)) {
+            return;
+        }
+
+        let field = |name: &str| event.get(name).and_then(Value::as_u64);
+        let (Some(vaddr), Some(size)) = (field("vaddr"), field("size")) else {
+            return;
+        };
+
+        let block = self.blocks.entry(vaddr).or_insert(Block { size, hits: 0 });
+
+        match kind {
+            // The hit count on exit already includes the first execution
+            Some(EventKind::BlockHits) => block.hits = field("hits").unwrap_or(block.hits),
+            _ => block.hits += 1,
+        }
+    }
+
+    /// Add the coverage of another trace, like another input to the same program
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The coverage of the other trace
+    pub fn merge(&mut self, other: &Coverage) {
+        for (vaddr, block) in &other.blocks {
+            self.blocks
+                .entry(*vaddr)
+                .and_modify(|merged| merged.hits += block.hits)
+                .or_insert(*block);
+        }
+    }
+
+    /// Write the coverage out, one block per line as its address, size, and hit count
+    ///
+    /// # Arguments
+    ///
+    /// * `out` - Where to write the coverage
+    pub fn write(&self, mut out: impl Write) -> io::Result<()> {
+        for (vaddr, block) in &self.blocks {
+            writeln!(out, "{:#x} {} {}", vaddr, block.size, block.hits)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A fixed-size map of control flow edge hit counts. Each edge is hashed to an entry, so
+/// distinct edges may share one, and counts saturate instead of wrapping
+#[derive(Debug, Clone)]
+pub struct EdgeMap {
+    /// The hit counts. Boxed, so pointers to it stay valid when the map moves
+    map: Box<[u8]>,
+}
+
+impl EdgeMap {
+    /// Instantiate a new empty `EdgeMap`
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The number of entries, like 65536 for AFL. Must not be zero
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0, "An edge map needs at least one entry");
+
+        Self {
+            map: vec![0; size].into_boxed_slice(),
+        }
+    }
+
+    /// Account for an event. Events other than edge events are ignored
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn add(&mut self, event: &Value) {
+        if EventKind::of(event) != Some(EventKind::Edge) {
+            return;
+        }
+
+        let field = |name: &str| event.get(name).and_then(Value::as_u64);
+        let (Some(src), Some(dst)) = (field("src"), field("dst")) else {
+            return;
+        };
+
+        // Spread nearby addresses over the map, and shift the source so that A -> B and B -> A
+        // are different edges, as AFL does with its random block ids
+        let id = |addr: u64| (addr ^ (addr >> 16)).wrapping_mul(0x9e3779b97f4a7c15);
+        let index = ((id(src) >> 1) ^ id(dst)) as usize % self.map.len();
+
+        self.map[index] = self.map[index].saturating_add(1);
+    }
+
+    /// The hit counts
+    pub fn as_slice(&self) -> &[u8] {
+        &self.map
+    }
+
+    /// A pointer to the hit counts, for observers that read the map in place
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.map.as_mut_ptr()
+    }
+
+    /// The number of entries
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether the map has no entries, which is never the case
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Reset every hit count to zero
+    pub fn clear(&mut self) {
+        self.map.fill(0);
+    }
+}
+
+impl Analyzer for Coverage {
+    fn on_event(&mut self, event: &Value) {
+        self.add(event);
+    }
+
+    fn on_end(&mut self) -> Report {
+        let mut text = Vec::new();
+        self.write(&mut text).expect("Could not write coverage");
+
+        let blocks = self
+            .blocks
+            .iter()
+            .map(|(vaddr, block)| json!({"vaddr": vaddr, "size": block.size, "hits": block.hits}))
+            .collect();
+
+        Report::new(String::from_utf8_lossy(&text), Value::Array(blocks))
+    }
+}
@@ -0,0 +1,144 @@
+//! Control flow comparison
+//!
+//! Compares the executed code of two traces, and finds the first point where they diverge.
+//! Both traces must log the same kind of execution events (instructions or translation
+//! blocks) for the comparison to make sense. As an `Analyzer`, `Diff` compares a trace to one
+//! read beforehand.
+
+use serde_json::{json, Value};
+
+use crate::{
+    analyze::{Analyzer, Report},
+    trace::pc,
+};
+
+/// The first point at which two traces executed different code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    /// The number of execution events both traces have in common before diverging
+    pub index: usize,
+    /// The address executed by the left trace, if it had not ended
+    pub left: Option<u64>,
+    /// The address executed by the right trace, if it had not ended
+    pub right: Option<u64>,
+}
+
+/// Find the first divergence between the execution events of two traces, if they diverge
+///
+/// # Arguments
+///
+/// * `left` - The events of the first trace
+/// * `right` - The events of the second trace
+pub fn first_divergence(
+    left: impl IntoIterator<Item = Value>,
+    right: impl IntoIterator<Item = Value>,
+) -> Option<Divergence> {
+    let mut left = left.into_iter().filter_map(|event| pc(&event));
+    let mut right = right.into_iter().filter_map(|event| pc(&event));
+    let mut index = 0;
+
+    loop {
+        match (left.next(), right.next()) {
+            (None, None) => return None,
+            (l, r) if l != r => {
+                return Some(Divergence {
+                    index,
+                    left: l,
+                    right: r,
+                })
+            }
+            _ => index += 1,
+        }
+    }
+}
+
+/// Compares a trace, event by event, to a reference trace
+#[derive(Debug, Clone, Default)]
+pub struct Diff {
+    /// The addresses executed by the reference trace
+    reference: Vec<u64>,
+    /// The number of execution events both traces have in common so far
+    index: usize,
+    /// The first divergence, once found
+    divergence: Option<Divergence>,
+}
+
+impl Diff {
+    /// Instantiate a new `Diff`, comparing to the execution events of a trace. The trace
+    /// compared to it is the left one of the divergence
+    ///
+    /// # Arguments
+    ///
+    /// * `reference` - The events of the reference trace
+    pub fn new(reference: impl IntoIterator<Item = Value>) -> Self {
+        Self {
+            reference: reference
+                .into_iter()
+                .filter_map(|event| pc(&event))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The first divergence between the traces so far, if they diverged
+    pub fn divergence(&self) -> Option<Divergence> {
+        self.divergence
+    }
+}
+
+impl Analyzer for Diff {
+    fn on_event(&mut self, event: &Value) {
+        let Some(pc) = pc(event) else {
+            return;
+        };
+
+        if self.divergence.is_some() {
+            return;
+        }
+
+        let right = self.reference.get(self.index).copied();
+
+        if right == Some(pc) {
+            self.index += 1;
+        } else {
+            self.divergence = Some(Divergence {
+                index: self.index,
+                left: Some(pc),
+                right,
+            });
+        }
+    }
+
+    fn on_end(&mut self) -> Report {
+        // The trace ended first
+        if self.divergence.is_none() && self.index < self.reference.len() {
+            self.divergence = Some(Divergence {
+                index: self.index,
+                left: None,
+                right: Some(self.reference[self.index]),
+            });
+        }
+
+        let show = |pc: Option<u64>| match pc {
+            Some(pc) => format!("{:#x}", pc),
+            None => "end of trace".to_string(),
+        };
+
+        match self.divergence {
+            Some(divergence) => Report::new(
+                format!(
+                    "Traces diverge after {} common events: {} != {}\n",
+                    divergence.index,
+                    show(divergence.left),
+                    show(divergence.right)
+                ),
+                json!({
+                    "index": divergence.index,
+                    "left": divergence.left,
+                    "right": divergence.right,
+                }),
+            ),
+            None => Report::new("Traces executed the same code\n", Value::Null),
+        }
+    }
+}
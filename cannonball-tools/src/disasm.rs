@@ -0,0 +1,119 @@
+//! Disassembly
+//!
+//! Traces log the addresses of the code that executed, and its bytes only when opcodes are
+//! logged. `Disassembler` reads the code of a program from its loadable segments instead, and
+//! disassembles it as x86-64, the architecture the driver runs programs as. Like `Symbolizer`,
+//! it must be given the address a position independent executable was loaded at.
+//!
+//! ```no_run
+//! use cannonball_tools::disasm::Disassembler;
+//!
+//! let disassembler = Disassembler::load("/bin/ls").unwrap();
+//!
+//! for (addr, insn) in disassembler.disassemble(0x401000, 16) {
+//!     println!("{:#x}: {}", addr, insn);
+//! }
+//! ```
+
+use goblin::elf::{program_header::PT_LOAD, Elf};
+use yaxpeax_arch::{Decoder, LengthedInstruction, U8Reader};
+use yaxpeax_x86::long_mode::InstDecoder;
+
+use std::{fs::read, io, path::Path};
+
+/// A loadable segment of a program
+#[derive(Debug, Clone)]
+struct Segment {
+    /// The address of the segment, before relocation
+    vaddr: u64,
+    /// The bytes of the segment that are in the file
+    data: Vec<u8>,
+}
+
+/// Disassembles the code of a program
+#[derive(Debug, Clone)]
+pub struct Disassembler {
+    /// The loadable segments of the program
+    segments: Vec<Segment>,
+    /// The address the program was loaded at, relative to its segments
+    bias: u64,
+}
+
+impl Disassembler {
+    /// Load the code of an ELF program
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The program
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let data = read(path)?;
+        let elf = Elf::parse(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let segments = elf
+            .program_headers
+            .iter()
+            .filter(|header| header.p_type == PT_LOAD)
+            .filter_map(|header| {
+                Some(Segment {
+                    vaddr: header.p_vaddr,
+                    data: data.get(header.file_range())?.to_vec(),
+                })
+            })
+            .collect();
+
+        Ok(Self { segments, bias: 0 })
+    }
+
+    /// Disassemble code of the program loaded at `bias`
+    ///
+    /// # Arguments
+    ///
+    /// * `bias` - The address the program was loaded at, relative to its segments
+    pub fn with_bias(mut self, bias: u64) -> Self {
+        self.bias = bias;
+        self
+    }
+
+    /// The bytes of the program at an address, up to the end of the segment they are in
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address
+    fn bytes(&self, addr: u64) -> Option<&[u8]> {
+        let addr = addr.checked_sub(self.bias)?;
+
+        self.segments.iter().find_map(|segment| {
+            let offset = usize::try_from(addr.checked_sub(segment.vaddr)?).ok()?;
+            segment.data.get(offset..)
+        })
+    }
+
+    /// Disassemble the instructions in a range of the program. Disassembly stops early at
+    /// bytes that do not decode or are not in the program, like code of a shared library
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the first instruction
+    /// * `size` - The size of the range in bytes
+    pub fn disassemble(&self, addr: u64, size: u64) -> Vec<(u64, String)> {
+        let Some(bytes) = self.bytes(addr) else {
+            return Vec::new();
+        };
+
+        let decoder = InstDecoder::default();
+        let mut reader = U8Reader::new(bytes);
+        let mut insns = Vec::new();
+        let mut offset = 0;
+
+        while offset < size {
+            let Ok(insn) = decoder.decode(&mut reader) else {
+                break;
+            };
+
+            insns.push((addr + offset, insn.to_string()));
+            offset += insn.len();
+        }
+
+        insns
+    }
+}
@@ -0,0 +1,1029 @@
+//! Running programs under QEMU with the Jaivana plugin
+//!
+//! `TraceOptions` selects the events the plugin logs, and `Driver` runs a program with them.
+//! Jaivana writes its events to the same stdout as the traced program, so the driver splits
+//! that stream: lines that are JSON objects are events, and anything else is output of the
+//! program.
+//!
+//! By default the program reads stdin of this process and writes its stderr to this process's
+//! stderr. With `StdioOptions::tty` it runs in a new pseudo-terminal instead, so interactive
+//! programs that check for a terminal can be traced, and its output can be copied to files.
+//! `LimitOptions` bounds the time and resources a program may use. Signals this process
+//! receives to interrupt it, like Ctrl+C, are forwarded to the program so that it exits
+//! through QEMU and the events logged until then are still read.
+//!
+//! Programs run under the QEMU the `qemu` crate builds along with this crate, executed from
+//! memory, with the default `embedded-qemu` feature. `Qemu::Path` runs a QEMU binary on disk
+//! instead, like one installed by a distribution package, and so does setting the `QEMU`
+//! environment variable to its path. Without the feature, QEMU is never built, and
+//! `qemu-x86_64` is looked up in `PATH` unless `QEMU` says otherwise.
+
+use clap::Args;
+#[cfg(feature = "embedded-qemu")]
+use memfd_exec::MemFdExecutable;
+#[cfg(feature = "embedded-qemu")]
+use qemu::qemu_x86_64;
+use serde_json::{from_str, Value};
+
+use std::{
+    collections::HashMap,
+    env::var_os,
+    ffi::OsStr,
+    fs::File,
+    io::{self, copy, stderr, stdin, BufRead, BufReader, BufWriter, Read, Write},
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::{self, Command},
+    sync::{Mutex, OnceLock},
+    thread::spawn,
+    time::Duration,
+};
+
+use crate::{
+    pty::Pty,
+    watch::{limit, Watcher},
+};
+
+#[derive(Args, Debug, Clone)]
+/// The events to log, and the part of the execution to log them in
+pub struct TraceOptions {
+    /// Whether to log instructions. If set, all instructions will be logged.
+    #[clap(short, long)]
+    pub insns: bool,
+    /// Only log one in every N instructions on each VCPU, for a statistical profile of long executions.
+    #[clap(short = 'r', long, default_value_t = 1)]
+    pub sample_rate: u64,
+    /// Whether to log branches. If `insns` is not set, only branch instructions will be logged.
+    #[clap(short, long)]
+    pub branches: bool,
+    /// Whether to log opcodes. If not set, only the instruction address will be log
+    #[clap(short, long)]
+    pub opcodes: bool,
+    /// Only log the first N bytes of each opcode, which is usually enough to tell instructions apart at a fraction of the trace size. All of them if not set.
+    #[clap(long, requires = "opcodes")]
+    pub opcode_size: Option<u64>,
+    /// Whether to log syscalls. If set, all syscalls will be logged.
+    #[clap(short, long)]
+    pub syscalls: bool,
+    /// Only log this syscall, by name, like openat. May be given multiple times.
+    #[clap(long, requires = "syscalls")]
+    pub syscall_filter: Vec<String>,
+    /// Do not log this syscall, by name, like futex. May be given multiple times.
+    #[clap(long, requires = "syscalls")]
+    pub syscall_exclude: Vec<String>,
+    /// Whether to log translation blocks instead of instructions. If set, one event is logged per executed translation block, and `insns` and `branches` are ignored.
+    #[clap(short, long)]
+    pub tbs: bool,
+    /// Whether to log each translation block only the first time it executes. If set, the number of times each block executed is logged on exit.
+    #[clap(short, long)]
+    pub dedup: bool,
+    /// Whether to log control flow edges. If set, an edge is logged each time execution moves from one translation block to the next.
+    #[clap(short, long)]
+    pub edges: bool,
+    /// Whether to log calls and returns, with the call stack depth. Only supported for x86, ARM and RISC-V programs.
+    #[clap(short, long)]
+    pub calls: bool,
+    /// Whether to log VCPUs being created and destroyed and, under system emulation, going idle and resuming.
+    #[clap(long)]
+    pub vcpu: bool,
+    /// Whether to log signals delivered to the program, and where it crashed if one killed it. Only supported in user mode.
+    #[clap(long)]
+    pub signals: bool,
+    /// Whether to log the changes the program makes to its memory map with mmap, munmap and mprotect. Only supported in user mode.
+    #[clap(long)]
+    pub maps: bool,
+    /// Whether to only trace code executed from memory the program mapped writable, like the code emitted by a JIT compiler. Only supported in user mode.
+    #[clap(long)]
+    pub trace_writable: bool,
+    /// Only trace the code of this module: `main` for the program itself, which needs the plugin to be built with `plugin-api-v2`, or the file name of a library it loads, like libfoo.so. May be given multiple times. Only supported in user mode.
+    #[clap(long)]
+    pub trace_module: Vec<String>,
+    /// Whether to log interrupts, exceptions and host calls. Needs the plugin to be built with `plugin-api-v5`.
+    #[clap(long)]
+    pub discon: bool,
+    /// Whether to log function entries and exits. Functions are read from the symbol table of the program. Only supported for x86, ARM and RISC-V programs.
+    #[clap(short, long)]
+    pub functions: bool,
+    /// Only log entries and exits of this function. May be given multiple times.
+    #[clap(short = 'F', long, requires = "functions")]
+    pub function: Vec<String>,
+    /// Whether to tag every event with the PID of the process that produced it, to tell apart the events of forked children.
+    #[clap(short = 'P', long)]
+    pub tag_pids: bool,
+    /// Whether to tag every event with the wall-clock time it was logged at, to merge the traces of separate programs into one timeline with `merge`.
+    #[clap(long)]
+    pub tag_time: bool,
+    /// Whether to log memory accesses. If set, memory accesses for already instrumented instructions will be logged.
+    #[clap(short, long)]
+    pub mem: bool,
+    /// Whether to log memory loads. Like `mem`, but stores are only logged if `writes` is set.
+    #[clap(long)]
+    pub reads: bool,
+    /// Whether to log memory stores. Like `mem`, but loads are only logged if `reads` is set.
+    #[clap(long)]
+    pub writes: bool,
+    /// Only log memory accesses to this range, given as 0xADDR:LEN:MODE where MODE is r, w or rw. Implies `mem`. May be given multiple times.
+    #[clap(short = 'W', long)]
+    pub watch: Vec<String>,
+    /// Log a breakpoint event each time the instruction at this address executes, e.g. 0x401000. May be given multiple times.
+    #[clap(long)]
+    pub break_pc: Vec<String>,
+    /// Hold a VCPU that hits a breakpoint until `ControlHandle::resume`. Needs a control channel, so it is only set by library users.
+    #[clap(skip)]
+    pub break_pause: bool,
+    /// Add a breakpoint at the entry point of the program, so it can be held before it runs. Needs the plugin to be built with `plugin-api-v2`.
+    #[clap(skip)]
+    pub break_entry: bool,
+    /// Only start tracing after this many instructions have executed.
+    #[clap(long)]
+    pub start_after_insns: Option<u64>,
+    /// Stop tracing after this many instructions have executed.
+    #[clap(long)]
+    pub stop_after_insns: Option<u64>,
+    /// Only start tracing after this many milliseconds.
+    #[clap(long)]
+    pub start_after_ms: Option<u64>,
+    /// Stop tracing after this many milliseconds.
+    #[clap(long)]
+    pub stop_after_ms: Option<u64>,
+    /// Only start tracing once the instruction at this address executes, e.g. 0x401000.
+    #[clap(long)]
+    pub start_pc: Option<String>,
+    /// Stop tracing once the instruction at this address executes, e.g. 0x401000.
+    #[clap(long)]
+    pub stop_pc: Option<String>,
+    /// The number of events the plugin buffers on each VCPU before writing them out. Larger batches are cheaper but arrive later. 4096 if not set.
+    #[clap(long)]
+    pub batch_size: Option<u64>,
+    /// The longest the plugin buffers events before writing them out, in milliseconds, for traces that log events slowly. No limit if not set.
+    #[clap(long)]
+    pub flush_interval_ms: Option<u64>,
+    /// Record the result of every system call, with the buffers it wrote to, to this replay log. Buffers are only recorded if the plugin is built with `plugin-api-v4`.
+    #[clap(long)]
+    pub replay_log: Option<PathBuf>,
+    /// Have the plugin write events to this sink instead of handing them to cannonball, as a framed binary stream: fd:N for a file descriptor this process inherited, fifo:PATH for a named pipe, file:PATH for files named PATH.0, PATH.1 and so on, vsock:CID:PORT for a consumer outside of the virtual machine, like `cannonball receive` on the host at CID 2, or tcp:HOST:PORT for a consumer on another machine. For pipelines like `cannonball run --sink fd:3 prog 3>&1 >/dev/null | analyzer`.
+    #[clap(long)]
+    pub sink: Option<String>,
+    /// Encrypt the events written to a vsock or TCP sink with the hex-encoded 32 byte key in this file, which the consumer must know too, like `cannonball receive --psk-file`. Needs the plugin to be built with the noise feature.
+    #[clap(long)]
+    pub sink_psk_file: Option<PathBuf>,
+    /// The number of times the plugin tries again to connect to a vsock or TCP sink that is not listening yet, before giving up. 0 if not set.
+    #[clap(long)]
+    pub sink_retries: Option<u32>,
+    /// How long the plugin waits between attempts to connect to a vsock or TCP sink, in milliseconds. 100 if not set.
+    #[clap(long)]
+    pub sink_retry_interval_ms: Option<u64>,
+    /// Have the plugin write events to this file instead if it cannot connect to its vsock or TCP sink, rather than failing to start.
+    #[clap(long)]
+    pub sink_fallback: Option<PathBuf>,
+    /// What the plugin does when it cannot write events out, like when the consumer of its sink exits early: terminate, to stop writing events and let the program run on, drop, to drop the events the consumer misses, or retry or retry:N, to write them again up to 3 or N times, connecting a vsock or TCP sink again first. terminate if not set.
+    #[clap(long)]
+    pub sink_on_error: Option<String>,
+    /// Have the plugin start a new file once the current one of a file sink would grow past this size, in bytes or with a K, M or G suffix, like 1G, so long-running programs do not fill the disk. Every file starts with the header of the trace.
+    #[clap(long)]
+    pub sink_rotate_size: Option<String>,
+    /// The number of files a rotated file sink keeps, removing the oldest ones. All of them if not set.
+    #[clap(long)]
+    pub sink_keep: Option<u64>,
+}
+
+impl Default for TraceOptions {
+    /// No events, logged over the whole execution
+    fn default() -> Self {
+        Self {
+            insns: false,
+            sample_rate: 1,
+            branches: false,
+            opcodes: false,
+            opcode_size: None,
+            syscalls: false,
+            syscall_filter: Vec::new(),
+            syscall_exclude: Vec::new(),
+            tbs: false,
+            dedup: false,
+            edges: false,
+            calls: false,
+            vcpu: false,
+            signals: false,
+            maps: false,
+            trace_writable: false,
+            trace_module: Vec::new(),
+            discon: false,
+            functions: false,
+            function: Vec::new(),
+            tag_pids: false,
+            tag_time: false,
+            mem: false,
+            reads: false,
+            writes: false,
+            watch: Vec::new(),
+            break_pc: Vec::new(),
+            break_pause: false,
+            break_entry: false,
+            start_after_insns: None,
+            stop_after_insns: None,
+            start_after_ms: None,
+            stop_after_ms: None,
+            start_pc: None,
+            stop_pc: None,
+            batch_size: None,
+            flush_interval_ms: None,
+            replay_log: None,
+            sink: None,
+            sink_psk_file: None,
+            sink_retries: None,
+            sink_retry_interval_ms: None,
+            sink_fallback: None,
+            sink_on_error: None,
+            sink_rotate_size: None,
+            sink_keep: None,
+        }
+    }
+}
+
+impl TraceOptions {
+    /// The arguments to pass to the plugin for these options
+    ///
+    /// # Arguments
+    ///
+    /// * `program` - The program that will be traced, whose symbols are read when tracing
+    ///   functions
+    pub fn plugin_args(&self, program: &Path) -> Vec<String> {
+        let mut args = vec![
+            format!("log_pc={}", self.insns),
+            format!("log_branch={}", self.branches),
+            format!("log_opcode={}", self.opcodes),
+            format!("log_syscall={}", self.syscalls),
+            format!("log_mem={}", self.mem),
+            format!("log_reads={}", self.reads),
+            format!("log_writes={}", self.writes),
+            format!("trace_tb={}", self.tbs),
+            format!("log_edges={}", self.edges),
+            format!("log_calls={}", self.calls),
+            format!("dedup={}", self.dedup),
+            format!("sample_rate={}", self.sample_rate),
+            format!("tag_pids={}", self.tag_pids),
+            format!("tag_time={}", self.tag_time),
+        ];
+
+        for (name, value) in [
+            ("start_after_insns", self.start_after_insns),
+            ("stop_after_insns", self.stop_after_insns),
+            ("start_after_ms", self.start_after_ms),
+            ("stop_after_ms", self.stop_after_ms),
+            ("batch_size", self.batch_size),
+            ("flush_interval_ms", self.flush_interval_ms),
+            ("opcode_size", self.opcode_size),
+        ] {
+            if let Some(value) = value {
+                args.push(format!("{}={}", name, value));
+            }
+        }
+
+        for (name, value) in [
+            ("trace_start_pc", &self.start_pc),
+            ("trace_stop_pc", &self.stop_pc),
+        ] {
+            if let Some(value) = value {
+                args.push(format!("{}={}", name, value));
+            }
+        }
+
+        for watch in &self.watch {
+            args.push(format!("watch={}", watch));
+        }
+
+        for pc in &self.break_pc {
+            args.push(format!("break_pc={}", pc));
+        }
+
+        if self.vcpu {
+            args.push("log_vcpu=true".to_string());
+        }
+
+        if self.signals {
+            args.push("log_signals=true".to_string());
+        }
+
+        if self.maps {
+            args.push("log_maps=true".to_string());
+        }
+
+        if self.trace_writable {
+            args.push("trace_writable=true".to_string());
+        }
+
+        for (name, syscalls) in [
+            ("syscall_filter", &self.syscall_filter),
+            ("syscall_exclude", &self.syscall_exclude),
+        ] {
+            if !syscalls.is_empty() {
+                args.push(format!("{}={}", name, syscalls.join(",")));
+            }
+        }
+
+        if !self.trace_module.is_empty() {
+            args.push(format!("trace_modules={}", self.trace_module.join(",")));
+        }
+
+        if self.discon {
+            args.push("log_discon=true".to_string());
+        }
+
+        if self.break_pause {
+            args.push("break_pause=true".to_string());
+        }
+
+        if self.break_entry {
+            args.push("break_entry=true".to_string());
+        }
+
+        if let Some(replay_log) = &self.replay_log {
+            args.push(format!("replay_log={}", replay_log.to_string_lossy()));
+        }
+
+        if let Some(sink) = &self.sink {
+            args.push(format!("sink={}", sink));
+        }
+
+        if let Some(psk_file) = &self.sink_psk_file {
+            args.push(format!("sink_psk_file={}", psk_file.to_string_lossy()));
+        }
+
+        if let Some(retries) = self.sink_retries {
+            args.push(format!("sink_retries={}", retries));
+        }
+
+        if let Some(interval) = self.sink_retry_interval_ms {
+            args.push(format!("sink_retry_interval_ms={}", interval));
+        }
+
+        if let Some(fallback) = &self.sink_fallback {
+            args.push(format!("sink_fallback={}", fallback.to_string_lossy()));
+        }
+
+        if let Some(on_error) = &self.sink_on_error {
+            args.push(format!("sink_on_error={}", on_error));
+        }
+
+        if let Some(rotate_size) = &self.sink_rotate_size {
+            args.push(format!("sink_rotate_size={}", rotate_size));
+        }
+
+        if let Some(keep) = self.sink_keep {
+            args.push(format!("sink_keep={}", keep));
+        }
+
+        if self.functions {
+            args.push("trace_functions=true".to_string());
+            args.push(format!("binary={}", program.to_string_lossy()));
+
+            if !self.function.is_empty() {
+                args.push(format!("functions={}", self.function.join(",")));
+            }
+        }
+
+        args
+    }
+}
+
+#[derive(Args, Debug, Clone, Default)]
+/// How the stdio of the traced program is connected
+pub struct StdioOptions {
+    /// Whether to run the program in a new pseudo-terminal, for interactive programs. Its stderr is then part of its output.
+    #[clap(long)]
+    pub tty: bool,
+    /// Also write the output of the program to this file.
+    #[clap(long)]
+    pub output_file: Option<PathBuf>,
+    /// Also write stderr of the program to this file. Not supported with `tty`.
+    #[clap(long, conflicts_with = "tty")]
+    pub stderr_file: Option<PathBuf>,
+}
+
+#[derive(Args, Debug, Clone)]
+/// Limits on the time and resources the traced program may use
+pub struct LimitOptions {
+    /// Kill the program after this many seconds.
+    #[clap(long)]
+    pub timeout: Option<u64>,
+    /// The signal to kill the program with when it times out. The default, SIGTERM, lets the plugin flush its events unless the program handles it.
+    #[clap(long, default_value_t = libc::SIGTERM)]
+    pub kill_signal: i32,
+    /// Limit the memory QEMU and the program may allocate to this many MiB.
+    #[clap(long)]
+    pub memory_limit: Option<u64>,
+    /// Limit the CPU time QEMU and the program may use to this many seconds.
+    #[clap(long)]
+    pub cpu_limit: Option<u64>,
+}
+
+impl Default for LimitOptions {
+    /// No limits
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            kill_signal: libc::SIGTERM,
+            memory_limit: None,
+            cpu_limit: None,
+        }
+    }
+}
+
+/// How a traced program exited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exit {
+    /// The exit code of the program, if it exited normally
+    pub code: Option<i32>,
+    /// The signal that killed the program, if it did not exit normally
+    pub signal: Option<i32>,
+    /// Whether the program was killed for running past its timeout
+    pub timed_out: bool,
+}
+
+/// The environment variable naming the QEMU binary to run programs under
+pub const QEMU_VAR: &str = "QEMU";
+
+/// The QEMU programs are run under
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Qemu {
+    /// The QEMU built by the `qemu` crate along with this crate, executed from memory
+    #[cfg(feature = "embedded-qemu")]
+    Embedded,
+    /// A QEMU user mode binary on disk, like `/usr/bin/qemu-x86_64`. A path without a
+    /// directory is looked up in `PATH`
+    Path(PathBuf),
+}
+
+impl Default for Qemu {
+    /// The binary named by the `QEMU` environment variable if it is set, the embedded QEMU
+    /// otherwise, and `qemu-x86_64` from `PATH` without the `embedded-qemu` feature
+    fn default() -> Self {
+        if let Some(path) = var_os(QEMU_VAR).filter(|path| !path.is_empty()) {
+            return Self::Path(PathBuf::from(path));
+        }
+
+        #[cfg(feature = "embedded-qemu")]
+        {
+            Self::Embedded
+        }
+
+        #[cfg(not(feature = "embedded-qemu"))]
+        {
+            Self::Path(PathBuf::from("qemu-x86_64"))
+        }
+    }
+}
+
+impl Qemu {
+    /// A command running this QEMU, without arguments
+    pub(crate) fn command(&self) -> QemuCommand {
+        match self {
+            #[cfg(feature = "embedded-qemu")]
+            Self::Embedded => {
+                QemuCommand::Embedded(MemFdExecutable::new("qemu-x86_64", qemu_x86_64()))
+            }
+            Self::Path(path) => QemuCommand::Path(Command::new(path)),
+        }
+    }
+
+    /// The version of this QEMU, like `7.1.0`, if it can be found. Each QEMU is only asked
+    /// once
+    pub fn version(&self) -> Option<String> {
+        static VERSIONS: OnceLock<Mutex<HashMap<Qemu, Option<String>>>> = OnceLock::new();
+
+        let versions = VERSIONS.get_or_init(Default::default);
+
+        if let Some(version) = versions.lock().unwrap().get(self) {
+            return version.clone();
+        }
+
+        let version = (|| {
+            // Like `qemu-x86_64 version 7.1.0`, followed by the copyright
+            let output = self
+                .command()
+                .arg("--version")
+                .stdout(Pipe::Piped)
+                .stderr(Pipe::Null)
+                .output()
+                .ok()?;
+            let stdout = String::from_utf8_lossy(&output);
+            let mut words = stdout.lines().next()?.split_whitespace();
+
+            words.find(|&word| word == "version")?;
+            words.next().map(|version| version.to_string())
+        })();
+
+        versions
+            .lock()
+            .unwrap()
+            .insert(self.clone(), version.clone());
+        version
+    }
+}
+
+/// Where a standard stream of QEMU is connected
+pub(crate) enum Pipe {
+    /// The same stream of this process
+    Inherit,
+    /// Nowhere
+    Null,
+    /// A pipe to this process
+    Piped,
+    /// A file, like a terminal
+    File(File),
+}
+
+impl From<Pipe> for process::Stdio {
+    fn from(pipe: Pipe) -> Self {
+        match pipe {
+            Pipe::Inherit => Self::inherit(),
+            Pipe::Null => Self::null(),
+            Pipe::Piped => Self::piped(),
+            Pipe::File(file) => file.into(),
+        }
+    }
+}
+
+#[cfg(feature = "embedded-qemu")]
+impl From<Pipe> for memfd_exec::Stdio {
+    fn from(pipe: Pipe) -> Self {
+        match pipe {
+            Pipe::Inherit => Self::inherit(),
+            Pipe::Null => Self::null(),
+            Pipe::Piped => Self::piped(),
+            Pipe::File(file) => file.into(),
+        }
+    }
+}
+
+/// A command running QEMU, from memory or from disk
+pub(crate) enum QemuCommand {
+    #[cfg(feature = "embedded-qemu")]
+    Embedded(MemFdExecutable<'static>),
+    Path(Command),
+}
+
+impl QemuCommand {
+    /// Add an argument
+    ///
+    /// # Arguments
+    ///
+    /// * `arg` - The argument
+    pub(crate) fn arg(&mut self, arg: impl AsRef<OsStr>) -> &mut Self {
+        match self {
+            #[cfg(feature = "embedded-qemu")]
+            Self::Embedded(exe) => {
+                exe.arg(arg);
+            }
+            Self::Path(command) => {
+                command.arg(arg);
+            }
+        }
+
+        self
+    }
+
+    /// Add arguments
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - The arguments
+    pub(crate) fn args(&mut self, args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> &mut Self {
+        for arg in args {
+            self.arg(arg);
+        }
+
+        self
+    }
+
+    /// Connect stdin
+    ///
+    /// # Arguments
+    ///
+    /// * `pipe` - Where to connect it
+    pub(crate) fn stdin(&mut self, pipe: Pipe) -> &mut Self {
+        match self {
+            #[cfg(feature = "embedded-qemu")]
+            Self::Embedded(exe) => {
+                exe.stdin(pipe);
+            }
+            Self::Path(command) => {
+                command.stdin(pipe);
+            }
+        }
+
+        self
+    }
+
+    /// Connect stdout
+    ///
+    /// # Arguments
+    ///
+    /// * `pipe` - Where to connect it
+    pub(crate) fn stdout(&mut self, pipe: Pipe) -> &mut Self {
+        match self {
+            #[cfg(feature = "embedded-qemu")]
+            Self::Embedded(exe) => {
+                exe.stdout(pipe);
+            }
+            Self::Path(command) => {
+                command.stdout(pipe);
+            }
+        }
+
+        self
+    }
+
+    /// Connect stderr
+    ///
+    /// # Arguments
+    ///
+    /// * `pipe` - Where to connect it
+    pub(crate) fn stderr(&mut self, pipe: Pipe) -> &mut Self {
+        match self {
+            #[cfg(feature = "embedded-qemu")]
+            Self::Embedded(exe) => {
+                exe.stderr(pipe);
+            }
+            Self::Path(command) => {
+                command.stderr(pipe);
+            }
+        }
+
+        self
+    }
+
+    /// Start QEMU
+    pub(crate) fn spawn(&mut self) -> io::Result<QemuChild> {
+        match self {
+            #[cfg(feature = "embedded-qemu")]
+            Self::Embedded(exe) => exe.spawn().map(QemuChild::Embedded),
+            Self::Path(command) => command.spawn().map(QemuChild::Path),
+        }
+    }
+
+    /// Run QEMU to completion, returning what it wrote to stdout
+    pub(crate) fn output(&mut self) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "embedded-qemu")]
+            Self::Embedded(exe) => exe.output().map(|output| output.stdout),
+            Self::Path(command) => command.output().map(|output| output.stdout),
+        }
+    }
+}
+
+/// A running QEMU
+pub(crate) enum QemuChild {
+    #[cfg(feature = "embedded-qemu")]
+    Embedded(memfd_exec::Child),
+    Path(process::Child),
+}
+
+impl QemuChild {
+    /// The process ID of QEMU
+    pub(crate) fn id(&self) -> u32 {
+        match self {
+            #[cfg(feature = "embedded-qemu")]
+            Self::Embedded(child) => child.id(),
+            Self::Path(child) => child.id(),
+        }
+    }
+
+    /// The pipe to stdin of QEMU, if it was piped and not taken yet
+    pub(crate) fn take_stdin(&mut self) -> Option<Box<dyn Write + Send>> {
+        match self {
+            #[cfg(feature = "embedded-qemu")]
+            Self::Embedded(child) => Some(Box::new(child.stdin.take()?)),
+            Self::Path(child) => Some(Box::new(child.stdin.take()?)),
+        }
+    }
+
+    /// The pipe from stdout of QEMU, if it was piped and not taken yet
+    pub(crate) fn take_stdout(&mut self) -> Option<Box<dyn Read + Send>> {
+        match self {
+            #[cfg(feature = "embedded-qemu")]
+            Self::Embedded(child) => Some(Box::new(child.stdout.take()?)),
+            Self::Path(child) => Some(Box::new(child.stdout.take()?)),
+        }
+    }
+
+    /// The pipe from stderr of QEMU, if it was piped and not taken yet
+    pub(crate) fn take_stderr(&mut self) -> Option<Box<dyn Read + Send>> {
+        match self {
+            #[cfg(feature = "embedded-qemu")]
+            Self::Embedded(child) => Some(Box::new(child.stderr.take()?)),
+            Self::Path(child) => Some(Box::new(child.stderr.take()?)),
+        }
+    }
+
+    /// Kill QEMU
+    pub(crate) fn kill(&mut self) -> io::Result<()> {
+        match self {
+            #[cfg(feature = "embedded-qemu")]
+            Self::Embedded(child) => child.kill(),
+            Self::Path(child) => child.kill(),
+        }
+    }
+
+    /// Wait for QEMU to exit, returning how it did
+    pub(crate) fn wait(&mut self) -> io::Result<Exit> {
+        match self {
+            #[cfg(feature = "embedded-qemu")]
+            Self::Embedded(child) => child.wait().map(|status| Exit {
+                code: status.code(),
+                signal: status.signal(),
+                timed_out: false,
+            }),
+            Self::Path(child) => child.wait().map(|status| Exit {
+                code: status.code(),
+                signal: status.signal(),
+                timed_out: false,
+            }),
+        }
+    }
+
+    /// Whether QEMU exited, without waiting for it to
+    pub(crate) fn exited(&mut self) -> io::Result<bool> {
+        match self {
+            #[cfg(feature = "embedded-qemu")]
+            Self::Embedded(child) => child.try_wait().map(|status| status.is_some()),
+            Self::Path(child) => child.try_wait().map(|status| status.is_some()),
+        }
+    }
+}
+
+/// The Jaivana plugin built alongside the running executable, which is where cargo puts it
+/// when building the workspace
+#[cfg(not(feature = "embedded-plugin"))]
+pub fn default_plugin() -> io::Result<PathBuf> {
+    cannonball_plugin_dist::find("jaivana")
+}
+
+/// The Jaivana plugin embedded in the running executable, extracted to the cache directory
+#[cfg(feature = "embedded-plugin")]
+pub fn default_plugin() -> io::Result<PathBuf> {
+    cannonball_plugin_dist::plugin_path()
+}
+
+/// Runs programs under QEMU with the Jaivana plugin
+pub struct Driver {
+    /// The QEMU programs run under
+    qemu: Qemu,
+    /// The path of the plugin
+    plugin: PathBuf,
+    /// The arguments passed to the plugin, unescaped
+    plugin_args: Vec<String>,
+    /// How the stdio of programs is connected
+    stdio: StdioOptions,
+    /// Limits on the time and resources programs may use
+    limits: LimitOptions,
+}
+
+impl Driver {
+    /// Instantiate a new `Driver`
+    ///
+    /// # Arguments
+    ///
+    /// * `plugin` - The path of the plugin
+    /// * `plugin_args` - The arguments to pass to the plugin, like `log_pc=true`. Commas in
+    ///   values are escaped when QEMU is run
+    pub fn new(plugin: PathBuf, plugin_args: Vec<String>) -> Self {
+        Self {
+            qemu: Qemu::default(),
+            plugin,
+            plugin_args,
+            stdio: StdioOptions::default(),
+            limits: LimitOptions::default(),
+        }
+    }
+
+    /// Run programs under another QEMU
+    ///
+    /// # Arguments
+    ///
+    /// * `qemu` - The QEMU
+    pub fn qemu(mut self, qemu: Qemu) -> Self {
+        self.qemu = qemu;
+        self
+    }
+
+    /// Connect the stdio of programs differently
+    ///
+    /// # Arguments
+    ///
+    /// * `stdio` - How the stdio of programs is connected
+    pub fn stdio(mut self, stdio: StdioOptions) -> Self {
+        self.stdio = stdio;
+        self
+    }
+
+    /// Limit the time and resources programs may use
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - The limits
+    pub fn limits(mut self, limits: LimitOptions) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// The QEMU command running a program under the plugin, with its stdio left to set up
+    ///
+    /// # Arguments
+    ///
+    /// * `program` - The program to run
+    /// * `args` - The arguments to the program
+    pub(crate) fn command(&self, program: &Path, args: &[String]) -> io::Result<QemuCommand> {
+        let mut plugin = self.plugin.canonicalize()?.to_string_lossy().to_string();
+
+        // QEMU splits plugin arguments on commas, so commas in values must be doubled
+        for arg in &self.plugin_args {
+            plugin.push(',');
+            plugin.push_str(&arg.replace(',', ",,"));
+        }
+
+        let mut exe = self.qemu.command();
+        exe.arg("-plugin")
+            .arg(plugin)
+            .arg("--")
+            .arg(program.canonicalize()?)
+            .args(args);
+
+        Ok(exe)
+    }
+
+    /// The limits on the time and resources programs may use
+    pub(crate) fn limit_options(&self) -> &LimitOptions {
+        &self.limits
+    }
+
+    /// Run a program to completion, returning how it exited. Events are passed to `on_event`
+    /// and the output of the program to `on_output`, line by line and in order
+    ///
+    /// # Arguments
+    ///
+    /// * `program` - The program to run
+    /// * `args` - The arguments to the program
+    /// * `input` - Data to feed to the program on stdin. If not set, the program takes input
+    ///   from the stdin of this process, through its terminal if it runs in one
+    /// * `on_event` - Called with each event
+    /// * `on_output` - Called with each line the program writes to stdout
+    pub fn run(
+        &self,
+        program: &Path,
+        args: &[String],
+        input: Option<Vec<u8>>,
+        mut on_event: impl FnMut(Value),
+        mut on_output: impl FnMut(&str),
+    ) -> io::Result<Exit> {
+        let pty = if self.stdio.tty {
+            Some(Pty::open()?)
+        } else {
+            None
+        };
+
+        // Dropped once the program is spawned, so that only the program holds the terminal
+        // and reading it ends when the program exits
+        let mut exe = {
+            let mut exe = self.command(program, args)?;
+
+            match &pty {
+                Some(pty) => {
+                    exe.stdin(Pipe::File(pty.slave.try_clone()?))
+                        .stdout(Pipe::File(pty.slave.try_clone()?))
+                        .stderr(Pipe::File(pty.slave.try_clone()?));
+                }
+                None => {
+                    exe.stdin(if input.is_some() {
+                        Pipe::Piped
+                    } else {
+                        Pipe::Inherit
+                    })
+                    .stdout(Pipe::Piped)
+                    .stderr(if self.stdio.stderr_file.is_some() {
+                        Pipe::Piped
+                    } else {
+                        Pipe::Inherit
+                    });
+                }
+            }
+
+            exe.spawn()?
+        };
+
+        // Set once QEMU is running, so the limits cover QEMU starting up but not this process
+        if let Some(memory_limit) = self.limits.memory_limit {
+            // Not RLIMIT_AS, because QEMU reserves much more address space than it uses
+            limit(exe.id(), libc::RLIMIT_DATA as _, memory_limit << 20)?;
+        }
+
+        if let Some(cpu_limit) = self.limits.cpu_limit {
+            limit(exe.id(), libc::RLIMIT_CPU as _, cpu_limit)?;
+        }
+
+        let watcher = Watcher::start(
+            exe.id(),
+            self.limits.timeout.map(Duration::from_secs),
+            self.limits.kill_signal,
+            self.stdio.tty,
+        )?;
+
+        let (output, terminal): (Box<dyn Read + Send>, _) = match pty {
+            Some(Pty { master, slave }) => {
+                drop(slave);
+                let terminal = master.try_clone()?;
+                (Box::new(master), Some(terminal))
+            }
+            None => (exe.take_stdout().expect("Failed to get stdout"), None),
+        };
+
+        let mut input_writer: Box<dyn Write + Send> = match terminal {
+            Some(terminal) => Box::new(terminal),
+            None if input.is_some() => exe.take_stdin().expect("Failed to get stdin"),
+            None => Box::new(io::sink()),
+        };
+
+        match input {
+            Some(input) => {
+                spawn(move || {
+                    // The program may exit without reading all of its input
+                    let _ = input_writer.write_all(&input);
+                });
+            }
+            None if self.stdio.tty => {
+                // Blocks on stdin of this process until it closes, so this thread may outlive
+                // the program
+                spawn(move || {
+                    let _ = copy(&mut stdin(), &mut input_writer);
+                });
+            }
+            None => {}
+        }
+
+        let stderr_thread = match &self.stdio.stderr_file {
+            Some(stderr_file) => {
+                let mut file = File::create(stderr_file)?;
+                let mut program_stderr = exe.take_stderr().expect("Failed to get stderr");
+
+                Some(spawn(move || -> io::Result<()> {
+                    let mut buf = [0; 4096];
+
+                    loop {
+                        let n = program_stderr.read(&mut buf)?;
+
+                        if n == 0 {
+                            return Ok(());
+                        }
+
+                        stderr().write_all(&buf[..n])?;
+                        file.write_all(&buf[..n])?;
+                    }
+                }))
+            }
+            None => None,
+        };
+
+        let mut output_file = match &self.stdio.output_file {
+            Some(output_file) => Some(BufWriter::new(File::create(output_file)?)),
+            None => None,
+        };
+
+        for line in BufReader::new(output).lines() {
+            let line = match line {
+                Ok(line) => line,
+                // Reading a terminal fails instead of ending once the program exits
+                Err(e) if self.stdio.tty && e.raw_os_error() == Some(libc::EIO) => break,
+                Err(e) => return Err(e),
+            };
+
+            match from_str::<Value>(&line) {
+                Ok(event @ Value::Object(_)) => on_event(event),
+                _ => {
+                    if let Some(output_file) = &mut output_file {
+                        writeln!(output_file, "{}", line)?;
+                    }
+
+                    on_output(&line)
+                }
+            }
+        }
+
+        if let Some(mut output_file) = output_file {
+            output_file.flush()?;
+        }
+
+        if let Some(stderr_thread) = stderr_thread {
+            stderr_thread
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("Failed to copy stderr")))?;
+        }
+
+        let exit = exe.wait()?;
+
+        Ok(Exit {
+            timed_out: watcher.stop(),
+            ..exit
+        })
+    }
+}
@@ -0,0 +1,702 @@
+//! Filtering events
+//!
+//! Live traces are too large to read whole, and piping them through `jq` to keep the events of
+//! interest means encoding and decoding every event again. A `Filter` is a small expression
+//! over the fields of an event, checked on decoded events before they are written out:
+//!
+//! ```text
+//! pc in 0x400000..0x500000 && type == syscall && syscall.num == 1
+//! ```
+//!
+//! * `type` is the kind of the event, named like `EventKind` in lowercase, e.g. `insn`, `tb`,
+//!   `syscall` or `sysmem`
+//! * `pc` is the address of the code the event is about: the instruction or block executed,
+//!   the instruction that accessed memory, the source of an edge, the site of a call or
+//!   return, or the address of a breakpoint, trigger or function
+//! * Any other name is a path into the event, with `.` between the fields of nested objects
+//!   and indices into arrays, e.g. `insn.vaddr` or `args.0`. A path may start with the kind of
+//!   event it applies to, like `syscall.num`, which only matches system call events
+//!
+//! Fields are compared to integers, in decimal or hexadecimal, to strings, quoted or not, and
+//! to `true`, `false` and `null`, with `==`, `!=`, `<`, `<=`, `>` and `>=`. `field in a..b`
+//! checks that an integer field is in a half-open range, and `a..=b` in a closed one. A field
+//! on its own checks that the event has it. Comparisons are combined with `&&`, `||`, `!` and
+//! parentheses. A comparison with a field the event does not have, or of a different type, is
+//! false, whatever the operator.
+//!
+//! A `FilterHandle` holds the filter of a running session, and can be cloned to change it
+//! from another thread while the session runs:
+//!
+#![cfg_attr(feature = "driver", doc = "```no_run")]
+#![cfg_attr(not(feature = "driver"), doc = "```ignore")]
+//! use cannonball_tools::{filter::{Filter, FilterHandle}, TraceSession};
+//!
+//! let filter = FilterHandle::new(Some(Filter::parse("type == syscall").unwrap()));
+//! let handle = filter.clone();
+//!
+//! std::thread::spawn(move || {
+//!     std::thread::sleep(std::time::Duration::from_millis(100));
+//!     handle.set(Some(Filter::parse("type == syscall && num == 1").unwrap()));
+//! });
+//!
+//! TraceSession::new("/bin/ls")
+//!     .run(|event| {
+//!         if filter.matches(&event) {
+//!             println!("{}", event);
+//!         }
+//!     })
+//!     .unwrap();
+//! ```
+
+use serde_json::Value;
+
+use std::{
+    cmp::Ordering,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use crate::trace::EventKind;
+
+/// Every kind of event, to look them up by name
+const KINDS: [EventKind; 26] = [
+    EventKind::Header,
+    EventKind::Final,
+    EventKind::RunEnd,
+    EventKind::Stats,
+    EventKind::InsnMix,
+    EventKind::Fork,
+    EventKind::Exec,
+    EventKind::Trigger,
+    EventKind::Breakpoint,
+    EventKind::Discon,
+    EventKind::Vcpu,
+    EventKind::Signal,
+    EventKind::Map,
+    EventKind::Unmap,
+    EventKind::Protect,
+    EventKind::Insn,
+    EventKind::TB,
+    EventKind::BlockHits,
+    EventKind::Edge,
+    EventKind::Call,
+    EventKind::Return,
+    EventKind::FunctionEnter,
+    EventKind::FunctionExit,
+    EventKind::Mem,
+    EventKind::SysMem,
+    EventKind::Syscall,
+];
+
+/// The name of a kind of event in filters
+///
+/// # Arguments
+///
+/// * `kind` - The kind of event
+fn kind_name(kind: EventKind) -> String {
+    format!("{:?}", kind).to_lowercase()
+}
+
+/// The kind of event with a name, in any case
+///
+/// # Arguments
+///
+/// * `name` - The name
+fn kind_named(name: &str) -> Option<EventKind> {
+    KINDS
+        .into_iter()
+        .find(|&kind| kind_name(kind).eq_ignore_ascii_case(name))
+}
+
+/// A filter that could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterError {
+    /// The offset in the filter the error is at
+    pub position: usize,
+    /// What is wrong
+    pub message: String,
+}
+
+impl Display for FilterError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.position)
+    }
+}
+
+impl Error for FilterError {}
+
+/// A token of a filter
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Int(i128),
+    Str(String),
+    /// An operator or a parenthesis
+    Punct(&'static str),
+}
+
+/// The operators and parentheses, longest first so that `..=` is not read as `..`
+const PUNCTS: [&str; 14] = [
+    "..=", "==", "!=", "<=", ">=", "&&", "||", "..", "<", ">", "!", "(", ")", "=",
+];
+
+/// Split a filter into tokens, along with their offsets
+///
+/// # Arguments
+///
+/// * `filter` - The filter
+fn tokenize(filter: &str) -> Result<Vec<(usize, Token)>, FilterError> {
+    let mut tokens = Vec::new();
+    let bytes = filter.as_bytes();
+    let mut i = 0;
+
+    let error = |position, message: &str| FilterError {
+        position,
+        message: message.to_string(),
+    };
+
+    while i < bytes.len() {
+        let c = bytes[i] as char;
+        let start = i;
+
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit()
+            || (c == '-' && bytes.get(i + 1).is_some_and(u8::is_ascii_digit))
+        {
+            i += 1;
+            while i < bytes.len() && (bytes[i] as char).is_ascii_alphanumeric() {
+                i += 1;
+            }
+
+            let text = &filter[start..i];
+            let (negative, digits) = match text.strip_prefix('-') {
+                Some(digits) => (true, digits),
+                None => (false, text),
+            };
+            let value = match digits.strip_prefix("0x") {
+                Some(hex) => i128::from_str_radix(hex, 16),
+                None => digits.parse(),
+            }
+            .map_err(|_| error(start, "Invalid integer"))?;
+
+            tokens.push((start, Token::Int(if negative { -value } else { value })));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            // Dots separate the fields of a path, but two of them make a range
+            while i < bytes.len()
+                && ((bytes[i] as char).is_ascii_alphanumeric()
+                    || bytes[i] == b'_'
+                    || (bytes[i] == b'.' && bytes.get(i + 1) != Some(&b'.')))
+            {
+                i += 1;
+            }
+
+            tokens.push((start, Token::Name(filter[start..i].to_string())));
+        } else if c == '"' {
+            let end = filter[i + 1..]
+                .find('"')
+                .ok_or_else(|| error(start, "Unterminated string"))?;
+
+            tokens.push((start, Token::Str(filter[i + 1..i + 1 + end].to_string())));
+            i += end + 2;
+        } else if let Some(punct) = PUNCTS.iter().find(|punct| filter[i..].starts_with(*punct)) {
+            i += punct.len();
+
+            // A single `=` is a typo for `==`
+            let punct = if *punct == "=" { "==" } else { punct };
+            tokens.push((start, Token::Punct(punct)));
+        } else {
+            return Err(error(start, &format!("Unexpected '{}'", c)));
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// A value a field is compared to, or the value of a field
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Literal {
+    Int(i128),
+    Str(String),
+    Bool(bool),
+    Null,
+}
+
+impl Literal {
+    /// The value of a field of an event, if it can be compared
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value of the field
+    fn of(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(number) => number
+                .as_u64()
+                .map(i128::from)
+                .or_else(|| number.as_i64().map(i128::from))
+                .map(Self::Int),
+            Value::String(string) => Some(Self::Str(string.clone())),
+            Value::Bool(bool) => Some(Self::Bool(*bool)),
+            Value::Null => Some(Self::Null),
+            _ => None,
+        }
+    }
+
+    /// How this value compares to another, if they are of the same type
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The other value
+    fn compare(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Self::Int(a), Self::Int(b)) => Some(a.cmp(b)),
+            (Self::Str(a), Self::Str(b)) => Some(a.cmp(b)),
+            (Self::Bool(a), Self::Bool(b)) => Some(a.cmp(b)),
+            (Self::Null, Self::Null) => Some(Ordering::Equal),
+            _ => None,
+        }
+    }
+}
+
+/// A field of an event
+#[derive(Debug, Clone, PartialEq)]
+enum Field {
+    /// The kind of the event
+    Type,
+    /// The address of the code the event is about
+    Pc,
+    /// A path into the event
+    Path {
+        /// The kind of event the path applies to, if it starts with one
+        kind: Option<EventKind>,
+        /// The fields and indices of the path, after the kind
+        path: Vec<String>,
+        /// The whole path, for events of another kind that have a field named like it
+        full: Vec<String>,
+    },
+}
+
+impl Field {
+    /// Parse the name of a field
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name
+    fn parse(name: &str) -> Self {
+        match name {
+            "type" => Self::Type,
+            "pc" => Self::Pc,
+            _ => {
+                let full: Vec<String> = name.split('.').map(str::to_string).collect();
+
+                match kind_named(&full[0]) {
+                    Some(kind) if full.len() > 1 => Self::Path {
+                        kind: Some(kind),
+                        path: full[1..].to_vec(),
+                        full,
+                    },
+                    _ => Self::Path {
+                        kind: None,
+                        path: full.clone(),
+                        full,
+                    },
+                }
+            }
+        }
+    }
+
+    /// The value of this field in an event, if the event has it
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    fn get(&self, event: &Value) -> Option<Literal> {
+        let kind = EventKind::of(event);
+
+        match self {
+            Self::Type => Some(Literal::Str(kind_name(kind?))),
+            Self::Pc => {
+                let path: &[&str] = match kind? {
+                    EventKind::Insn | EventKind::TB | EventKind::BlockHits => &["vaddr"],
+                    EventKind::Mem => &["insn", "vaddr"],
+                    EventKind::SysMem => &["mem", "insn", "vaddr"],
+                    EventKind::Trigger | EventKind::Breakpoint => &["pc"],
+                    EventKind::Discon => &["from_pc"],
+                    EventKind::Edge => &["src"],
+                    EventKind::Call => &["callsite"],
+                    EventKind::Return => &["site"],
+                    EventKind::FunctionEnter => &["entry"],
+                    EventKind::FunctionExit => &["exit"],
+                    _ => return None,
+                };
+
+                Literal::of(lookup(event, path.iter().copied())?)
+            }
+            Self::Path {
+                kind: Some(path_kind),
+                path,
+                ..
+            } if kind == Some(*path_kind) => {
+                Literal::of(lookup(event, path.iter().map(String::as_str))?)
+            }
+            Self::Path { full, .. } => Literal::of(lookup(event, full.iter().map(String::as_str))?),
+        }
+    }
+}
+
+/// The value at a path into an event
+///
+/// # Arguments
+///
+/// * `event` - The event
+/// * `path` - The fields and indices of the path
+fn lookup<'a, 'p>(event: &'a Value, mut path: impl Iterator<Item = &'p str>) -> Option<&'a Value> {
+    path.try_fold(event, |value, segment| match value {
+        Value::Object(object) => object.get(segment),
+        Value::Array(array) => array.get(segment.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+/// A comparison operator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    /// The operator a token is, if it is one
+    ///
+    /// # Arguments
+    ///
+    /// * `punct` - The token
+    fn of(punct: &str) -> Option<Self> {
+        Some(match punct {
+            "==" => Self::Eq,
+            "!=" => Self::Ne,
+            "<" => Self::Lt,
+            "<=" => Self::Le,
+            ">" => Self::Gt,
+            ">=" => Self::Ge,
+            _ => return None,
+        })
+    }
+
+    /// Whether values comparing this way satisfy the operator
+    ///
+    /// # Arguments
+    ///
+    /// * `ordering` - How the field compares to the value
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Self::Eq => ordering == Ordering::Equal,
+            Self::Ne => ordering != Ordering::Equal,
+            Self::Lt => ordering == Ordering::Less,
+            Self::Le => ordering != Ordering::Greater,
+            Self::Gt => ordering == Ordering::Greater,
+            Self::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+/// An expression of a filter
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    /// The field compared to a value
+    Compare(Field, Op, Literal),
+    /// The field in a range, from the first bound included to the second excluded
+    In(Field, i128, i128),
+    /// The event has the field
+    Has(Field),
+}
+
+impl Expr {
+    /// Whether an event matches this expression
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    fn matches(&self, event: &Value) -> bool {
+        match self {
+            Self::And(a, b) => a.matches(event) && b.matches(event),
+            Self::Or(a, b) => a.matches(event) || b.matches(event),
+            Self::Not(a) => !a.matches(event),
+            Self::Compare(field, op, value) => field
+                .get(event)
+                .and_then(|field| field.compare(value))
+                .is_some_and(|ordering| op.holds(ordering)),
+            Self::In(field, start, end) => {
+                matches!(field.get(event), Some(Literal::Int(value)) if *start <= value && value < *end)
+            }
+            Self::Has(field) => field.get(event).is_some(),
+        }
+    }
+}
+
+/// Parses the tokens of a filter by recursive descent, `||` binding looser than `&&`, which
+/// binds looser than `!`
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    /// The index of the next token
+    next: usize,
+    /// The length of the filter, where errors at its end are
+    len: usize,
+}
+
+impl Parser {
+    /// The next token, without consuming it
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    /// The offset of the next token
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map_or(self.len, |(position, _)| *position)
+    }
+
+    /// An error at the next token
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - What is wrong
+    fn error(&self, message: &str) -> FilterError {
+        FilterError {
+            position: self.position(),
+            message: message.to_string(),
+        }
+    }
+
+    /// Consume the next token if it is a punctuation or a keyword
+    ///
+    /// # Arguments
+    ///
+    /// * `punct` - The punctuation or keyword
+    fn eat(&mut self, punct: &str) -> bool {
+        let matches = match self.peek() {
+            Some(Token::Punct(p)) => *p == punct,
+            Some(Token::Name(name)) => name == punct,
+            _ => false,
+        };
+
+        if matches {
+            self.next += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Parse expressions joined with `||`
+    fn or(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.and()?;
+
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+
+        Ok(expr)
+    }
+
+    /// Parse expressions joined with `&&`
+    fn and(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.unary()?;
+
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+
+        Ok(expr)
+    }
+
+    /// Parse a negated or parenthesized expression, or a comparison
+    fn unary(&mut self) -> Result<Expr, FilterError> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+
+        if self.eat("(") {
+            let expr = self.or()?;
+
+            if !self.eat(")") {
+                return Err(self.error("Expected ')'"));
+            }
+
+            return Ok(expr);
+        }
+
+        self.comparison()
+    }
+
+    /// Parse a comparison, a range check or a field on its own
+    fn comparison(&mut self) -> Result<Expr, FilterError> {
+        let field = match self.peek() {
+            Some(Token::Name(name)) => Field::parse(name),
+            _ => return Err(self.error("Expected a field")),
+        };
+        self.next += 1;
+
+        if self.eat("in") {
+            let start = self.int()?;
+            let inclusive = if self.eat("..=") {
+                true
+            } else if self.eat("..") {
+                false
+            } else {
+                return Err(self.error("Expected '..' or '..='"));
+            };
+            let end = self.int()? + i128::from(inclusive);
+
+            return Ok(Expr::In(field, start, end));
+        }
+
+        let op = match self.peek() {
+            Some(Token::Punct(punct)) => Op::of(punct),
+            _ => None,
+        };
+        let Some(op) = op else {
+            return Ok(Expr::Has(field));
+        };
+        self.next += 1;
+
+        let position = self.position();
+        let value = match self.peek().cloned() {
+            Some(Token::Int(int)) => Literal::Int(int),
+            Some(Token::Str(string)) => Literal::Str(string),
+            Some(Token::Name(name)) => match name.as_str() {
+                "true" => Literal::Bool(true),
+                "false" => Literal::Bool(false),
+                "null" => Literal::Null,
+                _ => Literal::Str(name),
+            },
+            _ => return Err(self.error("Expected a value")),
+        };
+        self.next += 1;
+
+        // Kinds are named in any case, and a typo in one would silently match nothing
+        let value = match (&field, value) {
+            (Field::Type, Literal::Str(name)) => match kind_named(&name) {
+                Some(kind) => Literal::Str(kind_name(kind)),
+                None => {
+                    return Err(FilterError {
+                        position,
+                        message: format!("Unknown event type '{}'", name),
+                    })
+                }
+            },
+            (_, value) => value,
+        };
+
+        Ok(Expr::Compare(field, op, value))
+    }
+
+    /// Parse an integer
+    fn int(&mut self) -> Result<i128, FilterError> {
+        match self.peek() {
+            Some(Token::Int(int)) => {
+                let int = *int;
+                self.next += 1;
+                Ok(int)
+            }
+            _ => Err(self.error("Expected an integer")),
+        }
+    }
+}
+
+/// A filter over the fields of events
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    expr: Expr,
+}
+
+impl Filter {
+    /// Parse a filter
+    ///
+    /// # Arguments
+    ///
+    /// * `filter` - The filter, like `type == syscall && syscall.num == 1`
+    pub fn parse(filter: &str) -> Result<Self, FilterError> {
+        let mut parser = Parser {
+            tokens: tokenize(filter)?,
+            next: 0,
+            len: filter.len(),
+        };
+        let expr = parser.or()?;
+
+        if parser.peek().is_some() {
+            return Err(parser.error("Expected '&&', '||' or the end of the filter"));
+        }
+
+        Ok(Self { expr })
+    }
+
+    /// Whether an event matches the filter
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn matches(&self, event: &Value) -> bool {
+        self.expr.matches(event)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = FilterError;
+
+    fn from_str(filter: &str) -> Result<Self, Self::Err> {
+        Self::parse(filter)
+    }
+}
+
+/// The filter of a running session, which can be changed while it runs. Clones share the
+/// same filter
+#[derive(Debug, Clone, Default)]
+pub struct FilterHandle {
+    filter: Arc<RwLock<Option<Filter>>>,
+}
+
+impl FilterHandle {
+    /// Instantiate a new `FilterHandle`
+    ///
+    /// # Arguments
+    ///
+    /// * `filter` - The filter. If `None`, every event matches
+    pub fn new(filter: Option<Filter>) -> Self {
+        Self {
+            filter: Arc::new(RwLock::new(filter)),
+        }
+    }
+
+    /// Replace the filter, for the events after this call
+    ///
+    /// # Arguments
+    ///
+    /// * `filter` - The new filter. If `None`, every event matches
+    pub fn set(&self, filter: Option<Filter>) {
+        *self.filter.write().expect("Could not lock filter!") = filter;
+    }
+
+    /// Whether an event matches the current filter
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn matches(&self, event: &Value) -> bool {
+        self.filter
+            .read()
+            .expect("Could not lock filter!")
+            .as_ref()
+            .is_none_or(|filter| filter.matches(event))
+    }
+}
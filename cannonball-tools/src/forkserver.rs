@@ -0,0 +1,336 @@
+//! Fork server runs
+//!
+//! Starting QEMU and the traced program dominates the cost of short runs, like those of a
+//! fuzzer. `ForkServer` starts them once, runs the program up to a chosen instruction, and
+//! from there has the Jaivana plugin fork a run for each input it is given, over a Unix
+//! socket. The events of each run end with a `RunEnd` event logged once the run has exited.
+//!
+//! ```no_run
+//! use cannonball_tools::{driver::TraceOptions, TraceSession};
+//!
+//! let mut server = TraceSession::new("./target")
+//!     .options(TraceOptions {
+//!         edges: true,
+//!         ..Default::default()
+//!     })
+//!     .fork_server(0x401000)
+//!     .unwrap();
+//!
+//! for input in [b"a".to_vec(), b"b".to_vec()] {
+//!     let result = server.run(&input, |event| println!("{}", event)).unwrap();
+//!     println!("exited with {:?}", result.exit_code);
+//! }
+//! ```
+
+use serde_json::{from_str, Value};
+
+use std::{
+    env::temp_dir,
+    fs::{remove_file, write},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError},
+    },
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    driver::{Driver, Pipe, QemuChild},
+    session::TempPlugin,
+    trace::EventKind,
+    watch::limit,
+};
+
+/// How often the driver checks whether QEMU has reached the fork server or exited first
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The number of fork servers started by this process so far
+static FORK_SERVERS: AtomicUsize = AtomicUsize::new(0);
+
+/// How a run of a fork server exited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunResult {
+    /// The exit code of the run, if it exited normally
+    pub exit_code: Option<i32>,
+    /// The signal that killed the run, if any
+    pub signal: Option<i32>,
+    /// Whether the run was killed for running past its timeout
+    pub timed_out: bool,
+    /// The number of events logged by the run
+    pub events: u64,
+}
+
+/// A running QEMU instance serving runs
+struct Server {
+    /// The QEMU process
+    qemu: QemuChild,
+    /// Commands go out on this socket, and replies come back
+    control: BufReader<UnixStream>,
+    /// The lines QEMU writes to stdout, read on another thread
+    lines: Receiver<io::Result<String>>,
+}
+
+/// Runs a program from an instruction once per input, without starting it over
+pub struct ForkServer {
+    /// Runs QEMU with the plugin set up to serve runs
+    driver: Driver,
+    /// The program to run
+    program: PathBuf,
+    /// The arguments to the program
+    args: Vec<String>,
+    /// The socket the plugin connects to
+    socket: PathBuf,
+    /// The file holding the input of the current run
+    input: PathBuf,
+    /// The plugin, if it was written to a temporary file
+    _plugin: Option<TempPlugin>,
+    /// The running QEMU instance, if any
+    server: Option<Server>,
+    /// The events logged before the program reached the fork server
+    prelude: Vec<Value>,
+}
+
+impl ForkServer {
+    /// A new path for the socket of a fork server
+    pub(crate) fn socket_path() -> PathBuf {
+        temp_dir().join(format!(
+            "cannonball-{}-{}.sock",
+            process::id(),
+            FORK_SERVERS.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    /// Start a fork server, running the program until it reaches the fork server
+    ///
+    /// # Arguments
+    ///
+    /// * `driver` - Runs QEMU with the plugin set up to serve runs on `socket`
+    /// * `program` - The program to run
+    /// * `args` - The arguments to the program
+    /// * `socket` - The socket the plugin connects to
+    /// * `plugin` - The plugin, if it was written to a temporary file, kept until the server
+    ///   is dropped
+    pub(crate) fn start(
+        driver: Driver,
+        program: PathBuf,
+        args: Vec<String>,
+        socket: PathBuf,
+        plugin: Option<TempPlugin>,
+    ) -> io::Result<Self> {
+        let mut input = socket.clone();
+        input.set_extension("input");
+
+        let mut server = Self {
+            driver,
+            program,
+            args,
+            socket,
+            input,
+            _plugin: plugin,
+            server: None,
+            prelude: Vec::new(),
+        };
+        server.reset()?;
+
+        Ok(server)
+    }
+
+    /// The events logged before the program reached the fork server, like the header
+    pub fn prelude(&self) -> &[Value] {
+        &self.prelude
+    }
+
+    /// Start QEMU over, for example after the server itself crashed. The next run starts from
+    /// a fresh copy of the program
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.stop();
+        self.prelude.clear();
+
+        let _ = remove_file(&self.socket);
+        let listener = UnixListener::bind(&self.socket)?;
+        listener.set_nonblocking(true)?;
+
+        let mut qemu = self
+            .driver
+            .command(&self.program, &self.args)?
+            .stdin(Pipe::Null)
+            .stdout(Pipe::Piped)
+            .stderr(Pipe::Inherit)
+            .spawn()?;
+
+        let limits = self.driver.limit_options();
+
+        if let Some(memory_limit) = limits.memory_limit {
+            limit(qemu.id(), libc::RLIMIT_DATA as _, memory_limit << 20)?;
+        }
+
+        if let Some(cpu_limit) = limits.cpu_limit {
+            limit(qemu.id(), libc::RLIMIT_CPU as _, cpu_limit)?;
+        }
+
+        let stdout = qemu.take_stdout().expect("Failed to get stdout");
+        let (sender, lines) = channel();
+
+        spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                if sender.send(line).is_err() {
+                    return;
+                }
+            }
+        });
+
+        // Events keep coming until the program reaches the fork server, and must be read so
+        // QEMU does not block writing them
+        let control = loop {
+            match listener.accept() {
+                Ok((control, _)) => break control,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            for line in lines.try_iter() {
+                if let Ok(event @ Value::Object(_)) = from_str::<Value>(&line?) {
+                    self.prelude.push(event);
+                }
+            }
+
+            if qemu.exited()? {
+                return Err(io::Error::other(
+                    "The program exited before reaching the fork server",
+                ));
+            }
+
+            sleep(POLL_INTERVAL);
+        };
+        control.set_nonblocking(false)?;
+
+        // The server marks the end of the events logged before it started with run 0
+        loop {
+            let line = lines
+                .recv()
+                .map_err(|_| io::Error::other("The fork server exited while starting"))??;
+
+            if let Ok(event @ Value::Object(_)) = from_str::<Value>(&line) {
+                if EventKind::of(&event) == Some(EventKind::RunEnd) {
+                    break;
+                }
+
+                self.prelude.push(event);
+            }
+        }
+
+        self.server = Some(Server {
+            qemu,
+            control: BufReader::new(control),
+            lines,
+        });
+
+        Ok(())
+    }
+
+    /// Run the program from the fork server with an input
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - The input, fed to the program on stdin
+    /// * `on_event` - Called with each event of the run, in order
+    pub fn run(&mut self, input: &[u8], mut on_event: impl FnMut(Value)) -> io::Result<RunResult> {
+        if self.server.is_none() {
+            self.reset()?;
+        }
+
+        write(&self.input, input)?;
+
+        let limits = self.driver.limit_options().clone();
+        let server = self.server.as_mut().expect("No fork server");
+
+        writeln!(
+            server.control.get_mut(),
+            "run {}",
+            self.input.to_string_lossy()
+        )?;
+
+        let mut reply = String::new();
+        server.control.read_line(&mut reply)?;
+        let child = reply
+            .trim_end()
+            .strip_prefix("started ")
+            .and_then(|pid| pid.parse::<libc::pid_t>().ok())
+            .ok_or_else(|| io::Error::other("The fork server did not start the run"))?;
+
+        let deadline = limits
+            .timeout
+            .map(|timeout| Instant::now() + Duration::from_secs(timeout));
+        let mut timed_out = false;
+        let mut events = 0;
+
+        loop {
+            let line = match deadline {
+                Some(deadline) if !timed_out => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+
+                    match server.lines.recv_timeout(left) {
+                        Ok(line) => line,
+                        Err(RecvTimeoutError::Timeout) => {
+                            // The server logs the end of the run once the child is gone
+                            unsafe { libc::kill(child, limits.kill_signal) };
+                            timed_out = true;
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                _ => match server.lines.recv() {
+                    Ok(line) => line,
+                    Err(_) => break,
+                },
+            }?;
+
+            let Ok(event @ Value::Object(_)) = from_str::<Value>(&line) else {
+                continue;
+            };
+
+            if EventKind::of(&event) == Some(EventKind::RunEnd) {
+                let field = |name: &str| event.get(name).and_then(Value::as_i64);
+
+                return Ok(RunResult {
+                    exit_code: field("exit_code").map(|code| code as i32),
+                    signal: field("signal").map(|signal| signal as i32),
+                    timed_out,
+                    events,
+                });
+            }
+
+            events += 1;
+            on_event(event);
+        }
+
+        // QEMU exited without ending the run, so it has to be started over
+        self.stop();
+
+        Err(io::Error::other("The fork server exited during a run"))
+    }
+
+    /// Stop QEMU, if it is running
+    fn stop(&mut self) {
+        if let Some(mut server) = self.server.take() {
+            // Closing the socket tells the server to exit, but it may be stuck in a run
+            drop(server.control);
+            let _ = server.qemu.kill();
+            let _ = server.qemu.wait();
+        }
+    }
+}
+
+impl Drop for ForkServer {
+    fn drop(&mut self) {
+        self.stop();
+        let _ = remove_file(&self.socket);
+        let _ = remove_file(&self.input);
+    }
+}
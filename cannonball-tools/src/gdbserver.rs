@@ -0,0 +1,509 @@
+//! Debugging a traced program with gdb
+//!
+//! `GdbServer` lets gdb debug a program while it is traced. It speaks the GDB remote serial
+//! protocol to gdb over TCP, and carries out gdb's requests with the control channel of the
+//! Jaivana plugin (see `control`): breakpoints become plugin breakpoints that hold the VCPU
+//! hitting them, and registers and memory are read from the held VCPU. The trace goes on as
+//! usual meanwhile, so it records what the program did between the stops.
+//!
+//! The session must hold VCPUs at breakpoints and break at the entry point of the program,
+//! where it waits for gdb to connect:
+//!
+//! ```no_run
+//! use cannonball_tools::{driver::TraceOptions, gdbserver::GdbServer, TraceSession};
+//!
+//! let session = TraceSession::new("./target").options(TraceOptions {
+//!     break_pause: true,
+//!     break_entry: true,
+//!     ..Default::default()
+//! });
+//!
+//! // Then, in gdb: target remote 127.0.0.1:1234
+//! let result = GdbServer::bind("127.0.0.1:1234")
+//!     .unwrap()
+//!     .serve(session, |event| println!("{}", event))
+//!     .unwrap();
+//! ```
+//!
+//! gdb can set and remove software breakpoints, continue, read registers and memory, and
+//! detach. The plugin only stops VCPUs at breakpoints, so single-stepping, interrupting the
+//! program and writing to it are not supported, and killing it from gdb only detaches. Reading
+//! registers needs the plugin to be built with `plugin-api-v2` (QEMU 9.0), and reading memory
+//! with `plugin-api-v4` (QEMU 9.2). The registers are described to gdb as QEMU's gdbstub names
+//! them, so gdb knows their layout without being told the target.
+
+use goblin::elf::{
+    header::{EM_386, EM_AARCH64, EM_ARM, EM_PPC64, EM_RISCV, EM_X86_64},
+    Elf,
+};
+use serde_json::Value;
+
+use std::{
+    fmt::Write as _,
+    fs::read,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::Path,
+    sync::mpsc::{channel, Receiver},
+    thread::spawn,
+};
+
+use crate::{
+    control::{ControlHandle, Register},
+    session::TraceResult,
+    trace::EventKind,
+    TraceSession,
+};
+
+/// The largest packet gdb may send, which it is told when it connects
+const PACKET_SIZE: usize = 0x4000;
+
+/// The packet gdb reads the description of the registers with, followed by `OFFSET,LEN`
+const READ_TARGET: &str = "qXfer:features:read:target.xml:";
+
+/// Why the program stopped running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    /// A VCPU is held at a breakpoint
+    Breakpoint,
+    /// The program exited, with its exit code if it exited normally
+    Exited(Option<i32>),
+}
+
+/// Waits for gdb to debug a traced program
+pub struct GdbServer {
+    listener: TcpListener,
+}
+
+impl GdbServer {
+    /// Listen for gdb on an address
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address to listen on, like `127.0.0.1:1234`
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+        })
+    }
+
+    /// The address gdb connects to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Run a session, letting one gdb debug it once the program stops at its entry point. The
+    /// program runs to completion once gdb detaches or disconnects
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - The session, holding VCPUs at breakpoints and breaking at the entry point
+    /// * `on_event` - Called with each event, in order
+    pub fn serve(
+        self,
+        mut session: TraceSession,
+        mut on_event: impl FnMut(Value),
+    ) -> io::Result<TraceResult> {
+        let control = session.control()?;
+        let architecture = architecture(session.program());
+        let listener = self.listener;
+        let (stops, stopped) = channel();
+
+        let debugger = spawn(move || {
+            let served = debug(listener, &control, stopped, architecture);
+
+            // Nothing could continue a held VCPU anymore
+            control.set_break_pause(false).ok();
+            control.resume().ok();
+            served
+        });
+
+        let mut events = session.spawn();
+
+        for event in &mut events {
+            let paused = event.get("paused").and_then(Value::as_bool) == Some(true);
+
+            if paused && EventKind::of(&event) == Some(EventKind::Breakpoint) {
+                stops.send(Stop::Breakpoint).ok();
+            }
+
+            on_event(event);
+        }
+
+        let result = events.wait();
+        let exit_code = result.as_ref().ok().and_then(|result| result.exit_code);
+        stops.send(Stop::Exited(exit_code)).ok();
+
+        let served = debugger
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("The debugger thread panicked")));
+
+        // If the program could not be run, that is why gdb was not served
+        let result = result?;
+        served?;
+        Ok(result)
+    }
+}
+
+/// The architecture of a program, as gdb names it, if it is one gdb is likely to know
+///
+/// # Arguments
+///
+/// * `program` - The program
+fn architecture(program: &Path) -> Option<&'static str> {
+    let data = read(program).ok()?;
+    let elf = Elf::parse(&data).ok()?;
+
+    match (elf.header.e_machine, elf.is_64) {
+        (EM_X86_64, _) => Some("i386:x86-64"),
+        (EM_386, _) => Some("i386"),
+        (EM_AARCH64, _) => Some("aarch64"),
+        (EM_ARM, _) => Some("arm"),
+        (EM_RISCV, true) => Some("riscv:rv64"),
+        (EM_RISCV, false) => Some("riscv:rv32"),
+        (EM_PPC64, _) => Some("powerpc:common64"),
+        _ => None,
+    }
+}
+
+/// Wait for the program to stop at its entry point, then for gdb to connect, and serve it
+/// until it is done debugging
+///
+/// # Arguments
+///
+/// * `listener` - Accepts gdb's connection
+/// * `control` - The control channel of the session
+/// * `stopped` - Receives why the program stopped
+/// * `architecture` - The architecture of the program, as gdb names it, if known
+fn debug(
+    listener: TcpListener,
+    control: &ControlHandle,
+    stopped: Receiver<Stop>,
+    architecture: Option<&'static str>,
+) -> io::Result<()> {
+    if stopped.recv() != Ok(Stop::Breakpoint) {
+        return Err(io::Error::other(
+            "The program exited before reaching its entry point",
+        ));
+    }
+
+    let (stream, _) = listener.accept()?;
+
+    Connection {
+        stream: BufReader::new(stream),
+        control,
+        stopped,
+        architecture,
+        target: None,
+        done: false,
+    }
+    .serve()
+}
+
+/// A connection to gdb
+struct Connection<'a> {
+    stream: BufReader<TcpStream>,
+    control: &'a ControlHandle,
+    /// Receives why the program stopped
+    stopped: Receiver<Stop>,
+    /// The architecture of the program, as gdb names it, if known
+    architecture: Option<&'static str>,
+    /// The description of the registers given to gdb, once it asked for it
+    target: Option<String>,
+    /// Whether gdb is done debugging
+    done: bool,
+}
+
+impl Connection<'_> {
+    /// Answer gdb's packets until it is done debugging or disconnects
+    fn serve(mut self) -> io::Result<()> {
+        while !self.done {
+            let Some(packet) = self.read_packet()? else {
+                break;
+            };
+
+            if let Some(reply) = self.handle(&packet)? {
+                self.reply(&reply)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read the next packet from gdb and acknowledge it. Returns `None` once gdb disconnects
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0];
+
+        // Skip acknowledgements of our replies, and interrupts, which are not supported
+        loop {
+            if self.stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+
+        let mut packet = Vec::new();
+
+        if self.stream.read_until(b'#', &mut packet)? == 0 || packet.pop() != Some(b'#') {
+            return Ok(None);
+        }
+
+        // The checksum is not checked, TCP already makes sure the packet arrived intact
+        let mut checksum = [0; 2];
+        self.stream.read_exact(&mut checksum)?;
+        self.stream.get_mut().write_all(b"+")?;
+
+        Ok(Some(String::from_utf8_lossy(&packet).to_string()))
+    }
+
+    /// Send a reply to gdb
+    ///
+    /// # Arguments
+    ///
+    /// * `reply` - The reply, without its framing
+    fn reply(&mut self, reply: &str) -> io::Result<()> {
+        let checksum = reply.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(self.stream.get_mut(), "${}#{:02x}", reply, checksum)
+    }
+
+    /// Handle a packet from gdb, returning the reply to send. An empty reply tells gdb the
+    /// packet is not supported
+    ///
+    /// # Arguments
+    ///
+    /// * `packet` - The packet, without its framing
+    fn handle(&mut self, packet: &str) -> io::Result<Option<String>> {
+        let reply = match packet {
+            _ if packet.starts_with("qSupported") => {
+                format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE)
+            }
+            "?" => "S05".to_string(),
+            "g" => match self.control.registers() {
+                Ok(registers) => registers.iter().map(|r| hex(&r.value)).collect(),
+                Err(_) => "E01".to_string(),
+            },
+            "c" => self.resume()?,
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            "k" => {
+                self.done = true;
+                return Ok(None);
+            }
+            _ if packet.starts_with('D') => {
+                self.done = true;
+                "OK".to_string()
+            }
+            _ if packet.starts_with('H') || packet.starts_with('T') => "OK".to_string(),
+            _ if packet.starts_with('p') => {
+                let register = usize::from_str_radix(&packet[1..], 16).ok();
+
+                match (register, self.control.registers()) {
+                    (Some(register), Ok(registers)) if register < registers.len() => {
+                        hex(&registers[register].value)
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            _ if packet.starts_with('m') => match address_and_length(&packet[1..]) {
+                Some((addr, len)) => match self.control.read_memory(addr, len) {
+                    Ok(bytes) => hex(&bytes),
+                    Err(_) => "E01".to_string(),
+                },
+                None => "E01".to_string(),
+            },
+            _ if packet.starts_with("Z0,") || packet.starts_with("z0,") => {
+                let set = packet.starts_with('Z');
+
+                let done = match address_and_length(&packet[3..]) {
+                    Some((addr, _)) if set => self.control.add_breakpoint(addr),
+                    Some((addr, _)) => self.control.remove_breakpoint(addr),
+                    None => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+                };
+
+                match done {
+                    Ok(()) => "OK".to_string(),
+                    Err(_) => "E01".to_string(),
+                }
+            }
+            _ if packet.starts_with(READ_TARGET) => {
+                match address_and_length(&packet[READ_TARGET.len()..]) {
+                    Some((offset, len)) => match self.target() {
+                        Ok(target) => transfer(target, offset as usize, len),
+                        Err(_) => "E01".to_string(),
+                    },
+                    None => "E01".to_string(),
+                }
+            }
+            _ => String::new(),
+        };
+
+        Ok(Some(reply))
+    }
+
+    /// Continue the program and wait for it to stop again, returning the stop reply
+    fn resume(&mut self) -> io::Result<String> {
+        // Other VCPUs may have stopped along with the one gdb was told about, they are
+        // continued as well
+        while let Ok(stop) = self.stopped.try_recv() {
+            if let Stop::Exited(code) = stop {
+                return Ok(self.exited(code));
+            }
+        }
+
+        self.control.resume()?;
+
+        Ok(match self.stopped.recv() {
+            Ok(Stop::Breakpoint) => "S05".to_string(),
+            Ok(Stop::Exited(code)) => self.exited(code),
+            Err(_) => self.exited(None),
+        })
+    }
+
+    /// The stop reply for the program exiting, after which gdb is done
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - The exit code of the program, if it exited normally
+    fn exited(&mut self, code: Option<i32>) -> String {
+        self.done = true;
+
+        match code {
+            Some(code) => format!("W{:02x}", code as u8),
+            // Killed by a signal, or for running too long
+            None => "X09".to_string(),
+        }
+    }
+
+    /// The description of the registers of the target, built from the registers of the held
+    /// VCPU the first time it is asked for
+    fn target(&mut self) -> io::Result<&str> {
+        if self.target.is_none() {
+            self.target = Some(describe(&self.control.registers()?, self.architecture));
+        }
+
+        Ok(self.target.as_deref().expect("No target description"))
+    }
+}
+
+/// Describe registers to gdb as a target description. Registers that could not be read are
+/// left out, as they are from `g` replies
+///
+/// # Arguments
+///
+/// * `registers` - The registers, in the order of their register numbers
+/// * `architecture` - The architecture of the target, as gdb names it, if known
+fn describe(registers: &[Register], architecture: Option<&str>) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target version=\"1.0\">",
+    );
+
+    if let Some(architecture) = architecture {
+        write!(xml, "<architecture>{}</architecture>", architecture).ok();
+    }
+
+    let mut feature: Option<&str> = None;
+
+    for (regnum, register) in registers.iter().enumerate() {
+        if register.value.is_empty() {
+            continue;
+        }
+
+        if feature != Some(register.feature.as_str()) {
+            if feature.is_some() {
+                xml.push_str("</feature>");
+            }
+
+            write!(xml, "<feature name=\"{}\">", register.feature).ok();
+            feature = Some(&register.feature);
+        }
+
+        write!(
+            xml,
+            "<reg name=\"{}\" bitsize=\"{}\" regnum=\"{}\" type=\"{}\"/>",
+            register.name,
+            register.value.len() * 8,
+            regnum,
+            register_type(&register.name)
+        )
+        .ok();
+    }
+
+    if feature.is_some() {
+        xml.push_str("</feature>");
+    }
+
+    xml.push_str("</target>");
+    xml
+}
+
+/// The gdb type of a register. Program counters and stack pointers are told apart so gdb
+/// shows them as addresses, and x87 registers as extended precision floats
+///
+/// # Arguments
+///
+/// * `name` - The name of the register
+fn register_type(name: &str) -> &'static str {
+    match name {
+        "pc" | "rip" | "eip" => "code_ptr",
+        "sp" | "rsp" | "esp" | "fp" | "rbp" | "ebp" => "data_ptr",
+        _ if name.len() == 3 && name.starts_with("st") && name[2..].parse::<u8>().is_ok() => {
+            "i387_ext"
+        }
+        _ => "int",
+    }
+}
+
+/// A part of a document gdb reads in chunks with a `qXfer` packet. It starts with `m` if more
+/// of the document follows, or `l` if it is the last part
+///
+/// # Arguments
+///
+/// * `document` - The document
+/// * `offset` - Where the part starts
+/// * `len` - The most bytes the part may have
+fn transfer(document: &str, offset: usize, len: usize) -> String {
+    let start = offset.min(document.len());
+    let end = offset.saturating_add(len).min(document.len());
+    let more = if end < document.len() { 'm' } else { 'l' };
+
+    let mut reply = more.to_string();
+
+    // Characters that frame packets are escaped
+    for c in document[start..end].chars() {
+        if matches!(c, '#' | '$' | '}' | '*') {
+            reply.push('}');
+            reply.push((c as u8 ^ 0x20) as char);
+        } else {
+            reply.push(c);
+        }
+    }
+
+    reply
+}
+
+/// Parse the `ADDR,LEN` arguments of a packet, both in hex. Anything after the length, like
+/// the kind of a breakpoint, is ignored
+///
+/// # Arguments
+///
+/// * `args` - The arguments
+fn address_and_length(args: &str) -> Option<(u64, usize)> {
+    let mut parts = args.split([',', ';', ':']);
+    let addr = u64::from_str_radix(parts.next()?, 16).ok()?;
+    let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((addr, len))
+}
+
+/// Encode bytes in hex, as gdb expects registers and memory
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
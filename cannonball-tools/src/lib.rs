@@ -0,0 +1,108 @@
+//! Cannonball tools
+//!
+//! Everything needed to run a program under QEMU with the Jaivana plugin and to work with the
+//! traces it produces, as a library so other tools can embed it. The `cannonball` binary is a
+//! thin command line interface on top of it.
+//!
+//! * `session` is the entry point for embedding: `TraceSession` traces a program and reports
+//!   its exit code and statistics about the trace
+//! * `control` changes the events logged by a session while it runs
+//! * `forkserver` runs a program from an instruction once per input, without starting it over
+//! * `gdbserver` lets gdb debug a program while it is traced
+//! * `pool` runs many sessions in parallel, like one per input of a corpus
+//! * `tee` fans the events of a session out to several sinks, like a trace file and a live
+//!   analysis, each on a thread of its own
+//! * `driver` runs a program under QEMU with the plugin and hands back its events
+//! * `filter` keeps the events matching an expression over their fields, to slice large traces
+//! * `merge` interleaves the traces of programs traced separately into a single timeline, and
+//!   `split` writes the events of each process of a trace to a file of its own
+//! * `trace` reads traces and tells the kinds of events apart, and `symbols` names the
+//!   functions their addresses are in
+//! * `metadata` describes the run a trace file was written for, like the host, the input and
+//!   the versions of the plugin and QEMU, so traces of batch runs stay attributable
+//! * `output` writes traces in JSON or in more compact formats, like CBOR or the delta encoding
+//!   of `compact`, and `chrome` exports them for Perfetto
+//! * `sqlite`, with the `sqlite` feature, stores traces in a SQLite database to query with SQL,
+//!   and `parquet`, with the `parquet` feature, in Parquet files for dataframe tools
+//! * `serve`, with the `grpc` feature, publishes the events of a live trace over gRPC, and
+//!   `vsock` receives them from a plugin running in a virtual machine
+//! * `noise`, with the `noise` feature, encrypts events sent to another machine with a
+//!   pre-shared key
+//! * `cover`, `profile`, `strace` and `diff` are analyses over the events of a trace, and
+//!   `model` runs them through models of a CPU's caches and branch predictor. `analyze`
+//!   defines the `Analyzer` trait they implement, and the registry of analyzers by name
+//! * `maps` follows the memory a traced program mapped, and finds where it broke W^X
+//! * `cfg` rebuilds the control flow graph of each function a program executed, and writes
+//!   them for Graphviz or as JSON
+//! * `triage` groups the inputs that crash a program into buckets of those that crashed it
+//!   alike, by the signal and the last blocks executed
+//! * `verify` checks a trace against the program it was recorded from, to catch bugs in the
+//!   plugin and the codecs
+//! * `disasm`, with the `disasm` feature, disassembles the code of a traced program
+//! * `libafl`, with the `libafl` feature, makes cannonball a LibAFL executor for fuzzing
+//!
+//! Jaivana writes its events to stdout as newline-delimited JSON, and they are handled here as
+//! `serde_json::Value`s.
+//!
+//! Everything that runs QEMU or receives events over a socket (`session`, `control`,
+//! `forkserver`, `gdbserver`, `pool`, `driver` and `vsock`) needs the default `driver` feature.
+//! Without it, the crate only reads, writes and analyzes traces, and builds for
+//! `wasm32-unknown-unknown`, like for a trace viewer decoding traces in a browser. The default
+//! `embedded-qemu` feature builds QEMU along with the crate and runs it from memory. Without
+//! it, programs run under a QEMU binary on disk (see `driver::Qemu`).
+
+pub mod analyze;
+pub mod cfg;
+pub mod chrome;
+pub mod compact;
+#[cfg(feature = "driver")]
+pub mod control;
+pub mod cover;
+pub mod diff;
+#[cfg(feature = "disasm")]
+pub mod disasm;
+#[cfg(feature = "driver")]
+pub mod driver;
+pub mod filter;
+#[cfg(feature = "driver")]
+pub mod forkserver;
+#[cfg(feature = "driver")]
+pub mod gdbserver;
+#[cfg(feature = "libafl")]
+pub mod libafl;
+pub mod maps;
+pub mod merge;
+pub mod metadata;
+pub mod model;
+#[cfg(feature = "noise")]
+pub mod noise;
+pub mod output;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+#[cfg(feature = "driver")]
+pub mod pool;
+pub mod profile;
+#[cfg(feature = "driver")]
+mod pty;
+#[cfg(feature = "grpc")]
+pub mod serve;
+#[cfg(feature = "driver")]
+pub mod session;
+pub mod split;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod strace;
+pub mod symbols;
+pub mod tee;
+pub mod trace;
+pub mod triage;
+pub mod verify;
+#[cfg(feature = "driver")]
+pub mod vsock;
+#[cfg(feature = "driver")]
+mod watch;
+
+#[cfg(feature = "driver")]
+pub use pool::TracePool;
+#[cfg(feature = "driver")]
+pub use session::TraceSession;
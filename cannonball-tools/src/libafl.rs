@@ -0,0 +1,157 @@
+//! LibAFL integration
+//!
+//! `CannonballExecutor` makes cannonball a fuzzing backend: it runs the target under QEMU once
+//! for each input a LibAFL fuzzer generates, with the input on stdin, and counts the control
+//! flow edges it took in an `EdgeMap`. A `StdMapObserver` over that map feeds coverage back to
+//! the fuzzer. The sessions must log edges, and should log nothing else they do not need, since
+//! every event is parsed.
+//!
+//! ```ignore
+//! use cannonball_tools::{cover::EdgeMap, driver::TraceOptions, libafl::CannonballExecutor, TraceSession};
+//! use libafl::{bolts::tuples::tuple_list, observers::StdMapObserver};
+//!
+//! let mut map = EdgeMap::new(1 << 16);
+//! // The map is boxed, so the pointer stays valid once the executor owns it
+//! let observer = unsafe { StdMapObserver::from_mut_ptr("edges", map.as_mut_ptr(), map.len()) };
+//!
+//! let executor = CannonballExecutor::new(
+//!     || {
+//!         TraceSession::new("./target").options(TraceOptions {
+//!             edges: true,
+//!             ..Default::default()
+//!         })
+//!     },
+//!     map,
+//!     tuple_list!(observer),
+//! );
+//! ```
+
+use libafl::{
+    bolts::{tuples::MatchName, AsSlice},
+    executors::{Executor, ExitKind, HasObservers},
+    inputs::{HasTargetBytes, UsesInput},
+    observers::{ObserversTuple, UsesObservers},
+    state::UsesState,
+    Error,
+};
+
+use std::{
+    fmt::{self, Debug, Formatter},
+    marker::PhantomData,
+};
+
+use crate::{cover::EdgeMap, TraceSession};
+
+/// Runs a program under QEMU for each input, recording the edges it took
+pub struct CannonballExecutor<OT, S, F> {
+    /// Sets up the session for each run. The input is added to it
+    session: F,
+    /// The edges taken by the last run
+    map: EdgeMap,
+    /// The observers, which should include one over `map`
+    observers: OT,
+    /// The fuzzer state the executor runs inputs for
+    phantom: PhantomData<S>,
+}
+
+impl<OT, S, F> CannonballExecutor<OT, S, F>
+where
+    F: FnMut() -> TraceSession,
+{
+    /// Instantiate a new `CannonballExecutor`
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - Sets up the session for each run, which must log edges
+    /// * `map` - The map to count the edges of each run in
+    /// * `observers` - The observers, which should include a map observer over `map`
+    pub fn new(session: F, map: EdgeMap, observers: OT) -> Self {
+        Self {
+            session,
+            map,
+            observers,
+            phantom: PhantomData,
+        }
+    }
+
+    /// The edges taken by the last run
+    pub fn map(&self) -> &EdgeMap {
+        &self.map
+    }
+}
+
+impl<OT, S, F> Debug for CannonballExecutor<OT, S, F>
+where
+    OT: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CannonballExecutor")
+            .field("map_len", &self.map.len())
+            .field("observers", &self.observers)
+            .finish()
+    }
+}
+
+impl<EM, OT, S, F, Z> Executor<EM, Z> for CannonballExecutor<OT, S, F>
+where
+    EM: UsesState<State = S>,
+    S: UsesInput,
+    S::Input: HasTargetBytes,
+    OT: Debug + MatchName + ObserversTuple<S>,
+    F: FnMut() -> TraceSession,
+    Z: UsesState<State = S>,
+{
+    fn run_target(
+        &mut self,
+        _fuzzer: &mut Z,
+        _state: &mut Self::State,
+        _mgr: &mut EM,
+        input: &Self::Input,
+    ) -> Result<ExitKind, Error> {
+        // Observers reset the map before each run, but the map may not be observed
+        self.map.clear();
+
+        let map = &mut self.map;
+        let result = (self.session)()
+            .input(input.target_bytes().as_slice().to_vec())
+            .run(|event| map.add(&event))?;
+
+        // QEMU kills itself with the signal that killed the program, so it has no exit code
+        Ok(if result.timed_out {
+            ExitKind::Timeout
+        } else if result.exit_code.is_none() {
+            ExitKind::Crash
+        } else {
+            ExitKind::Ok
+        })
+    }
+}
+
+impl<OT, S, F> UsesState for CannonballExecutor<OT, S, F>
+where
+    S: UsesInput,
+{
+    type State = S;
+}
+
+impl<OT, S, F> UsesObservers for CannonballExecutor<OT, S, F>
+where
+    OT: ObserversTuple<S>,
+    S: UsesInput,
+{
+    type Observers = OT;
+}
+
+impl<OT, S, F> HasObservers for CannonballExecutor<OT, S, F>
+where
+    OT: ObserversTuple<S>,
+    S: UsesInput,
+{
+    fn observers(&self) -> &OT {
+        &self.observers
+    }
+
+    fn observers_mut(&mut self) -> &mut OT {
+        &mut self.observers
+    }
+}
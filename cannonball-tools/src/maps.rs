@@ -0,0 +1,309 @@
+//! Memory maps
+//!
+//! Traces recorded with `--maps` have an event for every change the program made to its memory
+//! map with `mmap`, `munmap` and `mprotect`. `MemoryMap` replays them to know what is mapped
+//! where, and with which protection, at any point of the trace. Memory QEMU mapped for the
+//! program itself, like its segments and its stack, is not in the map.
+//!
+//! `Wx` is an `Analyzer` built on it, finding where the program broke W^X: memory mapped both
+//! writable and executable, code executed from writable memory, and pages made executable
+//! after having been writable, like the pages a JIT compiler emits code to.
+//!
+//! ```no_run
+//! use std::{fs::File, io::BufReader};
+//!
+//! use cannonball_tools::{
+//!     maps::{prot_name, MemoryMap},
+//!     trace::events,
+//! };
+//!
+//! let mut map = MemoryMap::new();
+//!
+//! for event in events(BufReader::new(File::open("jit.trace").unwrap())) {
+//!     map.apply(&event.unwrap());
+//! }
+//!
+//! for (start, end, prot) in map.regions() {
+//!     println!("{:#x}-{:#x} {}", start, end, prot_name(prot));
+//! }
+//! ```
+
+use serde_json::{json, Value};
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    analyze::{Analyzer, Report},
+    trace::EventKind,
+};
+
+/// The size of a page
+const PAGE_SIZE: u64 = 0x1000;
+
+/// The protection of readable memory
+pub const PROT_READ: u64 = 0x1;
+/// The protection of writable memory
+pub const PROT_WRITE: u64 = 0x2;
+/// The protection of executable memory
+pub const PROT_EXEC: u64 = 0x4;
+
+/// A protection formatted like in `/proc/<pid>/maps`, like `r-x`
+///
+/// # Arguments
+///
+/// * `prot` - The protection, as the `PROT_*` bits
+pub fn prot_name(prot: u64) -> String {
+    [(PROT_READ, 'r'), (PROT_WRITE, 'w'), (PROT_EXEC, 'x')]
+        .iter()
+        .map(|(bit, c)| if prot & bit != 0 { *c } else { '-' })
+        .collect()
+}
+
+/// The memory a program mapped, replayed from the events of its trace
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMap {
+    /// The regions, by start address, as their end address and protection
+    regions: BTreeMap<u64, (u64, u64)>,
+}
+
+impl MemoryMap {
+    /// Instantiate a new empty `MemoryMap`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply the change an event made to the map, if it is a `Map`, `Unmap` or `Protect`
+    /// event. Returns whether it was one
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn apply(&mut self, event: &Value) -> bool {
+        let field = |name: &str| event.get(name).and_then(Value::as_u64);
+
+        let (Some(addr), Some(len)) = (field("addr"), field("len")) else {
+            return false;
+        };
+
+        let end = addr.saturating_add(len);
+
+        match EventKind::of(event) {
+            Some(EventKind::Map) => {
+                self.unmap(addr, end);
+                self.regions
+                    .insert(addr, (end, field("prot").unwrap_or_default()));
+            }
+            Some(EventKind::Unmap) => self.unmap(addr, end),
+            Some(EventKind::Protect) => {
+                self.split(addr);
+                self.split(end);
+
+                for (_, region) in self.regions.range_mut(addr..end) {
+                    region.1 = field("prot").unwrap_or_default();
+                }
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// The protection of the memory at an address, if it is in the map
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address
+    pub fn prot(&self, addr: u64) -> Option<u64> {
+        let (_, &(end, prot)) = self.regions.range(..=addr).next_back()?;
+        (addr < end).then_some(prot)
+    }
+
+    /// The regions of the map, in order, as their start and end addresses and protection.
+    /// Adjacent regions with the same protection are not merged
+    pub fn regions(&self) -> impl Iterator<Item = (u64, u64, u64)> + '_ {
+        self.regions
+            .iter()
+            .map(|(&start, &(end, prot))| (start, end, prot))
+    }
+
+    /// Split the region containing an address in two at it, if any
+    ///
+    /// # Arguments
+    ///
+    /// * `at` - The address
+    fn split(&mut self, at: u64) {
+        let Some((&start, &(end, prot))) = self.regions.range(..at).next_back() else {
+            return;
+        };
+
+        if at < end {
+            self.regions.insert(start, (at, prot));
+            self.regions.insert(at, (end, prot));
+        }
+    }
+
+    /// Remove a range of addresses from the map
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - The first address of the range
+    /// * `end` - The address after the last of the range
+    fn unmap(&mut self, start: u64, end: u64) {
+        self.split(start);
+        self.split(end);
+
+        let starts = self
+            .regions
+            .range(start..end)
+            .map(|(start, _)| *start)
+            .collect::<Vec<_>>();
+
+        for start in starts {
+            self.regions.remove(&start);
+        }
+    }
+}
+
+/// A way a program broke W^X
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// The event at this index mapped memory, or changed its protection, to be both writable
+    /// and executable
+    WritableExecutable { index: u64, addr: u64, len: u64 },
+    /// The event at this index executed code in writable memory. Only the first in each page
+    /// is reported
+    ExecutedWritable { index: u64, pc: u64 },
+    /// The event at this index made memory executable that had been writable before, like a
+    /// JIT compiler does once it emitted code
+    Jit { index: u64, addr: u64, len: u64 },
+}
+
+/// Finds where a program broke W^X
+#[derive(Debug, Clone, Default)]
+pub struct Wx {
+    /// The memory map so far
+    map: MemoryMap,
+    /// The pages that were mapped writable at some point
+    written: BTreeSet<u64>,
+    /// The pages code was executed from while they were writable
+    executed: BTreeSet<u64>,
+    /// The violations found, in order
+    violations: Vec<Violation>,
+    /// The index of the next event
+    index: u64,
+}
+
+impl Wx {
+    /// Instantiate a new `Wx`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The violations found so far, in order
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    /// Check that code executed from a range of memory that was not writable
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the event that executed it
+    /// * `pc` - The address of the code
+    fn check(&mut self, index: u64, pc: u64) {
+        let writable = self.map.prot(pc).is_some_and(|prot| prot & PROT_WRITE != 0);
+
+        if writable && self.executed.insert(pc / PAGE_SIZE) {
+            self.violations
+                .push(Violation::ExecutedWritable { index, pc });
+        }
+    }
+}
+
+impl Analyzer for Wx {
+    fn on_event(&mut self, event: &Value) {
+        let index = self.index;
+        self.index += 1;
+
+        let field = |name: &str| event.get(name).and_then(Value::as_u64);
+
+        match EventKind::of(event) {
+            Some(kind @ (EventKind::Map | EventKind::Protect)) => {
+                let (Some(addr), Some(len), Some(prot)) =
+                    (field("addr"), field("len"), field("prot"))
+                else {
+                    return;
+                };
+
+                let pages = addr / PAGE_SIZE..addr.saturating_add(len).div_ceil(PAGE_SIZE);
+
+                if prot & (PROT_WRITE | PROT_EXEC) == PROT_WRITE | PROT_EXEC {
+                    self.violations
+                        .push(Violation::WritableExecutable { index, addr, len });
+                } else if kind == EventKind::Protect
+                    && prot & PROT_EXEC != 0
+                    && pages.clone().any(|page| self.written.contains(&page))
+                {
+                    self.violations.push(Violation::Jit { index, addr, len });
+                }
+
+                if prot & PROT_WRITE != 0 {
+                    self.written.extend(pages);
+                }
+
+                self.map.apply(event);
+            }
+            Some(EventKind::Unmap) => {
+                self.map.apply(event);
+            }
+            Some(EventKind::Insn | EventKind::TB) => {
+                if let Some(pc) = field("vaddr") {
+                    self.check(index, pc);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_end(&mut self) -> Report {
+        let text = self
+            .violations
+            .iter()
+            .map(|violation| match violation {
+                Violation::WritableExecutable { index, addr, len } => format!(
+                    "event {}: {:#x}-{:#x} mapped writable and executable\n",
+                    index,
+                    addr,
+                    addr.saturating_add(*len)
+                ),
+                Violation::ExecutedWritable { index, pc } => {
+                    format!("event {}: executed writable memory at {:#x}\n", index, pc)
+                }
+                Violation::Jit { index, addr, len } => format!(
+                    "event {}: {:#x}-{:#x} made executable after being writable\n",
+                    index,
+                    addr,
+                    addr.saturating_add(*len)
+                ),
+            })
+            .collect::<String>();
+
+        let data = self
+            .violations
+            .iter()
+            .map(|violation| match violation {
+                Violation::WritableExecutable { index, addr, len } => {
+                    json!({ "kind": "writable_executable", "index": index, "addr": addr, "len": len })
+                }
+                Violation::ExecutedWritable { index, pc } => {
+                    json!({ "kind": "executed_writable", "index": index, "pc": pc })
+                }
+                Violation::Jit { index, addr, len } => {
+                    json!({ "kind": "jit", "index": index, "addr": addr, "len": len })
+                }
+            })
+            .collect();
+
+        Report::new(text, Value::Array(data))
+    }
+}
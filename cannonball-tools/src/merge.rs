@@ -0,0 +1,77 @@
+//! Trace merging
+//!
+//! Programs traced by separate QEMU processes, like a client and the server it talks to, each
+//! have a trace of their own. `merge` interleaves them into a single timeline, and tags every
+//! event with the index of the trace it came from as `stream`, so the merged trace can be
+//! handed to any other analysis and the programs still told apart.
+//!
+//! Events are ordered by `time_ns`, the wall-clock time the plugin logs them at with
+//! `tag_time=on`. An event without one is ordered as if it was logged at the same time as the
+//! event before it in its trace, and events logged at the same time by their position in their
+//! trace, so traces recorded without `tag_time` are interleaved event by event. The plugin
+//! writes the events of each VCPU in batches, so even a single trace is only in order of time
+//! once merged. The headers of the traces come first, in the order of the traces, so the
+//! merged trace starts with a header like any other.
+//!
+//! Traces are merged in memory, like they are compared by `diff`.
+//!
+//! ```no_run
+//! use cannonball_tools::{merge::merge, trace::events};
+//!
+//! use std::{fs::File, io::BufReader};
+//!
+//! let traces = ["client.trace", "server.trace"].map(|path| {
+//!     events(BufReader::new(File::open(path).unwrap()))
+//!         .collect::<Result<Vec<_>, _>>()
+//!         .unwrap()
+//! });
+//!
+//! for event in merge(traces) {
+//!     println!("{}", event);
+//! }
+//! ```
+
+use serde_json::Value;
+
+use crate::trace::EventKind;
+
+/// The field events of a merged trace are tagged with the index of their trace in
+pub const STREAM: &str = "stream";
+
+/// The field the plugin logs the time of an event in, in nanoseconds since the Unix epoch
+pub const TIME: &str = "time_ns";
+
+/// Merge traces into a single timeline, tagging each event with the index of its trace
+///
+/// # Arguments
+///
+/// * `traces` - The events of each trace
+pub fn merge(traces: impl IntoIterator<Item = impl IntoIterator<Item = Value>>) -> Vec<Value> {
+    let mut headers = Vec::new();
+    let mut events = Vec::new();
+
+    for (stream, trace) in traces.into_iter().enumerate() {
+        let mut time = 0;
+
+        for (index, mut event) in trace.into_iter().enumerate() {
+            time = event.get(TIME).and_then(Value::as_u64).unwrap_or(time);
+
+            if let Value::Object(fields) = &mut event {
+                fields.insert(STREAM.to_string(), stream.into());
+            }
+
+            if EventKind::of(&event) == Some(EventKind::Header) {
+                headers.push(event);
+            } else {
+                events.push(((time, index, stream), event));
+            }
+        }
+    }
+
+    events.sort_by_key(|(key, _)| *key);
+
+    headers
+        .into_iter()
+        .chain(events.into_iter().map(|(_, event)| event))
+        .collect()
+}
@@ -0,0 +1,178 @@
+//! Run metadata
+//!
+//! A trace says what it traced in its header, but not where, when, or with which builds of
+//! the tools, plugin and QEMU, which is what tells traces apart once a batch run has left
+//! thousands of them in a directory. Trace files written for a run start with a
+//! `RunMetadata` record describing it, before the events of the plugin, and `TraceReader`
+//! takes it off the front of the trace, to hand back with `TraceReader::metadata` rather than
+//! as an event:
+//!
+//! ```no_run
+//! use cannonball_tools::{output::OutputFormat, trace::TraceReader};
+//!
+//! let reader = TraceReader::open("ls.bin", OutputFormat::Binary).unwrap();
+//!
+//! if let Some(metadata) = reader.metadata() {
+//!     println!("run {} on {:?}", metadata.run_id, metadata.hostname);
+//! }
+//! ```
+//!
+//! The ID of a run is derived from what determines its trace: the program and its arguments,
+//! the arguments of the plugin, the input, and the versions of the tools, plugin and QEMU. Two
+//! runs of the same program on the same input with the same builds get the same ID, so a
+//! rerun of a batch can be matched up with the original, and told apart from it by its
+//! `start_time` and `hostname`. Inputs and plugins are identified by the XXH3-128 hash of
+//! their contents, which is fast enough for large corpora but is not a cryptographic hash.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use xxhash_rust::xxh3::xxh3_128;
+
+use std::{
+    env::{args_os, consts, var},
+    fs::read_to_string,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The field a record holding the metadata of a run keeps it in. Events never have it
+pub const RECORD: &str = "run_metadata";
+
+/// Where and how a trace was recorded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunMetadata {
+    /// The ID of the run, a UUID derived from what determines its trace
+    pub run_id: String,
+    /// When the run started, in seconds since the Unix epoch
+    pub start_time: u64,
+    /// The name of the host the run was on, if known
+    pub hostname: Option<String>,
+    /// The operating system of the host, like `linux`
+    pub os: String,
+    /// The architecture of the host, like `x86_64`
+    pub arch: String,
+    /// The command line of the process that ran the program, like `cannonball run ...`
+    pub command_line: Vec<String>,
+    /// The program traced
+    pub program: String,
+    /// The arguments to the program
+    pub args: Vec<String>,
+    /// The arguments passed to the plugin
+    pub plugin_args: Vec<String>,
+    /// The file fed to the program on stdin, if any
+    pub input_file: Option<String>,
+    /// The hash of the input fed to the program, if any
+    pub input_hash: Option<String>,
+    /// The hash of the plugin, which identifies its build
+    pub plugin_hash: Option<String>,
+    /// The version of cannonball-tools
+    pub tools_version: String,
+    /// The version of QEMU, like `7.1.0`, if known
+    pub qemu_version: Option<String>,
+}
+
+impl RunMetadata {
+    /// Describe a run starting now, on this host
+    ///
+    /// # Arguments
+    ///
+    /// * `program` - The program traced
+    /// * `args` - The arguments to the program
+    /// * `plugin_args` - The arguments passed to the plugin
+    /// * `input_file` - The file fed to the program on stdin, if any
+    /// * `input_hash` - The hash of the input fed to the program, see `content_hash`
+    /// * `plugin_hash` - The hash of the plugin, see `content_hash`
+    /// * `qemu_version` - The version of QEMU
+    pub fn new(
+        program: String,
+        args: Vec<String>,
+        plugin_args: Vec<String>,
+        input_file: Option<String>,
+        input_hash: Option<String>,
+        plugin_hash: Option<String>,
+        qemu_version: Option<String>,
+    ) -> Self {
+        let tools_version = env!("CARGO_PKG_VERSION").to_string();
+        // Serialized as JSON so that the ID does not depend on how the fields are laid out
+        let identity = json!([
+            program,
+            args,
+            plugin_args,
+            input_hash,
+            plugin_hash,
+            tools_version,
+            qemu_version,
+        ]);
+
+        Self {
+            run_id: uuid(xxh3_128(identity.to_string().as_bytes())),
+            start_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs()),
+            hostname: hostname(),
+            os: consts::OS.to_string(),
+            arch: consts::ARCH.to_string(),
+            command_line: args_os()
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect(),
+            program,
+            args,
+            plugin_args,
+            input_file,
+            input_hash,
+            plugin_hash,
+            tools_version,
+            qemu_version,
+        }
+    }
+
+    /// The metadata held by a record, if it is one holding the metadata of a run
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The record, as read from a trace
+    pub fn of(record: &Value) -> Option<Self> {
+        serde_json::from_value(record.get(RECORD)?.clone()).ok()
+    }
+
+    /// The record holding the metadata, to write to a trace before its events
+    pub fn to_record(&self) -> Value {
+        json!({ RECORD: self })
+    }
+}
+
+/// The hash of some contents, like an input or a plugin, as 32 hex digits
+///
+/// # Arguments
+///
+/// * `contents` - The contents
+pub fn content_hash(contents: &[u8]) -> String {
+    format!("{:032x}", xxh3_128(contents))
+}
+
+/// Format a hash as a UUID, with the version and variant of a custom (version 8) UUID
+///
+/// # Arguments
+///
+/// * `hash` - The hash
+fn uuid(hash: u128) -> String {
+    let bits = (hash & !(0xf << 76) & !(0b11 << 62)) | (0x8 << 76) | (0b10 << 62);
+    let hex = format!("{:032x}", bits);
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// The name of this host, if it can be found
+fn hostname() -> Option<String> {
+    read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|hostname| hostname.trim().to_string())
+        .or_else(|| var("HOSTNAME").ok())
+        .filter(|hostname| !hostname.is_empty())
+}
@@ -0,0 +1,454 @@
+//! Cache and branch predictor models
+//!
+//! `Model` replays the instruction and memory access events of a trace through models of an
+//! instruction cache, a data cache and a branch predictor, in the spirit of QEMU's `cache`
+//! plugin, and attributes their misses to the instructions that caused them. The trace must log
+//! every instruction with its whole opcode (`log_pc` and `log_opcode`, without `opcode_size`) to
+//! model the instruction cache and the branch predictor, and memory accesses (`log_mem`) to model
+//! the data cache.
+//!
+//! Events do not say whether a branch was taken. Each instruction ending a translation block is
+//! modeled as a branch, and it was taken if the next instruction executed on the same VCPU does
+//! not directly follow it. Blocks also end on page boundaries and when they grow too long, so a
+//! few instructions that are not branches are counted as branches that are never taken.
+//!
+//! ```no_run
+//! use cannonball_tools::{
+//!     model::{BranchPredictor, Cache, Model},
+//!     symbols::Symbolizer,
+//!     trace::events,
+//! };
+//! use std::{fs::File, io::{stdout, BufReader}};
+//!
+//! let mut model = Model::new(
+//!     Cache::new(16384, 8, 64),
+//!     Cache::new(16384, 8, 64),
+//!     BranchPredictor::new(4096, 8),
+//! );
+//!
+//! for event in events(BufReader::new(File::open("ls.trace").unwrap())) {
+//!     model.add(&event.unwrap());
+//! }
+//!
+//! let symbolizer = Symbolizer::load("/bin/ls").unwrap();
+//! model.write(Some(&symbolizer), 10, stdout()).unwrap();
+//! ```
+
+use serde_json::Value;
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+};
+
+use crate::{symbols::Symbolizer, trace::EventKind};
+
+/// A set associative cache with least recently used replacement. Only which lines are cached is
+/// modeled, not their contents, and loads and stores are handled alike
+#[derive(Debug, Clone)]
+pub struct Cache {
+    /// The tags of the lines in each set, most recently used first
+    sets: Vec<Vec<u64>>,
+    /// The number of lines in each set
+    ways: usize,
+    /// The log2 of the line size
+    line_shift: u32,
+    /// The number of accesses so far
+    pub accesses: u64,
+    /// The number of accesses so far that missed
+    pub misses: u64,
+}
+
+impl Cache {
+    /// Instantiate a new empty `Cache`
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The size of the cache in bytes
+    /// * `ways` - The number of lines in each set. Must divide the number of lines
+    /// * `line_size` - The size of a line in bytes. Must be a power of two
+    pub fn new(size: usize, ways: usize, line_size: usize) -> Self {
+        assert!(
+            line_size.is_power_of_two(),
+            "The line size of a cache must be a power of two"
+        );
+        assert!(
+            ways > 0 && size > 0 && size.is_multiple_of(ways * line_size),
+            "A cache must hold a whole number of sets"
+        );
+
+        Self {
+            sets: vec![Vec::with_capacity(ways); size / (ways * line_size)],
+            ways,
+            line_shift: line_size.trailing_zeros(),
+            accesses: 0,
+            misses: 0,
+        }
+    }
+
+    /// Access memory through the cache, and return whether the access hit. An access spanning
+    /// several lines misses if any of them missed
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address accessed
+    /// * `size` - The size of the access in bytes
+    pub fn access(&mut self, addr: u64, size: u64) -> bool {
+        let first = addr >> self.line_shift;
+        let last = addr.saturating_add(size.max(1) - 1) >> self.line_shift;
+        let n_sets = self.sets.len() as u64;
+        let mut hit = true;
+
+        for line in first..=last {
+            let set = &mut self.sets[(line % n_sets) as usize];
+
+            match set.iter().position(|tag| *tag == line) {
+                Some(way) => set[..=way].rotate_right(1),
+                None => {
+                    if set.len() == self.ways {
+                        set.pop();
+                    }
+                    set.insert(0, line);
+                    hit = false;
+                }
+            }
+        }
+
+        self.accesses += 1;
+
+        if !hit {
+            self.misses += 1;
+        }
+
+        hit
+    }
+}
+
+/// A branch predictor with a table of two bit saturating counters, indexed by the address of
+/// the branch hashed with the outcomes of the last branches, like gshare. Without history, it
+/// is a bimodal predictor
+#[derive(Debug, Clone)]
+pub struct BranchPredictor {
+    /// The counters. 0 and 1 predict not taken, 2 and 3 taken
+    counters: Vec<u8>,
+    /// The outcomes of the last branches, most recent in the lowest bit
+    history: u64,
+    /// The number of outcomes kept in the history
+    history_bits: u32,
+    /// The number of branches so far
+    pub branches: u64,
+    /// The number of branches so far that were mispredicted
+    pub mispredictions: u64,
+}
+
+impl BranchPredictor {
+    /// Instantiate a new `BranchPredictor`, with every counter weakly not taken
+    ///
+    /// # Arguments
+    ///
+    /// * `entries` - The number of counters. Must be a power of two
+    /// * `history_bits` - The number of branch outcomes hashed into the index, at most 64
+    pub fn new(entries: usize, history_bits: u32) -> Self {
+        assert!(
+            entries.is_power_of_two(),
+            "The number of entries of a branch predictor must be a power of two"
+        );
+        assert!(
+            history_bits <= 64,
+            "A branch predictor keeps at most 64 outcomes"
+        );
+
+        Self {
+            counters: vec![1; entries],
+            history: 0,
+            history_bits,
+            branches: 0,
+            mispredictions: 0,
+        }
+    }
+
+    /// Predict a branch, learn its outcome, and return whether the prediction was right
+    ///
+    /// # Arguments
+    ///
+    /// * `pc` - The address of the branch
+    /// * `taken` - Whether the branch was taken
+    pub fn predict(&mut self, pc: u64, taken: bool) -> bool {
+        let history = self.history & u64::MAX.checked_shr(64 - self.history_bits).unwrap_or(0);
+        let index = ((pc ^ history) as usize) & (self.counters.len() - 1);
+        let counter = &mut self.counters[index];
+        let correct = (*counter >= 2) == taken;
+
+        *counter = if taken {
+            (*counter + 1).min(3)
+        } else {
+            counter.saturating_sub(1)
+        };
+        self.history = (self.history << 1) | taken as u64;
+        self.branches += 1;
+
+        if !correct {
+            self.mispredictions += 1;
+        }
+
+        correct
+    }
+}
+
+/// A number of events, and how many of them missed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Misses {
+    pub total: u64,
+    pub missed: u64,
+}
+
+impl Misses {
+    /// Count an event
+    ///
+    /// # Arguments
+    ///
+    /// * `hit` - Whether the event hit
+    fn count(&mut self, hit: bool) {
+        self.total += 1;
+        self.missed += !hit as u64;
+    }
+
+    /// Add the counts of other events
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The other counts
+    fn merge(&mut self, other: &Misses) {
+        self.total += other.total;
+        self.missed += other.missed;
+    }
+
+    /// The share of events that missed, in percent
+    pub fn rate(&self) -> f64 {
+        self.missed as f64 * 100.0 / self.total.max(1) as f64
+    }
+}
+
+/// The misses caused by an instruction, or by the instructions of a function
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ModelStats {
+    /// Instruction fetches, through the instruction cache
+    pub fetches: Misses,
+    /// Memory accesses, through the data cache
+    pub accesses: Misses,
+    /// Branches, through the branch predictor
+    pub branches: Misses,
+}
+
+impl ModelStats {
+    /// Add the counts of other instructions
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The counts of the other instructions
+    fn merge(&mut self, other: &ModelStats) {
+        self.fetches.merge(&other.fetches);
+        self.accesses.merge(&other.accesses);
+        self.branches.merge(&other.branches);
+    }
+
+    /// The number of misses of every kind
+    pub fn missed(&self) -> u64 {
+        self.fetches.missed + self.accesses.missed + self.branches.missed
+    }
+}
+
+/// Models the caches and branch predictor of a CPU executing the events of a trace
+#[derive(Debug, Clone)]
+pub struct Model {
+    /// The instruction cache
+    pub icache: Cache,
+    /// The data cache
+    pub dcache: Cache,
+    /// The branch predictor
+    pub predictor: BranchPredictor,
+    /// The misses caused by each instruction, by address
+    pub by_pc: BTreeMap<u64, ModelStats>,
+    /// The last branch executed on each VCPU whose outcome is not known yet, and the address
+    /// of the instruction following it
+    pending: HashMap<Option<u64>, (u64, u64)>,
+}
+
+impl Model {
+    /// Instantiate a new `Model`
+    ///
+    /// # Arguments
+    ///
+    /// * `icache` - The instruction cache
+    /// * `dcache` - The data cache
+    /// * `predictor` - The branch predictor
+    pub fn new(icache: Cache, dcache: Cache, predictor: BranchPredictor) -> Self {
+        Self {
+            icache,
+            dcache,
+            predictor,
+            by_pc: BTreeMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Account for an event. Events other than instruction and memory access events are ignored
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn add(&mut self, event: &Value) {
+        match EventKind::of(event) {
+            Some(EventKind::Insn) => self.insn(event),
+            Some(EventKind::Mem) => self.mem(event),
+            Some(EventKind::SysMem) => {
+                if let Some(mem) = event.get("mem") {
+                    self.mem(mem);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Fetch an instruction, and resolve the branch executed before it on its VCPU
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The instruction event
+    fn insn(&mut self, event: &Value) {
+        let Some(vaddr) = event.get("vaddr").and_then(Value::as_u64) else {
+            return;
+        };
+        let vcpu = event.get("vcpu_idx").and_then(Value::as_u64);
+        // Without its opcode, an instruction is assumed to be a single byte
+        let size = event
+            .get("opcode")
+            .and_then(Value::as_array)
+            .map(|opcode| opcode.len() as u64)
+            .unwrap_or(1);
+
+        if let Some((pc, next)) = self.pending.remove(&vcpu) {
+            let correct = self.predictor.predict(pc, vaddr != next);
+            self.by_pc.entry(pc).or_default().branches.count(correct);
+        }
+
+        let hit = self.icache.access(vaddr, size);
+        self.by_pc.entry(vaddr).or_default().fetches.count(hit);
+
+        if event
+            .get("branch")
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+        {
+            self.pending.insert(vcpu, (vaddr, vaddr.wrapping_add(size)));
+        }
+    }
+
+    /// Access memory through the data cache
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The memory access event
+    fn mem(&mut self, event: &Value) {
+        let field = |name: &str| event.get(name).and_then(Value::as_u64);
+        let (Some(vaddr), Some(size)) = (field("vaddr"), field("size")) else {
+            return;
+        };
+        let pc = event
+            .get("insn")
+            .and_then(|insn| insn.get("vaddr"))
+            .and_then(Value::as_u64)
+            .unwrap_or_default();
+
+        let hit = self.dcache.access(vaddr, size);
+        self.by_pc.entry(pc).or_default().accesses.count(hit);
+    }
+
+    /// The misses caused by the instructions of each function, most misses first. Instructions
+    /// outside of the functions the symbolizer knows are grouped together, as `None`
+    ///
+    /// # Arguments
+    ///
+    /// * `symbolizer` - Names the functions instructions are in. If not set, every instruction
+    ///   is grouped together
+    pub fn by_function(
+        &self,
+        symbolizer: Option<&Symbolizer>,
+    ) -> Vec<(Option<String>, ModelStats)> {
+        let mut functions = HashMap::<Option<&str>, ModelStats>::new();
+
+        for (pc, stats) in &self.by_pc {
+            let name = symbolizer
+                .and_then(|symbolizer| symbolizer.symbolize(*pc))
+                .map(|(name, _)| name);
+            functions.entry(name).or_default().merge(stats);
+        }
+
+        let mut functions = functions
+            .into_iter()
+            .map(|(name, stats)| (name.map(|name| name.to_string()), stats))
+            .collect::<Vec<_>>();
+        functions.sort_by_key(|(name, stats)| (Reverse(stats.missed()), name.clone()));
+        functions
+    }
+
+    /// Write the miss rates of each model, then of the functions with the most misses
+    ///
+    /// # Arguments
+    ///
+    /// * `symbolizer` - Names the functions instructions are in
+    /// * `limit` - The number of functions to write
+    /// * `out` - Where to write the report
+    pub fn write(
+        &self,
+        symbolizer: Option<&Symbolizer>,
+        limit: usize,
+        mut out: impl Write,
+    ) -> io::Result<()> {
+        let rate = |missed: u64, total: u64| missed as f64 * 100.0 / total.max(1) as f64;
+
+        writeln!(
+            out,
+            "I-cache: {} misses in {} fetches ({:.2}%)",
+            self.icache.misses,
+            self.icache.accesses,
+            rate(self.icache.misses, self.icache.accesses)
+        )?;
+        writeln!(
+            out,
+            "D-cache: {} misses in {} accesses ({:.2}%)",
+            self.dcache.misses,
+            self.dcache.accesses,
+            rate(self.dcache.misses, self.dcache.accesses)
+        )?;
+        writeln!(
+            out,
+            "Branches: {} mispredicted of {} ({:.2}%)",
+            self.predictor.mispredictions,
+            self.predictor.branches,
+            rate(self.predictor.mispredictions, self.predictor.branches)
+        )?;
+        writeln!(
+            out,
+            "{:>10} {:>7} {:>10} {:>7} {:>10} {:>7}  function",
+            "fetches", "miss%", "accesses", "miss%", "branches", "miss%"
+        )?;
+
+        for (name, stats) in self.by_function(symbolizer).into_iter().take(limit) {
+            writeln!(
+                out,
+                "{:>10} {:>6.2}% {:>10} {:>6.2}% {:>10} {:>6.2}%  {}",
+                stats.fetches.total,
+                stats.fetches.rate(),
+                stats.accesses.total,
+                stats.accesses.rate(),
+                stats.branches.total,
+                stats.branches.rate(),
+                name.as_deref().unwrap_or("??")
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+//! Encrypted transport
+//!
+//! Events sent over TCP to another machine are readable, and forgeable, by anything on the
+//! path between them, which matters when tracing sensitive programs. With the `noise` feature,
+//! a `NoiseStream` wraps a connection in a Noise protocol channel
+//! (`Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s`) keyed with a pre-shared key: a peer that does not
+//! know the key fails the handshake, and every message after it is encrypted and
+//! authenticated.
+//!
+//! The key is 32 bytes, stored hex-encoded in a file given to both ends, like one made with
+//! `head -c 32 /dev/urandom | xxd -p -c 32 > trace.key`. The Jaivana plugin encrypts its sink
+//! with `sink=tcp:<host>:<port>,sink_psk_file=<path>`, and the consumer accepts it with
+//! `NoiseStream::accept`:
+//!
+//! ```no_run
+//! use std::{io::BufReader, net::TcpListener};
+//!
+//! use cannonball_tools::{
+//!     noise::{read_psk, NoiseStream},
+//!     output::OutputFormat,
+//!     trace::TraceReader,
+//! };
+//!
+//! let psk = read_psk("trace.key").unwrap();
+//! let (stream, _) = TcpListener::bind("0.0.0.0:5000").unwrap().accept().unwrap();
+//! let stream = NoiseStream::accept(stream, &psk).unwrap();
+//!
+//! for event in TraceReader::new(BufReader::new(stream), OutputFormat::Binary).unwrap() {
+//!     println!("{}", event.unwrap());
+//! }
+//! ```
+//!
+//! Each Noise message is preceded by its length as a big-endian `u16`, as the Noise
+//! specification recommends.
+
+use snow::{Builder, HandshakeState, TransportState};
+
+use std::{
+    fs::read_to_string,
+    io::{self, ErrorKind, Read, Write},
+    path::Path,
+};
+
+/// The Noise protocol of the channel
+pub const NOISE_PARAMS: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+
+/// The size of a pre-shared key
+pub const PSK_LEN: usize = 32;
+
+/// The largest Noise message
+const MAX_MESSAGE: usize = 65535;
+
+/// The size of the authentication tag of an encrypted message
+const TAG_LEN: usize = 16;
+
+/// The largest payload of a Noise message
+const MAX_PAYLOAD: usize = MAX_MESSAGE - TAG_LEN;
+
+/// Read a pre-shared key, stored hex-encoded in a file
+///
+/// # Arguments
+///
+/// * `path` - The file
+pub fn read_psk(path: impl AsRef<Path>) -> io::Result<[u8; PSK_LEN]> {
+    let hex = read_to_string(path)?;
+    let hex = hex.trim();
+    let invalid = || {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("A pre-shared key must be {} hex-encoded bytes", PSK_LEN),
+        )
+    };
+
+    if hex.len() != PSK_LEN * 2 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut psk = [0; PSK_LEN];
+
+    for (byte, digits) in psk.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+    }
+
+    Ok(psk)
+}
+
+/// Write a Noise message, preceded by its length
+///
+/// # Arguments
+///
+/// * `stream` - Where to write it
+/// * `message` - The message
+fn send(stream: &mut impl Write, message: &[u8]) -> io::Result<()> {
+    stream.write_all(&(message.len() as u16).to_be_bytes())?;
+    stream.write_all(message)
+}
+
+/// Read a Noise message written by `send` into `buf`, returning its length, or `None` if the
+/// stream ended between messages
+///
+/// # Arguments
+///
+/// * `stream` - Where to read it from
+/// * `buf` - Where to read it to, at least `MAX_MESSAGE` bytes long
+fn receive(stream: &mut impl Read, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    let mut len = [0; 2];
+
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = u16::from_be_bytes(len) as usize;
+    stream.read_exact(&mut buf[..len])?;
+    Ok(Some(len))
+}
+
+/// A connection wrapped in a Noise channel. Reads decrypt the messages of the peer, and writes
+/// are encrypted in messages of up to 64KiB, so a stream should be buffered
+pub struct NoiseStream<S: Read + Write> {
+    stream: S,
+    transport: TransportState,
+    /// The last message received, or sent
+    message: Vec<u8>,
+    /// The payload of the last message received
+    payload: Vec<u8>,
+    /// How much of `payload` has been read
+    read: usize,
+}
+
+impl<S: Read + Write> NoiseStream<S> {
+    /// Start the channel as the side that connected, which sends the first handshake message
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The connection
+    /// * `psk` - The pre-shared key
+    pub fn connect(stream: S, psk: &[u8; PSK_LEN]) -> io::Result<Self> {
+        let handshake = Builder::new(NOISE_PARAMS.parse().map_err(io::Error::other)?)
+            .psk(0, psk)
+            .build_initiator()
+            .map_err(io::Error::other)?;
+
+        Self::handshake(stream, handshake, true)
+    }
+
+    /// Start the channel as the side that accepted the connection. Fails if the peer does not
+    /// know the pre-shared key
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The connection
+    /// * `psk` - The pre-shared key
+    pub fn accept(stream: S, psk: &[u8; PSK_LEN]) -> io::Result<Self> {
+        let handshake = Builder::new(NOISE_PARAMS.parse().map_err(io::Error::other)?)
+            .psk(0, psk)
+            .build_responder()
+            .map_err(io::Error::other)?;
+
+        Self::handshake(stream, handshake, false)
+    }
+
+    /// Exchange handshake messages until the channel is established
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The connection
+    /// * `handshake` - The state of the handshake
+    /// * `sending` - Whether this side sends the next handshake message
+    fn handshake(
+        mut stream: S,
+        mut handshake: HandshakeState,
+        mut sending: bool,
+    ) -> io::Result<Self> {
+        let mut message = vec![0; MAX_MESSAGE];
+        let mut payload = vec![0; MAX_MESSAGE];
+
+        while !handshake.is_handshake_finished() {
+            if sending {
+                let len = handshake
+                    .write_message(&[], &mut message)
+                    .map_err(io::Error::other)?;
+                send(&mut stream, &message[..len])?;
+            } else {
+                let len = receive(&mut stream, &mut message)?
+                    .ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))?;
+                handshake
+                    .read_message(&message[..len], &mut payload)
+                    .map_err(|_| {
+                        io::Error::new(
+                            ErrorKind::PermissionDenied,
+                            "The peer does not know the pre-shared key",
+                        )
+                    })?;
+            }
+
+            sending = !sending;
+        }
+
+        Ok(Self {
+            stream,
+            transport: handshake.into_transport_mode().map_err(io::Error::other)?,
+            message,
+            payload,
+            read: 0,
+        })
+    }
+}
+
+impl<S: Read + Write> Read for NoiseStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Empty messages are skipped, so that an empty read means the end of the stream
+        while self.read == self.payload.len() {
+            let Some(len) = receive(&mut self.stream, &mut self.message)? else {
+                return Ok(0);
+            };
+
+            self.payload.resize(MAX_MESSAGE, 0);
+            let len = self
+                .transport
+                .read_message(&self.message[..len], &mut self.payload)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            self.payload.truncate(len);
+            self.read = 0;
+        }
+
+        let len = buf.len().min(self.payload.len() - self.read);
+        buf[..len].copy_from_slice(&self.payload[self.read..self.read + len]);
+        self.read += len;
+        Ok(len)
+    }
+}
+
+impl<S: Read + Write> Write for NoiseStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let payload = &buf[..buf.len().min(MAX_PAYLOAD)];
+        let len = self
+            .transport
+            .write_message(payload, &mut self.message)
+            .map_err(io::Error::other)?;
+        send(&mut self.stream, &self.message[..len])?;
+        Ok(payload.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
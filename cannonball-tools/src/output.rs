@@ -0,0 +1,224 @@
+//! Trace output formats
+//!
+//! Traces are newline-delimited JSON by default, which is easy to read and to grep but large.
+//! An `EventWriter` writes events in one of several formats, chosen with `OutputFormat`:
+//!
+//! * `json`: one JSON object per line, as logged by the plugin
+//! * `cbor`: a sequence of CBOR items, one per event
+//! * `msgpack`: a sequence of MessagePack maps, one per event
+//! * `binary`: one CBOR item per event, each preceded by its length as a little-endian `u32`,
+//!   so readers can skip events without decoding them
+//! * `compact`: instruction and translation block events with their addresses delta-encoded,
+//!   for long instruction traces (see `compact`)
+//! * `compact-repeat`: `compact`, with runs of identical events, like those of tight loops,
+//!   written once along with how many times they repeat
+//! * `chrome`: a Chrome trace of function calls and system calls, to view in Perfetto (see
+//!   `chrome`)
+//! * `sqlite`: a SQLite database, with the `sqlite` feature (see `sqlite`)
+//! * `parquet`: a Parquet file, with the `parquet` feature (see `parquet`)
+//!
+//! Databases and Parquet files can only be written to a file, with `OutputFormat::create`.
+//! Trace files written for a run with `OutputFormat::create_with_metadata` start with the
+//! metadata of the run (see `metadata`).
+//!
+#![cfg_attr(feature = "driver", doc = "```no_run")]
+#![cfg_attr(not(feature = "driver"), doc = "```ignore")]
+//! use cannonball_tools::{output::OutputFormat, TraceSession};
+//!
+//! let mut writer = OutputFormat::Cbor.create("ls.cbor").unwrap();
+//!
+//! TraceSession::new("/bin/ls")
+//!     .run(|event| writer.write(&event).unwrap())
+//!     .unwrap();
+//!
+//! writer.finish().unwrap();
+//! ```
+
+use clap::ValueEnum;
+use serde_json::Value;
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+#[cfg(feature = "parquet")]
+use crate::parquet::ParquetWriter;
+#[cfg(feature = "sqlite")]
+use crate::sqlite::SqliteWriter;
+use crate::{chrome::ChromeWriter, compact::CompactWriter, metadata::RunMetadata};
+
+/// The formats traces can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Newline-delimited JSON
+    #[default]
+    Json,
+    /// A sequence of CBOR items
+    Cbor,
+    /// A sequence of MessagePack maps
+    Msgpack,
+    /// Length-prefixed CBOR items
+    Binary,
+    /// Delta-encoded instruction and block events
+    Compact,
+    /// Delta-encoded instruction and block events, with runs of identical events coalesced
+    CompactRepeat,
+    /// A Chrome trace, for ui.perfetto.dev
+    Chrome,
+    /// A SQLite database
+    Sqlite,
+    /// A Parquet file
+    Parquet,
+}
+
+impl OutputFormat {
+    /// A writer of events in this format
+    ///
+    /// # Arguments
+    ///
+    /// * `out` - Where to write the events. Every event is a separate write, so files should
+    ///   be buffered
+    pub fn writer<W: Write + Send + 'static>(
+        self,
+        out: W,
+    ) -> io::Result<Box<dyn EventWriter + Send>> {
+        Ok(match self {
+            Self::Json => Box::new(JsonWriter { out }),
+            Self::Cbor => Box::new(CborWriter { out }),
+            Self::Msgpack => Box::new(MsgpackWriter { out }),
+            Self::Binary => Box::new(BinaryWriter { out }),
+            Self::Compact => Box::new(CompactWriter::new(out)),
+            Self::CompactRepeat => Box::new(CompactWriter::new(out).coalescing()),
+            Self::Chrome => Box::new(ChromeWriter::new(out)),
+            Self::Sqlite | Self::Parquet => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{:?} traces can only be written to a file", self),
+                ))
+            }
+        })
+    }
+
+    /// A writer of events in this format to a new file, buffered
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file to create
+    pub fn create(self, path: impl AsRef<Path>) -> io::Result<Box<dyn EventWriter + Send>> {
+        match self {
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite => Ok(Box::new(SqliteWriter::create(path)?)),
+            #[cfg(not(feature = "sqlite"))]
+            Self::Sqlite => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "SQLite traces need the sqlite feature",
+            )),
+            #[cfg(feature = "parquet")]
+            Self::Parquet => Ok(Box::new(ParquetWriter::create(path)?)),
+            #[cfg(not(feature = "parquet"))]
+            Self::Parquet => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Parquet traces need the parquet feature",
+            )),
+            _ => self.writer(BufWriter::new(File::create(path)?)),
+        }
+    }
+
+    /// A writer of the events of a run in this format to a new file, buffered, which first
+    /// writes the metadata of the run
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file to create
+    /// * `metadata` - The metadata of the run
+    pub fn create_with_metadata(
+        self,
+        path: impl AsRef<Path>,
+        metadata: &RunMetadata,
+    ) -> io::Result<Box<dyn EventWriter + Send>> {
+        let mut writer = self.create(path)?;
+        writer.write(&metadata.to_record())?;
+        Ok(writer)
+    }
+}
+
+/// Writes events in some format
+pub trait EventWriter {
+    /// Write an event
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    fn write(&mut self, event: &Value) -> io::Result<()>;
+
+    /// Write out everything written so far. Events may be buffered until then
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// Writes events as newline-delimited JSON
+struct JsonWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> EventWriter for JsonWriter<W> {
+    fn write(&mut self, event: &Value) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, event)?;
+        writeln!(self.out)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Writes events as a sequence of CBOR items
+struct CborWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> EventWriter for CborWriter<W> {
+    fn write(&mut self, event: &Value) -> io::Result<()> {
+        serde_cbor::to_writer(&mut self.out, event).map_err(io::Error::other)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Writes events as a sequence of MessagePack maps
+struct MsgpackWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> EventWriter for MsgpackWriter<W> {
+    fn write(&mut self, event: &Value) -> io::Result<()> {
+        // Named, so events are maps keyed by field name like their JSON
+        rmp_serde::encode::write_named(&mut self.out, event).map_err(io::Error::other)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Writes events as CBOR items, each preceded by its length
+struct BinaryWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> EventWriter for BinaryWriter<W> {
+    fn write(&mut self, event: &Value) -> io::Result<()> {
+        let item = serde_cbor::to_vec(event).map_err(io::Error::other)?;
+        let len = u32::try_from(item.len()).map_err(io::Error::other)?;
+
+        self.out.write_all(&len.to_le_bytes())?;
+        self.out.write_all(&item)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
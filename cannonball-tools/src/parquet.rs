@@ -0,0 +1,251 @@
+//! Parquet trace export
+//!
+//! JSON spends dozens of bytes on every instruction event. `ParquetWriter` stores a trace as a
+//! Parquet file instead, one row per event in row groups of a fixed number of events, which
+//! Polars, DuckDB and most other dataframe tools read directly. Columns hold what most events
+//! have in common, and are null for events that do not have them:
+//!
+//! * `seq`: the position of the event in the trace
+//! * `kind`: the kind of the event, like `Insn`
+//! * `pid` and `vcpu`: the process and VCPU that logged it
+//! * `pc`: the address of the instruction, block, function entry or exit, or edge source, or
+//!   the last instruction executed before a signal
+//! * `addr`: the address accessed by a memory access, the target of an edge or call, or the
+//!   handler of a signal
+//! * `size`: the size of a memory access or block
+//! * `opcode`: the bytes of an instruction
+//! * `isa_mode`: the instruction set of an instruction, on targets with several, like `thumb`
+//! * `branch` and `is_store`: the flags of instructions and memory accesses
+//!
+//! Anything else an event carries, like the arguments of a system call, is not stored.
+//!
+#![cfg_attr(feature = "driver", doc = "```no_run")]
+#![cfg_attr(not(feature = "driver"), doc = "```ignore")]
+//! use cannonball_tools::{output::EventWriter, parquet::ParquetWriter, TraceSession};
+//!
+//! let mut writer = ParquetWriter::create("ls.parquet").unwrap();
+//! TraceSession::new("/bin/ls")
+//!     .run(|event| writer.write(&event).unwrap())
+//!     .unwrap();
+//! writer.finish().unwrap();
+//! ```
+
+use arrow_array::{
+    builder::{BinaryBuilder, BooleanBuilder, StringBuilder, UInt32Builder, UInt64Builder},
+    ArrayRef, RecordBatch,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::{arrow::ArrowWriter, file::properties::WriterProperties};
+use serde_json::Value;
+
+use std::{fs::File, io, path::Path, sync::Arc};
+
+use crate::{output::EventWriter, trace::EventKind};
+
+/// The number of events in each row group
+pub const ROW_GROUP_SIZE: usize = 1 << 20;
+
+/// The columns of the rows of the row group being built
+struct Columns {
+    seq: UInt64Builder,
+    kind: StringBuilder,
+    pid: UInt32Builder,
+    vcpu: UInt32Builder,
+    pc: UInt64Builder,
+    addr: UInt64Builder,
+    size: UInt64Builder,
+    opcode: BinaryBuilder,
+    isa_mode: StringBuilder,
+    branch: BooleanBuilder,
+    is_store: BooleanBuilder,
+}
+
+impl Columns {
+    /// Instantiate new empty columns
+    fn new() -> Self {
+        Self {
+            seq: UInt64Builder::new(),
+            kind: StringBuilder::new(),
+            pid: UInt32Builder::new(),
+            vcpu: UInt32Builder::new(),
+            pc: UInt64Builder::new(),
+            addr: UInt64Builder::new(),
+            size: UInt64Builder::new(),
+            opcode: BinaryBuilder::new(),
+            isa_mode: StringBuilder::new(),
+            branch: BooleanBuilder::new(),
+            is_store: BooleanBuilder::new(),
+        }
+    }
+
+    /// The schema of the columns
+    fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("seq", DataType::UInt64, false),
+            Field::new("kind", DataType::Utf8, true),
+            Field::new("pid", DataType::UInt32, true),
+            Field::new("vcpu", DataType::UInt32, true),
+            Field::new("pc", DataType::UInt64, true),
+            Field::new("addr", DataType::UInt64, true),
+            Field::new("size", DataType::UInt64, true),
+            Field::new("opcode", DataType::Binary, true),
+            Field::new("isa_mode", DataType::Utf8, true),
+            Field::new("branch", DataType::Boolean, true),
+            Field::new("is_store", DataType::Boolean, true),
+        ]))
+    }
+
+    /// Take the rows built so far, leaving the columns empty
+    fn finish(&mut self) -> Vec<ArrayRef> {
+        vec![
+            Arc::new(self.seq.finish()),
+            Arc::new(self.kind.finish()),
+            Arc::new(self.pid.finish()),
+            Arc::new(self.vcpu.finish()),
+            Arc::new(self.pc.finish()),
+            Arc::new(self.addr.finish()),
+            Arc::new(self.size.finish()),
+            Arc::new(self.opcode.finish()),
+            Arc::new(self.isa_mode.finish()),
+            Arc::new(self.branch.finish()),
+            Arc::new(self.is_store.finish()),
+        ]
+    }
+}
+
+/// Writes events to a Parquet file
+pub struct ParquetWriter {
+    /// The Parquet file, until it is closed
+    out: Option<ArrowWriter<File>>,
+    /// The rows of the row group being built
+    columns: Columns,
+    /// The number of events written so far
+    seq: u64,
+    /// The number of rows in `columns`
+    rows: usize,
+}
+
+impl ParquetWriter {
+    /// Create a new Parquet file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Where to create the file
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let properties = WriterProperties::builder()
+            .set_max_row_group_size(ROW_GROUP_SIZE)
+            .build();
+        let out = ArrowWriter::try_new(File::create(path)?, Columns::schema(), Some(properties))
+            .map_err(io::Error::other)?;
+
+        Ok(Self {
+            out: Some(out),
+            columns: Columns::new(),
+            seq: 0,
+            rows: 0,
+        })
+    }
+
+    /// Write the rows built so far as a row group
+    fn flush(&mut self) -> io::Result<()> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+
+        let batch = RecordBatch::try_new(Columns::schema(), self.columns.finish())
+            .map_err(io::Error::other)?;
+        self.rows = 0;
+
+        let out = self.out.as_mut().expect("Parquet file already closed");
+        out.write(&batch)
+            .and_then(|_| out.flush())
+            .map_err(io::Error::other)
+    }
+}
+
+impl EventWriter for ParquetWriter {
+    fn write(&mut self, event: &Value) -> io::Result<()> {
+        let kind = EventKind::of(event);
+        let u64_of = |event: &Value, name: &str| event.get(name).and_then(Value::as_u64);
+        let bool_of = |event: &Value, name: &str| event.get(name).and_then(Value::as_bool);
+
+        // Accesses are nested in the physical access, and instructions in the access
+        let mem = event.get("mem").unwrap_or(event);
+        let insn = mem.get("insn").unwrap_or(event);
+
+        let (pc, addr) = match kind {
+            Some(EventKind::Mem) | Some(EventKind::SysMem) => {
+                (u64_of(insn, "vaddr"), u64_of(mem, "vaddr"))
+            }
+            Some(EventKind::Edge) => (u64_of(event, "src"), u64_of(event, "dst")),
+            Some(EventKind::Call) => (u64_of(event, "callsite"), u64_of(event, "target")),
+            Some(EventKind::Return) => (u64_of(event, "site"), u64_of(event, "target")),
+            Some(EventKind::FunctionEnter) => (u64_of(event, "entry"), None),
+            Some(EventKind::FunctionExit) => (u64_of(event, "exit"), None),
+            Some(EventKind::Trigger) | Some(EventKind::Breakpoint) => (u64_of(event, "pc"), None),
+            Some(EventKind::Discon) => (u64_of(event, "from_pc"), u64_of(event, "to_pc")),
+            Some(EventKind::Signal) => (u64_of(event, "pc"), u64_of(event, "handler")),
+            Some(EventKind::Map) | Some(EventKind::Unmap) | Some(EventKind::Protect) => {
+                (None, u64_of(event, "addr"))
+            }
+            _ => (u64_of(event, "vaddr"), None),
+        };
+        let opcode = insn.get("opcode").and_then(Value::as_array).map(|bytes| {
+            bytes
+                .iter()
+                .map(|byte| byte.as_u64().unwrap_or_default() as u8)
+                .collect::<Vec<u8>>()
+        });
+
+        let columns = &mut self.columns;
+        columns.seq.append_value(self.seq);
+        columns
+            .kind
+            .append_option(kind.map(|kind| format!("{:?}", kind)));
+        columns
+            .pid
+            .append_option(u64_of(event, "pid").map(|pid| pid as u32));
+        columns
+            .vcpu
+            .append_option(u64_of(insn, "vcpu_idx").map(|vcpu| vcpu as u32));
+        columns.pc.append_option(pc);
+        columns.addr.append_option(addr);
+        columns.size.append_option(u64_of(mem, "size"));
+        columns.opcode.append_option(opcode);
+        columns
+            .isa_mode
+            .append_option(insn.get("isa_mode").and_then(Value::as_str));
+        columns.branch.append_option(bool_of(insn, "branch"));
+        columns.is_store.append_option(bool_of(mem, "is_store"));
+
+        self.seq += 1;
+        self.rows += 1;
+
+        if self.rows == ROW_GROUP_SIZE {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.out.is_none() {
+            return Ok(());
+        }
+
+        self.flush()?;
+        self.out
+            .take()
+            .expect("Parquet file already closed")
+            .close()
+            .map(|_| ())
+            .map_err(io::Error::other)
+    }
+}
+
+impl Drop for ParquetWriter {
+    fn drop(&mut self) {
+        // A Parquet file is unreadable until its footer is written
+        let _ = self.finish();
+    }
+}
@@ -0,0 +1,98 @@
+//! Tracing many programs at once
+//!
+//! Collecting coverage over a corpus means tracing the same program once per input, thousands
+//! of times. `TracePool` runs tracing sessions on a fixed number of threads, each with its own
+//! QEMU instance, so they can proceed in parallel.
+//!
+//! ```no_run
+//! use cannonball_tools::{driver::TraceOptions, TracePool, TraceSession};
+//!
+//! let sessions = ["a", "b", "c"]
+//!     .iter()
+//!     .map(|input| {
+//!         TraceSession::new("/bin/cat")
+//!             .input_file(format!("corpus/{}", input))
+//!             .options(TraceOptions {
+//!                 tbs: true,
+//!                 dedup: true,
+//!                 ..Default::default()
+//!             })
+//!     })
+//!     .collect();
+//!
+//! let results = TracePool::new(4).run(sessions, |index, session| {
+//!     let mut events = 0;
+//!     session.run(|_| events += 1).map(|_| (index, events))
+//! });
+//! ```
+
+use std::{
+    sync::Mutex,
+    thread::{available_parallelism, scope},
+};
+
+use crate::TraceSession;
+
+/// Runs tracing sessions in parallel
+pub struct TracePool {
+    /// The number of sessions run at once
+    jobs: usize,
+}
+
+impl TracePool {
+    /// Instantiate a new `TracePool`
+    ///
+    /// # Arguments
+    ///
+    /// * `jobs` - The number of sessions to run at once. If zero, one per available CPU
+    pub fn new(jobs: usize) -> Self {
+        let jobs = match jobs {
+            0 => available_parallelism().map(|n| n.get()).unwrap_or(1),
+            jobs => jobs,
+        };
+
+        Self { jobs }
+    }
+
+    /// Run every session, returning what `job` returned for each in the order the sessions
+    /// were given. Sessions start in order, but may finish in any order
+    ///
+    /// # Arguments
+    ///
+    /// * `sessions` - The sessions to run
+    /// * `job` - Runs a session, given its index in `sessions`. It is called from several
+    ///   threads at once
+    pub fn run<R: Send>(
+        &self,
+        sessions: Vec<TraceSession>,
+        job: impl Fn(usize, TraceSession) -> R + Sync,
+    ) -> Vec<R> {
+        let count = sessions.len();
+        let queue = Mutex::new(sessions.into_iter().enumerate());
+        let results = Mutex::new(Vec::with_capacity(count));
+
+        scope(|s| {
+            for _ in 0..self.jobs.min(count) {
+                s.spawn(|| loop {
+                    // Released before the session runs, so other threads can take the next
+                    let next = queue.lock().expect("Failed to lock queue").next();
+
+                    match next {
+                        Some((index, session)) => {
+                            let result = job(index, session);
+                            results
+                                .lock()
+                                .expect("Failed to lock results")
+                                .push((index, result));
+                        }
+                        None => return,
+                    }
+                });
+            }
+        });
+
+        let mut results = results.into_inner().expect("Failed to lock results");
+        results.sort_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+}
@@ -0,0 +1,283 @@
+//! Block profile
+//!
+//! A profile counts how many times each translation block executed, and how many instructions
+//! that adds up to, for a perf-like report of where an emulated program spends its time. Like
+//! coverage, it is built from translation block events, and is cheapest to collect from a
+//! trace recorded with deduplication (`dedup=on`), which logs the hit count of each block on
+//! exit. Blocks are grouped into functions with a `Symbolizer`. As an `Analyzer`, a profile
+//! loads the symbolizer of the program named in the header of the trace.
+//!
+//! ```no_run
+//! use cannonball_tools::{profile::Profile, symbols::Symbolizer, trace::events};
+//! use std::{fs::File, io::{stdout, BufReader}};
+//!
+//! let mut profile = Profile::new();
+//!
+//! for event in events(BufReader::new(File::open("ls.trace").unwrap())) {
+//!     profile.add(&event.unwrap());
+//! }
+//!
+//! let symbolizer = Symbolizer::load("/bin/ls").unwrap();
+//! profile.write(Some(&symbolizer), None, 10, stdout()).unwrap();
+//! ```
+
+use serde_json::{json, Value};
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+};
+
+use crate::{
+    analyze::{Analyzer, Report},
+    symbols::Symbolizer,
+    trace::{guest_command, EventKind},
+};
+
+/// The number of functions and blocks in the report of a profile as an `Analyzer`
+const REPORT_LIMIT: usize = 20;
+
+/// Disassembles code given its address and size, into each instruction and its address
+pub type Disassemble<'a> = dyn Fn(u64, u64) -> Vec<(u64, String)> + 'a;
+
+/// An executed translation block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    /// The size of the block in bytes
+    pub size: u64,
+    /// The number of instructions in the block
+    pub n_insns: u64,
+    /// The number of times the block executed
+    pub hits: u64,
+}
+
+impl Block {
+    /// The number of instructions the block executed
+    pub fn insns(&self) -> u64 {
+        self.n_insns * self.hits
+    }
+}
+
+/// The blocks of a function that executed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Function {
+    /// The name of the function, if the symbolizer knows it
+    pub name: Option<String>,
+    /// The number of distinct blocks that executed
+    pub blocks: u64,
+    /// The number of times its blocks executed
+    pub hits: u64,
+    /// The number of instructions its blocks executed
+    pub insns: u64,
+}
+
+/// The number of times each translation block executed, by address
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    /// Every executed block
+    pub blocks: BTreeMap<u64, Block>,
+    /// Names the functions of the report, as an `Analyzer`, if the program of the trace has a
+    /// symbol table
+    symbolizer: Option<Symbolizer>,
+}
+
+impl Profile {
+    /// Instantiate a new empty `Profile`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Account for an event. Events other than translation block events are ignored
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn add(&mut self, event: &Value) {
+        let kind = EventKind::of(event);
+
+        if !matches!(kind, Some(EventKind::TB) | Some(EventKind::BlockHits)) {
+            return;
+        }
+
+        let field = |name: &str| event.get(name).and_then(Value::as_u64);
+        let (Some(vaddr), Some(size), Some(n_insns)) =
+            (field("vaddr"), field("size"), field("n_insns"))
+        else {
+            return;
+        };
+
+        let block = self.blocks.entry(vaddr).or_insert(Block {
+            size,
+            n_insns,
+            hits: 0,
+        });
+
+        match kind {
+            // The hit count on exit already includes the first execution
+            Some(EventKind::BlockHits) => block.hits = field("hits").unwrap_or(block.hits),
+            _ => block.hits += 1,
+        }
+    }
+
+    /// The number of instructions executed by every block
+    pub fn insns(&self) -> u64 {
+        self.blocks.values().map(Block::insns).sum()
+    }
+
+    /// The blocks that executed the most instructions, hottest first
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The number of blocks
+    pub fn hottest(&self, limit: usize) -> Vec<(u64, Block)> {
+        let mut blocks = self
+            .blocks
+            .iter()
+            .map(|(vaddr, block)| (*vaddr, *block))
+            .collect::<Vec<_>>();
+        blocks.sort_by_key(|(vaddr, block)| (Reverse(block.insns()), *vaddr));
+        blocks.truncate(limit);
+        blocks
+    }
+
+    /// The blocks of each function, hottest first. Blocks outside of the functions the
+    /// symbolizer knows are grouped together, with no name
+    ///
+    /// # Arguments
+    ///
+    /// * `symbolizer` - Names the functions blocks are in. If not set, every block is grouped
+    ///   together
+    pub fn by_function(&self, symbolizer: Option<&Symbolizer>) -> Vec<Function> {
+        let mut functions = HashMap::<Option<&str>, Function>::new();
+
+        for (vaddr, block) in &self.blocks {
+            let name = symbolizer
+                .and_then(|symbolizer| symbolizer.symbolize(*vaddr))
+                .map(|(name, _)| name);
+            let function = functions.entry(name).or_insert_with(|| Function {
+                name: name.map(|name| name.to_string()),
+                ..Default::default()
+            });
+
+            function.blocks += 1;
+            function.hits += block.hits;
+            function.insns += block.insns();
+        }
+
+        let mut functions = functions.into_values().collect::<Vec<_>>();
+        functions.sort_by(|a, b| b.insns.cmp(&a.insns).then_with(|| a.name.cmp(&b.name)));
+        functions
+    }
+
+    /// Write the hottest functions, then a listing of the hottest blocks
+    ///
+    /// # Arguments
+    ///
+    /// * `symbolizer` - Names the functions blocks are in
+    /// * `disassemble` - Disassembles a block given its address and size, to list its
+    ///   instructions under it
+    /// * `limit` - The number of functions and blocks to write
+    /// * `out` - Where to write the report
+    pub fn write(
+        &self,
+        symbolizer: Option<&Symbolizer>,
+        disassemble: Option<&Disassemble<'_>>,
+        limit: usize,
+        mut out: impl Write,
+    ) -> io::Result<()> {
+        let total = self.insns();
+        let share = |insns: u64| insns as f64 * 100.0 / total.max(1) as f64;
+
+        writeln!(
+            out,
+            "Instructions: {} in {} blocks",
+            total,
+            self.blocks.len()
+        )?;
+        writeln!(
+            out,
+            "{:>7} {:>12} {:>10} {:>7}  function",
+            "insns%", "insns", "hits", "blocks"
+        )?;
+
+        for function in self.by_function(symbolizer).into_iter().take(limit) {
+            writeln!(
+                out,
+                "{:>6.2}% {:>12} {:>10} {:>7}  {}",
+                share(function.insns),
+                function.insns,
+                function.hits,
+                function.blocks,
+                function.name.as_deref().unwrap_or("??")
+            )?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "{:>7} {:>10}  block", "insns%", "hits")?;
+
+        for (vaddr, block) in self.hottest(limit) {
+            let name = match symbolizer.and_then(|symbolizer| symbolizer.symbolize(vaddr)) {
+                Some((name, offset)) => format!(" <{}+{:#x}>", name, offset),
+                None => String::new(),
+            };
+
+            writeln!(
+                out,
+                "{:>6.2}% {:>10}  {:#x}{} ({} insns, {} bytes)",
+                share(block.insns()),
+                block.hits,
+                vaddr,
+                name,
+                block.n_insns,
+                block.size
+            )?;
+
+            if let Some(disassemble) = disassemble {
+                for (addr, insn) in disassemble(vaddr, block.size) {
+                    writeln!(out, "{:>20}  {:#x}: {}", "", addr, insn)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Analyzer for Profile {
+    fn on_start(&mut self, header: Option<&Value>) {
+        // Programs without a symbol table are still profiled, just not by function
+        self.symbolizer = header
+            .and_then(guest_command)
+            .and_then(|(program, _)| Symbolizer::load(program).ok());
+    }
+
+    fn on_event(&mut self, event: &Value) {
+        self.add(event);
+    }
+
+    fn on_end(&mut self) -> Report {
+        let symbolizer = self.symbolizer.as_ref();
+        let mut text = Vec::new();
+        self.write(symbolizer, None, REPORT_LIMIT, &mut text)
+            .expect("Could not write profile");
+
+        let functions = self
+            .by_function(symbolizer)
+            .into_iter()
+            .map(|function| {
+                json!({
+                    "name": function.name,
+                    "blocks": function.blocks,
+                    "hits": function.hits,
+                    "insns": function.insns,
+                })
+            })
+            .collect();
+
+        Report::new(
+            String::from_utf8_lossy(&text),
+            json!({"insns": self.insns(), "functions": Value::Array(functions)}),
+        )
+    }
+}
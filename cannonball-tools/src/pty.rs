@@ -0,0 +1,90 @@
+//! Pseudo-terminals for traced programs
+//!
+//! Programs that check whether they run in a terminal, like shells and editors, behave
+//! differently when their stdio is a pipe. Running QEMU on the slave side of a pseudo-terminal
+//! lets them be traced interactively, while the driver reads events and output from the
+//! master side.
+
+use libc::{
+    ioctl, isatty, openpty, tcgetattr, tcsetattr, termios, winsize, ECHO, ONLCR, STDIN_FILENO,
+    TCSANOW, TIOCGWINSZ,
+};
+
+use std::{
+    fs::File,
+    io,
+    mem::MaybeUninit,
+    os::unix::io::FromRawFd,
+    ptr::{null, null_mut},
+};
+
+/// A newly allocated pseudo-terminal
+pub(crate) struct Pty {
+    /// The master side, read and written by the driver
+    pub master: File,
+    /// The slave side, the terminal of the traced program
+    pub slave: File,
+}
+
+impl Pty {
+    /// Allocate a pseudo-terminal. If stdin of this process is a terminal, the new one takes
+    /// its settings and window size
+    pub fn open() -> io::Result<Self> {
+        let mut master = 0;
+        let mut slave = 0;
+        let mut term = MaybeUninit::<termios>::uninit();
+        let mut size = MaybeUninit::<winsize>::uninit();
+
+        let (term, size) = unsafe {
+            if isatty(STDIN_FILENO) == 1
+                && tcgetattr(STDIN_FILENO, term.as_mut_ptr()) == 0
+                && ioctl(STDIN_FILENO, TIOCGWINSZ, size.as_mut_ptr()) == 0
+            {
+                (Some(term.assume_init()), Some(size.assume_init()))
+            } else {
+                (None, None)
+            }
+        };
+
+        let rv = unsafe {
+            openpty(
+                &mut master,
+                &mut slave,
+                null_mut(),
+                term.as_ref().map_or(null(), |term| term as *const termios),
+                size.as_ref().map_or(null(), |size| size as *const winsize),
+            )
+        };
+
+        if rv != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let pty = unsafe {
+            Self {
+                master: File::from_raw_fd(master),
+                slave: File::from_raw_fd(slave),
+            }
+        };
+
+        // The terminal of this process already echoes input, and events are easier to tell
+        // apart from output without carriage returns
+        unsafe {
+            let mut term = MaybeUninit::<termios>::uninit();
+
+            if tcgetattr(slave, term.as_mut_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut term = term.assume_init();
+            term.c_lflag &= !ECHO;
+            term.c_oflag &= !ONLCR;
+
+            if tcsetattr(slave, TCSANOW, &term) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(pty)
+    }
+}
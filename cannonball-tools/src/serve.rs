@@ -0,0 +1,288 @@
+//! Serving events over gRPC
+//!
+//! `EventServer` publishes the events of a trace as they are logged, over gRPC, so tools in
+//! other languages can subscribe to a live trace. The service is defined in
+//! `proto/cannonball.proto`: `Subscribe` streams every event logged from then on, optionally
+//! only of some kinds, as `Event` messages carrying the event as JSON along with its kind,
+//! position in the trace, process and VCPU.
+//!
+//! Subscribers that fall too far behind miss events rather than slowing the trace down.
+//!
+#![cfg_attr(feature = "driver", doc = "```no_run")]
+#![cfg_attr(not(feature = "driver"), doc = "```ignore")]
+//! use cannonball_tools::{serve::EventServer, TraceSession};
+//!
+//! let mut server = EventServer::start("0.0.0.0:50051".parse().unwrap()).unwrap();
+//! server.wait_for_subscriber();
+//!
+//! TraceSession::new("/bin/ls")
+//!     .run(|event| server.publish(&event))
+//!     .unwrap();
+//! ```
+
+use serde_json::Value;
+use tokio::sync::{broadcast, oneshot};
+use tokio_stream::{
+    wrappers::{BroadcastStream, TcpListenerStream},
+    StreamExt,
+};
+use tonic::{
+    body::BoxBody,
+    codec::ProstCodec,
+    codegen::{empty_body, http, Body, BoxFuture, BoxStream, Context, Poll, Service, StdError},
+    server::{Grpc, NamedService, ServerStreamingService},
+    transport::Server,
+    Request, Response, Status,
+};
+
+use std::{
+    convert::Infallible,
+    io,
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+    thread::{sleep, spawn, JoinHandle},
+    time::Duration,
+};
+
+use crate::{output::EventWriter, trace::EventKind};
+
+/// The number of events a subscriber can fall behind by before it misses some
+const CAPACITY: usize = 1 << 16;
+
+/// How often `wait_for_subscriber` checks for a subscriber
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A request to subscribe to events
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubscribeRequest {
+    /// Only receive events of these kinds, like `Syscall`. All events if empty
+    #[prost(string, repeated, tag = "1")]
+    pub kinds: Vec<String>,
+}
+
+/// An event, as sent to subscribers
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Event {
+    /// The position of the event in the trace
+    #[prost(uint64, tag = "1")]
+    pub seq: u64,
+    /// The kind of the event, like `Syscall`, or empty if unknown
+    #[prost(string, tag = "2")]
+    pub kind: String,
+    /// The process that logged the event, if events are tagged with PIDs
+    #[prost(uint32, optional, tag = "3")]
+    pub pid: Option<u32>,
+    /// The VCPU that logged the event, if known
+    #[prost(uint32, optional, tag = "4")]
+    pub vcpu: Option<u32>,
+    /// The event, as JSON
+    #[prost(string, tag = "5")]
+    pub json: String,
+}
+
+/// Where subscribers get their events from, until the trace ends
+type Events = Arc<Mutex<Option<broadcast::Sender<Event>>>>;
+
+/// The `cannonball.Events` service
+#[derive(Clone)]
+struct EventsService {
+    events: Events,
+}
+
+impl NamedService for EventsService {
+    const NAME: &'static str = "cannonball.Events";
+}
+
+/// The `Subscribe` method
+struct Subscribe {
+    events: Events,
+}
+
+impl ServerStreamingService<SubscribeRequest> for Subscribe {
+    type Response = Event;
+    type ResponseStream = BoxStream<Event>;
+    type Future = BoxFuture<Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, request: Request<SubscribeRequest>) -> Self::Future {
+        let kinds = request.into_inner().kinds;
+        let events = self
+            .events
+            .lock()
+            .expect("Failed to lock events")
+            .as_ref()
+            .map(broadcast::Sender::subscribe);
+
+        Box::pin(async move {
+            let events = events.ok_or_else(|| Status::unavailable("The trace has ended"))?;
+            let stream = BroadcastStream::new(events).filter_map(move |event| match event {
+                Ok(event) if kinds.is_empty() || kinds.contains(&event.kind) => Some(Ok(event)),
+                // Missed events are skipped
+                _ => None,
+            });
+
+            Ok(Response::new(Box::pin(stream) as Self::ResponseStream))
+        })
+    }
+}
+
+impl<B> Service<http::Request<B>> for EventsService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let events = self.events.clone();
+
+        match request.uri().path() {
+            "/cannonball.Events/Subscribe" => Box::pin(async move {
+                let mut grpc = Grpc::new(ProstCodec::default());
+                Ok(grpc.server_streaming(Subscribe { events }, request).await)
+            }),
+            _ => Box::pin(async move {
+                // gRPC reports unknown methods with a status in the headers
+                Ok(http::Response::builder()
+                    .status(200)
+                    .header("grpc-status", "12")
+                    .header("content-type", "application/grpc")
+                    .body(empty_body())
+                    .expect("Failed to build response"))
+            }),
+        }
+    }
+}
+
+/// Publishes events to gRPC subscribers
+pub struct EventServer {
+    /// The events sent to subscribers
+    events: Events,
+    /// The number of events published so far
+    seq: u64,
+    /// Stops the server
+    shutdown: Option<oneshot::Sender<()>>,
+    /// The thread running the server
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl EventServer {
+    /// Start serving events on an address, on a thread of its own
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address to listen on
+    pub fn start(addr: SocketAddr) -> io::Result<Self> {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        let events = Arc::new(Mutex::new(Some(sender)));
+        let (shutdown, stopped) = oneshot::channel::<()>();
+
+        // Bound here, so an address in use is reported to the caller
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        let service = EventsService {
+            events: events.clone(),
+        };
+
+        let thread = spawn(move || {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()?;
+
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+
+                Server::builder()
+                    .add_service(service)
+                    .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                        let _ = stopped.await;
+                    })
+                    .await
+                    .map_err(io::Error::other)
+            })
+        });
+
+        Ok(Self {
+            events,
+            seq: 0,
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        })
+    }
+
+    /// The number of subscribers
+    pub fn subscribers(&self) -> usize {
+        self.events
+            .lock()
+            .expect("Failed to lock events")
+            .as_ref()
+            .map_or(0, broadcast::Sender::receiver_count)
+    }
+
+    /// Wait until a client subscribes, so it does not miss the first events
+    pub fn wait_for_subscriber(&self) {
+        while self.subscribers() == 0 {
+            sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Send an event to every subscriber
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn publish(&mut self, event: &Value) {
+        let field = |name: &str| event.get(name).and_then(Value::as_u64).map(|v| v as u32);
+        let message = Event {
+            seq: self.seq,
+            kind: EventKind::of(event)
+                .map(|kind| format!("{:?}", kind))
+                .unwrap_or_default(),
+            pid: field("pid"),
+            vcpu: field("vcpu_idx"),
+            json: event.to_string(),
+        };
+        self.seq += 1;
+
+        if let Some(events) = self.events.lock().expect("Failed to lock events").as_ref() {
+            // Fails only when nobody is subscribed
+            let _ = events.send(message);
+        }
+    }
+}
+
+impl EventWriter for EventServer {
+    fn write(&mut self, event: &Value) -> io::Result<()> {
+        self.publish(event);
+        Ok(())
+    }
+
+    /// End the streams of every subscriber once they have received every event, and stop the
+    /// server
+    fn finish(&mut self) -> io::Result<()> {
+        self.events.lock().expect("Failed to lock events").take();
+
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| io::Error::other("The server panicked"))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for EventServer {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
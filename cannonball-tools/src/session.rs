@@ -0,0 +1,558 @@
+//! Tracing sessions
+//!
+//! `TraceSession` is the entry point for embedding cannonball in another tool: configure the
+//! program to trace and the events to log with its builder methods, then either `run` it with
+//! a callback receiving each event, or `spawn` it and iterate over its events. Either way the
+//! result carries the exit code of the program and some statistics about the trace. Sinks
+//! registered with `sink` also receive every event, each on a thread of its own (see `tee`).
+//! `metadata` describes a run of the session, for the trace files it writes (see `metadata`).
+//!
+//! ```no_run
+//! use cannonball_tools::{driver::TraceOptions, TraceSession};
+//!
+//! let result = TraceSession::new("/bin/ls")
+//!     .arg("-l")
+//!     .input(b"".to_vec())
+//!     .options(TraceOptions {
+//!         syscalls: true,
+//!         ..Default::default()
+//!     })
+//!     .run(|event| println!("{}", event))
+//!     .unwrap();
+//!
+//! println!("exited with {:?} after {} events", result.exit_code, result.stats.events);
+//! ```
+
+use serde_json::Value;
+
+use std::{
+    collections::HashMap,
+    env::temp_dir,
+    fs::{read, remove_file, write},
+    io,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, Receiver},
+    },
+    thread::{spawn, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    control::ControlHandle,
+    driver::{default_plugin, Driver, LimitOptions, Qemu, StdioOptions, TraceOptions},
+    forkserver::ForkServer,
+    metadata::{content_hash, RunMetadata},
+    output::EventWriter,
+    tee::{SinkStats, Tee},
+    trace::EventKind,
+};
+
+/// Where the plugin to trace with comes from
+enum PluginSource {
+    /// The Jaivana plugin, from `default_plugin`
+    Default,
+    /// A plugin already on disk
+    Path(PathBuf),
+    /// A plugin in memory, written to a temporary file for the duration of the session
+    Bytes(Vec<u8>),
+}
+
+/// Data fed to the program on stdin
+enum Input {
+    /// Data in memory
+    Bytes(Vec<u8>),
+    /// The contents of a file, read when the session runs
+    File(PathBuf),
+}
+
+/// The number of temporary plugin files written by this process so far
+static TEMP_PLUGINS: AtomicUsize = AtomicUsize::new(0);
+
+/// A plugin written to a temporary file, removed when dropped
+pub(crate) struct TempPlugin {
+    /// The path of the temporary file
+    path: PathBuf,
+}
+
+impl TempPlugin {
+    /// Write a plugin to a new temporary file
+    ///
+    /// # Arguments
+    ///
+    /// * `plugin` - The plugin
+    fn new(plugin: &[u8]) -> io::Result<Self> {
+        // Unique per process and per call, so concurrent sessions do not clobber each other
+        let path = temp_dir().join(format!(
+            "libcannonball-{}-{}.so",
+            process::id(),
+            TEMP_PLUGINS.fetch_add(1, Ordering::Relaxed)
+        ));
+        write(&path, plugin)?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for TempPlugin {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// Statistics about a trace
+#[derive(Debug, Clone, Default)]
+pub struct TraceStats {
+    /// The number of events
+    pub events: u64,
+    /// The number of events of each kind. Events of unknown kinds are only counted in `events`
+    pub by_kind: HashMap<EventKind, u64>,
+    /// The number of lines the program wrote to stdout
+    pub output_lines: u64,
+    /// How long the program ran for
+    pub duration: Duration,
+    /// The last statistics reported by the plugin, with `stats=on`
+    pub plugin: Option<Value>,
+    /// The instruction mix reported by the plugin, with `stats=insn_mix`
+    pub insn_mix: Option<Value>,
+    /// The signal that killed the program as logged by the plugin, with signals logged
+    pub crash: Option<Value>,
+    /// The final event the plugin logged when QEMU exited, telling how the program exited
+    pub end: Option<Value>,
+    /// How each sink of the session kept up with the trace
+    pub sinks: Vec<SinkStats>,
+}
+
+impl TraceStats {
+    /// Account for an event
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    fn add(&mut self, event: &Value) {
+        self.events += 1;
+
+        if let Some(kind) = EventKind::of(event) {
+            *self.by_kind.entry(kind).or_default() += 1;
+
+            match kind {
+                EventKind::Stats => self.plugin = Some(event.clone()),
+                EventKind::InsnMix => self.insn_mix = Some(event.clone()),
+                EventKind::Final => self.end = Some(event.clone()),
+                EventKind::Signal if event.get("fatal") == Some(&Value::Bool(true)) => {
+                    self.crash = Some(event.clone())
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The outcome of a tracing session
+#[derive(Debug, Clone)]
+pub struct TraceResult {
+    /// The exit code of the program, if it exited normally
+    pub exit_code: Option<i32>,
+    /// The signal that killed the program, if it did not exit normally. QEMU kills itself with
+    /// the signal that killed the guest
+    pub signal: Option<i32>,
+    /// Whether the program was killed for running past its timeout
+    pub timed_out: bool,
+    /// Statistics about the trace
+    pub stats: TraceStats,
+}
+
+/// A program to trace, and how to trace it
+pub struct TraceSession {
+    /// The program to trace
+    program: PathBuf,
+    /// The arguments to the program
+    args: Vec<String>,
+    /// Data fed to the program on stdin
+    input: Option<Input>,
+    /// The events to log
+    options: TraceOptions,
+    /// Arguments passed to the plugin instead of the ones built from `options`
+    plugin_args: Option<Vec<String>>,
+    /// The plugin to trace with
+    plugin: PluginSource,
+    /// The QEMU to run the program under
+    qemu: Qemu,
+    /// How the stdio of the program is connected
+    stdio: StdioOptions,
+    /// Limits on the time and resources the program may use
+    limits: LimitOptions,
+    /// Called with each line the program writes to stdout
+    on_output: Box<dyn FnMut(&str) + Send>,
+    /// The channel the plugin takes commands from while the program runs, if any
+    control: Option<ControlHandle>,
+    /// Also receive every event
+    tee: Tee,
+}
+
+impl TraceSession {
+    /// Instantiate a new `TraceSession` for a program. By default, the program takes no
+    /// arguments and no input, its output is discarded, and it is traced with the default
+    /// `TraceOptions` and the Jaivana plugin from `driver::default_plugin`
+    ///
+    /// # Arguments
+    ///
+    /// * `program` - The program to trace
+    pub fn new(program: impl AsRef<Path>) -> Self {
+        Self {
+            program: program.as_ref().to_path_buf(),
+            args: Vec::new(),
+            input: None,
+            options: TraceOptions::default(),
+            plugin_args: None,
+            plugin: PluginSource::Default,
+            qemu: Qemu::default(),
+            stdio: StdioOptions::default(),
+            limits: LimitOptions::default(),
+            on_output: Box::new(|_| {}),
+            control: None,
+            tee: Tee::new(),
+        }
+    }
+
+    /// Add an argument to the program
+    ///
+    /// # Arguments
+    ///
+    /// * `arg` - The argument
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Add arguments to the program
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - The arguments
+    pub fn args(mut self, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Feed data to the program on stdin
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - The data
+    pub fn input(mut self, input: Vec<u8>) -> Self {
+        self.input = Some(Input::Bytes(input));
+        self
+    }
+
+    /// Feed the contents of a file to the program on stdin. The file is only read when the
+    /// session runs, so many sessions can be set up without holding all of their input
+    ///
+    /// # Arguments
+    ///
+    /// * `input_file` - The file
+    pub fn input_file(mut self, input_file: impl AsRef<Path>) -> Self {
+        self.input = Some(Input::File(input_file.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Select the events to log
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - The events to log, and the part of the execution to log them in
+    pub fn options(mut self, options: TraceOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Pass these arguments to the plugin as they are, instead of the ones built from the
+    /// options. This is how a session is set up to use a plugin other than Jaivana
+    ///
+    /// # Arguments
+    ///
+    /// * `plugin_args` - The arguments, like `log_pc=true`
+    pub fn plugin_args(mut self, plugin_args: Vec<String>) -> Self {
+        self.plugin_args = Some(plugin_args);
+        self
+    }
+
+    /// Trace with the plugin at a path
+    ///
+    /// # Arguments
+    ///
+    /// * `plugin` - The path of the plugin
+    pub fn plugin(mut self, plugin: impl AsRef<Path>) -> Self {
+        self.plugin = PluginSource::Path(plugin.as_ref().to_path_buf());
+        self
+    }
+
+    /// Run the program under another QEMU than the default, like one installed on the system
+    ///
+    /// # Arguments
+    ///
+    /// * `qemu` - The QEMU
+    pub fn qemu(mut self, qemu: Qemu) -> Self {
+        self.qemu = qemu;
+        self
+    }
+
+    /// Trace with a plugin held in memory, for example one embedded with `include_bytes!`. It
+    /// is written to a temporary file for the duration of the session
+    ///
+    /// # Arguments
+    ///
+    /// * `plugin` - The plugin
+    pub fn plugin_bytes(mut self, plugin: Vec<u8>) -> Self {
+        self.plugin = PluginSource::Bytes(plugin);
+        self
+    }
+
+    /// Connect the stdio of the program differently, for example to run it in a terminal
+    ///
+    /// # Arguments
+    ///
+    /// * `stdio` - How the stdio of the program is connected
+    pub fn stdio(mut self, stdio: StdioOptions) -> Self {
+        self.stdio = stdio;
+        self
+    }
+
+    /// Limit the time and resources the program may use
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - The limits
+    pub fn limits(mut self, limits: LimitOptions) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Handle the output of the program
+    ///
+    /// # Arguments
+    ///
+    /// * `on_output` - Called with each line the program writes to stdout
+    pub fn on_output(mut self, on_output: impl FnMut(&str) + Send + 'static) -> Self {
+        self.on_output = Box::new(on_output);
+        self
+    }
+
+    /// Also hand every event to a function, on a thread of its own. The session waits for it
+    /// once it falls `tee::DEFAULT_CAPACITY` events behind, and it stops at the first error it
+    /// returns
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name to report the statistics of the sink under
+    /// * `sink` - The function
+    pub fn sink(
+        mut self,
+        name: impl Into<String>,
+        sink: impl FnMut(&Value) -> io::Result<()> + Send + 'static,
+    ) -> Self {
+        self.tee.add(name, sink);
+        self
+    }
+
+    /// Also write every event with a writer, on a thread of its own, like to a trace file in
+    /// another format. The writer is finished once the program exits
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name to report the statistics of the sink under
+    /// * `writer` - The writer
+    pub fn sink_writer(
+        mut self,
+        name: impl Into<String>,
+        writer: Box<dyn EventWriter + Send>,
+    ) -> Self {
+        self.tee.add_writer(name, writer);
+        self
+    }
+
+    /// The program to trace
+    pub(crate) fn program(&self) -> &Path {
+        &self.program
+    }
+
+    /// The metadata of a run of the session starting now, to write to the trace files of the
+    /// run with `OutputFormat::create_with_metadata`. This reads the input and the plugin to
+    /// hash them
+    pub fn metadata(&self) -> io::Result<RunMetadata> {
+        let plugin_hash = match &self.plugin {
+            PluginSource::Default => content_hash(&read(default_plugin()?)?),
+            PluginSource::Path(path) => content_hash(&read(path)?),
+            PluginSource::Bytes(bytes) => content_hash(bytes),
+        };
+        let (input_file, input_hash) = match &self.input {
+            Some(Input::Bytes(input)) => (None, Some(content_hash(input))),
+            Some(Input::File(input_file)) => (
+                Some(input_file.to_string_lossy().to_string()),
+                Some(content_hash(&read(input_file)?)),
+            ),
+            None => (None, None),
+        };
+
+        Ok(RunMetadata::new(
+            self.program.to_string_lossy().to_string(),
+            self.args.clone(),
+            self.plugin_args
+                .clone()
+                .unwrap_or_else(|| self.options.plugin_args(&self.program)),
+            input_file,
+            input_hash,
+            Some(plugin_hash),
+            self.qemu.version(),
+        ))
+    }
+
+    /// Run the program to completion
+    ///
+    /// # Arguments
+    ///
+    /// * `on_event` - Called with each event, in order
+    pub fn run(mut self, mut on_event: impl FnMut(Value)) -> io::Result<TraceResult> {
+        // Kept alive until the program exits
+        let (driver, _temp) = self.driver(Vec::new())?;
+        let input = match self.input.take() {
+            Some(Input::Bytes(input)) => Some(input),
+            Some(Input::File(input_file)) => Some(read(input_file)?),
+            None => None,
+        };
+
+        let mut stats = TraceStats::default();
+        let mut output_lines = 0;
+        let on_output = &mut self.on_output;
+        let tee = &mut self.tee;
+        let start = Instant::now();
+
+        let exit = driver.run(
+            &self.program,
+            &self.args,
+            input,
+            |event| {
+                stats.add(&event);
+                tee.send(&event);
+                on_event(event);
+            },
+            |line| {
+                output_lines += 1;
+                on_output(line);
+            },
+        )?;
+
+        stats.output_lines = output_lines;
+        stats.duration = start.elapsed();
+        stats.sinks = std::mem::take(&mut self.tee).finish();
+
+        Ok(TraceResult {
+            exit_code: exit.code,
+            signal: exit.signal,
+            timed_out: exit.timed_out,
+            stats,
+        })
+    }
+
+    /// A handle to change the events logged while the program runs, from another thread.
+    /// This needs the Jaivana plugin. Every call returns a handle to the same channel
+    pub fn control(&mut self) -> io::Result<ControlHandle> {
+        if self.control.is_none() {
+            self.control = Some(ControlHandle::listen()?);
+        }
+
+        Ok(self.control.clone().expect("No control channel"))
+    }
+
+    /// Run the program up to an instruction once, and fork a run from there for each input
+    /// afterwards. This needs the Jaivana plugin. The input, output and sinks of the session
+    /// are not used, since each run gets its own input
+    ///
+    /// # Arguments
+    ///
+    /// * `pc` - The address of the instruction to fork runs from. The program must not read
+    ///   its input before it
+    pub fn fork_server(mut self, pc: u64) -> io::Result<ForkServer> {
+        let socket = ForkServer::socket_path();
+        let (driver, temp) = self.driver(vec![
+            format!("forkserver={}", socket.to_string_lossy()),
+            format!("forkserver_pc={:#x}", pc),
+        ])?;
+
+        ForkServer::start(driver, self.program, self.args, socket, temp)
+    }
+
+    /// The driver running the program, and the temporary plugin file it uses, if any, which
+    /// must be kept alive as long as the driver is used
+    ///
+    /// # Arguments
+    ///
+    /// * `extra_args` - Arguments to pass to the plugin besides those of the session
+    fn driver(&mut self, extra_args: Vec<String>) -> io::Result<(Driver, Option<TempPlugin>)> {
+        let (plugin, temp) = match &self.plugin {
+            PluginSource::Default => (default_plugin()?, None),
+            PluginSource::Path(path) => (path.clone(), None),
+            PluginSource::Bytes(bytes) => {
+                let temp = TempPlugin::new(bytes)?;
+                (temp.path.clone(), Some(temp))
+            }
+        };
+        let mut plugin_args = match self.plugin_args.take() {
+            Some(plugin_args) => plugin_args,
+            None => self.options.plugin_args(&self.program),
+        };
+        plugin_args.extend(extra_args);
+
+        if let Some(control) = &self.control {
+            plugin_args.push(format!("control={}", control.path().to_string_lossy()));
+        }
+
+        let driver = Driver::new(plugin, plugin_args)
+            .qemu(self.qemu.clone())
+            .stdio(self.stdio.clone())
+            .limits(self.limits.clone());
+
+        Ok((driver, temp))
+    }
+
+    /// Start the program on another thread, and iterate over its events as they come
+    pub fn spawn(self) -> Events {
+        let (sender, receiver) = channel();
+        // Sending fails once the receiving `Events` is dropped, and the rest of the events
+        // are then discarded
+        let handle = spawn(move || {
+            self.run(|event| {
+                let _ = sender.send(event);
+            })
+        });
+
+        Events { receiver, handle }
+    }
+}
+
+/// The events of a session started with `TraceSession::spawn`
+pub struct Events {
+    /// Receives the events from the thread running the session
+    receiver: Receiver<Value>,
+    /// The thread running the session
+    handle: JoinHandle<io::Result<TraceResult>>,
+}
+
+impl Iterator for Events {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl Events {
+    /// Wait for the program to exit, discarding any events that were not consumed
+    pub fn wait(self) -> io::Result<TraceResult> {
+        drop(self.receiver);
+        self.handle
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("Session panicked")))
+    }
+}
@@ -0,0 +1,176 @@
+//! Splitting traces per process
+//!
+//! In user mode, QEMU forks along with the program it runs, and the plugin in the child keeps
+//! writing to the same stdout or sink as its parent, so the events of every process end up in
+//! one trace. Traced with `tag_pids=on`, every event carries the `pid` of the process that
+//! logged it, and a `PidSplitter` writes the events of each process to a file of its own, named
+//! after the trace with the PID appended, like `ls.trace.1234`.
+//!
+//! The header of the trace starts the file of every process, so each reads back on its own.
+//! An event without a `pid` is written to the file of the first process of the trace.
+//!
+//! ```no_run
+//! use cannonball_tools::{
+//!     output::{EventWriter, OutputFormat},
+//!     split::PidSplitter,
+//!     trace::TraceReader,
+//! };
+//!
+//! use std::{fs::File, io::BufReader};
+//!
+//! let reader = TraceReader::new(
+//!     BufReader::new(File::open("sh.trace").unwrap()),
+//!     OutputFormat::Json,
+//! )
+//! .unwrap();
+//! let mut splitter = PidSplitter::new("sh.trace", OutputFormat::Json, None);
+//!
+//! for event in reader {
+//!     splitter.write(&event.unwrap()).unwrap();
+//! }
+//! splitter.finish().unwrap();
+//!
+//! for pid in splitter.pids() {
+//!     println!("{}", PidSplitter::path_of("sh.trace", pid).display());
+//! }
+//! ```
+
+use serde_json::Value;
+
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    metadata::RunMetadata,
+    output::{EventWriter, OutputFormat},
+    trace::EventKind,
+};
+
+/// The field the plugin logs the PID of the process that logged an event in, with
+/// `tag_pids=on`
+pub const PID: &str = "pid";
+
+/// The PID of the process that logged an event, if the event was tagged with it
+///
+/// # Arguments
+///
+/// * `event` - The event
+pub fn pid(event: &Value) -> Option<u32> {
+    event
+        .get(PID)
+        .and_then(Value::as_u64)
+        .and_then(|pid| pid.try_into().ok())
+}
+
+/// Writes the events of each process of a trace to a file of its own
+pub struct PidSplitter {
+    /// The path of the trace, which the PIDs are appended to
+    path: PathBuf,
+    /// The format to write the files in
+    format: OutputFormat,
+    /// The run the trace was recorded for, written at the start of every file
+    metadata: Option<RunMetadata>,
+    /// The header of the trace, once it is seen
+    header: Option<Value>,
+    /// The first process of the trace, whose file gets the events without a PID
+    first: Option<u32>,
+    /// The file of each process, by PID
+    writers: BTreeMap<u32, Box<dyn EventWriter + Send>>,
+}
+
+impl PidSplitter {
+    /// Instantiate a new `PidSplitter`. Files are created when the first event of their
+    /// process is written
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the trace. The file of each process is named after it
+    /// * `format` - The format to write the files in
+    /// * `metadata` - The run the trace was recorded for, if it is known
+    pub fn new(
+        path: impl AsRef<Path>,
+        format: OutputFormat,
+        metadata: Option<&RunMetadata>,
+    ) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            format,
+            metadata: metadata.cloned(),
+            header: None,
+            first: None,
+            writers: BTreeMap::new(),
+        }
+    }
+
+    /// The path of the file of a process
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the trace
+    /// * `pid` - The PID of the process
+    pub fn path_of(path: impl AsRef<Path>, pid: u32) -> PathBuf {
+        let mut name = path.as_ref().as_os_str().to_owned();
+        name.push(format!(".{}", pid));
+        PathBuf::from(name)
+    }
+
+    /// The PIDs of the processes written so far, in increasing order
+    pub fn pids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.writers.keys().copied()
+    }
+
+    /// The file of a process, created along with its header if it is new
+    ///
+    /// # Arguments
+    ///
+    /// * `pid` - The PID of the process
+    fn writer(&mut self, pid: u32) -> io::Result<&mut Box<dyn EventWriter + Send>> {
+        if !self.writers.contains_key(&pid) {
+            let path = Self::path_of(&self.path, pid);
+            let mut writer = match &self.metadata {
+                Some(metadata) => self.format.create_with_metadata(path, metadata)?,
+                None => self.format.create(path)?,
+            };
+
+            if let Some(header) = &self.header {
+                writer.write(header)?;
+            }
+
+            self.writers.insert(pid, writer);
+        }
+
+        Ok(self.writers.get_mut(&pid).expect("Writer was just created"))
+    }
+}
+
+impl EventWriter for PidSplitter {
+    fn write(&mut self, event: &Value) -> io::Result<()> {
+        let pid = pid(event).or(self.first).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Events are not tagged with the PID of their process, which needs tag_pids=on",
+            )
+        })?;
+        self.first.get_or_insert(pid);
+
+        if EventKind::of(event) == Some(EventKind::Header) && self.header.is_none() {
+            // Written by `writer` to the files of the processes after this one
+            self.writer(pid)?.write(event)?;
+            self.header = Some(event.clone());
+            return Ok(());
+        }
+
+        self.writer(pid)?.write(event)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        for writer in self.writers.values_mut() {
+            writer.finish()?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+//! SQLite trace storage
+//!
+//! Traces too large to grep are easier to query with SQL. `SqliteWriter` stores a trace in a
+//! SQLite database, with the events that are most often queried in tables of their own:
+//!
+//! * `events(seq, kind, pid, vcpu, json)`: every event, in order
+//! * `insns(seq, pid, vcpu, pc, opcode, branch)`: instruction events
+//! * `mem(seq, pid, vcpu, pc, vaddr, size, is_store, value)`: memory accesses
+//! * `blocks(seq, pid, vcpu, pc, size, n_insns)`: translation block events
+//! * `syscalls(seq, pid, vcpu, num, rv, args)`: system calls, with their arguments as JSON
+//!
+//! `seq` is the position of the event in the trace, and the other tables are indexed by PC
+//! (and `mem` by address too). SQLite integers are signed, so addresses and values of 2^63 and
+//! above are stored as negative numbers. `printf('%x', pc)` shows them as expected.
+//!
+#![cfg_attr(feature = "driver", doc = "```no_run")]
+#![cfg_attr(not(feature = "driver"), doc = "```ignore")]
+//! use cannonball_tools::{driver::TraceOptions, output::EventWriter, sqlite::SqliteWriter, TraceSession};
+//!
+//! let mut writer = SqliteWriter::create("ls.db").unwrap();
+//!
+//! TraceSession::new("/bin/ls")
+//!     .options(TraceOptions {
+//!         insns: true,
+//!         ..Default::default()
+//!     })
+//!     .run(|event| writer.write(&event).unwrap())
+//!     .unwrap();
+//!
+//! writer.finish().unwrap();
+//! ```
+
+use rusqlite::{params, Connection};
+use serde_json::Value;
+
+use std::{fs::remove_file, io, path::Path};
+
+use crate::{output::EventWriter, trace::EventKind};
+
+/// The number of events inserted in each transaction
+const TRANSACTION_SIZE: u64 = 100_000;
+
+/// The tables and indexes of a trace database
+const SCHEMA: &str = "
+    CREATE TABLE events (seq INTEGER PRIMARY KEY, kind TEXT, pid INTEGER, vcpu INTEGER, json TEXT NOT NULL);
+    CREATE TABLE insns (seq INTEGER PRIMARY KEY, pid INTEGER, vcpu INTEGER, pc INTEGER NOT NULL, opcode BLOB, branch INTEGER);
+    CREATE TABLE mem (seq INTEGER PRIMARY KEY, pid INTEGER, vcpu INTEGER, pc INTEGER, vaddr INTEGER NOT NULL, size INTEGER, is_store INTEGER, value INTEGER);
+    CREATE TABLE blocks (seq INTEGER PRIMARY KEY, pid INTEGER, vcpu INTEGER, pc INTEGER NOT NULL, size INTEGER, n_insns INTEGER);
+    CREATE TABLE syscalls (seq INTEGER PRIMARY KEY, pid INTEGER, vcpu INTEGER, num INTEGER NOT NULL, rv INTEGER, args TEXT);
+    CREATE INDEX insns_pc ON insns (pc);
+    CREATE INDEX mem_pc ON mem (pc);
+    CREATE INDEX mem_vaddr ON mem (vaddr);
+    CREATE INDEX blocks_pc ON blocks (pc);
+    CREATE INDEX syscalls_num ON syscalls (num);
+";
+
+/// Writes events to a SQLite database
+pub struct SqliteWriter {
+    /// The database
+    db: Connection,
+    /// The number of events written so far
+    seq: u64,
+    /// Whether the database has been committed
+    finished: bool,
+}
+
+impl SqliteWriter {
+    /// Create a new trace database, replacing any file at `path`
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Where to create the database
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let _ = remove_file(&path);
+        let db = Connection::open(path).map_err(io::Error::other)?;
+
+        // Nothing else reads the database while it is written, and a trace that was cut short
+        // would be of no use anyway
+        db.execute_batch("PRAGMA journal_mode = OFF; PRAGMA synchronous = OFF;")
+            .and_then(|_| db.execute_batch(SCHEMA))
+            .and_then(|_| db.execute_batch("BEGIN"))
+            .map_err(io::Error::other)?;
+
+        Ok(Self {
+            db,
+            seq: 0,
+            finished: false,
+        })
+    }
+
+    /// Insert an event in the tables it belongs in
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    fn insert(&mut self, event: &Value) -> rusqlite::Result<()> {
+        let seq = self.seq as i64;
+        let kind = EventKind::of(event);
+        let int = |event: &Value, name: &str| {
+            event
+                .get(name)
+                .and_then(|value| value.as_i64().or(value.as_u64().map(|value| value as i64)))
+        };
+        let pid = int(event, "pid");
+        let vcpu = int(event, "vcpu_idx");
+
+        self.db
+            .prepare_cached("INSERT INTO events VALUES (?1, ?2, ?3, ?4, ?5)")?
+            .execute(params![
+                seq,
+                kind.map(|kind| format!("{:?}", kind)),
+                pid,
+                vcpu,
+                event.to_string()
+            ])?;
+
+        match kind {
+            Some(EventKind::Insn) => {
+                let opcode = event.get("opcode").and_then(Value::as_array).map(|bytes| {
+                    bytes
+                        .iter()
+                        .map(|byte| byte.as_u64().unwrap_or_default() as u8)
+                        .collect::<Vec<u8>>()
+                });
+
+                self.db
+                    .prepare_cached("INSERT INTO insns VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?
+                    .execute(params![
+                        seq,
+                        pid,
+                        vcpu,
+                        int(event, "vaddr"),
+                        opcode,
+                        event.get("branch").and_then(Value::as_bool)
+                    ])?;
+            }
+            Some(EventKind::Mem) | Some(EventKind::SysMem) => {
+                // Physical accesses wrap the virtual access
+                let mem = event.get("mem").unwrap_or(event);
+                let insn = mem.get("insn").unwrap_or(&Value::Null);
+
+                self.db
+                    .prepare_cached("INSERT INTO mem VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)")?
+                    .execute(params![
+                        seq,
+                        pid,
+                        int(insn, "vcpu_idx"),
+                        int(insn, "vaddr"),
+                        int(mem, "vaddr"),
+                        int(mem, "size"),
+                        mem.get("is_store").and_then(Value::as_bool),
+                        int(mem, "value")
+                    ])?;
+            }
+            Some(EventKind::TB) => {
+                self.db
+                    .prepare_cached("INSERT INTO blocks VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?
+                    .execute(params![
+                        seq,
+                        pid,
+                        vcpu,
+                        int(event, "vaddr"),
+                        int(event, "size"),
+                        int(event, "n_insns")
+                    ])?;
+            }
+            Some(EventKind::Syscall) => {
+                self.db
+                    .prepare_cached("INSERT INTO syscalls VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?
+                    .execute(params![
+                        seq,
+                        pid,
+                        vcpu,
+                        int(event, "num"),
+                        int(event, "rv"),
+                        event.get("args").map(Value::to_string)
+                    ])?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl EventWriter for SqliteWriter {
+    fn write(&mut self, event: &Value) -> io::Result<()> {
+        self.insert(event).map_err(io::Error::other)?;
+        self.seq += 1;
+
+        if self.seq.is_multiple_of(TRANSACTION_SIZE) {
+            self.db
+                .execute_batch("COMMIT; BEGIN")
+                .map_err(io::Error::other)?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.finished {
+            self.finished = true;
+            self.db.execute_batch("COMMIT").map_err(io::Error::other)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for SqliteWriter {
+    fn drop(&mut self) {
+        // Like `ChromeWriter`, a writer that was not finished is finished when dropped
+        let _ = self.finish();
+    }
+}
@@ -0,0 +1,118 @@
+//! System call listing
+//!
+//! Formats the system call events of a trace one per line, in the spirit of `strace`. System
+//! calls are listed by number, with every argument in hex, truncated to the width of the
+//! addresses of the guest so those of 32-bit guests are not shown sign extended. The listing
+//! ends like `strace`'s, with how the guest exited, like `+++ exited with 0 after 1234 instructions +++`. `Strace`
+//! collects the listing as an `Analyzer`, reading that width from the header of the trace.
+
+use serde_json::Value;
+
+use crate::{
+    analyze::{Analyzer, Report},
+    trace::{address_bits, exit_status, truncate_address, EventKind},
+};
+
+/// Format a system call, fork, exec, or final event. Other events are not formatted
+///
+/// # Arguments
+///
+/// * `event` - The event
+/// * `address_bits` - The width of the addresses of the guest, see `trace::address_bits`
+pub fn format(event: &Value, address_bits: u32) -> Option<String> {
+    let field = |name: &str| event.get(name).and_then(Value::as_u64);
+    let pid = match event.get("pid").and_then(Value::as_u64) {
+        Some(pid) => format!("[pid {}] ", pid),
+        None => String::new(),
+    };
+
+    match EventKind::of(event)? {
+        EventKind::Syscall => {
+            let args = event
+                .get("args")?
+                .as_array()?
+                .iter()
+                .map(|arg| {
+                    let arg = arg.as_u64().unwrap_or_default();
+                    format!("{:#x}", truncate_address(arg, address_bits))
+                })
+                .collect::<Vec<String>>()
+                .join(", ");
+            let rv = match event.get("rv").and_then(Value::as_i64) {
+                Some(rv) => rv.to_string(),
+                None => "?".to_string(),
+            };
+
+            Some(format!(
+                "{}syscall_{}({}) = {}",
+                pid,
+                event.get("num")?.as_i64()?,
+                args,
+                rv
+            ))
+        }
+        EventKind::Fork => Some(format!("{}+++ forked from {} +++", pid, field("parent")?)),
+        EventKind::Exec => Some(format!(
+            "{}+++ exec of the image at {:#x} +++",
+            pid,
+            truncate_address(field("pathname")?, address_bits)
+        )),
+        EventKind::Final => Some(format!(
+            "{}+++ {} after {} instructions +++",
+            pid,
+            exit_status(event).unwrap_or_else(|| "exited".to_string()),
+            field("insns")?
+        )),
+        _ => None,
+    }
+}
+
+/// The system calls of a trace, formatted
+#[derive(Debug, Clone)]
+pub struct Strace {
+    /// The system calls, forks and execs, one line each
+    pub lines: Vec<String>,
+    /// The width of the addresses of the guest, once the header is read
+    address_bits: u32,
+}
+
+impl Strace {
+    /// Instantiate a new empty `Strace`
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for Strace {
+    fn default() -> Self {
+        Self {
+            lines: Vec::new(),
+            address_bits: 64,
+        }
+    }
+}
+
+impl Analyzer for Strace {
+    fn on_event(&mut self, event: &Value) {
+        if EventKind::of(event) == Some(EventKind::Header) {
+            self.address_bits = address_bits(event);
+        }
+
+        if let Some(line) = format(event, self.address_bits) {
+            self.lines.push(line);
+        }
+    }
+
+    fn on_end(&mut self) -> Report {
+        let text = self
+            .lines
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect::<String>();
+
+        Report::new(
+            text,
+            Value::Array(self.lines.iter().cloned().map(Value::String).collect()),
+        )
+    }
+}
@@ -0,0 +1,107 @@
+//! Symbolizing addresses
+//!
+//! Traces log addresses, not names. `Symbolizer` names the function an address is in from the
+//! symbol table of the traced program. Position independent executables are loaded at an
+//! address of QEMU's choosing, which the symbol table does not know about, so it must be given
+//! with `with_bias` to symbolize their addresses.
+//!
+//! ```no_run
+//! use cannonball_tools::symbols::Symbolizer;
+//!
+//! let symbolizer = Symbolizer::load("/bin/ls").unwrap();
+//!
+//! if let Some((name, offset)) = symbolizer.symbolize(0x401000) {
+//!     println!("{}+{:#x}", name, offset);
+//! }
+//! ```
+
+use goblin::elf::Elf;
+
+use std::{fs::read, io, path::Path};
+
+/// A function from the symbol table of a program
+#[derive(Debug, Clone)]
+struct Function {
+    /// The name of the function
+    name: String,
+    /// The address of the first instruction of the function, before relocation
+    start: u64,
+    /// The size of the function in bytes, or 0 if unknown
+    size: u64,
+}
+
+/// Names the functions addresses are in
+#[derive(Debug, Clone)]
+pub struct Symbolizer {
+    /// Functions, sorted by start address
+    functions: Vec<Function>,
+    /// The address the program was loaded at, relative to its symbol table
+    bias: u64,
+}
+
+impl Symbolizer {
+    /// Load the function symbols of an ELF program
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The program
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&read(path)?)
+    }
+
+    /// Read the function symbols of an ELF program already in memory
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The program
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        let elf = Elf::parse(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut functions: Vec<Function> = elf
+            .syms
+            .iter()
+            .filter(|sym| sym.is_function() && sym.st_value != 0)
+            .filter_map(|sym| {
+                Some(Function {
+                    name: elf.strtab.get_at(sym.st_name)?.to_string(),
+                    start: sym.st_value,
+                    size: sym.st_size,
+                })
+            })
+            .collect();
+
+        functions.sort_by_key(|f| f.start);
+        functions.dedup_by_key(|f| f.start);
+
+        Ok(Self { functions, bias: 0 })
+    }
+
+    /// Symbolize addresses of the program loaded at `bias`
+    ///
+    /// # Arguments
+    ///
+    /// * `bias` - The address the program was loaded at, relative to its symbol table
+    pub fn with_bias(mut self, bias: u64) -> Self {
+        self.bias = bias;
+        self
+    }
+
+    /// The function an address is in, and the offset of the address in it
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address
+    pub fn symbolize(&self, addr: u64) -> Option<(&str, u64)> {
+        let addr = addr.checked_sub(self.bias)?;
+        let index = self.functions.partition_point(|f| f.start <= addr);
+        let function = &self.functions[index.checked_sub(1)?];
+        let offset = addr - function.start;
+
+        // Functions of unknown size are assumed to run up to the next one
+        if function.size == 0 || offset < function.size {
+            Some((&function.name, offset))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Fanning out events
+//!
+//! A trace is often wanted in more than one place at once, like written to a file while an
+//! analysis runs over it live. A `Tee` hands every event to several sinks, each running on a
+//! thread of its own behind a bounded queue, so a slow sink, like one compressing to disk, does
+//! not hold up the others until its queue fills. Once it does, the session waits for it, which
+//! is its backpressure on the trace, and the wait is accounted in the `SinkStats` of the sink.
+//!
+//! Sinks are usually registered on a `TraceSession`, which reports their statistics along with
+//! its own:
+//!
+#![cfg_attr(feature = "driver", doc = "```no_run")]
+#![cfg_attr(not(feature = "driver"), doc = "```ignore")]
+//! use cannonball_tools::{cover::Coverage, output::OutputFormat, TraceSession};
+//!
+//! let mut coverage = Coverage::new();
+//! let result = TraceSession::new("/bin/ls")
+//!     .sink_writer("ls.trace", OutputFormat::Binary.create("ls.trace").unwrap())
+//!     .run(|event| coverage.add(&event))
+//!     .unwrap();
+//!
+//! for sink in &result.stats.sinks {
+//!     println!("{}: waited {:?} for {} events", sink.name, sink.stalled, sink.events);
+//! }
+//! ```
+//!
+//! Events are shared by the sinks rather than copied for each of them.
+
+use serde_json::Value;
+
+use std::{
+    io,
+    sync::{
+        mpsc::{sync_channel, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::{spawn, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::output::EventWriter;
+
+/// The number of events a sink queues by default before the trace waits for it
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+/// How a sink kept up with the trace
+#[derive(Debug, Clone, Default)]
+pub struct SinkStats {
+    /// The name the sink was registered with
+    pub name: String,
+    /// The number of events handed to the sink
+    pub events: u64,
+    /// The number of times the queue of the sink was full, and the trace waited for it
+    pub stalls: u64,
+    /// How long the trace waited for the sink in total
+    pub stalled: Duration,
+    /// The number of events the sink did not take because it had stopped
+    pub dropped: u64,
+    /// Why the sink stopped before the end of the trace, if it did
+    pub error: Option<String>,
+}
+
+/// Adapts a function taking events to an `EventWriter`
+struct FnWriter<F>(F);
+
+impl<F: FnMut(&Value) -> io::Result<()>> EventWriter for FnWriter<F> {
+    fn write(&mut self, event: &Value) -> io::Result<()> {
+        (self.0)(event)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A sink running on its own thread
+struct Sink {
+    /// Queues events for the thread, until the sink is finished
+    sender: SyncSender<Arc<Value>>,
+    /// The thread, which returns an error if the sink stopped early
+    thread: JoinHandle<io::Result<()>>,
+    /// How the sink kept up so far
+    stats: SinkStats,
+}
+
+/// Hands every event to several sinks
+pub struct Tee {
+    /// The sinks, in the order they were added
+    sinks: Vec<Sink>,
+    /// The number of events each sink queues
+    capacity: usize,
+}
+
+impl Default for Tee {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tee {
+    /// Instantiate a new `Tee` without sinks, which queues `DEFAULT_CAPACITY` events per sink
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    /// Instantiate a new `Tee` without sinks
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - The number of events each sink added from now on queues before the
+    ///   trace waits for it
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            sinks: Vec::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Whether there are no sinks
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    /// Add a sink calling a function with each event. The sink stops at the first error the
+    /// function returns
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name to report the statistics of the sink under
+    /// * `sink` - The function
+    pub fn add(
+        &mut self,
+        name: impl Into<String>,
+        sink: impl FnMut(&Value) -> io::Result<()> + Send + 'static,
+    ) {
+        self.add_writer(name, Box::new(FnWriter(sink)));
+    }
+
+    /// Add a sink writing each event with a writer, which is finished at the end of the trace
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name to report the statistics of the sink under
+    /// * `writer` - The writer, like one from `OutputFormat::create`
+    pub fn add_writer(&mut self, name: impl Into<String>, mut writer: Box<dyn EventWriter + Send>) {
+        let (sender, receiver) = sync_channel::<Arc<Value>>(self.capacity);
+        // Returning drops the receiver, so the trace stops queueing events for the sink
+        let thread = spawn(move || {
+            for event in receiver {
+                writer.write(&event)?;
+            }
+
+            writer.finish()
+        });
+
+        self.sinks.push(Sink {
+            sender,
+            thread,
+            stats: SinkStats {
+                name: name.into(),
+                ..Default::default()
+            },
+        });
+    }
+
+    /// Hand an event to every sink, waiting for those whose queue is full
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn send(&mut self, event: &Value) {
+        if self.sinks.is_empty() {
+            return;
+        }
+
+        let event = Arc::new(event.clone());
+
+        for sink in &mut self.sinks {
+            let stats = &mut sink.stats;
+
+            let sent = match sink.sender.try_send(event.clone()) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(event)) => {
+                    let start = Instant::now();
+                    let sent = sink.sender.send(event).map_err(|_| ());
+                    stats.stalls += 1;
+                    stats.stalled += start.elapsed();
+                    sent
+                }
+                Err(TrySendError::Disconnected(_)) => Err(()),
+            };
+
+            match sent {
+                Ok(()) => stats.events += 1,
+                Err(()) => stats.dropped += 1,
+            }
+        }
+    }
+
+    /// Wait for every sink to handle the events queued for it, and finish them
+    pub fn finish(self) -> Vec<SinkStats> {
+        self.sinks
+            .into_iter()
+            .map(|sink| {
+                let mut stats = sink.stats;
+                drop(sink.sender);
+
+                stats.error = match sink.thread.join() {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => Some(e.to_string()),
+                    Err(_) => Some("Sink panicked".to_string()),
+                };
+
+                stats
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,541 @@
+//! Reading traces
+//!
+//! A trace is newline-delimited JSON, one event per line, as written by the Jaivana plugin.
+//! Events do not carry their type, so `EventKind::of` tells them apart by their fields.
+//!
+//! Traces written in another format by `output` are read back with `TraceReader`, which also
+//! reads traces compressed with gzip, and compact traces whatever format it is given:
+//!
+//! ```no_run
+//! use cannonball_tools::{output::OutputFormat, trace::TraceReader};
+//!
+//! for event in TraceReader::open("ls.bin.gz", OutputFormat::Binary).unwrap() {
+//!     println!("{}", event.unwrap());
+//! }
+//! ```
+//!
+//! Trace files written for a run start with the metadata of the run, which is not an event:
+//! `TraceReader` hands it back with `metadata` instead, and `events` skips it.
+//!
+//! The plugin writes events to a set of rotated files with `sink=file:<path>` and
+//! `sink_rotate_size=N`, `<path>.0`, `<path>.1` and so on, which `TraceReader::open_rotated`
+//! reads back in order as one trace.
+
+use flate2::bufread::MultiGzDecoder;
+use serde::Deserialize;
+use serde_json::{from_str, Value};
+
+use std::{
+    fs::{read_dir, File},
+    io::{self, copy, sink, BufRead, BufReader, ErrorKind, Read},
+    path::{Path, PathBuf},
+    vec,
+};
+
+use crate::{
+    compact::{self, Deltas, Repeats},
+    metadata::{self, RunMetadata},
+    output::OutputFormat,
+};
+
+/// The first bytes of a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The kinds of events logged by the Jaivana plugin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Header,
+    Final,
+    RunEnd,
+    Stats,
+    InsnMix,
+    Fork,
+    Exec,
+    Trigger,
+    Breakpoint,
+    Discon,
+    Vcpu,
+    Signal,
+    Map,
+    Unmap,
+    Protect,
+    Insn,
+    TB,
+    BlockHits,
+    Edge,
+    Call,
+    Return,
+    FunctionEnter,
+    FunctionExit,
+    Mem,
+    SysMem,
+    Syscall,
+}
+
+impl EventKind {
+    /// The kind of an event, if it is one logged by the Jaivana plugin
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn of(event: &Value) -> Option<Self> {
+        let has = |field: &str| event.get(field).is_some();
+
+        // Checked so that no kind is mistaken for one whose fields are a subset of its own
+        if has("plugin_api_version") {
+            Some(Self::Header)
+        } else if has("signaled") {
+            Some(Self::Final)
+        } else if has("run") {
+            Some(Self::RunEnd)
+        } else if has("high_water") {
+            Some(Self::Stats)
+        } else if has("classes") {
+            Some(Self::InsnMix)
+        } else if has("parent") && has("child") {
+            Some(Self::Fork)
+        } else if has("pathname") {
+            Some(Self::Exec)
+        } else if has("trigger") {
+            Some(Self::Trigger)
+        } else if has("paused") {
+            Some(Self::Breakpoint)
+        } else if has("discon") {
+            Some(Self::Discon)
+        } else if has("vcpu") {
+            Some(Self::Vcpu)
+        } else if has("fatal") {
+            Some(Self::Signal)
+        } else if has("flags") && has("prot") {
+            Some(Self::Map)
+        } else if has("prot") && has("len") {
+            Some(Self::Protect)
+        } else if has("addr") && has("len") {
+            Some(Self::Unmap)
+        } else if has("paddr") {
+            Some(Self::SysMem)
+        } else if has("insn") {
+            Some(Self::Mem)
+        } else if has("num") && has("args") {
+            Some(Self::Syscall)
+        } else if has("hits") {
+            Some(Self::BlockHits)
+        } else if has("n_insns") {
+            Some(Self::TB)
+        } else if has("src") && has("dst") {
+            Some(Self::Edge)
+        } else if has("callsite") {
+            Some(Self::Call)
+        } else if has("site") {
+            Some(Self::Return)
+        } else if has("entry") {
+            Some(Self::FunctionEnter)
+        } else if has("exit") {
+            Some(Self::FunctionExit)
+        } else if has("branch") {
+            Some(Self::Insn)
+        } else {
+            None
+        }
+    }
+}
+
+/// Read the events of a trace. Lines that are not JSON objects, like output of the traced
+/// program, and the metadata of the run are skipped
+///
+/// # Arguments
+///
+/// * `reader` - The trace
+pub fn events(reader: impl BufRead) -> impl Iterator<Item = io::Result<Value>> {
+    reader.lines().filter_map(|line| match line {
+        Ok(line) => match from_str::<Value>(&line) {
+            Ok(event @ Value::Object(_)) if event.get(metadata::RECORD).is_none() => {
+                Some(Ok(event))
+            }
+            _ => None,
+        },
+        Err(e) => Some(Err(e)),
+    })
+}
+
+/// Reads the events of a trace written in any format `TraceReader` can read back: JSON, CBOR,
+/// MessagePack, binary or compact
+pub struct TraceReader<R: BufRead> {
+    /// The trace
+    reader: R,
+    /// The format of the trace
+    format: OutputFormat,
+    /// The line being read, for JSON traces
+    line: String,
+    /// The last address of each VCPU, for compact traces
+    deltas: Deltas,
+    /// The runs of identical events, for compact traces that coalesce them
+    repeats: Repeats,
+    /// The metadata of the run the trace was written for, if it starts with it
+    metadata: Option<RunMetadata>,
+    /// The first event, read while looking for the metadata
+    first: Option<io::Result<Value>>,
+}
+
+impl TraceReader<Box<dyn BufRead + Send>> {
+    /// Open a trace file, decompressing it if it is compressed with gzip
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The trace file
+    /// * `format` - The format the trace was written in
+    pub fn open(path: impl AsRef<Path>, format: OutputFormat) -> io::Result<Self> {
+        Self::decompress(BufReader::new(File::open(path)?), format)
+    }
+
+    /// Read a trace, decompressing it if it is compressed with gzip, like one already in
+    /// memory
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The trace
+    /// * `format` - The format the trace was written in
+    pub fn decompress(
+        mut reader: impl BufRead + Send + 'static,
+        format: OutputFormat,
+    ) -> io::Result<Self> {
+        let mut reader: Box<dyn BufRead + Send> = if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+            Box::new(BufReader::new(MultiGzDecoder::new(reader)))
+        } else {
+            Box::new(reader)
+        };
+
+        // Compact traces say what they are in their header
+        let format = if reader.fill_buf()?.starts_with(&compact::MAGIC) {
+            OutputFormat::Compact
+        } else {
+            format
+        };
+
+        Self::new(reader, format)
+    }
+
+    /// Open a set of files rotated by a file sink of the plugin, `<path>.0`, `<path>.1` and
+    /// so on, as one trace. Every file starts with the header of the trace, which is only read
+    /// from the oldest file left, since older ones may have been removed with `sink_keep`
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path the files are named after
+    pub fn open_rotated(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut files = rotated_files(path.as_ref())?.into_iter();
+        let first = files.next().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::NotFound,
+                format!("No rotated files of {}", path.as_ref().to_string_lossy()),
+            )
+        })?;
+
+        // Sinks write the binary format
+        Self::new(
+            Box::new(Rotated {
+                files,
+                file: BufReader::new(File::open(first)?),
+            }),
+            OutputFormat::Binary,
+        )
+    }
+}
+
+/// The files of a set rotated by a file sink of the plugin, in the order they were written
+///
+/// # Arguments
+///
+/// * `path` - The path the files are named after
+pub fn rotated_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let mut files = Vec::new();
+
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let index = file_name
+            .to_string_lossy()
+            .strip_prefix(name.as_ref())
+            .and_then(|suffix| suffix.strip_prefix('.'))
+            .and_then(|index| index.parse::<u64>().ok());
+
+        if let Some(index) = index {
+            files.push((index, entry.path()));
+        }
+    }
+
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Reads the files of a rotated set one after the other, skipping the header that each file
+/// after the first starts with
+struct Rotated {
+    /// The files left to read
+    files: vec::IntoIter<PathBuf>,
+    /// The file being read
+    file: BufReader<File>,
+}
+
+impl Read for Rotated {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl BufRead for Rotated {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.file.fill_buf()?.is_empty() {
+            let Some(path) = self.files.next() else {
+                break;
+            };
+            let mut file = BufReader::new(File::open(path)?);
+
+            // A file cut short before its header was written holds no events either
+            if !file.fill_buf()?.is_empty() {
+                let mut len = [0; 4];
+                file.read_exact(&mut len)?;
+                copy(
+                    &mut (&mut file).take(u32::from_le_bytes(len) as u64),
+                    &mut sink(),
+                )?;
+            }
+
+            self.file = file;
+        }
+
+        self.file.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.file.consume(amt)
+    }
+}
+
+impl<R: BufRead> TraceReader<R> {
+    /// Instantiate a new `TraceReader`
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The trace
+    /// * `format` - The format the trace was written in. Chrome traces, databases and Parquet
+    ///   files cannot be read back
+    pub fn new(mut reader: R, format: OutputFormat) -> io::Result<Self> {
+        match format {
+            OutputFormat::Json
+            | OutputFormat::Cbor
+            | OutputFormat::Msgpack
+            | OutputFormat::Binary
+            | OutputFormat::Compact
+            | OutputFormat::CompactRepeat => {
+                // Compact traces say in their header whether they coalesce events
+                let (format, repeats) = match format {
+                    OutputFormat::Compact | OutputFormat::CompactRepeat => {
+                        (OutputFormat::Compact, compact::read_header(&mut reader)?)
+                    }
+                    _ => (format, Repeats::default()),
+                };
+
+                let mut trace = Self {
+                    reader,
+                    format,
+                    line: String::new(),
+                    deltas: Deltas::default(),
+                    repeats,
+                    metadata: None,
+                    first: None,
+                };
+
+                match trace.read() {
+                    Some(Ok(record)) if record.get(metadata::RECORD).is_some() => {
+                        trace.metadata = RunMetadata::of(&record);
+                    }
+                    first => trace.first = first,
+                }
+
+                Ok(trace)
+            }
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{:?} traces cannot be read back", format),
+            )),
+        }
+    }
+
+    /// The metadata of the run the trace was written for, if it starts with it
+    pub fn metadata(&self) -> Option<&RunMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Read the next record of the trace
+    fn read(&mut self) -> Option<io::Result<Value>> {
+        if let Some(event) = self.repeats.next() {
+            return Some(Ok(event));
+        }
+
+        if self.format == OutputFormat::Json {
+            loop {
+                self.line.clear();
+
+                match self.reader.read_line(&mut self.line) {
+                    Ok(0) => return None,
+                    // Lines that are not events are skipped, like `events` does
+                    Ok(_) => match from_str::<Value>(&self.line) {
+                        Ok(event @ Value::Object(_)) => return Some(Ok(event)),
+                        _ => continue,
+                    },
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+        }
+
+        match self.reader.fill_buf() {
+            Ok([]) => return None,
+            Ok(_) => {}
+            Err(e) => return Some(Err(e)),
+        }
+
+        // Each event is decoded by a deserializer of its own, which reads no further than it
+        Some(match self.format {
+            OutputFormat::Cbor => {
+                Value::deserialize(&mut serde_cbor::Deserializer::from_reader(&mut self.reader))
+                    .map_err(io::Error::other)
+            }
+            OutputFormat::Msgpack => {
+                rmp_serde::from_read(&mut self.reader).map_err(io::Error::other)
+            }
+            OutputFormat::Compact => {
+                compact::read_event(&mut self.reader, &mut self.deltas, &mut self.repeats)
+            }
+            _ => self.next_binary(),
+        })
+    }
+
+    /// Read the next event of a binary trace
+    fn next_binary(&mut self) -> io::Result<Value> {
+        let mut len = [0; 4];
+        self.reader.read_exact(&mut len)?;
+
+        let mut item = vec![0; u32::from_le_bytes(len) as usize];
+        self.reader.read_exact(&mut item)?;
+
+        serde_cbor::from_slice(&item).map_err(io::Error::other)
+    }
+}
+
+impl<R: BufRead> Iterator for TraceReader<R> {
+    type Item = io::Result<Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.first.take().or_else(|| self.read())
+    }
+}
+
+/// The address of the code an event executed, for instruction and translation block events
+///
+/// # Arguments
+///
+/// * `event` - The event
+pub fn pc(event: &Value) -> Option<u64> {
+    match EventKind::of(event) {
+        Some(EventKind::Insn) | Some(EventKind::TB) => event.get("vaddr")?.as_u64(),
+        _ => None,
+    }
+}
+
+/// How the guest exited, like `exited with 0` or `killed by SIGSEGV`, read from the final
+/// event of a trace. `None` if the plugin could not tell, like under system emulation
+///
+/// # Arguments
+///
+/// * `last` - The final event of the trace
+pub fn exit_status(last: &Value) -> Option<String> {
+    if last.get("signaled").and_then(Value::as_bool)? {
+        let signal = last.get("signal").and_then(Value::as_i64);
+
+        return Some(match last.get("signal_name").and_then(Value::as_str) {
+            Some(name) => format!("killed by {}", name),
+            None => match signal {
+                Some(signal) => format!("killed by signal {}", signal),
+                None => "killed by a signal".to_string(),
+            },
+        });
+    }
+
+    last.get("exit_code")
+        .and_then(Value::as_i64)
+        .map(|code| format!("exited with {}", code))
+}
+
+/// The width of the addresses of the guest a trace was recorded from, in bits, read from the
+/// header of the trace. Traces recorded before headers had it are of 64-bit guests
+///
+/// # Arguments
+///
+/// * `header` - The header event of the trace
+pub fn address_bits(header: &Value) -> u32 {
+    header
+        .get("address_bits")
+        .and_then(Value::as_u64)
+        .map_or(64, |bits| bits as u32)
+}
+
+/// Truncate an address to the width of the addresses of a guest. 32-bit guests sign extend
+/// the system call arguments they log, so pointers above 2GB have their upper 32 bits set
+///
+/// # Arguments
+///
+/// * `addr` - The address
+/// * `address_bits` - The width of the addresses of the guest, see `address_bits`
+pub fn truncate_address(addr: u64, address_bits: u32) -> u64 {
+    if address_bits >= 64 {
+        addr
+    } else {
+        addr & ((1 << address_bits) - 1)
+    }
+}
+
+/// The program and arguments a trace was recorded with, read from its header. QEMU must have
+/// been run with `--` before the program, like `Driver` does
+///
+/// # Arguments
+///
+/// * `header` - The header event of the trace
+pub fn guest_command(header: &Value) -> Option<(PathBuf, Vec<String>)> {
+    let command_line = header
+        .get("command_line")?
+        .as_array()?
+        .iter()
+        .map(|arg| arg.as_str().map(|arg| arg.to_string()))
+        .collect::<Option<Vec<String>>>()?;
+    let mut guest = command_line
+        .into_iter()
+        .skip_while(|arg| arg != "--")
+        .skip(1);
+
+    Some((PathBuf::from(guest.next()?), guest.collect()))
+}
+
+/// The arguments the plugin was run with, read from the header of a trace
+///
+/// # Arguments
+///
+/// * `header` - The header event of the trace
+pub fn plugin_args(header: &Value) -> Option<Vec<String>> {
+    header
+        .get("plugin_args")?
+        .as_array()?
+        .iter()
+        .map(|arg| arg.as_str().map(|arg| arg.to_string()))
+        .collect()
+}
@@ -0,0 +1,306 @@
+//! Crash triage
+//!
+//! Fuzzers find many inputs that crash a program the same way. Triage groups them into buckets
+//! of inputs that crashed alike, so each bug is looked at once. Without a call stack, a crash is
+//! told apart by what led to it: the signal that killed the program and the last translation
+//! blocks it executed, from a trace of every block (`trace_tb` without `dedup`) and its signals
+//! (`log_signals`). A `Recorder` keeps only those last blocks while the program runs, like a
+//! flight recorder, and `Buckets` groups the crashes by a hash of them.
+//!
+//! ```no_run
+//! use cannonball_tools::{
+//!     trace::events,
+//!     triage::{Buckets, Recorder},
+//! };
+//! use std::{fs::File, io::{stdout, BufReader}};
+//!
+//! let mut buckets = Buckets::new();
+//!
+//! for input in ["crash-1.trace", "crash-2.trace"] {
+//!     let mut recorder = Recorder::new(16);
+//!
+//!     for event in events(BufReader::new(File::open(input).unwrap())) {
+//!         recorder.add(&event.unwrap());
+//!     }
+//!
+//!     buckets.add(input, recorder.crash(None));
+//! }
+//!
+//! buckets.write(None, stdout()).unwrap();
+//! ```
+
+use serde_json::{json, Value};
+use xxhash_rust::xxh3::xxh3_64;
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, VecDeque},
+    io::{self, Write},
+};
+
+use crate::{symbols::Symbolizer, trace::EventKind};
+
+/// How a program crashed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Crash {
+    /// The signal that killed the program, if known
+    pub signal: Option<i64>,
+    /// The name of the signal, like `SIGSEGV`, if known
+    pub name: Option<String>,
+    /// The address of the last instruction executed before the signal, if the plugin logged it
+    pub pc: Option<u64>,
+    /// The addresses of the last blocks executed, oldest first
+    pub blocks: Vec<u64>,
+}
+
+impl Crash {
+    /// A hash of the signal and the last blocks, equal for crashes that are alike
+    pub fn hash(&self) -> u64 {
+        let mut bytes = self.signal.unwrap_or_default().to_le_bytes().to_vec();
+
+        for block in &self.blocks {
+            bytes.extend_from_slice(&block.to_le_bytes());
+        }
+
+        xxh3_64(&bytes)
+    }
+
+    /// The crash as JSON
+    pub fn to_json(&self) -> Value {
+        json!({
+            "hash": format!("{:016x}", self.hash()),
+            "signal": self.signal,
+            "name": self.name,
+            "pc": self.pc,
+            "blocks": self.blocks,
+        })
+    }
+}
+
+/// Keeps the last blocks a program executed, and the signal that killed it
+#[derive(Debug, Clone, Default)]
+pub struct Recorder {
+    /// The number of blocks to keep
+    limit: usize,
+    /// The addresses of the last blocks executed, oldest first
+    blocks: VecDeque<u64>,
+    /// The fatal signal event, if the plugin logged one
+    signal: Option<Value>,
+    /// The final event, if the plugin logged one
+    end: Option<Value>,
+}
+
+impl Recorder {
+    /// Instantiate a new `Recorder`
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The number of blocks to keep
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            blocks: VecDeque::with_capacity(limit),
+            ..Default::default()
+        }
+    }
+
+    /// Account for an event. Events other than translation block, signal and final events
+    /// are ignored
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn add(&mut self, event: &Value) {
+        match EventKind::of(event) {
+            Some(EventKind::TB) => {
+                let Some(vaddr) = event.get("vaddr").and_then(Value::as_u64) else {
+                    return;
+                };
+
+                if self.blocks.len() == self.limit {
+                    self.blocks.pop_front();
+                }
+
+                if self.limit > 0 {
+                    self.blocks.push_back(vaddr);
+                }
+            }
+            Some(EventKind::Signal) if event.get("fatal") == Some(&Value::Bool(true)) => {
+                self.signal = Some(event.clone());
+            }
+            Some(EventKind::Final) => self.end = Some(event.clone()),
+            _ => {}
+        }
+    }
+
+    /// How the program crashed, if it was killed by a signal
+    ///
+    /// # Arguments
+    ///
+    /// * `signal` - The signal QEMU was killed by, from its exit status, for when the plugin
+    ///   did not log the signal
+    pub fn crash(&self, signal: Option<i32>) -> Option<Crash> {
+        let signaled = self
+            .end
+            .as_ref()
+            .and_then(|end| end.get("signaled"))
+            .and_then(Value::as_bool)
+            .unwrap_or_default();
+
+        if self.signal.is_none() && !signaled && signal.is_none() {
+            return None;
+        }
+
+        let field = |name: &str| {
+            [&self.signal, &self.end]
+                .into_iter()
+                .flatten()
+                .find_map(|event| event.get(name).filter(|value| !value.is_null()))
+        };
+
+        Some(Crash {
+            signal: field("signal")
+                .and_then(Value::as_i64)
+                .or(signal.map(i64::from)),
+            name: field("name")
+                .or_else(|| field("signal_name"))
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            pc: field("pc").and_then(Value::as_u64),
+            blocks: self.blocks.iter().copied().collect(),
+        })
+    }
+}
+
+/// Inputs that crashed a program alike
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bucket {
+    /// The crash of the first input added
+    pub crash: Crash,
+    /// The inputs, sorted by name
+    pub inputs: Vec<String>,
+}
+
+/// Inputs grouped by how they crashed a program
+#[derive(Debug, Clone, Default)]
+pub struct Buckets {
+    /// The buckets, by hash of their crash
+    pub buckets: BTreeMap<u64, Bucket>,
+    /// The inputs that did not crash the program, sorted by name
+    pub clean: Vec<String>,
+}
+
+impl Buckets {
+    /// Instantiate a new empty `Buckets`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an input to the bucket of its crash. Inputs are kept sorted, so buckets do not
+    /// depend on the order inputs finished running in
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - The name of the input
+    /// * `crash` - How it crashed the program, if it did
+    pub fn add(&mut self, input: &str, crash: Option<Crash>) {
+        let inputs = match crash {
+            Some(crash) => {
+                &mut self
+                    .buckets
+                    .entry(crash.hash())
+                    .or_insert_with(|| Bucket {
+                        crash,
+                        inputs: Vec::new(),
+                    })
+                    .inputs
+            }
+            None => &mut self.clean,
+        };
+
+        let index = inputs.partition_point(|other| other.as_str() < input);
+        inputs.insert(index, input.to_string());
+    }
+
+    /// The buckets, with the most inputs first
+    pub fn sorted(&self) -> Vec<(u64, &Bucket)> {
+        let mut buckets = self
+            .buckets
+            .iter()
+            .map(|(hash, bucket)| (*hash, bucket))
+            .collect::<Vec<_>>();
+        buckets.sort_by_key(|(hash, bucket)| (Reverse(bucket.inputs.len()), *hash));
+        buckets
+    }
+
+    /// Write the buckets, with the most inputs first, each with its inputs and the last blocks
+    /// executed before the crash
+    ///
+    /// # Arguments
+    ///
+    /// * `symbolizer` - Names the functions blocks are in
+    /// * `out` - Where to write the buckets
+    pub fn write(&self, symbolizer: Option<&Symbolizer>, mut out: impl Write) -> io::Result<()> {
+        let symbolize = |addr: u64| match symbolizer.and_then(|s| s.symbolize(addr)) {
+            Some((name, offset)) => format!("{:#x} <{}+{:#x}>", addr, name, offset),
+            None => format!("{:#x}", addr),
+        };
+
+        writeln!(
+            out,
+            "{} buckets of {} crashing inputs, {} inputs did not crash",
+            self.buckets.len(),
+            self.buckets
+                .values()
+                .map(|bucket| bucket.inputs.len())
+                .sum::<usize>(),
+            self.clean.len()
+        )?;
+
+        for (hash, bucket) in self.sorted() {
+            let crash = &bucket.crash;
+            let signal = crash
+                .name
+                .clone()
+                .or_else(|| crash.signal.map(|signal| format!("signal {}", signal)))
+                .unwrap_or_else(|| "a signal".to_string());
+
+            writeln!(out)?;
+            write!(
+                out,
+                "{:016x}: {} inputs, {}",
+                hash,
+                bucket.inputs.len(),
+                signal
+            )?;
+
+            match crash.pc {
+                Some(pc) => writeln!(out, " at pc={}", symbolize(pc))?,
+                None => writeln!(out)?,
+            }
+
+            writeln!(out, "  inputs: {}", bucket.inputs.join(", "))?;
+
+            for block in &crash.blocks {
+                writeln!(out, "  {}", symbolize(*block))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The buckets as JSON, with the most inputs first
+    pub fn to_json(&self) -> Value {
+        let buckets = self
+            .sorted()
+            .into_iter()
+            .map(|(_, bucket)| {
+                let mut value = bucket.crash.to_json();
+                value["inputs"] = json!(bucket.inputs);
+                value
+            })
+            .collect::<Vec<_>>();
+
+        json!({ "buckets": buckets, "clean": self.clean })
+    }
+}
@@ -0,0 +1,378 @@
+//! Verifying traces
+//!
+//! A bug in the plugin or in a codec shows up in a trace as events that cannot happen, like an
+//! instruction at an address no code is mapped at, or a memory access attributed to the wrong
+//! instruction. `Verifier` checks the events of a trace against the program it was recorded
+//! from:
+//!
+//! * Every event is one the plugin logs, and the header comes first
+//! * Every instruction, translation block and memory access executed code mapped executable
+//!
+//! Where code is mapped is reconstructed the way `/proc/<pid>/maps` would show it: the
+//! executable loadable segments of the program and of its interpreter, from their ELF program
+//! headers, and memory the program maps executable with `mmap` and `mprotect`, from its system
+//! call events. Without system call events, only statically linked programs can be verified.
+//!
+//! QEMU chooses where position independent code is loaded. The interpreter is found where the
+//! first instruction executed is, which is its entry point, or the program's if it has no
+//! interpreter. A position independent program with an interpreter is found where the first
+//! instruction outside of every known mapping is, which is its entry point once the interpreter
+//! has mapped the libraries.
+//!
+//! Code QEMU maps for the guest itself, like the vDSO, is not known, so each page of it that is
+//! executed is reported once. Instructions after an exec event ran another program, so they are
+//! not checked.
+//!
+//! ```no_run
+//! use std::{fs::File, io::BufReader};
+//!
+//! use cannonball_tools::{trace::events, verify::Verifier};
+//!
+//! let mut verifier = Verifier::load("/bin/ls").unwrap();
+//!
+//! for event in events(BufReader::new(File::open("ls.trace").unwrap())) {
+//!     verifier.verify(&event.unwrap());
+//! }
+//!
+//! for problem in verifier.problems() {
+//!     println!("{}", problem);
+//! }
+//! ```
+
+use goblin::elf::{
+    header::ET_DYN,
+    program_header::{PF_X, PT_LOAD},
+    Elf,
+};
+use serde_json::Value;
+
+use std::{
+    collections::HashSet,
+    fmt::{self, Display, Formatter},
+    fs::read,
+    io,
+    path::Path,
+};
+
+use crate::trace::EventKind;
+
+/// The most problems a `Verifier` keeps, past which they are only counted
+const MAX_PROBLEMS: usize = 64;
+
+/// The size of a page, which code is mapped in
+const PAGE_SIZE: u64 = 0x1000;
+
+/// The number of the `mmap` system call on x86_64, the target the driver runs
+const MMAP: i64 = 9;
+/// The number of the `mprotect` system call on x86_64
+const MPROTECT: i64 = 10;
+/// The protection of executable memory
+const PROT_EXEC: u64 = 0x4;
+
+/// The executable segments of an ELF file
+#[derive(Debug, Clone)]
+struct Image {
+    /// The entry point, before relocation
+    entry: u64,
+    /// The executable segments, as start and end addresses before relocation
+    segments: Vec<(u64, u64)>,
+    /// Where the image was loaded, relative to its program headers, once known. Only
+    /// position independent images are loaded elsewhere than at 0
+    bias: Option<u64>,
+}
+
+impl Image {
+    /// Read the executable segments of an ELF file, returning them along with the path of its
+    /// interpreter, if it has one
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The ELF file
+    fn load(path: impl AsRef<Path>) -> io::Result<(Self, Option<String>)> {
+        let data = read(path)?;
+        let elf = Elf::parse(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let segments = elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == PT_LOAD && ph.p_flags & PF_X != 0)
+            .map(|ph| (ph.p_vaddr, ph.p_vaddr.saturating_add(ph.p_memsz)))
+            .collect();
+        let bias = if elf.header.e_type == ET_DYN {
+            None
+        } else {
+            Some(0)
+        };
+
+        Ok((
+            Self {
+                entry: elf.entry,
+                segments,
+                bias,
+            },
+            elf.interpreter.map(|interpreter| interpreter.to_string()),
+        ))
+    }
+
+    /// Whether a range of code lies in one of the segments, once the image is loaded
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - The first address of the code
+    /// * `end` - The address after the last of the code
+    fn contains(&self, start: u64, end: u64) -> bool {
+        let Some(bias) = self.bias else {
+            return false;
+        };
+
+        self.segments.iter().any(|&(s, e)| {
+            let (s, e) = (s.wrapping_add(bias), e.wrapping_add(bias));
+            s <= start && end <= e
+        })
+    }
+}
+
+/// A problem found in a trace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The event at this index is not one the plugin logs
+    UnknownEvent(u64),
+    /// The header is the event at this index, instead of the first
+    LateHeader(u64),
+    /// Events executed code in a page that is not mapped executable
+    Unmapped {
+        /// The index of the first of the events
+        index: u64,
+        /// The address of the code the first of the events executed
+        pc: u64,
+        /// The number of events that executed code in the page
+        count: u64,
+    },
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownEvent(index) => write!(f, "event {} is not one the plugin logs", index),
+            Self::LateHeader(index) => write!(f, "event {} is a header, but not the first", index),
+            Self::Unmapped { index, pc, count } => write!(
+                f,
+                "event {} executed unmapped code at {:#x}, and {} more in its page",
+                index,
+                pc,
+                count - 1
+            ),
+        }
+    }
+}
+
+/// Checks the events of a trace against the program it was recorded from
+#[derive(Debug, Clone)]
+pub struct Verifier {
+    /// The program
+    program: Image,
+    /// The interpreter of the program, if it has one
+    interpreter: Option<Image>,
+    /// Memory the program mapped executable, as start and end addresses
+    mapped: Vec<(u64, u64)>,
+    /// The index of the next event
+    index: u64,
+    /// The pages unmapped code was executed in
+    unmapped: HashSet<u64>,
+    /// Whether code was executed yet
+    started: bool,
+    /// Whether the program called `execve`, so the code executed is not its own anymore
+    exec: bool,
+    /// The problems found, up to `MAX_PROBLEMS`
+    problems: Vec<Problem>,
+    /// The number of problems found, including those not kept
+    count: u64,
+}
+
+impl Verifier {
+    /// Read the executable segments of a program and of its interpreter
+    ///
+    /// # Arguments
+    ///
+    /// * `program` - The program the trace was recorded from
+    pub fn load(program: impl AsRef<Path>) -> io::Result<Self> {
+        let (program, interpreter) = Image::load(program)?;
+        let interpreter = match interpreter {
+            Some(interpreter) => Some(Image::load(interpreter)?.0),
+            None => None,
+        };
+
+        Ok(Self {
+            program,
+            interpreter,
+            mapped: Vec::new(),
+            unmapped: HashSet::new(),
+            index: 0,
+            started: false,
+            exec: false,
+            problems: Vec::new(),
+            count: 0,
+        })
+    }
+
+    /// Check the next event of the trace
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn verify(&mut self, event: &Value) {
+        let index = self.index;
+        self.index += 1;
+
+        let Some(kind) = EventKind::of(event) else {
+            self.report(Problem::UnknownEvent(index));
+            return;
+        };
+
+        let u64_of = |event: &Value, name: &str| event.get(name).and_then(Value::as_u64);
+
+        match kind {
+            EventKind::Header if index != 0 => self.report(Problem::LateHeader(index)),
+            EventKind::Exec => self.exec = true,
+            EventKind::Syscall => self.map(event),
+            EventKind::Insn => {
+                if let Some(pc) = u64_of(event, "vaddr") {
+                    self.check(index, pc, 1);
+                }
+            }
+            EventKind::TB | EventKind::BlockHits => {
+                if let (Some(pc), Some(size)) = (u64_of(event, "vaddr"), u64_of(event, "size")) {
+                    self.check(index, pc, size);
+                }
+            }
+            EventKind::Mem | EventKind::SysMem => {
+                // The instruction is nested in the access, and the access in the physical one
+                let mem = event.get("mem").unwrap_or(event);
+
+                if let Some(pc) = mem.get("insn").and_then(|insn| u64_of(insn, "vaddr")) {
+                    self.check(index, pc, 1);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The problems found so far, up to a limit, in the order they were found
+    pub fn problems(&self) -> &[Problem] {
+        &self.problems
+    }
+
+    /// The number of problems found so far, including those past the limit of `problems`
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Record a problem
+    ///
+    /// # Arguments
+    ///
+    /// * `problem` - The problem
+    fn report(&mut self, problem: Problem) {
+        self.count += 1;
+
+        if self.problems.len() < MAX_PROBLEMS {
+            self.problems.push(problem);
+        }
+    }
+
+    /// Record memory a system call mapped executable
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The system call event
+    fn map(&mut self, event: &Value) {
+        let num = event.get("num").and_then(Value::as_i64);
+        let rv = event.get("rv").and_then(Value::as_i64);
+        let args = event
+            .get("args")
+            .and_then(Value::as_array)
+            .map(|args| args.iter().filter_map(Value::as_u64).collect::<Vec<u64>>())
+            .unwrap_or_default();
+
+        let (Some(num), Some(rv), [addr, len, prot, ..]) = (num, rv, args.as_slice()) else {
+            return;
+        };
+
+        // Failed calls return a negated error number
+        if prot & PROT_EXEC == 0 || (-4096..0).contains(&rv) {
+            return;
+        }
+
+        let start = match num {
+            MMAP => rv as u64,
+            MPROTECT => *addr,
+            _ => return,
+        };
+
+        self.mapped.push((start, start.saturating_add(*len)));
+    }
+
+    /// Check that an event executed mapped code
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the event
+    /// * `pc` - The address of the code
+    /// * `size` - The size of the code
+    fn check(&mut self, index: u64, pc: u64, size: u64) {
+        if self.exec {
+            return;
+        }
+
+        let end = pc.saturating_add(size);
+
+        if !self.started {
+            self.started = true;
+
+            // The first instruction executed is the entry point of the interpreter, or of the
+            // program if it has none
+            let image = self.interpreter.as_mut().unwrap_or(&mut self.program);
+
+            if image.bias.is_none() {
+                image.bias = Some(pc.wrapping_sub(image.entry));
+            }
+        }
+
+        let mapped = self.program.contains(pc, end)
+            || self
+                .interpreter
+                .as_ref()
+                .is_some_and(|interpreter| interpreter.contains(pc, end))
+            || self.mapped.iter().any(|&(s, e)| s <= pc && end <= e);
+
+        if mapped {
+            return;
+        }
+
+        // The program is entered once the interpreter has mapped everything else
+        if self.program.bias.is_none() && pc.wrapping_sub(self.program.entry) & (PAGE_SIZE - 1) == 0
+        {
+            self.program.bias = Some(pc.wrapping_sub(self.program.entry));
+            return;
+        }
+
+        let page = pc / PAGE_SIZE;
+
+        if self.unmapped.insert(page) {
+            self.report(Problem::Unmapped {
+                index,
+                pc,
+                count: 1,
+            });
+            return;
+        }
+
+        // Pages past the limit of `problems` are only counted once
+        for problem in &mut self.problems {
+            match problem {
+                Problem::Unmapped { pc, count, .. } if *pc / PAGE_SIZE == page => *count += 1,
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! vsock transport
+//!
+//! Unix sockets do not cross the boundary of a virtual machine, so when QEMU runs in a guest
+//! and the consumer on its host, events cannot be streamed over one. `AF_VSOCK` sockets can,
+//! addressed by the context id (CID) of the machine and a port: the host is always CID 2
+//! (`VMADDR_CID_HOST`), and each guest has a CID of its own.
+//!
+//! The Jaivana plugin connects to a consumer with `sink=vsock:<cid>:<port>` and writes the
+//! framed binary stream of a sink to it. The consumer listens with a `VsockListener`:
+//!
+//! ```no_run
+//! use std::io::BufReader;
+//!
+//! use cannonball_tools::{output::OutputFormat, trace::TraceReader, vsock::VsockListener};
+//!
+//! // In the guest: qemu-x86_64 -plugin ./libjaivana.so,log_pc=on,sink=vsock:2:5000 /bin/ls
+//! let listener = VsockListener::bind(5000).unwrap();
+//! let (stream, peer) = listener.accept().unwrap();
+//! eprintln!("Receiving events from {}", peer);
+//!
+//! for event in TraceReader::new(BufReader::new(stream), OutputFormat::Binary).unwrap() {
+//!     println!("{}", event.unwrap());
+//! }
+//! ```
+
+use libc::{
+    accept, bind, c_int, connect, listen, sockaddr, sockaddr_vm, socket, socklen_t, AF_VSOCK,
+    SOCK_CLOEXEC, SOCK_STREAM, VMADDR_CID_ANY,
+};
+
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::File,
+    io::{self, ErrorKind, Read, Write},
+    mem::{size_of, zeroed},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    str::FromStr,
+};
+
+/// The number of connections waiting to be accepted a listener queues
+const BACKLOG: c_int = 16;
+
+/// The address of a vsock socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VsockAddr {
+    /// The context id of the machine
+    pub cid: u32,
+    /// The port
+    pub port: u32,
+}
+
+impl VsockAddr {
+    /// Instantiate a new `VsockAddr`
+    ///
+    /// # Arguments
+    ///
+    /// * `cid` - The context id of the machine, 2 for the host
+    /// * `port` - The port
+    pub fn new(cid: u32, port: u32) -> Self {
+        Self { cid, port }
+    }
+
+    /// The address as a `sockaddr_vm`
+    fn raw(&self) -> sockaddr_vm {
+        let mut raw: sockaddr_vm = unsafe { zeroed() };
+        raw.svm_family = AF_VSOCK as _;
+        raw.svm_cid = self.cid;
+        raw.svm_port = self.port;
+        raw
+    }
+}
+
+impl Display for VsockAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "vsock:{}:{}", self.cid, self.port)
+    }
+}
+
+impl FromStr for VsockAddr {
+    type Err = io::Error;
+
+    /// Parse an address given as `<cid>:<port>`, optionally prefixed with `vsock:`
+    fn from_str(addr: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid vsock address {}", addr),
+            )
+        };
+
+        let (cid, port) = addr
+            .strip_prefix("vsock:")
+            .unwrap_or(addr)
+            .split_once(':')
+            .ok_or_else(invalid)?;
+
+        Ok(Self {
+            cid: cid.parse().map_err(|_| invalid())?,
+            port: port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// A new vsock stream socket
+fn vsock_socket() -> io::Result<OwnedFd> {
+    let fd = unsafe { socket(AF_VSOCK, SOCK_STREAM | SOCK_CLOEXEC, 0) };
+
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// A connected vsock socket
+#[derive(Debug)]
+pub struct VsockStream {
+    /// The socket, as a file to read and write it with
+    file: File,
+}
+
+impl VsockStream {
+    /// Connect to a vsock socket
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the socket
+    pub fn connect(addr: VsockAddr) -> io::Result<Self> {
+        let fd = vsock_socket()?;
+        let raw = addr.raw();
+
+        let connected = unsafe {
+            connect(
+                fd.as_raw_fd(),
+                &raw as *const sockaddr_vm as *const sockaddr,
+                size_of::<sockaddr_vm>() as socklen_t,
+            )
+        };
+
+        if connected == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { file: fd.into() })
+    }
+}
+
+impl Read for VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for VsockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl AsRawFd for VsockStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// A vsock socket listening for connections
+#[derive(Debug)]
+pub struct VsockListener {
+    fd: OwnedFd,
+}
+
+impl VsockListener {
+    /// Listen for connections from any machine on a port
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The port
+    pub fn bind(port: u32) -> io::Result<Self> {
+        let fd = vsock_socket()?;
+        let raw = VsockAddr::new(VMADDR_CID_ANY, port).raw();
+
+        let bound = unsafe {
+            bind(
+                fd.as_raw_fd(),
+                &raw as *const sockaddr_vm as *const sockaddr,
+                size_of::<sockaddr_vm>() as socklen_t,
+            )
+        };
+
+        if bound == -1 || unsafe { listen(fd.as_raw_fd(), BACKLOG) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { fd })
+    }
+
+    /// Wait for a connection, returning it along with the address it came from
+    pub fn accept(&self) -> io::Result<(VsockStream, VsockAddr)> {
+        let mut raw: sockaddr_vm = unsafe { zeroed() };
+        let mut len = size_of::<sockaddr_vm>() as socklen_t;
+
+        let fd = unsafe {
+            accept(
+                self.fd.as_raw_fd(),
+                &mut raw as *mut sockaddr_vm as *mut sockaddr,
+                &mut len,
+            )
+        };
+
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let stream = VsockStream {
+            file: unsafe { File::from_raw_fd(fd) },
+        };
+
+        Ok((stream, VsockAddr::new(raw.svm_cid, raw.svm_port)))
+    }
+}
+
+impl AsRawFd for VsockListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
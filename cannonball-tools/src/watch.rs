@@ -0,0 +1,196 @@
+//! Watching traced programs
+//!
+//! A `Watcher` runs alongside each traced program. It kills the program when its time is up,
+//! and forwards the signals this process receives to interrupt it, like Ctrl+C, so the program
+//! exits through QEMU and the plugin gets to flush its events instead of the driver dying with
+//! events left unread.
+//!
+//! Signal handlers can do very little safely, so the handler only records the signal and the
+//! watchers of all running programs pick it up.
+
+use libc::{
+    c_int, c_void, kill, pid_t, prlimit, rlimit, sigaction, sigemptyset, siginfo_t, SA_SIGINFO,
+    SIGHUP, SIGINT, SIGTERM, SI_KERNEL,
+};
+
+use std::{
+    io,
+    mem::zeroed,
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering},
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Mutex,
+    },
+    thread::{spawn, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// The signals forwarded to traced programs
+const FORWARDED: [c_int; 3] = [SIGINT, SIGTERM, SIGHUP];
+
+/// How often watchers check for received signals
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The number of signals received so far
+static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+/// The last signal received
+static LAST_SIGNAL: AtomicI32 = AtomicI32::new(0);
+/// Whether the last signal was sent by the terminal, like Ctrl+C
+static FROM_TERMINAL: AtomicBool = AtomicBool::new(false);
+
+/// The number of running watchers, and the handlers to restore once none are left
+static HANDLERS: Mutex<(usize, Vec<(c_int, sigaction)>)> = Mutex::new((0, Vec::new()));
+
+/// Records a received signal
+extern "C" fn on_signal(signal: c_int, info: *mut siginfo_t, _: *mut c_void) {
+    // Terminals signal their whole foreground process group, programs included
+    let from_terminal = !info.is_null() && unsafe { (*info).si_code } == SI_KERNEL;
+
+    LAST_SIGNAL.store(signal, Ordering::SeqCst);
+    FROM_TERMINAL.store(from_terminal, Ordering::SeqCst);
+    RECEIVED.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Install the handler for forwarded signals, if no watcher is running yet
+fn install_handlers() -> io::Result<()> {
+    let mut handlers = HANDLERS.lock().expect("Failed to lock handlers");
+
+    if handlers.0 == 0 {
+        for signal in FORWARDED {
+            unsafe {
+                let mut action: sigaction = zeroed();
+                let mut previous: sigaction = zeroed();
+                action.sa_sigaction =
+                    on_signal as extern "C" fn(c_int, *mut siginfo_t, *mut c_void) as usize;
+                action.sa_flags = SA_SIGINFO;
+                sigemptyset(&mut action.sa_mask);
+
+                if sigaction(signal, &action, &mut previous) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                handlers.1.push((signal, previous));
+            }
+        }
+    }
+
+    handlers.0 += 1;
+
+    Ok(())
+}
+
+/// Restore the handlers replaced by `install_handlers`, if no other watcher is running
+fn restore_handlers() {
+    let mut handlers = HANDLERS.lock().expect("Failed to lock handlers");
+    handlers.0 -= 1;
+
+    if handlers.0 == 0 {
+        for (signal, previous) in handlers.1.drain(..) {
+            unsafe { sigaction(signal, &previous, null_mut()) };
+        }
+    }
+}
+
+/// Limit a resource of a running process
+///
+/// # Arguments
+///
+/// * `pid` - The process
+/// * `resource` - The resource, like `RLIMIT_CPU`
+/// * `limit` - The limit, in the unit of the resource
+pub(crate) fn limit(pid: u32, resource: c_int, limit: u64) -> io::Result<()> {
+    let limit = rlimit {
+        rlim_cur: limit,
+        rlim_max: limit,
+    };
+
+    if unsafe { prlimit(pid as pid_t, resource as _, &limit, null_mut()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Watches a running program until it is stopped or dropped
+pub(crate) struct Watcher {
+    /// Dropped to stop the watching thread
+    stop: Option<Sender<()>>,
+    /// The watching thread, which returns whether it killed the program
+    thread: Option<JoinHandle<bool>>,
+}
+
+impl Watcher {
+    /// Start watching a program
+    ///
+    /// # Arguments
+    ///
+    /// * `pid` - The process to watch
+    /// * `timeout` - How long the program may run before it is killed
+    /// * `kill_signal` - The signal to kill the program with
+    /// * `own_terminal` - Whether the program runs in a terminal of its own, and so does not
+    ///   receive the signals sent by the terminal of this process
+    pub fn start(
+        pid: u32,
+        timeout: Option<Duration>,
+        kill_signal: c_int,
+        own_terminal: bool,
+    ) -> io::Result<Self> {
+        install_handlers()?;
+
+        let (stop, stopped) = channel::<()>();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut seen = RECEIVED.load(Ordering::SeqCst);
+
+        let thread = spawn(move || loop {
+            match stopped.recv_timeout(POLL_INTERVAL) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => return false,
+            }
+
+            let received = RECEIVED.load(Ordering::SeqCst);
+
+            if received != seen {
+                seen = received;
+
+                if own_terminal || !FROM_TERMINAL.load(Ordering::SeqCst) {
+                    unsafe { kill(pid as pid_t, LAST_SIGNAL.load(Ordering::SeqCst)) };
+                }
+            }
+
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                unsafe { kill(pid as pid_t, kill_signal) };
+                return true;
+            }
+        });
+
+        Ok(Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Stop watching the program, returning whether it was killed for running out of time
+    pub fn stop(mut self) -> bool {
+        self.finish()
+    }
+
+    /// Stop the watching thread, if it is still running
+    fn finish(&mut self) -> bool {
+        match self.thread.take() {
+            Some(thread) => {
+                self.stop.take();
+                let timed_out = thread.join().unwrap_or(false);
+                restore_handlers();
+                timed_out
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
@@ -1,31 +1,86 @@
 extern crate cbindgen;
 
 use bindgen::builder;
-use qemu::{include_qemu_plugin_h, __unbuilt_qemu_plugin_h};
+use qemu::{__unbuilt_qemu_plugin_h, include_qemu_plugin_h};
 
-use std::{env::var, fs::write, path::PathBuf};
+use std::{
+    env::var,
+    fmt::Write,
+    fs::{copy, read_to_string, write},
+    path::PathBuf,
+};
+
+/// The targets with a system call table in `syscalls`
+const SYSCALL_TARGETS: &[&str] = &[
+    "aarch64",
+    "arm",
+    "i386",
+    "loongarch64",
+    "riscv32",
+    "riscv64",
+    "x86_64",
+];
+
+/// Names of system calls that are Rust keywords, and must be raw identifiers
+const KEYWORDS: &[&str] = &["break"];
+
+/// Generate the system calls of a target from its table, as the arguments of the `sysno!`
+/// macro in `src/syscalls.rs`
+///
+/// # Arguments
+///
+/// * `table` - The contents of the table
+fn syscalls(table: &str) -> String {
+    table
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .fold(String::new(), |mut syscalls, line| {
+            let (num, name) = line
+                .split_once(' ')
+                .expect("System call tables hold `<number> <name>` lines");
+            let ident = if KEYWORDS.contains(&name) {
+                format!("r#{}", name)
+            } else {
+                name.to_string()
+            };
+            writeln!(syscalls, "    {} = {} => \"{}\",", ident, num, name).unwrap();
+            syscalls
+        })
+}
 
 fn main() {
     let out_dir = PathBuf::from(var("OUT_DIR").unwrap());
     let qemu_plugin_header = out_dir.join("qemu-plugin.h");
     let qemu_plugin_bindings = out_dir.join("qemu_plugin_bindings.rs");
 
-    // Write the qemu plugin header
+    // Write the qemu plugin header. A header from another QEMU build can be supplied with
+    // `QEMU_PLUGIN_H` to generate bindings for a newer plugin API version than the one
+    // shipped by the `qemu` crate
+    println!("cargo:rerun-if-env-changed=QEMU_PLUGIN_H");
 
     let building_docs = var("DOCS_RS").is_ok();
-    
-    
-    let qemu_plugin_header_contents = if !building_docs {
-        include_qemu_plugin_h()
+
+    if let Ok(header) = var("QEMU_PLUGIN_H") {
+        copy(header, &qemu_plugin_header).expect("Failed to copy QEMU_PLUGIN_H");
     } else {
-        __unbuilt_qemu_plugin_h()
-    };
+        let qemu_plugin_header_contents = if !building_docs {
+            include_qemu_plugin_h()
+        } else {
+            __unbuilt_qemu_plugin_h()
+        };
 
-    write(&qemu_plugin_header, &qemu_plugin_header_contents)
-        .expect("Failed to write qemu-plugin.h");
+        write(&qemu_plugin_header, &qemu_plugin_header_contents)
+            .expect("Failed to write qemu-plugin.h");
+    }
+
+    // Newer headers include glib.h for the arrays registers and memory are read into. The
+    // parts of it they use are vendored in `include`, so glib's development headers are not
+    // needed to build
+    println!("cargo:rerun-if-changed=include");
 
     let rust_bindings = builder()
         .header(qemu_plugin_header.to_str().unwrap())
+        .clang_arg("-Iinclude")
         .blocklist_function("qemu_plugin_install")
         .blocklist_item("qemu_plugin_version")
         .generate()
@@ -34,4 +89,18 @@ fn main() {
     rust_bindings
         .write_to_file(qemu_plugin_bindings)
         .expect("Couldn't write bindings for qemu-plugin.h");
+
+    // Generate the system call numbers of each target from the vendored tables
+    println!("cargo:rerun-if-changed=syscalls");
+
+    for target in SYSCALL_TARGETS {
+        let table = read_to_string(format!("syscalls/{}.tbl", target))
+            .expect("Failed to read system call table");
+
+        write(
+            out_dir.join(format!("syscalls_{}.rs", target)),
+            format!("sysno! {{\n{}}}\n", syscalls(&table)),
+        )
+        .expect("Failed to write system calls");
+    }
 }
@@ -0,0 +1,32 @@
+/*
+ * The parts of glib that qemu-plugin.h uses, so bindings for it can be generated without the
+ * glib development headers installed. QEMU and the plugins it loads share its glib, so these
+ * must match glib's own definitions. Only the public fields of the arrays are declared, as in
+ * glib, which allocates them.
+ */
+
+#ifndef CANNONBALL_GLIB_H
+#define CANNONBALL_GLIB_H
+
+#include <stddef.h>
+
+typedef char gchar;
+typedef int gint;
+typedef unsigned int guint;
+typedef unsigned char guint8;
+typedef size_t gsize;
+typedef gint gboolean;
+typedef void *gpointer;
+typedef const void *gconstpointer;
+
+typedef struct _GArray {
+    gchar *data;
+    guint len;
+} GArray;
+
+typedef struct _GByteArray {
+    guint8 *data;
+    guint len;
+} GByteArray;
+
+#endif /* CANNONBALL_GLIB_H */
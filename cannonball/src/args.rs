@@ -11,12 +11,14 @@ lazy_static! {
     static ref TRUE_STRINGS: HashSet<String> = {
         let mut set = HashSet::new();
         set.insert("true".to_string());
+        set.insert("on".to_string());
         set
     };
     /// Strings representing a false value that will be parsed into a `false` value
     static ref FALSE_STRINGS: HashSet<String> = {
         let mut set = HashSet::new();
         set.insert("false".to_string());
+        set.insert("off".to_string());
         set
     };
 }
@@ -79,4 +81,41 @@ impl Args {
 
         Self { raw, args }
     }
+
+    /// Keep the arguments of the plugin named `name` apart from those of other plugins. An
+    /// argument `name.key=value` is the argument `key=value` of this plugin only, and takes
+    /// precedence over a `key=value` given to every plugin. Arguments namespaced to another
+    /// plugin are left as they are, so plugins sharing an argument list never read each
+    /// other's
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the plugin, as in its `Identity`
+    pub fn namespaced(self, name: &str) -> Self {
+        let prefix = format!("{}.", name);
+
+        let raw: Vec<String> = self
+            .raw
+            .into_iter()
+            .map(|arg| match arg.strip_prefix(&prefix) {
+                Some(arg) => arg.to_string(),
+                None => arg,
+            })
+            .collect();
+
+        // Namespaced arguments override the others whatever order they were given in
+        let mut args = HashMap::new();
+        for (key, value) in self.args {
+            match key.strip_prefix(&prefix) {
+                Some(key) => {
+                    args.insert(key.to_string(), value);
+                }
+                None => {
+                    args.entry(key).or_insert(value);
+                }
+            }
+        }
+
+        Self { raw, args }
+    }
 }
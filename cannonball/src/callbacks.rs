@@ -10,9 +10,15 @@
 //! * `vcpu_tb_trans`
 //! * `vcpu_syscall`
 //! * `vcpu_syscall_ret`
+//! * `vcpu_discon` (with the `plugin-api-v5` feature)
 //! * `atexit`
 //! * `flush`
 //!
+//! Callbacks are Rust functions rather than `extern "C"` ones: QEMU calls trampolines in the
+//! core, which call each callback submitted for an event in turn and catch their panics (see
+//! `panic`). Callbacks registered at translation time are called the same way, through data
+//! the core allocates for them until the next flush.
+//!
 //! These can be registered statically like so. The `Lazy` bit is a little fucked, sorry about
 //! that. If you have a nicer way to do this, please let me know @novafacing everywhere fine
 //! posts and interactions are sold.
@@ -26,7 +32,7 @@
 //! use cannonball::callbacks::{StaticCallbackType, VCPUTBTransCallback};
 //! use cannonball::api::qemu_plugin_tb;
 //!
-//! extern "C" fn testfn(id: u64, tb: *mut qemu_plugin_tb) {
+//! fn testfn(id: u64, tb: *mut qemu_plugin_tb) {
 //!     println!("Hello from testfn! We are translating a TB!");
 //! }
 //!
@@ -56,23 +62,75 @@
 //!     SetupCallbackType::Setup(&scb)
 //! }
 //! ```
+//!
+//! Setup that can fail, like connecting to a consumer that is not listening, is registered
+//! as a `FallibleSetupCallback` instead. If it returns an error, the error is printed and
+//! installation of the plugin fails, so QEMU exits rather than running a plugin that was only
+//! half set up.
+//!
+//! ```
+//! use inventory;
+//! use once_cell::sync::Lazy;
+//! use cannonball::callbacks::{FallibleSetupCallback, SetupCallbackType};
+//!
+//! inventory::submit! {
+//!     static scb: Lazy<FallibleSetupCallback> = Lazy::new(|| {
+//!         FallibleSetupCallback::new(|_, args| {
+//!             if args.raw.is_empty() {
+//!                 return Err("no arguments given".into());
+//!             }
+//!
+//!             Ok(())
+//!         })
+//!     });
+//!     SetupCallbackType::FallibleSetup(&scb)
+//! }
+//! ```
+//!
+//! `AtExitCallback` takes a closure rather than a function and a data pointer, so any state
+//! needed when QEMU exits can be moved into it.
+//!
+//! ```
+//! // Example of an at exit callback reporting how long QEMU ran for
+//! use std::time::Instant;
+//!
+//! use inventory;
+//! use once_cell::sync::Lazy;
+//! use cannonball::callbacks::{AtExitCallback, StaticCallbackType};
+//!
+//! inventory::submit! {
+//!     static ecb: Lazy<AtExitCallback> = Lazy::new(|| {
+//!         let start = Instant::now();
+//!         AtExitCallback::new(move |_| println!("ran for {:?}", start.elapsed()))
+//!     });
+//!     StaticCallbackType::AtExit(&ecb)
+//! }
+//! ```
 
 use libc::c_void;
 use once_cell::sync::Lazy;
 
+use std::{error::Error, ptr::null_mut, sync::Arc};
+
+#[cfg(feature = "plugin-api-v5")]
+use crate::api::{qemu_plugin_discon_type, qemu_plugin_register_vcpu_discon_cb};
 use crate::{
     api::{
-        qemu_info_t, qemu_plugin_cb_flags_QEMU_PLUGIN_CB_NO_REGS, qemu_plugin_id_t,
-        qemu_plugin_insn, qemu_plugin_mem_rw_QEMU_PLUGIN_MEM_R, qemu_plugin_meminfo_t,
-        qemu_plugin_register_atexit_cb, qemu_plugin_register_flush_cb,
-        qemu_plugin_register_vcpu_exit_cb, qemu_plugin_register_vcpu_idle_cb,
-        qemu_plugin_register_vcpu_init_cb, qemu_plugin_register_vcpu_insn_exec_cb,
-        qemu_plugin_register_vcpu_mem_cb, qemu_plugin_register_vcpu_resume_cb,
-        qemu_plugin_register_vcpu_syscall_cb, qemu_plugin_register_vcpu_syscall_ret_cb,
-        qemu_plugin_register_vcpu_tb_exec_cb, qemu_plugin_register_vcpu_tb_trans_cb,
-        qemu_plugin_tb,
+        qemu_info_t, qemu_plugin_cb_flags, qemu_plugin_cb_flags_QEMU_PLUGIN_CB_NO_REGS,
+        qemu_plugin_cb_flags_QEMU_PLUGIN_CB_R_REGS, qemu_plugin_id_t, qemu_plugin_insn,
+        qemu_plugin_meminfo_t, qemu_plugin_register_atexit_cb, qemu_plugin_register_vcpu_exit_cb,
+        qemu_plugin_register_vcpu_idle_cb, qemu_plugin_register_vcpu_init_cb,
+        qemu_plugin_register_vcpu_insn_exec_cb, qemu_plugin_register_vcpu_mem_cb,
+        qemu_plugin_register_vcpu_resume_cb, qemu_plugin_register_vcpu_syscall_cb,
+        qemu_plugin_register_vcpu_syscall_ret_cb, qemu_plugin_register_vcpu_tb_exec_cb,
+        qemu_plugin_register_vcpu_tb_trans_cb, qemu_plugin_tb,
     },
     args::Args,
+    exit, guest, instrument,
+    mem::MemAccessKind,
+    panic::guard,
+    signal,
+    tb::{alloc, on_tb_handle_exec, TBHandle},
 };
 
 /// Trait for a callback that registers itself with QEMU during plugin installation
@@ -123,16 +181,40 @@ impl SetupCallback {
     }
 }
 
+/// Why a `FallibleSetupCallback` failed
+pub type SetupError = Box<dyn Error + Send + Sync>;
+
+/// Setup callback that can fail, failing the installation of the plugin
+pub struct FallibleSetupCallback {
+    /// Callback receiving a pointer the qemu info struct and the arguments passed to the plugin
+    pub cb: Box<dyn Fn(*const qemu_info_t, &Args) -> Result<(), SetupError> + Send + Sync>,
+}
+
+impl FallibleSetupCallback {
+    /// Instantiate a new `FallibleSetupCallback` with the given callback
+    ///
+    /// # Arguments
+    ///
+    /// * `cb` - Callback receiving a pointer the qemu info struct and the arguments passed to the plugin
+    pub fn new(
+        cb: impl Fn(*const qemu_info_t, &Args) -> Result<(), SetupError> + Send + Sync + 'static,
+    ) -> Self {
+        Self { cb: Box::new(cb) }
+    }
+}
+
 /// Enum wrapper for the setup callback and other non-QEMU callbacks
 pub enum SetupCallbackType {
     /// A setup callback
     Setup(&'static Lazy<SetupCallback>),
+    /// A setup callback that can fail
+    FallibleSetup(&'static Lazy<FallibleSetupCallback>),
 }
 
 /// Callback fired when a VCPU is initialized
 pub struct VCPUInitCallback {
     /// Callback receiving the plugin id and the vcpu id
-    pub cb: unsafe extern "C" fn(u64, u32) -> (),
+    pub cb: unsafe fn(u64, u32) -> (),
 }
 
 /// Callback fired when a VCPU is initialized. In user mode, this only happens once, but in
@@ -143,22 +225,16 @@ impl VCPUInitCallback {
     /// # Arguments
     ///
     /// * `cb` - Callback receiving the plugin id and the vcpu id
-    pub fn new(cb: unsafe extern "C" fn(u64, u32) -> ()) -> Self {
+    pub fn new(cb: unsafe fn(u64, u32) -> ()) -> Self {
         Self { cb }
     }
 }
 
-impl Register for VCPUInitCallback {
-    fn register(&self, id: u64) {
-        unsafe { qemu_plugin_register_vcpu_init_cb(id as qemu_plugin_id_t, Some(self.cb)) };
-    }
-}
-
 /// Callback fired when a VCPU exits. In user mode, this only happens once, but in
 /// system mode this can happen any number of times
 pub struct VCPUExitCallback {
     /// Callback receiving the plugin id and the vcpu id
-    pub cb: unsafe extern "C" fn(u64, u32) -> (),
+    pub cb: unsafe fn(u64, u32) -> (),
 }
 
 impl VCPUExitCallback {
@@ -167,21 +243,15 @@ impl VCPUExitCallback {
     /// # Arguments
     ///
     /// * `cb` - Callback receiving the plugin id and the vcpu id
-    pub fn new(cb: unsafe extern "C" fn(u64, u32) -> ()) -> Self {
+    pub fn new(cb: unsafe fn(u64, u32) -> ()) -> Self {
         Self { cb }
     }
 }
 
-impl Register for VCPUExitCallback {
-    fn register(&self, id: u64) {
-        unsafe { qemu_plugin_register_vcpu_exit_cb(id as qemu_plugin_id_t, Some(self.cb)) };
-    }
-}
-
 /// Callback fired when a VCPU starts to idle. This is only fired in system mode
 pub struct VCPUIdleCallback {
     /// Callback receiving the plugin id and the vcpu id
-    pub cb: unsafe extern "C" fn(u64, u32) -> (),
+    pub cb: unsafe fn(u64, u32) -> (),
 }
 
 impl VCPUIdleCallback {
@@ -190,21 +260,14 @@ impl VCPUIdleCallback {
     /// # Arguments
     ///
     /// * `cb` - Callback receiving the plugin id and the vcpu id
-    pub fn new(cb: unsafe extern "C" fn(u64, u32) -> ()) -> Self {
+    pub fn new(cb: unsafe fn(u64, u32) -> ()) -> Self {
         Self { cb }
     }
 }
 
-/// Callback fired when a VCPU resumes from idle. This is only fired in system mode
-impl Register for VCPUIdleCallback {
-    fn register(&self, id: u64) {
-        unsafe { qemu_plugin_register_vcpu_idle_cb(id as qemu_plugin_id_t, Some(self.cb)) };
-    }
-}
-
 /// Callback fired when a VCPU resumes from idle. This is only fired in system mode
 pub struct VCPUResumeCallback {
-    pub cb: unsafe extern "C" fn(u64, u32) -> (),
+    pub cb: unsafe fn(u64, u32) -> (),
 }
 
 impl VCPUResumeCallback {
@@ -213,21 +276,15 @@ impl VCPUResumeCallback {
     /// # Arguments
     ///
     /// * `cb` - Callback receiving the plugin id and the vcpu id
-    pub fn new(cb: unsafe extern "C" fn(u64, u32) -> ()) -> Self {
+    pub fn new(cb: unsafe fn(u64, u32) -> ()) -> Self {
         Self { cb }
     }
 }
 
-impl Register for VCPUResumeCallback {
-    fn register(&self, id: u64) {
-        unsafe { qemu_plugin_register_vcpu_resume_cb(id as qemu_plugin_id_t, Some(self.cb)) };
-    }
-}
-
 /// Callback fired when translation block is translated by TCG
 pub struct VCPUTBTransCallback {
     /// Callback receiving the plugin id and a pointer to the *opaque* translation block object
-    pub cb: unsafe extern "C" fn(u64, *mut qemu_plugin_tb) -> (),
+    pub cb: unsafe fn(u64, *mut qemu_plugin_tb) -> (),
 }
 
 impl VCPUTBTransCallback {
@@ -236,21 +293,18 @@ impl VCPUTBTransCallback {
     /// # Arguments
     ///
     /// * `cb` - Callback receiving the plugin id and a pointer to the *opaque* translation block object
-    pub fn new(cb: unsafe extern "C" fn(u64, *mut qemu_plugin_tb) -> ()) -> Self {
+    pub fn new(cb: unsafe fn(u64, *mut qemu_plugin_tb) -> ()) -> Self {
         Self { cb }
     }
 }
 
-impl Register for VCPUTBTransCallback {
-    fn register(&self, id: u64) {
-        unsafe { qemu_plugin_register_vcpu_tb_trans_cb(id, Some(self.cb)) };
-    }
-}
+/// A function receiving the plugin id, vcpu id, syscall number, and arguments 0 through 7
+type SyscallFn = unsafe fn(u64, u32, i64, u64, u64, u64, u64, u64, u64, u64, u64);
 
 /// Callback fired when a system call is executed
 pub struct VCPUSyscallCallback {
     /// Callback receiving the plugin id, vcpu id, syscall number, and arguments 0 through 7
-    pub cb: unsafe extern "C" fn(u64, u32, i64, u64, u64, u64, u64, u64, u64, u64, u64) -> (),
+    pub cb: SyscallFn,
 }
 
 impl VCPUSyscallCallback {
@@ -264,24 +318,16 @@ impl VCPUSyscallCallback {
     /// the same plugin id and vcpu id, and the return value of the system call can be associated
     /// with its arguments by tracking the next return callback with the same plugin id and vcpu id
     /// as this system call callback.
-    pub fn new(
-        cb: unsafe extern "C" fn(u64, u32, i64, u64, u64, u64, u64, u64, u64, u64, u64) -> (),
-    ) -> Self {
+    pub fn new(cb: SyscallFn) -> Self {
         Self { cb }
     }
 }
 
-impl Register for VCPUSyscallCallback {
-    fn register(&self, id: u64) {
-        unsafe { qemu_plugin_register_vcpu_syscall_cb(id as qemu_plugin_id_t, Some(self.cb)) };
-    }
-}
-
 /// Callback fired when a system call returns
 pub struct VCPUSyscallRetCallback {
     /// Callback receiving the plugin id, vcpu id, system call number, and the return value
     /// of the system call
-    pub cb: unsafe extern "C" fn(u64, u32, i64, i64) -> (),
+    pub cb: unsafe fn(u64, u32, i64, i64) -> (),
 }
 
 impl VCPUSyscallRetCallback {
@@ -294,91 +340,109 @@ impl VCPUSyscallRetCallback {
     /// This callback will be the the next callback fired after the `VCPUSyscallCallback` callback
     /// for the same vcpu id and plugin id. Therefore it is sufficient to track these two values
     /// to determine which syscall is returning and associate a return value to the arguments.
-    pub fn new(cb: unsafe extern "C" fn(u64, u32, i64, i64) -> ()) -> Self {
+    pub fn new(cb: unsafe fn(u64, u32, i64, i64) -> ()) -> Self {
         Self { cb }
     }
 }
 
-impl Register for VCPUSyscallRetCallback {
-    fn register(&self, id: u64) {
-        unsafe { qemu_plugin_register_vcpu_syscall_ret_cb(id as qemu_plugin_id_t, Some(self.cb)) };
+/// Callback fired when a VCPU leaves the code it was executing other than by a branch: when it
+/// takes an interrupt or an exception, or makes a host call (like semihosting). This is mostly
+/// seen in system mode, where the guest kernel handles these
+#[cfg(feature = "plugin-api-v5")]
+pub struct VCPUDisconCallback {
+    /// Callback receiving the plugin id, vcpu id, the type of the discontinuity, the address of
+    /// the instruction it happened at, and the address execution continues at
+    pub cb: unsafe fn(u64, u32, qemu_plugin_discon_type, u64, u64) -> (),
+    /// The types of discontinuities `cb` is fired for, as a mask of
+    /// `qemu_plugin_discon_type_QEMU_PLUGIN_DISCON_*` values
+    pub types: qemu_plugin_discon_type,
+}
+
+#[cfg(feature = "plugin-api-v5")]
+impl VCPUDisconCallback {
+    /// Instantiate a new `VCPUDisconCallback` with the given callback
+    ///
+    /// # Arguments
+    ///
+    /// * `cb` - Callback receiving the plugin id, vcpu id, the type of the discontinuity, the
+    ///   address of the instruction it happened at, and the address execution continues at
+    /// * `types` - The types of discontinuities `cb` is fired for, like
+    ///   `qemu_plugin_discon_type_QEMU_PLUGIN_DISCON_ALL`
+    pub fn new(
+        cb: unsafe fn(u64, u32, qemu_plugin_discon_type, u64, u64) -> (),
+        types: qemu_plugin_discon_type,
+    ) -> Self {
+        Self { cb, types }
     }
 }
 
+/// A closure called with the plugin id when QEMU exits
+type AtExitFn = dyn Fn(u64) + Send + Sync;
+
 /// Callback fired when the plugin exits. Unless manually unregistered, this callback will be fired
-/// when QEMU exits.
-pub struct AtExitCallback<T>
-where
-    T: Send + Sync + Into<*mut c_void> + 'static,
-{
-    /// Callback receiving the plugin id and a pointer to `data`
-    pub cb: unsafe extern "C" fn(u64, *mut c_void) -> (),
-    /// The data passed to `cb` when it is fired
-    pub data: T,
+/// when QEMU exits. The callback is a closure, so any state it needs can be moved into it
+pub struct AtExitCallback {
+    /// Closure receiving the plugin id. Every registration holds a reference to it, so it lives
+    /// until QEMU fires the callback even if the `AtExitCallback` does not
+    cb: Arc<AtExitFn>,
 }
 
-impl<T> AtExitCallback<T>
-where
-    T: Send + Sync + Into<*mut c_void> + 'static,
-{
-    /// Instantiate a new `AtExitCallback` with the given callback and data
+impl AtExitCallback {
+    /// Instantiate a new `AtExitCallback` with the given closure
     ///
     /// # Arguments
     ///
-    /// * `cb` - Callback receiving the plugin id and a pointer to `data`
-    /// * `data` - The data passed to `cb` when it is fired, this can be anything and will
-    ///           be passed to `cb` as a pointer to the original `data` value
-    pub fn new(cb: unsafe extern "C" fn(u64, *mut c_void) -> (), data: T) -> Self {
-        Self { cb, data }
+    /// * `cb` - Closure receiving the plugin id
+    pub fn new(cb: impl Fn(u64) + Send + Sync + 'static) -> Self {
+        Self { cb: Arc::new(cb) }
     }
 }
 
-impl<T> Register for AtExitCallback<T>
-where
-    T: Send + Sync + Into<*mut c_void> + 'static,
-{
+impl Register for AtExitCallback {
     fn register(&self, id: u64) {
-        unsafe {
-            qemu_plugin_register_atexit_cb(
-                id as qemu_plugin_id_t,
-                Some(self.cb),
-                Box::into_raw(Box::new(&self.data)) as *mut c_void,
-            )
-        };
+        // Freed by the trampoline. A registration dropped by a reset is never fired, so its
+        // reference is leaked, which keeps the closure alive but costs no more than a pointer
+        let data = Box::into_raw(Box::new(self.cb.clone())) as *mut c_void;
+
+        unsafe { qemu_plugin_register_atexit_cb(id as qemu_plugin_id_t, Some(on_atexit), data) };
     }
 }
 
-pub struct AtExitData(*mut c_void);
-
-unsafe impl Send for AtExitData {}
-unsafe impl Sync for AtExitData {}
-
-impl Into<*mut c_void> for AtExitData {
-    fn into(self) -> *mut c_void {
-        self.0
-    }
+/// Trampoline for `AtExitCallback`, calling the closure passed as `data` by `register`
+///
+/// # Safety
+///
+/// `data` must be the pointer `register` passed, and QEMU fires the callback at most once, so
+/// the box it points to can be taken back and freed here
+unsafe extern "C" fn on_atexit(id: qemu_plugin_id_t, data: *mut c_void) {
+    let cb = Box::from_raw(data as *mut Arc<AtExitFn>);
+    guard("atexit", || cb(id));
 }
 
-// TODO: Document flush callback
-/// Callback fired when ??? (No documentation in QEMU on when exactly a flush occurs). Please
-/// open an issue if you know what this callback is for!
+/// Callback fired when QEMU flushes the translation cache. Every translated block is discarded
+/// along with the callbacks registered on it, and blocks are translated again the next time
+/// they execute. Any pointer or cache a plugin keeps about translated code is stale at this
+/// point and should be dropped here. Per-TB data owned by the core (`TBHandle` and `TBData`)
+/// has already been freed when this is called. See the `tb` module for when flushes happen
 pub struct FlushCallback {
-    pub cb: unsafe extern "C" fn(u64) -> (),
+    /// Callback receiving the plugin id
+    pub cb: unsafe fn(u64) -> (),
 }
 
 impl FlushCallback {
-    pub fn new(cb: unsafe extern "C" fn(u64) -> ()) -> Self {
+    /// Instantiate a new `FlushCallback` with the given callback
+    ///
+    /// # Arguments
+    ///
+    /// * `cb` - Callback receiving the plugin id
+    pub fn new(cb: unsafe fn(u64) -> ()) -> Self {
         Self { cb }
     }
 }
 
-impl Register for FlushCallback {
-    fn register(&self, id: u64) {
-        unsafe { qemu_plugin_register_flush_cb(id as qemu_plugin_id_t, Some(self.cb)) };
-    }
-}
-
-/// Variant container for static callbacks that are called when a plugin is loaded
+/// Variant container for static callbacks that are called when a plugin is loaded. QEMU holds
+/// a single callback of each kind for a plugin, so the core registers its own for every kind
+/// submitted and calls each callback of that kind from it, catching their panics (see `panic`)
 pub enum StaticCallbackType {
     VCPUInit(&'static Lazy<VCPUInitCallback>),
     VCPUExit(&'static Lazy<VCPUExitCallback>),
@@ -387,33 +451,201 @@ pub enum StaticCallbackType {
     VCPUTBTrans(&'static Lazy<VCPUTBTransCallback>),
     VCPUSyscall(&'static Lazy<VCPUSyscallCallback>),
     VCPUSyscallRet(&'static Lazy<VCPUSyscallRetCallback>),
-    AtExit(&'static Lazy<AtExitCallback<AtExitData>>),
+    #[cfg(feature = "plugin-api-v5")]
+    VCPUDiscon(&'static Lazy<VCPUDisconCallback>),
+    AtExit(&'static Lazy<AtExitCallback>),
     Flush(&'static Lazy<FlushCallback>),
 }
 
 impl Register for StaticCallbackType {
     fn register(&self, id: u64) {
-        match self {
-            StaticCallbackType::VCPUInit(cb) => cb.register(id),
-            StaticCallbackType::VCPUExit(cb) => cb.register(id),
-            StaticCallbackType::VCPUIdle(cb) => cb.register(id),
-            StaticCallbackType::VCPUResume(cb) => cb.register(id),
-            StaticCallbackType::VCPUTBTrans(cb) => cb.register(id),
-            StaticCallbackType::VCPUSyscall(cb) => cb.register(id),
-            StaticCallbackType::VCPUSyscallRet(cb) => cb.register(id),
-            StaticCallbackType::AtExit(cb) => cb.register(id),
-            StaticCallbackType::Flush(cb) => cb.register(id),
+        // Registering the same callback again for another callback of its kind changes nothing
+        unsafe {
+            match self {
+                StaticCallbackType::VCPUInit(_) => {
+                    qemu_plugin_register_vcpu_init_cb(id, Some(on_vcpu_init))
+                }
+                StaticCallbackType::VCPUExit(_) => {
+                    qemu_plugin_register_vcpu_exit_cb(id, Some(on_vcpu_exit))
+                }
+                StaticCallbackType::VCPUIdle(_) => {
+                    qemu_plugin_register_vcpu_idle_cb(id, Some(on_vcpu_idle))
+                }
+                StaticCallbackType::VCPUResume(_) => {
+                    qemu_plugin_register_vcpu_resume_cb(id, Some(on_vcpu_resume))
+                }
+                StaticCallbackType::VCPUTBTrans(_) => {
+                    qemu_plugin_register_vcpu_tb_trans_cb(id, Some(on_vcpu_tb_trans))
+                }
+                StaticCallbackType::VCPUSyscall(_) => {
+                    qemu_plugin_register_vcpu_syscall_cb(id, Some(on_vcpu_syscall))
+                }
+                StaticCallbackType::VCPUSyscallRet(_) => {
+                    qemu_plugin_register_vcpu_syscall_ret_cb(id, Some(on_vcpu_syscall_ret))
+                }
+                // Each type of discontinuity is registered separately, so callbacks for
+                // different types add up
+                #[cfg(feature = "plugin-api-v5")]
+                StaticCallbackType::VCPUDiscon(cb) => {
+                    qemu_plugin_register_vcpu_discon_cb(id, cb.types, Some(on_vcpu_discon))
+                }
+                StaticCallbackType::AtExit(_) => {
+                    qemu_plugin_register_atexit_cb(id, Some(on_static_atexit), null_mut())
+                }
+                // The core registers its own flush callback, which frees per-TB data before
+                // calling the plugin's
+                StaticCallbackType::Flush(_) => {}
+            }
         }
     }
 }
 
+/// Call every static callback of a kind, catching the panic of each
+///
+/// # Arguments
+///
+/// * `callback` - The name of the kind, for the log
+/// * `f` - Calls a static callback if it is of the kind
+fn dispatch(callback: &str, f: impl Fn(&StaticCallbackType)) {
+    for static_cb in inventory::iter::<StaticCallbackType> {
+        guard(callback, || f(static_cb));
+    }
+}
+
+/// Called by QEMU when a VCPU is initialized
+extern "C" fn on_vcpu_init(id: qemu_plugin_id_t, vcpu_index: u32) {
+    dispatch("vcpu_init", |static_cb| {
+        if let StaticCallbackType::VCPUInit(cb) = static_cb {
+            unsafe { (cb.cb)(id, vcpu_index) };
+        }
+    });
+}
+
+/// Called by QEMU when a VCPU exits
+extern "C" fn on_vcpu_exit(id: qemu_plugin_id_t, vcpu_index: u32) {
+    dispatch("vcpu_exit", |static_cb| {
+        if let StaticCallbackType::VCPUExit(cb) = static_cb {
+            unsafe { (cb.cb)(id, vcpu_index) };
+        }
+    });
+}
+
+/// Called by QEMU when a VCPU starts to idle
+extern "C" fn on_vcpu_idle(id: qemu_plugin_id_t, vcpu_index: u32) {
+    dispatch("vcpu_idle", |static_cb| {
+        if let StaticCallbackType::VCPUIdle(cb) = static_cb {
+            unsafe { (cb.cb)(id, vcpu_index) };
+        }
+    });
+}
+
+/// Called by QEMU when a VCPU resumes from idle
+extern "C" fn on_vcpu_resume(id: qemu_plugin_id_t, vcpu_index: u32) {
+    dispatch("vcpu_resume", |static_cb| {
+        if let StaticCallbackType::VCPUResume(cb) = static_cb {
+            unsafe { (cb.cb)(id, vcpu_index) };
+        }
+    });
+}
+
+/// Called by QEMU when a translation block is translated. The core learns the guest base
+/// from it too (see `guest`), which is why it is also registered without any
+/// `VCPUTBTransCallback`, and tells the instruction set of the block once for all of them
+/// (see `instrument::isa_mode`)
+pub(crate) extern "C" fn on_vcpu_tb_trans(id: qemu_plugin_id_t, tb: *mut qemu_plugin_tb) {
+    guard("vcpu_tb_trans", || {
+        instrument::translating(Some(tb));
+        guest::on_tb_trans(tb);
+    });
+    dispatch("vcpu_tb_trans", |static_cb| {
+        if let StaticCallbackType::VCPUTBTrans(cb) = static_cb {
+            unsafe { (cb.cb)(id, tb) };
+        }
+    });
+    instrument::translating(None);
+}
+
+/// Called by QEMU when a system call is executed. The core watches for the guest exiting and
+/// installing signal handlers from it too (see `exit` and `signal`), which is why it is also
+/// registered without any `VCPUSyscallCallback`
+#[allow(clippy::too_many_arguments)]
+pub(crate) extern "C" fn on_vcpu_syscall(
+    id: qemu_plugin_id_t,
+    vcpu_index: u32,
+    num: i64,
+    a1: u64,
+    a2: u64,
+    a3: u64,
+    a4: u64,
+    a5: u64,
+    a6: u64,
+    a7: u64,
+    a8: u64,
+) {
+    guard("vcpu_syscall", || {
+        exit::on_syscall(num, a1, a2, a3);
+        signal::on_syscall(vcpu_index, num, a1, a2);
+    });
+    dispatch("vcpu_syscall", |static_cb| {
+        if let StaticCallbackType::VCPUSyscall(cb) = static_cb {
+            unsafe { (cb.cb)(id, vcpu_index, num, a1, a2, a3, a4, a5, a6, a7, a8) };
+        }
+    });
+}
+
+/// Called by QEMU when a system call returns. The core learns signal handlers from it too
+/// (see `signal`), which is why it is also registered without any `VCPUSyscallRetCallback`
+pub(crate) extern "C" fn on_vcpu_syscall_ret(
+    id: qemu_plugin_id_t,
+    vcpu_index: u32,
+    num: i64,
+    ret: i64,
+) {
+    guard("vcpu_syscall_ret", || {
+        signal::on_syscall_ret(vcpu_index, num, ret)
+    });
+    dispatch("vcpu_syscall_ret", |static_cb| {
+        if let StaticCallbackType::VCPUSyscallRet(cb) = static_cb {
+            unsafe { (cb.cb)(id, vcpu_index, num, ret) };
+        }
+    });
+}
+
+/// Called by QEMU when a VCPU leaves the code it was executing other than by a branch. Only
+/// the callbacks registered for the type of the discontinuity are called
+#[cfg(feature = "plugin-api-v5")]
+extern "C" fn on_vcpu_discon(
+    id: qemu_plugin_id_t,
+    vcpu_index: u32,
+    discon_type: qemu_plugin_discon_type,
+    from_pc: u64,
+    to_pc: u64,
+) {
+    dispatch("vcpu_discon", |static_cb| {
+        if let StaticCallbackType::VCPUDiscon(cb) = static_cb {
+            if cb.types & discon_type != 0 {
+                unsafe { (cb.cb)(id, vcpu_index, discon_type, from_pc, to_pc) };
+            }
+        }
+    });
+}
+
+/// Called by QEMU when it exits, for the `AtExitCallback`s submitted to `inventory`
+extern "C" fn on_static_atexit(id: qemu_plugin_id_t, _data: *mut c_void) {
+    dispatch("atexit", |static_cb| {
+        if let StaticCallbackType::AtExit(cb) = static_cb {
+            (cb.cb)(id);
+        }
+    });
+}
+
 /// Callback fired when a translation block is executed
 pub struct VCPUTBExecCallback<T>
 where
     T: Send + Sync + Clone + Into<*mut c_void> + 'static,
 {
     /// Callback receiving the vcpu id and a pointer to the `data` field
-    pub cb: unsafe extern "C" fn(u32, *mut c_void) -> (),
+    pub cb: unsafe fn(u32, *mut c_void) -> (),
     /// Data passed to `cb` when it is fired
     pub data: T,
 }
@@ -427,9 +659,10 @@ where
     /// # Arguments
     ///
     /// * `cb` - Callback receiving the vcpu id and a pointer to the `data` field
-    /// * `data` - Data passed to `cb` when it is fired, this can be anything and will
-    ///           be passed to `cb` as a pointer to the original `data` value
-    pub fn new(cb: unsafe extern "C" fn(u32, *mut c_void) -> (), data: T) -> Self {
+    /// * `data` - Data passed to `cb` when it is fired, as a pointer to the original value.
+    ///   This is a `TBData` for data freed with the translated code, or a `CallbackData`
+    ///   for data that outlives it
+    pub fn new(cb: unsafe fn(u32, *mut c_void) -> (), data: T) -> Self {
         Self { cb, data }
     }
 }
@@ -439,11 +672,56 @@ where
     T: Send + Sync + Clone + Into<*mut c_void> + 'static,
 {
     fn register(&self, tb: *mut qemu_plugin_tb) {
-        let data = self.data.clone().into();
+        let exec = Exec::alloc("vcpu_tb_exec", self.cb, self.data.clone().into());
         unsafe {
             qemu_plugin_register_vcpu_tb_exec_cb(
                 tb,
-                Some(self.cb),
+                Some(on_exec),
+                qemu_plugin_cb_flags_QEMU_PLUGIN_CB_NO_REGS,
+                exec,
+            )
+        };
+    }
+}
+
+/// Callback fired when a translation block is executed, receiving a `TBHandle` describing
+/// the block instead of a raw data pointer
+pub struct VCPUTBHandleCallback<T>
+where
+    T: Send + Sync + Clone + 'static,
+{
+    /// Callback receiving the vcpu id and the handle of the block, which holds a copy of `data`
+    pub cb: fn(u32, &TBHandle),
+    /// Data attached to the handle of each block the callback is registered on
+    pub data: T,
+}
+
+impl<T> VCPUTBHandleCallback<T>
+where
+    T: Send + Sync + Clone + 'static,
+{
+    /// Instantiate a new `VCPUTBHandleCallback` with the given callback and data
+    ///
+    /// # Arguments
+    ///
+    /// * `cb` - Callback receiving the vcpu id and the handle of the block
+    /// * `data` - Data attached to the handle of each block the callback is registered on,
+    ///   retrieved in `cb` with `TBHandle::data`
+    pub fn new(cb: fn(u32, &TBHandle), data: T) -> Self {
+        Self { cb, data }
+    }
+}
+
+impl<T> RegisterTBExec for VCPUTBHandleCallback<T>
+where
+    T: Send + Sync + Clone + 'static,
+{
+    fn register(&self, tb: *mut qemu_plugin_tb) {
+        let data = TBHandle::alloc(tb, self.cb, self.data.clone());
+        unsafe {
+            qemu_plugin_register_vcpu_tb_exec_cb(
+                tb,
+                Some(on_tb_handle_exec),
                 qemu_plugin_cb_flags_QEMU_PLUGIN_CB_NO_REGS,
                 data,
             )
@@ -457,9 +735,11 @@ where
     T: Send + Sync + Clone + Into<*mut c_void> + 'static,
 {
     /// Callback receiving the vcpu id and a pointer to the `data` field
-    pub cb: unsafe extern "C" fn(u32, *mut c_void) -> (),
+    pub cb: unsafe fn(u32, *mut c_void) -> (),
     /// Data passed to `cb` when it is fired
     pub data: T,
+    /// Whether `cb` accesses the registers of the VCPU
+    pub flags: qemu_plugin_cb_flags,
 }
 
 impl<T> VCPUInsnExecCallback<T>
@@ -471,10 +751,22 @@ where
     /// # Arguments
     ///
     /// * `cb` - Callback receiving the vcpu id and a pointer to the `data` field
-    /// * `data` - Data passed to `cb` when it is fired, this can be anything and will
-    ///           be passed to `cb` as a pointer to the original `data` value
-    pub fn new(cb: unsafe extern "C" fn(u32, *mut c_void) -> (), data: T) -> Self {
-        Self { cb, data }
+    /// * `data` - Data passed to `cb` when it is fired, as a pointer to the original value.
+    ///   This is a `TBData` for data freed with the translated code, or a `CallbackData`
+    ///   for data that outlives it
+    pub fn new(cb: unsafe fn(u32, *mut c_void) -> (), data: T) -> Self {
+        Self {
+            cb,
+            data,
+            flags: qemu_plugin_cb_flags_QEMU_PLUGIN_CB_NO_REGS,
+        }
+    }
+
+    /// Let `cb` read the registers of the VCPU, which QEMU then keeps up to date before
+    /// firing it. This makes the instruction slower to execute
+    pub fn reading_registers(mut self) -> Self {
+        self.flags = qemu_plugin_cb_flags_QEMU_PLUGIN_CB_R_REGS;
+        self
     }
 }
 
@@ -483,14 +775,9 @@ where
     T: Send + Sync + Clone + Into<*mut c_void> + 'static,
 {
     fn register(&self, insn: *mut qemu_plugin_insn) {
-        let data: *mut c_void = self.data.clone().into();
+        let exec = Exec::alloc("vcpu_insn_exec", self.cb, self.data.clone().into());
         unsafe {
-            qemu_plugin_register_vcpu_insn_exec_cb(
-                insn,
-                Some(self.cb),
-                qemu_plugin_cb_flags_QEMU_PLUGIN_CB_NO_REGS,
-                data,
-            );
+            qemu_plugin_register_vcpu_insn_exec_cb(insn, Some(on_exec), self.flags, exec);
         };
     }
 }
@@ -502,28 +789,44 @@ where
 {
     /// Callback receiving the vcpu id, the opaque memory info object, the virtual address of the
     /// memory access, and a pointer to the `data` field
-    pub cb: unsafe extern "C" fn(u32, qemu_plugin_meminfo_t, u64, *mut c_void) -> (),
+    pub cb: unsafe fn(u32, qemu_plugin_meminfo_t, u64, *mut c_void) -> (),
     /// Data passed to `cb` when it is fired
     pub data: T,
+    /// The accesses `cb` is fired for
+    pub access: MemAccessKind,
 }
 
 impl<T> VCPUMemCallback<T>
 where
     T: Send + Sync + Clone + Into<*mut c_void> + 'static,
 {
-    /// Instantiate a new `VCPUMemCallback` with the given callback and data
+    /// Instantiate a new `VCPUMemCallback` with the given callback and data, fired for both
+    /// loads and stores
     ///
     /// # Arguments
     ///
     /// * `cb` - Callback receiving the vcpu id, the opaque memory info object, the virtual address of the
     ///          memory access, and a pointer to the `data` field
-    /// * `data` - Data passed to `cb` when it is fired, this can be anything and will
-    ///           be passed to `cb` as a pointer to the original `data` value
-    pub fn new(
-        cb: unsafe extern "C" fn(u32, qemu_plugin_meminfo_t, u64, *mut c_void) -> (),
-        data: T,
-    ) -> Self {
-        Self { cb, data }
+    /// * `data` - Data passed to `cb` when it is fired, as a pointer to the original value.
+    ///   This is a `TBData` for data freed with the translated code, or a `CallbackData`
+    ///   for data that outlives it
+    pub fn new(cb: unsafe fn(u32, qemu_plugin_meminfo_t, u64, *mut c_void) -> (), data: T) -> Self {
+        Self {
+            cb,
+            data,
+            access: MemAccessKind::ReadWrite,
+        }
+    }
+
+    /// Only fire `cb` for some accesses. Instrumenting fewer accesses makes the instruction
+    /// faster to execute
+    ///
+    /// # Arguments
+    ///
+    /// * `access` - The accesses to fire `cb` for
+    pub fn accesses(mut self, access: MemAccessKind) -> Self {
+        self.access = access;
+        self
     }
 }
 
@@ -532,15 +835,82 @@ where
     T: Send + Sync + Clone + Into<*mut c_void> + 'static,
 {
     fn register(&self, insn: *mut qemu_plugin_insn) {
-        let data = self.data.clone().into();
+        let mem = alloc(Mem {
+            cb: self.cb,
+            data: self.data.clone().into(),
+        });
         unsafe {
             qemu_plugin_register_vcpu_mem_cb(
                 insn,
-                Some(self.cb),
+                Some(on_mem),
                 qemu_plugin_cb_flags_QEMU_PLUGIN_CB_NO_REGS,
-                qemu_plugin_mem_rw_QEMU_PLUGIN_MEM_R,
-                data,
+                self.access.into(),
+                mem,
             );
         };
     }
 }
+
+/// An execution callback registered on translated code, with its data. Allocated by the core
+/// when the callback is registered, and freed on the next flush
+struct Exec {
+    /// The name of the callback, for the log if it panics
+    callback: &'static str,
+    /// The callback
+    cb: unsafe fn(u32, *mut c_void),
+    /// The data it was registered with
+    data: *mut c_void,
+}
+
+// The data is shared with QEMU and every VCPU as a raw pointer, like it is without a trampoline
+unsafe impl Send for Exec {}
+unsafe impl Sync for Exec {}
+
+impl Exec {
+    /// Allocate an execution callback until the next flush, returning the pointer to pass to
+    /// `on_exec`
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - The name of the callback
+    /// * `cb` - The callback
+    /// * `data` - The data it was registered with
+    fn alloc(
+        callback: &'static str,
+        cb: unsafe fn(u32, *mut c_void),
+        data: *mut c_void,
+    ) -> *mut c_void {
+        alloc(Self { callback, cb, data })
+    }
+}
+
+/// Called by QEMU when translated code with an execution callback executes, with the `Exec`
+/// allocated for it as the data pointer
+unsafe extern "C" fn on_exec(vcpu_index: u32, exec: *mut c_void) {
+    let exec = &*(exec as *const Exec);
+    guard(exec.callback, || (exec.cb)(vcpu_index, exec.data));
+}
+
+/// A memory callback registered on an instruction, with its data. Allocated by the core when
+/// the callback is registered, and freed on the next flush
+struct Mem {
+    /// The callback
+    cb: unsafe fn(u32, qemu_plugin_meminfo_t, u64, *mut c_void),
+    /// The data it was registered with
+    data: *mut c_void,
+}
+
+unsafe impl Send for Mem {}
+unsafe impl Sync for Mem {}
+
+/// Called by QEMU when an instruction with a memory callback accesses memory, with the `Mem`
+/// allocated for it as the data pointer
+unsafe extern "C" fn on_mem(
+    vcpu_index: u32,
+    info: qemu_plugin_meminfo_t,
+    vaddr: u64,
+    mem: *mut c_void,
+) {
+    let mem = &*(mem as *const Mem);
+    guard("vcpu_mem", || (mem.cb)(vcpu_index, info, vaddr, mem.data));
+}
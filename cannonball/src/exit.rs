@@ -0,0 +1,186 @@
+//! How the guest exits, in user mode
+//!
+//! QEMU fires the `atexit` callback whether the guest exited or was killed by a signal, and
+//! does not tell plugins which: under user mode emulation, a fatal signal makes QEMU run the
+//! same exit path before it dumps core. The core tells the two apart by watching the system
+//! calls of the guest, on the targets `syscalls` knows, so `atexit` callbacks can ask
+//! `status` how the guest went. A guest that exited called `exit_group`, or `exit` as the
+//! last system call of its last thread. A guest that did not was killed by a signal, either
+//! one it sent itself with `kill`, `tkill` or `tgkill`, like `abort` does with `SIGABRT`, or
+//! one raised by a fault, like `SIGSEGV`, which QEMU delivers without a system call.
+//!
+//! ```
+//! // Example reporting crashes when QEMU exits
+//! use inventory;
+//! use once_cell::sync::Lazy;
+//! use cannonball::callbacks::{AtExitCallback, StaticCallbackType};
+//! use cannonball::exit::{status, ExitStatus};
+//!
+//! inventory::submit! {
+//!     static ecb: Lazy<AtExitCallback> = Lazy::new(|| {
+//!         AtExitCallback::new(|_| match status() {
+//!             Some(ExitStatus::Signaled(Some(signal))) => println!("killed by signal {}", signal),
+//!             Some(ExitStatus::Signaled(None)) => println!("killed by a fault"),
+//!             Some(ExitStatus::Exited(code)) => println!("exited with {}", code),
+//!             None => {}
+//!         })
+//!     });
+//!     StaticCallbackType::AtExit(&ecb)
+//! }
+//! ```
+
+use libc::getpid;
+use once_cell::sync::OnceCell;
+
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+
+use crate::{
+    api::{qemu_plugin_id_t, qemu_plugin_register_vcpu_syscall_cb},
+    callbacks::on_vcpu_syscall,
+    guest,
+    signal::is_fatal,
+    syscalls,
+};
+
+/// The exit code of the guest, with `EXITED` set, once it called `exit_group`
+const EXITED: u64 = 1 << 32;
+
+/// The exit code of a thread of the guest, with `THREAD_EXITED` set, while it called `exit`
+/// last
+const THREAD_EXITED: u64 = 1 << 33;
+
+/// The numbers of the system calls ending or signaling the guest on the target, set when the
+/// plugin is installed if the target is known
+static SYSCALLS: OnceCell<Syscalls> = OnceCell::new();
+
+/// How the guest exited so far, as `EXITED` or `THREAD_EXITED` and the exit code, or 0
+static EXIT: AtomicU64 = AtomicU64::new(0);
+
+/// The last fatal signal the guest sent itself, or 0
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// The numbers of the system calls ending or signaling the guest
+struct Syscalls {
+    exit: i64,
+    exit_group: i64,
+    kill: Option<i64>,
+    tkill: Option<i64>,
+    tgkill: i64,
+}
+
+/// How the guest exited
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExitStatus {
+    /// The guest exited with an exit code
+    Exited(i32),
+    /// The guest was killed by a signal: the last fatal one it sent itself, if it did, or one
+    /// raised by a fault otherwise
+    Signaled(Option<i32>),
+}
+
+/// Record the system calls of the target QEMU emulates. Only called from
+/// `qemu_plugin_install`
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target, e.g. `x86_64`
+pub(crate) fn set_target_name(target_name: &str) {
+    let number = |name| syscalls::number(target_name, name);
+
+    // Newer targets, like aarch64 and riscv64, only have `tgkill`
+    if let (Some(exit), Some(exit_group), Some(tgkill)) =
+        (number("exit"), number("exit_group"), number("tgkill"))
+    {
+        let _ = SYSCALLS.set(Syscalls {
+            exit,
+            exit_group,
+            kill: number("kill"),
+            tkill: number("tkill"),
+            tgkill,
+        });
+    }
+}
+
+/// Register the callback watching for exits, in user mode on a known target. It is the
+/// core's `vcpu_syscall` callback, shared with the plugin's `VCPUSyscallCallback`s, and is
+/// registered again after a reset like them
+///
+/// # Arguments
+///
+/// * `id` - The plugin ID to register the callback with
+pub(crate) fn register(id: qemu_plugin_id_t) {
+    if guest::is_user_emulation() && SYSCALLS.get().is_some() {
+        unsafe { qemu_plugin_register_vcpu_syscall_cb(id, Some(on_vcpu_syscall)) };
+    }
+}
+
+/// Called when the guest makes a system call, to learn how it exits
+///
+/// # Arguments
+///
+/// * `num` - The system call number
+/// * `a1` - The first argument of the system call
+/// * `a2` - The second argument of the system call
+/// * `a3` - The third argument of the system call
+pub(crate) fn on_syscall(num: i64, a1: u64, a2: u64, a3: u64) {
+    let Some(syscalls) = SYSCALLS.get() else {
+        return;
+    };
+
+    // Every argument is an `int`, and the guest shares the process ID of QEMU
+    let pid = unsafe { getpid() };
+    let (a1, a2, a3) = (a1 as i32, a2 as i32, a3 as i32);
+    let exit = EXIT.load(Ordering::Relaxed);
+
+    if num == syscalls.exit_group {
+        EXIT.store(EXITED | a1 as u32 as u64, Ordering::Relaxed);
+        return;
+    }
+
+    if num == syscalls.exit {
+        // The process only exits if this was its last thread, which no later system call of
+        // another thread tells
+        if exit & EXITED == 0 {
+            EXIT.store(THREAD_EXITED | a1 as u32 as u64, Ordering::Relaxed);
+        }
+        return;
+    }
+
+    let signal = if (Some(num) == syscalls.kill && (a1 == pid || a1 == 0))
+        || (Some(num) == syscalls.tkill && a1 == pid)
+    {
+        a2
+    } else if num == syscalls.tgkill && a1 == pid {
+        a3
+    } else {
+        0
+    };
+
+    if is_fatal(signal) {
+        SIGNAL.store(signal, Ordering::Relaxed);
+    }
+
+    // Other threads are still running
+    if exit & THREAD_EXITED != 0 {
+        EXIT.store(0, Ordering::Relaxed);
+    }
+}
+
+/// How the guest exited, for `atexit` callbacks. Returns `None` under system emulation, or on
+/// targets whose system calls are not known. Until QEMU exits, this is how the guest would
+/// have exited if it stopped there
+pub fn status() -> Option<ExitStatus> {
+    if !guest::is_user_emulation() || SYSCALLS.get().is_none() {
+        return None;
+    }
+
+    let exit = EXIT.load(Ordering::Relaxed);
+
+    if exit & (EXITED | THREAD_EXITED) != 0 {
+        Some(ExitStatus::Exited(exit as u32 as i32))
+    } else {
+        Some(ExitStatus::Signaled(
+            Some(SIGNAL.load(Ordering::Relaxed)).filter(|signal| *signal != 0),
+        ))
+    }
+}
@@ -0,0 +1,345 @@
+//! Guest memory in user mode
+//!
+//! Under user mode emulation, QEMU maps the memory of the guest into its own address space, at
+//! the guest address plus a fixed offset, the guest base. The plugin runs in the same process,
+//! so it can read guest memory through host pointers, without a plugin API call and without
+//! the `plugin-api-v4` feature `mem::read_memory` needs. This makes it cheap to sample buffers
+//! from memory or system call callbacks.
+//!
+//! The guest base is learned from the host address of the first translation block QEMU
+//! translates. Under system emulation guest memory is not mapped linearly, so
+//! `GuestAddr::to_host` always returns `None`, but `Instruction::host_addr` still gives the
+//! host address of each instruction.
+//!
+//! A host pointer is only valid while the guest keeps the memory mapped, which QEMU does not
+//! tell plugins. `GuestAddr::read` only reads memory in the regions the plugin registers with
+//! `add_region`, for example the loadable segments of the program or the ranges `mmap` calls
+//! return, and unregisters with `remove_region` when they are unmapped.
+//!
+//! Guest memory, and the registers `registers::Register::read` reads, hold values in the byte
+//! order of the target, which `endianness` gives once the plugin is installed. Values QEMU
+//! passes to callbacks, like system call arguments and the values of memory accesses, are
+//! already integers of the host.
+//!
+//! Addresses are always passed as 64 bit integers. On 32-bit targets, those of instructions
+//! and memory accesses are zero extended, but system call arguments and return values are
+//! sign extended like the `long` they are, so an argument pointing above 2GB has its upper 32
+//! bits set. `truncate_address` truncates them to the `address_bits` of the target.
+//!
+//! ```
+//! // Example logging the first bytes of anonymous mappings when they are unmapped
+//! use std::sync::atomic::{AtomicU64, Ordering};
+//!
+//! use inventory;
+//! use once_cell::sync::Lazy;
+//! use cannonball::callbacks::{StaticCallbackType, VCPUSyscallCallback, VCPUSyscallRetCallback};
+//! use cannonball::guest::{add_region, remove_region, GuestAddr};
+//!
+//! // System call numbers on x86_64
+//! const MMAP: i64 = 9;
+//! const MUNMAP: i64 = 11;
+//!
+//! static MMAP_LEN: AtomicU64 = AtomicU64::new(0);
+//!
+//! fn on_syscall(
+//!     _id: u64, _vcpu_index: u32, num: i64, a0: u64, a1: u64, _a2: u64, _a3: u64,
+//!     _a4: u64, _a5: u64, _a6: u64, _a7: u64,
+//! ) {
+//!     if num == MMAP {
+//!         MMAP_LEN.store(a1, Ordering::Relaxed);
+//!     } else if num == MUNMAP {
+//!         if let Some(data) = GuestAddr(a0).read(a1.min(16) as usize) {
+//!             println!("munmap({:#x}): {:x?}", a0, data);
+//!         }
+//!         remove_region(GuestAddr(a0), a1);
+//!     }
+//! }
+//!
+//! fn on_syscall_ret(_id: u64, _vcpu_index: u32, num: i64, ret: i64) {
+//!     // Failed calls return a negated error number
+//!     if num == MMAP && !(-4096..0).contains(&ret) {
+//!         add_region(GuestAddr(ret as u64), MMAP_LEN.load(Ordering::Relaxed));
+//!     }
+//! }
+//!
+//! inventory::submit! {
+//!     static scb: Lazy<VCPUSyscallCallback> = Lazy::new(|| VCPUSyscallCallback::new(on_syscall));
+//!     StaticCallbackType::VCPUSyscall(&scb)
+//! }
+//!
+//! inventory::submit! {
+//!     static rcb: Lazy<VCPUSyscallRetCallback> =
+//!         Lazy::new(|| VCPUSyscallRetCallback::new(on_syscall_ret));
+//!     StaticCallbackType::VCPUSyscallRet(&rcb)
+//! }
+//! ```
+
+use lazy_static::lazy_static;
+use once_cell::sync::OnceCell;
+
+use std::{slice::from_raw_parts, sync::RwLock};
+
+use crate::{
+    api::{qemu_plugin_id_t, qemu_plugin_register_vcpu_tb_trans_cb, qemu_plugin_tb},
+    callbacks::on_vcpu_tb_trans,
+    instrument::instructions,
+};
+
+/// Whether QEMU is running a system emulation, set when the plugin is installed
+static SYSTEM_EMULATION: OnceCell<bool> = OnceCell::new();
+
+/// The byte order of the target, set when the plugin is installed
+static ENDIANNESS: OnceCell<Endianness> = OnceCell::new();
+
+/// The width of the virtual addresses of the target in bits, set when the plugin is installed
+static ADDRESS_BITS: OnceCell<u32> = OnceCell::new();
+
+/// The offset of guest memory in the address space of QEMU, in user mode
+static GUEST_BASE: OnceCell<u64> = OnceCell::new();
+
+lazy_static! {
+    /// The guest address ranges known to be mapped, as start and end addresses
+    static ref REGIONS: RwLock<Vec<(u64, u64)>> = RwLock::new(Vec::new());
+}
+
+/// Record whether QEMU is running a system emulation
+///
+/// # Arguments
+///
+/// * `system_emulation` - Whether QEMU is running a system emulation
+pub(crate) fn set_system_emulation(system_emulation: bool) {
+    // A reinstalled plugin sees the same mode
+    let _ = SYSTEM_EMULATION.set(system_emulation);
+}
+
+/// Whether QEMU is running a user mode emulation. False until the plugin is installed
+pub(crate) fn is_user_emulation() -> bool {
+    SYSTEM_EMULATION.get() == Some(&false)
+}
+
+/// Record the byte order and address width of the target QEMU emulates. Only called from
+/// `qemu_plugin_install`
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target, e.g. `s390x`
+pub(crate) fn set_target_name(target_name: &str) {
+    let _ = ENDIANNESS.set(Endianness::of(target_name));
+    let _ = ADDRESS_BITS.set(address_bits_of(target_name));
+}
+
+/// The width of the virtual addresses of a QEMU target in bits, 32 or 64
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target, e.g. `i386`
+pub fn address_bits_of(target_name: &str) -> u32 {
+    match target_name {
+        "arm" | "armeb" | "avr" | "cris" | "hexagon" | "hppa" | "i386" | "m68k" | "microblaze"
+        | "microblazeel" | "mips" | "mipsel" | "mipsn32" | "mipsn32el" | "nios2" | "or1k"
+        | "ppc" | "riscv32" | "rx" | "sh4" | "sh4eb" | "sparc" | "sparc32plus" | "tricore"
+        | "xtensa" | "xtensaeb" => 32,
+        _ => 64,
+    }
+}
+
+/// The width of the virtual addresses of the target in bits. 64 until the plugin is installed
+pub fn address_bits() -> u32 {
+    ADDRESS_BITS.get().copied().unwrap_or(64)
+}
+
+/// Truncate an address to the width of the addresses of the target, like a system call
+/// argument a 32-bit target sign extended
+///
+/// # Arguments
+///
+/// * `addr` - The address
+pub fn truncate_address(addr: u64) -> u64 {
+    addr & (u64::MAX >> (64 - address_bits()))
+}
+
+/// The byte order of the target. Little endian until the plugin is installed
+pub fn endianness() -> Endianness {
+    ENDIANNESS.get().copied().unwrap_or_default()
+}
+
+/// The byte order of values in guest memory and registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Endianness {
+    /// Least significant byte first, like x86_64, aarch64 and riscv64
+    #[default]
+    Little,
+    /// Most significant byte first, like s390x, mips and sparc64
+    Big,
+}
+
+impl Endianness {
+    /// The byte order of a QEMU target. Targets that can switch byte order at run time, like
+    /// `ppc64` under system emulation, have the one they start in
+    ///
+    /// # Arguments
+    ///
+    /// * `target_name` - The name of the QEMU target, e.g. `s390x`
+    pub fn of(target_name: &str) -> Self {
+        match target_name {
+            "aarch64_be" | "armeb" | "hppa" | "m68k" | "microblaze" | "mips" | "mips64"
+            | "mipsn32" | "or1k" | "ppc" | "ppc64" | "s390x" | "sh4eb" | "sparc"
+            | "sparc32plus" | "sparc64" | "xtensaeb" => Self::Big,
+            _ => Self::Little,
+        }
+    }
+
+    /// The name of the byte order, `little` or `big`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Little => "little",
+            Self::Big => "big",
+        }
+    }
+
+    /// Decode an unsigned integer of at most 8 bytes in this byte order, like the value of a
+    /// register. Returns `None` if there are more bytes
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The bytes of the integer
+    pub fn read_u64(self, bytes: &[u8]) -> Option<u64> {
+        let mut buf = [0; 8];
+        let len = bytes.len();
+
+        if len > buf.len() {
+            return None;
+        }
+
+        Some(match self {
+            Self::Little => {
+                buf[..len].copy_from_slice(bytes);
+                u64::from_le_bytes(buf)
+            }
+            Self::Big => {
+                buf[8 - len..].copy_from_slice(bytes);
+                u64::from_be_bytes(buf)
+            }
+        })
+    }
+}
+
+/// Register the callback learning the guest base, in user mode. It is the core's
+/// `vcpu_tb_trans` callback, shared with the plugin's `VCPUTBTransCallback`s, and is
+/// registered again after a reset like them
+///
+/// # Arguments
+///
+/// * `id` - The plugin ID to register the callback with
+pub(crate) fn register(id: qemu_plugin_id_t) {
+    if is_user_emulation() && GUEST_BASE.get().is_none() {
+        unsafe { qemu_plugin_register_vcpu_tb_trans_cb(id, Some(on_vcpu_tb_trans)) };
+    }
+}
+
+/// Called when a translation block is translated, until the guest base is known. The guest
+/// base is the offset between the host and guest address of any instruction
+///
+/// # Arguments
+///
+/// * `tb` - The translation block
+pub(crate) fn on_tb_trans(tb: *mut qemu_plugin_tb) {
+    if GUEST_BASE.get().is_some() {
+        return;
+    }
+
+    if let Some(insn) = instructions(tb).next() {
+        if let Some(haddr) = insn.host_addr() {
+            let _ = GUEST_BASE.set((haddr as u64).wrapping_sub(insn.vaddr()));
+        }
+    }
+}
+
+/// The offset of guest memory in the address space of QEMU. Only known in user mode, once the
+/// first translation block has been translated
+pub fn guest_base() -> Option<u64> {
+    GUEST_BASE.get().copied()
+}
+
+/// Register a range of guest memory as mapped, so `GuestAddr::read` reads from it
+///
+/// # Arguments
+///
+/// * `start` - The first address of the range
+/// * `len` - The size of the range in bytes
+pub fn add_region(start: GuestAddr, len: u64) {
+    if len > 0 {
+        let end = start.0.saturating_add(len);
+        REGIONS.write().unwrap().push((start.0, end));
+    }
+}
+
+/// Unregister a range of guest memory that was unmapped. Regions overlapping it only partly
+/// are trimmed down to what is left of them
+///
+/// # Arguments
+///
+/// * `start` - The first address of the range
+/// * `len` - The size of the range in bytes
+pub fn remove_region(start: GuestAddr, len: u64) {
+    let (start, end) = (start.0, start.0.saturating_add(len));
+    let mut regions = REGIONS.write().unwrap();
+
+    *regions = regions
+        .iter()
+        .flat_map(|&(s, e)| {
+            if e <= start || end <= s {
+                vec![(s, e)]
+            } else {
+                [(s, start), (end, e)]
+                    .into_iter()
+                    .filter(|(s, e)| s < e)
+                    .collect()
+            }
+        })
+        .collect();
+}
+
+/// Whether a range of guest memory lies in a registered region
+///
+/// # Arguments
+///
+/// * `start` - The first address of the range
+/// * `end` - The address after the last of the range
+fn is_mapped(start: u64, end: u64) -> bool {
+    REGIONS
+        .read()
+        .unwrap()
+        .iter()
+        .any(|&(s, e)| s <= start && end <= e)
+}
+
+/// An address in the memory of the guest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GuestAddr(pub u64);
+
+impl GuestAddr {
+    /// The address QEMU maps this guest address at. Returns `None` under system emulation, or
+    /// before the guest base is known. The memory may not be mapped
+    pub fn to_host(self) -> Option<*const u8> {
+        Some(guest_base()?.wrapping_add(self.0) as *const u8)
+    }
+
+    /// Read guest memory at this address through its host address. Returns `None` unless the
+    /// whole range lies in a region registered with `add_region`, or if `to_host` does
+    ///
+    /// # Arguments
+    ///
+    /// * `len` - The number of bytes to read
+    pub fn read(self, len: usize) -> Option<Vec<u8>> {
+        let end = self.0.checked_add(len as u64)?;
+
+        if !is_mapped(self.0, end) {
+            return None;
+        }
+
+        let host = self.to_host()?;
+
+        Some(unsafe { from_raw_parts(host, len) }.to_vec())
+    }
+}
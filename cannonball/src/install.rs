@@ -2,18 +2,40 @@
 //!
 //! This module will handle installation and registration with QEMU. It exports the
 //! `qemu_plugin_install` function which is called by QEMU when the plugin is loaded. This
-//! function will run setup callbacks and register static callbacks with QEMU.
+//! function will run setup callbacks and register static callbacks with QEMU. If a fallible
+//! setup callback fails, installation fails with its error and no callback is registered, and
+//! so does it if a setup callback panics (see `panic`).
+//!
+//! It also exports `cannonball_plugin_manifest`, which returns the identity of the plugin as
+//! a JSON object, like `{"name":"jaivana","version":"0.1.1","cannonball":"0.2.6",
+//! "plugin_api_version":1}`, so tools can tell which plugin a library is without loading it
+//! into QEMU.
 
 use inventory;
 use libc::{c_char, c_int};
+use once_cell::sync::OnceCell;
+
+use std::{
+    ffi::{CStr, CString},
+    ptr::null,
+};
 
 use crate::{
-    api::{qemu_info_t, qemu_plugin_id_t},
+    api::{qemu_info_t, qemu_plugin_id_t, qemu_plugin_register_flush_cb, QEMU_PLUGIN_VERSION},
     args::Args,
     callbacks::{Register, SetupCallbackType, StaticCallbackType},
+    exit, guest, instrument,
+    panic::{guard, guard_install},
+    plugin::Plugin,
+    signal,
+    tb::free_allocations,
 };
 
 const PLUGIN_INSTALL_SUCCESS: c_int = 0;
+const PLUGIN_INSTALL_FAILURE: c_int = -1;
+
+/// The manifest of the plugin, built the first time it is asked for
+static MANIFEST: OnceCell<CString> = OnceCell::new();
 
 inventory::collect!(SetupCallbackType);
 inventory::collect!(StaticCallbackType);
@@ -27,19 +49,133 @@ pub extern "C" fn qemu_plugin_install(
     argc: c_int,
     argv: *const *const c_char,
 ) -> c_int {
-    let args = Args::new(argc, argv);
+    guard_install("install", || install(id, info, argc, argv)).unwrap_or(PLUGIN_INSTALL_FAILURE)
+}
+
+/// Install the plugin, running its setup callbacks and registering its static callbacks
+///
+/// # Arguments
+///
+/// * `id` - The plugin ID QEMU assigned
+/// * `info` - The information QEMU passes about the emulation
+/// * `argc` - The number of arguments passed to the plugin
+/// * `argv` - The arguments passed to the plugin
+fn install(
+    id: qemu_plugin_id_t,
+    info: *const qemu_info_t,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> c_int {
+    let mut args = Args::new(argc, argv);
+
+    if !Plugin::install(id) {
+        eprintln!(
+            "{} is already installed, and cannot be loaded twice",
+            Plugin::identity().map_or("The plugin", |identity| identity.name)
+        );
+        return PLUGIN_INSTALL_FAILURE;
+    }
+
+    if let Some(identity) = Plugin::identity() {
+        args = args.namespaced(identity.name);
+    }
+
+    if !info.is_null() {
+        guest::set_system_emulation(unsafe { (*info).system_emulation });
+
+        let target_name = unsafe { (*info).target_name };
+        if !target_name.is_null() {
+            let target_name = unsafe { CStr::from_ptr(target_name) }.to_string_lossy();
+            guest::set_target_name(&target_name);
+            exit::set_target_name(&target_name);
+            signal::set_target_name(&target_name);
+            instrument::set_target_name(&target_name);
+        }
+    }
 
     for setup_cb in inventory::iter::<SetupCallbackType> {
         match setup_cb {
             SetupCallbackType::Setup(setup_cb) => {
                 (setup_cb.cb)(info, &args);
             }
+            SetupCallbackType::FallibleSetup(setup_cb) => {
+                // Printed rather than written with `qemu_plugin_outs`, which only logs with
+                // `-d plugin`, so the reason QEMU exits is always shown
+                if let Err(e) = (setup_cb.cb)(info, &args) {
+                    eprintln!(
+                        "{} could not be set up: {}",
+                        Plugin::identity().map_or("The plugin", |identity| identity.name),
+                        e
+                    );
+                    return PLUGIN_INSTALL_FAILURE;
+                }
+            }
         }
     }
 
+    register_static_callbacks(id);
+
+    PLUGIN_INSTALL_SUCCESS
+}
+
+#[no_mangle]
+/// The manifest of the plugin, as a nul-terminated JSON object with its name and version, the
+/// version of cannonball it was built with and the plugin API version it requires. The name
+/// and version are null if the plugin did not submit an `Identity`
+pub extern "C" fn cannonball_plugin_manifest() -> *const c_char {
+    guard_install("manifest", manifest).unwrap_or(null())
+}
+
+/// The manifest of the plugin, built the first time it is asked for
+fn manifest() -> *const c_char {
+    MANIFEST
+        .get_or_init(|| {
+            // Package names and versions need no escaping beyond what `Debug` does
+            let (name, version) = match Plugin::identity() {
+                Some(identity) => (
+                    format!("{:?}", identity.name),
+                    format!("{:?}", identity.version),
+                ),
+                None => ("null".to_string(), "null".to_string()),
+            };
+
+            CString::new(format!(
+                r#"{{"name":{},"version":{},"cannonball":{:?},"plugin_api_version":{}}}"#,
+                name,
+                version,
+                env!("CARGO_PKG_VERSION"),
+                QEMU_PLUGIN_VERSION
+            ))
+            .expect("Manifest contains a nul byte!")
+        })
+        .as_ptr()
+}
+
+/// Register every static callback with QEMU. This happens on installation, and again after
+/// the plugin is reset because resetting unregisters every callback
+///
+/// # Arguments
+///
+/// * `id` - The plugin ID to register the callbacks with
+pub(crate) fn register_static_callbacks(id: qemu_plugin_id_t) {
     for callback in inventory::iter::<StaticCallbackType> {
         callback.register(id);
     }
 
-    PLUGIN_INSTALL_SUCCESS
+    unsafe { qemu_plugin_register_flush_cb(id, Some(on_flush)) };
+    guest::register(id);
+    exit::register(id);
+    signal::register(id);
+}
+
+/// Called by QEMU when the translation cache is flushed. Per-TB data owned by the core is
+/// freed, then the plugin's flush callbacks are called
+pub(crate) extern "C" fn on_flush(id: qemu_plugin_id_t) {
+    free_allocations();
+
+    for callback in inventory::iter::<StaticCallbackType> {
+        if let StaticCallbackType::Flush(cb) = callback {
+            guard("flush", || unsafe { (cb.cb)(id) });
+        }
+    }
 }
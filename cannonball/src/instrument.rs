@@ -0,0 +1,389 @@
+//! Translation block instrumentation helpers
+//!
+//! Most plugins do the same thing in their `vcpu_tb_trans` callback: walk the instructions
+//! of the translation block and decide, per instruction, whether to attach a callback to it.
+//! `TBInstrumenter` centralizes that walk. The plugin supplies a predicate deciding what to
+//! do with each `Instruction`, and only registers callbacks on the instructions that need
+//! them, so no callback is registered (and no overhead is paid at execution time) for
+//! instructions the plugin is not interested in.
+//!
+//! ```
+//! // Example instrumenting only the last instruction of each translation block
+//! use std::{ffi::c_void, ptr::null_mut};
+//!
+//! use inventory;
+//! use once_cell::sync::Lazy;
+//! use cannonball::api::qemu_plugin_tb;
+//! use cannonball::callbacks::{
+//!     RegisterInsnExec, StaticCallbackType, VCPUInsnExecCallback, VCPUTBTransCallback,
+//! };
+//! use cannonball::instrument::{InstrumentAction, TBInstrumenter};
+//!
+//! #[derive(Clone)]
+//! struct NoData;
+//!
+//! impl From<NoData> for *mut c_void {
+//!     fn from(_: NoData) -> Self {
+//!         null_mut()
+//!     }
+//! }
+//!
+//! fn on_branch(vcpu_index: u32, _data: *mut c_void) {
+//!     println!("vcpu {} reached the end of a block", vcpu_index);
+//! }
+//!
+//! fn on_tb_trans(_id: u64, tb: *mut qemu_plugin_tb) {
+//!     TBInstrumenter::new(|insn| {
+//!         if insn.is_last() {
+//!             InstrumentAction::Callback
+//!         } else {
+//!             InstrumentAction::Skip
+//!         }
+//!     })
+//!     .instrument(tb, |insn| {
+//!         VCPUInsnExecCallback::new(on_branch, NoData).register(insn.raw());
+//!     });
+//! }
+//!
+//! inventory::submit! {
+//!     static tcb: Lazy<VCPUTBTransCallback> = Lazy::new(|| VCPUTBTransCallback::new(on_tb_trans));
+//!     StaticCallbackType::VCPUTBTrans(&tcb)
+//! }
+//! ```
+
+use libc::c_void;
+use once_cell::sync::OnceCell;
+
+use std::{cell::Cell, ffi::CStr, slice::from_raw_parts};
+
+use crate::api::{
+    qemu_plugin_insn, qemu_plugin_insn_data, qemu_plugin_insn_haddr, qemu_plugin_insn_size,
+    qemu_plugin_insn_symbol, qemu_plugin_insn_vaddr, qemu_plugin_op,
+    qemu_plugin_op_QEMU_PLUGIN_INLINE_ADD_U64, qemu_plugin_register_vcpu_insn_exec_inline,
+    qemu_plugin_tb, qemu_plugin_tb_get_insn, qemu_plugin_tb_n_insns,
+};
+
+/// The QEMU targets whose instructions have an `IsaMode`: little endian 32-bit ARM, in user
+/// and system mode, which switches between the ARM and Thumb instruction sets. `armeb` is
+/// left out since the instruction set is told from halfwords read as little endian, and
+/// `aarch64` translates A64 only
+const ISA_MODE_TARGETS: &[&str] = &["arm"];
+
+/// Whether the target is one of `ISA_MODE_TARGETS`. Set when the plugin is installed
+static ISA_MODES: OnceCell<bool> = OnceCell::new();
+
+thread_local! {
+    /// Whether the last translation block translated on this thread whose instruction set
+    /// could be told from its instructions was Thumb, to guess the instruction set of blocks
+    /// that could be either. VCPUs translate the blocks they execute on their own thread, so
+    /// each VCPU follows its own instruction set, except with single-threaded TCG, where they
+    /// all share one thread
+    static LAST_THUMB: Cell<bool> = const { Cell::new(false) };
+    /// The instruction set of the translation block being translated on this thread, told once
+    /// when its translation starts
+    static TB_ISA_MODE: Cell<Option<(*mut qemu_plugin_tb, Option<IsaMode>)>> =
+        const { Cell::new(None) };
+}
+
+/// Record the target QEMU emulates. Only called from `qemu_plugin_install`
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target, e.g. `arm`
+pub(crate) fn set_target_name(target_name: &str) {
+    let _ = ISA_MODES.set(ISA_MODE_TARGETS.contains(&target_name));
+}
+
+/// Tell the instruction set of a translation block once, before the plugin's
+/// `VCPUTBTransCallback`s instrument it, or forget it once they are done. Only called from the
+/// core's `vcpu_tb_trans` callback
+///
+/// # Arguments
+///
+/// * `tb` - The translation block being translated, or `None` once its translation is done
+pub(crate) fn translating(tb: Option<*mut qemu_plugin_tb>) {
+    TB_ISA_MODE.set(tb.map(|tb| (tb, tell_isa_mode(tb))));
+}
+
+/// The instruction set an instruction was translated in, on targets that have several. Each
+/// translation block is translated in a single instruction set, the one the VCPU was in when
+/// execution reached it, and disassembling its instructions in any other gives garbage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IsaMode {
+    /// The 32-bit ARM instruction set (A32), of 4 byte instructions
+    Arm,
+    /// The Thumb instruction set (T32), of 2 and 4 byte instructions
+    Thumb,
+}
+
+impl IsaMode {
+    /// The name of the instruction set, as disassemblers name it
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Arm => "arm",
+            Self::Thumb => "thumb",
+        }
+    }
+}
+
+/// An instruction in a translation block that is being translated. Instructions are only
+/// valid for the duration of the `vcpu_tb_trans` callback they were obtained in
+pub struct Instruction {
+    /// The opaque QEMU instruction
+    insn: *mut qemu_plugin_insn,
+    /// The index of the instruction in its translation block
+    index: usize,
+    /// Whether this is the last instruction of its translation block
+    last: bool,
+    /// The instruction set of its translation block, on targets with several
+    isa_mode: Option<IsaMode>,
+}
+
+impl Instruction {
+    /// Instantiate a new `Instruction` for the instruction at `index` in a translation block
+    ///
+    /// # Arguments
+    ///
+    /// * `tb` - The translation block containing the instruction
+    /// * `index` - The index of the instruction in `tb`
+    /// * `n_insns` - The number of instructions in `tb`
+    /// * `isa_mode` - The instruction set of `tb`, on targets with several
+    fn new(
+        tb: *mut qemu_plugin_tb,
+        index: usize,
+        n_insns: usize,
+        isa_mode: Option<IsaMode>,
+    ) -> Self {
+        Self {
+            insn: unsafe { qemu_plugin_tb_get_insn(tb, index) },
+            index,
+            last: index == n_insns - 1,
+            isa_mode,
+        }
+    }
+
+    /// The opaque QEMU instruction, for registering callbacks on
+    pub fn raw(&self) -> *mut qemu_plugin_insn {
+        self.insn
+    }
+
+    /// The index of the instruction in its translation block
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Whether this is the last instruction of its translation block. The last instruction is
+    /// the one that transfers control out of the block, for example a branch, call, or return
+    pub fn is_last(&self) -> bool {
+        self.last
+    }
+
+    /// The guest virtual address of the instruction
+    pub fn vaddr(&self) -> u64 {
+        unsafe { qemu_plugin_insn_vaddr(self.insn) }
+    }
+
+    /// The size of the instruction in bytes
+    pub fn size(&self) -> usize {
+        unsafe { qemu_plugin_insn_size(self.insn) }
+    }
+
+    /// The address QEMU translated the instruction from in its own address space. Under user
+    /// mode emulation this maps the guest address linearly (see `guest`), under system
+    /// emulation it is the host address of the guest RAM backing the instruction. Returns
+    /// `None` if QEMU does not know it, like for instructions in device memory
+    pub fn host_addr(&self) -> Option<*const u8> {
+        let haddr = unsafe { qemu_plugin_insn_haddr(self.insn) };
+
+        if haddr.is_null() {
+            None
+        } else {
+            Some(haddr as *const u8)
+        }
+    }
+
+    /// The instruction set the instruction was translated in, on targets with several (see
+    /// `IsaMode`). Its bytes must be disassembled in this instruction set
+    pub fn isa_mode(&self) -> Option<IsaMode> {
+        self.isa_mode
+    }
+
+    /// The raw bytes of the instruction
+    pub fn data(&self) -> &[u8] {
+        unsafe { from_raw_parts(qemu_plugin_insn_data(self.insn) as *const u8, self.size()) }
+    }
+
+    /// The name of the symbol containing the instruction, if QEMU has a symbol table for the
+    /// binary it is in
+    pub fn symbol(&self) -> Option<&CStr> {
+        let symbol = unsafe { qemu_plugin_insn_symbol(self.insn) };
+
+        if symbol.is_null() {
+            None
+        } else {
+            Some(unsafe { CStr::from_ptr(symbol) })
+        }
+    }
+}
+
+/// Iterate over the instructions of a translation block that is being translated
+///
+/// # Arguments
+///
+/// * `tb` - The translation block being translated
+pub fn instructions(tb: *mut qemu_plugin_tb) -> impl Iterator<Item = Instruction> {
+    let n_insns = unsafe { qemu_plugin_tb_n_insns(tb) };
+    let isa_mode = isa_mode(tb);
+    (0..n_insns).map(move |index| Instruction::new(tb, index, n_insns, isa_mode))
+}
+
+/// The instruction set of a translation block that is being translated, on targets with
+/// several. It is told once per translation, when it starts, so this is cheap to call
+///
+/// # Arguments
+///
+/// * `tb` - The translation block being translated
+pub fn isa_mode(tb: *mut qemu_plugin_tb) -> Option<IsaMode> {
+    match TB_ISA_MODE.get() {
+        Some((translating, isa_mode)) if translating == tb => isa_mode,
+        _ => tell_isa_mode(tb),
+    }
+}
+
+/// Tell the instruction set of a translation block. QEMU does not tell plugins, so it is told
+/// from the instructions of the block: only Thumb has 2 byte instructions and instructions on
+/// 2 byte boundaries, and the first halfword of a 4 byte Thumb instruction always starts with
+/// `0b11101`, `0b11110` or `0b11111`. A block of 4 byte instructions that all start that way,
+/// like a lone `bl`, could be either, and is guessed to be in the instruction set of the last
+/// block translated on the same thread that could not
+///
+/// # Arguments
+///
+/// * `tb` - The translation block being translated
+fn tell_isa_mode(tb: *mut qemu_plugin_tb) -> Option<IsaMode> {
+    if !ISA_MODES.get().copied().unwrap_or(false) {
+        return None;
+    }
+
+    let n_insns = unsafe { qemu_plugin_tb_n_insns(tb) };
+    let mut thumb = None;
+
+    for insn in (0..n_insns).map(|index| Instruction::new(tb, index, n_insns, None)) {
+        if insn.size() == 2 || insn.vaddr() % 4 != 0 {
+            thumb = Some(true);
+            break;
+        }
+
+        // The target is little endian, so the first halfword is in the first two bytes
+        if let [low, high, ..] = *insn.data() {
+            if u16::from_le_bytes([low, high]) >> 11 < 0b11101 {
+                thumb = Some(false);
+            }
+        }
+    }
+
+    let thumb = match thumb {
+        Some(thumb) => {
+            LAST_THUMB.set(thumb);
+            thumb
+        }
+        None => LAST_THUMB.get(),
+    };
+
+    Some(if thumb { IsaMode::Thumb } else { IsaMode::Arm })
+}
+
+/// An inline operation QEMU performs directly in the translated code when an instruction
+/// executes, without calling back into the plugin
+pub struct InlineOp {
+    /// The operation to perform
+    pub op: qemu_plugin_op,
+    /// Pointer to the value the operation is performed on
+    pub ptr: *mut c_void,
+    /// Immediate operand of the operation
+    pub imm: u64,
+}
+
+impl InlineOp {
+    /// Instantiate a new `InlineOp` adding `imm` to the `u64` at `ptr` each time the
+    /// instruction executes. The addition is not atomic, so with multiple VCPUs the count is
+    /// approximate
+    ///
+    /// # Arguments
+    ///
+    /// * `ptr` - Pointer to the counter, which must live as long as the translation
+    /// * `imm` - The value to add
+    pub fn add_u64(ptr: *mut u64, imm: u64) -> Self {
+        Self {
+            op: qemu_plugin_op_QEMU_PLUGIN_INLINE_ADD_U64,
+            ptr: ptr as *mut c_void,
+            imm,
+        }
+    }
+}
+
+/// What to do with an instruction during translation
+pub enum InstrumentAction {
+    /// Register callbacks on the instruction
+    Callback,
+    /// Register an inline operation on the instruction
+    Inline(InlineOp),
+    /// Do not instrument the instruction
+    Skip,
+}
+
+/// Helper instrumenting the instructions of a translation block according to a predicate
+pub struct TBInstrumenter<F>
+where
+    F: Fn(&Instruction) -> InstrumentAction,
+{
+    /// Predicate deciding what to do with each instruction
+    predicate: F,
+}
+
+impl<F> TBInstrumenter<F>
+where
+    F: Fn(&Instruction) -> InstrumentAction,
+{
+    /// Instantiate a new `TBInstrumenter` with the given predicate
+    ///
+    /// # Arguments
+    ///
+    /// * `predicate` - Called on each instruction of a translation block to decide whether to
+    ///   register callbacks on it, register an inline operation on it, or skip it
+    pub fn new(predicate: F) -> Self {
+        Self { predicate }
+    }
+
+    /// Instrument the instructions of a translation block. Inline operations are registered
+    /// directly, and `register` is called on each instruction the predicate requested
+    /// callbacks for, so the plugin can register its callbacks with whatever data they need.
+    /// Returns the number of instructions `register` was called on
+    ///
+    /// # Arguments
+    ///
+    /// * `tb` - The translation block being translated
+    /// * `register` - Called to register callbacks on an instruction
+    pub fn instrument(
+        &self,
+        tb: *mut qemu_plugin_tb,
+        mut register: impl FnMut(&Instruction),
+    ) -> usize {
+        let mut registered = 0;
+
+        for insn in instructions(tb) {
+            match (self.predicate)(&insn) {
+                InstrumentAction::Callback => {
+                    register(&insn);
+                    registered += 1;
+                }
+                InstrumentAction::Inline(op) => unsafe {
+                    qemu_plugin_register_vcpu_insn_exec_inline(insn.raw(), op.op, op.ptr, op.imm);
+                },
+                InstrumentAction::Skip => {}
+            }
+        }
+
+        registered
+    }
+}
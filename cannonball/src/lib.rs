@@ -21,7 +21,23 @@ use libc::c_int;
 pub mod api;
 pub mod args;
 pub mod callbacks;
+pub mod exit;
+pub mod guest;
 pub mod install;
+pub mod instrument;
+pub mod mem;
+pub mod panic;
+pub mod plugin;
+#[cfg(feature = "plugin-api-v2")]
+pub mod registers;
+#[cfg(feature = "plugin-api-v2")]
+pub mod scoreboard;
+pub mod signal;
+pub mod syscalls;
+pub mod tb;
+#[cfg(feature = "plugin-api-v2")]
+pub mod time;
+pub mod vcpu;
 
 use api::QEMU_PLUGIN_VERSION;
 
@@ -0,0 +1,214 @@
+//! Memory access information
+//!
+//! Memory callbacks receive an opaque `qemu_plugin_meminfo_t` describing the access. `MemInfo`
+//! wraps it and exposes the properties of the access: its size, signedness, endianness,
+//! direction, and (with the `plugin-api-v3` feature, QEMU 9.1 and later) the value that was
+//! loaded or stored.
+//!
+//! Which accesses a memory callback is fired for, loads, stores or both, is chosen when it is
+//! registered with `MemAccessKind`.
+//!
+//! In system emulation, `MemInfo::hwaddr` additionally resolves the physical address an access
+//! was translated to, and whether it targeted device memory (MMIO) rather than RAM.
+//!
+//! Outside of memory callbacks, `read_memory` (with the `plugin-api-v4` feature, QEMU 9.2 and
+//! later) reads guest memory at a virtual address of the current VCPU.
+
+use crate::api::{
+    qemu_plugin_get_hwaddr, qemu_plugin_hwaddr, qemu_plugin_hwaddr_is_io,
+    qemu_plugin_hwaddr_phys_addr, qemu_plugin_mem_is_big_endian, qemu_plugin_mem_is_sign_extended,
+    qemu_plugin_mem_is_store, qemu_plugin_mem_rw, qemu_plugin_mem_rw_QEMU_PLUGIN_MEM_R,
+    qemu_plugin_mem_rw_QEMU_PLUGIN_MEM_RW, qemu_plugin_mem_rw_QEMU_PLUGIN_MEM_W,
+    qemu_plugin_mem_size_shift, qemu_plugin_meminfo_t,
+};
+#[cfg(feature = "plugin-api-v3")]
+use crate::api::{
+    qemu_plugin_mem_get_value, qemu_plugin_mem_value_type_QEMU_PLUGIN_MEM_VALUE_U128,
+    qemu_plugin_mem_value_type_QEMU_PLUGIN_MEM_VALUE_U16,
+    qemu_plugin_mem_value_type_QEMU_PLUGIN_MEM_VALUE_U32,
+    qemu_plugin_mem_value_type_QEMU_PLUGIN_MEM_VALUE_U64,
+    qemu_plugin_mem_value_type_QEMU_PLUGIN_MEM_VALUE_U8,
+};
+#[cfg(feature = "plugin-api-v4")]
+use crate::{api::qemu_plugin_read_memory_vaddr, registers::ByteArray};
+
+/// The memory accesses a memory callback is fired for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemAccessKind {
+    /// Loads only
+    Read,
+    /// Stores only
+    Write,
+    /// Both loads and stores
+    #[default]
+    ReadWrite,
+}
+
+impl From<MemAccessKind> for qemu_plugin_mem_rw {
+    fn from(kind: MemAccessKind) -> Self {
+        match kind {
+            MemAccessKind::Read => qemu_plugin_mem_rw_QEMU_PLUGIN_MEM_R,
+            MemAccessKind::Write => qemu_plugin_mem_rw_QEMU_PLUGIN_MEM_W,
+            MemAccessKind::ReadWrite => qemu_plugin_mem_rw_QEMU_PLUGIN_MEM_RW,
+        }
+    }
+}
+
+/// Information about a memory access, valid for the duration of the memory callback it was
+/// passed to
+#[derive(Debug, Clone, Copy)]
+pub struct MemInfo {
+    /// The opaque QEMU memory info
+    info: qemu_plugin_meminfo_t,
+}
+
+impl MemInfo {
+    /// Instantiate a new `MemInfo` from the info passed to a memory callback
+    ///
+    /// # Arguments
+    ///
+    /// * `info` - The opaque memory info object passed to the callback
+    pub fn new(info: qemu_plugin_meminfo_t) -> Self {
+        Self { info }
+    }
+
+    /// The opaque QEMU memory info
+    pub fn raw(&self) -> qemu_plugin_meminfo_t {
+        self.info
+    }
+
+    /// The size of the access as a power of two
+    pub fn size_shift(&self) -> u32 {
+        unsafe { qemu_plugin_mem_size_shift(self.info) }
+    }
+
+    /// The size of the access in bytes
+    pub fn size(&self) -> usize {
+        1 << self.size_shift()
+    }
+
+    /// Whether the accessed value is sign extended
+    pub fn is_sign_extended(&self) -> bool {
+        unsafe { qemu_plugin_mem_is_sign_extended(self.info) }
+    }
+
+    /// Whether the access is big endian
+    pub fn is_big_endian(&self) -> bool {
+        unsafe { qemu_plugin_mem_is_big_endian(self.info) }
+    }
+
+    /// Whether the access is a store (otherwise it is a load)
+    pub fn is_store(&self) -> bool {
+        unsafe { qemu_plugin_mem_is_store(self.info) }
+    }
+
+    /// The hardware address information of the access. This is only available in system
+    /// emulation, in user mode `None` is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `vaddr` - The virtual address of the access, as passed to the memory callback
+    pub fn hwaddr(&self, vaddr: u64) -> Option<HwAddr> {
+        let haddr = unsafe { qemu_plugin_get_hwaddr(self.info, vaddr) };
+
+        if haddr.is_null() {
+            None
+        } else {
+            Some(HwAddr { haddr })
+        }
+    }
+
+    /// The value loaded or stored by the access. For stores this is the data written, for
+    /// loads the data read
+    #[cfg(feature = "plugin-api-v3")]
+    pub fn value(&self) -> MemValue {
+        let value = unsafe { qemu_plugin_mem_get_value(self.info) };
+
+        unsafe {
+            match value.type_ {
+                qemu_plugin_mem_value_type_QEMU_PLUGIN_MEM_VALUE_U8 => MemValue::U8(value.data.u8),
+                qemu_plugin_mem_value_type_QEMU_PLUGIN_MEM_VALUE_U16 => {
+                    MemValue::U16(value.data.u16)
+                }
+                qemu_plugin_mem_value_type_QEMU_PLUGIN_MEM_VALUE_U32 => {
+                    MemValue::U32(value.data.u32)
+                }
+                qemu_plugin_mem_value_type_QEMU_PLUGIN_MEM_VALUE_U64 => {
+                    MemValue::U64(value.data.u64)
+                }
+                qemu_plugin_mem_value_type_QEMU_PLUGIN_MEM_VALUE_U128 => MemValue::U128(
+                    ((value.data.u128.high as u128) << 64) | value.data.u128.low as u128,
+                ),
+                _ => unreachable!("Unknown memory value type {}", value.type_),
+            }
+        }
+    }
+}
+
+/// Hardware address information of a memory access in system emulation, valid for the
+/// duration of the memory callback it was obtained in
+#[derive(Debug, Clone, Copy)]
+pub struct HwAddr {
+    /// The opaque QEMU hardware address
+    haddr: *mut qemu_plugin_hwaddr,
+}
+
+impl HwAddr {
+    /// The opaque QEMU hardware address
+    pub fn raw(&self) -> *mut qemu_plugin_hwaddr {
+        self.haddr
+    }
+
+    /// The guest physical address of the access. For IO accesses this is the offset into the
+    /// device's memory region
+    pub fn phys_addr(&self) -> u64 {
+        unsafe { qemu_plugin_hwaddr_phys_addr(self.haddr) }
+    }
+
+    /// Whether the access targeted device memory (MMIO) instead of RAM
+    pub fn is_io(&self) -> bool {
+        unsafe { qemu_plugin_hwaddr_is_io(self.haddr) }
+    }
+}
+
+/// The value of a memory access, sized like the access
+#[cfg(feature = "plugin-api-v3")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemValue {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    U128(u128),
+}
+
+#[cfg(feature = "plugin-api-v3")]
+impl From<MemValue> for u128 {
+    fn from(value: MemValue) -> Self {
+        match value {
+            MemValue::U8(v) => v as u128,
+            MemValue::U16(v) => v as u128,
+            MemValue::U32(v) => v as u128,
+            MemValue::U64(v) => v as u128,
+            MemValue::U128(v) => v,
+        }
+    }
+}
+
+/// Read guest memory at a virtual address of the current VCPU. Returns `None` if any of it
+/// could not be read, for example because it is not mapped
+///
+/// # Arguments
+///
+/// * `vaddr` - The first address to read
+/// * `len` - The number of bytes to read
+#[cfg(feature = "plugin-api-v4")]
+pub fn read_memory(vaddr: u64, len: usize) -> Option<Vec<u8>> {
+    let buf = ByteArray::new();
+
+    if unsafe { qemu_plugin_read_memory_vaddr(vaddr, buf.as_ptr(), len) } {
+        Some(buf.to_vec())
+    } else {
+        None
+    }
+}
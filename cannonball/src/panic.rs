@@ -0,0 +1,145 @@
+//! Panics in callbacks
+//!
+//! QEMU calls the plugin through C functions, and a panic must not unwind out of one into
+//! QEMU. The core owns every function QEMU calls: installation, the static callbacks, which it
+//! registers once and dispatches to every callback submitted to `inventory`, and the callbacks
+//! registered at translation time, which it calls through trampolines. Each of them catches a
+//! panic in the plugin's callback, logs it with `qemu_plugin_outs` (shown with `-d plugin`,
+//! the panic message itself is also printed to stderr), and returns to QEMU as if the callback
+//! had returned.
+//!
+//! State a callback was updating when it panicked may be left half updated, and a lock it held
+//! is poisoned, so by default QEMU keeps running but later callbacks may panic too. A plugin
+//! that would rather stop tracing than trace wrong can have the core uninstall it on the first
+//! panic instead:
+//!
+//! ```
+//! use inventory;
+//! use once_cell::sync::Lazy;
+//! use cannonball::callbacks::{SetupCallback, SetupCallbackType};
+//! use cannonball::panic::{set_on_panic, OnPanic};
+//!
+//! inventory::submit! {
+//!     static scb: Lazy<SetupCallback> = Lazy::new(|| {
+//!         SetupCallback::new(|_, _| set_on_panic(OnPanic::Uninstall))
+//!     });
+//!     SetupCallbackType::Setup(&scb)
+//! }
+//! ```
+//!
+//! A panic during setup fails the installation of the plugin, like a `FallibleSetupCallback`
+//! returning an error.
+
+use std::{
+    any::Any,
+    ffi::CString,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+use crate::{api::qemu_plugin_outs, plugin::Plugin};
+
+/// What the core does when a callback panics
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnPanic {
+    /// Log the panic and keep running
+    #[default]
+    Continue = 0,
+    /// Log the panic and uninstall the plugin, so QEMU runs on without it
+    Uninstall = 1,
+}
+
+/// The policy set with `set_on_panic`
+static ON_PANIC: AtomicU8 = AtomicU8::new(OnPanic::Continue as u8);
+
+/// Whether a panic already uninstalled the plugin
+static UNINSTALLING: AtomicBool = AtomicBool::new(false);
+
+/// Choose what the core does when a callback panics. This can be called at any time, usually
+/// from a setup callback
+///
+/// # Arguments
+///
+/// * `on_panic` - The policy
+pub fn set_on_panic(on_panic: OnPanic) {
+    ON_PANIC.store(on_panic as u8, Ordering::Relaxed);
+}
+
+/// The policy the core follows when a callback panics
+pub fn on_panic() -> OnPanic {
+    match ON_PANIC.load(Ordering::Relaxed) {
+        1 => OnPanic::Uninstall,
+        _ => OnPanic::Continue,
+    }
+}
+
+/// Call a callback of the plugin, catching a panic. Returns `None` if it panicked, once the
+/// panic has been handled as the policy says
+///
+/// # Arguments
+///
+/// * `callback` - The name of the callback, for the log
+/// * `f` - The callback
+pub(crate) fn guard<R>(callback: &str, f: impl FnOnce() -> R) -> Option<R> {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => Some(result),
+        Err(payload) => {
+            panicked(callback, payload, on_panic() == OnPanic::Uninstall);
+            None
+        }
+    }
+}
+
+/// Call a callback of the plugin while it is being installed, catching a panic. Returns `None`
+/// if it panicked, in which case installation fails rather than the plugin being uninstalled
+///
+/// # Arguments
+///
+/// * `callback` - The name of the callback, for the log
+/// * `f` - The callback
+pub(crate) fn guard_install<R>(callback: &str, f: impl FnOnce() -> R) -> Option<R> {
+    catch_unwind(AssertUnwindSafe(f))
+        .map_err(|payload| panicked(callback, payload, false))
+        .ok()
+}
+
+/// The message a panic was raised with
+///
+/// # Arguments
+///
+/// * `payload` - The payload caught from the panic
+fn message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
+/// Log a panic caught in a callback, and uninstall the plugin unless a panic already did
+///
+/// # Arguments
+///
+/// * `callback` - The name of the callback
+/// * `payload` - The payload caught from the panic
+/// * `uninstall` - Whether to uninstall the plugin
+fn panicked(callback: &str, payload: Box<dyn Any + Send>, uninstall: bool) {
+    let uninstall = uninstall && !UNINSTALLING.swap(true, Ordering::Relaxed);
+    let log = format!(
+        "{}: {} callback panicked: {}{}\n",
+        Plugin::identity().map_or("cannonball", |identity| identity.name),
+        callback,
+        message(payload.as_ref()),
+        if uninstall { ", uninstalling" } else { "" },
+    );
+
+    // Nul bytes would only come from the panic message, and are dropped rather than the log
+    if let Ok(log) = CString::new(log.replace('\0', "")) {
+        unsafe { qemu_plugin_outs(log.as_ptr()) };
+    }
+
+    if uninstall {
+        Plugin::current().uninstall(|_| {});
+    }
+}
@@ -0,0 +1,243 @@
+//! Runtime control of the plugin
+//!
+//! QEMU assigns the plugin an id when it is installed, which `Plugin` wraps. It can be used
+//! to reset the plugin, which unregisters every callback and flushes the translation cache,
+//! or to uninstall it entirely.
+//!
+//! Resetting is how a plugin switches its instrumentation on and off mid-run: callbacks
+//! registered at translation time stay attached to translated code until it is flushed, so
+//! after changing what it wants to instrument the plugin resets itself, and every translation
+//! block is translated (and instrumented) again the next time it executes. The static
+//! callbacks registered through `inventory` are registered again automatically once the reset
+//! completes, so the plugin keeps receiving `vcpu_tb_trans` and friends.
+//!
+//! ```
+//! // Example switching instruction callbacks off after the first system call
+//! use std::{
+//!     ffi::c_void,
+//!     ptr::null_mut,
+//!     sync::atomic::{AtomicBool, Ordering},
+//! };
+//!
+//! use inventory;
+//! use once_cell::sync::Lazy;
+//! use cannonball::api::qemu_plugin_tb;
+//! use cannonball::callbacks::{
+//!     RegisterInsnExec, StaticCallbackType, VCPUInsnExecCallback, VCPUSyscallCallback,
+//!     VCPUTBTransCallback,
+//! };
+//! use cannonball::instrument::instructions;
+//! use cannonball::plugin::Plugin;
+//!
+//! static TRACING: AtomicBool = AtomicBool::new(true);
+//!
+//! #[derive(Clone)]
+//! struct NoData;
+//!
+//! impl From<NoData> for *mut c_void {
+//!     fn from(_: NoData) -> Self {
+//!         null_mut()
+//!     }
+//! }
+//!
+//! fn on_insn_exec(vcpu_index: u32, _data: *mut c_void) {
+//!     println!("vcpu {} executed an instruction", vcpu_index);
+//! }
+//!
+//! fn on_tb_trans(_id: u64, tb: *mut qemu_plugin_tb) {
+//!     if TRACING.load(Ordering::Relaxed) {
+//!         for insn in instructions(tb) {
+//!             VCPUInsnExecCallback::new(on_insn_exec, NoData).register(insn.raw());
+//!         }
+//!     }
+//! }
+//!
+//! fn on_syscall(
+//!     _id: u64, _vcpu_index: u32, _num: i64, _a0: u64, _a1: u64, _a2: u64, _a3: u64,
+//!     _a4: u64, _a5: u64, _a6: u64, _a7: u64,
+//! ) {
+//!     if TRACING.swap(false, Ordering::Relaxed) {
+//!         Plugin::current().reset(|_| println!("instruction callbacks removed"));
+//!     }
+//! }
+//!
+//! inventory::submit! {
+//!     static tcb: Lazy<VCPUTBTransCallback> = Lazy::new(|| VCPUTBTransCallback::new(on_tb_trans));
+//!     StaticCallbackType::VCPUTBTrans(&tcb)
+//! }
+//!
+//! inventory::submit! {
+//!     static scb: Lazy<VCPUSyscallCallback> = Lazy::new(|| VCPUSyscallCallback::new(on_syscall));
+//!     StaticCallbackType::VCPUSyscall(&scb)
+//! }
+//! ```
+//!
+//! A plugin tells who it is by submitting an `Identity` to `inventory`. The identity is
+//! exported as a manifest, which tools read with `dlsym` to tell plugins apart, and its name
+//! namespaces the plugin's arguments: `-plugin ./libjaivana.so,jaivana.log_pc=true` sets
+//! `log_pc` for the `jaivana` plugin only (see `Args::namespaced`).
+//!
+//! ```
+//! use cannonball::plugin::Identity;
+//!
+//! inventory::submit! {
+//!     Identity::new(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+//! }
+//! ```
+//!
+//! QEMU loads each plugin with local symbol binding, so every cannonball plugin has its own
+//! copy of this crate and of its state. Several plugins can be loaded at once, and each keys
+//! its state by the id QEMU assigned it. Loading the same plugin twice fails its second
+//! installation, as both would share one copy.
+
+use lazy_static::lazy_static;
+use once_cell::sync::OnceCell;
+
+use std::sync::Mutex;
+
+use crate::{
+    api::{qemu_plugin_id_t, qemu_plugin_reset, qemu_plugin_uninstall},
+    install::{on_flush, register_static_callbacks},
+    panic::guard,
+};
+
+/// The id QEMU assigned to the plugin, set when it is installed
+static PLUGIN_ID: OnceCell<qemu_plugin_id_t> = OnceCell::new();
+
+/// The name and version of a plugin, which it submits to `inventory`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Identity {
+    /// The name of the plugin, which namespaces its arguments
+    pub name: &'static str,
+    /// The version of the plugin
+    pub version: &'static str,
+}
+
+impl Identity {
+    /// Instantiate a new `Identity`
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the plugin, usually `env!("CARGO_PKG_NAME")`
+    /// * `version` - The version of the plugin, usually `env!("CARGO_PKG_VERSION")`
+    pub const fn new(name: &'static str, version: &'static str) -> Self {
+        Self { name, version }
+    }
+}
+
+inventory::collect!(Identity);
+
+/// A callback run once a reset or uninstall requested through `Plugin` has completed
+type Completion = Box<dyn FnOnce(Plugin) + Send + 'static>;
+
+lazy_static! {
+    /// Callbacks waiting for the reset in progress to complete
+    static ref RESET_CALLBACKS: Mutex<Vec<Completion>> = Mutex::new(Vec::new());
+    /// Callbacks waiting for the uninstall in progress to complete
+    static ref UNINSTALL_CALLBACKS: Mutex<Vec<Completion>> = Mutex::new(Vec::new());
+}
+
+/// The installed plugin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Plugin {
+    /// The id QEMU assigned to the plugin
+    id: qemu_plugin_id_t,
+}
+
+impl Plugin {
+    /// Record the id QEMU assigned to the plugin on installation. Returns `false` if the
+    /// plugin was already installed, which happens when QEMU is asked to load it twice
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The id passed to `qemu_plugin_install`
+    pub(crate) fn install(id: qemu_plugin_id_t) -> bool {
+        PLUGIN_ID.set(id).is_ok()
+    }
+
+    /// The identity the plugin submitted, if it submitted one
+    pub fn identity() -> Option<&'static Identity> {
+        inventory::iter::<Identity>.into_iter().next()
+    }
+
+    /// Get the installed plugin. This can be called from setup callbacks onward
+    pub fn current() -> Self {
+        Self {
+            id: *PLUGIN_ID
+                .get()
+                .expect("The plugin has not been installed yet!"),
+        }
+    }
+
+    /// The id QEMU assigned to the plugin
+    pub fn id(&self) -> qemu_plugin_id_t {
+        self.id
+    }
+
+    /// Reset the plugin. Every callback is unregistered and the translation cache is flushed,
+    /// so translation blocks are translated (and instrumented) again when they next execute.
+    /// The reset happens asynchronously: callbacks keep firing until it completes, at which
+    /// point the static callbacks are registered again and `done` is called. If a reset is
+    /// already in progress, QEMU does not start another one, and `done` is called when the
+    /// one in progress completes
+    ///
+    /// # Arguments
+    ///
+    /// * `done` - Called once the reset has completed
+    pub fn reset(&self, done: impl FnOnce(Plugin) + Send + 'static) {
+        RESET_CALLBACKS
+            .lock()
+            .expect("Could not lock reset callbacks!")
+            .push(Box::new(done));
+
+        unsafe { qemu_plugin_reset(self.id, Some(on_reset)) };
+    }
+
+    /// Uninstall the plugin. Every callback is unregistered and the plugin is unloaded. This
+    /// happens asynchronously: callbacks keep firing until it completes, at which point `done`
+    /// is called, right before the plugin is unloaded
+    ///
+    /// # Arguments
+    ///
+    /// * `done` - Called once every callback has been unregistered
+    pub fn uninstall(self, done: impl FnOnce(Plugin) + Send + 'static) {
+        UNINSTALL_CALLBACKS
+            .lock()
+            .expect("Could not lock uninstall callbacks!")
+            .push(Box::new(done));
+
+        unsafe { qemu_plugin_uninstall(self.id, Some(on_uninstall)) };
+    }
+}
+
+/// Run every callback waiting in `callbacks`
+///
+/// # Arguments
+///
+/// * `callback` - The name of the callbacks, for the log if one panics
+/// * `callbacks` - The waiting callbacks
+/// * `id` - The id of the plugin
+fn complete(callback: &str, callbacks: &Mutex<Vec<Completion>>, id: qemu_plugin_id_t) {
+    let callbacks: Vec<Completion> = callbacks
+        .lock()
+        .expect("Could not lock completion callbacks!")
+        .drain(..)
+        .collect();
+
+    for done in callbacks {
+        guard(callback, || done(Plugin { id }));
+    }
+}
+
+/// Called by QEMU once a reset has completed. The translation cache was flushed while no flush
+/// callback was registered, so the flush is handled here
+extern "C" fn on_reset(id: qemu_plugin_id_t) {
+    on_flush(id);
+    register_static_callbacks(id);
+    complete("reset", &RESET_CALLBACKS, id);
+}
+
+/// Called by QEMU once an uninstall has completed
+extern "C" fn on_uninstall(id: qemu_plugin_id_t) {
+    complete("uninstall", &UNINSTALL_CALLBACKS, id);
+}
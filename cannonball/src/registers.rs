@@ -0,0 +1,140 @@
+//! Reading registers
+//!
+//! QEMU exposes the registers of the current VCPU by the names its gdbstub gives them, like
+//! `rip` or `x0`. `registers` lists them in the order of the gdbstub's register numbers, and
+//! `Register::read` reads the value of one as raw bytes in the guest's byte order.
+//!
+//! Registers can only be read from a callback registered with the
+//! `qemu_plugin_cb_flags_QEMU_PLUGIN_CB_R_REGS` flag (see
+//! `VCPUInsnExecCallback::reading_registers`), on the VCPU the callback fired for. They are
+//! only available with plugin API version 2 (QEMU 9.0) and later, so this module requires the
+//! `plugin-api-v2` feature.
+
+use libc::{c_char, c_int};
+
+use std::{ffi::CStr, slice::from_raw_parts};
+
+use crate::api::{
+    qemu_plugin_get_registers, qemu_plugin_read_register, qemu_plugin_reg_descriptor,
+    qemu_plugin_register, GArray, GByteArray,
+};
+
+extern "C" {
+    // Provided by glib, which QEMU links against
+    fn g_array_free(array: *mut GArray, free_segment: c_int) -> *mut c_char;
+    fn g_byte_array_new() -> *mut GByteArray;
+    fn g_byte_array_free(array: *mut GByteArray, free_segment: c_int) -> *mut u8;
+}
+
+/// A growable byte buffer owned by glib, freed when dropped
+pub(crate) struct ByteArray {
+    array: *mut GByteArray,
+}
+
+impl ByteArray {
+    /// Allocate a new empty buffer
+    pub(crate) fn new() -> Self {
+        Self {
+            array: unsafe { g_byte_array_new() },
+        }
+    }
+
+    /// The glib array, to pass to QEMU
+    pub(crate) fn as_ptr(&self) -> *mut GByteArray {
+        self.array
+    }
+
+    /// Copy the contents of the buffer
+    pub(crate) fn to_vec(&self) -> Vec<u8> {
+        let array = unsafe { &*self.array };
+
+        if array.data.is_null() {
+            Vec::new()
+        } else {
+            unsafe { from_raw_parts(array.data, array.len as usize) }.to_vec()
+        }
+    }
+}
+
+impl Drop for ByteArray {
+    fn drop(&mut self) {
+        unsafe { g_byte_array_free(self.array, 1) };
+    }
+}
+
+/// A register of the current VCPU
+#[derive(Debug, Clone)]
+pub struct Register {
+    /// The opaque QEMU handle of the register
+    handle: *mut qemu_plugin_register,
+    /// The name of the register, like `rip`
+    name: String,
+    /// The gdbstub feature the register belongs to, like `org.gnu.gdb.i386.core`
+    feature: String,
+}
+
+/// Copy a string owned by QEMU
+///
+/// # Arguments
+///
+/// * `s` - The string, which may be null
+fn owned(s: *const c_char) -> String {
+    if s.is_null() {
+        String::new()
+    } else {
+        unsafe { CStr::from_ptr(s) }.to_string_lossy().to_string()
+    }
+}
+
+impl Register {
+    /// The name of the register, like `rip`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The gdbstub feature the register belongs to, like `org.gnu.gdb.i386.core`
+    pub fn feature(&self) -> &str {
+        &self.feature
+    }
+
+    /// Read the value of the register on the current VCPU, in the guest's byte order. Returns
+    /// `None` if QEMU could not read it
+    pub fn read(&self) -> Option<Vec<u8>> {
+        let buf = ByteArray::new();
+
+        if unsafe { qemu_plugin_read_register(self.handle, buf.as_ptr()) } < 0 {
+            None
+        } else {
+            Some(buf.to_vec())
+        }
+    }
+}
+
+/// The registers of the current VCPU, in the order of the gdbstub's register numbers
+pub fn registers() -> Vec<Register> {
+    let array = unsafe { qemu_plugin_get_registers() };
+
+    if array.is_null() {
+        return Vec::new();
+    }
+
+    let descriptors = unsafe {
+        from_raw_parts(
+            (*array).data as *const qemu_plugin_reg_descriptor,
+            (*array).len as usize,
+        )
+    };
+
+    let registers = descriptors
+        .iter()
+        .map(|descriptor| Register {
+            handle: descriptor.handle,
+            name: owned(descriptor.name),
+            feature: owned(descriptor.feature),
+        })
+        .collect();
+
+    unsafe { g_array_free(array, 1) };
+
+    registers
+}
@@ -0,0 +1,178 @@
+//! Per-VCPU scoreboards
+//!
+//! A scoreboard is a QEMU-managed array holding one entry per VCPU. Because every VCPU only
+//! touches its own entry, callbacks can keep per-VCPU state (counters, last PC, etc.) without
+//! taking any lock. QEMU grows the array when VCPUs are added and zero-initializes new entries,
+//! so entry types must be valid when zeroed (see `Zeroable`).
+//!
+//! Scoreboards are only available with plugin API version 2 (QEMU 9.0) and later, so this
+//! module requires the `plugin-api-v2` feature.
+//!
+//! ```
+//! // Example counting the instructions executed by each VCPU
+//! use std::{
+//!     ffi::c_void,
+//!     ptr::null_mut,
+//!     sync::atomic::{AtomicU64, Ordering},
+//! };
+//!
+//! use inventory;
+//! use once_cell::sync::Lazy;
+//! use cannonball::api::{qemu_plugin_tb, qemu_plugin_tb_get_insn, qemu_plugin_tb_n_insns};
+//! use cannonball::callbacks::{
+//!     AtExitCallback, RegisterInsnExec, StaticCallbackType, VCPUInsnExecCallback,
+//!     VCPUTBTransCallback,
+//! };
+//! use cannonball::scoreboard::Scoreboard;
+//!
+//! static COUNTS: Lazy<Scoreboard<AtomicU64>> = Lazy::new(Scoreboard::new);
+//!
+//! #[derive(Clone)]
+//! struct NoData;
+//!
+//! impl From<NoData> for *mut c_void {
+//!     fn from(_: NoData) -> Self {
+//!         null_mut()
+//!     }
+//! }
+//!
+//! fn on_insn_exec(vcpu_index: u32, _data: *mut c_void) {
+//!     COUNTS.get(vcpu_index).fetch_add(1, Ordering::Relaxed);
+//! }
+//!
+//! fn on_tb_trans(_id: u64, tb: *mut qemu_plugin_tb) {
+//!     for idx in 0..unsafe { qemu_plugin_tb_n_insns(tb) } {
+//!         let insn = unsafe { qemu_plugin_tb_get_insn(tb, idx) };
+//!         VCPUInsnExecCallback::new(on_insn_exec, NoData).register(insn);
+//!     }
+//! }
+//!
+//! inventory::submit! {
+//!     static tcb: Lazy<VCPUTBTransCallback> = Lazy::new(|| VCPUTBTransCallback::new(on_tb_trans));
+//!     StaticCallbackType::VCPUTBTrans(&tcb)
+//! }
+//!
+//! inventory::submit! {
+//!     static ecb: Lazy<AtExitCallback> = Lazy::new(|| {
+//!         AtExitCallback::new(|_| {
+//!             for (vcpu_index, count) in COUNTS.iter().enumerate() {
+//!                 println!("vcpu {}: {} insns", vcpu_index, count.load(Ordering::Relaxed));
+//!             }
+//!         })
+//!     });
+//!     StaticCallbackType::AtExit(&ecb)
+//! }
+//! ```
+
+use std::{
+    marker::PhantomData,
+    mem::size_of,
+    sync::atomic::{
+        AtomicBool, AtomicI16, AtomicI32, AtomicI64, AtomicI8, AtomicIsize, AtomicU16, AtomicU32,
+        AtomicU64, AtomicU8, AtomicUsize,
+    },
+};
+
+use crate::{
+    api::{
+        qemu_plugin_scoreboard, qemu_plugin_scoreboard_find, qemu_plugin_scoreboard_free,
+        qemu_plugin_scoreboard_new,
+    },
+    vcpu::num_vcpus,
+};
+
+/// Marker for types whose all-zero bit pattern is a valid value. QEMU zero-fills scoreboard
+/// entries, so only these types can be stored in a `Scoreboard`
+///
+/// # Safety
+///
+/// Implementors must be valid when every byte of their representation is zero
+pub unsafe trait Zeroable {}
+
+macro_rules! impl_zeroable {
+    ($($t:ty),*) => {
+        $(unsafe impl Zeroable for $t {})*
+    };
+}
+
+impl_zeroable!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, bool);
+impl_zeroable!(AtomicBool, AtomicU8, AtomicU16, AtomicU32, AtomicU64);
+impl_zeroable!(AtomicUsize, AtomicI8, AtomicI16, AtomicI32);
+impl_zeroable!(AtomicI64, AtomicIsize);
+
+unsafe impl<T: Zeroable, const N: usize> Zeroable for [T; N] {}
+
+/// A QEMU scoreboard holding one `T` per VCPU. The scoreboard is freed when dropped
+pub struct Scoreboard<T: Zeroable> {
+    /// The opaque QEMU scoreboard
+    score: *mut qemu_plugin_scoreboard,
+    _marker: PhantomData<T>,
+}
+
+// Entries are only ever handed out as shared references, so the scoreboard can be shared
+// between VCPU threads as long as `T` itself can be
+unsafe impl<T: Zeroable + Send + Sync> Send for Scoreboard<T> {}
+unsafe impl<T: Zeroable + Send + Sync> Sync for Scoreboard<T> {}
+
+impl<T: Zeroable> Scoreboard<T> {
+    /// Allocate a new scoreboard with a zeroed entry for each VCPU
+    pub fn new() -> Self {
+        Self {
+            score: unsafe { qemu_plugin_scoreboard_new(size_of::<T>()) },
+            _marker: PhantomData,
+        }
+    }
+
+    /// Get the entry for a VCPU. Entries are shared between every callback running on the
+    /// VCPU, so `T` should use interior mutability (e.g. atomics) to be updated. References
+    /// must not be held across callbacks, because QEMU may reallocate the scoreboard when a
+    /// VCPU is added
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_index` - The index of the VCPU to get the entry for
+    pub fn get(&self, vcpu_index: u32) -> &T {
+        unsafe { &*self.as_ptr(vcpu_index) }
+    }
+
+    /// Get a mutable reference to the entry for a VCPU
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_index` - The index of the VCPU to get the entry for
+    ///
+    /// # Safety
+    ///
+    /// This must only be called from a callback running on VCPU `vcpu_index`, and no other
+    /// reference to the same entry may be alive while the returned reference is
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get_mut(&self, vcpu_index: u32) -> &mut T {
+        &mut *self.as_ptr(vcpu_index)
+    }
+
+    /// Get a raw pointer to the entry for a VCPU
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_index` - The index of the VCPU to get the entry for
+    pub fn as_ptr(&self, vcpu_index: u32) -> *mut T {
+        unsafe { qemu_plugin_scoreboard_find(self.score, vcpu_index) as *mut T }
+    }
+
+    /// Iterate over the entries of every VCPU that has been initialized, in VCPU index order
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        (0..num_vcpus()).map(move |vcpu_index| self.get(vcpu_index))
+    }
+}
+
+impl<T: Zeroable> Default for Scoreboard<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Zeroable> Drop for Scoreboard<T> {
+    fn drop(&mut self) {
+        unsafe { qemu_plugin_scoreboard_free(self.score) };
+    }
+}
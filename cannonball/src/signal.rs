@@ -0,0 +1,238 @@
+//! Signals of the guest, in user mode
+//!
+//! QEMU delivers signals to the guest without telling plugins: a signal with a handler makes
+//! the guest jump to it, and a fatal one makes QEMU exit (see `exit`). The core watches the
+//! `rt_sigaction` system calls of the guest, on the targets `syscalls` knows, to learn which
+//! handler each signal has, so a plugin can tell a signal was delivered when execution reaches
+//! a handler, with `signals_handled_at`. Returning from a handler is the `rt_sigreturn`
+//! system call, which plugins see like any other.
+//!
+//! ```
+//! // Example logging the delivery of signals to handlers
+//! use inventory;
+//! use once_cell::sync::Lazy;
+//! use cannonball::api::qemu_plugin_tb;
+//! use cannonball::callbacks::{StaticCallbackType, VCPUTBTransCallback};
+//! use cannonball::instrument::instructions;
+//! use cannonball::signal::{name, signals_handled_at};
+//!
+//! fn on_tb_trans(_id: u64, tb: *mut qemu_plugin_tb) {
+//!     if let Some(insn) = instructions(tb).next() {
+//!         for signal in signals_handled_at(insn.vaddr()) {
+//!             println!("{:#x} handles {}", insn.vaddr(), name(signal).unwrap_or("a signal"));
+//!         }
+//!     }
+//! }
+//!
+//! inventory::submit! {
+//!     static tcb: Lazy<VCPUTBTransCallback> = Lazy::new(|| VCPUTBTransCallback::new(on_tb_trans));
+//!     StaticCallbackType::VCPUTBTrans(&tcb)
+//! }
+//! ```
+//!
+//! A handler installed before its code was first translated is found as soon as it is, which
+//! is the usual case. Code translated earlier, like a function called directly before it was
+//! made a handler, is only seen again once QEMU flushes its translations (see `tb`).
+
+use lazy_static::lazy_static;
+use once_cell::sync::OnceCell;
+
+use std::{
+    collections::HashMap,
+    slice::from_raw_parts,
+    sync::{Mutex, RwLock},
+};
+
+use crate::{
+    api::{
+        qemu_plugin_id_t, qemu_plugin_register_vcpu_syscall_cb,
+        qemu_plugin_register_vcpu_syscall_ret_cb,
+    },
+    callbacks::{on_vcpu_syscall, on_vcpu_syscall_ret},
+    guest::{self, address_bits, endianness, truncate_address, GuestAddr},
+    syscalls,
+};
+
+/// The number of `rt_sigaction` on the target, set when the plugin is installed if the
+/// target is known
+static RT_SIGACTION: OnceCell<i64> = OnceCell::new();
+
+/// The names of the signals, by number. Signals are numbered alike on every target `syscalls`
+/// knows
+const NAMES: [&str; 31] = [
+    "SIGHUP",
+    "SIGINT",
+    "SIGQUIT",
+    "SIGILL",
+    "SIGTRAP",
+    "SIGABRT",
+    "SIGBUS",
+    "SIGFPE",
+    "SIGKILL",
+    "SIGUSR1",
+    "SIGSEGV",
+    "SIGUSR2",
+    "SIGPIPE",
+    "SIGALRM",
+    "SIGTERM",
+    "SIGSTKFLT",
+    "SIGCHLD",
+    "SIGCONT",
+    "SIGSTOP",
+    "SIGTSTP",
+    "SIGTTIN",
+    "SIGTTOU",
+    "SIGURG",
+    "SIGXCPU",
+    "SIGXFSZ",
+    "SIGVTALRM",
+    "SIGPROF",
+    "SIGWINCH",
+    "SIGIO",
+    "SIGPWR",
+    "SIGSYS",
+];
+
+lazy_static! {
+    /// The handler of every signal that has one, by signal
+    static ref HANDLERS: RwLock<HashMap<i32, u64>> = RwLock::new(HashMap::new());
+    /// The signal and new action of the `rt_sigaction` each VCPU is in, until it returns
+    static ref PENDING: Mutex<HashMap<u32, (i32, u64)>> = Mutex::new(HashMap::new());
+}
+
+/// Record the system calls of the target QEMU emulates. Only called from
+/// `qemu_plugin_install`
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target, e.g. `x86_64`
+pub(crate) fn set_target_name(target_name: &str) {
+    if let Some(num) = syscalls::number(target_name, "rt_sigaction") {
+        let _ = RT_SIGACTION.set(num);
+    }
+}
+
+/// Register the callbacks watching for handlers, in user mode on a known target. They are the
+/// core's `vcpu_syscall` and `vcpu_syscall_ret` callbacks, shared with the plugin's, and are
+/// registered again after a reset like them
+///
+/// # Arguments
+///
+/// * `id` - The plugin ID to register the callbacks with
+pub(crate) fn register(id: qemu_plugin_id_t) {
+    if guest::is_user_emulation() && RT_SIGACTION.get().is_some() {
+        unsafe {
+            qemu_plugin_register_vcpu_syscall_cb(id, Some(on_vcpu_syscall));
+            qemu_plugin_register_vcpu_syscall_ret_cb(id, Some(on_vcpu_syscall_ret));
+        }
+    }
+}
+
+/// Called when the guest makes a system call. The new action of a signal is only read once
+/// `rt_sigaction` succeeded, because only then is it known to point to mapped memory
+///
+/// # Arguments
+///
+/// * `vcpu_index` - The VCPU making the system call
+/// * `num` - The system call number
+/// * `a1` - The first argument of the system call
+/// * `a2` - The second argument of the system call
+pub(crate) fn on_syscall(vcpu_index: u32, num: i64, a1: u64, a2: u64) {
+    // A null action only asks for the current one
+    if Some(&num) == RT_SIGACTION.get() && a2 != 0 {
+        PENDING
+            .lock()
+            .unwrap()
+            .insert(vcpu_index, (a1 as i32, truncate_address(a2)));
+    }
+}
+
+/// Called when a system call of the guest returns
+///
+/// # Arguments
+///
+/// * `vcpu_index` - The VCPU the system call returns on
+/// * `num` - The system call number
+/// * `ret` - The return value of the system call
+pub(crate) fn on_syscall_ret(vcpu_index: u32, num: i64, ret: i64) {
+    if Some(&num) != RT_SIGACTION.get() {
+        return;
+    }
+
+    let Some((signal, act)) = PENDING.lock().unwrap().remove(&vcpu_index) else {
+        return;
+    };
+
+    if ret != 0 {
+        return;
+    }
+
+    // The handler is the first field of the action on every target, a pointer of the guest.
+    // QEMU just read it to carry out the system call, so its memory is mapped
+    let Some(host) = GuestAddr(act).to_host() else {
+        return;
+    };
+
+    let bytes = unsafe { from_raw_parts(host, address_bits() as usize / 8) };
+    let Some(handler) = endianness().read_u64(bytes) else {
+        return;
+    };
+
+    let mut handlers = HANDLERS.write().unwrap();
+
+    // `SIG_DFL` and `SIG_IGN` are not handlers
+    if handler > 1 {
+        handlers.insert(signal, handler);
+    } else {
+        handlers.remove(&signal);
+    }
+}
+
+/// Whether a signal kills the process unless it is handled
+///
+/// # Arguments
+///
+/// * `signal` - The signal number
+pub(crate) fn is_fatal(signal: i32) -> bool {
+    // Except for SIGCHLD, SIGCONT, SIGSTOP, SIGTSTP, SIGTTIN, SIGTTOU, SIGURG and SIGWINCH
+    matches!(signal, 1..=16 | 24..=27 | 29..=31)
+}
+
+/// The name of a signal, like `SIGSEGV`, if it is a standard signal
+///
+/// # Arguments
+///
+/// * `signal` - The signal number
+pub fn name(signal: i32) -> Option<&'static str> {
+    NAMES
+        .get(usize::try_from(signal).ok()?.checked_sub(1)?)
+        .copied()
+}
+
+/// The handler the guest installed for a signal, if it has one
+///
+/// # Arguments
+///
+/// * `signal` - The signal number
+pub fn handler(signal: i32) -> Option<u64> {
+    HANDLERS.read().unwrap().get(&signal).copied()
+}
+
+/// The signals whose handler starts at an address, in order. Empty for most addresses, and
+/// always under system emulation
+///
+/// # Arguments
+///
+/// * `pc` - The address
+pub fn signals_handled_at(pc: u64) -> Vec<i32> {
+    let mut signals: Vec<i32> = HANDLERS
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, handler)| **handler == pc)
+        .map(|(signal, _)| *signal)
+        .collect();
+
+    signals.sort_unstable();
+    signals
+}
@@ -0,0 +1,171 @@
+//! System call numbers
+//!
+//! QEMU reports system calls by their raw number, which depends on the target. Each module
+//! here holds the system calls of one target as a `Sysno` enum, generated at build time from
+//! the tables of Linux vendored in `syscalls/`, so a plugin can match on names instead of
+//! numbers:
+//!
+//! ```
+//! use cannonball::syscalls::x86_64::Sysno;
+//!
+//! fn on_syscall(num: i64) {
+//!     match Sysno::new(num) {
+//!         Some(Sysno::openat) => println!("opening a file"),
+//!         Some(sysno) => println!("{}", sysno),
+//!         None => println!("unknown system call {}", num),
+//!     }
+//! }
+//! ```
+//!
+//! A plugin running on any target, or a consumer decoding its events, can look system calls
+//! up by the name of the QEMU target instead, with `name` and `number`.
+
+/// Declare the system calls of a target, as `<name> = <number> => "<name>"`
+macro_rules! sysno {
+    ($($sysno:ident = $num:literal => $name:literal,)*) => {
+        use std::fmt;
+
+        /// A system call of the target
+        #[allow(non_camel_case_types)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        #[repr(i64)]
+        pub enum Sysno {
+            $($sysno = $num,)*
+        }
+
+        impl Sysno {
+            /// Every system call of the target, in order of their numbers
+            pub const ALL: &'static [Sysno] = &[$(Sysno::$sysno,)*];
+
+            /// The system call with a number, if the target has one
+            ///
+            /// # Arguments
+            ///
+            /// * `num` - The system call number
+            pub fn new(num: i64) -> Option<Self> {
+                match num {
+                    $($num => Some(Self::$sysno),)*
+                    _ => None,
+                }
+            }
+
+            /// The system call with a name, if the target has one
+            ///
+            /// # Arguments
+            ///
+            /// * `name` - The name of the system call, e.g. `openat`
+            pub fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    $($name => Some(Self::$sysno),)*
+                    _ => None,
+                }
+            }
+
+            /// The number of the system call
+            pub fn num(self) -> i64 {
+                self as i64
+            }
+
+            /// The name of the system call
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Self::$sysno => $name,)*
+                }
+            }
+        }
+
+        impl TryFrom<i64> for Sysno {
+            type Error = i64;
+
+            /// The system call with a number, or the number if the target has none
+            fn try_from(num: i64) -> Result<Self, i64> {
+                Self::new(num).ok_or(num)
+            }
+        }
+
+        impl From<Sysno> for i64 {
+            fn from(sysno: Sysno) -> Self {
+                sysno.num()
+            }
+        }
+
+        impl fmt::Display for Sysno {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+    };
+}
+
+/// System calls of the `aarch64` target
+pub mod aarch64 {
+    include!(concat!(env!("OUT_DIR"), "/syscalls_aarch64.rs"));
+}
+
+/// System calls of the `arm` target
+pub mod arm {
+    include!(concat!(env!("OUT_DIR"), "/syscalls_arm.rs"));
+}
+
+/// System calls of the `i386` target
+pub mod i386 {
+    include!(concat!(env!("OUT_DIR"), "/syscalls_i386.rs"));
+}
+
+/// System calls of the `loongarch64` target
+pub mod loongarch64 {
+    include!(concat!(env!("OUT_DIR"), "/syscalls_loongarch64.rs"));
+}
+
+/// System calls of the `riscv32` target
+pub mod riscv32 {
+    include!(concat!(env!("OUT_DIR"), "/syscalls_riscv32.rs"));
+}
+
+/// System calls of the `riscv64` target
+pub mod riscv64 {
+    include!(concat!(env!("OUT_DIR"), "/syscalls_riscv64.rs"));
+}
+
+/// System calls of the `x86_64` target
+pub mod x86_64 {
+    include!(concat!(env!("OUT_DIR"), "/syscalls_x86_64.rs"));
+}
+
+/// The name of a system call on a target, if both are known
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target, e.g. `x86_64`
+/// * `num` - The system call number
+pub fn name(target_name: &str, num: i64) -> Option<&'static str> {
+    match target_name {
+        "aarch64" => aarch64::Sysno::new(num).map(aarch64::Sysno::name),
+        "arm" => arm::Sysno::new(num).map(arm::Sysno::name),
+        "i386" => i386::Sysno::new(num).map(i386::Sysno::name),
+        "loongarch64" => loongarch64::Sysno::new(num).map(loongarch64::Sysno::name),
+        "riscv32" => riscv32::Sysno::new(num).map(riscv32::Sysno::name),
+        "riscv64" => riscv64::Sysno::new(num).map(riscv64::Sysno::name),
+        "x86_64" => x86_64::Sysno::new(num).map(x86_64::Sysno::name),
+        _ => None,
+    }
+}
+
+/// The number of a system call on a target, if both are known
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target, e.g. `x86_64`
+/// * `name` - The name of the system call, e.g. `openat`
+pub fn number(target_name: &str, name: &str) -> Option<i64> {
+    match target_name {
+        "aarch64" => aarch64::Sysno::from_name(name).map(aarch64::Sysno::num),
+        "arm" => arm::Sysno::from_name(name).map(arm::Sysno::num),
+        "i386" => i386::Sysno::from_name(name).map(i386::Sysno::num),
+        "loongarch64" => loongarch64::Sysno::from_name(name).map(loongarch64::Sysno::num),
+        "riscv32" => riscv32::Sysno::from_name(name).map(riscv32::Sysno::num),
+        "riscv64" => riscv64::Sysno::from_name(name).map(riscv64::Sysno::num),
+        "x86_64" => x86_64::Sysno::from_name(name).map(x86_64::Sysno::num),
+        _ => None,
+    }
+}
@@ -0,0 +1,304 @@
+//! Per translation block data
+//!
+//! A `vcpu_tb_exec` callback only receives the VCPU index and an opaque data pointer, so a
+//! plugin that needs to know which block executed has to allocate something at translation
+//! time and pass it along. `TBHandle` does that for you: when a `VCPUTBHandleCallback` is
+//! registered on a translation block, the core allocates a handle holding the block's address,
+//! size, and instruction count along with a copy of the callback's data, and the callback
+//! receives a reference to it. The data can be downcast back to its original type with
+//! `TBHandle::data`.
+//!
+//! Handles are owned by the core and live until the translation cache is flushed, at which
+//! point the blocks they describe no longer exist and every handle is freed. References to a
+//! handle must not be kept past the callback that received them.
+//!
+//! Plugins registering callbacks with their own data can hand it to the core the same way
+//! with `TBData`, instead of leaking it on every translation, and read it back in the callback
+//! with `TBData::get`. This is how data is attached to each translated instruction: one
+//! `TBData` registered with both the exec and the memory callbacks of an instruction is shared
+//! by them, so every execution and access finds its instruction directly, without keying
+//! instructions in a global map that has to be reaped. Anything a plugin caches about
+//! translated code itself (for example a map from block address to some analysis) should be
+//! cleared in a `FlushCallback`, which is called right after the core frees its allocations.
+//!
+//! A flush happens when the translation buffer is full, when the plugin is reset, and on a
+//! handful of target specific events (for example a debugger changing single stepping). QEMU
+//! also invalidates individual blocks, for instance when the guest writes to translated code
+//! or unmaps it, but does not notify plugins of those, so their data is only freed on the next
+//! flush.
+//!
+//! Data that has to outlive translated code, like state kept across every translation of a
+//! block, is passed in a `CallbackData` instead, which is never freed. Both can be registered
+//! with any callback taking data and only accept data that can be shared between VCPU
+//! threads, so plugins never need to wrap raw pointers in types of their own that claim to be
+//! `Send` and `Sync`.
+//!
+//! ```
+//! // Example counting the instructions executed in each block with a known name
+//! use inventory;
+//! use once_cell::sync::Lazy;
+//! use cannonball::api::qemu_plugin_tb;
+//! use cannonball::callbacks::{
+//!     RegisterTBExec, StaticCallbackType, VCPUTBHandleCallback, VCPUTBTransCallback,
+//! };
+//! use cannonball::tb::TBHandle;
+//!
+//! fn on_tb_exec(vcpu_index: u32, tb: &TBHandle) {
+//!     if let Some(name) = tb.data::<String>() {
+//!         println!("vcpu {} executed {} instructions in {}", vcpu_index, tb.n_insns(), name);
+//!     }
+//! }
+//!
+//! fn on_tb_trans(_id: u64, tb: *mut qemu_plugin_tb) {
+//!     VCPUTBHandleCallback::new(on_tb_exec, "main".to_string()).register(tb);
+//! }
+//!
+//! inventory::submit! {
+//!     static tcb: Lazy<VCPUTBTransCallback> = Lazy::new(|| VCPUTBTransCallback::new(on_tb_trans));
+//!     StaticCallbackType::VCPUTBTrans(&tcb)
+//! }
+//! ```
+
+use lazy_static::lazy_static;
+use libc::c_void;
+
+use std::{any::Any, sync::Mutex};
+
+use crate::{
+    api::{
+        qemu_plugin_insn_size, qemu_plugin_insn_vaddr, qemu_plugin_tb, qemu_plugin_tb_get_insn,
+        qemu_plugin_tb_n_insns, qemu_plugin_tb_vaddr,
+    },
+    panic::guard,
+};
+
+/// Data allocated by the core for translated code. Allocations are only freed while no VCPU
+/// is executing translated code, so the pointer can be moved between threads
+struct Allocation {
+    /// The allocated data
+    ptr: *mut c_void,
+    /// Frees `ptr` with its original type
+    free: unsafe fn(*mut c_void),
+}
+
+unsafe impl Send for Allocation {}
+
+lazy_static! {
+    /// Every allocation made since the translation cache was last flushed
+    static ref ALLOCATIONS: Mutex<Vec<Allocation>> = Mutex::new(Vec::new());
+}
+
+/// Free a boxed `T`
+///
+/// # Arguments
+///
+/// * `ptr` - The pointer returned by `Box::into_raw` for the box
+unsafe fn free<T>(ptr: *mut c_void) {
+    drop(Box::from_raw(ptr as *mut T));
+}
+
+/// Move `data` to the heap until the next flush of the translation cache, returning a pointer
+/// to it
+///
+/// # Arguments
+///
+/// * `data` - The data to allocate
+pub(crate) fn alloc<T: Send + Sync + 'static>(data: T) -> *mut c_void {
+    let ptr = Box::into_raw(Box::new(data)) as *mut c_void;
+
+    ALLOCATIONS
+        .lock()
+        .expect("Could not lock TB allocations!")
+        .push(Allocation {
+            ptr,
+            free: free::<T>,
+        });
+
+    ptr
+}
+
+#[derive(Clone)]
+/// Callback data owned by the core and freed when the translation cache is flushed. This is
+/// meant for data registered with callbacks at translation time, which is only needed for as
+/// long as the translated code exists. Callbacks receive a pointer to the original value
+pub struct TBData(*mut c_void);
+
+// `new` only accepts data that can be shared between VCPU threads, and callbacks only get
+// shared references to it
+unsafe impl Send for TBData {}
+unsafe impl Sync for TBData {}
+
+impl TBData {
+    /// Instantiate a new `TBData`, moving `data` to the heap until the next flush
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The data to pass to callbacks
+    pub fn new<T: Send + Sync + 'static>(data: T) -> Self {
+        Self(alloc(data))
+    }
+
+    /// The data a callback was registered with, from the pointer QEMU passes it
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The data pointer the callback received
+    ///
+    /// # Safety
+    ///
+    /// The callback must have been registered with a `TBData` created from a `T`. The
+    /// reference must not be kept past the callback, since the data is freed on the next flush
+    pub unsafe fn get<'a, T>(data: *mut c_void) -> &'a T {
+        &*(data as *const T)
+    }
+}
+
+impl From<TBData> for *mut c_void {
+    fn from(data: TBData) -> Self {
+        data.0
+    }
+}
+
+/// Callback data that is never freed, for data that outlives translated code, like state
+/// shared by every translation of a block. The data is immutable, since every VCPU may read
+/// it at once: state the callbacks update goes behind atomics or a lock inside it. Callbacks
+/// receive a pointer to the original value
+pub struct CallbackData<T: 'static>(&'static T);
+
+impl<T: Send + Sync + 'static> CallbackData<T> {
+    /// Instantiate a new `CallbackData`, moving `data` to the heap for the rest of the run
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The data to pass to callbacks
+    pub fn new(data: T) -> Self {
+        Self(Box::leak(Box::new(data)))
+    }
+
+    /// Instantiate a new `CallbackData` from data that already lives for the rest of the run,
+    /// like a `static` or data leaked once and registered with many callbacks
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The data to pass to callbacks
+    pub fn shared(data: &'static T) -> Self {
+        Self(data)
+    }
+
+    /// The data a callback was registered with, from the pointer QEMU passes it
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The data pointer the callback received
+    ///
+    /// # Safety
+    ///
+    /// The callback must have been registered with a `CallbackData<T>`
+    pub unsafe fn get(data: *mut c_void) -> &'static T {
+        &*(data as *const T)
+    }
+}
+
+impl<T: 'static> Clone for CallbackData<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: 'static> Copy for CallbackData<T> {}
+
+impl<T: 'static> From<CallbackData<T>> for *mut c_void {
+    fn from(data: CallbackData<T>) -> Self {
+        data.0 as *const T as *mut c_void
+    }
+}
+
+/// A translated block, passed to the callbacks registered on it through
+/// `VCPUTBHandleCallback`
+pub struct TBHandle {
+    /// The callback to call with the handle when the block executes
+    cb: fn(u32, &TBHandle),
+    /// The virtual address of the first instruction of the block
+    vaddr: u64,
+    /// The size of the block in bytes
+    size: usize,
+    /// The number of instructions in the block
+    n_insns: usize,
+    /// The data the callback was registered with
+    data: Box<dyn Any + Send + Sync>,
+}
+
+impl TBHandle {
+    /// Allocate a handle for a translation block that is being translated. The handle is
+    /// freed on the next flush of the translation cache
+    ///
+    /// # Arguments
+    ///
+    /// * `tb` - The translation block being translated
+    /// * `cb` - The callback to call with the handle when the block executes
+    /// * `data` - The data to attach to the handle
+    pub(crate) fn alloc(
+        tb: *mut qemu_plugin_tb,
+        cb: fn(u32, &TBHandle),
+        data: impl Any + Send + Sync,
+    ) -> *mut c_void {
+        let (vaddr, n_insns) = unsafe { (qemu_plugin_tb_vaddr(tb), qemu_plugin_tb_n_insns(tb)) };
+        let size = if n_insns == 0 {
+            0
+        } else {
+            let last = unsafe { qemu_plugin_tb_get_insn(tb, n_insns - 1) };
+            let (last_vaddr, last_size) =
+                unsafe { (qemu_plugin_insn_vaddr(last), qemu_plugin_insn_size(last)) };
+            (last_vaddr - vaddr) as usize + last_size
+        };
+
+        alloc(Self {
+            cb,
+            vaddr,
+            size,
+            n_insns,
+            data: Box::new(data),
+        })
+    }
+
+    /// The virtual address of the first instruction of the block
+    pub fn vaddr(&self) -> u64 {
+        self.vaddr
+    }
+
+    /// The size of the block in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The number of instructions in the block
+    pub fn n_insns(&self) -> usize {
+        self.n_insns
+    }
+
+    /// The data the callback was registered with, if it is a `T`
+    pub fn data<T: Any>(&self) -> Option<&T> {
+        self.data.downcast_ref::<T>()
+    }
+}
+
+/// Called by QEMU when a block with a `VCPUTBHandleCallback` executes, with the handle
+/// allocated for it as the data pointer
+pub(crate) unsafe extern "C" fn on_tb_handle_exec(vcpu_index: u32, data: *mut c_void) {
+    let handle = &*(data as *const TBHandle);
+    guard("vcpu_tb_exec", || (handle.cb)(vcpu_index, handle));
+}
+
+/// Free every allocation. This must only be called once the translation cache has been
+/// flushed, when no callback can receive them anymore
+pub(crate) fn free_allocations() {
+    let allocations: Vec<Allocation> = ALLOCATIONS
+        .lock()
+        .expect("Could not lock TB allocations!")
+        .drain(..)
+        .collect();
+
+    for allocation in allocations {
+        unsafe { (allocation.free)(allocation.ptr) };
+    }
+}
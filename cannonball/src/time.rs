@@ -0,0 +1,120 @@
+//! Controlling virtual time
+//!
+//! A plugin can ask QEMU for control of the virtual clock, the clock the guest reads its time
+//! from. Once granted, the clock no longer follows the host, and only moves when the plugin
+//! moves it. Pacing the clock by the instructions executed, like `-icount` does, makes the time
+//! a guest observes deterministic from run to run. The time the plugin set can also be used to
+//! timestamp events with guest time rather than host time.
+//!
+//! Only one plugin can control time, and only in system emulation: user mode programs read
+//! their time from the host kernel, which the plugin cannot move.
+//!
+//! Time control is only available with plugin API version 2 (QEMU 9.0) and later, so this
+//! module requires the `plugin-api-v2` feature.
+//!
+//! ```
+//! // Example advancing the clock by one nanosecond per instruction executed
+//! use std::ffi::c_void;
+//!
+//! use inventory;
+//! use once_cell::sync::Lazy;
+//! use cannonball::api::{qemu_plugin_tb, qemu_plugin_tb_n_insns};
+//! use cannonball::callbacks::{
+//!     RegisterTBExec, StaticCallbackType, VCPUTBExecCallback, VCPUTBTransCallback,
+//! };
+//! use cannonball::time::TimeControl;
+//!
+//! static CLOCK: Lazy<TimeControl> =
+//!     Lazy::new(|| TimeControl::request().expect("Another plugin controls time!"));
+//!
+//! #[derive(Clone)]
+//! struct Insns(usize);
+//!
+//! impl From<Insns> for *mut c_void {
+//!     fn from(insns: Insns) -> Self {
+//!         insns.0 as *mut c_void
+//!     }
+//! }
+//!
+//! fn on_tb_exec(_vcpu_index: u32, data: *mut c_void) {
+//!     CLOCK.advance_ns(data as i64);
+//! }
+//!
+//! fn on_tb_trans(_id: u64, tb: *mut qemu_plugin_tb) {
+//!     let n_insns = unsafe { qemu_plugin_tb_n_insns(tb) };
+//!     VCPUTBExecCallback::new(on_tb_exec, Insns(n_insns)).register(tb);
+//! }
+//!
+//! inventory::submit! {
+//!     static tcb: Lazy<VCPUTBTransCallback> = Lazy::new(|| VCPUTBTransCallback::new(on_tb_trans));
+//!     StaticCallbackType::VCPUTBTrans(&tcb)
+//! }
+//! ```
+
+use std::{
+    ffi::c_void,
+    sync::atomic::{AtomicI64, Ordering},
+};
+
+use crate::api::{qemu_plugin_request_time_control, qemu_plugin_update_ns};
+
+/// Control of the virtual clock, granted to the plugin by QEMU
+#[derive(Debug)]
+pub struct TimeControl {
+    /// The opaque handle proving the plugin was granted control
+    handle: *const c_void,
+    /// The time the clock was last moved to, in nanoseconds
+    now: AtomicI64,
+}
+
+// The handle is only a token QEMU compares against, so it can be used from any VCPU thread
+unsafe impl Send for TimeControl {}
+unsafe impl Sync for TimeControl {}
+
+impl TimeControl {
+    /// Request control of the virtual clock. Returns `None` if another plugin already controls
+    /// it. The clock stands still from here on until the plugin moves it
+    pub fn request() -> Option<Self> {
+        let handle = unsafe { qemu_plugin_request_time_control() };
+
+        if handle.is_null() {
+            None
+        } else {
+            Some(Self {
+                handle,
+                now: AtomicI64::new(0),
+            })
+        }
+    }
+
+    /// The time the clock was last moved to by the plugin, in nanoseconds. Events can be
+    /// timestamped with it to order them in guest time
+    pub fn now_ns(&self) -> i64 {
+        self.now.load(Ordering::Acquire)
+    }
+
+    /// Move the clock to a time. The clock only moves forward, so earlier times than the
+    /// current one are ignored. QEMU moves the clock asynchronously, once the VCPU leaves the
+    /// code it is executing, so the guest may not observe the new time immediately
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - The time to move the clock to, in nanoseconds
+    pub fn update_ns(&self, time: i64) {
+        if self.now.fetch_max(time, Ordering::AcqRel) < time {
+            unsafe { qemu_plugin_update_ns(self.handle, time) };
+        }
+    }
+
+    /// Move the clock forward, returning the time it was moved to
+    ///
+    /// # Arguments
+    ///
+    /// * `delta` - How far to move the clock, in nanoseconds
+    pub fn advance_ns(&self, delta: i64) -> i64 {
+        let delta = delta.max(0);
+        let time = self.now.fetch_add(delta, Ordering::AcqRel) + delta;
+        unsafe { qemu_plugin_update_ns(self.handle, time) };
+        time
+    }
+}
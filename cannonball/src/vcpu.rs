@@ -0,0 +1,63 @@
+//! Enumerating VCPUs
+//!
+//! QEMU only tells a plugin about a VCPU from callbacks running on it, so code that runs
+//! outside of them (setup, at exit) has to ask QEMU for the VCPUs it knows of. `vcpus`
+//! iterates over the index of every VCPU that has been initialized, which can be used to
+//! collect per-VCPU state, like the entries of a `Scoreboard`, when the plugin exits.
+//!
+//! ```
+//! // Example printing the VCPUs the program ran on when it exits
+//! use inventory;
+//! use once_cell::sync::Lazy;
+//! use cannonball::callbacks::{AtExitCallback, StaticCallbackType};
+//! use cannonball::vcpu::vcpus;
+//!
+//! inventory::submit! {
+//!     static ecb: Lazy<AtExitCallback> = Lazy::new(|| {
+//!         AtExitCallback::new(|_| {
+//!             for vcpu_index in vcpus() {
+//!                 println!("vcpu {}", vcpu_index);
+//!             }
+//!         })
+//!     });
+//!     StaticCallbackType::AtExit(&ecb)
+//! }
+//! ```
+
+use std::cell::RefCell;
+
+#[cfg(feature = "plugin-api-v2")]
+use crate::api::qemu_plugin_num_vcpus;
+use crate::{
+    api::{qemu_plugin_id_t, qemu_plugin_vcpu_for_each},
+    plugin::Plugin,
+};
+
+thread_local! {
+    /// The VCPUs visited by `qemu_plugin_vcpu_for_each`. QEMU visits them on the calling
+    /// thread before returning, so each thread collects into its own list
+    static VISITED: RefCell<Vec<u32>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Record a VCPU visited by `qemu_plugin_vcpu_for_each`
+extern "C" fn visit(_id: qemu_plugin_id_t, vcpu_index: u32) {
+    VISITED.with(|visited| visited.borrow_mut().push(vcpu_index));
+}
+
+/// Iterate over the index of every VCPU that has been initialized, in index order. The
+/// VCPUs are collected when this is called, so VCPUs initialized while iterating are not
+/// visited. This must not be called before the plugin is installed
+pub fn vcpus() -> impl Iterator<Item = u32> {
+    unsafe { qemu_plugin_vcpu_for_each(Plugin::current().id(), Some(visit)) };
+
+    let mut vcpus = VISITED.with(|visited| visited.take());
+    vcpus.sort_unstable();
+    vcpus.into_iter()
+}
+
+/// The number of VCPUs that have been initialized. Unlike `vcpus`, this can be called from
+/// any callback
+#[cfg(feature = "plugin-api-v2")]
+pub fn num_vcpus() -> u32 {
+    unsafe { qemu_plugin_num_vcpus() }.max(0) as u32
+}
@@ -3,12 +3,12 @@
 //! This is the main entry point for the Jaivana driver, and puts *everything* together to
 //! create an all-in-one binary tracing tool.
 
+use cannonball_plugin_dist::find;
 use clap::Parser;
 use memfd_exec::{MemFdExecutable, Stdio};
 use qemu::qemu_x86_64;
 
 use std::{
-    env::temp_dir,
     fs::{read, write},
     io::{Read, Write},
     path::PathBuf,
@@ -21,18 +21,90 @@ struct Args {
     /// Whether to log instructions. If set, all instructions will be logged.
     #[clap(short, long)]
     pub insns: bool,
+    /// Only log one in every N instructions on each VCPU, for a statistical profile of long executions.
+    #[clap(short = 'r', long, default_value_t = 1)]
+    pub sample_rate: u64,
     /// Whether to log branches. If `insns` is not set, only branch instructions will be logged.
     #[clap(short, long)]
     pub branches: bool,
     /// Whether to log opcodes. If not set, only the instruction address will be log
     #[clap(short, long)]
     pub opcodes: bool,
+    /// Only log the first N bytes of each opcode, which is usually enough to tell instructions apart at a fraction of the trace size. All of them if not set.
+    #[clap(long, requires = "opcodes")]
+    pub opcode_size: Option<u64>,
     /// Whether to log syscalls. If set, all syscalls will be logged.
     #[clap(short, long)]
     pub syscalls: bool,
+    /// Whether to log translation blocks instead of instructions. If set, one event is logged per executed translation block, and `insns` and `branches` are ignored.
+    #[clap(short, long)]
+    pub tbs: bool,
+    /// Whether to log each translation block only the first time it executes. If set, the number of times each block executed is logged on exit.
+    #[clap(short, long)]
+    pub dedup: bool,
+    /// Whether to log control flow edges. If set, an edge is logged each time execution moves from one translation block to the next.
+    #[clap(short, long)]
+    pub edges: bool,
+    /// Whether to log calls and returns, with the call stack depth. Only supported for x86 programs.
+    #[clap(short, long)]
+    pub calls: bool,
+    /// Whether to log function entries and exits. Functions are read from the symbol table of the program.
+    #[clap(short, long)]
+    pub functions: bool,
+    /// Only log entries and exits of this function. May be given multiple times.
+    #[clap(short = 'F', long, requires = "functions")]
+    pub function: Vec<String>,
+    /// Whether to tag every event with the PID of the process that produced it, to tell apart the events of forked children.
+    #[clap(short = 'P', long)]
+    pub tag_pids: bool,
+    /// Whether to tag every event with the wall-clock time it was logged at, to merge the traces of separate programs into one timeline.
+    #[clap(long)]
+    pub tag_time: bool,
     /// Whether to log memory accesses. If set, memory accesses for already instrumented instructions will be logged.
     #[clap(short, long)]
     pub mem: bool,
+    /// Whether to log memory loads. Like `mem`, but stores are only logged if `writes` is set.
+    #[clap(long)]
+    pub reads: bool,
+    /// Whether to log memory stores. Like `mem`, but loads are only logged if `reads` is set.
+    #[clap(long)]
+    pub writes: bool,
+    /// Only log memory accesses to this range, given as 0xADDR:LEN:MODE where MODE is r, w or rw. Implies `mem`. May be given multiple times.
+    #[clap(short = 'W', long)]
+    pub watch: Vec<String>,
+    /// Log a breakpoint event each time the instruction at this address executes, e.g. 0x401000. May be given multiple times.
+    #[clap(long)]
+    pub break_pc: Vec<String>,
+    /// Only start tracing after this many instructions have executed.
+    #[clap(long)]
+    pub start_after_insns: Option<u64>,
+    /// Stop tracing after this many instructions have executed.
+    #[clap(long)]
+    pub stop_after_insns: Option<u64>,
+    /// Only start tracing after this many milliseconds.
+    #[clap(long)]
+    pub start_after_ms: Option<u64>,
+    /// Stop tracing after this many milliseconds.
+    #[clap(long)]
+    pub stop_after_ms: Option<u64>,
+    /// Only start tracing once the instruction at this address executes, e.g. 0x401000.
+    #[clap(long)]
+    pub start_pc: Option<String>,
+    /// Stop tracing once the instruction at this address executes, e.g. 0x401000.
+    #[clap(long)]
+    pub stop_pc: Option<String>,
+    /// The number of events the plugin buffers on each VCPU before writing them out. Larger batches are cheaper but arrive later. 4096 if not set.
+    #[clap(long)]
+    pub batch_size: Option<u64>,
+    /// The longest the plugin buffers events before writing them out, in milliseconds, for traces that log events slowly. No limit if not set.
+    #[clap(long)]
+    pub flush_interval_ms: Option<u64>,
+    /// Record the result of every system call, with the buffers it wrote to, to this replay log. Buffers are only recorded if the plugin is built with `plugin-api-v4`.
+    #[clap(long)]
+    pub replay_log: Option<PathBuf>,
+    /// Whether to count the instructions executed by class with inline counters, and log the histogram on exit.
+    #[clap(long)]
+    pub insn_mix: bool,
     /// An input file to feed to the program. If not set, the program will take input via this driver's stdin.
     #[clap(short = 'I', long)]
     pub input_file: Option<PathBuf>,
@@ -50,27 +122,28 @@ struct Args {
 fn main() {
     let args = Args::parse();
 
-    #[cfg(debug_assertions)]
-    let plugin = include_bytes!(concat!(
-        env!("CARGO_MANIFEST_DIR"),
-        "/../../target/debug/libjaivana.so"
-    ));
-
-    #[cfg(not(debug_assertions))]
-    let plugin = include_bytes!(concat!(
-        env!("CARGO_MANIFEST_DIR"),
-        "/../../target/release/libjaivana.so"
-    ));
-
-    let plugin_args = format!(
-        "log_pc={},log_branch={},log_opcode={},log_syscall={},log_mem={}",
-        args.insns, args.branches, args.opcodes, args.syscalls, args.mem
+    let mut plugin_args = format!(
+        "log_pc={},log_branch={},log_opcode={},log_syscall={},log_mem={},log_reads={},log_writes={},trace_tb={},log_edges={},log_calls={},dedup={},sample_rate={},tag_pids={},tag_time={}",
+        args.insns,
+        args.branches,
+        args.opcodes,
+        args.syscalls,
+        args.mem,
+        args.reads,
+        args.writes,
+        args.tbs,
+        args.edges,
+        args.calls,
+        args.dedup,
+        args.sample_rate,
+        args.tag_pids,
+        args.tag_time
     );
 
     let qemu = qemu_x86_64();
 
-    // Write the plugin to a temporary file
-    let plugin_path = temp_dir().join("libjaivana.so");
+    // Cargo builds the plugin next to this binary
+    let plugin_path = find("jaivana").expect("Failed to find the plugin");
 
     let program_path = args
         .program
@@ -79,7 +152,59 @@ fn main() {
         .to_string_lossy()
         .to_string();
 
-    write(&plugin_path, plugin).unwrap();
+    for (name, value) in [
+        ("start_after_insns", args.start_after_insns),
+        ("stop_after_insns", args.stop_after_insns),
+        ("start_after_ms", args.start_after_ms),
+        ("stop_after_ms", args.stop_after_ms),
+        ("batch_size", args.batch_size),
+        ("flush_interval_ms", args.flush_interval_ms),
+        ("opcode_size", args.opcode_size),
+    ] {
+        if let Some(value) = value {
+            plugin_args.push_str(&format!(",{}={}", name, value));
+        }
+    }
+
+    for (name, value) in [
+        ("trace_start_pc", &args.start_pc),
+        ("trace_stop_pc", &args.stop_pc),
+    ] {
+        if let Some(value) = value {
+            plugin_args.push_str(&format!(",{}={}", name, value));
+        }
+    }
+
+    for watch in &args.watch {
+        plugin_args.push_str(&format!(",watch={}", watch));
+    }
+
+    for pc in &args.break_pc {
+        plugin_args.push_str(&format!(",break_pc={}", pc));
+    }
+
+    if args.insn_mix {
+        plugin_args.push_str(",stats=insn_mix");
+    }
+
+    if let Some(replay_log) = &args.replay_log {
+        plugin_args.push_str(&format!(
+            ",replay_log={}",
+            replay_log.to_string_lossy().replace(',', ",,")
+        ));
+    }
+
+    // QEMU splits plugin arguments on commas, so commas in values must be doubled
+    if args.functions {
+        plugin_args.push_str(&format!(
+            ",trace_functions=true,binary={}",
+            program_path.replace(',', ",,")
+        ));
+
+        if !args.function.is_empty() {
+            plugin_args.push_str(&format!(",functions={}", args.function.join(",,")));
+        }
+    }
 
     let mut exe = MemFdExecutable::new("qemu-x86_64", qemu)
         .arg("-plugin")
@@ -0,0 +1,245 @@
+//! Breakpoints
+//!
+//! With `break_pc=0x...`, a `BreakpointEvent` is logged each time the instruction at that
+//! address is about to execute, whether or not the tracing window is open. `break_pc` can be
+//! given several times, and breakpoints can be added and removed while the program runs with
+//! the `break` and `delete` commands of the control channel.
+//!
+//! With `break_pause=on`, which needs `control=<socket>`, the VCPU that hit a breakpoint is
+//! also held until the driver sends `continue` (see `control`). Its events are written out
+//! first, so the driver sees the `BreakpointEvent` while the VCPU waits. If the driver
+//! disconnects, every held VCPU is let go and breakpoints no longer pause.
+//!
+//! While a VCPU is held, the driver can inspect it with the `regs` and `read` commands. The
+//! registers and memory of a VCPU can only be read on its own thread, so the control thread
+//! queues these as requests the held VCPU answers (see `Inspect`). Reading registers needs the
+//! `plugin-api-v2` feature (QEMU 9.0 and later), and reading memory `plugin-api-v4` (QEMU 9.2
+//! and later).
+//!
+//! With `break_entry=on` (also `plugin-api-v2`), a breakpoint is added at the entry point of
+//! the program, so a driver can stop it before it runs its first instruction.
+
+use serde_json::Value;
+
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Sender},
+        Condvar, Mutex,
+    },
+};
+
+#[cfg(feature = "plugin-api-v4")]
+use cannonball::mem::read_memory;
+#[cfg(feature = "plugin-api-v2")]
+use cannonball::{api::qemu_plugin_entry_code, registers::registers};
+#[cfg(feature = "plugin-api-v2")]
+use serde_json::json;
+
+use crate::watchpoints::parse_u64;
+
+/// A request for the state of a VCPU held at a breakpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inspect {
+    /// The values of every register, in the order of the gdbstub's register numbers
+    Registers,
+    /// `len` bytes of memory from `addr` on
+    Memory { addr: u64, len: usize },
+}
+
+impl Inspect {
+    /// Answer the request on the VCPU it was made of
+    fn answer(&self) -> Result<Value, String> {
+        match self {
+            #[cfg(feature = "plugin-api-v2")]
+            Inspect::Registers => Ok(json!({
+                "registers": registers()
+                    .iter()
+                    .map(|register| json!({
+                        "name": register.name(),
+                        "feature": register.feature(),
+                        "value": register.read(),
+                    }))
+                    .collect::<Vec<_>>()
+            })),
+            #[cfg(feature = "plugin-api-v4")]
+            Inspect::Memory { addr, len } => match read_memory(*addr, *len) {
+                Some(bytes) => Ok(json!({ "bytes": bytes })),
+                None => Err(format!("Could not read {} bytes at {:#x}", len, addr)),
+            },
+            #[allow(unreachable_patterns)]
+            _ => Err(format!(
+                "{:?} is not supported by the plugin API version Jaivana was built for",
+                self
+            )),
+        }
+    }
+}
+
+/// The breakpoints, and the VCPUs held at them
+#[derive(Debug, Default)]
+struct State {
+    /// The addresses of the breakpoints
+    pcs: HashSet<u64>,
+    /// The number of times held VCPUs were continued, so a VCPU knows when to go on
+    resumes: u64,
+    /// The number of VCPUs held at a breakpoint
+    held: usize,
+    /// Requests for the state of a held VCPU, with where to send the answers
+    requests: VecDeque<(Inspect, Sender<Result<Value, String>>)>,
+}
+
+/// Breakpoints, which may hold the VCPUs that hit them until they are continued
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    state: Mutex<State>,
+    /// Signalled when held VCPUs are continued or a request is made of them
+    resumed: Condvar,
+    /// Whether VCPUs are held at breakpoints
+    pause: AtomicBool,
+    /// Whether a breakpoint is still to be added at the entry point of the program
+    entry: AtomicBool,
+}
+
+impl Breakpoints {
+    /// Parse the breakpoints given as `break_pc` plugin arguments, and add them. Arguments
+    /// with the same name are collapsed into one by `Args`, so the raw arguments are read
+    ///
+    /// # Arguments
+    ///
+    /// * `raw` - The raw plugin arguments
+    pub fn parse(&self, raw: &[String]) -> Result<(), String> {
+        for pc in raw.iter().filter_map(|arg| arg.strip_prefix("break_pc=")) {
+            self.add(parse_u64(pc)?);
+        }
+
+        Ok(())
+    }
+
+    /// Add a breakpoint, returning whether there was none at `pc` yet
+    ///
+    /// # Arguments
+    ///
+    /// * `pc` - The address of the instruction to break at
+    pub fn add(&self, pc: u64) -> bool {
+        self.state.lock().unwrap().pcs.insert(pc)
+    }
+
+    /// Remove a breakpoint, returning whether there was one at `pc`
+    ///
+    /// # Arguments
+    ///
+    /// * `pc` - The address of the breakpoint
+    pub fn remove(&self, pc: u64) -> bool {
+        self.state.lock().unwrap().pcs.remove(&pc)
+    }
+
+    /// Whether there is a breakpoint at an address
+    ///
+    /// # Arguments
+    ///
+    /// * `pc` - The address
+    pub fn contains(&self, pc: u64) -> bool {
+        self.state.lock().unwrap().pcs.contains(&pc)
+    }
+
+    /// Whether there are no breakpoints
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().pcs.is_empty()
+    }
+
+    /// Whether VCPUs are held at breakpoints
+    pub fn pauses(&self) -> bool {
+        self.pause.load(Ordering::Relaxed)
+    }
+
+    /// Set whether VCPUs are held at breakpoints
+    ///
+    /// # Arguments
+    ///
+    /// * `pause` - Whether VCPUs are held
+    pub fn set_pause(&self, pause: bool) {
+        self.pause.store(pause, Ordering::Relaxed);
+    }
+
+    /// Add a breakpoint at the entry point of the program once it is known, which is when the
+    /// first block is translated
+    pub fn break_at_entry(&self) {
+        self.entry.store(true, Ordering::Relaxed);
+    }
+
+    /// Add the breakpoint at the entry point of the program, if it is still to be added. This
+    /// must be called on a VCPU thread
+    pub fn add_entry(&self) {
+        if self.entry.swap(false, Ordering::Relaxed) {
+            #[cfg(feature = "plugin-api-v2")]
+            self.add(unsafe { qemu_plugin_entry_code() });
+        }
+    }
+
+    /// Hold the calling VCPU until it is continued, answering requests for its state meanwhile
+    ///
+    /// # Arguments
+    ///
+    /// * `announce` - Tells the driver the VCPU is held. It is called once the VCPU counts as
+    ///   held, so the driver can make requests of it as soon as it knows
+    pub fn hold(&self, announce: impl FnOnce()) {
+        let resumes = {
+            let mut state = self.state.lock().unwrap();
+            state.held += 1;
+            state.resumes
+        };
+
+        announce();
+
+        let mut state = self.state.lock().unwrap();
+
+        while state.resumes == resumes {
+            if let Some((request, reply)) = state.requests.pop_front() {
+                drop(state);
+                reply.send(request.answer()).ok();
+                state = self.state.lock().unwrap();
+            } else {
+                state = self.resumed.wait(state).unwrap();
+            }
+        }
+    }
+
+    /// Make a request of a held VCPU and wait for its answer. If several are held, the first
+    /// to see the request answers it
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request
+    pub fn inspect(&self, request: Inspect) -> Result<Value, String> {
+        let (reply, answer) = channel();
+
+        {
+            let mut state = self.state.lock().unwrap();
+
+            if state.held == 0 {
+                return Err("No VCPU is held at a breakpoint".to_string());
+            }
+
+            state.requests.push_back((request, reply));
+            self.resumed.notify_all();
+        }
+
+        answer
+            .recv()
+            .map_err(|_| "The VCPU was continued before answering".to_string())?
+    }
+
+    /// Continue every held VCPU, returning how many there were. Requests they have not
+    /// answered yet are dropped
+    pub fn resume(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let held = state.held;
+        state.held = 0;
+        state.resumes += 1;
+        state.requests.clear();
+        self.resumed.notify_all();
+        held
+    }
+}
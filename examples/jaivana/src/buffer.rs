@@ -0,0 +1,851 @@
+//! Per-VCPU event buffers
+//!
+//! Instruction and memory events are produced on every executed instruction, so they must
+//! not go through the global context lock. Instead, every VCPU thread serializes its events
+//! into a buffer of its own and writes them out to stdout in batches. A buffer is flushed:
+//!
+//! * At the end of a translation block, once it holds at least `batch_size=N` events (4096 by
+//!   default), or once `flush_interval_ms=N` milliseconds have passed since it was last
+//!   flushed, if set, so that slow traces do not sit in the buffer
+//! * On every system call
+//! * When its VCPU exits, and for all buffers when QEMU exits
+//!
+//! Each buffer sits behind its own mutex only so that the exit path can drain buffers that
+//! belong to other threads. On the hot path that mutex is only ever taken by its owning
+//! thread, so it is never contended.
+//!
+//! When the traced program forks, parent and child write to the same stdout. Events can then
+//! be tagged with the PID of the process that produced them (see `tag_pid`) so consumers can
+//! tell the two apart. They can also be tagged with the wall-clock time they were buffered at
+//! (see `tag_time`), so the traces of programs traced by separate QEMU processes, like a
+//! client and a server, can be merged into one timeline.
+//!
+//! Events go to stdout as newline-delimited JSON by default. With `sink=fd:N` they go to an
+//! inherited file descriptor instead, and with `sink=fifo:<path>` to a named pipe, which is
+//! created if it does not exist and opened once a reader opens it, and with
+//! `sink=vsock:<cid>:<port>` to a vsock socket, for a consumer outside of the virtual machine
+//! QEMU runs in, like its host at CID 2, and with `sink=tcp:<host>:<port>` to a TCP
+//! connection, for a consumer on another machine. Network sinks are encrypted with
+//! `sink_psk_file=<path>` (see `secure`). With `sink=file:<path>` they go to files, which are
+//! rotated with `sink_rotate_size=N` and `sink_keep=N` (see `rotate`).
+//!
+//! A consumer that is not listening yet when QEMU starts refuses network sinks. They are
+//! connected again up to `sink_retries=N` times, `sink_retry_interval_ms=N` milliseconds
+//! apart (100 by default). If they still cannot be connected, events are written to the file
+//! `sink_fallback=<path>` instead, if given, and otherwise the plugin fails to install, so
+//! QEMU exits with the error rather than tracing to nowhere. Sinks carry the framed
+//! binary stream of the tools' `binary` format, each event a CBOR item preceded by its length
+//! as a little-endian `u32`, so a consumer at the other end of a shell pipeline reads events
+//! without telling them apart from the output of the program:
+//!
+//! ```text
+//! qemu-x86_64 -plugin ./libjaivana.so,log_pc=on,sink=fd:3 /bin/ls 3>&1 >/dev/null | analyzer
+//! ```
+//!
+//! A consumer that goes away while QEMU runs, like an analyzer that exits early, fails the
+//! writes of the events it misses. What happens then is chosen with `sink_on_error`, like the
+//! `OnError` policy of `cannonball_client::sender`: by default (`terminate`), events are no
+//! longer written out and QEMU keeps running, with `drop` the events that cannot be written
+//! out are dropped and later ones are written again, and with `retry` or `retry:N` they are
+//! written again up to 3 or `N` times, 100 milliseconds apart, before writing stops. A vsock
+//! or TCP sink that lost its connection is connected again before every attempt, and the new
+//! connection starts with the header of the trace, then the first event the old one did not
+//! take whole. Writes never raise `SIGPIPE`, which would be delivered to the guest or kill QEMU.
+//!
+//! The buffers also keep the statistics reported by `stats`: how much has been written out,
+//! how full a buffer got before it was flushed, and how many events were discarded.
+
+use cannonball_client::sender::OnError;
+use lazy_static::lazy_static;
+use libc::{
+    connect, fcntl, mkfifo, pthread_sigmask, sigaddset, sigemptyset, sigismember, sigpending,
+    sigset_t, sigtimedwait, sockaddr, sockaddr_vm, socket, socklen_t, timespec, AF_VSOCK, EPIPE,
+    F_GETFD, SIGPIPE, SIG_BLOCK, SIG_SETMASK, SOCK_CLOEXEC, SOCK_STREAM,
+};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use serde_json::to_writer;
+
+use crate::{events::Written, rotate::RotatingFile};
+
+use std::{
+    any::type_name,
+    collections::HashMap,
+    ffi::CString,
+    fs::{File, OpenOptions},
+    io::{self, stdout, ErrorKind, Read, Write},
+    mem::{size_of, zeroed},
+    net::TcpStream,
+    os::unix::{ffi::OsStrExt, io::FromRawFd},
+    path::Path,
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::sleep,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Number of events a buffer holds before it is flushed at the next translation block boundary
+static BATCH_SIZE: AtomicUsize = AtomicUsize::new(4096);
+
+/// The longest a buffer holds events before it is flushed at the next translation block
+/// boundary, in nanoseconds, or 0 for no limit. Checking costs reading the clock at every
+/// boundary, so there is no limit by default
+static FLUSH_INTERVAL: AtomicU64 = AtomicU64::new(0);
+
+/// The PID every event is tagged with, or 0 if events are not tagged
+static PID: AtomicU32 = AtomicU32::new(0);
+
+/// Whether every event is tagged with the time it was buffered at, which costs reading the
+/// clock per event
+static TIME: AtomicBool = AtomicBool::new(false);
+
+/// The number of events written out so far, by every buffer
+static WRITTEN_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+/// The number of bytes written out so far, by every buffer
+static WRITTEN_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// The time spent writing out events so far, by every buffer, in nanoseconds
+static WRITE_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// The most events any buffer held when it was flushed
+static HIGH_WATER: AtomicU64 = AtomicU64::new(0);
+
+/// The number of events discarded without being written out
+static DISCARDED_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether every buffer counts the events it buffers by type, which costs a lookup per event
+static COUNT_KINDS: AtomicBool = AtomicBool::new(false);
+
+/// What is done when events cannot be written out, like when the consumer went away
+static ON_ERROR: OnceCell<OnError> = OnceCell::new();
+
+/// Whether events are no longer written out, since they could not be and the `OnError` policy
+/// gave up
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// Whether an error writing events out was reported already, so it is only reported once
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Where events are written out, if not to stdout. Events written to a sink are framed
+static SINK: OnceCell<Mutex<Box<dyn Write + Send>>> = OnceCell::new();
+
+/// Connects a sink again
+type Connect = Box<dyn Fn() -> io::Result<Box<dyn Write + Send>> + Send + Sync>;
+
+/// Connects a vsock or TCP sink again, if the sink is one
+static RECONNECT: OnceCell<Connect> = OnceCell::new();
+
+/// The framed header of the trace, written at the start of a connection made again. The
+/// plugin writes it out before any other event, so it is the first frame of the first batch
+static HEADER: OnceCell<Vec<u8>> = OnceCell::new();
+
+#[derive(Serialize)]
+/// An event tagged with the PID of the process that produced it, or the time it was produced
+struct Tagged<'a, T: Serialize> {
+    /// The PID of the process
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<u32>,
+    /// The time the event was buffered at, in nanoseconds since the Unix epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time_ns: Option<u64>,
+    /// The event
+    #[serde(flatten)]
+    event: &'a T,
+}
+
+/// Newline-delimited JSON events waiting to be written out
+struct EventBuffer {
+    /// Serialized events, one per line
+    data: Vec<u8>,
+    /// Number of events in `data`
+    events: usize,
+    /// Number of events buffered so far by type, if counted
+    kinds: HashMap<&'static str, u64>,
+    /// When the buffer was last flushed
+    flushed: Instant,
+}
+
+impl EventBuffer {
+    /// Instantiate a new empty buffer
+    fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            events: 0,
+            kinds: HashMap::new(),
+            flushed: Instant::now(),
+        }
+    }
+
+    /// Serialize an event onto the end of the buffer
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event to serialize
+    fn push<T: Serialize>(&mut self, event: &T) {
+        let pid = Some(PID.load(Ordering::Relaxed)).filter(|pid| *pid != 0);
+        let time_ns = TIME.load(Ordering::Relaxed).then(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64)
+        });
+
+        let encoded = if pid.is_none() && time_ns.is_none() {
+            self.encode(event)
+        } else {
+            self.encode(&Tagged {
+                pid,
+                time_ns,
+                event,
+            })
+        };
+
+        if !encoded {
+            DISCARDED_EVENTS.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.events += 1;
+
+        if COUNT_KINDS.load(Ordering::Relaxed) {
+            *self.kinds.entry(type_name::<T>()).or_default() += 1;
+        }
+    }
+
+    /// Serialize an event onto the end of the buffer, as a JSON line, or as a framed CBOR item
+    /// if events are written to a sink. An event that cannot be serialized is left out, rather
+    /// than panicking in a QEMU callback, and whether it was written is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event to serialize
+    fn encode<T: Serialize>(&mut self, event: &T) -> bool {
+        let start = self.data.len();
+
+        if SINK.get().is_none() {
+            if to_writer(&mut self.data, event).is_err() {
+                self.data.truncate(start);
+                return false;
+            }
+            self.data.push(b'\n');
+            return true;
+        }
+
+        // The length is filled in once the item is written after it
+        self.data.extend([0; 4]);
+        if serde_cbor::to_writer(&mut self.data, event).is_err() {
+            self.data.truncate(start);
+            return false;
+        }
+        let len = (self.data.len() - start - 4) as u32;
+        self.data[start..start + 4].copy_from_slice(&len.to_le_bytes());
+        true
+    }
+
+    /// Write every buffered event to stdout or the sink in a single write and empty the buffer
+    fn flush(&mut self) {
+        self.flushed = Instant::now();
+
+        if self.data.is_empty() {
+            return;
+        }
+
+        let start = self.flushed;
+        if STOPPED.load(Ordering::Relaxed) || write_out(&self.data).is_err() {
+            DISCARDED_EVENTS.fetch_add(self.events as u64, Ordering::Relaxed);
+        } else {
+            WRITE_NANOS.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+            WRITTEN_EVENTS.fetch_add(self.events as u64, Ordering::Relaxed);
+            WRITTEN_BYTES.fetch_add(self.data.len() as u64, Ordering::Relaxed);
+            HIGH_WATER.fetch_max(self.events as u64, Ordering::Relaxed);
+        }
+
+        self.data.clear();
+        self.events = 0;
+    }
+}
+
+/// Write a batch of events out to stdout or the sink, handling errors as the `OnError` policy
+/// says. Fails if the batch was not written out, because it was dropped or writing stopped
+///
+/// # Arguments
+///
+/// * `data` - The batch
+fn write_out(data: &[u8]) -> io::Result<()> {
+    let first = RECONNECT.get().is_some()
+        && HEADER.get().is_none()
+        && HEADER
+            .set(data[..frame_end(data, 0).min(data.len())].to_vec())
+            .is_ok();
+    let mut written = 0;
+    let Err(e) = without_sigpipe(|| write_from(data, &mut written)) else {
+        return Ok(());
+    };
+
+    match ON_ERROR.get().copied().unwrap_or_default() {
+        OnError::Drop => {
+            report(&e, "dropping them");
+            Err(e)
+        }
+        OnError::Terminate => {
+            STOPPED.store(true, Ordering::Relaxed);
+            report(&e, "no longer writing events out");
+            Err(e)
+        }
+        OnError::Retry { attempts, interval } => {
+            let mut error = e;
+
+            for _ in 0..attempts {
+                sleep(interval);
+
+                // Only what was not written yet is written again, so the consumer never sees
+                // a frame twice
+                let write = || match RECONNECT.get() {
+                    Some(connect) => reconnect(connect, data, &mut written, first),
+                    None => write_from(data, &mut written),
+                };
+
+                match without_sigpipe(write) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => error = e,
+                }
+            }
+
+            STOPPED.store(true, Ordering::Relaxed);
+            report(&error, "no longer writing events out");
+            Err(error)
+        }
+    }
+}
+
+/// Write the rest of a batch of events out to stdout or the sink
+///
+/// # Arguments
+///
+/// * `data` - The batch
+/// * `written` - How much of the batch was written out already, advanced as it is written
+fn write_from(data: &[u8], written: &mut usize) -> io::Result<()> {
+    let mut sink;
+    let mut out;
+    let out: &mut dyn Write = match SINK.get() {
+        Some(s) => {
+            sink = s.lock().expect("Could not lock sink!");
+            &mut **sink
+        }
+        None => {
+            out = stdout().lock();
+            &mut out
+        }
+    };
+
+    while *written < data.len() {
+        match out.write(&data[*written..]) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => *written += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    out.flush()
+}
+
+/// Connect a sink again and write the rest of a batch of events to the new connection, after
+/// the header of the trace. An event the old connection took part of is written whole
+///
+/// # Arguments
+///
+/// * `connect` - Connects the sink
+/// * `data` - The batch
+/// * `written` - How much of the batch was written out already, advanced as it is written
+/// * `first` - Whether the batch is the first one, which starts with the header
+fn reconnect(
+    connect: impl Fn() -> io::Result<Box<dyn Write + Send>>,
+    data: &[u8],
+    written: &mut usize,
+    first: bool,
+) -> io::Result<()> {
+    let stream = connect()?;
+    *SINK
+        .get()
+        .expect("Reconnected without a sink!")
+        .lock()
+        .expect("Could not lock sink!") = stream;
+
+    let mut start = 0;
+
+    while start < *written && frame_end(data, start) <= *written {
+        start = frame_end(data, start);
+    }
+
+    *written = start;
+
+    if !(first && start == 0) {
+        if let Some(header) = HEADER.get() {
+            write_from(header, &mut 0)?;
+        }
+    }
+
+    write_from(data, written)
+}
+
+/// The end of the framed event starting at an offset of a batch, or the end of the batch if
+/// the offset is past its last event
+///
+/// # Arguments
+///
+/// * `data` - The batch
+/// * `start` - The offset the event starts at
+fn frame_end(data: &[u8], start: usize) -> usize {
+    data.get(start..start + 4).map_or(data.len(), |len| {
+        start + 4 + u32::from_le_bytes(len.try_into().unwrap()) as usize
+    })
+}
+
+/// Run a write with `SIGPIPE` blocked on this thread, so a consumer that went away fails it
+/// with `EPIPE` rather than raising the signal, which QEMU would deliver to the guest in user
+/// mode or die of
+///
+/// # Arguments
+///
+/// * `write` - The write
+fn without_sigpipe(write: impl FnOnce() -> io::Result<()>) -> io::Result<()> {
+    unsafe {
+        let mut pipe: sigset_t = zeroed();
+        let mut old: sigset_t = zeroed();
+        let mut pending: sigset_t = zeroed();
+        sigemptyset(&mut pipe);
+        sigaddset(&mut pipe, SIGPIPE);
+        pthread_sigmask(SIG_BLOCK, &pipe, &mut old);
+        sigpending(&mut pending);
+        let was_pending = sigismember(&pending, SIGPIPE) == 1;
+
+        let result = write();
+
+        // The write raised the signal, unless one was pending already, which is left alone
+        if !was_pending && matches!(&result, Err(e) if e.raw_os_error() == Some(EPIPE)) {
+            let now = timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            };
+            sigtimedwait(&pipe, null_mut(), &now);
+        }
+
+        pthread_sigmask(SIG_SETMASK, &old, null_mut());
+        result
+    }
+}
+
+/// Report an error writing events out, the first time there is one
+///
+/// # Arguments
+///
+/// * `e` - The error
+/// * `outcome` - What is done about it
+fn report(e: &io::Error, outcome: &str) {
+    if !REPORTED.swap(true, Ordering::Relaxed) {
+        eprintln!("Could not write events out ({}), {}", e, outcome);
+    }
+}
+
+lazy_static! {
+    /// Every buffer that has been created, so all of them can be drained on exit. This is only
+    /// locked when a thread creates its buffer and when QEMU exits
+    static ref BUFFERS: Mutex<Vec<Arc<Mutex<EventBuffer>>>> = Mutex::new(Vec::new());
+}
+
+thread_local! {
+    /// The buffer of the VCPU running on this thread
+    static BUFFER: Arc<Mutex<EventBuffer>> = {
+        let buffer = Arc::new(Mutex::new(EventBuffer::new()));
+        BUFFERS
+            .lock()
+            .expect("Could not lock buffers!")
+            .push(buffer.clone());
+        buffer
+    };
+}
+
+/// Buffer an event produced by the VCPU running on the current thread
+///
+/// # Arguments
+///
+/// * `event` - The event to buffer
+/// * `boundary` - Whether this event ends a translation block, in which case the buffer is
+///   flushed if it holds a full batch or has held events for longer than the flush interval
+pub fn push<T: Serialize>(event: &T, boundary: bool) {
+    BUFFER.with(|buffer| {
+        let mut buffer = buffer.lock().expect("Could not lock buffer!");
+        buffer.push(event);
+
+        if !boundary {
+            return;
+        }
+
+        let interval = FLUSH_INTERVAL.load(Ordering::Relaxed);
+
+        if buffer.events >= BATCH_SIZE.load(Ordering::Relaxed)
+            || (interval != 0 && buffer.flushed.elapsed().as_nanos() as u64 >= interval)
+        {
+            buffer.flush();
+        }
+    });
+}
+
+/// Set what is done when events cannot be written out
+///
+/// # Arguments
+///
+/// * `on_error` - The policy
+pub fn set_on_error(on_error: OnError) {
+    let _ = ON_ERROR.set(on_error);
+}
+
+/// Set the number of events a buffer holds before it is flushed at the next translation block
+/// boundary
+///
+/// # Arguments
+///
+/// * `batch_size` - The number of events, at least 1
+pub fn set_batch_size(batch_size: usize) {
+    BATCH_SIZE.store(batch_size.max(1), Ordering::Relaxed);
+}
+
+/// Set the longest a buffer holds events before it is flushed at the next translation block
+/// boundary
+///
+/// # Arguments
+///
+/// * `interval` - The longest time, or zero for no limit
+pub fn set_flush_interval(interval: Duration) {
+    FLUSH_INTERVAL.store(interval.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Connect to a consumer over a vsock socket
+///
+/// # Arguments
+///
+/// * `addr` - The address of the consumer, as `<cid>:<port>`
+fn connect_vsock(addr: &str) -> io::Result<File> {
+    let invalid = || {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid vsock address {}", addr),
+        )
+    };
+
+    let (cid, port) = addr.split_once(':').ok_or_else(invalid)?;
+    let mut raw: sockaddr_vm = unsafe { zeroed() };
+    raw.svm_family = AF_VSOCK as _;
+    raw.svm_cid = cid.parse().map_err(|_| invalid())?;
+    raw.svm_port = port.parse().map_err(|_| invalid())?;
+
+    let fd = unsafe { socket(AF_VSOCK, SOCK_STREAM | SOCK_CLOEXEC, 0) };
+
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Owned from here on, so it is closed if the connection fails
+    let file = unsafe { File::from_raw_fd(fd) };
+    let connected = unsafe {
+        connect(
+            fd,
+            &raw as *const sockaddr_vm as *const sockaddr,
+            size_of::<sockaddr_vm>() as socklen_t,
+        )
+    };
+
+    if connected == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(file)
+}
+
+/// Encrypt a network sink with a pre-shared key, if one is given
+///
+/// # Arguments
+///
+/// * `stream` - The connection to the consumer
+/// * `psk_file` - The file holding the pre-shared key
+fn encrypting<S: Read + Write + Send + 'static>(
+    stream: S,
+    psk_file: Option<&str>,
+) -> io::Result<Box<dyn Write + Send>> {
+    let Some(psk_file) = psk_file else {
+        return Ok(Box::new(stream));
+    };
+
+    #[cfg(feature = "noise")]
+    {
+        Ok(Box::new(crate::secure::SecureSink::connect(
+            stream, psk_file,
+        )?))
+    }
+
+    #[cfg(not(feature = "noise"))]
+    {
+        let _ = (stream, psk_file);
+        Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "sink_psk_file requires Jaivana to be built with the noise feature",
+        ))
+    }
+}
+
+/// How a sink is opened
+#[derive(Debug, Clone, Default)]
+pub struct SinkOptions<'a> {
+    /// A file holding the pre-shared key to encrypt a vsock or TCP sink with
+    pub psk_file: Option<&'a str>,
+    /// The number of times to connect a vsock or TCP sink again if it cannot be connected
+    pub retries: u32,
+    /// How long to wait before connecting again
+    pub retry_interval: Duration,
+    /// A file to write events to if a vsock or TCP sink cannot be connected at all
+    pub fallback: Option<&'a str>,
+    /// The size a file sink starts a new file at, if it is rotated
+    pub rotate_size: Option<u64>,
+    /// The number of files a rotated file sink keeps, if not all of them
+    pub keep: Option<u64>,
+}
+
+/// Connect to a consumer, again and again while it refuses the connection, like when it is
+/// not listening yet
+///
+/// # Arguments
+///
+/// * `options` - How many times to connect, and how long to wait in between
+/// * `connect` - Connects to the consumer
+fn retrying<T>(options: &SinkOptions, mut connect: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempts = 0;
+
+    loop {
+        match connect() {
+            // An invalid address stays invalid
+            Err(e) if attempts < options.retries && e.kind() != ErrorKind::InvalidInput => {
+                attempts += 1;
+                sleep(options.retry_interval);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// The fallback file of a network sink that could not be connected, or why it could not
+///
+/// # Arguments
+///
+/// * `sink` - The sink
+/// * `options` - How the sink was connected, with its fallback file
+/// * `e` - Why it could not be connected
+fn falling_back(
+    sink: &str,
+    options: &SinkOptions,
+    e: io::Error,
+) -> io::Result<Box<dyn Write + Send>> {
+    match options.fallback {
+        Some(path) if e.kind() != ErrorKind::InvalidInput => {
+            eprintln!(
+                "Could not connect to the sink {} ({}), writing events to {} instead",
+                sink, e, path
+            );
+            Ok(Box::new(File::create(path)?))
+        }
+        _ => Err(io::Error::new(
+            e.kind(),
+            format!(
+                "Could not connect to the sink {} after {} attempts: {}",
+                sink,
+                options.retries + 1,
+                e
+            ),
+        )),
+    }
+}
+
+/// Connect a network sink again the way it was first connected, if the connection is lost
+///
+/// # Arguments
+///
+/// * `options` - How the sink was connected
+/// * `connect` - Connects the sink, encrypted with the pre-shared key in a file, if given
+fn reconnecting(
+    options: &SinkOptions,
+    connect: impl Fn(Option<&str>) -> io::Result<Box<dyn Write + Send>> + Send + Sync + 'static,
+) {
+    let psk_file = options.psk_file.map(str::to_string);
+    let _ = RECONNECT.set(Box::new(move || connect(psk_file.as_deref())));
+}
+
+/// Write events out to a sink instead of stdout. Must be called before any event is buffered
+///
+/// # Arguments
+///
+/// * `sink` - The sink, as `fd:N` for an open file descriptor, `fifo:<path>` for a named
+///   pipe, `file:<path>` for files, `vsock:<cid>:<port>` for a vsock socket,
+///   `tcp:<host>:<port>` for a TCP connection or `stdout`
+/// * `options` - How to open the sink
+pub fn set_sink(sink: &str, options: &SinkOptions) -> io::Result<()> {
+    let invalid = |message: String| io::Error::new(ErrorKind::InvalidInput, message);
+    let network = sink.starts_with("vsock:") || sink.starts_with("tcp:");
+
+    if options.psk_file.is_some() && !network {
+        return Err(invalid(format!(
+            "Only vsock and TCP sinks are encrypted, not {}",
+            sink
+        )));
+    }
+
+    if (options.rotate_size.is_some() || options.keep.is_some()) && !sink.starts_with("file:") {
+        return Err(invalid(format!(
+            "Only file sinks are rotated, not {}",
+            sink
+        )));
+    }
+
+    let file: Box<dyn Write + Send> = if sink == "stdout" {
+        return Ok(());
+    } else if let Some(fd) = sink.strip_prefix("fd:") {
+        let fd = fd
+            .parse()
+            .map_err(|_| invalid(format!("Invalid file descriptor {}", fd)))?;
+
+        if unsafe { fcntl(fd, F_GETFD) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Box::new(unsafe { File::from_raw_fd(fd) })
+    } else if let Some(path) = sink.strip_prefix("fifo:") {
+        let c_path = CString::new(Path::new(path).as_os_str().as_bytes())
+            .map_err(|_| invalid(format!("Invalid path {}", path)))?;
+
+        // An existing pipe is reused, so the consumer can create it before QEMU starts
+        if unsafe { mkfifo(c_path.as_ptr(), 0o600) } == -1 {
+            let e = io::Error::last_os_error();
+
+            if e.kind() != ErrorKind::AlreadyExists {
+                return Err(e);
+            }
+        }
+
+        // Blocks until the consumer opens the pipe
+        Box::new(OpenOptions::new().write(true).open(path)?)
+    } else if let Some(path) = sink.strip_prefix("file:") {
+        Box::new(RotatingFile::create(
+            path,
+            options.rotate_size.unwrap_or(u64::MAX),
+            options.keep,
+        )?)
+    } else if let Some(addr) = sink.strip_prefix("vsock:") {
+        match retrying(options, || connect_vsock(addr)) {
+            Ok(stream) => {
+                let addr = addr.to_string();
+                reconnecting(options, move |psk_file| {
+                    encrypting(connect_vsock(&addr)?, psk_file)
+                });
+                encrypting(stream, options.psk_file)?
+            }
+            Err(e) => falling_back(sink, options, e)?,
+        }
+    } else if let Some(addr) = sink.strip_prefix("tcp:") {
+        match retrying(options, || TcpStream::connect(addr)) {
+            Ok(stream) => {
+                let addr = addr.to_string();
+                reconnecting(options, move |psk_file| {
+                    encrypting(TcpStream::connect(&addr)?, psk_file)
+                });
+                encrypting(stream, options.psk_file)?
+            }
+            Err(e) => falling_back(sink, options, e)?,
+        }
+    } else {
+        return Err(invalid(format!("Invalid sink {}", sink)));
+    };
+
+    SINK.set(Mutex::new(file))
+        .map_err(|_| invalid("Sink already set".to_string()))
+}
+
+/// Flush the buffer of the VCPU running on the current thread
+pub fn flush() {
+    BUFFER.with(|buffer| buffer.lock().expect("Could not lock buffer!").flush());
+}
+
+/// Flush the buffers of every VCPU
+pub fn flush_all() {
+    for buffer in BUFFERS.lock().expect("Could not lock buffers!").iter() {
+        buffer.lock().expect("Could not lock buffer!").flush();
+    }
+}
+
+/// Empty the buffers of every VCPU without writing them out. The child of a fork inherits the
+/// buffers of the parent, whose events are the parent's to write
+pub fn discard_all() {
+    for buffer in BUFFERS.lock().expect("Could not lock buffers!").iter() {
+        let mut buffer = buffer.lock().expect("Could not lock buffer!");
+        DISCARDED_EVENTS.fetch_add(buffer.events as u64, Ordering::Relaxed);
+        buffer.data.clear();
+        buffer.events = 0;
+    }
+}
+
+/// Tag every event buffered from now on with a PID
+///
+/// # Arguments
+///
+/// * `pid` - The PID of the process producing the events
+pub fn tag_pid(pid: u32) {
+    PID.store(pid, Ordering::Relaxed);
+}
+
+/// Change the PID events are tagged with, if they are tagged at all
+///
+/// # Arguments
+///
+/// * `pid` - The PID of the process producing the events
+pub fn retag_pid(pid: u32) {
+    if PID.load(Ordering::Relaxed) != 0 {
+        PID.store(pid, Ordering::Relaxed);
+    }
+}
+
+/// Tag every event buffered from now on with the time it was buffered at. The clock is the
+/// wall clock, shared by every process on the machine, so it is not monotonic
+pub fn tag_time() {
+    TIME.store(true, Ordering::Relaxed);
+}
+
+/// How much every buffer has written out so far
+pub fn written() -> Written {
+    Written {
+        events: WRITTEN_EVENTS.load(Ordering::Relaxed),
+        bytes: WRITTEN_BYTES.load(Ordering::Relaxed),
+        write_nanos: WRITE_NANOS.load(Ordering::Relaxed),
+        high_water: HIGH_WATER.load(Ordering::Relaxed),
+        discarded: DISCARDED_EVENTS.load(Ordering::Relaxed),
+    }
+}
+
+/// Count the events buffered from now on by type
+pub fn count_kinds() {
+    COUNT_KINDS.store(true, Ordering::Relaxed);
+}
+
+/// The number of events buffered so far by every buffer, by type name, like `TBEvent`. Empty
+/// unless `count_kinds` was called
+pub fn kinds() -> HashMap<String, u64> {
+    let mut kinds = HashMap::new();
+
+    for buffer in BUFFERS.lock().expect("Could not lock buffers!").iter() {
+        for (kind, count) in buffer.lock().expect("Could not lock buffer!").kinds.iter() {
+            let name = kind.rsplit("::").next().unwrap_or(kind).to_string();
+            *kinds.entry(name).or_default() += count;
+        }
+    }
+
+    kinds
+}
@@ -0,0 +1,151 @@
+//! Control channel
+//!
+//! With `control=<socket>`, the plugin connects to a Unix socket the driver listens on and
+//! takes commands from it while the program runs, on a thread of its own. Each command is a
+//! line, answered with one line of JSON, `{"ok":true}` or `{"error":"..."}` unless it says
+//! otherwise:
+//!
+//! * `set <arg> <value>` changes a boolean plugin argument selecting events, like
+//!   `set log_syscall true`, or `sample_rate`. Translation blocks are instrumented for the
+//!   selected events when translated, so every block is translated again
+//! * `flush` writes out the events buffered by every VCPU
+//! * `stats` answers with the number of events and bytes written out so far
+//! * `break <pc>` and `delete <pc>` add and remove a breakpoint, and `set break_pause <value>`
+//!   sets whether breakpoints hold the VCPUs that hit them
+//! * `continue` lets go of every VCPU held at a breakpoint, and answers with how many there
+//!   were as `resumed`
+//! * `regs` answers with the registers of a held VCPU as `registers`, a list of their `name`,
+//!   gdbstub `feature` and `value` in bytes, and `read <addr> <len>` with `len` bytes of its
+//!   memory from `addr` on as `bytes` (see `breakpoints`)
+//!
+//! When the driver disconnects, held VCPUs are let go, since nothing could continue them.
+
+use cannonball::plugin::Plugin;
+use serde_json::{json, Value};
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    sync::atomic::Ordering,
+    thread::spawn,
+};
+
+use crate::{
+    breakpoints::Inspect, buffer, watchpoints::parse_u64, BREAKPOINTS, CONTEXT, SAMPLE_RATE,
+};
+
+/// Connect to the driver and handle its commands on a new thread, until it disconnects
+///
+/// # Arguments
+///
+/// * `socket` - The Unix socket the driver listens on
+pub fn connect(socket: PathBuf) {
+    let control = UnixStream::connect(socket).expect("Could not connect to driver!");
+
+    spawn(move || {
+        let mut commands = BufReader::new(&control);
+        let mut command = String::new();
+
+        loop {
+            command.clear();
+
+            if commands.read_line(&mut command).unwrap_or(0) == 0 {
+                break;
+            }
+
+            let reply = handle(command.trim_end()).unwrap_or_else(|e| json!({ "error": e }));
+
+            if writeln!(&control, "{}", reply).is_err() {
+                break;
+            }
+        }
+
+        BREAKPOINTS.set_pause(false);
+        BREAKPOINTS.resume();
+    });
+}
+
+/// Handle a command
+///
+/// # Arguments
+///
+/// * `command` - The command, without its newline
+fn handle(command: &str) -> Result<Value, String> {
+    let mut words = command.split_whitespace();
+
+    match (words.next(), words.next(), words.next()) {
+        (Some("set"), Some("sample_rate"), Some(value)) => {
+            let rate = value.parse::<u64>().map_err(|e| e.to_string())?;
+            SAMPLE_RATE.store(rate.max(1), Ordering::Relaxed);
+            Ok(json!({ "ok": true }))
+        }
+        (Some("set"), Some("break_pause"), Some(value)) => {
+            let pause = value.parse::<bool>().map_err(|e| e.to_string())?;
+            BREAKPOINTS.set_pause(pause);
+            Ok(json!({ "ok": true }))
+        }
+        (Some("set"), Some(arg), Some(value)) => {
+            let value = value.parse::<bool>().map_err(|e| e.to_string())?;
+
+            {
+                let mut jv = CONTEXT.write().unwrap();
+
+                // `log_mem` selects memory accesses in both directions
+                if arg == "log_mem" {
+                    jv.log_reads = value;
+                    jv.log_writes = value;
+                } else {
+                    let setting = match arg {
+                        "log_pc" => &mut jv.log_pc,
+                        "log_opcode" => &mut jv.log_opcode,
+                        "log_branch" => &mut jv.log_branch,
+                        "log_reads" => &mut jv.log_reads,
+                        "log_writes" => &mut jv.log_writes,
+                        "log_syscall" => &mut jv.log_syscall,
+                        "log_vcpu" => &mut jv.log_vcpu,
+                        "log_signals" => &mut jv.log_signals,
+                        "trace_tb" => &mut jv.trace_tb,
+                        "dedup" => &mut jv.dedup,
+                        "log_edges" => &mut jv.log_edges,
+                        "log_calls" => &mut jv.log_calls,
+                        _ => return Err(format!("Unknown argument {}", arg)),
+                    };
+                    *setting = value;
+                }
+            }
+
+            Plugin::current().reset(|_| {});
+            Ok(json!({ "ok": true }))
+        }
+        (Some(command @ ("break" | "delete")), Some(pc), None) => {
+            let pc = parse_u64(pc)?;
+
+            if command == "break" {
+                BREAKPOINTS.add(pc);
+            } else if !BREAKPOINTS.remove(pc) {
+                return Err(format!("No breakpoint at {:#x}", pc));
+            }
+
+            // Blocks are instrumented for breakpoints when translated
+            Plugin::current().reset(|_| {});
+            Ok(json!({ "ok": true }))
+        }
+        (Some("regs"), None, None) => BREAKPOINTS.inspect(Inspect::Registers),
+        (Some("read"), Some(addr), Some(len)) => BREAKPOINTS.inspect(Inspect::Memory {
+            addr: parse_u64(addr)?,
+            len: parse_u64(len)? as usize,
+        }),
+        (Some("continue"), None, None) => {
+            Ok(json!({ "ok": true, "resumed": BREAKPOINTS.resume() }))
+        }
+        (Some("flush"), None, None) => {
+            buffer::flush_all();
+            Ok(json!({ "ok": true }))
+        }
+        (Some("stats"), None, None) => {
+            serde_json::to_value(buffer::written()).map_err(|e| e.to_string())
+        }
+        _ => Err(format!("Unknown command {}", command)),
+    }
+}
@@ -1,82 +1,66 @@
-use serde::Serialize;
+//! Trace events
+//!
+//! The events are defined in `cannonball-events`, where consumers of traces can deserialize
+//! them. What builds them from the state of QEMU is here.
 
-#[derive(Debug, Serialize, Clone)]
-pub struct InsnEvent {
-    pub vcpu_idx: Option<u32>,
-    pub vaddr: u64,
-    pub opcode: Option<Vec<u8>>,
-    pub branch: bool,
-}
+use cannonball::qemu_plugin_version;
 
-impl InsnEvent {
-    /// Instantiate a new `InsnEvent` from the raw arguments passed to the plugin
-    ///
-    /// # Arguments
-    ///
-    /// * `vaddr` - The virtual address of the instruction
-    /// * `opcode` - The opcode of the instruction, optional
-    /// * `branch` - Whether or not the instruction is a branch (in this case, `branch`
-    ///             is a bit of a misnomer -- it actually just means "last insn in the basic
-    ///             block" not exclusively *conditional* branches)
-    pub fn new(vcpu_idx: Option<u32>, vaddr: u64, opcode: Option<Vec<u8>>, branch: bool) -> Self {
-        Self {
-            vcpu_idx,
-            vaddr,
-            opcode,
-            branch,
-        }
-    }
-}
+pub use cannonball_events::jaivana::*;
 
-#[derive(Debug, Serialize, Clone)]
-pub struct MemEvent {
-    pub vaddr: u64,
-    pub is_sext: bool,
-    pub is_be: bool,
-    pub is_store: bool,
-    pub size_shift: u32,
-    pub insn: InsnEvent,
-}
+use crate::syscalls::SyscallFilter;
 
-impl MemEvent {
-    /// Instantiate a new `MemEvent` from the raw arguments passed to the plugin
-    ///
-    /// # Arguments
-    ///
-    /// * `vaddr` - The virtual address of the memory access
-    /// * `is_sext` - Whether or not the memory access is sign extended
-    /// * `is_be` - Whether or not the memory access is big endian
-    /// * `is_store` - Whether or not the memory access is a store
-    /// * `size_shift` - The size of the memory access, as a power of 2
-    /// * `insn` - The instruction that caused the memory access
-    pub fn new(
-        vaddr: u64,
-        is_sext: bool,
-        is_be: bool,
-        is_store: bool,
-        size_shift: u32,
-        insn: InsnEvent,
-    ) -> Self {
-        Self {
-            vaddr,
-            is_sext,
-            is_be,
-            is_store,
-            size_shift,
-            insn,
-        }
+/// Instantiate a new `HeaderEvent` describing the trace. It is always the first event
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target
+/// * `version` - The current and minimum plugin API versions supported by QEMU
+/// * `system_emulation` - Whether QEMU is emulating a whole system
+/// * `sample_rate` - Only one in `sample_rate` instruction events is logged on each VCPU.
+///   When this is greater than 1, instruction events are a statistical sample
+/// * `plugin_args` - The arguments passed to the plugin, which select the logged events
+/// * `syscalls` - The system calls logged, selected by name
+/// * `invocation` - How QEMU was invoked
+pub fn header(
+    target_name: Option<String>,
+    version: Option<(i32, i32)>,
+    system_emulation: Option<bool>,
+    sample_rate: u64,
+    plugin_args: Vec<String>,
+    syscalls: &SyscallFilter,
+    invocation: Invocation,
+) -> HeaderEvent {
+    HeaderEvent {
+        endianness: target_name.as_deref().map(
+            |target_name| match cannonball::guest::Endianness::of(target_name) {
+                cannonball::guest::Endianness::Little => Endianness::Little,
+                cannonball::guest::Endianness::Big => Endianness::Big,
+            },
+        ),
+        address_bits: target_name
+            .as_deref()
+            .map(cannonball::guest::address_bits_of),
+        target_name,
+        api_version: version.map(|(cur, _)| cur),
+        min_api_version: version.map(|(_, min)| min),
+        plugin_api_version: qemu_plugin_version,
+        system_emulation,
+        sample_rate,
+        plugin_args,
+        syscall_filter: syscalls.included(),
+        syscall_exclude: syscalls.excluded(),
+        invocation,
     }
 }
 
-#[derive(Debug, Serialize, Clone)]
-pub struct SyscallEvent {
-    pub num: i64,
-    pub rv: Option<i64>,
-    pub args: Vec<u64>,
-}
-
-impl SyscallEvent {
-    pub fn new(num: i64, rv: Option<i64>, args: Vec<u64>) -> Self {
-        Self { num, rv, args }
+/// The instruction set cannonball says an instruction was translated in, as logged
+///
+/// # Arguments
+///
+/// * `isa_mode` - The instruction set of the instruction
+pub fn isa_mode(isa_mode: cannonball::instrument::IsaMode) -> IsaMode {
+    match isa_mode {
+        cannonball::instrument::IsaMode::Arm => IsaMode::Arm,
+        cannonball::instrument::IsaMode::Thumb => IsaMode::Thumb,
     }
 }
@@ -0,0 +1,225 @@
+//! Control flow classification
+//!
+//! Calls and returns are detected at translation time by decoding just enough of the last
+//! instruction of each translation block to tell what kind of control transfer it performs.
+//! Their targets are only known once the next block starts executing, so the classification
+//! is stashed alongside the block and resolved in its execution callback.
+//!
+//! x86 (`x86_64` and `i386`), little-endian ARM (`aarch64`, and `arm` in both the ARM and
+//! Thumb instruction sets) and RISC-V (`riscv64` and `riscv32`) targets are supported. Their
+//! calls are the instructions that link a return address (`call`, `bl`, `blr`, `blx` and
+//! `jal` or `jalr` into `ra` or `t0`) and their returns are the instructions their ABIs return
+//! with (`ret`, `bx lr`, `pop {..., pc}` and `jalr` through `ra` or `t0`). Calls and returns
+//! cannot be told apart from other jumps on other targets, so setup fails there (see
+//! `supported`).
+
+use cannonball::instrument::IsaMode;
+
+/// The kind of control transfer performed by the last instruction of a translation block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    /// A call, which pushes a return address
+    Call,
+    /// A return to a previously pushed return address
+    Return,
+    /// Any other instruction, including jumps and fall through to the next block
+    Other,
+}
+
+/// Whether calls and returns are classified on a target
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target, e.g. `x86_64`
+pub fn supported(target_name: &str) -> bool {
+    matches!(
+        target_name,
+        "x86_64" | "i386" | "aarch64" | "arm" | "riscv64" | "riscv32"
+    )
+}
+
+/// Classify the control transfer performed by an instruction
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target, e.g. `x86_64`
+/// * `opcode` - The raw bytes of the instruction
+/// * `isa_mode` - The instruction set the instruction was translated in, on `arm`
+pub fn classify(target_name: &str, opcode: &[u8], isa_mode: Option<IsaMode>) -> Transfer {
+    match target_name {
+        "x86_64" => classify_x86(opcode, true),
+        "i386" => classify_x86(opcode, false),
+        "aarch64" => classify_aarch64(opcode),
+        // Only Thumb has 2 byte instructions
+        "arm" if isa_mode == Some(IsaMode::Thumb) || opcode.len() == 2 => classify_thumb(opcode),
+        "arm" => classify_arm(opcode),
+        "riscv64" => classify_riscv(opcode, false),
+        "riscv32" => classify_riscv(opcode, true),
+        _ => Transfer::Other,
+    }
+}
+
+/// Classify an x86 instruction
+///
+/// # Arguments
+///
+/// * `opcode` - The raw bytes of the instruction
+/// * `long_mode` - Whether the instruction is 64-bit code, where `0x40`-`0x4f` are REX
+///   prefixes instead of `inc`/`dec`
+fn classify_x86(opcode: &[u8], long_mode: bool) -> Transfer {
+    let mut bytes = opcode.iter().copied().skip_while(|b| {
+        matches!(
+            b,
+            0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0x66 | 0x67 | 0xf0 | 0xf2 | 0xf3
+        ) || (long_mode && (0x40..=0x4f).contains(b))
+    });
+
+    match (bytes.next(), bytes.next()) {
+        // call rel, and far call ptr16:32 outside of long mode
+        (Some(0xe8), _) => Transfer::Call,
+        (Some(0x9a), _) if !long_mode => Transfer::Call,
+        // call r/m and far call m16:32 are `ff /2` and `ff /3`
+        (Some(0xff), Some(modrm)) if matches!((modrm >> 3) & 7, 2 | 3) => Transfer::Call,
+        // near and far returns, with and without an immediate
+        (Some(0xc2 | 0xc3 | 0xca | 0xcb), _) => Transfer::Return,
+        _ => Transfer::Other,
+    }
+}
+
+/// Classify an AArch64 instruction
+///
+/// # Arguments
+///
+/// * `opcode` - The raw bytes of the instruction
+fn classify_aarch64(opcode: &[u8]) -> Transfer {
+    let Some(word) = word(opcode) else {
+        return Transfer::Other;
+    };
+
+    // bl, blr, and blraa, blrab and their forms with a zero modifier
+    if word & 0xfc00_0000 == 0x9400_0000
+        || word & 0xffff_fc1f == 0xd63f_0000
+        || word & 0xfeff_f800 == 0xd63f_0800
+    {
+        Transfer::Call
+    // ret, which returns through x30 unless given another register, and retaa and retab
+    } else if word & 0xffff_fc1f == 0xd65f_0000 || word & 0xffff_fbff == 0xd65f_0bff {
+        Transfer::Return
+    } else {
+        Transfer::Other
+    }
+}
+
+/// Classify an ARM (A32) instruction
+///
+/// # Arguments
+///
+/// * `opcode` - The raw bytes of the instruction
+fn classify_arm(opcode: &[u8]) -> Transfer {
+    let Some(word) = word(opcode) else {
+        return Transfer::Other;
+    };
+
+    // The condition is ignored, except for `0b1111`, which makes `bl` a `blx`
+    let unconditional = word >> 28 == 0xf;
+
+    // bl and blx to a label, and blx to a register
+    if (!unconditional && word & 0x0f00_0000 == 0x0b00_0000)
+        || word & 0xfe00_0000 == 0xfa00_0000
+        || (!unconditional && word & 0x0fff_fff0 == 0x012f_ff30)
+    {
+        return Transfer::Call;
+    }
+
+    if unconditional {
+        return Transfer::Other;
+    }
+
+    match word & 0x0fff_ffff {
+        // bx lr, mov pc, lr and pop {pc}
+        0x012f_ff1e | 0x01a0_f00e | 0x049d_f004 => Transfer::Return,
+        // pop {..., pc}, that is ldmia sp!, {..., pc}
+        word if word & 0x0fff_8000 == 0x08bd_8000 => Transfer::Return,
+        _ => Transfer::Other,
+    }
+}
+
+/// Classify a Thumb (T32) instruction
+///
+/// # Arguments
+///
+/// * `opcode` - The raw bytes of the instruction
+fn classify_thumb(opcode: &[u8]) -> Transfer {
+    let halfword = |i: usize| {
+        opcode
+            .get(i..i + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    };
+
+    match (halfword(0), halfword(2)) {
+        // bl and blx to a label
+        (Some(first), Some(second)) if first & 0xf800 == 0xf000 && second & 0xc000 == 0xc000 => {
+            Transfer::Call
+        }
+        // pop.w {..., pc}, and pop.w {pc}, that is ldr.w pc, [sp], #4
+        (Some(0xe8bd), Some(second)) if second & 0x8000 != 0 => Transfer::Return,
+        (Some(0xf85d), Some(0xfb04)) => Transfer::Return,
+        // blx to a register
+        (Some(first), None) if first & 0xff87 == 0x4780 => Transfer::Call,
+        // bx lr and pop {..., pc}
+        (Some(0x4770), None) => Transfer::Return,
+        (Some(first), None) if first & 0xff00 == 0xbd00 => Transfer::Return,
+        _ => Transfer::Other,
+    }
+}
+
+/// Classify a RISC-V instruction. Calls link the return address into `ra` or, for millicode,
+/// `t0`, and returns jump through either of them, as the hints of the ISA manual say
+///
+/// # Arguments
+///
+/// * `opcode` - The raw bytes of the instruction
+/// * `rv32` - Whether the instruction is 32-bit code, where compressed `c.jal` exists
+fn classify_riscv(opcode: &[u8], rv32: bool) -> Transfer {
+    let link = |reg: u32| reg == 1 || reg == 5;
+
+    // Compressed instructions are 2 bytes and do not end in `0b11`
+    if let [low, high] = *opcode {
+        let halfword = u16::from_le_bytes([low, high]);
+        let rs1 = (halfword as u32 >> 7) & 0x1f;
+
+        return match halfword {
+            // c.jal
+            _ if rv32 && halfword & 0xe003 == 0x2001 => Transfer::Call,
+            // c.jalr, which links into ra
+            _ if halfword & 0xf07f == 0x9002 && rs1 != 0 => Transfer::Call,
+            // c.jr ra, that is ret, and c.jr t0
+            _ if halfword & 0xf07f == 0x8002 && link(rs1) => Transfer::Return,
+            _ => Transfer::Other,
+        };
+    }
+
+    let Some(word) = word(opcode) else {
+        return Transfer::Other;
+    };
+    let rd = (word >> 7) & 0x1f;
+    let rs1 = (word >> 15) & 0x1f;
+
+    match word & 0x7f {
+        // jal
+        0x6f if link(rd) => Transfer::Call,
+        // jalr, which returns when it jumps through a link register without linking
+        0x67 if link(rd) => Transfer::Call,
+        0x67 if link(rs1) => Transfer::Return,
+        _ => Transfer::Other,
+    }
+}
+
+/// The little-endian word a 4 byte instruction is made of
+///
+/// # Arguments
+///
+/// * `opcode` - The raw bytes of the instruction
+fn word(opcode: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(opcode.try_into().ok()?))
+}
@@ -0,0 +1,140 @@
+//! Fork server
+//!
+//! Fuzzers run the same program over and over with different inputs, and starting QEMU and
+//! the program each time costs more than most runs. With `forkserver=<socket>` and
+//! `forkserver_pc=0x...`, the plugin instead runs the program once up to the instruction at
+//! `forkserver_pc`, like the end of its initialization, and serves runs from there on commands
+//! received on the Unix socket at `forkserver=<socket>`:
+//!
+//! * `run <path>` forks QEMU. The child continues from `forkserver_pc` with the file at `path`
+//!   as its stdin, and the server replies `started <pid>` with its PID. Once the child has
+//!   exited, and so has written all of its events, the server logs a `RunEndEvent`
+//! * Anything else, or closing the socket, makes QEMU exit
+//!
+//! Once connected, the server logs a `RunEndEvent` for run 0, so the driver can tell the
+//! events logged before the server started from those of the first run.
+//!
+//! The program must not read its input before `forkserver_pc`.
+
+use libc::{
+    close, dup2, fork, open, waitpid, O_RDONLY, STDIN_FILENO, WEXITSTATUS, WIFEXITED, WIFSIGNALED,
+    WTERMSIG,
+};
+
+use std::{
+    ffi::CString,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{buffer, events::RunEndEvent, PID};
+
+/// Whether the server has started. Children of the server execute `forkserver_pc` again
+/// without serving
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Where the fork server runs from, and where it gets commands
+pub struct ForkServer {
+    /// The socket commands are received on
+    socket: PathBuf,
+    /// The address of the instruction the server runs from
+    pc: u64,
+}
+
+impl ForkServer {
+    /// Instantiate a new `ForkServer`
+    ///
+    /// # Arguments
+    ///
+    /// * `socket` - The Unix socket to receive commands on, listened on by the driver
+    /// * `pc` - The address of the instruction to run the server from
+    pub fn new(socket: PathBuf, pc: u64) -> Self {
+        Self { socket, pc }
+    }
+
+    /// The address of the instruction the server runs from
+    pub fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    /// Serve runs until the driver is done, then exit. Called when the instruction at `pc`
+    /// executes, and only returns in the child of a run, which then goes on executing
+    pub fn serve(&self) {
+        if STARTED.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let control = UnixStream::connect(&self.socket).expect("Could not connect to driver!");
+
+        buffer::flush_all();
+        buffer::push(&RunEndEvent::new(0, 0, None, None), false);
+        buffer::flush();
+
+        let mut commands = BufReader::new(&control);
+        let mut command = String::new();
+        let mut runs = 0;
+
+        loop {
+            command.clear();
+
+            if commands.read_line(&mut command).unwrap_or(0) == 0 {
+                break;
+            }
+
+            let Some(input) = command.trim_end().strip_prefix("run ") else {
+                break;
+            };
+            let input = CString::new(input).expect("Input path contains a NUL byte!");
+
+            // Children start with copies of the buffers, which must not be written out twice
+            buffer::flush_all();
+            let child = unsafe { fork() };
+
+            if child < 0 {
+                panic!("Could not fork a run!");
+            }
+
+            if child == 0 {
+                unsafe {
+                    let fd = open(input.as_ptr(), O_RDONLY);
+
+                    if fd >= 0 {
+                        dup2(fd, STDIN_FILENO);
+                        close(fd);
+                    }
+                }
+
+                // A run is not a fork of the program, so no `ForkEvent` is logged for it
+                let pid = process::id();
+                PID.store(pid, Ordering::Relaxed);
+                buffer::retag_pid(pid);
+                return;
+            }
+
+            writeln!(&control, "started {}", child).expect("Could not reply to driver!");
+
+            let mut status = 0;
+            unsafe { waitpid(child, &mut status, 0) };
+            runs += 1;
+
+            let (exit_code, signal) = if WIFEXITED(status) {
+                (Some(WEXITSTATUS(status)), None)
+            } else if WIFSIGNALED(status) {
+                (None, Some(WTERMSIG(status)))
+            } else {
+                (None, None)
+            };
+
+            buffer::push(
+                &RunEndEvent::new(runs, child as u32, exit_code, signal),
+                false,
+            );
+            buffer::flush();
+        }
+
+        process::exit(0);
+    }
+}
@@ -0,0 +1,150 @@
+//! Function symbol table for function-level tracing
+//!
+//! Function entry points come from the symbol table of the traced binary, which the plugin
+//! reads itself (its path is passed as the `binary` argument). For position independent
+//! executables the symbol values are relative to a load bias that is only known once QEMU has
+//! loaded the binary. QEMU already knows it, and `qemu_plugin_insn_symbol` names the function
+//! containing an instruction, so the bias is inferred from the first translated instruction
+//! that QEMU attributes to a function in the table: the load bias is page aligned, so an
+//! address inside a function smaller than a page pins it down exactly.
+
+use goblin::elf::{
+    header::{EM_ARM, ET_DYN},
+    Elf,
+};
+
+use std::{collections::HashSet, fs::read, path::Path};
+
+/// Alignment of the load bias of position independent executables
+const PAGE_SIZE: u64 = 0x1000;
+
+#[derive(Debug, Clone)]
+/// A function from the symbol table of the traced binary
+pub struct Function {
+    /// The name of the function
+    pub name: String,
+    /// The address of the first instruction of the function, before relocation
+    pub start: u64,
+    /// The size of the function in bytes
+    pub size: u64,
+}
+
+#[derive(Debug)]
+/// The functions of the traced binary that are traced
+pub struct Functions {
+    /// Traced functions, sorted by start address
+    functions: Vec<Function>,
+    /// The load bias of the binary, if it is known yet
+    bias: Option<u64>,
+}
+
+impl Functions {
+    /// Load the function symbols of an ELF binary
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the binary
+    /// * `filter` - If given, only functions with these names are traced
+    pub fn load(path: &Path, filter: Option<&HashSet<String>>) -> Result<Self, String> {
+        let data = read(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        let elf = Elf::parse(&data).map_err(|e| format!("Could not parse ELF: {}", e))?;
+        // The lowest bit of the address of a Thumb function is set, and not part of it
+        let thumb_bit = if elf.header.e_machine == EM_ARM { 1 } else { 0 };
+
+        let mut functions: Vec<Function> = elf
+            .syms
+            .iter()
+            .filter(|sym| sym.is_function() && sym.st_value != 0)
+            .filter_map(|sym| {
+                let name = elf.strtab.get_at(sym.st_name)?;
+
+                let traced = match filter {
+                    Some(filter) => filter.contains(name),
+                    None => true,
+                };
+
+                if traced {
+                    Some(Function {
+                        name: name.to_string(),
+                        start: sym.st_value & !thumb_bit,
+                        size: sym.st_size,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        functions.sort_by_key(|f| f.start);
+        functions.dedup_by_key(|f| f.start);
+
+        Ok(Self {
+            functions,
+            // Executables that are not position independent are loaded where they say
+            bias: if elf.header.e_type == ET_DYN {
+                None
+            } else {
+                Some(0)
+            },
+        })
+    }
+
+    /// Whether the load bias is known, and functions can be looked up
+    pub fn resolved(&self) -> bool {
+        self.bias.is_some()
+    }
+
+    /// Try to infer the load bias from an instruction QEMU attributed to a function
+    ///
+    /// # Arguments
+    ///
+    /// * `vaddr` - The virtual address of the instruction
+    /// * `symbol` - The name of the function QEMU attributed the instruction to
+    pub fn infer_bias(&mut self, vaddr: u64, symbol: &str) {
+        if self.bias.is_some() {
+            return;
+        }
+
+        if let Some(function) = self
+            .functions
+            .iter()
+            .find(|f| f.name == symbol && f.size > 0 && f.size <= PAGE_SIZE)
+        {
+            // The bias is the page aligned value placing `vaddr` inside the function
+            let bias = (vaddr.wrapping_sub(function.start)) & !(PAGE_SIZE - 1);
+            let offset = vaddr.wrapping_sub(bias);
+
+            if (function.start..function.start + function.size).contains(&offset) {
+                self.bias = Some(bias);
+            }
+        }
+    }
+
+    /// Get the traced function starting at an address, if there is one
+    ///
+    /// # Arguments
+    ///
+    /// * `vaddr` - The virtual address of an instruction
+    pub fn entry(&self, vaddr: u64) -> Option<&Function> {
+        let offset = vaddr.wrapping_sub(self.bias?);
+
+        self.functions
+            .binary_search_by_key(&offset, |f| f.start)
+            .ok()
+            .map(|i| &self.functions[i])
+    }
+
+    /// Get the traced function containing an address, if there is one
+    ///
+    /// # Arguments
+    ///
+    /// * `vaddr` - The virtual address of an instruction
+    pub fn containing(&self, vaddr: u64) -> Option<&Function> {
+        let offset = vaddr.wrapping_sub(self.bias?);
+        let i = self.functions.partition_point(|f| f.start <= offset);
+
+        self.functions[..i]
+            .last()
+            .filter(|f| offset < f.start + f.size)
+    }
+}
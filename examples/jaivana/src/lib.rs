@@ -7,41 +7,207 @@
 //!
 //! Jaivana can log the following events:
 //!
-//! * Instruction execution:
+//! * Translation block execution (instead of instruction execution, with `trace_tb=on`):
+//!     * The address of the first instruction
+//!     * The size of the block in bytes and its number of instructions
+//! * Unique translation blocks (with `dedup=on`), logged only the first time they execute,
+//!   and a hit count for every block when QEMU exits
+//! * Control flow edges (with `log_edges=on`):
+//!     * The address of the last instruction of a translation block
+//!     * The address of the first instruction of the block executed next on the same VCPU
+//! * Calls and returns (with `log_calls=on`, on x86, ARM and RISC-V targets, see `flow`):
+//!     * The address of the call or return instruction
+//!     * The address it transferred control to
+//!     * The call stack depth after the transfer
+//! * Function entries and exits (with `trace_functions=on` and `binary=<path>`, optionally
+//!   only for the functions listed in `functions=foo,,bar`, on the targets `log_calls`
+//!   supports):
+//!     * The name of the function
+//!     * The address of its entry point, or of the return instruction leaving it
+//! * Instruction execution (optionally only one in every `sample_rate=N` instructions on
+//!   each VCPU):
 //!     * The program counter (PC)
-//!     * The instruction opcode
+//!     * The instruction opcode (optionally only its first `opcode_size=N` bytes)
+//!     * The instruction set it is in, on 32-bit ARM targets (`arm` or `thumb`)
 //!     * Whether the instruction terminates a basic block
-//!     * Memory reads and writes (read/write vaddr)
-//! * System calls:
+//!     * Memory reads and writes (read/write vaddr), with the instruction that made them. Only
+//!       the instructions whose events are logged, with `log_pc` or `log_branch`, are
+//!       instrumented to log their execution, so logging memory accesses alone does not
+//!       log instructions, nor pay for it
+//! * System calls (optionally only those listed in `syscall_filter=openat,,read`, or all but
+//!   those in `syscall_exclude=futex,,clock_gettime`, see `syscalls`):
 //!     * Syscall number
 //!     * Syscall arguments
 //!     * Syscall return value
+//! * VCPU lifecycle (with `log_vcpu=on`): VCPUs being created and destroyed and, in system
+//!   mode, going idle and resuming
+//! * Interrupts, exceptions and host calls (with `log_discon=on` and the `plugin-api-v5`
+//!   feature), mostly for kernels and firmware under system emulation:
+//!     * Which of the three it was
+//!     * The address of the instruction it happened at
+//!     * The address execution continued at, like the handler of the interrupt
+//! * Signals (with `log_signals=on`, user mode only), delivered to a handler of the guest or
+//!   killing it:
+//!     * The signal number and name, like `SIGSEGV`
+//!     * The address of the last instruction executed before it, which is the faulting
+//!       instruction when instructions are logged (the start of its block when only blocks
+//!       are), if known
+//!     * The address of the handler, if any
+//! * Memory map changes (with `log_maps=on`, user mode only): the memory the guest maps,
+//!   unmaps and changes the protection of (see `maps`)
+//!
+//! In user mode, `trace_modules=main,,libfoo.so` only instruments the code of the program and
+//! of the libraries listed, leaving out the C library and the dynamic loader (see `maps`).
+//!
+//! Every trace starts with a `HeaderEvent` recording the target, its byte order and the width
+//! of its addresses, the plugin API versions, the plugin arguments, the system calls selected
+//! by name, and how QEMU was invoked (its command line, and in user mode the environment the
+//! guest inherits). Events are encoded the same whatever the byte order of the target or the
+//! host: integers are integers of JSON or CBOR, and lengths framing a binary stream are little
+//! endian. Only opcodes, raw bytes copied from the guest, are in the byte order of the target.
+//!
+//! Every trace ends with a `FinalEvent`, logged when QEMU exits, recording how the guest
+//! exited (its exit code, or the signal that killed it, in user mode), how many instructions
+//! it executed, and how many events of each type were logged. A trace without one was cut
+//! short, like by an exec or by QEMU being killed.
+//!
+//! Tracing can be limited to a window of the execution with `start_after_insns=N`,
+//! `stop_after_insns=N`, `start_after_ms=N` and `stop_after_ms=N`, and between the
+//! executions of two instructions with `trace_start_pc=0x...` and `trace_stop_pc=0x...`
+//! (see `window`). A `TriggerEvent` marks where a PC trigger started or stopped tracing.
+//!
+//! Events are not printed as soon as they happen. Each VCPU thread serializes its events
+//! into its own buffer (see `buffer`), which is written out in batches so the hot path
+//! never contends on a lock shared with other VCPUs. With `sink=fd:N` or `sink=fifo:<path>`,
+//! they are written as a framed binary stream to a file descriptor or a named pipe instead of
+//! stdout, for shell pipelines, and with `sink=tcp:<host>:<port>` to another machine,
+//! encrypted with the key in `sink_psk_file=<path>` if set (see `buffer` and `secure`). A
+//! network sink that cannot be connected is tried again `sink_retries=N` times, and then
+//! replaced by the file `sink_fallback=<path>` if given, or fails the installation of the
+//! plugin. With `sink=file:<path>`, they are written to files, which long-running programs
+//! can bound with `sink_rotate_size=N` and `sink_keep=N` (see `rotate`). If events cannot be
+//! written out while QEMU runs, like when the consumer exits early, they stop being written
+//! out, or with `sink_on_error=drop` or `retry:N` are dropped or written again (see `buffer`).
+//!
+//! In user mode, a guest fork also forks QEMU, and the child keeps tracing to the same
+//! stdout. The child logs a `ForkEvent` first, and with `tag_pids=on` every event carries the
+//! PID of the process that produced it, so the trace of each process can be told apart.
+//! With `tag_time=on`, every event carries `time_ns`, the wall-clock time it was logged at in
+//! nanoseconds since the Unix epoch, so the traces of programs traced by separate QEMU
+//! processes can be merged into one timeline (see `cannonball merge`).
+//!
+//! When the guest calls `execve`, QEMU replaces itself with the new image and the plugin is
+//! gone, without ever reaching its exit callback. An `ExecEvent` is logged and every buffer
+//! is written out before the system call, so the trace up to the exec is complete. If the
+//! exec fails, tracing simply continues.
+//!
+//! For fuzzing, `forkserver=<socket>` and `forkserver_pc=0x...` run the program once up to an
+//! instruction and then fork a run from there for each input the driver sends over a Unix
+//! socket (see `forkserver`). A `RunEndEvent` follows the events of each run.
+//!
+//! With `control=<socket>`, the events logged can be changed while the program runs, by
+//! commands sent over a Unix socket (see `control`).
+//!
+//! With `break_pc=0x...`, a `BreakpointEvent` is logged when the instruction at the address
+//! executes, and with `break_pause=on` the VCPU waits there until the driver continues it over
+//! the control channel, which can read its registers and memory meanwhile (see `breakpoints`).
+//!
+//! With `watch=0xADDR:LEN:rw`, only the memory accesses touching a watched range of memory
+//! are logged (see `watchpoints`).
+//!
+//! With `replay_log=<path>`, the results of system calls are recorded in a replay log, for a
+//! replay harness to feed them back to a later run (see `replay`).
+//!
+//! With `stats=on`, `StatsEvent`s report how much the trace has logged so far and how fast,
+//! periodically and on exit (see `stats`).
+//!
+//! With `stats=insn_mix`, instructions are counted by class with inline operations, and an
+//! `InsnMixEvent` reports the counts on exit (see `mix`), on x86 targets only. `stats` may be given twice to
+//! collect both.
 
+mod breakpoints;
+mod buffer;
+mod control;
 mod events;
+mod flow;
+mod forkserver;
+mod functions;
+mod maps;
+mod mix;
+mod replay;
+mod rotate;
+#[cfg(feature = "noise")]
+mod secure;
+mod stats;
+mod syscalls;
+mod watchpoints;
+mod window;
 
 use cannonball::{
     api::{
         qemu_info_t, qemu_plugin_insn_data, qemu_plugin_insn_size, qemu_plugin_insn_vaddr,
-        qemu_plugin_mem_is_big_endian, qemu_plugin_mem_is_sign_extended, qemu_plugin_mem_is_store,
-        qemu_plugin_mem_size_shift, qemu_plugin_meminfo_t, qemu_plugin_tb, qemu_plugin_tb_get_insn,
-        qemu_plugin_tb_n_insns,
+        qemu_plugin_meminfo_t, qemu_plugin_op_QEMU_PLUGIN_INLINE_ADD_U64,
+        qemu_plugin_register_vcpu_tb_exec_inline, qemu_plugin_tb, qemu_plugin_tb_get_insn,
+        qemu_plugin_tb_n_insns, qemu_plugin_tb_vaddr,
     },
     args::{Args, QEMUArg},
     callbacks::{
-        RegisterInsnExec, SetupCallback, SetupCallbackType, StaticCallbackType,
-        VCPUInsnExecCallback, VCPUMemCallback, VCPUSyscallCallback, VCPUSyscallRetCallback,
-        VCPUTBTransCallback,
+        AtExitCallback, FallibleSetupCallback, RegisterInsnExec, RegisterTBExec, SetupCallbackType,
+        SetupError, StaticCallbackType, VCPUExitCallback, VCPUIdleCallback, VCPUInitCallback,
+        VCPUInsnExecCallback, VCPUMemCallback, VCPUResumeCallback, VCPUSyscallCallback,
+        VCPUSyscallRetCallback, VCPUTBExecCallback, VCPUTBHandleCallback, VCPUTBTransCallback,
+    },
+    exit::{self, ExitStatus},
+    guest::truncate_address,
+    instrument::{self, instructions, Instruction, InstrumentAction, TBInstrumenter},
+    mem::{MemAccessKind, MemInfo},
+    plugin::{Identity, Plugin},
+    signal::{self, signals_handled_at},
+    tb::{CallbackData, TBData, TBHandle},
+};
+#[cfg(feature = "plugin-api-v5")]
+use cannonball::{
+    api::{
+        qemu_plugin_discon_type, qemu_plugin_discon_type_QEMU_PLUGIN_DISCON_ALL,
+        qemu_plugin_discon_type_QEMU_PLUGIN_DISCON_EXCEPTION,
+        qemu_plugin_discon_type_QEMU_PLUGIN_DISCON_HOSTCALL,
+        qemu_plugin_discon_type_QEMU_PLUGIN_DISCON_INTERRUPT,
     },
+    callbacks::VCPUDisconCallback,
 };
 use inventory::submit;
 use lazy_static::lazy_static;
 use libc::c_void;
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 
-use events::{InsnEvent, MemEvent, SyscallEvent};
-use serde_json::to_string;
+use breakpoints::Breakpoints;
+use events::{
+    BlockHitsEvent, BreakpointEvent, CallEvent, EdgeEvent, ExecEvent, FinalEvent, ForkEvent,
+    FunctionEnterEvent, FunctionExitEvent, InsnEvent, Invocation, MemEvent, ReturnEvent,
+    SignalEvent, SysMemEvent, SyscallEvent, TBEvent, TriggerEvent, VcpuEvent, VcpuState,
+};
+#[cfg(feature = "plugin-api-v5")]
+use events::{Discon, DisconEvent};
+use flow::{classify, Transfer};
+use forkserver::ForkServer;
+use functions::Functions;
+use syscalls::{exec_pathname, SyscallFilter};
+use watchpoints::Watchpoints;
+use window::{Phase, Window};
 
-use std::{collections::HashMap, ffi::CStr, num::Wrapping, slice::from_raw_parts, sync::Mutex};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    ffi::CStr,
+    path::PathBuf,
+    process,
+    slice::from_raw_parts,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Mutex, RwLock,
+    },
+    time::Duration,
+};
 
 #[derive(Debug)]
 struct Context {
@@ -61,21 +227,33 @@ struct Context {
     // Settings enabling/disabling logging of events
     pub log_pc: bool,
     pub log_opcode: bool,
+    // The most bytes of each opcode to log, if not all of them
+    pub opcode_size: Option<usize>,
     pub log_branch: bool,
-    pub log_mem: bool,
+    // Log loads and stores. `log_mem` sets both
+    pub log_reads: bool,
+    pub log_writes: bool,
     pub log_syscall: bool,
-
-    // Temporary storage for the last syscall executed on each (plugin id, vcpu) pair
-    // stores the syscall arguments and number until the syscall returns, then the return
-    // value can be associated and the event can be dispatched and removed from this map
-    pub syscalls: HashMap<(u64, u32), SyscallEvent>,
-    // Sequential ephemeral key for indexing temporary instruction store
-    pub ikey: Wrapping<u64>,
-    pub klimit: Wrapping<u64>,
-    // Temporary store for instructions, indexed by ephemeral sequential key `ikey`
-    // stores an instruction from the time it is translated until it is either executed
-    // or a memory access is made, at which point the instruction is dispatched and removed
-    pub insns: HashMap<u64, InsnEvent>,
+    // The system calls logged, by name, when logging system calls
+    pub syscall_filter: SyscallFilter,
+    // Log interrupts, exceptions and host calls
+    pub log_discon: bool,
+    // Log VCPUs being created, destroyed, going idle and resuming
+    pub log_vcpu: bool,
+    // Log signals delivered to handlers or killing the guest
+    pub log_signals: bool,
+    // Log the system calls changing the memory map
+    pub log_maps: bool,
+    // Log one event per translation block execution instead of one per instruction
+    pub trace_tb: bool,
+    // Log each translation block once, and its hit count on exit
+    pub dedup: bool,
+    // Log control flow edges between translation blocks
+    pub log_edges: bool,
+    // Log calls and returns
+    pub log_calls: bool,
+    // Log entries and exits of the functions in `FUNCTIONS`
+    pub trace_functions: bool,
 }
 
 impl Context {
@@ -90,12 +268,21 @@ impl Context {
     /// * `args` - The original arguments to the plugin
     /// * `log_pc` - Whether to log the program counter
     /// * `log_opcode` - Whether to log the instruction opcode
+    /// * `opcode_size` - The most bytes of each opcode to log, if not all of them
     /// * `log_branch` - Whether to log whether the instruction terminates a basic block
-    /// * `log_mem` - Whether to log memory accesses
+    /// * `log_reads` - Whether to log memory loads
+    /// * `log_writes` - Whether to log memory stores
     /// * `log_syscall` - Whether to log system calls
-    /// * `syscalls` - The temporary storage for the last syscall executed on each (plugin id, vcpu) pair
-    /// * `ikey` - The sequential ephemeral key for indexing temporary instruction store
-    /// * `insns` - The temporary store for instructions, indexed by ephemeral sequential key `ikey`
+    /// * `syscall_filter` - The system calls logged
+    /// * `log_discon` - Whether to log interrupts, exceptions and host calls
+    /// * `log_vcpu` - Whether to log changes in the lifecycle and power state of VCPUs
+    /// * `log_signals` - Whether to log signals delivered to the guest
+    /// * `log_maps` - Whether to log the memory the guest maps, unmaps and protects
+    /// * `trace_tb` - Whether to log translation blocks instead of instructions
+    /// * `dedup` - Whether to log only the first execution of each translation block
+    /// * `log_edges` - Whether to log control flow edges between translation blocks
+    /// * `log_calls` - Whether to log calls and returns
+    /// * `trace_functions` - Whether to log function entries and exits
     pub fn new() -> Self {
         Self {
             target_name: None,
@@ -105,71 +292,136 @@ impl Context {
             args: None,
             log_pc: false,
             log_opcode: false,
+            opcode_size: None,
             log_branch: false,
-            log_mem: false,
+            log_reads: false,
+            log_writes: false,
             log_syscall: false,
-            syscalls: HashMap::new(),
-            ikey: Wrapping(0),
-            klimit: Wrapping(1024),
-            insns: HashMap::new(),
+            syscall_filter: SyscallFilter::default(),
+            log_discon: false,
+            log_vcpu: false,
+            log_signals: false,
+            log_maps: false,
+            trace_tb: false,
+            dedup: false,
+            log_edges: false,
+            log_calls: false,
+            trace_functions: false,
         }
     }
 
-    /// Return an incrementing sequential key for indexing temporary instruction store and reap
-    /// old entries in case something goes wrong and a callback is not triggered for them
-    pub fn ikey(&mut self) -> u64 {
-        let key = self.ikey;
-        let reap = key - self.klimit;
-        self.insns.remove(&reap.0);
-        self.ikey += Wrapping(1);
-        key.0
+    /// The memory accesses to log, if any
+    pub fn mem_access(&self) -> Option<MemAccessKind> {
+        match (self.log_reads, self.log_writes) {
+            (true, true) => Some(MemAccessKind::ReadWrite),
+            (true, false) => Some(MemAccessKind::Read),
+            (false, true) => Some(MemAccessKind::Write),
+            (false, false) => None,
+        }
     }
 }
 
 lazy_static! {
-    /// The global context for the tracing plugin
-    static ref CONTEXT: Mutex<Context> = Mutex::new(Context::new());
+    /// The global context for the tracing plugin. It is only written by `setup` and by the
+    /// commands of the control socket, so the callbacks of every VCPU read it at once
+    static ref CONTEXT: RwLock<Context> = RwLock::new(Context::new());
 }
 
-#[derive(Clone)]
-// `*mut c_void` is not `Send + Sync` so we need to use a newtype to wrap it. The `From` and
-// `Into` implementations are for convenience, we could just as easily `as` it around in
-// the code.
-struct ExecKey(*mut c_void);
+/// The hit counters of every translation block, by address and size, when deduplicating. Only
+/// locked when a block is translated and on exit
+static BLOCKS: Lazy<Mutex<HashMap<(u64, usize), &'static BlockHits>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
-unsafe impl Send for ExecKey {}
-unsafe impl Sync for ExecKey {}
+/// The functions of the target binary to log entries and exits of. The load bias is inferred
+/// from the blocks translated, so this is locked when a block is translated while tracing
+/// functions
+static FUNCTIONS: OnceCell<Mutex<Functions>> = OnceCell::new();
 
-impl ExecKey {
-    fn new(v: u64) -> Self {
-        Self(v as *mut c_void)
-    }
+/// The part of the execution that is traced. This is checked on every translation block
+/// execution while the window is bounded, so it is kept out of the context
+static WINDOW: OnceCell<Window> = OnceCell::new();
+
+/// The fork server, if runs are forked from an instruction
+static FORK_SERVER: OnceCell<ForkServer> = OnceCell::new();
+
+/// The breakpoints. They can be added while the program runs, so there always are some, if
+/// none at all
+static BREAKPOINTS: Lazy<Breakpoints> = Lazy::new(Breakpoints::default);
+
+/// The watched ranges of memory, if only accesses to them are logged. This is checked on every
+/// memory access, so it is kept out of the context
+static WATCHPOINTS: OnceCell<Watchpoints> = OnceCell::new();
+
+/// The PID of the traced process. A VCPU seeing a different PID is running in the child of a
+/// fork
+static PID: AtomicU32 = AtomicU32::new(0);
+
+/// Only one in `SAMPLE_RATE` instruction events is logged on each VCPU. This is read on every
+/// executed instruction, so it is kept out of the context
+static SAMPLE_RATE: AtomicU64 = AtomicU64::new(1);
+
+/// The number of instructions executed, counted by an inline operation on every translation
+/// block. Counted without atomics, so it is approximate with several VCPUs running at once
+static INSNS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+/// A translation block and the number of times it has executed. These are leaked and kept in
+/// `BLOCKS` so a block that is translated again keeps counting where it left off, and
+/// shared by every translation of the block as a `CallbackData`
+struct BlockHits {
+    /// The translation block, logged the first time it executes
+    evt: TBEvent,
+    /// The number of times the block has executed, on every VCPU
+    hits: AtomicU64,
 }
 
-impl Into<*mut c_void> for ExecKey {
-    fn into(self) -> *mut c_void {
-        self.0
-    }
+#[derive(Debug, Clone)]
+/// The bounds of a translation block, allocated at translation time for the edge callback
+struct TBBounds {
+    /// The virtual address of the first instruction of the block
+    start: u64,
+    /// The virtual address of the last instruction of the block
+    last: u64,
+    /// The control transfer performed by the last instruction of the block
+    transfer: Transfer,
 }
 
-impl From<*mut c_void> for ExecKey {
-    fn from(v: *mut c_void) -> Self {
-        Self(v)
-    }
+#[derive(Debug, Default)]
+/// Call tracking state of a VCPU
+struct CallState {
+    /// The call or return that ended the previous translation block, and its address. Its
+    /// target is the start of the next block executed
+    pending: Option<(Transfer, u64)>,
+    /// The current call stack depth, relative to the start of the trace
+    depth: i64,
 }
 
-impl Into<u64> for ExecKey {
-    fn into(self) -> u64 {
-        self.0 as u64
-    }
+thread_local! {
+    /// The last instruction of the previous translation block executed by each VCPU running on
+    /// this thread. Keyed by VCPU because with single-threaded TCG every VCPU runs on the same
+    /// thread
+    static LAST_TB_PC: RefCell<HashMap<u32, u64>> = RefCell::new(HashMap::new());
+    /// The call tracking state of each VCPU running on this thread
+    static CALLS: RefCell<HashMap<u32, CallState>> = RefCell::new(HashMap::new());
+    /// The number of instructions executed by each VCPU running on this thread, when sampling
+    static SAMPLES: RefCell<HashMap<u32, u64>> = RefCell::new(HashMap::new());
+    /// The VCPU and address of the last instruction or block that started executing on this
+    /// thread, which is where a signal raised by a fault was raised. A signal is delivered on
+    /// the thread that raised it
+    static LAST_PC: Cell<Option<(u32, u64)>> = const { Cell::new(None) };
+    /// The system call each (plugin id, VCPU) pair running on this thread is in, when logging
+    /// system calls. Its number and arguments are kept until it returns with its return value.
+    /// A system call returns on the thread it was entered on
+    static SYSCALLS: RefCell<HashMap<(u64, u32), SyscallEvent>> = RefCell::new(HashMap::new());
 }
 
 /// Called on plugin load with the arguments passed to the plugin on the command
 /// line. We use this function to initialize our global context with the information
 /// QEMU provides us about the target, including the name, whether we are running in
-/// system mode, and the number of VCPUs.
-extern "C" fn setup(info: *const qemu_info_t, args: &Args) {
-    let mut jv = CONTEXT.lock().unwrap();
+/// system mode, and the number of VCPUs. Fails if the arguments are invalid or the sink cannot
+/// be opened, which fails the installation of the plugin.
+fn setup(info: *const qemu_info_t, args: &Args) -> Result<(), SetupError> {
+    let mut jv = CONTEXT.write().unwrap();
     unsafe {
         let info = &*info;
         jv.target_name = Some(
@@ -196,139 +448,813 @@ extern "C" fn setup(info: *const qemu_info_t, args: &Args) {
         jv.log_opcode = *log_opcode;
     }
 
+    if let Some(QEMUArg::Int(opcode_size)) = args.args.get("opcode_size") {
+        jv.opcode_size = Some((*opcode_size).max(1) as usize);
+    }
+
     if let Some(QEMUArg::Bool(log_branch)) = args.args.get("log_branch") {
         jv.log_branch = *log_branch;
     }
 
     if let Some(QEMUArg::Bool(log_mem)) = args.args.get("log_mem") {
-        jv.log_mem = *log_mem;
+        jv.log_reads = *log_mem;
+        jv.log_writes = *log_mem;
+    }
+
+    // Only add to `log_mem`, so either direction can be logged on its own
+    if let Some(QEMUArg::Bool(log_reads)) = args.args.get("log_reads") {
+        jv.log_reads |= *log_reads;
+    }
+
+    if let Some(QEMUArg::Bool(log_writes)) = args.args.get("log_writes") {
+        jv.log_writes |= *log_writes;
+    }
+
+    let watchpoints = Watchpoints::parse(&args.raw)?;
+
+    if !watchpoints.is_empty() {
+        // Watching memory logs accesses to it even without `log_mem`
+        jv.log_reads = true;
+        jv.log_writes = true;
+        WATCHPOINTS
+            .set(watchpoints)
+            .expect("Watchpoints already set!");
     }
 
     if let Some(QEMUArg::Bool(log_syscall)) = args.args.get("log_syscall") {
         jv.log_syscall = *log_syscall;
     }
+
+    // Names are comma separated, which QEMU requires to be escaped as `,,`
+    let names = |name: &str| match args.args.get(name) {
+        Some(QEMUArg::Str(names)) => Some(names.as_str()),
+        _ => None,
+    };
+    jv.syscall_filter = SyscallFilter::parse(
+        &jv.target_name.clone().unwrap_or_default(),
+        names("syscall_filter"),
+        names("syscall_exclude"),
+    )?;
+
+    if let Some(QEMUArg::Bool(log_discon)) = args.args.get("log_discon") {
+        // Discontinuities are reported by QEMU with plugin API version 5
+        if *log_discon && cfg!(not(feature = "plugin-api-v5")) {
+            return Err(
+                "log_discon requires Jaivana to be built with the plugin-api-v5 feature".into(),
+            );
+        }
+
+        jv.log_discon = *log_discon;
+    }
+
+    if let Some(QEMUArg::Bool(log_vcpu)) = args.args.get("log_vcpu") {
+        jv.log_vcpu = *log_vcpu;
+    }
+
+    if let Some(QEMUArg::Bool(log_signals)) = args.args.get("log_signals") {
+        jv.log_signals = *log_signals;
+    }
+
+    if let Some(QEMUArg::Bool(log_maps)) = args.args.get("log_maps") {
+        jv.log_maps = *log_maps;
+    }
+
+    let trace_writable = matches!(args.args.get("trace_writable"), Some(QEMUArg::Bool(true)));
+
+    // Names are comma separated, which QEMU requires to be escaped as `,,`
+    let modules = match args.args.get("trace_modules") {
+        Some(QEMUArg::Str(names)) => Some(maps::Modules::parse(names)),
+        _ => None,
+    };
+
+    // Where QEMU loaded the program is read from it with plugin API version 2
+    if modules.as_ref().is_some_and(maps::Modules::program) && cfg!(not(feature = "plugin-api-v2"))
+    {
+        return Err(
+            "trace_modules=main requires Jaivana to be built with the plugin-api-v2 feature".into(),
+        );
+    }
+
+    if jv.log_maps || trace_writable || modules.is_some() {
+        // The memory map only changes through system calls in user mode
+        if jv.system_emulation != Some(false) {
+            return Err(
+                "log_maps, trace_writable and trace_modules require user mode emulation".into(),
+            );
+        }
+
+        maps::start(
+            &jv.target_name.clone().unwrap_or_default(),
+            trace_writable,
+            modules,
+        );
+    }
+
+    if let Some(QEMUArg::Bool(trace_tb)) = args.args.get("trace_tb") {
+        jv.trace_tb = *trace_tb;
+    }
+
+    if let Some(QEMUArg::Bool(dedup)) = args.args.get("dedup") {
+        jv.dedup = *dedup;
+    }
+
+    if let Some(QEMUArg::Bool(log_edges)) = args.args.get("log_edges") {
+        jv.log_edges = *log_edges;
+    }
+
+    if let Some(QEMUArg::Bool(log_calls)) = args.args.get("log_calls") {
+        jv.log_calls = *log_calls;
+    }
+
+    if jv.log_calls && !flow::supported(&jv.target_name.clone().unwrap_or_default()) {
+        return Err(format!(
+            "log_calls is not supported on {} targets",
+            jv.target_name.clone().unwrap_or_default()
+        )
+        .into());
+    }
+
+    if let Some(QEMUArg::Bool(trace_functions)) = args.args.get("trace_functions") {
+        jv.trace_functions = *trace_functions;
+    }
+
+    if let Some(QEMUArg::Int(sample_rate)) = args.args.get("sample_rate") {
+        SAMPLE_RATE.store((*sample_rate).max(1) as u64, Ordering::Relaxed);
+    }
+
+    if let Some(QEMUArg::Int(batch_size)) = args.args.get("batch_size") {
+        buffer::set_batch_size((*batch_size).max(1) as usize);
+    }
+
+    if let Some(QEMUArg::Int(interval)) = args.args.get("flush_interval_ms") {
+        buffer::set_flush_interval(Duration::from_millis((*interval).max(0) as u64));
+    }
+
+    if let Some(QEMUArg::Str(on_error)) = args.args.get("sink_on_error") {
+        buffer::set_on_error(on_error.parse()?);
+    }
+
+    if let Some(QEMUArg::Str(sink)) = args.args.get("sink") {
+        let str_arg = |name: &str| match args.args.get(name) {
+            Some(QEMUArg::Str(value)) => Some(value.as_str()),
+            _ => None,
+        };
+        let retries = match args.args.get("sink_retries") {
+            Some(QEMUArg::Int(retries)) => (*retries).clamp(0, u32::MAX as i64) as u32,
+            _ => 0,
+        };
+        let retry_interval = match args.args.get("sink_retry_interval_ms") {
+            Some(QEMUArg::Int(interval)) => (*interval).max(0) as u64,
+            _ => 100,
+        };
+        let rotate_size = match args.args.get("sink_rotate_size") {
+            Some(QEMUArg::Int(size)) => Some((*size).max(0) as u64),
+            Some(QEMUArg::Str(size)) => Some(rotate::parse_size(size)?),
+            _ => None,
+        };
+        let keep = match args.args.get("sink_keep") {
+            Some(QEMUArg::Int(keep)) => Some((*keep).max(0) as u64),
+            _ => None,
+        };
+
+        let options = buffer::SinkOptions {
+            psk_file: str_arg("sink_psk_file"),
+            retries,
+            retry_interval: Duration::from_millis(retry_interval),
+            fallback: str_arg("sink_fallback"),
+            rotate_size,
+            keep,
+        };
+
+        buffer::set_sink(sink, &options)?;
+    }
+
+    PID.store(process::id(), Ordering::Relaxed);
+
+    if let Some(QEMUArg::Str(path)) = args.args.get("replay_log") {
+        let max_bytes = match args.args.get("replay_max_bytes") {
+            Some(QEMUArg::Int(max_bytes)) => (*max_bytes).clamp(0, u32::MAX as i64) as u32,
+            _ => 64 * 1024,
+        };
+        let target_name = jv.target_name.clone().unwrap_or_default();
+        replay::start(&PathBuf::from(path), &target_name, max_bytes)
+            .map_err(|e| format!("Could not create the replay log {}: {}", path, e))?;
+    }
+
+    if let Some(QEMUArg::Bool(true)) = args.args.get("tag_pids") {
+        buffer::tag_pid(process::id());
+    }
+
+    if let Some(QEMUArg::Bool(true)) = args.args.get("tag_time") {
+        buffer::tag_time();
+    }
+
+    let arg_u64 = |name: &str| match args.args.get(name) {
+        Some(QEMUArg::Int(value)) => Some((*value).max(0) as u64),
+        // Addresses are usually given in hex, which is not parsed as an integer
+        Some(QEMUArg::Str(value)) => value
+            .strip_prefix("0x")
+            .and_then(|hex| u64::from_str_radix(hex, 16).ok()),
+        _ => None,
+    };
+
+    WINDOW
+        .set(Window::new(
+            arg_u64("start_after_insns"),
+            arg_u64("stop_after_insns"),
+            arg_u64("start_after_ms").map(Duration::from_millis),
+            arg_u64("stop_after_ms").map(Duration::from_millis),
+            arg_u64("trace_start_pc"),
+            arg_u64("trace_stop_pc"),
+        ))
+        .expect("Window already set!");
+
+    if let Some(QEMUArg::Str(socket)) = args.args.get("forkserver") {
+        let pc = arg_u64("forkserver_pc").ok_or("forkserver requires `forkserver_pc`")?;
+        FORK_SERVER
+            .set(ForkServer::new(PathBuf::from(socket), pc))
+            .ok()
+            .expect("Fork server already set!");
+    }
+
+    // `stats` may be given several times, to collect both the statistics of the trace and the
+    // instruction mix, so it is read from the raw arguments
+    for mode in args.raw.iter().filter_map(|arg| arg.strip_prefix("stats=")) {
+        match QEMUArg::new(mode) {
+            QEMUArg::Bool(true) => {
+                let interval = match args.args.get("stats_interval_ms") {
+                    Some(QEMUArg::Int(interval)) => (*interval).max(1) as u64,
+                    _ => 1000,
+                };
+                stats::start(Duration::from_millis(interval));
+            }
+            QEMUArg::Str(mode) if mode == "insn_mix" => {
+                if !mix::supported(&jv.target_name.clone().unwrap_or_default()) {
+                    return Err(format!(
+                        "stats=insn_mix is not supported on {} targets",
+                        jv.target_name.clone().unwrap_or_default()
+                    )
+                    .into());
+                }
+
+                mix::enable();
+            }
+            QEMUArg::Bool(false) => {}
+            _ => return Err(format!("Invalid stats mode {}", mode).into()),
+        }
+    }
+
+    BREAKPOINTS
+        .parse(&args.raw)
+        .map_err(|e| format!("Invalid breakpoint: {}", e))?;
+
+    if let Some(QEMUArg::Bool(true)) = args.args.get("break_pause") {
+        // Only the driver can continue a VCPU held at a breakpoint
+        if !args.args.contains_key("control") {
+            return Err("break_pause requires a control channel as `control`".into());
+        }
+
+        BREAKPOINTS.set_pause(true);
+    }
+
+    if let Some(QEMUArg::Bool(true)) = args.args.get("break_entry") {
+        // The entry point is read from QEMU with plugin API version 2
+        if cfg!(not(feature = "plugin-api-v2")) {
+            return Err(
+                "break_entry requires Jaivana to be built with the plugin-api-v2 feature".into(),
+            );
+        }
+
+        BREAKPOINTS.break_at_entry();
+    }
+
+    if let Some(QEMUArg::Str(socket)) = args.args.get("control") {
+        control::connect(PathBuf::from(socket));
+    }
+
+    if jv.trace_functions {
+        // Exits are the return instructions leaving a function
+        if !flow::supported(&jv.target_name.clone().unwrap_or_default()) {
+            return Err(format!(
+                "trace_functions is not supported on {} targets",
+                jv.target_name.clone().unwrap_or_default()
+            )
+            .into());
+        }
+
+        let Some(QEMUArg::Str(binary)) = args.args.get("binary") else {
+            return Err(
+                "trace_functions requires the path of the target binary as `binary`".into(),
+            );
+        };
+
+        // Names are comma separated, which QEMU requires to be escaped as `,,`
+        let filter = match args.args.get("functions") {
+            Some(QEMUArg::Str(names)) => Some(names.split(',').map(|n| n.to_string()).collect()),
+            _ => None,
+        };
+
+        let functions = Functions::load(&PathBuf::from(binary), filter.as_ref())
+            .map_err(|e| format!("Could not load function symbols: {}", e))?;
+        FUNCTIONS
+            .set(Mutex::new(functions))
+            .expect("Functions already set!");
+    }
+
+    // Events are always counted by type, for the `FinalEvent`
+    buffer::count_kinds();
+
+    // The header goes out before any other event so consumers know how to interpret them
+    let header = events::header(
+        jv.target_name.clone(),
+        jv.version,
+        jv.system_emulation,
+        SAMPLE_RATE.load(Ordering::Relaxed),
+        args.raw.clone(),
+        &jv.syscall_filter,
+        Invocation::current(jv.system_emulation.unwrap_or(false)),
+    );
+    buffer::push(&header, false);
+    buffer::flush();
+
+    Ok(())
 }
 
 submit! {
-    // Register the `SetupCallback` function to run during plugin setup
-    static scb: Lazy<SetupCallback> = Lazy::new(|| {
-        SetupCallback::new(|info, args| {
-            setup(info, args);
-        })
+    // Register the `setup` function to run during plugin setup
+    static scb: Lazy<FallibleSetupCallback> = Lazy::new(|| {
+        FallibleSetupCallback::new(setup)
     });
-    SetupCallbackType::Setup(&scb)
+    SetupCallbackType::FallibleSetup(&scb)
+}
+
+submit! {
+    // Name the plugin, which namespaces its arguments
+    Identity::new(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
 }
 
 /// Called on execution of each instruction after registration in `on_tb_trans`. This
 /// function just logs the instruction at the time it is executed (instead of at the time
 /// it is translated, which does not necessarily happen in execution order)
-unsafe extern "C" fn on_insn_exec(vcpu_idx: u32, data: *mut c_void) {
-    let mut jv = CONTEXT.lock().unwrap();
-    // Since `ExecKey` is a newtype we can just cast it back. If you get really fancy, you can
-    // use a `Box::into_raw(Box::new(T))` pattern to pass around a full object, but it is easier
-    // for the sake of example to store it globally. The callback types do support more
-    // complex use cases though.
-    let ekey: ExecKey = data.into();
-    let key: u64 = ekey.into();
+unsafe fn on_insn_exec(vcpu_idx: u32, data: *mut c_void) {
+    // The data pointer is the `InsnEvent` allocated for this instruction in `on_tb_trans`, so
+    // no lookup in the global context (and no lock) is needed on this path.
+    let sample_rate = SAMPLE_RATE.load(Ordering::Relaxed);
+    let insn_evt = TBData::get::<InsnEvent>(data);
+
+    // Sampled out instructions still executed, and may be the ones faulting
+    LAST_PC.with(|last| last.set(Some((vcpu_idx, insn_evt.vaddr))));
 
-    if let Some(insn_evt) = jv.insns.get(&key) {
-        let mut insn_evt = insn_evt.clone();
-        insn_evt.vcpu_idx = Some(vcpu_idx);
-        let insn_evt = to_string(&insn_evt).unwrap();
-        println!("{}", insn_evt);
+    if sample_rate > 1 {
+        let sampled = SAMPLES.with(|samples| {
+            let mut samples = samples.borrow_mut();
+            let count = samples.entry(vcpu_idx).or_default();
+            *count += 1;
+            *count % sample_rate == 0
+        });
 
-        jv.insns.remove(&key);
+        if !sampled {
+            return;
+        }
     }
+
+    let mut insn_evt = insn_evt.clone();
+    insn_evt.vcpu_idx = Some(vcpu_idx);
+    // The last instruction of a TB is the only point where the buffer may be flushed, so a
+    // batch never splits a basic block
+    let boundary = insn_evt.branch;
+    buffer::push(&insn_evt, boundary);
+}
+
+/// Called on execution of each translation block when tracing translation blocks
+fn on_tb_exec(vcpu_idx: u32, tb: &TBHandle) {
+    LAST_PC.with(|last| last.set(Some((vcpu_idx, tb.vaddr()))));
+    let tb_evt = TBEvent::new(Some(vcpu_idx), tb.vaddr(), tb.size(), tb.n_insns());
+    buffer::push(&tb_evt, true);
+}
+
+/// Called on execution of each translation block while the tracing window is bounded and has
+/// not closed yet, to count executed instructions. When the window opens or closes, the plugin
+/// is reset so every translation block is instrumented again for the new phase
+fn on_tb_window(_vcpu_idx: u32, tb: &TBHandle) {
+    if let Some(window) = WINDOW.get() {
+        if window.advance(tb.n_insns() as u64).is_some() {
+            Plugin::current().reset(|_| {});
+        }
+    }
+}
+
+/// Called on execution of a trigger instruction of the tracing window. If it opens or closes
+/// the window, the trigger is logged and the plugin is reset like in `on_tb_window`
+unsafe fn on_trigger(vcpu_idx: u32, data: *mut c_void) {
+    let mut evt = TBData::get::<TriggerEvent>(data).clone();
+    evt.vcpu_idx = Some(vcpu_idx);
+
+    if let Some(window) = WINDOW.get() {
+        if window.pull(evt.trigger).is_some() {
+            buffer::push(&evt, false);
+            buffer::flush();
+            Plugin::current().reset(|_| {});
+        }
+    }
+}
+
+/// Called on execution of an instruction with a breakpoint. The breakpoint is logged and
+/// written out, and if breakpoints pause, the VCPU is held until the driver continues it
+unsafe fn on_breakpoint(vcpu_idx: u32, data: *mut c_void) {
+    let pc = *TBData::get::<u64>(data);
+
+    // The breakpoint may have been removed since the block was translated
+    if !BREAKPOINTS.contains(pc) {
+        return;
+    }
+
+    let paused = BREAKPOINTS.pauses();
+    let announce = || {
+        buffer::push(&BreakpointEvent::new(Some(vcpu_idx), pc, paused), false);
+        buffer::flush();
+    };
+
+    if paused {
+        BREAKPOINTS.hold(announce);
+    } else {
+        announce();
+    }
+}
+
+/// Called on execution of the instruction the fork server runs from
+unsafe fn on_fork_point(_vcpu_idx: u32, _data: *mut c_void) {
+    if let Some(server) = FORK_SERVER.get() {
+        server.serve();
+    }
+}
+
+/// Called on execution of each translation block when deduplicating. The block is only logged
+/// the first time it executes, after that it is only counted
+unsafe fn on_tb_dedup(vcpu_idx: u32, data: *mut c_void) {
+    let block = CallbackData::<BlockHits>::get(data);
+    LAST_PC.with(|last| last.set(Some((vcpu_idx, block.evt.vaddr))));
+
+    if block.hits.fetch_add(1, Ordering::Relaxed) == 0 {
+        let mut tb_evt = block.evt.clone();
+        tb_evt.vcpu_idx = Some(vcpu_idx);
+        buffer::push(&tb_evt, true);
+    }
+}
+
+/// Called on execution of a translation block starting a signal handler of the guest, when
+/// logging signals. Reaching the handler is how the delivery of the signal shows, unless the
+/// guest calls it directly, which it cannot be told apart from
+fn on_signal_handler(vcpu_idx: u32, tb: &TBHandle) {
+    // The handler may have been replaced since the block was translated
+    let signals = signals_handled_at(tb.vaddr());
+
+    if signals.is_empty() || !WINDOW.get().map(Window::is_open).unwrap_or(true) {
+        return;
+    }
+
+    // With one handler for several signals, which was delivered is not known
+    let signal = match signals[..] {
+        [signal] => Some(signal),
+        _ => None,
+    };
+    let pc = LAST_PC
+        .with(Cell::get)
+        .filter(|(vcpu, _)| *vcpu == vcpu_idx)
+        .map(|(_, pc)| pc);
+
+    let evt = SignalEvent::new(
+        Some(vcpu_idx),
+        signal,
+        signal.and_then(signal::name).map(str::to_string),
+        pc,
+        Some(tb.vaddr()),
+        false,
+    );
+    buffer::push(&evt, false);
+}
+
+/// Called on execution of each translation block when logging edges. The edge from the end of
+/// the previous block executed by this VCPU to the start of this one is logged
+unsafe fn on_tb_edge(vcpu_idx: u32, data: *mut c_void) {
+    let bounds = TBData::get::<TBBounds>(data);
+
+    let src = LAST_TB_PC.with(|last| last.borrow_mut().insert(vcpu_idx, bounds.last));
+
+    if let Some(src) = src {
+        buffer::push(&EdgeEvent::new(vcpu_idx, src, bounds.start), false);
+    }
+}
+
+/// Called on execution of each translation block when logging calls. If the previous block
+/// executed by this VCPU ended in a call or return, this block is its target
+unsafe fn on_tb_call(vcpu_idx: u32, data: *mut c_void) {
+    let bounds = TBData::get::<TBBounds>(data);
+
+    CALLS.with(|calls| {
+        let mut calls = calls.borrow_mut();
+        let state = calls.entry(vcpu_idx).or_default();
+
+        match state.pending.take() {
+            Some((Transfer::Call, site)) => {
+                state.depth += 1;
+                buffer::push(
+                    &CallEvent::new(vcpu_idx, site, bounds.start, state.depth),
+                    false,
+                );
+            }
+            Some((Transfer::Return, site)) => {
+                state.depth -= 1;
+                buffer::push(
+                    &ReturnEvent::new(vcpu_idx, site, bounds.start, state.depth),
+                    false,
+                );
+            }
+            _ => {}
+        }
+
+        if bounds.transfer != Transfer::Other {
+            state.pending = Some((bounds.transfer, bounds.last));
+        }
+    });
+}
+
+/// Called on execution of the entry point of a traced function
+unsafe fn on_function_enter(vcpu_idx: u32, data: *mut c_void) {
+    let mut evt = TBData::get::<FunctionEnterEvent>(data).clone();
+    evt.vcpu_idx = Some(vcpu_idx);
+    buffer::push(&evt, false);
+}
+
+/// Called on execution of a return instruction in a traced function
+unsafe fn on_function_exit(vcpu_idx: u32, data: *mut c_void) {
+    let mut evt = TBData::get::<FunctionExitEvent>(data).clone();
+    evt.vcpu_idx = Some(vcpu_idx);
+    buffer::push(&evt, false);
+}
+
+/// Instrument the entry points and return instructions of traced functions in a translation
+/// block. Only these instructions get callbacks, so tracing functions is far cheaper than
+/// tracing instructions
+///
+/// # Arguments
+///
+/// * `functions` - The traced functions
+/// * `target_name` - The name of the QEMU target, used to recognize return instructions
+/// * `tb` - The translation block being translated
+fn instrument_functions(functions: &mut Functions, target_name: &str, tb: *mut qemu_plugin_tb) {
+    if !functions.resolved() {
+        for insn in instructions(tb) {
+            if let Some(symbol) = insn.symbol() {
+                functions.infer_bias(insn.vaddr(), &symbol.to_string_lossy());
+            }
+        }
+    }
+
+    let is_exit = |insn: &Instruction| {
+        insn.is_last()
+            && classify(target_name, insn.data(), insn.isa_mode()) == Transfer::Return
+            && functions.containing(insn.vaddr()).is_some()
+    };
+
+    let instrumenter = TBInstrumenter::new(|insn| {
+        if functions.entry(insn.vaddr()).is_some() || is_exit(insn) {
+            InstrumentAction::Callback
+        } else {
+            InstrumentAction::Skip
+        }
+    });
+
+    instrumenter.instrument(tb, |insn| {
+        if let Some(function) = functions.entry(insn.vaddr()) {
+            let evt = FunctionEnterEvent::new(None, function.name.clone(), insn.vaddr());
+            VCPUInsnExecCallback::new(on_function_enter, TBData::new(evt)).register(insn.raw());
+        }
+
+        if is_exit(insn) {
+            if let Some(function) = functions.containing(insn.vaddr()) {
+                let evt = FunctionExitEvent::new(None, function.name.clone(), insn.vaddr());
+                VCPUInsnExecCallback::new(on_function_exit, TBData::new(evt)).register(insn.raw());
+            }
+        }
+    });
 }
 
 /// Called on memory access by an instruction, but not necessarily before or after the instruction
-/// executes. Therefore, we use a second duplicate entry of the original isntruction to back-
-/// correlate memory accesses with executions, but we don't know which comes first.
-unsafe extern "C" fn on_mem_access(
+/// executes. The instruction is shared with the execution callback through the same
+/// `InsnEvent`, so memory accesses are correlated with their instruction directly.
+unsafe fn on_mem_access(
     vcpu_index: u32,
     info: qemu_plugin_meminfo_t,
     vaddr: u64,
     data: *mut c_void,
 ) {
-    let mut jv = CONTEXT.lock().unwrap();
-    let ekey: ExecKey = data.into();
-    let key: u64 = ekey.into();
-
-    if let Some(insn_evt) = jv.insns.get(&key) {
-        let mut insn_evt = insn_evt.clone();
-        insn_evt.vcpu_idx = Some(vcpu_index);
-
-        let is_sext = qemu_plugin_mem_is_sign_extended(info);
-        let is_be = qemu_plugin_mem_is_big_endian(info);
-        let is_store = qemu_plugin_mem_is_store(info);
-        let size_shift = qemu_plugin_mem_size_shift(info);
-
-        let mem_evt = MemEvent::new(
-            vaddr,
-            is_sext,
-            is_be,
-            is_store,
-            size_shift,
-            insn_evt.clone(),
-        );
+    let info = MemInfo::new(info);
+
+    // Checked before anything is copied, since most accesses are not watched
+    if let Some(watchpoints) = WATCHPOINTS.get() {
+        if !watchpoints.hits(vaddr, info.size() as u64, info.is_store()) {
+            return;
+        }
+    }
+
+    let mut insn_evt = TBData::get::<InsnEvent>(data).clone();
+    insn_evt.vcpu_idx = Some(vcpu_index);
+
+    #[cfg(feature = "plugin-api-v3")]
+    let value = Some(info.value().into());
+    #[cfg(not(feature = "plugin-api-v3"))]
+    let value = None;
 
-        let json = to_string(&mem_evt).unwrap();
-        println!("{}", json);
+    let mem_evt = MemEvent::new(
+        vaddr,
+        info.is_sign_extended(),
+        info.is_big_endian(),
+        info.is_store(),
+        info.size_shift(),
+        value,
+        insn_evt,
+    );
 
-        jv.insns.remove(&key);
+    // In system emulation, also record where the access landed physically
+    match info.hwaddr(vaddr) {
+        Some(hwaddr) => buffer::push(
+            &SysMemEvent::new(hwaddr.phys_addr(), hwaddr.is_io(), mem_evt),
+            false,
+        ),
+        None => buffer::push(&mem_evt, false),
     }
 }
 
 /// Called on translation of a new translation block. We use this function to register additional
 /// callbacks for execution and memory access. We also use this function to populate
 /// information about the instructions, depending on what logging is enabled by the arguments
-unsafe extern "C" fn on_tb_trans(_id: u64, tb: *mut qemu_plugin_tb) {
-    let mut jv = CONTEXT.lock().unwrap();
-
-    let n_isns = qemu_plugin_tb_n_insns(tb);
-    let first_insn = if jv.log_pc || jv.log_mem {
-        0
-    } else if jv.log_branch {
-        n_isns - 1
-    } else {
-        // TODO: We can probably eliminate this overhead but for example's sake
-        // this is probably fine. Skip the whole TB if we aren't logging anything
-        n_isns
-    };
+unsafe fn on_tb_trans(_id: u64, tb: *mut qemu_plugin_tb) {
+    let jv = CONTEXT.read().unwrap();
 
-    for insn_idx in first_insn..n_isns {
-        let branch = insn_idx == n_isns - 1;
-        let insn = qemu_plugin_tb_get_insn(tb, insn_idx);
-        let vaddr = qemu_plugin_insn_vaddr(insn);
+    // Every instruction is counted, whether or not it is traced
+    qemu_plugin_register_vcpu_tb_exec_inline(
+        tb,
+        qemu_plugin_op_QEMU_PLUGIN_INLINE_ADD_U64,
+        INSNS.as_ptr() as *mut c_void,
+        qemu_plugin_tb_n_insns(tb) as u64,
+    );
 
-        let mut evt = InsnEvent::new(None, vaddr, None, branch);
+    // Registered first, so the delivery is logged before the first instruction of the handler
+    if jv.log_signals && !signals_handled_at(qemu_plugin_tb_vaddr(tb)).is_empty() {
+        VCPUTBHandleCallback::new(on_signal_handler, ()).register(tb);
+    }
 
-        if jv.log_opcode {
-            let opcode_len = qemu_plugin_insn_size(insn);
-            let raw_opcode = qemu_plugin_insn_data(insn);
-            // reinterpret the raw opcode as a slice of bytes
-            let opcode: Vec<u8> = from_raw_parts(raw_opcode as *const u8, opcode_len as usize)
-                .iter()
-                .map(|x| *x)
-                .collect();
-
-            evt.opcode = Some(opcode);
+    if let Some(server) = FORK_SERVER.get() {
+        for insn in instructions(tb) {
+            if insn.vaddr() == server.pc() {
+                VCPUInsnExecCallback::new(on_fork_point, TBData::new(())).register(insn.raw());
+            }
         }
+    }
 
-        let exec_key = *&jv.ikey();
-        jv.insns.insert(exec_key, evt.clone());
+    // Breakpoints are hit whether or not the tracing window is open. A held VCPU may be asked
+    // for its registers, so QEMU must keep them up to date
+    BREAKPOINTS.add_entry();
+
+    if !BREAKPOINTS.is_empty() {
+        for insn in instructions(tb) {
+            if BREAKPOINTS.contains(insn.vaddr()) {
+                let data = TBData::new(insn.vaddr());
+                VCPUInsnExecCallback::new(on_breakpoint, data)
+                    .reading_registers()
+                    .register(insn.raw());
+            }
+        }
+    }
 
-        let exec_cb = VCPUInsnExecCallback::new(on_insn_exec, ExecKey::new(exec_key));
-        exec_cb.register(insn);
+    // Until the tracing window closes, instructions are counted and triggers are instrumented
+    // to know when it opens or closes. Nothing else is instrumented while it is not open
+    if let Some(window) = WINDOW.get() {
+        if window.is_bounded() && window.phase() != Phase::Done {
+            VCPUTBHandleCallback::new(on_tb_window, ()).register(tb);
+        }
 
-        if jv.log_mem {
-            let mem_key = *&jv.ikey();
-            jv.insns.insert(mem_key, evt.clone());
+        for insn in instructions(tb) {
+            if let Some(trigger) = window.trigger_at(insn.vaddr()) {
+                let data = TBData::new(TriggerEvent::new(None, insn.vaddr(), trigger));
+                VCPUInsnExecCallback::new(on_trigger, data).register(insn.raw());
+            }
+        }
 
-            let mem_cb = VCPUMemCallback::new(on_mem_access, ExecKey::new(mem_key));
-            mem_cb.register(insn);
+        if !window.is_open() {
+            return;
         }
     }
+
+    if !maps::traced(qemu_plugin_tb_vaddr(tb)) {
+        return;
+    }
+
+    let target_name = jv.target_name.clone().unwrap_or_default();
+
+    if mix::enabled() {
+        mix::instrument(&target_name, tb);
+    }
+
+    if let Some(functions) = FUNCTIONS.get() {
+        instrument_functions(&mut functions.lock().unwrap(), &target_name, tb);
+    }
+
+    let vaddr = qemu_plugin_tb_vaddr(tb);
+    let n_insns = qemu_plugin_tb_n_insns(tb);
+    let last = qemu_plugin_tb_get_insn(tb, n_insns - 1);
+    let last_vaddr = qemu_plugin_insn_vaddr(last);
+    let last_size = qemu_plugin_insn_size(last);
+    let bounds = TBBounds {
+        start: vaddr,
+        last: last_vaddr,
+        transfer: if jv.log_calls {
+            classify(
+                &target_name,
+                from_raw_parts(qemu_plugin_insn_data(last) as *const u8, last_size),
+                instrument::isa_mode(tb),
+            )
+        } else {
+            Transfer::Other
+        },
+    };
+
+    let size = (last_vaddr - vaddr) as usize + last_size;
+
+    // When tracing translation blocks, a single callback on the TB replaces the instruction
+    // callbacks, which are then only needed for memory accesses. Deduplication replaces it
+    // with a callback that counts executions and only logs the first
+    if jv.dedup {
+        let block = *BLOCKS
+            .lock()
+            .unwrap()
+            .entry((vaddr, size))
+            .or_insert_with(|| {
+                Box::leak(Box::new(BlockHits {
+                    evt: TBEvent::new(None, vaddr, size, n_insns),
+                    hits: AtomicU64::new(0),
+                }))
+            });
+        VCPUTBExecCallback::new(on_tb_dedup, CallbackData::shared(block)).register(tb);
+    } else if jv.trace_tb {
+        VCPUTBHandleCallback::new(on_tb_exec, ()).register(tb);
+    }
+
+    if jv.log_edges {
+        let data = TBData::new(bounds.clone());
+        VCPUTBExecCallback::new(on_tb_edge, data).register(tb);
+    }
+
+    if jv.log_calls {
+        let data = TBData::new(bounds);
+        VCPUTBExecCallback::new(on_tb_call, data).register(tb);
+    }
+
+    // Which callbacks an instruction gets is decided here, from the events logged, rather
+    // than in the callbacks: instruction events need an execution callback on every
+    // instruction with `log_pc`, or only on the last of the TB with `log_branch`, and memory
+    // accesses a memory callback. An instruction needing neither is not instrumented at all,
+    // so logging only memory accesses does not pay for a callback per executed instruction
+    let log_mem = jv.mem_access();
+    let log_exec =
+        |insn: &Instruction| !jv.trace_tb && (jv.log_pc || (jv.log_branch && insn.is_last()));
+    let instrumenter = TBInstrumenter::new(|insn| {
+        if log_exec(insn) || log_mem.is_some() {
+            InstrumentAction::Callback
+        } else {
+            InstrumentAction::Skip
+        }
+    });
+
+    instrumenter.instrument(tb, |insn| {
+        let mut evt = InsnEvent::new(None, insn.vaddr(), None, insn.is_last());
+        evt.isa_mode = insn.isa_mode().map(events::isa_mode);
+
+        if jv.log_opcode {
+            let opcode = insn.data();
+            let size = jv
+                .opcode_size
+                .map_or(opcode.len(), |max| opcode.len().min(max));
+            evt.opcode = Some(opcode[..size].to_vec());
+        }
+
+        // The event is shared by the exec and mem callbacks of this instruction until the
+        // translation is flushed. Translations are cached by QEMU, so this happens once per
+        // translated instruction rather than once per execution.
+        let data = TBData::new(evt);
+
+        if log_exec(insn) {
+            let exec_cb = VCPUInsnExecCallback::new(on_insn_exec, data.clone());
+            exec_cb.register(insn.raw());
+            stats::count_insn_callback();
+        }
+
+        if let Some(access) = log_mem {
+            let mem_cb = VCPUMemCallback::new(on_mem_access, data).accesses(access);
+            mem_cb.register(insn.raw());
+        }
+    });
 }
 
 submit! {
@@ -343,7 +1269,8 @@ submit! {
 /// Called on each system call entry. We use this function to populate the arguments and
 /// number of the syscall, and then we store it until we get an event returning from the system
 /// call so we can populate the return value.
-unsafe extern "C" fn on_syscall(
+#[allow(clippy::too_many_arguments)]
+unsafe fn on_syscall(
     id: u64,
     vcpu_idx: u32,
     num: i64,
@@ -356,12 +1283,44 @@ unsafe extern "C" fn on_syscall(
     arg6: u64,
     arg7: u64,
 ) {
-    let mut jv = CONTEXT.lock().unwrap();
+    // Flush before entering the syscall so everything that happened before it is visible
+    // even if the syscall blocks
+    buffer::flush();
+
+    let args = [arg0, arg1, arg2, arg3, arg4, arg5, arg6, arg7];
+    replay::enter(id, vcpu_idx, num, args);
+    maps::enter(vcpu_idx, num, &args);
+
+    let jv = CONTEXT.read().unwrap();
+    let args = args.to_vec();
 
-    if jv.log_syscall {
-        let args = vec![arg0, arg1, arg2, arg3, arg4, arg5, arg6, arg7];
+    let target_name = jv.target_name.clone().unwrap_or_default();
+    // Filtered system calls are dropped here, before anything is serialized
+    let log_syscall = jv.log_syscall
+        && jv.syscall_filter.logs(num)
+        && WINDOW.get().map(Window::is_open).unwrap_or(true);
+
+    if let Some(pathname) = exec_pathname(&target_name, num) {
+        // A successful exec never returns, so the syscall is logged now, without its return
+        // value
+        if log_syscall {
+            buffer::push(&SyscallEvent::new(num, None, args.clone()), false);
+        }
+
+        let exec = ExecEvent::new(
+            Some(vcpu_idx),
+            num,
+            truncate_address(args[pathname]),
+            truncate_address(args[pathname + 1]),
+        );
+        buffer::push(&exec, false);
+        buffer::flush_all();
+        return;
+    }
+
+    if log_syscall {
         let syscall = SyscallEvent::new(num, None, args);
-        jv.syscalls.insert((id, vcpu_idx), syscall);
+        SYSCALLS.with(|syscalls| syscalls.borrow_mut().insert((id, vcpu_idx), syscall));
     }
 }
 
@@ -376,13 +1335,37 @@ submit! {
 
 /// Called on each system call exit. We use this function to populate the return value of the
 /// system call, and then we print the syscall event.
-unsafe extern "C" fn on_syscall_ret(id: u64, vcpu_idx: u32, _num: i64, rv: i64) {
-    let mut jv = CONTEXT.lock().unwrap();
+unsafe fn on_syscall_ret(id: u64, vcpu_idx: u32, _num: i64, rv: i64) {
+    // QEMU forks along with the guest, so the first syscall to return in a new process is the
+    // one that created it. This does not depend on the syscall numbers of the target
+    let pid = process::id();
+    let parent = PID.swap(pid, Ordering::Relaxed);
+
+    if parent != pid {
+        // Buffers were copied from the parent, and only the parent should write them out
+        buffer::discard_all();
+        buffer::retag_pid(pid);
+        buffer::push(&ForkEvent::new(Some(vcpu_idx), parent, pid), false);
+        buffer::flush();
+    }
+
+    replay::exit(id, vcpu_idx, rv);
+
+    let log_maps = CONTEXT.read().unwrap().log_maps;
 
-    if jv.log_syscall {
-        let mut syscall = jv.syscalls.remove(&(id, vcpu_idx)).unwrap();
+    // The map is followed whether or not the change is logged
+    maps::exit(
+        vcpu_idx,
+        rv,
+        log_maps && WINDOW.get().map(Window::is_open).unwrap_or(true),
+    );
+
+    // The syscall was not recorded if it was entered before the tracing window opened
+    let syscall = SYSCALLS.with(|syscalls| syscalls.borrow_mut().remove(&(id, vcpu_idx)));
+    if let Some(mut syscall) = syscall {
         syscall.rv = Some(rv);
-        println!("{}", to_string(&syscall).unwrap());
+        buffer::push(&syscall, false);
+        buffer::flush();
     }
 }
 
@@ -392,3 +1375,177 @@ submit! {
     });
     StaticCallbackType::VCPUSyscallRet(&sysretcb)
 }
+
+/// Called when a VCPU takes an interrupt or exception, or makes a host call. The vector of an
+/// interrupt is not reported by QEMU, but the handler it continues at identifies it
+#[cfg(feature = "plugin-api-v5")]
+unsafe fn on_discon(
+    _id: u64,
+    vcpu_idx: u32,
+    discon: qemu_plugin_discon_type,
+    from_pc: u64,
+    to_pc: u64,
+) {
+    let log_discon = CONTEXT.read().unwrap().log_discon;
+    if !log_discon || !WINDOW.get().map(Window::is_open).unwrap_or(true) {
+        return;
+    }
+
+    let discon = if discon == qemu_plugin_discon_type_QEMU_PLUGIN_DISCON_INTERRUPT {
+        Discon::Interrupt
+    } else if discon == qemu_plugin_discon_type_QEMU_PLUGIN_DISCON_EXCEPTION {
+        Discon::Exception
+    } else if discon == qemu_plugin_discon_type_QEMU_PLUGIN_DISCON_HOSTCALL {
+        Discon::Hostcall
+    } else {
+        return;
+    };
+
+    buffer::push(
+        &DisconEvent::new(Some(vcpu_idx), discon, from_pc, to_pc),
+        false,
+    );
+}
+
+#[cfg(feature = "plugin-api-v5")]
+submit! {
+    static disconcb: Lazy<VCPUDisconCallback> = Lazy::new(|| {
+        VCPUDisconCallback::new(on_discon, qemu_plugin_discon_type_QEMU_PLUGIN_DISCON_ALL)
+    });
+    StaticCallbackType::VCPUDiscon(&disconcb)
+}
+
+/// Log a change in the lifecycle or power state of a VCPU, if VCPU events are logged
+///
+/// # Arguments
+///
+/// * `vcpu_idx` - The VCPU
+/// * `state` - Its new state
+fn log_vcpu(vcpu_idx: u32, state: VcpuState) {
+    let log_vcpu = CONTEXT.read().unwrap().log_vcpu;
+    if log_vcpu && WINDOW.get().map(Window::is_open).unwrap_or(true) {
+        buffer::push(&VcpuEvent::new(Some(vcpu_idx), state), false);
+    }
+}
+
+/// Called when a VCPU is created
+unsafe fn on_vcpu_init(_id: u64, vcpu_idx: u32) {
+    log_vcpu(vcpu_idx, VcpuState::Init);
+}
+
+submit! {
+    static vcpuinitcb: Lazy<VCPUInitCallback> = Lazy::new(|| {
+        VCPUInitCallback::new(on_vcpu_init)
+    });
+    StaticCallbackType::VCPUInit(&vcpuinitcb)
+}
+
+/// Called when a VCPU exits. The buffer of the exiting VCPU thread is flushed so its last
+/// partial batch is not lost
+unsafe fn on_vcpu_exit(_id: u64, vcpu_idx: u32) {
+    log_vcpu(vcpu_idx, VcpuState::Exit);
+    buffer::flush();
+}
+
+submit! {
+    static vcpuexitcb: Lazy<VCPUExitCallback> = Lazy::new(|| {
+        VCPUExitCallback::new(on_vcpu_exit)
+    });
+    StaticCallbackType::VCPUExit(&vcpuexitcb)
+}
+
+/// Called when a VCPU goes idle. The buffer of its thread is flushed, because an idle VCPU may
+/// not log anything else for a long time
+unsafe fn on_vcpu_idle(_id: u64, vcpu_idx: u32) {
+    log_vcpu(vcpu_idx, VcpuState::Idle);
+    buffer::flush();
+}
+
+submit! {
+    static vcpuidlecb: Lazy<VCPUIdleCallback> = Lazy::new(|| {
+        VCPUIdleCallback::new(on_vcpu_idle)
+    });
+    StaticCallbackType::VCPUIdle(&vcpuidlecb)
+}
+
+/// Called when a VCPU resumes from idle
+unsafe fn on_vcpu_resume(_id: u64, vcpu_idx: u32) {
+    log_vcpu(vcpu_idx, VcpuState::Resume);
+}
+
+submit! {
+    static vcpuresumecb: Lazy<VCPUResumeCallback> = Lazy::new(|| {
+        VCPUResumeCallback::new(on_vcpu_resume)
+    });
+    StaticCallbackType::VCPUResume(&vcpuresumecb)
+}
+
+/// Called when QEMU exits. Every VCPU buffer is drained, because threads that are still
+/// running when the guest calls `exit_group` never get to flush their own buffers. When
+/// deduplicating, the hit count of every block is logged first, and so is the instruction mix
+/// when it is counted. A fatal signal is logged before them, when logging signals, and the
+/// `FinalEvent` after everything else
+fn on_exit(_id: u64) {
+    let log_signals = CONTEXT.read().unwrap().log_signals;
+
+    // QEMU exits on the thread the fatal signal was raised on, so the last instruction it
+    // executed is the one that faulted
+    if let (true, Some(ExitStatus::Signaled(signal))) = (log_signals, exit::status()) {
+        let last = LAST_PC.with(Cell::get);
+        let evt = SignalEvent::new(
+            last.map(|(vcpu, _)| vcpu),
+            signal,
+            signal.and_then(signal::name).map(str::to_string),
+            last.map(|(_, pc)| pc),
+            None,
+            true,
+        );
+        buffer::push(&evt, false);
+    }
+
+    // The hit count table is dumped last, after every other event
+    for block in BLOCKS.lock().unwrap().values() {
+        let hits = BlockHitsEvent::new(
+            block.evt.vaddr,
+            block.evt.size,
+            block.evt.n_insns,
+            block.hits.load(Ordering::Relaxed),
+        );
+        buffer::push(&hits, false);
+    }
+
+    mix::report();
+    buffer::flush_all();
+
+    // Reported after everything else is written out, so it accounts for every event
+    stats::report();
+    report_final();
+}
+
+/// Log the `FinalEvent`, last, telling how the guest exited. The exit status is only known in
+/// user mode, on the targets `cannonball::syscalls` knows
+fn report_final() {
+    let status = exit::status();
+    let (exit_code, signal) = match status {
+        Some(ExitStatus::Exited(code)) => (Some(code), None),
+        Some(ExitStatus::Signaled(signal)) => (None, signal),
+        None => (None, None),
+    };
+
+    let evt = FinalEvent::new(
+        exit_code,
+        matches!(status, Some(ExitStatus::Signaled(_))),
+        signal,
+        signal.and_then(signal::name).map(str::to_string),
+        INSNS.load(Ordering::Relaxed),
+        buffer::written().events,
+        buffer::kinds(),
+    );
+    buffer::push(&evt, false);
+    buffer::flush();
+}
+
+submit! {
+    static exitcb: Lazy<AtExitCallback> = Lazy::new(|| AtExitCallback::new(on_exit));
+    StaticCallbackType::AtExit(&exitcb)
+}
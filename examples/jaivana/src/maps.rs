@@ -0,0 +1,400 @@
+//! Memory map of the guest
+//!
+//! With `log_maps=on`, in user mode, the system calls changing the memory map of the guest are
+//! followed like `ptrace` would: a `MapEvent` is logged for every successful `mmap` (`mmap2` on
+//! 32-bit targets), an `UnmapEvent` for every `munmap` and a `ProtectEvent` for every
+//! `mprotect`, once they returned, so only changes that happened are logged. Consumers replay
+//! them to know what is mapped where at any point of the trace, like to find code executed from
+//! writable memory.
+//!
+//! The plugin keeps the same map, so with `trace_writable=on` it only traces the code executed
+//! from memory mapped writable, like the code a JIT compiler emits, and skips everything else.
+//! Whether code is traced is decided when it is translated. Memory QEMU maps for the guest
+//! itself, like the program and the stack, is not in the map, so code in it is never traced
+//! this way.
+//!
+//! With `trace_modules=main,,libfoo.so`, it only traces the code of the modules listed, which
+//! is also decided when code is translated, so the code of the others, like the C library and
+//! the dynamic loader, costs nothing to skip. `main` is the program itself, whose code QEMU
+//! loads from the executable `LOAD` segments of its program headers (this needs the
+//! `plugin-api-v2` feature). Other modules are the files the guest maps with `mmap`, found
+//! through `/proc/self/fd` since the plugin shares the file descriptors of the guest. They are
+//! named by the file name the link there resolves to, or its start up to a dot, so `libfoo.so`
+//! also names `libfoo.so.1`. The dynamic loader is mapped by QEMU with the program, and is
+//! never traced this way.
+//!
+//! `mremap` and `brk` are not followed.
+
+#[cfg(feature = "plugin-api-v2")]
+use cannonball::api::{qemu_plugin_end_code, qemu_plugin_start_code};
+use cannonball::{
+    guest::{address_bits, truncate_address},
+    syscalls,
+};
+use once_cell::sync::OnceCell;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::read_link,
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+};
+
+use crate::{
+    buffer,
+    events::{MapEvent, ProtectEvent, UnmapEvent},
+};
+
+/// The size of a page. Lengths are rounded up to it, like the kernel does
+const PAGE_SIZE: u64 = 0x1000;
+
+/// The protection of writable memory
+const PROT_WRITE: u64 = 0x2;
+
+/// A system call changing the memory map
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    /// `mmap`, with its offset in bytes, or `mmap2`, with its offset in pages
+    Map {
+        pages: bool,
+    },
+    Unmap,
+    Protect,
+}
+
+/// The modules whose code is traced, with `trace_modules`
+#[derive(Debug, Default)]
+pub struct Modules {
+    /// Whether the code of the program itself is traced
+    program: bool,
+    /// The names of the other modules
+    names: Vec<String>,
+    /// The addresses of the code of the program, once it is loaded
+    #[cfg(feature = "plugin-api-v2")]
+    program_code: OnceCell<(u64, u64)>,
+}
+
+impl Modules {
+    /// Parse the modules listed in `trace_modules`
+    ///
+    /// # Arguments
+    ///
+    /// * `names` - The names of the modules, comma separated
+    pub fn parse(names: &str) -> Self {
+        let (program, names): (Vec<&str>, Vec<&str>) = names
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .partition(|name| *name == "main");
+
+        Self {
+            program: !program.is_empty(),
+            names: names.into_iter().map(|name| name.to_string()).collect(),
+            #[cfg(feature = "plugin-api-v2")]
+            program_code: OnceCell::new(),
+        }
+    }
+
+    /// Whether the code of the program itself is traced
+    pub fn program(&self) -> bool {
+        self.program
+    }
+
+    /// Whether a mapped file is one of the modules
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the file
+    fn matches(&self, path: &Path) -> bool {
+        let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+            return false;
+        };
+
+        self.names.iter().any(|name| {
+            file_name
+                .strip_prefix(name.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+        })
+    }
+
+    /// Whether an address is in the code of the program, when it is traced. QEMU only knows
+    /// where the code is once it loaded the program, after the plugin was installed, so this
+    /// must be called from a VCPU
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address
+    fn in_program(&self, addr: u64) -> bool {
+        #[cfg(feature = "plugin-api-v2")]
+        if self.program {
+            let &(start, end) = self
+                .program_code
+                .get_or_init(|| unsafe { (qemu_plugin_start_code(), qemu_plugin_end_code()) });
+            return (start..end).contains(&addr);
+        }
+
+        let _ = addr;
+        false
+    }
+}
+
+/// The file mapped by a system call, if any
+///
+/// # Arguments
+///
+/// * `fd` - The file descriptor passed to `mmap`
+fn mapped_file(fd: i32) -> Option<PathBuf> {
+    // Anonymous mappings pass -1
+    if fd < 0 {
+        return None;
+    }
+
+    read_link(format!("/proc/self/fd/{}", fd)).ok()
+}
+
+/// The memory mapped, as ranges of addresses, their protection and whether they map one of
+/// the traced modules
+#[derive(Debug, Default)]
+struct Regions {
+    /// The regions, by start address, as their end address, protection and whether they map
+    /// a traced module
+    regions: BTreeMap<u64, (u64, u64, bool)>,
+}
+
+impl Regions {
+    /// Split the region containing an address in two at it, if any
+    ///
+    /// # Arguments
+    ///
+    /// * `at` - The address
+    fn split(&mut self, at: u64) {
+        let Some((&start, &(end, prot, module))) = self.regions.range(..at).next_back() else {
+            return;
+        };
+
+        if at < end {
+            self.regions.insert(start, (at, prot, module));
+            self.regions.insert(at, (end, prot, module));
+        }
+    }
+
+    /// Remove a range of addresses from the map
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - The first address of the range
+    /// * `end` - The address after the last of the range
+    fn unmap(&mut self, start: u64, end: u64) {
+        self.split(start);
+        self.split(end);
+
+        let starts = self
+            .regions
+            .range(start..end)
+            .map(|(start, _)| *start)
+            .collect::<Vec<_>>();
+
+        for start in starts {
+            self.regions.remove(&start);
+        }
+    }
+
+    /// Map a range of addresses, replacing whatever was mapped there
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - The first address of the range
+    /// * `end` - The address after the last of the range
+    /// * `prot` - The protection of the range
+    /// * `module` - Whether the range maps a traced module
+    fn map(&mut self, start: u64, end: u64, prot: u64, module: bool) {
+        self.unmap(start, end);
+        self.regions.insert(start, (end, prot, module));
+    }
+
+    /// Change the protection of the mapped parts of a range of addresses
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - The first address of the range
+    /// * `end` - The address after the last of the range
+    /// * `prot` - The new protection
+    fn protect(&mut self, start: u64, end: u64, prot: u64) {
+        self.split(start);
+        self.split(end);
+
+        for (_, region) in self.regions.range_mut(start..end) {
+            region.1 = prot;
+        }
+    }
+
+    /// The protection of the memory at an address, and whether it maps a traced module, if it
+    /// is in the map
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address
+    fn get(&self, addr: u64) -> Option<(u64, bool)> {
+        let (_, &(end, prot, module)) = self.regions.range(..=addr).next_back()?;
+        (addr < end).then_some((prot, module))
+    }
+}
+
+/// Follows the system calls changing the memory map
+struct Tracker {
+    /// The name of the QEMU target, which system call numbers depend on
+    target_name: String,
+    /// Whether only code in writable memory is traced
+    writable_only: bool,
+    /// The modules code is traced in, if not all of them
+    modules: Option<Modules>,
+    /// The system calls changing the map entered and not returned yet, by VCPU, with their
+    /// arguments
+    pending: Mutex<HashMap<u32, (Change, [u64; 6])>>,
+    /// The memory map
+    regions: RwLock<Regions>,
+}
+
+/// The tracker, if the memory map is followed
+static TRACKER: OnceCell<Tracker> = OnceCell::new();
+
+/// Start following the memory map of the guest
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target, e.g. `x86_64`
+/// * `writable_only` - Whether to only trace code in writable memory
+/// * `modules` - The modules to only trace code in, if not all of them
+pub fn start(target_name: &str, writable_only: bool, modules: Option<Modules>) {
+    let _ = TRACKER.set(Tracker {
+        target_name: target_name.to_string(),
+        writable_only,
+        modules,
+        pending: Mutex::new(HashMap::new()),
+        regions: RwLock::new(Regions::default()),
+    });
+}
+
+/// Remember a system call being entered, if it changes the memory map, to follow it once it
+/// returns
+///
+/// # Arguments
+///
+/// * `vcpu_idx` - The VCPU making the system call
+/// * `num` - The system call number
+/// * `args` - The arguments of the system call
+pub fn enter(vcpu_idx: u32, num: i64, args: &[u64]) {
+    let Some(tracker) = TRACKER.get() else {
+        return;
+    };
+
+    let change = match syscalls::name(&tracker.target_name, num) {
+        // The `mmap` of 32-bit targets like i386 takes its arguments in a structure, and C
+        // libraries use `mmap2` there instead
+        Some("mmap") if address_bits() == 32 => return,
+        Some("mmap") => Change::Map { pages: false },
+        Some("mmap2") => Change::Map { pages: true },
+        Some("munmap") => Change::Unmap,
+        Some("mprotect") => Change::Protect,
+        _ => return,
+    };
+
+    let mut call = [0; 6];
+    let len = args.len().min(call.len());
+    call[..len].copy_from_slice(&args[..len]);
+
+    tracker
+        .pending
+        .lock()
+        .unwrap()
+        .insert(vcpu_idx, (change, call));
+}
+
+/// Follow a system call returning, if it changed the memory map, and log the change
+///
+/// # Arguments
+///
+/// * `vcpu_idx` - The VCPU the system call returned on
+/// * `rv` - The return value of the system call
+/// * `log` - Whether to log the change
+pub fn exit(vcpu_idx: u32, rv: i64, log: bool) {
+    let Some(tracker) = TRACKER.get() else {
+        return;
+    };
+
+    let Some((change, [addr, len, prot, flags, fd, offset])) =
+        tracker.pending.lock().unwrap().remove(&vcpu_idx)
+    else {
+        return;
+    };
+
+    // Failed calls return a negated error number
+    if (-4096..0).contains(&rv) {
+        return;
+    }
+
+    let len = len.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    let vcpu_idx = Some(vcpu_idx);
+    let mut regions = tracker.regions.write().unwrap();
+
+    match change {
+        Change::Map { pages } => {
+            let addr = truncate_address(rv as u64);
+            let offset = if pages { offset * PAGE_SIZE } else { offset };
+            let fd = fd as i32;
+            let module = tracker
+                .modules
+                .as_ref()
+                .is_some_and(|modules| mapped_file(fd).is_some_and(|path| modules.matches(&path)));
+            regions.map(addr, addr.saturating_add(len), prot, module);
+
+            if log {
+                buffer::push(
+                    &MapEvent::new(vcpu_idx, addr, len, prot, flags, fd, offset),
+                    false,
+                );
+            }
+        }
+        Change::Unmap => {
+            let addr = truncate_address(addr);
+            regions.unmap(addr, addr.saturating_add(len));
+
+            if log {
+                buffer::push(&UnmapEvent::new(vcpu_idx, addr, len), false);
+            }
+        }
+        Change::Protect => {
+            let addr = truncate_address(addr);
+            regions.protect(addr, addr.saturating_add(len), prot);
+
+            if log {
+                buffer::push(&ProtectEvent::new(vcpu_idx, addr, len, prot), false);
+            }
+        }
+    }
+}
+
+/// Whether the code at an address is traced. Only code in memory mapped writable is, when
+/// only writable code is traced, and only code in the traced modules, when they are listed.
+/// All of it is otherwise
+///
+/// # Arguments
+///
+/// * `addr` - The address of the code
+pub fn traced(addr: u64) -> bool {
+    let Some(tracker) = TRACKER.get() else {
+        return true;
+    };
+
+    if !tracker.writable_only && tracker.modules.is_none() {
+        return true;
+    }
+
+    let region = tracker.regions.read().unwrap().get(addr);
+    let writable = !tracker.writable_only || region.is_some_and(|(prot, _)| prot & PROT_WRITE != 0);
+    let module = tracker
+        .modules
+        .as_ref()
+        .is_none_or(|modules| modules.in_program(addr) || region.is_some_and(|(_, module)| module));
+
+    writable && module
+}
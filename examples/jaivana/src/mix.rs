@@ -0,0 +1,318 @@
+//! Instruction mix
+//!
+//! With `stats=insn_mix`, every instruction is classified once, when it is translated, and
+//! counted by an inline operation adding to the counter of its class. Nothing calls back into
+//! the plugin when instructions execute, so this is about as cheap as instrumentation gets,
+//! like QEMU's `howvec` plugin. An `InsnMixEvent` logs the count of each class when QEMU exits.
+//!
+//! Counters are shared by every VCPU and updated without atomics, so with several VCPUs
+//! running at once the counts are approximate. Only x86 targets (`x86_64` and `i386`) are
+//! decoded, and counting the mix fails setup on every other target (see `supported`), rather
+//! than counting every instruction as `other`.
+
+use yaxpeax_arch::{Decoder, U8Reader};
+
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use cannonball::{
+    api::qemu_plugin_tb,
+    instrument::{InlineOp, InstrumentAction, TBInstrumenter},
+};
+
+use crate::{buffer, events::InsnMixEvent};
+
+/// A class of instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    /// Moves between registers and memory, including pushes, pops and conditional moves
+    Move,
+    /// Integer arithmetic
+    Arith,
+    /// Bitwise operations, shifts and rotates
+    Logic,
+    /// Comparisons that only set flags
+    Compare,
+    /// Conditional and unconditional jumps
+    Branch,
+    /// Near and far calls
+    Call,
+    /// Returns from calls and from interrupts
+    Return,
+    /// String instructions, with or without a repeat prefix
+    String,
+    /// Floating point and vector instructions
+    Vector,
+    /// System calls, interrupts and privileged or serializing instructions
+    System,
+    /// Instructions that do nothing, including prefetches and branch target markers
+    Nop,
+    /// Anything else, like instructions that could not be decoded
+    Other,
+}
+
+/// Every class, in the order of their counters
+const CLASSES: [Class; 12] = [
+    Class::Move,
+    Class::Arith,
+    Class::Logic,
+    Class::Compare,
+    Class::Branch,
+    Class::Call,
+    Class::Return,
+    Class::String,
+    Class::Vector,
+    Class::System,
+    Class::Nop,
+    Class::Other,
+];
+
+impl Class {
+    /// The name of the class in `InsnMixEvent`s
+    pub fn name(&self) -> &'static str {
+        match self {
+            Class::Move => "move",
+            Class::Arith => "arith",
+            Class::Logic => "logic",
+            Class::Compare => "compare",
+            Class::Branch => "branch",
+            Class::Call => "call",
+            Class::Return => "return",
+            Class::String => "string",
+            Class::Vector => "vector",
+            Class::System => "system",
+            Class::Nop => "nop",
+            Class::Other => "other",
+        }
+    }
+}
+
+/// Whether the instruction mix is counted
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The number of instructions of each class executed so far, indexed like `CLASSES`. Inline
+/// operations add to them directly, so they must never move
+static COUNTS: [AtomicU64; CLASSES.len()] = [const { AtomicU64::new(0) }; CLASSES.len()];
+
+/// Define a function classifying an instruction with one of yaxpeax's x86 decoders. The
+/// decoders of each mode have their own types, but the same opcode and operand names, except
+/// for the jump on a zero counter register
+macro_rules! classify_x86 {
+    ($name:ident, $mode:ident, $jcxz:ident) => {
+        /// Classify an x86 instruction
+        ///
+        /// # Arguments
+        ///
+        /// * `bytes` - The raw bytes of the instruction
+        fn $name(bytes: &[u8]) -> Class {
+            use yaxpeax_x86::$mode::{register_class, InstDecoder, Opcode, Operand};
+
+            let Ok(insn) = InstDecoder::default().decode(&mut U8Reader::new(bytes)) else {
+                return Class::Other;
+            };
+
+            // Vector and x87 instructions are told apart by their registers rather than by
+            // their many opcodes
+            let vector = (0..insn.operand_count()).any(|i| match insn.operand(i) {
+                Operand::Register(reg)
+                | Operand::RegisterMaskMerge(reg, _, _)
+                | Operand::RegisterMaskMergeSae(reg, _, _, _)
+                | Operand::RegisterMaskMergeSaeNoround(reg, _, _) => matches!(
+                    reg.class(),
+                    register_class::X
+                        | register_class::Y
+                        | register_class::Z
+                        | register_class::ST
+                        | register_class::MM
+                        | register_class::K
+                ),
+                _ => false,
+            });
+
+            if vector {
+                return Class::Vector;
+            }
+
+            let opcode = insn.opcode();
+
+            if opcode.is_jcc() {
+                return Class::Branch;
+            }
+
+            if opcode.is_cmovcc() || opcode.is_setcc() {
+                return Class::Move;
+            }
+
+            match opcode {
+                Opcode::MOV
+                | Opcode::MOVZX
+                | Opcode::MOVSX
+                | Opcode::MOVSXD
+                | Opcode::MOVBE
+                | Opcode::XCHG
+                | Opcode::PUSH
+                | Opcode::POP
+                | Opcode::PUSHF
+                | Opcode::POPF
+                | Opcode::LEA
+                | Opcode::ENTER
+                | Opcode::LEAVE
+                | Opcode::CBW
+                | Opcode::CWDE
+                | Opcode::CDQE
+                | Opcode::CWD
+                | Opcode::CDQ
+                | Opcode::CQO
+                | Opcode::LAHF
+                | Opcode::SAHF
+                | Opcode::BSWAP => Class::Move,
+                Opcode::ADD
+                | Opcode::ADC
+                | Opcode::SUB
+                | Opcode::SBB
+                | Opcode::INC
+                | Opcode::DEC
+                | Opcode::NEG
+                | Opcode::MUL
+                | Opcode::IMUL
+                | Opcode::DIV
+                | Opcode::IDIV
+                | Opcode::XADD
+                | Opcode::CMPXCHG
+                | Opcode::CMPXCHG8B
+                | Opcode::CMPXCHG16B => Class::Arith,
+                Opcode::AND
+                | Opcode::OR
+                | Opcode::XOR
+                | Opcode::NOT
+                | Opcode::SHL
+                | Opcode::SHR
+                | Opcode::SAL
+                | Opcode::SAR
+                | Opcode::ROL
+                | Opcode::ROR
+                | Opcode::RCL
+                | Opcode::RCR
+                | Opcode::SHLD
+                | Opcode::SHRD
+                | Opcode::BTC
+                | Opcode::BTR
+                | Opcode::BTS
+                | Opcode::BSF
+                | Opcode::BSR
+                | Opcode::TZCNT
+                | Opcode::LZCNT
+                | Opcode::POPCNT
+                | Opcode::ANDN => Class::Logic,
+                Opcode::CMP | Opcode::TEST | Opcode::BT => Class::Compare,
+                Opcode::JMP
+                | Opcode::JMPF
+                | Opcode::LOOP
+                | Opcode::LOOPZ
+                | Opcode::LOOPNZ
+                | Opcode::$jcxz => Class::Branch,
+                Opcode::CALL | Opcode::CALLF => Class::Call,
+                Opcode::RETURN | Opcode::RETF | Opcode::IRET | Opcode::IRETD | Opcode::IRETQ => {
+                    Class::Return
+                }
+                Opcode::MOVS
+                | Opcode::STOS
+                | Opcode::LODS
+                | Opcode::CMPS
+                | Opcode::SCAS
+                | Opcode::INS
+                | Opcode::OUTS => Class::String,
+                Opcode::SYSCALL
+                | Opcode::SYSRET
+                | Opcode::SYSENTER
+                | Opcode::SYSEXIT
+                | Opcode::INT
+                | Opcode::INTO
+                | Opcode::HLT
+                | Opcode::CPUID
+                | Opcode::RDTSC
+                | Opcode::RDTSCP
+                | Opcode::IN
+                | Opcode::OUT
+                | Opcode::UD2 => Class::System,
+                Opcode::NOP
+                | Opcode::PREFETCHNTA
+                | Opcode::PREFETCH0
+                | Opcode::PREFETCH1
+                | Opcode::PREFETCH2
+                | Opcode::ENDBR64
+                | Opcode::ENDBR32 => Class::Nop,
+                _ => Class::Other,
+            }
+        }
+    };
+}
+
+classify_x86!(classify_x86_64, long_mode, JRCXZ);
+classify_x86!(classify_i386, protected_mode, JECXZ);
+
+/// Whether instructions are classified on a target
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target, e.g. `x86_64`
+pub fn supported(target_name: &str) -> bool {
+    matches!(target_name, "x86_64" | "i386")
+}
+
+/// Classify an instruction
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target, e.g. `x86_64`
+/// * `bytes` - The raw bytes of the instruction
+pub fn classify(target_name: &str, bytes: &[u8]) -> Class {
+    match target_name {
+        "x86_64" => classify_x86_64(bytes),
+        "i386" => classify_i386(bytes),
+        _ => Class::Other,
+    }
+}
+
+/// Start counting the instruction mix
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether the instruction mix is counted
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Count the instructions of a translation block by class each time it executes
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target, e.g. `x86_64`
+/// * `tb` - The translation block being translated
+pub fn instrument(target_name: &str, tb: *mut qemu_plugin_tb) {
+    TBInstrumenter::new(|insn| {
+        let class = classify(target_name, insn.data());
+        let index = CLASSES.iter().position(|c| *c == class).unwrap_or_default();
+
+        InstrumentAction::Inline(InlineOp::add_u64(COUNTS[index].as_ptr(), 1))
+    })
+    .instrument(tb, |_| {});
+}
+
+/// Log the instruction mix so far, if it is counted
+pub fn report() {
+    if !enabled() {
+        return;
+    }
+
+    let classes = CLASSES
+        .iter()
+        .zip(COUNTS.iter())
+        .map(|(class, count)| (class.name().to_string(), count.load(Ordering::Relaxed)))
+        .collect::<BTreeMap<_, _>>();
+
+    buffer::push(&InsnMixEvent::new(classes), false);
+}
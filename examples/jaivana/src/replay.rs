@@ -0,0 +1,203 @@
+//! Replay logs
+//!
+//! With `replay_log=<path>`, the result of every system call the program makes is recorded in
+//! a replay log, so a replay harness can feed a later run the same results and have it execute
+//! the same way. Each record holds the system call, its arguments and its return value, and,
+//! with the `plugin-api-v4` feature (QEMU 9.2 and later), the buffers it wrote to guest memory,
+//! like the data `read` read or the `struct stat` of `fstat` (see `syscalls::outputs`). Buffers
+//! are cut to `replay_max_bytes=N` bytes (64KiB by default).
+//!
+//! System calls are recorded whether or not they are logged as events, and whether or not the
+//! tracing window is open, since replaying needs all of them. The log is a file of its own, in
+//! the format read by `cannonball-client` (see its `replay` module). Records are written with a
+//! single write to a file opened for appending, so the children of forks record to the same
+//! log, with their own PID.
+
+use once_cell::sync::OnceCell;
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    process,
+    sync::Mutex,
+};
+
+#[cfg(feature = "plugin-api-v4")]
+use cannonball::{guest::truncate_address, mem::read_memory};
+
+use crate::syscalls::outputs;
+
+/// The magic bytes a replay log starts with
+const MAGIC: &[u8; 8] = b"CBREPLAY";
+
+/// The version of the replay log format
+const VERSION: u32 = 1;
+
+/// Header flag set if output buffers are recorded
+const BUFFERS: u32 = 1 << 0;
+
+/// The number of arguments recorded for each system call
+const ARGS: usize = 8;
+
+/// A system call that was entered, as its number and arguments
+type Entered = (i64, [u64; ARGS]);
+
+/// Records system calls to a replay log
+struct Recorder {
+    /// The log
+    log: Mutex<File>,
+    /// The system calls entered and not returned yet, by plugin id and VCPU
+    pending: Mutex<HashMap<(u64, u32), Entered>>,
+    /// The name of the QEMU target, which system call numbers depend on
+    target_name: String,
+    /// The most bytes of each output buffer recorded
+    max_bytes: u32,
+}
+
+/// The recorder, if system calls are recorded
+static RECORDER: OnceCell<Recorder> = OnceCell::new();
+
+/// Start recording system calls to a new replay log, replacing any file at `path`
+///
+/// # Arguments
+///
+/// * `path` - The replay log
+/// * `target_name` - The name of the QEMU target, e.g. `x86_64`
+/// * `max_bytes` - The most bytes of each output buffer to record
+pub fn start(path: &Path, target_name: &str, max_bytes: u32) -> io::Result<()> {
+    let flags = if cfg!(feature = "plugin-api-v4") {
+        BUFFERS
+    } else {
+        0
+    };
+
+    let mut header = Vec::new();
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header.extend_from_slice(&flags.to_le_bytes());
+    header.extend_from_slice(&max_bytes.to_le_bytes());
+    header.extend_from_slice(&(target_name.len() as u32).to_le_bytes());
+    header.extend_from_slice(target_name.as_bytes());
+    File::create(path)?.write_all(&header)?;
+
+    let recorder = Recorder {
+        log: Mutex::new(OpenOptions::new().append(true).open(path)?),
+        pending: Mutex::new(HashMap::new()),
+        target_name: target_name.to_string(),
+        max_bytes,
+    };
+
+    RECORDER
+        .set(recorder)
+        .map_err(|_| io::Error::other("Already recording system calls"))
+}
+
+/// Remember a system call being entered, to record it once it returns
+///
+/// # Arguments
+///
+/// * `id` - The plugin id
+/// * `vcpu_idx` - The VCPU making the system call
+/// * `num` - The system call number
+/// * `args` - The arguments of the system call
+pub fn enter(id: u64, vcpu_idx: u32, num: i64, args: [u64; ARGS]) {
+    if let Some(recorder) = RECORDER.get() {
+        recorder
+            .pending
+            .lock()
+            .unwrap()
+            .insert((id, vcpu_idx), (num, args));
+    }
+}
+
+/// Record a system call returning. This must be called on the VCPU thread it returned on, so
+/// its output buffers can be read
+///
+/// # Arguments
+///
+/// * `id` - The plugin id
+/// * `vcpu_idx` - The VCPU the system call returned on
+/// * `rv` - The return value of the system call
+pub fn exit(id: u64, vcpu_idx: u32, rv: i64) {
+    let Some(recorder) = RECORDER.get() else {
+        return;
+    };
+
+    // The system call was entered before recording started
+    let Some((num, args)) = recorder.pending.lock().unwrap().remove(&(id, vcpu_idx)) else {
+        return;
+    };
+
+    let buffers = recorder.buffers(num, &args, rv);
+
+    let mut record = Vec::new();
+    record.extend_from_slice(&process::id().to_le_bytes());
+    record.extend_from_slice(&vcpu_idx.to_le_bytes());
+    record.extend_from_slice(&num.to_le_bytes());
+    record.extend_from_slice(&rv.to_le_bytes());
+
+    for arg in args {
+        record.extend_from_slice(&arg.to_le_bytes());
+    }
+
+    record.extend_from_slice(&(buffers.len() as u32).to_le_bytes());
+
+    for (arg, size, data) in buffers {
+        record.extend_from_slice(&(arg as u32).to_le_bytes());
+        record.extend_from_slice(&args[arg].to_le_bytes());
+        record.extend_from_slice(&size.to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&data);
+    }
+
+    recorder
+        .log
+        .lock()
+        .unwrap()
+        .write_all(&record)
+        .expect("Could not write to the replay log!");
+}
+
+impl Recorder {
+    /// Read the buffers a system call wrote to, as the argument pointing to each, its size, and
+    /// the bytes of it that were read. Nothing is read if the system call failed
+    ///
+    /// # Arguments
+    ///
+    /// * `num` - The system call number
+    /// * `args` - The arguments of the system call
+    /// * `rv` - The return value of the system call
+    fn buffers(&self, num: i64, args: &[u64; ARGS], rv: i64) -> Vec<(usize, u64, Vec<u8>)> {
+        if rv < 0 {
+            return Vec::new();
+        }
+
+        outputs(&self.target_name, num, rv)
+            .into_iter()
+            .filter(|(arg, size)| args[*arg] != 0 && *size > 0)
+            .filter_map(|(arg, size)| {
+                let len = size.min(self.max_bytes as u64) as usize;
+                Some((arg, size, read(args[arg], len)?))
+            })
+            .collect()
+    }
+}
+
+/// Read guest memory of the current VCPU
+///
+/// # Arguments
+///
+/// * `vaddr` - The first address to read, as passed to a system call
+/// * `len` - The number of bytes to read
+#[cfg(feature = "plugin-api-v4")]
+fn read(vaddr: u64, len: usize) -> Option<Vec<u8>> {
+    read_memory(truncate_address(vaddr), len)
+}
+
+/// Guest memory can only be read with plugin API version 4, so nothing is read
+#[cfg(not(feature = "plugin-api-v4"))]
+fn read(_vaddr: u64, _len: usize) -> Option<Vec<u8>> {
+    None
+}
@@ -0,0 +1,137 @@
+//! Rotated file sinks
+//!
+//! Long-running programs, like services, log events for as long as they run, and a single
+//! trace file grows without bound. With `sink=file:<path>`, events are written to `<path>.0`,
+//! and with `sink_rotate_size=N` (in bytes, or with a `K`, `M` or `G` suffix) a new file is
+//! started once the current one would grow past `N` bytes: `<path>.1`, `<path>.2` and so on.
+//! With `sink_keep=N`, only the last `N` files are kept, and older ones are removed as new
+//! ones are started.
+//!
+//! Files are only started between the batches of events the buffers write out, so every file
+//! holds whole events, and a batch larger than `N` makes a file larger than `N`. Every file
+//! starts with the header of the trace, so each of them can be read on its own, and the tools
+//! read a whole set back as one trace with `TraceReader::open_rotated`.
+
+use std::{
+    fs::{remove_file, File},
+    io::{self, ErrorKind, Write},
+};
+
+/// A file sink split into files of a bounded size
+pub struct RotatingFile {
+    /// The path the files are named after
+    path: String,
+    /// The size a file may not grow past, unless a single batch is larger
+    rotate_size: u64,
+    /// The number of files to keep, or all of them
+    keep: Option<u64>,
+    /// The index of the current file
+    index: u64,
+    /// The current file
+    file: File,
+    /// The number of bytes written to the current file
+    size: u64,
+    /// The framed header of the trace, written at the start of every file. The plugin writes
+    /// it out before any other event, so it is the first frame written to the sink
+    header: Option<Vec<u8>>,
+}
+
+impl RotatingFile {
+    /// Create the first file of a rotated sink
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path the files are named after, as `<path>.<index>`
+    /// * `rotate_size` - The size a file may not grow past
+    /// * `keep` - The number of files to keep, if not all of them
+    pub fn create(path: &str, rotate_size: u64, keep: Option<u64>) -> io::Result<Self> {
+        if keep == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "sink_keep must keep at least one file",
+            ));
+        }
+
+        Ok(Self {
+            path: path.to_string(),
+            rotate_size: rotate_size.max(1),
+            keep,
+            index: 0,
+            file: File::create(format!("{}.0", path))?,
+            size: 0,
+            header: None,
+        })
+    }
+
+    /// Start the next file with the header of the trace, and remove the oldest one if it is
+    /// no longer kept
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.index += 1;
+        self.file = File::create(format!("{}.{}", self.path, self.index))?;
+        self.size = 0;
+
+        if let Some(keep) = self.keep.filter(|keep| self.index >= *keep) {
+            match remove_file(format!("{}.{}", self.path, self.index - keep)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+
+        if let Some(header) = &self.header {
+            self.file.write_all(header)?;
+            self.size = header.len() as u64;
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    /// Write a batch of framed events, to a new file if it would grow the current one past
+    /// the rotation size. The whole batch is always written
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.header.is_none() {
+            let len = buf.get(..4).map_or(0, |len| {
+                u32::from_le_bytes(len.try_into().unwrap()) as usize
+            });
+            self.header = buf.get(..4 + len).map(|header| header.to_vec());
+        }
+
+        let header_len = self.header.as_ref().map_or(0, |header| header.len() as u64);
+
+        // A file holding only the header has no events to make room for
+        if self.size > header_len && self.size + buf.len() as u64 > self.rotate_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Parse a size in bytes, with an optional binary `K`, `M` or `G` suffix
+///
+/// # Arguments
+///
+/// * `size` - The size, like `4096` or `1G`
+pub fn parse_size(size: &str) -> io::Result<u64> {
+    let invalid = || io::Error::new(ErrorKind::InvalidInput, format!("Invalid size {}", size));
+    let (digits, unit) = match size.char_indices().last() {
+        Some((at, 'K' | 'k')) => (&size[..at], 1 << 10),
+        Some((at, 'M' | 'm')) => (&size[..at], 1 << 20),
+        Some((at, 'G' | 'g')) => (&size[..at], 1 << 30),
+        _ => (size, 1),
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|digits| digits.checked_mul(unit))
+        .ok_or_else(invalid)
+}
@@ -0,0 +1,126 @@
+//! Encrypted sinks
+//!
+//! Events sent to another machine with `sink=tcp:<host>:<port>` or `sink=vsock:<cid>:<port>`
+//! are encrypted and authenticated with a pre-shared key when `sink_psk_file=<path>` names a
+//! file holding it as 64 hex digits. The channel is the one `cannonball-tools` accepts with its
+//! `noise` feature: a `Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s` handshake, with this side as the
+//! initiator, then transport messages each preceded by their length as a big-endian `u16`.
+
+use snow::{Builder, TransportState};
+
+use std::{
+    fs::read_to_string,
+    io::{self, ErrorKind, Read, Write},
+};
+
+/// The Noise protocol of the channel
+const NOISE_PARAMS: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+
+/// The size of a pre-shared key
+const PSK_LEN: usize = 32;
+
+/// The largest Noise message
+const MAX_MESSAGE: usize = 65535;
+
+/// The largest payload of a Noise message, leaving room for its authentication tag
+const MAX_PAYLOAD: usize = MAX_MESSAGE - 16;
+
+/// Read a pre-shared key, stored hex-encoded in a file
+///
+/// # Arguments
+///
+/// * `path` - The file
+fn read_psk(path: &str) -> io::Result<[u8; PSK_LEN]> {
+    let hex = read_to_string(path)?;
+    let hex = hex.trim();
+    let invalid = || {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{} must hold {} hex-encoded bytes", path, PSK_LEN),
+        )
+    };
+
+    if hex.len() != PSK_LEN * 2 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut psk = [0; PSK_LEN];
+
+    for (byte, digits) in psk.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+    }
+
+    Ok(psk)
+}
+
+/// A sink whose writes are encrypted
+pub struct SecureSink<S: Read + Write> {
+    stream: S,
+    transport: TransportState,
+    /// The last message sent
+    message: Vec<u8>,
+}
+
+impl<S: Read + Write> SecureSink<S> {
+    /// Run the handshake with the consumer over a connected sink. Fails if the consumer does
+    /// not know the key
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The connection to the consumer
+    /// * `psk_file` - The file holding the pre-shared key
+    pub fn connect(mut stream: S, psk_file: &str) -> io::Result<Self> {
+        let psk = read_psk(psk_file)?;
+        let mut handshake = Builder::new(NOISE_PARAMS.parse().map_err(io::Error::other)?)
+            .psk(0, &psk)
+            .build_initiator()
+            .map_err(io::Error::other)?;
+        let mut message = vec![0; MAX_MESSAGE];
+        let mut payload = vec![0; MAX_MESSAGE];
+
+        // -> psk, e
+        let len = handshake
+            .write_message(&[], &mut message)
+            .map_err(io::Error::other)?;
+        stream.write_all(&(len as u16).to_be_bytes())?;
+        stream.write_all(&message[..len])?;
+
+        // <- e, ee
+        let mut len = [0; 2];
+        stream.read_exact(&mut len)?;
+        let len = u16::from_be_bytes(len) as usize;
+        stream.read_exact(&mut message[..len])?;
+        handshake
+            .read_message(&message[..len], &mut payload)
+            .map_err(|_| {
+                io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "The consumer does not know the pre-shared key",
+                )
+            })?;
+
+        Ok(Self {
+            stream,
+            transport: handshake.into_transport_mode().map_err(io::Error::other)?,
+            message,
+        })
+    }
+}
+
+impl<S: Read + Write> Write for SecureSink<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let payload = &buf[..buf.len().min(MAX_PAYLOAD)];
+        let len = self
+            .transport
+            .write_message(payload, &mut self.message)
+            .map_err(io::Error::other)?;
+        self.stream.write_all(&(len as u16).to_be_bytes())?;
+        self.stream.write_all(&self.message[..len])?;
+        Ok(payload.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
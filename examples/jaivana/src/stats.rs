@@ -0,0 +1,67 @@
+//! Trace statistics
+//!
+//! With `stats=on`, the plugin logs a `StatsEvent` every `stats_interval_ms=N` milliseconds
+//! (every second by default) and once more when QEMU exits. Each reports on the trace so far:
+//! how many events and bytes were written out and how fast, how long writing them took, the
+//! most events a buffer held before it was flushed, how many events were discarded, and how
+//! many events of each type were logged. These are what to look at when choosing a batch size
+//! or which events to log. They also tell how many instructions were given a callback logging
+//! their execution, which only the instructions whose events are logged get when they are
+//! translated, so turning instruction events off removes that overhead entirely.
+//!
+//! Statistics are reported by a thread of their own, which the child of a fork does not
+//! inherit, so a child only reports once, when it exits.
+
+use once_cell::sync::OnceCell;
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
+};
+
+use crate::{buffer, events::StatsEvent};
+
+/// When statistics started being collected, if they are
+static START: OnceCell<Instant> = OnceCell::new();
+
+/// The number of instruction execution callbacks registered to log instruction events. Counted
+/// at translation time, so it is counted whether or not statistics are collected
+static INSN_CALLBACKS: AtomicU64 = AtomicU64::new(0);
+
+/// Count an instruction execution callback registered to log instruction events
+pub fn count_insn_callback() {
+    INSN_CALLBACKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Start collecting statistics, and log them every `interval`
+///
+/// # Arguments
+///
+/// * `interval` - How often to log statistics
+pub fn start(interval: Duration) {
+    if START.set(Instant::now()).is_err() {
+        return;
+    }
+
+    spawn(move || loop {
+        sleep(interval);
+        report();
+    });
+}
+
+/// Log the statistics of the trace so far, if they are collected
+pub fn report() {
+    let Some(start) = START.get() else {
+        return;
+    };
+
+    let stats = StatsEvent::new(
+        start.elapsed(),
+        buffer::written(),
+        buffer::kinds(),
+        INSN_CALLBACKS.load(Ordering::Relaxed),
+    );
+    buffer::push(&stats, false);
+    buffer::flush();
+}
@@ -0,0 +1,180 @@
+//! Target specific system call numbers
+//!
+//! QEMU reports system calls by their raw number, which depends on the target. Only the
+//! system calls Jaivana needs to recognize are listed here: those replacing the process image,
+//! and those writing results to guest memory, which replay logs record (see `replay`). They
+//! are recognized by name, with the tables of `cannonball::syscalls`.
+//!
+//! The system calls logged are selected by name too: with `syscall_filter=openat,,read`, only
+//! the ones listed are, and with `syscall_exclude=futex,,clock_gettime`, all but the ones
+//! listed are. Both can be given, and a system call listed in both is not logged. The others
+//! are dropped on entry, before they are serialized, so frequent ones cost next to nothing.
+//! A name the target has no system call for, or any name on a target `cannonball::syscalls`
+//! has no table for, like s390x, fails the installation of the plugin.
+
+use cannonball::syscalls;
+
+use std::collections::BTreeMap;
+
+/// If a system call replaces the process image (`execve` or `execveat`), the index of its
+/// pathname argument. The argument vector is the argument right after it
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target, e.g. `x86_64`
+/// * `num` - The system call number
+pub fn exec_pathname(target_name: &str, num: i64) -> Option<usize> {
+    match syscalls::name(target_name, num)? {
+        "execve" => Some(0),
+        // execveat takes a directory file descriptor first
+        "execveat" => Some(1),
+        _ => None,
+    }
+}
+
+/// How many bytes a system call writes to a buffer it is passed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    /// As many as it returns, like `read`
+    Returned,
+    /// A structure of a fixed size, like the `struct stat` of `fstat`
+    Fixed(u64),
+}
+
+/// The buffers a system call writes to on a target, as the index of the argument pointing to
+/// each and how many bytes it writes to it. Only 64-bit targets are known
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target, e.g. `x86_64`
+/// * `num` - The system call number
+fn output_args(target_name: &str, num: i64) -> &'static [(usize, Output)] {
+    use Output::{Fixed, Returned};
+
+    // Targets using the generic system call table lay `struct stat` out differently
+    let x86_64 = match target_name {
+        "x86_64" => true,
+        "aarch64" | "riscv64" | "loongarch64" => false,
+        _ => return &[],
+    };
+
+    match syscalls::name(target_name, num).unwrap_or_default() {
+        "read" | "pread64" | "recvfrom" | "readlink" | "getdents64" => &[(1, Returned)],
+        "getrandom" | "getcwd" => &[(0, Returned)],
+        "readlinkat" => &[(2, Returned)],
+        "stat" | "fstat" | "lstat" if x86_64 => &[(1, Fixed(144))],
+        "fstat" => &[(1, Fixed(128))],
+        "newfstatat" if x86_64 => &[(2, Fixed(144))],
+        "newfstatat" => &[(2, Fixed(128))],
+        "clock_gettime" | "getrlimit" => &[(1, Fixed(16))],
+        "gettimeofday" => &[(0, Fixed(16))],
+        "time" | "pipe" | "pipe2" => &[(0, Fixed(8))],
+        "uname" => &[(0, Fixed(390))],
+        "wait4" => &[(1, Fixed(4))],
+        "sysinfo" => &[(0, Fixed(112))],
+        "prlimit64" => &[(3, Fixed(16))],
+        _ => &[],
+    }
+}
+
+/// The buffers a system call that succeeded wrote to, as the index of the argument pointing
+/// to each and its size in bytes
+///
+/// # Arguments
+///
+/// * `target_name` - The name of the QEMU target, e.g. `x86_64`
+/// * `num` - The system call number
+/// * `rv` - The return value of the system call
+pub fn outputs(target_name: &str, num: i64, rv: i64) -> Vec<(usize, u64)> {
+    output_args(target_name, num)
+        .iter()
+        .map(|(arg, output)| match output {
+            Output::Returned => (*arg, rv.max(0) as u64),
+            Output::Fixed(size) => (*arg, *size),
+        })
+        .collect()
+}
+
+/// The system calls logged, selected with `syscall_filter` and `syscall_exclude`
+#[derive(Debug, Default)]
+pub struct SyscallFilter {
+    /// The system calls logged, by number, if not all of them
+    include: Option<BTreeMap<i64, &'static str>>,
+    /// The system calls not logged, by number
+    exclude: BTreeMap<i64, &'static str>,
+}
+
+impl SyscallFilter {
+    /// Parse the system calls to log and not to log. Fails on a name the target has no system
+    /// call for
+    ///
+    /// # Arguments
+    ///
+    /// * `target_name` - The name of the QEMU target, e.g. `x86_64`
+    /// * `include` - The names of the system calls to log, comma separated, if not all of them
+    /// * `exclude` - The names of the system calls not to log, comma separated
+    pub fn parse(
+        target_name: &str,
+        include: Option<&str>,
+        exclude: Option<&str>,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            include: include
+                .map(|names| Self::numbers(target_name, names))
+                .transpose()?,
+            exclude: exclude
+                .map(|names| Self::numbers(target_name, names))
+                .transpose()?
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Look the system calls in a list up by name
+    ///
+    /// # Arguments
+    ///
+    /// * `target_name` - The name of the QEMU target, e.g. `x86_64`
+    /// * `names` - The names of the system calls, comma separated
+    fn numbers(target_name: &str, names: &str) -> Result<BTreeMap<i64, &'static str>, String> {
+        names
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                let num = syscalls::number(target_name, name)
+                    .ok_or_else(|| format!("No system call {} on {}", name, target_name))?;
+                Ok((num, syscalls::name(target_name, num).unwrap_or_default()))
+            })
+            .collect()
+    }
+
+    /// Whether a system call is logged
+    ///
+    /// # Arguments
+    ///
+    /// * `num` - The system call number
+    pub fn logs(&self, num: i64) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.contains_key(&num))
+            && !self.exclude.contains_key(&num)
+    }
+
+    /// The names of the system calls logged, in the order of their numbers, if not all of them
+    pub fn included(&self) -> Option<Vec<String>> {
+        self.include.as_ref().map(Self::names)
+    }
+
+    /// The names of the system calls not logged, in the order of their numbers
+    pub fn excluded(&self) -> Vec<String> {
+        Self::names(&self.exclude)
+    }
+
+    /// The names of some system calls, in the order of their numbers
+    ///
+    /// # Arguments
+    ///
+    /// * `syscalls` - The system calls
+    fn names(syscalls: &BTreeMap<i64, &'static str>) -> Vec<String> {
+        syscalls.values().map(|name| name.to_string()).collect()
+    }
+}
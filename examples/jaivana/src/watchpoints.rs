@@ -0,0 +1,121 @@
+//! Memory watchpoints
+//!
+//! Logging every memory access drowns the few that matter, like those to one buffer, in all
+//! the others. With `watch=0xADDR:LEN:rw`, memory accesses are logged as with `log_mem=on`,
+//! but only those touching one of the `LEN` bytes from `ADDR` on. The mode is `r` to only log
+//! reads, `w` to only log writes, or `rw` (the default if it is left out) for both. Several
+//! ranges can be watched by giving `watch` several times.
+
+use std::str::FromStr;
+
+/// A watched range of memory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    /// The first watched address
+    start: u64,
+    /// The number of watched bytes
+    len: u64,
+    /// Whether reads are watched
+    read: bool,
+    /// Whether writes are watched
+    write: bool,
+}
+
+impl Watchpoint {
+    /// Whether an access touches the watched range, in a watched direction
+    ///
+    /// # Arguments
+    ///
+    /// * `vaddr` - The first address accessed
+    /// * `size` - The number of bytes accessed
+    /// * `is_store` - Whether the access is a write
+    pub fn hits(&self, vaddr: u64, size: u64, is_store: bool) -> bool {
+        let watched = if is_store { self.write } else { self.read };
+
+        watched
+            && vaddr < self.start.saturating_add(self.len)
+            && self.start < vaddr.saturating_add(size)
+    }
+}
+
+/// Parse a length or address, in hex with a `0x` prefix or in decimal
+///
+/// # Arguments
+///
+/// * `value` - The value to parse
+pub fn parse_u64(value: &str) -> Result<u64, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|e| format!("Invalid number {}: {}", value, e))
+}
+
+impl FromStr for Watchpoint {
+    type Err = String;
+
+    /// Parse a watchpoint of the form `0xADDR:LEN:MODE`, where `MODE` may be left out
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+
+        let (Some(start), Some(len)) = (parts.next(), parts.next()) else {
+            return Err(format!("Watchpoint {} is not of the form 0xADDR:LEN:rw", s));
+        };
+
+        let (read, write) = match parts.next() {
+            None | Some("rw") | Some("wr") => (true, true),
+            Some("r") => (true, false),
+            Some("w") => (false, true),
+            Some(mode) => return Err(format!("Invalid watchpoint mode {}", mode)),
+        };
+
+        Ok(Self {
+            start: parse_u64(start)?,
+            len: parse_u64(len)?,
+            read,
+            write,
+        })
+    }
+}
+
+/// The watched ranges of memory
+#[derive(Debug, Clone, Default)]
+pub struct Watchpoints {
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl Watchpoints {
+    /// Parse the watchpoints given as `watch` plugin arguments. Arguments with the same name
+    /// are collapsed into one by `Args`, so the raw arguments are read instead
+    ///
+    /// # Arguments
+    ///
+    /// * `raw` - The raw plugin arguments
+    pub fn parse(raw: &[String]) -> Result<Self, String> {
+        Ok(Self {
+            watchpoints: raw
+                .iter()
+                .filter_map(|arg| arg.strip_prefix("watch="))
+                .map(Watchpoint::from_str)
+                .collect::<Result<Vec<_>, _>>()?,
+        })
+    }
+
+    /// Whether no memory is watched
+    pub fn is_empty(&self) -> bool {
+        self.watchpoints.is_empty()
+    }
+
+    /// Whether an access touches any watched range, in a watched direction
+    ///
+    /// # Arguments
+    ///
+    /// * `vaddr` - The first address accessed
+    /// * `size` - The number of bytes accessed
+    /// * `is_store` - Whether the access is a write
+    pub fn hits(&self, vaddr: u64, size: u64, is_store: bool) -> bool {
+        self.watchpoints
+            .iter()
+            .any(|watchpoint| watchpoint.hits(vaddr, size, is_store))
+    }
+}
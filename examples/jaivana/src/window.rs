@@ -0,0 +1,205 @@
+//! Tracing windows
+//!
+//! Long running programs are often only interesting for part of their execution. A window
+//! keeps tracing dormant until a number of instructions have executed and/or some time has
+//! passed, and ends it after another threshold. While the window is not open, translation
+//! blocks only get a cheap callback counting the instructions they execute; every time the
+//! window opens or closes the plugin is reset so that the translation cache is flushed and
+//! every block is instrumented again for the new phase.
+//!
+//! A window can also be opened and closed by triggers: executing the instruction at a start
+//! PC or a stop PC. The instructions at those PCs are always instrumented (as long as the
+//! trigger can still change the phase) with a callback pulling the trigger.
+
+use crate::events::Trigger;
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+    time::{Duration, Instant},
+};
+
+/// The phase of a tracing window. Phases only ever move forward
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// The start threshold has not been reached yet
+    Dormant = 0,
+    /// Events are being traced
+    Tracing = 1,
+    /// The stop threshold has been reached
+    Done = 2,
+}
+
+impl From<u8> for Phase {
+    fn from(phase: u8) -> Self {
+        match phase {
+            0 => Phase::Dormant,
+            1 => Phase::Tracing,
+            _ => Phase::Done,
+        }
+    }
+}
+
+/// Thresholds bounding the part of the execution that is traced
+#[derive(Debug)]
+pub struct Window {
+    /// Number of instructions to execute before tracing starts
+    start_insns: u64,
+    /// Number of instructions after which tracing stops
+    stop_insns: Option<u64>,
+    /// Time to wait before tracing starts
+    start_after: Duration,
+    /// Time after which tracing stops
+    stop_after: Option<Duration>,
+    /// PC of the instruction that starts tracing when executed
+    start_pc: Option<u64>,
+    /// PC of the instruction that stops tracing when executed
+    stop_pc: Option<u64>,
+    /// Whether the instruction at `start_pc` has executed
+    start_hit: AtomicBool,
+    /// Whether the instruction at `stop_pc` has executed
+    stop_hit: AtomicBool,
+    /// When the window was created, on plugin setup
+    created: Instant,
+    /// Number of instructions executed so far, on every VCPU
+    insns: AtomicU64,
+    /// The current `Phase`
+    phase: AtomicU8,
+}
+
+impl Window {
+    /// Instantiate a new `Window`. Each threshold is optional, a window with none of them is
+    /// always open
+    ///
+    /// # Arguments
+    ///
+    /// * `start_insns` - Number of instructions to execute before tracing starts
+    /// * `stop_insns` - Number of instructions after which tracing stops
+    /// * `start_after` - Time to wait before tracing starts
+    /// * `stop_after` - Time after which tracing stops
+    /// * `start_pc` - PC of the instruction that starts tracing when executed
+    /// * `stop_pc` - PC of the instruction that stops tracing when executed
+    pub fn new(
+        start_insns: Option<u64>,
+        stop_insns: Option<u64>,
+        start_after: Option<Duration>,
+        stop_after: Option<Duration>,
+        start_pc: Option<u64>,
+        stop_pc: Option<u64>,
+    ) -> Self {
+        let window = Self {
+            start_insns: start_insns.unwrap_or(0),
+            stop_insns,
+            start_after: start_after.unwrap_or_default(),
+            stop_after,
+            start_pc,
+            stop_pc,
+            start_hit: AtomicBool::new(false),
+            stop_hit: AtomicBool::new(false),
+            created: Instant::now(),
+            insns: AtomicU64::new(0),
+            phase: AtomicU8::new(Phase::Dormant as u8),
+        };
+
+        window
+            .phase
+            .store(window.phase_at(0) as u8, Ordering::Relaxed);
+        window
+    }
+
+    /// Whether the window has an instruction or time threshold, in which case executed
+    /// instructions need to be counted
+    pub fn is_bounded(&self) -> bool {
+        self.start_insns > 0
+            || self.stop_insns.is_some()
+            || !self.start_after.is_zero()
+            || self.stop_after.is_some()
+    }
+
+    /// The current phase of the window
+    pub fn phase(&self) -> Phase {
+        self.phase.load(Ordering::Relaxed).into()
+    }
+
+    /// Whether events should currently be traced
+    pub fn is_open(&self) -> bool {
+        self.phase() == Phase::Tracing
+    }
+
+    /// The phase the window should be in after `insns` instructions have executed
+    ///
+    /// # Arguments
+    ///
+    /// * `insns` - Number of instructions executed so far
+    fn phase_at(&self, insns: u64) -> Phase {
+        let elapsed = self.created.elapsed();
+
+        if matches!(self.stop_insns, Some(stop) if insns >= stop)
+            || matches!(self.stop_after, Some(stop) if elapsed >= stop)
+            || self.stop_hit.load(Ordering::Relaxed)
+        {
+            Phase::Done
+        } else if insns >= self.start_insns
+            && elapsed >= self.start_after
+            && (self.start_pc.is_none() || self.start_hit.load(Ordering::Relaxed))
+        {
+            Phase::Tracing
+        } else {
+            Phase::Dormant
+        }
+    }
+
+    /// Account for executed instructions. If this moves the window to a new phase, the new
+    /// phase is returned. Only one caller ever observes each transition
+    ///
+    /// # Arguments
+    ///
+    /// * `n_insns` - Number of instructions just executed
+    pub fn advance(&self, n_insns: u64) -> Option<Phase> {
+        let insns = self.insns.fetch_add(n_insns, Ordering::Relaxed) + n_insns;
+        self.transition(self.phase_at(insns))
+    }
+
+    /// Move the window forward to `phase`, returning it if the window was not already there
+    ///
+    /// # Arguments
+    ///
+    /// * `phase` - The phase to move to
+    fn transition(&self, phase: Phase) -> Option<Phase> {
+        let previous = Phase::from(self.phase.fetch_max(phase as u8, Ordering::Relaxed));
+
+        if previous < phase {
+            Some(phase)
+        } else {
+            None
+        }
+    }
+
+    /// The kind of trigger at an instruction, if the instruction is a trigger that can still
+    /// change the phase of the window and so must be instrumented
+    ///
+    /// # Arguments
+    ///
+    /// * `vaddr` - The virtual address of the instruction
+    pub fn trigger_at(&self, vaddr: u64) -> Option<Trigger> {
+        match self.phase() {
+            Phase::Dormant if self.start_pc == Some(vaddr) => Some(Trigger::Start),
+            Phase::Dormant | Phase::Tracing if self.stop_pc == Some(vaddr) => Some(Trigger::Stop),
+            _ => None,
+        }
+    }
+
+    /// Pull a trigger, after its instruction executed. If this moves the window to a new
+    /// phase, the new phase is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `trigger` - The trigger that was pulled
+    pub fn pull(&self, trigger: Trigger) -> Option<Phase> {
+        match trigger {
+            Trigger::Start => self.start_hit.store(true, Ordering::Relaxed),
+            Trigger::Stop => self.stop_hit.store(true, Ordering::Relaxed),
+        }
+
+        self.advance(0)
+    }
+}
@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+
+/// An allocator function Magpie hooks
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Function {
+    Malloc,
+    Calloc,
+    Realloc,
+    Free,
+}
+
+/// An image defining allocator functions that was mapped, and whose functions are hooked
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LoadEvent {
+    pub path: String,
+    pub bias: u64,
+    pub functions: Vec<(Function, u64)>,
+}
+
+/// A call to an allocator function that returned
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CallEvent {
+    pub vcpu_idx: u32,
+    pub function: Function,
+    pub call_site: Option<u64>,
+    pub ptr: Option<u64>,
+    pub size: Option<u64>,
+    pub addr: Option<u64>,
+}
+
+/// An access to freed memory, made outside of the allocator
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AccessEvent {
+    pub vcpu_idx: u32,
+    pub pc: u64,
+    pub addr: u64,
+    pub size: usize,
+    pub is_store: bool,
+    pub value: Option<u128>,
+}
+
+/// The events Magpie logs, one JSON object per line
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum HeapEvent {
+    Load(LoadEvent),
+    Call(CallEvent),
+    Access(AccessEvent),
+}
@@ -0,0 +1,137 @@
+//! Magpie driver binary
+//!
+//! Runs a program under QEMU with the Magpie plugin, which logs its calls to the allocator
+//! and its accesses to freed memory, then prints a report of the leaks and use after free
+//! candidates found in the log.
+
+mod events;
+mod report;
+
+use cannonball_plugin_dist::find;
+use clap::Parser;
+use memfd_exec::{MemFdExecutable, Stdio};
+use qemu::qemu_x86_64;
+
+use std::{
+    env::temp_dir,
+    fs::{read, remove_file, write, File},
+    io::{BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    process::id,
+    thread::spawn,
+};
+
+use events::HeapEvent;
+use report::Report;
+
+#[derive(Parser, Debug)]
+/// Find leaks and uses of freed memory with the Magpie QEMU plugin
+struct Args {
+    /// A file to keep the plugin's event log in, one JSON event per line. If not set, the log is deleted after the report is printed.
+    #[clap(short, long)]
+    pub events: Option<PathBuf>,
+    /// An input file to feed to the program. If not set, the program will take input via this driver's stdin.
+    #[clap(short = 'I', long)]
+    pub input_file: Option<PathBuf>,
+    /// An output file to write the program's output to. If not set, the program's output will be written to this driver's stdout.
+    #[clap(short = 'O', long)]
+    pub output_file: Option<PathBuf>,
+    /// The program to run
+    #[clap()]
+    pub program: PathBuf,
+    /// The arguments to the program
+    #[clap(num_args = 1.., last = true)]
+    pub args: Vec<String>,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let program_path = args
+        .program
+        .canonicalize()
+        .expect("Failed to find program")
+        .to_string_lossy()
+        .to_string();
+
+    let log_path = args
+        .events
+        .clone()
+        .unwrap_or_else(|| temp_dir().join(format!("magpie-{}.jsonl", id())));
+
+    // QEMU splits plugin arguments on commas, so commas in values must be doubled
+    let plugin_args = format!(
+        ",log={},binary={}",
+        log_path.to_string_lossy().replace(',', ",,"),
+        program_path.replace(',', ",,")
+    );
+
+    let qemu = qemu_x86_64();
+
+    // Cargo builds the plugin next to this binary
+    let plugin_path = find("magpie").expect("Failed to find the plugin");
+
+    let mut exe = MemFdExecutable::new("qemu-x86_64", qemu)
+        .arg("-plugin")
+        .arg(format!(
+            "{}{}",
+            plugin_path.canonicalize().unwrap().to_string_lossy(),
+            plugin_args
+        ))
+        .arg("--")
+        .arg(program_path)
+        .args(args.args)
+        .stdin(if args.input_file.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::Inherit
+        })
+        .stdout(if args.output_file.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::Inherit
+        })
+        .stderr(Stdio::inherit())
+        .spawn()
+        .expect("Failed to spawn QEMU");
+
+    if let Some(input_file) = args.input_file {
+        let mut stdin = exe.stdin.take().expect("Failed to get stdin");
+        let input = read(input_file).expect("Failed to read input file");
+        spawn(move || {
+            stdin.write_all(&input).expect("Failed to write input");
+        });
+    }
+
+    let output = args.output_file.map(|output_file| {
+        let mut stdout = exe.stdout.take().expect("Failed to get stdout");
+        spawn(move || {
+            let mut output = Vec::new();
+            stdout
+                .read_to_end(&mut output)
+                .expect("Failed to read output");
+            write(output_file, output).expect("Failed to write output");
+        })
+    });
+
+    exe.wait().expect("Failed to wait for QEMU");
+
+    if let Some(output) = output {
+        output.join().expect("Failed to write output");
+    }
+
+    let log = File::open(&log_path).expect("Failed to open event log");
+    let mut report = Report::default();
+
+    for line in BufReader::new(log).lines() {
+        let line = line.expect("Failed to read event log");
+        report.add(serde_json::from_str::<HeapEvent>(&line).expect("Failed to parse event"));
+    }
+
+    if args.events.is_none() {
+        remove_file(&log_path).ok();
+    }
+
+    // The report goes to stderr to keep it apart from the program's own output
+    eprint!("{}", report);
+}
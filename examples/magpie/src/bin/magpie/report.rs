@@ -0,0 +1,282 @@
+//! Heap report
+//!
+//! The report replays the allocator calls the plugin logged to know, at each point of the
+//! program, which chunks were live and which were freed, and by which calls. Allocations still
+//! live at the end of the log are leaks, grouped by the call site that allocated them. Each
+//! access to freed memory is attributed to the freed chunk it touched, and the allocation and
+//! free of that chunk, and accesses are grouped by the instruction that made them. They are
+//! only candidates: the allocator may hand out freed memory without a hooked call, like an
+//! allocation made through `posix_memalign`.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display, Formatter},
+};
+
+use crate::events::{AccessEvent, CallEvent, Function, HeapEvent, LoadEvent};
+
+/// A chunk handed out by the allocator
+#[derive(Debug, Clone)]
+struct Chunk {
+    /// The function that allocated it
+    function: Function,
+    /// Where it was allocated from
+    call_site: Option<u64>,
+    /// The size requested
+    size: u64,
+}
+
+/// A chunk that was freed, and where it was freed from
+#[derive(Debug, Clone)]
+struct Freed {
+    chunk: Chunk,
+    free_site: Option<u64>,
+}
+
+/// Accesses to freed memory made by one instruction, to chunks allocated and freed at the
+/// same call sites
+#[derive(Debug, Clone)]
+struct UseAfterFree {
+    /// The first of the accesses
+    access: AccessEvent,
+    /// The offset of the first access in its chunk
+    offset: u64,
+    /// The chunk the first access touched
+    freed: Freed,
+    /// The number of accesses
+    count: u64,
+}
+
+/// Chunks that were never freed, allocated by the same function at the same call site
+#[derive(Debug, Clone)]
+struct Leak {
+    function: Function,
+    call_site: Option<u64>,
+    /// The number of chunks
+    chunks: u64,
+    /// Their total size
+    bytes: u64,
+}
+
+/// The state of the heap, and what was found wrong with it
+#[derive(Debug, Default)]
+pub struct Report {
+    /// The images whose allocator functions were hooked
+    images: Vec<LoadEvent>,
+    /// The number of allocator calls of each function
+    calls: HashMap<Function, u64>,
+    /// The live chunks, by address
+    live: HashMap<u64, Chunk>,
+    /// The freed chunks that were not handed out again, by address
+    freed: BTreeMap<u64, Freed>,
+    /// Accesses to freed memory, by the instruction making them and the sites the chunk was
+    /// allocated and freed at
+    uses: BTreeMap<(u64, Option<u64>, Option<u64>), UseAfterFree>,
+}
+
+/// Format a call site, which is not known if the call instruction was not seen
+///
+/// # Arguments
+///
+/// * `site` - The address of the call instruction
+fn site(site: Option<u64>) -> String {
+    match site {
+        Some(addr) => format!("{:#x}", addr),
+        None => "an unknown call site".to_string(),
+    }
+}
+
+impl Report {
+    /// Add an event from the log to the report
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    pub fn add(&mut self, event: HeapEvent) {
+        match event {
+            HeapEvent::Load(load) => self.images.push(load),
+            HeapEvent::Call(call) => self.call(call),
+            HeapEvent::Access(access) => self.access(access),
+        }
+    }
+
+    /// Record an allocator call
+    ///
+    /// # Arguments
+    ///
+    /// * `call` - The call
+    fn call(&mut self, call: CallEvent) {
+        *self.calls.entry(call.function).or_default() += 1;
+
+        let ptr = call.ptr.unwrap_or_default();
+        let size = call.size.unwrap_or_default();
+        let addr = call.addr.unwrap_or_default();
+
+        // A failed reallocation leaves the old chunk alone, unless it was a free
+        if call.function == Function::Realloc && addr == 0 && size != 0 {
+            return;
+        }
+
+        if ptr != 0 {
+            if let Some(chunk) = self.live.remove(&ptr) {
+                self.freed.insert(
+                    ptr,
+                    Freed {
+                        chunk,
+                        free_site: call.call_site,
+                    },
+                );
+            }
+        }
+
+        if addr != 0 {
+            // Freed chunks the new chunk overlaps are not freed memory anymore
+            let end = addr.saturating_add(size.max(1));
+            let reused = self
+                .freed
+                .range(..end)
+                .rev()
+                .take_while(|(&start, freed)| start.saturating_add(freed.chunk.size.max(1)) > addr)
+                .map(|(&start, _)| start)
+                .collect::<Vec<_>>();
+
+            for start in reused {
+                self.freed.remove(&start);
+            }
+
+            self.live.insert(
+                addr,
+                Chunk {
+                    function: call.function,
+                    call_site: call.call_site,
+                    size,
+                },
+            );
+        }
+    }
+
+    /// Record an access to freed memory, if it touches a freed chunk
+    ///
+    /// # Arguments
+    ///
+    /// * `access` - The access
+    fn access(&mut self, access: AccessEvent) {
+        let end = access.addr.saturating_add(access.size.max(1) as u64);
+
+        let Some((&start, freed)) = self
+            .freed
+            .range(..end)
+            .next_back()
+            .filter(|(&start, freed)| start.saturating_add(freed.chunk.size.max(1)) > access.addr)
+        else {
+            return;
+        };
+
+        let key = (access.pc, freed.chunk.call_site, freed.free_site);
+        let offset = access.addr.wrapping_sub(start);
+        let freed = freed.clone();
+
+        self.uses
+            .entry(key)
+            .and_modify(|group| group.count += 1)
+            .or_insert(UseAfterFree {
+                access,
+                offset,
+                freed,
+                count: 1,
+            });
+    }
+
+    /// The chunks still live, grouped by the function and call site that allocated them,
+    /// largest first
+    fn leaks(&self) -> Vec<Leak> {
+        let mut leaks = HashMap::<(Function, Option<u64>), Leak>::new();
+
+        for chunk in self.live.values() {
+            let leak = leaks
+                .entry((chunk.function, chunk.call_site))
+                .or_insert(Leak {
+                    function: chunk.function,
+                    call_site: chunk.call_site,
+                    chunks: 0,
+                    bytes: 0,
+                });
+            leak.chunks += 1;
+            leak.bytes += chunk.size;
+        }
+
+        let mut leaks = leaks.into_values().collect::<Vec<_>>();
+        leaks.sort_by_key(|leak| (Reverse(leak.bytes), leak.call_site));
+        leaks
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Magpie heap report")?;
+
+        if self.images.is_empty() {
+            writeln!(f, "  No allocator was found, so nothing was tracked")?;
+            return Ok(());
+        }
+
+        for image in &self.images {
+            writeln!(
+                f,
+                "  Allocator hooked in {} at {:#x}",
+                image.path, image.bias
+            )?;
+        }
+
+        for function in [
+            Function::Malloc,
+            Function::Calloc,
+            Function::Realloc,
+            Function::Free,
+        ] {
+            let calls = self.calls.get(&function).copied().unwrap_or_default();
+            writeln!(f, "  {:?}: {} calls", function, calls)?;
+        }
+
+        let leaks = self.leaks();
+        writeln!(
+            f,
+            "Leaks: {} bytes in {} chunks",
+            leaks.iter().map(|leak| leak.bytes).sum::<u64>(),
+            self.live.len()
+        )?;
+
+        for leak in &leaks {
+            writeln!(
+                f,
+                "  {} bytes in {} chunks from {:?} called at {}",
+                leak.bytes,
+                leak.chunks,
+                leak.function,
+                site(leak.call_site)
+            )?;
+        }
+
+        writeln!(f, "Use after free candidates: {}", self.uses.len())?;
+
+        for group in self.uses.values() {
+            writeln!(
+                f,
+                "  {:#x}: {} of {} bytes at {:#x}, offset {} in a {} byte chunk from {:?} called at {}, freed at {} ({} accesses)",
+                group.access.pc,
+                if group.access.is_store { "store" } else { "load" },
+                group.access.size,
+                group.access.addr,
+                group.offset,
+                group.freed.chunk.size,
+                group.freed.chunk.function,
+                site(group.freed.chunk.call_site),
+                site(group.freed.free_site),
+                group.count,
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+
+use crate::images::Function;
+
+/// An image defining allocator functions that was mapped, and whose functions are hooked
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LoadEvent {
+    pub path: String,
+    pub bias: u64,
+    pub functions: Vec<(Function, u64)>,
+}
+
+impl LoadEvent {
+    /// Instantiate a new `LoadEvent`
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the image
+    /// * `bias` - The address the image was loaded at, relative to its symbol table
+    /// * `functions` - The allocator functions it defines, and their addresses
+    pub fn new(path: String, bias: u64, functions: Vec<(Function, u64)>) -> Self {
+        Self {
+            path,
+            bias,
+            functions,
+        }
+    }
+}
+
+/// A call to an allocator function that returned
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CallEvent {
+    pub vcpu_idx: u32,
+    pub function: Function,
+    pub call_site: Option<u64>,
+    pub ptr: Option<u64>,
+    pub size: Option<u64>,
+    pub addr: Option<u64>,
+}
+
+impl CallEvent {
+    /// Instantiate a new `CallEvent`
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_idx` - The VCPU that made the call
+    /// * `function` - The function called
+    /// * `call_site` - The address of the call instruction, if it was seen
+    /// * `ptr` - The allocation passed to `realloc` or `free`
+    /// * `size` - The size requested from `malloc`, `calloc` or `realloc`
+    /// * `addr` - The allocation returned by `malloc`, `calloc` or `realloc`
+    pub fn new(
+        vcpu_idx: u32,
+        function: Function,
+        call_site: Option<u64>,
+        ptr: Option<u64>,
+        size: Option<u64>,
+        addr: Option<u64>,
+    ) -> Self {
+        Self {
+            vcpu_idx,
+            function,
+            call_site,
+            ptr,
+            size,
+            addr,
+        }
+    }
+}
+
+/// An access to freed memory, made outside of the allocator
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AccessEvent {
+    pub vcpu_idx: u32,
+    pub pc: u64,
+    pub addr: u64,
+    pub size: usize,
+    pub is_store: bool,
+    pub value: Option<u128>,
+}
+
+impl AccessEvent {
+    /// Instantiate a new `AccessEvent`
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_idx` - The VCPU that made the access
+    /// * `pc` - The address of the instruction making the access
+    /// * `addr` - The address accessed
+    /// * `size` - The size of the access
+    /// * `is_store` - Whether the access is a store
+    /// * `value` - The value loaded or stored, if it was captured
+    pub fn new(
+        vcpu_idx: u32,
+        pc: u64,
+        addr: u64,
+        size: usize,
+        is_store: bool,
+        value: Option<u128>,
+    ) -> Self {
+        Self {
+            vcpu_idx,
+            pc,
+            addr,
+            size,
+            is_store,
+            value,
+        }
+    }
+}
+
+/// The events Magpie logs, one JSON object per line
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum HeapEvent {
+    Load(LoadEvent),
+    Call(CallEvent),
+    Access(AccessEvent),
+}
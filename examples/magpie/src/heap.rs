@@ -0,0 +1,102 @@
+//! Heap chunks
+//!
+//! The plugin keeps the size of every live allocation, and the ranges of memory that were
+//! freed and have not been handed out again, so accesses to freed memory can be reported as
+//! they happen. Which allocation a freed chunk belonged to is left to the consumer of the
+//! events, which sees the same allocations and frees.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// The live and freed chunks of the heap
+#[derive(Debug, Default)]
+pub struct Heap {
+    /// The size of each live allocation, by address
+    live: HashMap<u64, u64>,
+    /// The end of each freed range of memory, by start address. Ranges do not overlap
+    freed: BTreeMap<u64, u64>,
+}
+
+impl Heap {
+    /// Record an allocation. Any freed memory it covers is live again
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the allocation
+    /// * `size` - The size of the allocation
+    pub fn allocate(&mut self, addr: u64, size: u64) {
+        if addr == 0 {
+            return;
+        }
+
+        let end = addr.saturating_add(size.max(1));
+        let overlapping = self
+            .freed
+            .range(..end)
+            .rev()
+            .take_while(|(_, &freed_end)| freed_end > addr)
+            .map(|(&start, &freed_end)| (start, freed_end))
+            .collect::<Vec<_>>();
+
+        // The parts of freed ranges outside the allocation are still freed
+        for (start, freed_end) in overlapping {
+            self.freed.remove(&start);
+
+            if start < addr {
+                self.freed.insert(start, addr);
+            }
+
+            if freed_end > end {
+                self.freed.insert(end, freed_end);
+            }
+        }
+
+        self.live.insert(addr, size);
+    }
+
+    /// Record a free of an allocation. Frees of addresses that are not live allocations are
+    /// ignored
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the allocation
+    pub fn free(&mut self, addr: u64) {
+        if let Some(size) = self.live.remove(&addr) {
+            self.freed.insert(addr, addr.saturating_add(size.max(1)));
+        }
+    }
+
+    /// Record a reallocation, which frees the old allocation if it moved
+    ///
+    /// # Arguments
+    ///
+    /// * `old` - The address of the old allocation
+    /// * `size` - The requested size
+    /// * `addr` - The address of the new allocation, which is null if it failed
+    pub fn reallocate(&mut self, old: u64, size: u64, addr: u64) {
+        // A failed reallocation leaves the old allocation alone, unless it was a free
+        if addr == 0 && size != 0 {
+            return;
+        }
+
+        if old != 0 {
+            self.free(old);
+        }
+
+        self.allocate(addr, size);
+    }
+
+    /// Whether an access touches freed memory
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the access
+    /// * `size` - The size of the access
+    pub fn is_freed(&self, addr: u64, size: u64) -> bool {
+        let end = addr.saturating_add(size.max(1));
+
+        self.freed
+            .range(..end)
+            .next_back()
+            .is_some_and(|(_, &freed_end)| freed_end > addr)
+    }
+}
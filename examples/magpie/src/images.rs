@@ -0,0 +1,139 @@
+//! Allocator symbols of ELF images
+//!
+//! The allocator functions are found in the symbol tables of the ELF images the program maps,
+//! which for a dynamically linked program means the C library. Shared libraries are mapped by
+//! the dynamic loader with `mmap`, and in QEMU user mode the plugin runs in the same process
+//! as the guest with the same file descriptors, so the mapped file is found through
+//! `/proc/self/fd`. The load bias of the image follows from the file offset that was mapped
+//! and the address it was mapped at.
+
+use goblin::elf::{header::ET_DYN, program_header::PT_LOAD, Elf};
+use serde::{Deserialize, Serialize};
+
+use std::{fs::read, path::Path};
+
+/// An allocator function Magpie hooks
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    Malloc,
+    Calloc,
+    Realloc,
+    Free,
+}
+
+impl Function {
+    /// The allocator function with a symbol name, if it is one
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the symbol
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "malloc" => Some(Self::Malloc),
+            "calloc" => Some(Self::Calloc),
+            "realloc" => Some(Self::Realloc),
+            "free" => Some(Self::Free),
+            _ => None,
+        }
+    }
+}
+
+/// A loadable segment of an image
+#[derive(Debug, Clone)]
+struct Segment {
+    /// The offset of the segment in the file
+    offset: u64,
+    /// The address of the segment, before relocation
+    vaddr: u64,
+    /// The size of the segment in the file
+    size: u64,
+}
+
+/// An ELF image defining allocator functions
+#[derive(Debug, Clone)]
+pub struct Image {
+    /// The allocator functions, and their addresses before relocation
+    pub functions: Vec<(Function, u64)>,
+    /// Whether the image is position independent, and is loaded at a bias
+    pub position_independent: bool,
+    /// The loadable segments of the image
+    segments: Vec<Segment>,
+}
+
+impl Image {
+    /// Load the allocator symbols of an ELF image. Returns `None` if the file is not an ELF
+    /// image or defines none of the allocator functions
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the image
+    pub fn load(path: &Path) -> Option<Self> {
+        let data = read(path).ok()?;
+        let elf = Elf::parse(&data).ok()?;
+
+        // Shared libraries usually only keep their dynamic symbols
+        let mut functions: Vec<(Function, u64)> = elf
+            .syms
+            .iter()
+            .map(|sym| (sym, elf.strtab.get_at(sym.st_name)))
+            .chain(
+                elf.dynsyms
+                    .iter()
+                    .map(|sym| (sym, elf.dynstrtab.get_at(sym.st_name))),
+            )
+            .filter(|(sym, _)| sym.is_function() && sym.st_value != 0)
+            .filter_map(|(sym, name)| Some((Function::from_name(name?)?, sym.st_value)))
+            .collect();
+
+        functions.sort_by_key(|(_, vaddr)| *vaddr);
+        functions.dedup();
+
+        if functions.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            functions,
+            position_independent: elf.header.e_type == ET_DYN,
+            segments: elf
+                .program_headers
+                .iter()
+                .filter(|header| header.p_type == PT_LOAD)
+                .map(|header| Segment {
+                    offset: header.p_offset,
+                    vaddr: header.p_vaddr,
+                    size: header.p_filesz,
+                })
+                .collect(),
+        })
+    }
+
+    /// The load bias of the image, given that the page at `offset` in the file was mapped at
+    /// `addr`. Returns `None` if no loadable segment contains the offset
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The offset in the file that was mapped
+    /// * `addr` - The address it was mapped at
+    pub fn bias(&self, offset: u64, addr: u64) -> Option<u64> {
+        if !self.position_independent {
+            return Some(0);
+        }
+
+        // Segments are mapped from the start of the page containing them, which is at the
+        // same offset from the segment in the file as in memory
+        self.segments
+            .iter()
+            .find(|segment| {
+                (segment.offset & !0xfff) <= offset && offset < segment.offset + segment.size
+            })
+            .map(|segment| {
+                addr.wrapping_sub(
+                    segment
+                        .vaddr
+                        .wrapping_sub(segment.offset)
+                        .wrapping_add(offset),
+                )
+            })
+    }
+}
@@ -0,0 +1,520 @@
+//! Magpie heap tracking plugin
+//!
+//! Magpie hooks the allocator of an x86_64 program running in QEMU user mode, and logs every
+//! call to `malloc`, `calloc`, `realloc` and `free` with its arguments, its result, and the
+//! call site it was made from. It also logs the accesses the program makes to memory that was
+//! freed and not allocated again, which the driver binary correlates with the allocations and
+//! frees to report use after free candidates, along with the allocations that were never freed.
+//!
+//! The allocator functions are found in the symbol tables of the images the program maps
+//! (see `images`): the shared libraries the dynamic loader maps with `mmap`, and the program
+//! itself if it is statically linked and its path is given as `binary=PATH`. Each image
+//! defining them is logged with a `Load` event, with the bias it was loaded at.
+//!
+//! A hooked function is entered when its first instruction executes, where its arguments are
+//! read from registers. It returns when a `ret` executes with the stack pointer it was entered
+//! with, which also catches returns from functions it tail called, and its result is read from
+//! `rax`. The call site is the call instruction that pushed the return address. Calls the
+//! allocator makes to itself, like `realloc` calling `malloc`, are not logged, and its own
+//! accesses to freed memory are not reported.
+//!
+//! Arguments and results are read from registers, so Magpie needs plugin API version 2 (QEMU
+//! 9.0) or later. Built with the `plugin-api-v3` feature, accesses to freed memory carry the
+//! value loaded or stored.
+
+mod events;
+mod heap;
+mod images;
+
+use cannonball::{
+    api::{qemu_info_t, qemu_plugin_meminfo_t, qemu_plugin_tb},
+    args::{Args, QEMUArg},
+    callbacks::{
+        RegisterInsnExec, SetupCallback, SetupCallbackType, StaticCallbackType,
+        VCPUInsnExecCallback, VCPUMemCallback, VCPUSyscallCallback, VCPUSyscallRetCallback,
+        VCPUTBTransCallback,
+    },
+    guest::endianness,
+    instrument::instructions,
+    mem::MemInfo,
+    plugin::Identity,
+    registers::{registers, Register},
+    tb::TBData,
+};
+use inventory::submit;
+use lazy_static::lazy_static;
+use libc::c_void;
+use once_cell::sync::Lazy;
+use serde_json::to_vec;
+use yaxpeax_arch::{Decoder, U8Reader};
+use yaxpeax_x86::long_mode::{InstDecoder, Opcode};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::CStr,
+    fs::{read_link, File},
+    io::{stdout, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use events::{AccessEvent, CallEvent, HeapEvent, LoadEvent};
+use heap::Heap;
+use images::{Function, Image};
+
+/// The `mmap` system call number, on x86_64
+const MMAP: i64 = 9;
+
+/// The `munmap` system call number, on x86_64
+const MUNMAP: i64 = 11;
+
+/// A call to a hooked allocator function, until it returns
+#[derive(Debug, Clone)]
+struct Call {
+    /// The function called
+    function: Function,
+    /// The address of the call instruction, if it was seen
+    call_site: Option<u64>,
+    /// The stack pointer on entry, which points to the return address
+    rsp: u64,
+    /// The first two arguments
+    args: [u64; 2],
+}
+
+/// The heap tracking state of a VCPU
+#[derive(Debug, Default)]
+struct Vcpu {
+    /// The address of the last call instruction that pushed its return address to each stack
+    /// slot, by the address of the slot
+    calls: HashMap<u64, u64>,
+    /// The allocator call being made, until it returns
+    pending: Option<Call>,
+    /// The file and offset being mapped by an `mmap` call, until it returns
+    mmap: Option<(PathBuf, u64)>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The file events are logged to, or stdout if not set
+    log: Option<File>,
+    /// The images that were mapped, and their allocator symbols if they define any
+    images: HashMap<PathBuf, Option<Arc<Image>>>,
+    /// The bias each hooked image was loaded at
+    loaded: HashMap<PathBuf, u64>,
+    /// The hooked allocator functions, by entry address
+    hooks: BTreeMap<u64, Function>,
+    /// The live and freed chunks of the heap
+    heap: Heap,
+    /// The heap tracking state of each VCPU
+    vcpus: HashMap<u32, Vcpu>,
+}
+
+lazy_static! {
+    /// The global state of the heap tracking plugin
+    static ref STATE: Mutex<State> = Mutex::new(State::default());
+}
+
+thread_local! {
+    /// The registers of the VCPU running on this thread, by name. Register handles are the
+    /// same for every VCPU, so they are only looked up once
+    static REGISTERS: HashMap<String, Register> = registers()
+        .into_iter()
+        .map(|register| (register.name().to_string(), register))
+        .collect();
+}
+
+/// The value of a general purpose register of the current VCPU, which can only be read from
+/// callbacks registered to read registers
+///
+/// # Arguments
+///
+/// * `name` - The name of the register
+fn read_register(name: &str) -> Option<u64> {
+    let value = REGISTERS.with(|registers| registers.get(name)?.read())?;
+
+    endianness().read_u64(&value)
+}
+
+impl State {
+    /// Log an event as a line of JSON
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event
+    fn log_event(&mut self, event: HeapEvent) {
+        let mut line = to_vec(&event).expect("Could not serialize event!");
+        line.push(b'\n');
+
+        // Each line is written at once, so lines from different threads are not interleaved
+        match self.log.as_mut() {
+            Some(log) => log.write_all(&line),
+            None => stdout().lock().write_all(&line),
+        }
+        .expect("Could not write event!");
+    }
+
+    /// Hook the allocator functions of an image that was mapped, unless it is already hooked
+    /// at the same bias
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the image
+    /// * `offset` - The offset in the file that was mapped
+    /// * `addr` - The address it was mapped at
+    fn map(&mut self, path: PathBuf, offset: u64, addr: u64) {
+        let image = self
+            .images
+            .entry(path.clone())
+            .or_insert_with(|| Image::load(&path).map(Arc::new))
+            .clone();
+
+        let Some(bias) = image.as_ref().and_then(|image| image.bias(offset, addr)) else {
+            return;
+        };
+
+        if self.loaded.get(&path) == Some(&bias) {
+            return;
+        }
+
+        let functions = image
+            .iter()
+            .flat_map(|image| image.functions.iter())
+            .map(|&(function, vaddr)| (function, vaddr.wrapping_add(bias)))
+            .collect::<Vec<_>>();
+
+        self.hooks
+            .extend(functions.iter().map(|&(f, vaddr)| (vaddr, f)));
+        self.loaded.insert(path.clone(), bias);
+
+        self.log_event(HeapEvent::Load(LoadEvent::new(
+            path.to_string_lossy().to_string(),
+            bias,
+            functions,
+        )));
+    }
+
+    /// Record the result of an allocator call that returned, and log it
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_idx` - The VCPU that made the call
+    /// * `call` - The call
+    /// * `rv` - The value it returned
+    fn finish(&mut self, vcpu_idx: u32, call: Call, rv: u64) {
+        let [arg0, arg1] = call.args;
+
+        let (ptr, size, addr) = match call.function {
+            Function::Malloc => {
+                self.heap.allocate(rv, arg0);
+                (None, Some(arg0), Some(rv))
+            }
+            Function::Calloc => {
+                let size = arg0.saturating_mul(arg1);
+                self.heap.allocate(rv, size);
+                (None, Some(size), Some(rv))
+            }
+            Function::Realloc => {
+                self.heap.reallocate(arg0, arg1, rv);
+                (Some(arg0), Some(arg1), Some(rv))
+            }
+            Function::Free => {
+                self.heap.free(arg0);
+                (Some(arg0), None, None)
+            }
+        };
+
+        self.log_event(HeapEvent::Call(CallEvent::new(
+            vcpu_idx,
+            call.function,
+            call.call_site,
+            ptr,
+            size,
+            addr,
+        )));
+    }
+}
+
+/// Called on plugin load with the arguments passed to the plugin on the command line, to open
+/// the log and hook the allocator of a statically linked program
+fn setup(info: *const qemu_info_t, args: &Args) {
+    let target_name = unsafe { CStr::from_ptr((*info).target_name) }.to_string_lossy();
+
+    if target_name != "x86_64" {
+        panic!("Magpie only supports x86_64 programs, not {}!", target_name);
+    }
+
+    let mut state = STATE.lock().expect("setup: Could not lock state!");
+
+    if let Some(QEMUArg::Str(log)) = args.args.get("log") {
+        state.log = Some(File::create(log).expect("Could not create log file!"));
+    }
+
+    // QEMU loads the program itself, so its bias is only known if it is not position
+    // independent. Position independent programs are dynamically linked, and get the
+    // allocator from a shared library
+    if let Some(QEMUArg::Str(binary)) = args.args.get("binary") {
+        let path = PathBuf::from(binary);
+        let image = Image::load(&path).map(Arc::new);
+        let is_static = image
+            .as_ref()
+            .is_some_and(|image| !image.position_independent);
+
+        state.images.insert(path.clone(), image);
+
+        if is_static {
+            state.map(path, 0, 0);
+        }
+    }
+}
+
+submit! {
+    static scb: Lazy<SetupCallback> = Lazy::new(|| {
+        SetupCallback::new(|info, args| {
+            setup(info, args);
+        })
+    });
+    SetupCallbackType::Setup(&scb)
+}
+
+submit! {
+    // Name the plugin, which namespaces its arguments
+    Identity::new(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+/// Called before each call instruction executes, to remember the stack slot it pushes its
+/// return address to
+unsafe fn on_call(vcpu_idx: u32, data: *mut c_void) {
+    let pc = *(data as *const u64);
+
+    let Some(rsp) = read_register("rsp") else {
+        return;
+    };
+
+    let mut state = STATE.lock().expect("on_call: Could not lock state!");
+    state
+        .vcpus
+        .entry(vcpu_idx)
+        .or_default()
+        .calls
+        .insert(rsp.wrapping_sub(8), pc);
+}
+
+/// Called before the first instruction of a hooked allocator function executes, to read its
+/// arguments. Calls made while another allocator call is pending are part of that call
+unsafe fn on_entry(vcpu_idx: u32, data: *mut c_void) {
+    let function = *(data as *const Function);
+    let mut state = STATE.lock().expect("on_entry: Could not lock state!");
+    let vcpu = state.vcpus.entry(vcpu_idx).or_default();
+
+    if vcpu.pending.is_some() {
+        return;
+    }
+
+    let (Some(rsp), Some(rdi), Some(rsi)) = (
+        read_register("rsp"),
+        read_register("rdi"),
+        read_register("rsi"),
+    ) else {
+        return;
+    };
+
+    vcpu.pending = Some(Call {
+        function,
+        call_site: vcpu.calls.get(&rsp).copied(),
+        rsp,
+        args: [rdi, rsi],
+    });
+}
+
+/// Called before each return instruction executes. A return with the stack pointer a pending
+/// allocator call was entered with returns from it
+unsafe fn on_return(vcpu_idx: u32, _data: *mut c_void) {
+    let mut state = STATE.lock().expect("on_return: Could not lock state!");
+
+    let Some(entry_rsp) = state
+        .vcpus
+        .get(&vcpu_idx)
+        .and_then(|vcpu| vcpu.pending.as_ref())
+        .map(|call| call.rsp)
+    else {
+        return;
+    };
+
+    if read_register("rsp") != Some(entry_rsp) {
+        return;
+    }
+
+    let Some(call) = state
+        .vcpus
+        .get_mut(&vcpu_idx)
+        .and_then(|vcpu| vcpu.pending.take())
+    else {
+        return;
+    };
+
+    let rv = read_register("rax").unwrap_or_default();
+    state.finish(vcpu_idx, call, rv);
+}
+
+/// Called on each memory access. Accesses to freed memory made outside of the allocator are
+/// logged
+unsafe fn on_mem_access(vcpu_idx: u32, info: qemu_plugin_meminfo_t, vaddr: u64, data: *mut c_void) {
+    let pc = *(data as *const u64);
+    let info = MemInfo::new(info);
+    let mut state = STATE.lock().expect("on_mem_access: Could not lock state!");
+
+    let in_allocator = state
+        .vcpus
+        .get(&vcpu_idx)
+        .is_some_and(|vcpu| vcpu.pending.is_some());
+
+    if in_allocator || !state.heap.is_freed(vaddr, info.size() as u64) {
+        return;
+    }
+
+    #[cfg(feature = "plugin-api-v3")]
+    let value = Some(info.value().into());
+    #[cfg(not(feature = "plugin-api-v3"))]
+    let value = None;
+
+    state.log_event(HeapEvent::Access(AccessEvent::new(
+        vcpu_idx,
+        pc,
+        vaddr,
+        info.size(),
+        info.is_store(),
+        value,
+    )));
+}
+
+/// Called on translation of each translation block. Hooked function entries, calls and
+/// returns read registers, and every memory access is checked
+unsafe fn on_tb_trans(_id: u64, tb: *mut qemu_plugin_tb) {
+    let decoder = InstDecoder::default();
+    let state = STATE.lock().expect("on_tb_trans: Could not lock state!");
+
+    for insn in instructions(tb) {
+        let vaddr = insn.vaddr();
+
+        if let Some(&function) = state.hooks.get(&vaddr) {
+            VCPUInsnExecCallback::new(on_entry, TBData::new(function))
+                .reading_registers()
+                .register(insn.raw());
+        }
+
+        let data = insn.data();
+        let opcode = decoder
+            .decode(&mut U8Reader::new(data))
+            .map(|decoded| decoded.opcode());
+
+        match opcode {
+            Ok(Opcode::CALL) => VCPUInsnExecCallback::new(on_call, TBData::new(vaddr))
+                .reading_registers()
+                .register(insn.raw()),
+            Ok(Opcode::RETURN) => VCPUInsnExecCallback::new(on_return, TBData::new(()))
+                .reading_registers()
+                .register(insn.raw()),
+            _ => {}
+        }
+
+        VCPUMemCallback::new(on_mem_access, TBData::new(vaddr)).register(insn.raw());
+    }
+}
+
+submit! {
+    static tbcb: Lazy<VCPUTBTransCallback> = Lazy::new(|| {
+        VCPUTBTransCallback::new(on_tb_trans)
+    });
+    StaticCallbackType::VCPUTBTrans(&tbcb)
+}
+
+/// Called on each system call entry, to find the file an `mmap` call maps, and to unhook the
+/// functions of images that are unmapped
+#[allow(clippy::too_many_arguments)]
+unsafe fn on_syscall(
+    _id: u64,
+    vcpu_idx: u32,
+    num: i64,
+    arg0: u64,
+    arg1: u64,
+    _arg2: u64,
+    _arg3: u64,
+    arg4: u64,
+    arg5: u64,
+    _arg6: u64,
+    _arg7: u64,
+) {
+    let mut state = STATE.lock().expect("on_syscall: Could not lock state!");
+
+    match num {
+        // The file descriptor is only open until the program closes it, after mapping it
+        MMAP if (arg4 as i32) >= 0 => {
+            if let Ok(path) = read_link(format!("/proc/self/fd/{}", arg4 as i32)) {
+                state.vcpus.entry(vcpu_idx).or_default().mmap = Some((path, arg5));
+            }
+        }
+        MUNMAP => {
+            let hooked = state
+                .hooks
+                .range(arg0..arg0.saturating_add(arg1))
+                .map(|(&vaddr, _)| vaddr)
+                .collect::<Vec<_>>();
+
+            for vaddr in hooked {
+                state.hooks.remove(&vaddr);
+            }
+
+            // Images with none of their functions hooked anymore are hooked again if they are
+            // mapped again
+            let State {
+                images,
+                loaded,
+                hooks,
+                ..
+            } = &mut *state;
+
+            loaded.retain(|path, bias| {
+                images
+                    .get(path)
+                    .into_iter()
+                    .flatten()
+                    .flat_map(|image| image.functions.iter())
+                    .any(|&(_, vaddr)| hooks.contains_key(&vaddr.wrapping_add(*bias)))
+            });
+        }
+        _ => {}
+    }
+}
+
+submit! {
+    static syscb: Lazy<VCPUSyscallCallback> = Lazy::new(|| {
+        VCPUSyscallCallback::new(on_syscall)
+    });
+    StaticCallbackType::VCPUSyscall(&syscb)
+}
+
+/// Called on each system call exit. A file mapped by `mmap` is hooked once the address it was
+/// mapped at is known
+unsafe fn on_syscall_ret(_id: u64, vcpu_idx: u32, num: i64, rv: i64) {
+    let mut state = STATE.lock().expect("on_syscall_ret: Could not lock state!");
+
+    let Some((path, offset)) = state
+        .vcpus
+        .get_mut(&vcpu_idx)
+        .and_then(|vcpu| vcpu.mmap.take())
+    else {
+        return;
+    };
+
+    // Errors are returned as negated error numbers
+    if num == MMAP && !(-4095..0).contains(&rv) {
+        state.map(path, offset, rv as u64);
+    }
+}
+
+submit! {
+    static sysretcb: Lazy<VCPUSyscallRetCallback> = Lazy::new(|| {
+        VCPUSyscallRetCallback::new(on_syscall_ret)
+    });
+    StaticCallbackType::VCPUSyscallRet(&sysretcb)
+}
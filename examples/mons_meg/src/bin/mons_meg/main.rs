@@ -1,20 +1,18 @@
-mod events;
-
+use cannonball_plugin_dist::find;
 use clap::Parser;
 use memfd_exec::{MemFdExecutable, Stdio};
 use qemu::qemu_x86_64;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use serde_cbor::Deserializer;
 use std::{
     error::Error,
     fs::File,
     io::{BufRead, BufReader, Read, Write},
-    os::unix::net::UnixListener,
     path::PathBuf,
 };
-use tokio::{fs::write, io::AsyncWriteExt, join, spawn, task::spawn_blocking};
+use tokio::{io::AsyncWriteExt, join, spawn, task::spawn_blocking};
+use tokio_stream::StreamExt;
 
-use events::Event;
+use cannonball_client::consumer::EventStream;
 
 #[derive(Parser, Debug)]
 /// Trace a program with the Jaivana QEMU plugin
@@ -152,25 +150,8 @@ async fn main() {
         None => None,
     };
 
-    #[cfg(debug_assertions)]
-    let plugin = include_bytes!(concat!(
-        env!("CARGO_MANIFEST_DIR"),
-        "/../../target/debug/libmons_meg.so"
-    ));
-
-    #[cfg(not(debug_assertions))]
-    let plugin = include_bytes!(concat!(
-        env!("CARGO_MANIFEST_DIR"),
-        "/../target/release/libmons_meg.so"
-    ));
-
-    let pluginid = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(8)
-        .map(char::from)
-        .collect::<String>();
-    let pluginpath = PathBuf::from(format!("/tmp/qemu-{}.so", pluginid));
-    write(&pluginpath, plugin).await.unwrap();
+    // Cargo builds the plugin next to this binary
+    let pluginpath = find("mons_meg").expect("Failed to find the plugin");
     let plugin_args = format!(
         "{},log_pc={},log_opcode={},log_branch={},log_mem={},log_syscall={},socket_path={}",
         pluginpath.to_str().unwrap(),
@@ -188,7 +169,7 @@ async fn main() {
     qemu_args.push(program_path);
     qemu_args.extend(args.args);
 
-    let listen_sock = UnixListener::bind(&sockpath).unwrap();
+    let mut events = EventStream::bind(&sockpath).unwrap().max_connections(1);
 
     let mut outfile_stream = match args.output_file {
         Some(path) => {
@@ -199,19 +180,17 @@ async fn main() {
     };
 
     let qemu_task = spawn(async move { run_qemu(input_data, qemu_args).await });
-    // Spawn a task that reads from the socket and decodes the cbor encoded data
-    let socket_task = spawn_blocking(move || {
-        let (mut stream, _) = listen_sock.accept().unwrap();
-        let it = Deserializer::from_reader(&mut stream).into_iter::<Event>();
-        for event in it {
+    // Spawn a task that reads the events QEMU writes to the socket
+    let socket_task = spawn(async move {
+        while let Some(event) = events.next().await {
+            let (_, event) = event.unwrap();
             match outfile_stream {
                 Some(ref mut file) => {
-                    let event = event.unwrap();
                     file.write_all(format!("{:?}\n", event).as_bytes())
                         .expect("Failed to write to output file");
                 }
                 None => {
-                    info!("{:?}", event.unwrap());
+                    info!("{:?}", event);
                 }
             }
         }
@@ -16,35 +16,44 @@
 //!     * Syscall number
 //!     * Syscall arguments
 //!     * Syscall return value
-
-mod events;
+//!
+//! Events are written to the UNIX socket in `socket_path`. If the consumer goes away, the
+//! plugin stops logging by default and QEMU keeps running; `on_error=retry`, `retry:N` or
+//! `drop` reconnect to it or drop the events it misses instead (see
+//! `cannonball_client::sender`).
+//!
+//! With `flight_recorder=N`, events are not sent anywhere while the program runs. Only the
+//! last `N` are kept in memory, and they are written to the file `dump_file=<path>` when the
+//! program is killed by a signal, or every time it executes the instruction at
+//! `dump_pc=0x...`, like the first instruction of `abort` (see
+//! `cannonball_client::recorder`). `socket_path` is not needed then.
 
 use cannonball::{
-    api::{
-        qemu_info_t, qemu_plugin_insn_data, qemu_plugin_insn_size, qemu_plugin_insn_vaddr,
-        qemu_plugin_mem_is_big_endian, qemu_plugin_mem_is_sign_extended, qemu_plugin_mem_is_store,
-        qemu_plugin_mem_size_shift, qemu_plugin_meminfo_t, qemu_plugin_tb, qemu_plugin_tb_get_insn,
-        qemu_plugin_tb_n_insns,
-    },
+    api::{qemu_info_t, qemu_plugin_meminfo_t, qemu_plugin_tb},
     args::{Args, QEMUArg},
     callbacks::{
-        RegisterInsnExec, SetupCallback, SetupCallbackType, StaticCallbackType,
-        VCPUInsnExecCallback, VCPUMemCallback, VCPUSyscallCallback, VCPUSyscallRetCallback,
-        VCPUTBTransCallback,
+        AtExitCallback, FallibleSetupCallback, RegisterInsnExec, SetupCallbackType, SetupError,
+        StaticCallbackType, VCPUInsnExecCallback, VCPUMemCallback, VCPUSyscallCallback,
+        VCPUSyscallRetCallback, VCPUTBTransCallback,
     },
+    exit::{status, ExitStatus},
+    instrument::{Instruction, InstrumentAction, TBInstrumenter},
+    mem::MemInfo,
+    plugin::Identity,
+    tb::TBData,
 };
 use inventory::submit;
 use lazy_static::lazy_static;
 use libc::c_void;
 use once_cell::sync::Lazy;
 
-use events::{Event, InsnEvent, MemEvent, SyscallEvent};
-use serde_cbor::to_writer;
-
-use std::{
-    collections::HashMap, ffi::CStr, num::Wrapping, os::unix::net::UnixStream, path::PathBuf,
-    slice::from_raw_parts, sync::Mutex,
+use cannonball_client::{
+    recorder::FlightRecorder,
+    sender::{ClientError, OnError, Sender},
 };
+use cannonball_events::{Event, InsnEvent, MemEvent, SysMemEvent, SyscallEvent};
+
+use std::{collections::HashMap, ffi::CStr, path::PathBuf, sync::Mutex};
 
 #[derive(Debug)]
 struct Context {
@@ -72,17 +81,16 @@ struct Context {
     // stores the syscall arguments and number until the syscall returns, then the return
     // value can be associated and the event can be dispatched and removed from this map
     pub syscalls: HashMap<(u64, u32), SyscallEvent>,
-    // Sequential ephemeral key for indexing temporary instruction store
-    pub ikey: Wrapping<u64>,
-    pub klimit: Wrapping<u64>,
-    // Temporary store for instructions, indexed by ephemeral sequential key `ikey`
-    // stores an instruction from the time it is translated until it is either executed
-    // or a memory access is made, at which point the instruction is dispatched and removed
-    pub insns: HashMap<u64, InsnEvent>,
     /// Path to the socket to send events to
     pub socket_path: Option<PathBuf>,
-    /// The socket to send events to
-    pub sock: Option<UnixStream>,
+    /// The sender writing events to the socket
+    pub sender: Option<Sender>,
+    /// The recorder keeping the last events instead, in flight recorder mode
+    pub recorder: Option<FlightRecorder>,
+    /// The file the recorder is dumped to
+    pub dump_file: Option<PathBuf>,
+    /// The address of the instruction the recorder is dumped at, if any
+    pub dump_pc: Option<u64>,
 }
 
 impl Context {
@@ -101,8 +109,6 @@ impl Context {
     /// * `log_mem` - Whether to log memory accesses
     /// * `log_syscall` - Whether to log system calls
     /// * `syscalls` - The temporary storage for the last syscall executed on each (plugin id, vcpu) pair
-    /// * `ikey` - The sequential ephemeral key for indexing temporary instruction store
-    /// * `insns` - The temporary store for instructions, indexed by ephemeral sequential key `ikey`
     pub fn new() -> Self {
         Self {
             target_name: None,
@@ -116,32 +122,59 @@ impl Context {
             log_mem: false,
             log_syscall: false,
             syscalls: HashMap::new(),
-            ikey: Wrapping(0),
-            klimit: Wrapping(1024),
-            insns: HashMap::new(),
             socket_path: None,
-            sock: None,
+            sender: None,
+            recorder: None,
+            dump_file: None,
+            dump_pc: None,
         }
     }
 
-    /// Return an incrementing sequential key for indexing temporary instruction store and reap
-    /// old entries in case something goes wrong and a callback is not triggered for them
-    pub fn ikey(&mut self) -> u64 {
-        let key = self.ikey;
-        let reap = key - self.klimit;
-        self.insns.remove(&reap.0);
-        self.ikey += Wrapping(1);
-        key.0
+    /// Send an event to the socket, or record it in flight recorder mode. Errors are printed
+    /// rather than panicking in the guest, and once the sender shut down as its error policy
+    /// says, events are no longer logged
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event to log
+    pub fn log_event(&mut self, event: Event) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.record(&event) {
+                eprintln!("mons_meg: {}", e);
+            }
+            return;
+        }
+
+        let Some(sender) = self.sender.as_mut() else {
+            return;
+        };
+
+        match sender.send(&event) {
+            Ok(()) | Err(ClientError::Shutdown) => {}
+            Err(e) => eprintln!("mons_meg: {}", e),
+        }
     }
 
-    pub fn log_event(&self, event: Event) {
-        to_writer(
-            self.sock
-                .as_ref()
-                .expect("log_event: Could not get socket!"),
-            &event,
-        )
-        .unwrap();
+    /// Write the events kept by the flight recorder to the dump file, if recording
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - Why the events are dumped, for the log
+    pub fn dump(&self, reason: &str) {
+        let (Some(recorder), Some(dump_file)) = (self.recorder.as_ref(), self.dump_file.as_ref())
+        else {
+            return;
+        };
+
+        match recorder.dump(dump_file) {
+            Ok(events) => eprintln!(
+                "mons_meg: {}, dumped the last {} events to {}",
+                reason,
+                events,
+                dump_file.display()
+            ),
+            Err(e) => eprintln!("mons_meg: {}", e),
+        }
     }
 }
 
@@ -150,44 +183,12 @@ lazy_static! {
     static ref CONTEXT: Mutex<Context> = Mutex::new(Context::new());
 }
 
-#[derive(Clone)]
-// `*mut c_void` is not `Send + Sync` so we need to use a newtype to wrap it. The `From` and
-// `Into` implementations are for convenience, we could just as easily `as` it around in
-// the code.
-struct ExecKey(*mut c_void);
-
-unsafe impl Send for ExecKey {}
-unsafe impl Sync for ExecKey {}
-
-impl ExecKey {
-    fn new(v: u64) -> Self {
-        Self(v as *mut c_void)
-    }
-}
-
-impl Into<*mut c_void> for ExecKey {
-    fn into(self) -> *mut c_void {
-        self.0
-    }
-}
-
-impl From<*mut c_void> for ExecKey {
-    fn from(v: *mut c_void) -> Self {
-        Self(v)
-    }
-}
-
-impl Into<u64> for ExecKey {
-    fn into(self) -> u64 {
-        self.0 as u64
-    }
-}
-
 /// Called on plugin load with the arguments passed to the plugin on the command
 /// line. We use this function to initialize our global context with the information
 /// QEMU provides us about the target, including the name, whether we are running in
-/// system mode, and the number of VCPUs.
-extern "C" fn setup(info: *const qemu_info_t, args: &Args) {
+/// system mode, and the number of VCPUs. Setup fails if the socket cannot be connected to, and
+/// QEMU does not start.
+fn setup(info: *const qemu_info_t, args: &Args) -> Result<(), SetupError> {
     let mut jv = CONTEXT.lock().expect("setup: Could not lock context!");
     unsafe {
         let info = &*info;
@@ -227,140 +228,172 @@ extern "C" fn setup(info: *const qemu_info_t, args: &Args) {
         jv.log_syscall = *log_syscall;
     }
 
-    if let Some(QEMUArg::Str(socket_path)) = args.args.get("socket_path") {
-        jv.socket_path = Some(PathBuf::from(socket_path));
-        jv.sock = Some(
-            UnixStream::connect(jv.socket_path.as_ref().expect("No socket path!"))
-                .expect("Could not connect to socket!"),
-        );
+    if let Some(QEMUArg::Int(capacity)) = args.args.get("flight_recorder") {
+        let Some(QEMUArg::Str(dump_file)) = args.args.get("dump_file") else {
+            return Err("flight_recorder requires dump_file".into());
+        };
+
+        jv.dump_pc = match args.args.get("dump_pc") {
+            Some(QEMUArg::Int(pc)) => Some(*pc as u64),
+            // Addresses are usually given in hex, which is not parsed as an integer
+            Some(QEMUArg::Str(pc)) => Some(
+                pc.strip_prefix("0x")
+                    .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| format!("Invalid dump_pc {}", pc))?,
+            ),
+            _ => None,
+        };
+        jv.recorder = Some(FlightRecorder::new((*capacity).max(1) as usize));
+        jv.dump_file = Some(PathBuf::from(dump_file));
+
+        return Ok(());
     }
+
+    let on_error = match args.args.get("on_error") {
+        Some(QEMUArg::Str(on_error)) => on_error.parse()?,
+        _ => OnError::default(),
+    };
+
+    let Some(QEMUArg::Str(socket_path)) = args.args.get("socket_path") else {
+        return Err("socket_path is required".into());
+    };
+
+    jv.sender = Some(Sender::connect(socket_path)?.on_error(on_error));
+    jv.socket_path = Some(PathBuf::from(socket_path));
+
+    Ok(())
 }
 
 submit! {
-    // Register the `SetupCallback` function to run during plugin setup
-    static scb: Lazy<SetupCallback> = Lazy::new(|| {
-        SetupCallback::new(|info, args| {
-            setup(info, args);
-        })
+    // Register the `FallibleSetupCallback` function to run during plugin setup
+    static scb: Lazy<FallibleSetupCallback> = Lazy::new(|| {
+        FallibleSetupCallback::new(setup)
     });
-    SetupCallbackType::Setup(&scb)
+    SetupCallbackType::FallibleSetup(&scb)
+}
+
+submit! {
+    // Name the plugin, which namespaces its arguments
+    Identity::new(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
 }
 
 /// Called on execution of each instruction after registration in `on_tb_trans`. This
 /// function just logs the instruction at the time it is executed (instead of at the time
 /// it is translated, which does not necessarily happen in execution order)
-unsafe extern "C" fn on_insn_exec(vcpu_idx: u32, data: *mut c_void) {
+unsafe fn on_insn_exec(vcpu_idx: u32, data: *mut c_void) {
+    // The data is the `InsnEvent` allocated for this instruction in `on_tb_trans`, which the
+    // core owns until the translation cache is flushed, so every execution finds it
+    let mut insn_evt = TBData::get::<InsnEvent>(data).clone();
+    insn_evt.vcpu_idx = Some(vcpu_idx);
+
     let mut jv = CONTEXT
         .lock()
         .expect("on_insn_exec: Could not lock context!");
-    // Since `ExecKey` is a newtype we can just cast it back. If you get really fancy, you can
-    // use a `Box::into_raw(Box::new(T))` pattern to pass around a full object, but it is easier
-    // for the sake of example to store it globally. The callback types do support more
-    // complex use cases though.
-    let ekey: ExecKey = data.into();
-    let key: u64 = ekey.into();
-
-    if let Some(insn_evt) = jv.insns.get(&key) {
-        let mut insn_evt = insn_evt.clone();
-        insn_evt.vcpu_idx = Some(vcpu_idx);
-        let event = Event::Insn(insn_evt);
-        jv.log_event(event);
-        jv.insns.remove(&key);
-    }
+    jv.log_event(Event::Insn(insn_evt));
 }
 
 /// Called on memory access by an instruction, but not necessarily before or after the instruction
-/// executes. Therefore, we use a second duplicate entry of the original isntruction to back-
-/// correlate memory accesses with executions, but we don't know which comes first.
-unsafe extern "C" fn on_mem_access(
+/// executes. The instruction is shared with the execution callback through the same
+/// `InsnEvent`, so memory accesses are correlated with their instruction directly.
+unsafe fn on_mem_access(
     vcpu_index: u32,
     info: qemu_plugin_meminfo_t,
     vaddr: u64,
     data: *mut c_void,
 ) {
+    let mut insn_evt = TBData::get::<InsnEvent>(data).clone();
+    insn_evt.vcpu_idx = Some(vcpu_index);
+
+    let info = MemInfo::new(info);
+
+    #[cfg(feature = "plugin-api-v3")]
+    let value = Some(info.value().into());
+    #[cfg(not(feature = "plugin-api-v3"))]
+    let value = None;
+
+    let mem_evt = MemEvent::new(
+        vaddr,
+        info.is_sign_extended(),
+        info.is_big_endian(),
+        info.is_store(),
+        info.size_shift(),
+        value,
+        insn_evt,
+    );
+
+    // In system emulation, also record where the access landed physically
+    let event = match info.hwaddr(vaddr) {
+        Some(hwaddr) => Event::SysMem(SysMemEvent::new(
+            hwaddr.phys_addr(),
+            hwaddr.is_io(),
+            mem_evt,
+        )),
+        None => Event::Mem(mem_evt),
+    };
+
     let mut jv = CONTEXT
         .lock()
         .expect("on_mem_access: Could not lock context!");
-    let ekey: ExecKey = data.into();
-    let key: u64 = ekey.into();
-
-    if let Some(insn_evt) = jv.insns.get(&key) {
-        let mut insn_evt = insn_evt.clone();
-        insn_evt.vcpu_idx = Some(vcpu_index);
-
-        let is_sext = qemu_plugin_mem_is_sign_extended(info);
-        let is_be = qemu_plugin_mem_is_big_endian(info);
-        let is_store = qemu_plugin_mem_is_store(info);
-        let size_shift = qemu_plugin_mem_size_shift(info);
-
-        let mem_evt = MemEvent::new(
-            vaddr,
-            is_sext,
-            is_be,
-            is_store,
-            size_shift,
-            insn_evt.clone(),
-        );
-
-        let event = Event::Mem(mem_evt);
-        jv.log_event(event);
-
-        jv.insns.remove(&key);
-    }
+    jv.log_event(event);
 }
 
 /// Called on translation of a new translation block. We use this function to register additional
 /// callbacks for execution and memory access. We also use this function to populate
 /// information about the instructions, depending on what logging is enabled by the arguments
-unsafe extern "C" fn on_tb_trans(_id: u64, tb: *mut qemu_plugin_tb) {
-    let mut jv = CONTEXT
+unsafe fn on_tb_trans(_id: u64, tb: *mut qemu_plugin_tb) {
+    let jv = CONTEXT
         .lock()
         .expect("on_tb_trans: Could not lock context!");
 
-    let n_isns = qemu_plugin_tb_n_insns(tb);
-    let first_insn = if jv.log_pc || jv.log_mem {
-        0
-    } else if jv.log_branch {
-        n_isns - 1
-    } else {
-        // TODO: We can probably eliminate this overhead but for example's sake
-        // this is probably fine. Skip the whole TB if we aren't logging anything
-        n_isns
-    };
-
-    for insn_idx in first_insn..n_isns {
-        let branch = insn_idx == n_isns - 1;
-        let insn = qemu_plugin_tb_get_insn(tb, insn_idx);
-        let vaddr = qemu_plugin_insn_vaddr(insn);
+    // Every instruction is instrumented when logging all instructions or memory accesses,
+    // otherwise only the last instruction of the TB is, or none at all, and the instruction
+    // the flight recorder is dumped at
+    let log_all = jv.log_pc || jv.log_mem;
+    let log_branch = jv.log_branch;
+    let dump_pc = jv.dump_pc;
+    let logged = |insn: &Instruction| log_all || (log_branch && insn.is_last());
+    let instrumenter = TBInstrumenter::new(|insn| {
+        if logged(insn) || Some(insn.vaddr()) == dump_pc {
+            InstrumentAction::Callback
+        } else {
+            InstrumentAction::Skip
+        }
+    });
 
-        let mut evt = InsnEvent::new(None, vaddr, None, branch);
+    instrumenter.instrument(tb, |insn| {
+        let mut evt = InsnEvent::new(None, insn.vaddr(), None, insn.is_last());
 
         if jv.log_opcode {
-            let opcode_len = qemu_plugin_insn_size(insn);
-            let raw_opcode = qemu_plugin_insn_data(insn);
-            // reinterpret the raw opcode as a slice of bytes
-            let opcode: Vec<u8> = from_raw_parts(raw_opcode as *const u8, opcode_len as usize)
-                .iter()
-                .map(|x| *x)
-                .collect();
-
-            evt.opcode = Some(opcode);
+            evt.opcode = Some(insn.data().to_vec());
         }
 
-        let exec_key = *&jv.ikey();
-        jv.insns.insert(exec_key, evt.clone());
+        // The event is shared by the exec and mem callbacks of this instruction until the
+        // translation is flushed
+        let data = TBData::new(evt);
 
-        let exec_cb = VCPUInsnExecCallback::new(on_insn_exec, ExecKey::new(exec_key));
-        exec_cb.register(insn);
+        if logged(insn) {
+            let exec_cb = VCPUInsnExecCallback::new(on_insn_exec, data.clone());
+            exec_cb.register(insn.raw());
 
-        if jv.log_mem {
-            let mem_key = *&jv.ikey();
-            jv.insns.insert(mem_key, evt.clone());
+            if jv.log_mem {
+                let mem_cb = VCPUMemCallback::new(on_mem_access, data.clone());
+                mem_cb.register(insn.raw());
+            }
+        }
 
-            let mem_cb = VCPUMemCallback::new(on_mem_access, ExecKey::new(mem_key));
-            mem_cb.register(insn);
+        // Registered after logging the instruction, so the dump ends with it
+        if Some(insn.vaddr()) == dump_pc {
+            let dump_cb = VCPUInsnExecCallback::new(on_dump_pc, data);
+            dump_cb.register(insn.raw());
         }
-    }
+    });
+}
+
+/// Called on execution of the instruction at `dump_pc`, to dump the flight recorder
+unsafe fn on_dump_pc(_vcpu_idx: u32, data: *mut c_void) {
+    let pc = TBData::get::<InsnEvent>(data).vaddr;
+    let jv = CONTEXT.lock().expect("on_dump_pc: Could not lock context!");
+    jv.dump(&format!("reached {:#x}", pc));
 }
 
 submit! {
@@ -375,7 +408,8 @@ submit! {
 /// Called on each system call entry. We use this function to populate the arguments and
 /// number of the syscall, and then we store it until we get an event returning from the system
 /// call so we can populate the return value.
-unsafe extern "C" fn on_syscall(
+#[allow(clippy::too_many_arguments)]
+unsafe fn on_syscall(
     id: u64,
     vcpu_idx: u32,
     num: i64,
@@ -408,7 +442,7 @@ submit! {
 
 /// Called on each system call exit. We use this function to populate the return value of the
 /// system call, and then we print the syscall event.
-unsafe extern "C" fn on_syscall_ret(id: u64, vcpu_idx: u32, _num: i64, rv: i64) {
+unsafe fn on_syscall_ret(id: u64, vcpu_idx: u32, _num: i64, rv: i64) {
     let mut jv = CONTEXT
         .lock()
         .expect("on_syscall_ret: Could not lock context!");
@@ -430,3 +464,22 @@ submit! {
     });
     StaticCallbackType::VCPUSyscallRet(&sysretcb)
 }
+
+/// Called when QEMU exits. In flight recorder mode, the last events are dumped if the program
+/// was killed by a signal, which is when they tell why it crashed
+fn on_exit(_id: u64) {
+    let jv = CONTEXT.lock().expect("on_exit: Could not lock context!");
+
+    match status() {
+        Some(ExitStatus::Signaled(Some(signal))) => {
+            jv.dump(&format!("killed by signal {}", signal))
+        }
+        Some(ExitStatus::Signaled(None)) => jv.dump("killed by a signal"),
+        Some(ExitStatus::Exited(_)) | None => {}
+    }
+}
+
+submit! {
+    static exitcb: Lazy<AtExitCallback> = Lazy::new(|| AtExitCallback::new(on_exit));
+    StaticCallbackType::AtExit(&exitcb)
+}
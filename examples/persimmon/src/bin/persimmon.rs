@@ -0,0 +1,96 @@
+//! Persimmon driver binary
+//!
+//! Runs a program under QEMU with the Persimmon plugin, which prints a JSON event each time
+//! data read from the program's inputs reaches a branch or a system call.
+
+use cannonball_plugin_dist::find;
+use clap::Parser;
+use memfd_exec::{MemFdExecutable, Stdio};
+use qemu::qemu_x86_64;
+
+use std::{
+    fs::{read, write},
+    io::{Read, Write},
+    path::PathBuf,
+    thread::spawn,
+};
+
+#[derive(Parser, Debug)]
+/// Track the data a program reads from its inputs with the Persimmon QEMU plugin
+struct Args {
+    /// A file descriptor whose input is tainted. May be given multiple times. Standard input if not set.
+    #[clap(short = 'f', long)]
+    pub taint_fd: Vec<u64>,
+    /// An input file to feed to the program. If not set, the program will take input via this driver's stdin.
+    #[clap(short = 'I', long)]
+    pub input_file: Option<PathBuf>,
+    /// An output file to write the program's output to. If not set, the program's output will be written to this driver's stdout.
+    #[clap(short = 'O', long)]
+    pub output_file: Option<PathBuf>,
+    /// The program to run
+    #[clap()]
+    pub program: PathBuf,
+    /// The arguments to the program
+    #[clap(num_args = 1.., last = true)]
+    pub args: Vec<String>,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let plugin_args = args
+        .taint_fd
+        .iter()
+        .map(|fd| format!(",taint_fd={}", fd))
+        .collect::<String>();
+
+    let qemu = qemu_x86_64();
+
+    // Cargo builds the plugin next to this binary
+    let plugin_path = find("persimmon").expect("Failed to find the plugin");
+
+    let mut exe = MemFdExecutable::new("qemu-x86_64", qemu)
+        .arg("-plugin")
+        .arg(format!(
+            "{}{}",
+            plugin_path.canonicalize().unwrap().to_string_lossy(),
+            plugin_args
+        ))
+        .arg("--")
+        .arg(args.program.to_string_lossy().to_string())
+        .args(args.args)
+        .stdin(if args.input_file.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::Inherit
+        })
+        .stdout(if args.output_file.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::Inherit
+        })
+        .stderr(Stdio::inherit())
+        .spawn()
+        .expect("Failed to spawn QEMU");
+
+    if let Some(input_file) = args.input_file {
+        let mut stdin = exe.stdin.take().expect("Failed to get stdin");
+        let input = read(input_file).expect("Failed to read input file");
+        spawn(move || {
+            stdin.write_all(&input).expect("Failed to write input");
+        });
+    }
+
+    if let Some(output_file) = args.output_file {
+        let mut stdout = exe.stdout.take().expect("Failed to get stdout");
+        let mut output = Vec::new();
+        spawn(move || {
+            stdout
+                .read_to_end(&mut output)
+                .expect("Failed to read output");
+            write(output_file, output).expect("Failed to write output");
+        });
+    }
+
+    exe.wait().expect("Failed to wait for QEMU");
+}
@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{semantics::BranchKind, taint::Span};
+
+/// A system call that read input, whose bytes are tainted
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SourceEvent {
+    pub source: u32,
+    pub vcpu_idx: u32,
+    pub num: i64,
+    pub fd: u64,
+    pub addr: u64,
+    pub len: u64,
+}
+
+impl SourceEvent {
+    /// Instantiate a new `SourceEvent`
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The number identifying the source in the labels of tainted bytes
+    /// * `vcpu_idx` - The VCPU that made the system call
+    /// * `num` - The system call number
+    /// * `fd` - The file descriptor read from
+    /// * `addr` - The buffer the input was read into
+    /// * `len` - The number of bytes read
+    pub fn new(source: u32, vcpu_idx: u32, num: i64, fd: u64, addr: u64, len: u64) -> Self {
+        Self {
+            source,
+            vcpu_idx,
+            num,
+            fd,
+            addr,
+            len,
+        }
+    }
+}
+
+/// The value of a register holding tainted data
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RegisterValue {
+    pub name: String,
+    pub taint: Vec<Span>,
+    pub value: Option<Vec<u8>>,
+}
+
+/// The value of tainted memory that was loaded
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MemoryValue {
+    pub addr: u64,
+    pub size: usize,
+    pub taint: Vec<Span>,
+    pub value: Option<u128>,
+}
+
+/// The instruction the flags a conditional branch depends on were computed by
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FlagsOrigin {
+    pub pc: u64,
+    pub insn: String,
+    pub registers: Vec<RegisterValue>,
+    pub memory: Vec<MemoryValue>,
+}
+
+/// A branch whose direction or target depends on tainted data
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BranchEvent {
+    pub vcpu_idx: u32,
+    pub pc: u64,
+    pub insn: String,
+    pub kind: BranchKind,
+    pub taint: Vec<Span>,
+    pub flags: Option<FlagsOrigin>,
+    pub registers: Vec<RegisterValue>,
+    pub memory: Vec<MemoryValue>,
+}
+
+impl BranchEvent {
+    /// Instantiate a new `BranchEvent`
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_idx` - The VCPU that executed the branch
+    /// * `pc` - The address of the branch
+    /// * `insn` - The disassembled branch
+    /// * `kind` - The kind of branch
+    /// * `taint` - The input bytes its direction or target depends on
+    /// * `flags` - Where the flags it depends on came from, for conditional branches
+    /// * `registers` - The tainted registers its target is read from
+    /// * `memory` - The tainted memory its target is read from
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        vcpu_idx: u32,
+        pc: u64,
+        insn: String,
+        kind: BranchKind,
+        taint: Vec<Span>,
+        flags: Option<FlagsOrigin>,
+        registers: Vec<RegisterValue>,
+        memory: Vec<MemoryValue>,
+    ) -> Self {
+        Self {
+            vcpu_idx,
+            pc,
+            insn,
+            kind,
+            taint,
+            flags,
+            registers,
+            memory,
+        }
+    }
+}
+
+/// An argument of a system call holding tainted data
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TaintedArg {
+    pub index: usize,
+    pub value: u64,
+    pub taint: Vec<Span>,
+}
+
+/// A buffer passed to a system call holding tainted data
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TaintedBuffer {
+    pub addr: u64,
+    pub len: u64,
+    pub taint: Vec<Span>,
+}
+
+/// A system call made with tainted arguments, or writing tainted data
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SyscallEvent {
+    pub vcpu_idx: u32,
+    pub num: i64,
+    pub args: Vec<TaintedArg>,
+    pub buffer: Option<TaintedBuffer>,
+}
+
+impl SyscallEvent {
+    /// Instantiate a new `SyscallEvent`
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_idx` - The VCPU that made the system call
+    /// * `num` - The system call number
+    /// * `args` - The tainted arguments
+    /// * `buffer` - The tainted buffer it writes out, if any
+    pub fn new(
+        vcpu_idx: u32,
+        num: i64,
+        args: Vec<TaintedArg>,
+        buffer: Option<TaintedBuffer>,
+    ) -> Self {
+        Self {
+            vcpu_idx,
+            num,
+            args,
+            buffer,
+        }
+    }
+}
+
+/// The events Persimmon logs, one JSON object per line
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum TaintEvent {
+    Source(SourceEvent),
+    Branch(BranchEvent),
+    Syscall(SyscallEvent),
+}
@@ -0,0 +1,562 @@
+//! Persimmon taint tracking plugin
+//!
+//! Persimmon follows the bytes a program reads from its inputs through registers and memory,
+//! and reports when they reach a branch or a system call. It runs x86_64 programs in QEMU user
+//! mode.
+//!
+//! Input bytes are tainted when they are read by `read`, `pread64` or `recvfrom` from one of
+//! the file descriptors given with `taint_fd=N` (which may be given several times, and is
+//! standard input if none are). Each of these system calls is a source, logged with a
+//! `SourceEvent`, and each byte it read is labeled with the source and its offset in what was
+//! read. Taint then moves along with the data it labels, byte by byte through moves and
+//! coarsely through everything else (see `semantics`).
+//!
+//! Persimmon logs the following events:
+//!
+//! * Sources, the system calls that read tainted input:
+//!     * The system call number and the file descriptor read
+//!     * The buffer the input was read into, and its size
+//! * Branches depending on tainted data:
+//!     * Conditional branches, with the instruction that computed the flags they depend on
+//!     * Indirect jumps and calls, with the register or memory their target is read from
+//!     * Returns to an address read from tainted memory
+//! * System calls with tainted arguments, or writing tainted data with `write`, `pwrite64` or
+//!   `sendto`
+//!
+//! Taint is reported as runs of input bytes, as a source and a range of offsets in what it
+//! read. Built with the `plugin-api-v2` feature, branches report the values of the tainted
+//! registers they depend on as well, and with `plugin-api-v3` the values of the tainted memory.
+//!
+//! Every instruction is instrumented, and every memory access is checked, so Persimmon is
+//! slow, and makes a good stress test for `cannonball`'s callbacks.
+
+mod events;
+mod semantics;
+mod taint;
+
+use cannonball::{
+    api::{qemu_info_t, qemu_plugin_meminfo_t, qemu_plugin_tb},
+    args::Args,
+    callbacks::{
+        RegisterInsnExec, SetupCallback, SetupCallbackType, StaticCallbackType,
+        VCPUInsnExecCallback, VCPUMemCallback, VCPUSyscallCallback, VCPUSyscallRetCallback,
+        VCPUTBTransCallback,
+    },
+    instrument::instructions,
+    mem::MemInfo,
+    plugin::Identity,
+    tb::TBData,
+};
+use inventory::submit;
+use lazy_static::lazy_static;
+use libc::c_void;
+use once_cell::sync::Lazy;
+use serde_json::to_writer;
+use yaxpeax_x86::long_mode::RegSpec;
+
+#[cfg(feature = "plugin-api-v2")]
+use cannonball::registers::registers;
+
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    io::{stdout, Write},
+    sync::{Arc, Mutex},
+};
+
+use events::{
+    BranchEvent, FlagsOrigin, MemoryValue, RegisterValue, SourceEvent, SyscallEvent, TaintEvent,
+    TaintedArg, TaintedBuffer,
+};
+use semantics::{decode, Access, Insn, Operand};
+use taint::{full_name, union, Memory, Registers, Taint};
+
+/// The system calls that read input into the buffer in their second argument, on x86_64
+const READS: [i64; 3] = [
+    0,  // read
+    17, // pread64
+    45, // recvfrom
+];
+
+/// The system calls that write out the buffer in their second argument, of the size in their
+/// third, on x86_64
+const WRITES: [i64; 3] = [
+    1,  // write
+    18, // pwrite64
+    44, // sendto
+];
+
+/// The registers system call arguments are passed in, on x86_64
+const SYSCALL_ARGS: [fn() -> RegSpec; 6] = [
+    RegSpec::rdi,
+    RegSpec::rsi,
+    RegSpec::rdx,
+    RegSpec::r10,
+    RegSpec::r8,
+    RegSpec::r9,
+];
+
+/// The most bytes of a buffer written out by a system call that are checked for taint
+const MAX_BUFFER: u64 = 1 << 20;
+
+/// The instruction the flags were last computed by, while they are tainted
+#[derive(Debug, Clone)]
+struct Origin {
+    /// The instruction
+    insn: Arc<Insn>,
+    /// The tainted registers it read
+    registers: Vec<(RegSpec, Taint)>,
+    /// The tainted memory it loaded
+    memory: Vec<MemoryValue>,
+}
+
+/// The taint tracking state of a VCPU
+#[derive(Debug, Default)]
+struct Vcpu {
+    /// The taint of its registers
+    registers: Registers,
+    /// The instruction it executed last, and the memory accesses it made, which are only
+    /// known once it executed. Its effect is applied when the next instruction executes
+    last: Option<(Arc<Insn>, Vec<Access>)>,
+    /// Where the flags came from, if they are tainted
+    flags: Option<Origin>,
+    /// The system call being made, as its number and arguments, until it returns
+    syscall: Option<(i64, [u64; 6])>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The file descriptors input is tainted from
+    fds: Vec<u64>,
+    /// The number of sources so far
+    sources: u32,
+    /// The taint of memory
+    memory: Memory,
+    /// The taint tracking state of each VCPU
+    vcpus: HashMap<u32, Vcpu>,
+}
+
+lazy_static! {
+    /// The global state of the taint tracking plugin
+    static ref STATE: Mutex<State> = Mutex::new(State::default());
+}
+
+/// Log an event to stdout as a line of JSON
+///
+/// # Arguments
+///
+/// * `event` - The event
+fn log_event(event: TaintEvent) {
+    let mut out = stdout().lock();
+    to_writer(&mut out, &event).expect("Could not serialize event!");
+    out.write_all(b"\n").expect("Could not write event!");
+}
+
+/// The value of a full register of the current VCPU. Registers can only be read with plugin
+/// API version 2 and later, from callbacks registered to read them
+///
+/// # Arguments
+///
+/// * `name` - The name of the register
+#[cfg(feature = "plugin-api-v2")]
+fn read_register(name: &str) -> Option<Vec<u8>> {
+    registers()
+        .into_iter()
+        .find(|register| register.name() == name)?
+        .read()
+}
+
+#[cfg(not(feature = "plugin-api-v2"))]
+fn read_register(_name: &str) -> Option<Vec<u8>> {
+    None
+}
+
+/// The values of tainted registers, named by the full registers holding them. Values are only
+/// read if `read` is set, from a callback registered to read registers
+///
+/// # Arguments
+///
+/// * `registers` - The registers and their taint
+/// * `read` - Whether to read their values
+fn register_values(registers: &[(RegSpec, Taint)], read: bool) -> Vec<RegisterValue> {
+    registers
+        .iter()
+        .filter_map(|(reg, taint)| {
+            let name = full_name(*reg)?;
+
+            Some(RegisterValue {
+                value: if read { read_register(&name) } else { None },
+                name,
+                taint: taint.spans(),
+            })
+        })
+        .collect()
+}
+
+/// The tainted registers among some operands, with their taint
+///
+/// # Arguments
+///
+/// * `operands` - The operands
+/// * `registers` - The taint of the registers
+fn tainted_registers(
+    operands: impl IntoIterator<Item = RegSpec>,
+    registers: &Registers,
+) -> Vec<(RegSpec, Taint)> {
+    operands
+        .into_iter()
+        .map(|reg| (reg, union(registers.read(reg).iter())))
+        .filter(|(_, taint)| !taint.is_clean())
+        .collect()
+}
+
+/// The tainted memory among the loads an instruction made, with their taint and values
+///
+/// # Arguments
+///
+/// * `accesses` - The memory accesses the instruction made
+/// * `memory` - The taint of memory
+fn tainted_loads(accesses: &[Access], memory: &Memory) -> Vec<MemoryValue> {
+    accesses
+        .iter()
+        .filter(|access| !access.is_store)
+        .filter_map(|access| {
+            let taint = union(memory.read(access.addr, access.size).iter());
+
+            (!taint.is_clean()).then(|| MemoryValue {
+                addr: access.addr,
+                size: access.size,
+                taint: taint.spans(),
+                value: access.value,
+            })
+        })
+        .collect()
+}
+
+/// The register operands of an instruction
+///
+/// # Arguments
+///
+/// * `operands` - The operands
+fn register_operands(operands: &[Operand]) -> impl Iterator<Item = RegSpec> + '_ {
+    operands.iter().filter_map(|operand| match operand {
+        Operand::Register(reg) => Some(*reg),
+        _ => None,
+    })
+}
+
+impl State {
+    /// Report a branch depending on tainted data
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_idx` - The VCPU executing the branch
+    /// * `insn` - The branch
+    /// * `accesses` - The memory accesses it made, if it executed already
+    /// * `read` - Whether register values can be read
+    fn check_branch(&self, vcpu_idx: u32, insn: &Insn, accesses: &[Access], read: bool) {
+        let (Some(sink), Some(vcpu)) = (&insn.sink, self.vcpus.get(&vcpu_idx)) else {
+            return;
+        };
+
+        let taint = insn.sink_taint(&vcpu.registers, &self.memory, accesses);
+
+        if taint.is_clean() {
+            return;
+        }
+
+        let flags = vcpu
+            .flags
+            .as_ref()
+            .filter(|_| sink.operands.contains(&Operand::Flags))
+            .map(|origin| FlagsOrigin {
+                pc: origin.insn.pc,
+                insn: origin.insn.text.clone(),
+                registers: register_values(&origin.registers, read),
+                memory: origin.memory.clone(),
+            });
+        let registers = tainted_registers(
+            register_operands(&sink.operands).chain(sink.address.iter().copied()),
+            &vcpu.registers,
+        );
+
+        log_event(TaintEvent::Branch(BranchEvent::new(
+            vcpu_idx,
+            insn.pc,
+            insn.text.clone(),
+            sink.kind,
+            taint.spans(),
+            flags,
+            register_values(&registers, read),
+            tainted_loads(accesses, &self.memory),
+        )));
+    }
+
+    /// Propagate taint through the last instruction a VCPU executed, now that its memory
+    /// accesses are known
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_idx` - The VCPU
+    fn finish(&mut self, vcpu_idx: u32) {
+        let Some((insn, accesses)) = self
+            .vcpus
+            .get_mut(&vcpu_idx)
+            .and_then(|vcpu| vcpu.last.take())
+        else {
+            return;
+        };
+
+        // Branches reading their target from memory are checked once the load is known
+        if insn.sink.as_ref().is_some_and(|sink| sink.reads_memory()) {
+            self.check_branch(vcpu_idx, &insn, &accesses, false);
+        }
+
+        let vcpu = self.vcpus.entry(vcpu_idx).or_default();
+        let sources = insn.sources();
+        let registers = tainted_registers(register_operands(&sources), &vcpu.registers);
+        let memory = tainted_loads(&accesses, &self.memory);
+
+        insn.apply(&mut vcpu.registers, &mut self.memory, &accesses);
+
+        if insn.writes_flags() {
+            vcpu.flags = (!vcpu.registers.flags.is_clean()).then(|| Origin {
+                insn: insn.clone(),
+                registers,
+                memory,
+            });
+        }
+    }
+}
+
+/// Called on plugin load with the arguments passed to the plugin on the command line, to
+/// choose the file descriptors input is tainted from
+fn setup(info: *const qemu_info_t, args: &Args) {
+    let target_name = unsafe { CStr::from_ptr((*info).target_name) }.to_string_lossy();
+
+    if target_name != "x86_64" {
+        panic!(
+            "Persimmon only supports x86_64 programs, not {}!",
+            target_name
+        );
+    }
+
+    let mut state = STATE.lock().expect("setup: Could not lock state!");
+
+    // `taint_fd` may be given several times, so it is read from the raw arguments
+    state.fds = args
+        .raw
+        .iter()
+        .filter_map(|arg| arg.strip_prefix("taint_fd="))
+        .map(|fd| fd.parse().expect("Invalid taint_fd!"))
+        .collect();
+
+    if state.fds.is_empty() {
+        state.fds.push(0);
+    }
+}
+
+submit! {
+    static scb: Lazy<SetupCallback> = Lazy::new(|| {
+        SetupCallback::new(|info, args| {
+            setup(info, args);
+        })
+    });
+    SetupCallbackType::Setup(&scb)
+}
+
+submit! {
+    // Name the plugin, which namespaces its arguments
+    Identity::new(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+/// Called before each instruction executes. The effect of the previous instruction on the same
+/// VCPU is applied first, then branches whose target is in a register are checked while their
+/// registers can be read
+unsafe fn on_insn_exec(vcpu_idx: u32, data: *mut c_void) {
+    let insn = (*(data as *const Arc<Insn>)).clone();
+    let mut state = STATE.lock().expect("on_insn_exec: Could not lock state!");
+
+    state.finish(vcpu_idx);
+
+    if insn.sink.as_ref().is_some_and(|sink| !sink.reads_memory()) {
+        state.check_branch(vcpu_idx, &insn, &[], true);
+    }
+
+    state.vcpus.entry(vcpu_idx).or_default().last = Some((insn, Vec::new()));
+}
+
+/// Called on each memory access, after the instruction making it started executing. The
+/// access is kept until the instruction's effect is applied
+unsafe fn on_mem_access(
+    vcpu_idx: u32,
+    info: qemu_plugin_meminfo_t,
+    vaddr: u64,
+    _data: *mut c_void,
+) {
+    let info = MemInfo::new(info);
+
+    #[cfg(feature = "plugin-api-v3")]
+    let value = Some(info.value().into());
+    #[cfg(not(feature = "plugin-api-v3"))]
+    let value = None;
+
+    let access = Access {
+        addr: vaddr,
+        size: info.size(),
+        is_store: info.is_store(),
+        value,
+    };
+
+    let mut state = STATE.lock().expect("on_mem_access: Could not lock state!");
+
+    if let Some((_, accesses)) = state
+        .vcpus
+        .get_mut(&vcpu_idx)
+        .and_then(|vcpu| vcpu.last.as_mut())
+    {
+        accesses.push(access);
+    }
+}
+
+/// Called on translation of each translation block. Every instruction is decoded, and its
+/// decoded form is handed to the callbacks registered on it
+unsafe fn on_tb_trans(_id: u64, tb: *mut qemu_plugin_tb) {
+    for insn in instructions(tb) {
+        let decoded = Arc::new(decode(insn.vaddr(), insn.data()));
+        let reads_registers = decoded
+            .sink
+            .as_ref()
+            .is_some_and(|sink| !sink.reads_memory());
+
+        let exec_cb = VCPUInsnExecCallback::new(on_insn_exec, TBData::new(decoded));
+
+        // Branches read the values of their registers when they are tainted
+        if cfg!(feature = "plugin-api-v2") && reads_registers {
+            exec_cb.reading_registers().register(insn.raw());
+        } else {
+            exec_cb.register(insn.raw());
+        }
+
+        VCPUMemCallback::new(on_mem_access, TBData::new(())).register(insn.raw());
+    }
+}
+
+submit! {
+    static tbcb: Lazy<VCPUTBTransCallback> = Lazy::new(|| {
+        VCPUTBTransCallback::new(on_tb_trans)
+    });
+    StaticCallbackType::VCPUTBTrans(&tbcb)
+}
+
+/// Called on each system call entry. System calls with tainted arguments, or writing out
+/// tainted data, are reported
+#[allow(clippy::too_many_arguments)]
+unsafe fn on_syscall(
+    _id: u64,
+    vcpu_idx: u32,
+    num: i64,
+    arg0: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+    _arg6: u64,
+    _arg7: u64,
+) {
+    let args = [arg0, arg1, arg2, arg3, arg4, arg5];
+    let mut state = STATE.lock().expect("on_syscall: Could not lock state!");
+
+    state.finish(vcpu_idx);
+
+    let vcpu = state.vcpus.entry(vcpu_idx).or_default();
+    vcpu.syscall = Some((num, args));
+
+    let tainted = SYSCALL_ARGS
+        .iter()
+        .enumerate()
+        .filter_map(|(index, reg)| {
+            let taint = union(vcpu.registers.read(reg()).iter());
+
+            (!taint.is_clean()).then(|| TaintedArg {
+                index,
+                value: args[index],
+                taint: taint.spans(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let buffer = if WRITES.contains(&num) {
+        let len = arg2.min(MAX_BUFFER);
+        let taint = union(state.memory.read(arg1, len as usize).iter());
+
+        (!taint.is_clean()).then(|| TaintedBuffer {
+            addr: arg1,
+            len,
+            taint: taint.spans(),
+        })
+    } else {
+        None
+    };
+
+    if !tainted.is_empty() || buffer.is_some() {
+        log_event(TaintEvent::Syscall(SyscallEvent::new(
+            vcpu_idx, num, tainted, buffer,
+        )));
+    }
+}
+
+submit! {
+    static syscb: Lazy<VCPUSyscallCallback> = Lazy::new(|| {
+        VCPUSyscallCallback::new(on_syscall)
+    });
+    StaticCallbackType::VCPUSyscall(&syscb)
+}
+
+/// Called on each system call exit. The registers the kernel clobbers are untainted, and the
+/// input read by a source is tainted, while anything else read replaces tainted memory with
+/// untainted data
+unsafe fn on_syscall_ret(_id: u64, vcpu_idx: u32, _num: i64, rv: i64) {
+    let mut state = STATE.lock().expect("on_syscall_ret: Could not lock state!");
+    let state = &mut *state;
+
+    let vcpu = state.vcpus.entry(vcpu_idx).or_default();
+
+    for reg in [RegSpec::rax(), RegSpec::rcx(), RegSpec::r11()] {
+        vcpu.registers.write(reg, &[]);
+    }
+
+    let Some((num, args)) = vcpu.syscall.take() else {
+        return;
+    };
+
+    if !READS.contains(&num) || rv <= 0 {
+        return;
+    }
+
+    let (fd, addr, len) = (args[0], args[1], rv as u64);
+
+    if state.fds.contains(&fd) {
+        let source = state.sources;
+        state.sources += 1;
+
+        let taints = (0..len as u32)
+            .map(|offset| Taint::label(source, offset))
+            .collect::<Vec<_>>();
+        state.memory.write(addr, &taints);
+
+        log_event(TaintEvent::Source(SourceEvent::new(
+            source, vcpu_idx, num, fd, addr, len,
+        )));
+    } else {
+        state.memory.clear(addr, len);
+    }
+}
+
+submit! {
+    static sysretcb: Lazy<VCPUSyscallRetCallback> = Lazy::new(|| {
+        VCPUSyscallRetCallback::new(on_syscall_ret)
+    });
+    StaticCallbackType::VCPUSyscallRet(&sysretcb)
+}
@@ -0,0 +1,516 @@
+//! Taint propagation rules
+//!
+//! Each instruction is decoded once, when it is translated, into an `Insn` describing how it
+//! moves taint between its operands. The rules are coarse on purpose: moves copy the taint of
+//! each byte to the byte they write, and everything else taints every byte it writes with the
+//! taint of every byte it reads (including the flags, for instructions that read them). Taint
+//! only flows through data, not through addresses, so looking up a table with a tainted index
+//! does not taint the value read, except for the target of indirect branches.
+//!
+//! Memory operands are not resolved when decoding. QEMU reports the accesses an instruction
+//! made after it executes, and `Insn::apply` reads the taint of whatever the instruction
+//! loaded and writes the taint of whatever it stored. Instructions this module does not know
+//! are assumed to compute their first operand from all of their operands.
+//!
+//! Only x86_64 is supported.
+
+use serde::{Deserialize, Serialize};
+use yaxpeax_arch::{Decoder, U8Reader};
+use yaxpeax_x86::long_mode::{InstDecoder, Instruction, Opcode, Operand as X86Operand, RegSpec};
+
+use crate::taint::{union, Memory, Registers, Taint};
+
+/// An operand of an instruction, as far as taint is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// A register
+    Register(RegSpec),
+    /// Whatever the instruction loaded from or stored to memory
+    Memory,
+    /// A constant, which is never tainted
+    Immediate,
+    /// The flags
+    Flags,
+}
+
+impl Operand {
+    /// Convert an operand decoded by yaxpeax
+    ///
+    /// # Arguments
+    ///
+    /// * `operand` - The operand
+    fn new(operand: &X86Operand) -> Self {
+        match operand {
+            X86Operand::Register(reg)
+            | X86Operand::RegisterMaskMerge(reg, _, _)
+            | X86Operand::RegisterMaskMergeSae(reg, _, _, _)
+            | X86Operand::RegisterMaskMergeSaeNoround(reg, _, _) => Operand::Register(*reg),
+            operand if operand.is_memory() => Operand::Memory,
+            _ => Operand::Immediate,
+        }
+    }
+}
+
+/// How the bytes of a source smaller than its destination are extended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extend {
+    /// The upper bytes are zero, and untainted
+    Zero,
+    /// The upper bytes are copies of the sign bit, and tainted like the top byte
+    Sign,
+}
+
+/// How an instruction moves taint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Effect {
+    /// The destination is a copy of the source, byte for byte
+    Copy {
+        dst: Operand,
+        src: Operand,
+        extend: Extend,
+    },
+    /// Every byte of every destination is computed from every byte of every source. With no
+    /// sources, the destinations are untainted
+    Combine {
+        dsts: Vec<Operand>,
+        srcs: Vec<Operand>,
+    },
+    /// The operands swap values
+    Exchange(Operand, Operand),
+    /// Nothing is tainted or untainted
+    Nothing,
+}
+
+/// A kind of branch whose target or direction can depend on tainted data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BranchKind {
+    /// A conditional branch, whose direction depends on the flags or a counter
+    Conditional,
+    /// An indirect jump or call, whose target is read from a register or memory
+    Indirect,
+    /// A return, whose target is read from the stack
+    Return,
+}
+
+/// A branch that is reported when the operands it depends on are tainted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sink {
+    /// The kind of branch
+    pub kind: BranchKind,
+    /// The operands its direction or target is read from
+    pub operands: Vec<Operand>,
+    /// The registers the address of its memory operand is computed from, if any
+    pub address: Vec<RegSpec>,
+}
+
+impl Sink {
+    /// Whether the operands of the branch are only known after it executes, once QEMU reported
+    /// what it loaded
+    pub fn reads_memory(&self) -> bool {
+        self.operands.contains(&Operand::Memory)
+    }
+}
+
+/// A memory access made by an instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Access {
+    /// The first address accessed
+    pub addr: u64,
+    /// The number of bytes accessed
+    pub size: usize,
+    /// Whether the access is a store
+    pub is_store: bool,
+    /// The value loaded or stored, if it was captured
+    pub value: Option<u128>,
+}
+
+/// A decoded instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Insn {
+    /// The address of the instruction
+    pub pc: u64,
+    /// The disassembled instruction
+    pub text: String,
+    /// How it moves taint
+    pub effect: Effect,
+    /// The branch it is, if it is one that is reported
+    pub sink: Option<Sink>,
+}
+
+/// The registers the address of a memory operand is computed from, other than `rip`
+///
+/// # Arguments
+///
+/// * `operand` - The operand
+fn address_registers(operand: &X86Operand) -> Vec<RegSpec> {
+    let regs = match *operand {
+        X86Operand::RegDeref(base)
+        | X86Operand::RegDisp(base, _)
+        | X86Operand::RegScale(base, _)
+        | X86Operand::RegScaleDisp(base, _, _)
+        | X86Operand::RegDerefMasked(base, _)
+        | X86Operand::RegDispMasked(base, _, _)
+        | X86Operand::RegScaleMasked(base, _, _)
+        | X86Operand::RegScaleDispMasked(base, _, _, _) => vec![base],
+        X86Operand::RegIndexBase(base, index)
+        | X86Operand::RegIndexBaseDisp(base, index, _)
+        | X86Operand::RegIndexBaseScale(base, index, _)
+        | X86Operand::RegIndexBaseScaleDisp(base, index, _, _)
+        | X86Operand::RegIndexBaseMasked(base, index, _)
+        | X86Operand::RegIndexBaseDispMasked(base, index, _, _)
+        | X86Operand::RegIndexBaseScaleMasked(base, index, _, _)
+        | X86Operand::RegIndexBaseScaleDispMasked(base, index, _, _, _) => vec![base, index],
+        _ => Vec::new(),
+    };
+
+    regs.into_iter()
+        .filter(|reg| *reg != RegSpec::RIP)
+        .collect()
+}
+
+/// Decide how an instruction moves taint and whether it is a branch to report
+///
+/// # Arguments
+///
+/// * `insn` - The instruction
+fn effect(insn: &Instruction) -> (Effect, Option<Sink>) {
+    let operands = (0..insn.operand_count())
+        .map(|i| insn.operand(i))
+        .collect::<Vec<_>>();
+    let ops = operands.iter().map(Operand::new).collect::<Vec<_>>();
+    let first = ops.first().copied().unwrap_or(Operand::Immediate);
+    let second = ops.get(1).copied().unwrap_or(Operand::Immediate);
+    let rax = Operand::Register(RegSpec::rax());
+    let rcx = Operand::Register(RegSpec::rcx());
+    let rdx = Operand::Register(RegSpec::rdx());
+    let copy = |dst, src, extend| Effect::Copy { dst, src, extend };
+    let combine = |dsts: &[Operand], srcs: &[Operand]| Effect::Combine {
+        dsts: dsts.to_vec(),
+        srcs: srcs.to_vec(),
+    };
+    let branch = |kind, operands: Vec<Operand>| Sink {
+        kind,
+        operands,
+        address: Vec::new(),
+    };
+    // Clearing a register by combining it with itself does not depend on its value
+    let clears = ops.len() >= 2 && ops.iter().all(|op| *op == first) && first != Operand::Memory;
+
+    let opcode = insn.opcode();
+
+    let effect = match opcode {
+        Opcode::MOV
+        | Opcode::MOVD
+        | Opcode::MOVQ
+        | Opcode::MOVAPS
+        | Opcode::MOVUPS
+        | Opcode::MOVAPD
+        | Opcode::MOVUPD
+        | Opcode::MOVDQA
+        | Opcode::MOVDQU
+        | Opcode::VMOVDQA
+        | Opcode::VMOVDQU
+        | Opcode::MOVNTDQ
+        | Opcode::LDDQU
+        | Opcode::MOVSS
+        | Opcode::MOVSD
+        | Opcode::MOVZX
+        | Opcode::MOVS
+        | Opcode::STOS
+        | Opcode::LODS => copy(first, second, Extend::Zero),
+        Opcode::MOVSX | Opcode::MOVSXD => copy(first, second, Extend::Sign),
+        Opcode::PUSH => copy(Operand::Memory, first, Extend::Zero),
+        Opcode::POP => copy(first, Operand::Memory, Extend::Zero),
+        Opcode::PUSHF => copy(Operand::Memory, Operand::Flags, Extend::Zero),
+        Opcode::POPF => copy(Operand::Flags, Operand::Memory, Extend::Zero),
+        Opcode::LEAVE => copy(
+            Operand::Register(RegSpec::rbp()),
+            Operand::Memory,
+            Extend::Zero,
+        ),
+        // The return address pushed by a call is a constant
+        Opcode::CALL => copy(Operand::Memory, Operand::Immediate, Extend::Zero),
+        Opcode::LEA => Effect::Combine {
+            dsts: vec![first],
+            srcs: operands
+                .get(1)
+                .map(address_registers)
+                .unwrap_or_default()
+                .into_iter()
+                .map(Operand::Register)
+                .collect(),
+        },
+        Opcode::XCHG => Effect::Exchange(first, second),
+        Opcode::CMP | Opcode::TEST | Opcode::BT | Opcode::CMPS | Opcode::SCAS => {
+            combine(&[Operand::Flags], &ops)
+        }
+        Opcode::XOR | Opcode::SUB if clears => combine(&[first, Operand::Flags], &[]),
+        Opcode::PXOR | Opcode::VPXOR | Opcode::XORPS | Opcode::XORPD if clears => {
+            combine(&[first], &[])
+        }
+        Opcode::ADC | Opcode::SBB | Opcode::RCL | Opcode::RCR => combine(
+            &[first, Operand::Flags],
+            &[&ops[..], &[Operand::Flags]].concat(),
+        ),
+        Opcode::ADD
+        | Opcode::OR
+        | Opcode::AND
+        | Opcode::SUB
+        | Opcode::XOR
+        | Opcode::ROL
+        | Opcode::ROR
+        | Opcode::SHL
+        | Opcode::SHR
+        | Opcode::SAL
+        | Opcode::SAR
+        | Opcode::INC
+        | Opcode::DEC
+        | Opcode::NEG
+        | Opcode::BTC
+        | Opcode::BTR
+        | Opcode::BTS
+        | Opcode::BSF
+        | Opcode::BSR
+        | Opcode::TZCNT
+        | Opcode::SHRD => combine(&[first, Operand::Flags], &ops),
+        Opcode::IMUL if ops.len() > 1 => combine(&[first, Operand::Flags], &ops),
+        Opcode::MUL | Opcode::IMUL | Opcode::DIV | Opcode::IDIV => {
+            combine(&[rax, rdx, Operand::Flags], &[rax, rdx, first])
+        }
+        Opcode::CBW | Opcode::CWDE | Opcode::CDQE => combine(&[rax], &[rax]),
+        Opcode::CWD | Opcode::CDQ | Opcode::CQO => combine(&[rdx], &[rax]),
+        Opcode::CMPXCHG => combine(&[first, rax, Operand::Flags], &[first, second, rax]),
+        Opcode::XADD => combine(&[first, second, Operand::Flags], &ops),
+        opcode if opcode.is_cmovcc() => combine(&[first], &[first, second, Operand::Flags]),
+        opcode if opcode.is_setcc() => combine(&[first], &[Operand::Flags]),
+        opcode if opcode.is_jcc() => Effect::Nothing,
+        Opcode::JMP
+        | Opcode::RETURN
+        | Opcode::JRCXZ
+        | Opcode::LOOP
+        | Opcode::LOOPZ
+        | Opcode::LOOPNZ
+        | Opcode::NOP
+        | Opcode::ENDBR64
+        | Opcode::SYSCALL => Effect::Nothing,
+        _ => match first {
+            Operand::Register(_) | Operand::Memory => combine(&[first], &ops),
+            _ => Effect::Nothing,
+        },
+    };
+
+    let sink = match opcode {
+        opcode if opcode.is_jcc() => Some(branch(BranchKind::Conditional, vec![Operand::Flags])),
+        Opcode::JRCXZ | Opcode::LOOP => Some(branch(BranchKind::Conditional, vec![rcx])),
+        Opcode::LOOPZ | Opcode::LOOPNZ => {
+            Some(branch(BranchKind::Conditional, vec![rcx, Operand::Flags]))
+        }
+        Opcode::JMP | Opcode::CALL if first != Operand::Immediate => Some(Sink {
+            kind: BranchKind::Indirect,
+            operands: vec![first],
+            address: operands.first().map(address_registers).unwrap_or_default(),
+        }),
+        Opcode::RETURN => Some(branch(BranchKind::Return, vec![Operand::Memory])),
+        _ => None,
+    };
+
+    (effect, sink)
+}
+
+/// Decode an instruction. Instructions that cannot be decoded move no taint
+///
+/// # Arguments
+///
+/// * `pc` - The address of the instruction
+/// * `bytes` - The bytes of the instruction
+pub fn decode(pc: u64, bytes: &[u8]) -> Insn {
+    let decoder = InstDecoder::default();
+    let mut reader = U8Reader::new(bytes);
+
+    match decoder.decode(&mut reader) {
+        Ok(insn) => {
+            let (effect, sink) = effect(&insn);
+
+            Insn {
+                pc,
+                text: insn.to_string(),
+                effect,
+                sink,
+            }
+        }
+        Err(_) => Insn {
+            pc,
+            text: "(bad)".to_string(),
+            effect: Effect::Nothing,
+            sink: None,
+        },
+    }
+}
+
+/// The taint of each byte of an operand. The taint of memory operands is read from the loads
+/// the instruction made, in order
+///
+/// # Arguments
+///
+/// * `operand` - The operand
+/// * `registers` - The taint of the registers
+/// * `memory` - The taint of memory
+/// * `accesses` - The memory accesses the instruction made
+fn read(
+    operand: Operand,
+    registers: &Registers,
+    memory: &Memory,
+    accesses: &[Access],
+) -> Vec<Taint> {
+    match operand {
+        Operand::Register(reg) => registers.read(reg),
+        Operand::Memory => accesses
+            .iter()
+            .filter(|access| !access.is_store)
+            .flat_map(|access| memory.read(access.addr, access.size))
+            .collect(),
+        Operand::Immediate => Vec::new(),
+        Operand::Flags => vec![registers.flags.clone()],
+    }
+}
+
+/// Set the taint of an operand. The taint of memory operands is spread over the stores the
+/// instruction made, in order
+///
+/// # Arguments
+///
+/// * `operand` - The operand
+/// * `taints` - The taint of each byte of the operand, from least to most significant
+/// * `registers` - The taint of the registers
+/// * `memory` - The taint of memory
+/// * `accesses` - The memory accesses the instruction made
+fn write(
+    operand: Operand,
+    taints: &[Taint],
+    registers: &mut Registers,
+    memory: &mut Memory,
+    accesses: &[Access],
+) {
+    match operand {
+        Operand::Register(reg) => registers.write(reg, taints),
+        Operand::Memory => {
+            let mut offset = 0;
+
+            for access in accesses.iter().filter(|access| access.is_store) {
+                let bytes = (offset..offset + access.size)
+                    .map(|i| taints.get(i).cloned().unwrap_or_default())
+                    .collect::<Vec<_>>();
+                memory.write(access.addr, &bytes);
+                offset += access.size;
+            }
+        }
+        Operand::Immediate => {}
+        Operand::Flags => registers.flags = union(taints),
+    }
+}
+
+/// The size of an operand in bytes, if it has a fixed size. Memory operands are as large as
+/// the stores the instruction made
+///
+/// # Arguments
+///
+/// * `operand` - The operand
+/// * `registers` - The taint of the registers
+/// * `accesses` - The memory accesses the instruction made
+fn size(operand: Operand, registers: &Registers, accesses: &[Access]) -> usize {
+    match operand {
+        Operand::Register(reg) => registers.read(reg).len(),
+        Operand::Memory => accesses
+            .iter()
+            .filter(|access| access.is_store)
+            .map(|access| access.size)
+            .sum(),
+        Operand::Immediate => 0,
+        Operand::Flags => 1,
+    }
+}
+
+impl Insn {
+    /// The operands the instruction reads data from
+    pub fn sources(&self) -> Vec<Operand> {
+        match &self.effect {
+            Effect::Copy { src, .. } => vec![*src],
+            Effect::Combine { srcs, .. } => srcs.clone(),
+            Effect::Exchange(a, b) => vec![*a, *b],
+            Effect::Nothing => Vec::new(),
+        }
+    }
+
+    /// Whether the instruction computes the flags
+    pub fn writes_flags(&self) -> bool {
+        match &self.effect {
+            Effect::Copy { dst, .. } => *dst == Operand::Flags,
+            Effect::Combine { dsts, .. } => dsts.contains(&Operand::Flags),
+            _ => false,
+        }
+    }
+
+    /// Propagate taint through the instruction, once it executed
+    ///
+    /// # Arguments
+    ///
+    /// * `registers` - The taint of the registers of the VCPU it executed on
+    /// * `memory` - The taint of memory
+    /// * `accesses` - The memory accesses it made
+    pub fn apply(&self, registers: &mut Registers, memory: &mut Memory, accesses: &[Access]) {
+        match &self.effect {
+            Effect::Copy { dst, src, extend } => {
+                let mut taints = read(*src, registers, memory, accesses);
+                let len = size(*dst, registers, accesses);
+                let fill = match extend {
+                    Extend::Zero => Taint::default(),
+                    Extend::Sign => taints.last().cloned().unwrap_or_default(),
+                };
+                taints.resize(len.max(taints.len()), fill);
+                write(*dst, &taints, registers, memory, accesses);
+            }
+            Effect::Combine { dsts, srcs } => {
+                let taint = union(
+                    srcs.iter()
+                        .flat_map(|src| read(*src, registers, memory, accesses))
+                        .collect::<Vec<_>>()
+                        .iter(),
+                );
+
+                for dst in dsts {
+                    let len = size(*dst, registers, accesses);
+                    write(*dst, &vec![taint.clone(); len], registers, memory, accesses);
+                }
+            }
+            Effect::Exchange(a, b) => {
+                let a_taints = read(*a, registers, memory, accesses);
+                let b_taints = read(*b, registers, memory, accesses);
+                write(*a, &b_taints, registers, memory, accesses);
+                write(*b, &a_taints, registers, memory, accesses);
+            }
+            Effect::Nothing => {}
+        }
+    }
+
+    /// The taint of the operands of the branch the instruction is, if it is one that is
+    /// reported, including the registers the address of a memory operand is computed from
+    ///
+    /// # Arguments
+    ///
+    /// * `registers` - The taint of the registers of the VCPU it executes on
+    /// * `memory` - The taint of memory
+    /// * `accesses` - The memory accesses it made
+    pub fn sink_taint(&self, registers: &Registers, memory: &Memory, accesses: &[Access]) -> Taint {
+        let Some(sink) = &self.sink else {
+            return Taint::default();
+        };
+
+        let operands = sink
+            .operands
+            .iter()
+            .flat_map(|operand| read(*operand, registers, memory, accesses));
+        let address = sink.address.iter().flat_map(|reg| registers.read(*reg));
+
+        union(operands.chain(address).collect::<Vec<_>>().iter())
+    }
+}
@@ -0,0 +1,299 @@
+//! Shadow state
+//!
+//! Every byte of guest memory and of the general purpose and vector registers has a `Taint`:
+//! the set of input bytes its value was computed from, each identified by a `Label` naming
+//! the source it was read by and its offset in that source. Untainted bytes have the empty
+//! set, and untainted memory is not stored at all.
+//!
+//! Taint sets are shared between the bytes they were copied to, so moving tainted data around
+//! only clones a pointer. A new set is only allocated when two different sets are combined.
+
+use serde::{Deserialize, Serialize};
+use yaxpeax_x86::long_mode::{register_class, RegSpec};
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+};
+
+/// An input byte, as the source that read it and its offset in what the source read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Label {
+    pub source: u32,
+    pub offset: u32,
+}
+
+/// A contiguous run of input bytes of one source, from `start` up to but not including `end`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub source: u32,
+    pub start: u32,
+    pub end: u32,
+}
+
+/// The input bytes a byte of data was computed from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Taint(Option<Arc<BTreeSet<Label>>>);
+
+impl Taint {
+    /// The taint of a single input byte
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The source that read the byte
+    /// * `offset` - The offset of the byte in what the source read
+    pub fn label(source: u32, offset: u32) -> Self {
+        Self(Some(Arc::new(BTreeSet::from([Label { source, offset }]))))
+    }
+
+    /// Whether the data was not computed from any input
+    pub fn is_clean(&self) -> bool {
+        self.0.is_none()
+    }
+
+    /// The taint of data computed from both this data and `other`
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The other data
+    pub fn union(&self, other: &Taint) -> Taint {
+        match (&self.0, &other.0) {
+            (_, None) => self.clone(),
+            (None, _) => other.clone(),
+            (Some(a), Some(b)) if Arc::ptr_eq(a, b) || b.is_subset(a) => self.clone(),
+            (Some(a), Some(b)) if a.is_subset(b) => other.clone(),
+            (Some(a), Some(b)) => Taint(Some(Arc::new(a.union(b).copied().collect()))),
+        }
+    }
+
+    /// The input bytes, merged into runs of consecutive offsets
+    pub fn spans(&self) -> Vec<Span> {
+        let mut spans: Vec<Span> = Vec::new();
+
+        for label in self.0.iter().flat_map(|labels| labels.iter()) {
+            match spans.last_mut() {
+                Some(span) if span.source == label.source && span.end == label.offset => {
+                    span.end += 1;
+                }
+                _ => spans.push(Span {
+                    source: label.source,
+                    start: label.offset,
+                    end: label.offset + 1,
+                }),
+            }
+        }
+
+        spans
+    }
+}
+
+/// The taint of data computed from all of `taints`
+///
+/// # Arguments
+///
+/// * `taints` - The taint of each piece of data
+pub fn union<'a>(taints: impl IntoIterator<Item = &'a Taint>) -> Taint {
+    taints
+        .into_iter()
+        .fold(Taint::default(), |acc, taint| acc.union(taint))
+}
+
+/// The taint of guest memory, by byte address
+#[derive(Debug, Default)]
+pub struct Memory {
+    bytes: HashMap<u64, Taint>,
+}
+
+impl Memory {
+    /// The taint of each byte of a range of memory
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The first address of the range
+    /// * `len` - The number of bytes in the range
+    pub fn read(&self, addr: u64, len: usize) -> Vec<Taint> {
+        (0..len as u64)
+            .map(|i| {
+                self.bytes
+                    .get(&addr.wrapping_add(i))
+                    .cloned()
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// Set the taint of each byte of a range of memory
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The first address of the range
+    /// * `taints` - The taint of each byte, starting at `addr`
+    pub fn write(&mut self, addr: u64, taints: &[Taint]) {
+        for (i, taint) in taints.iter().enumerate() {
+            let addr = addr.wrapping_add(i as u64);
+
+            if taint.is_clean() {
+                self.bytes.remove(&addr);
+            } else {
+                self.bytes.insert(addr, taint.clone());
+            }
+        }
+    }
+
+    /// Untaint a range of memory
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The first address of the range
+    /// * `len` - The number of bytes in the range
+    pub fn clear(&mut self, addr: u64, len: u64) {
+        for i in 0..len {
+            self.bytes.remove(&addr.wrapping_add(i));
+        }
+    }
+}
+
+/// The general purpose registers, by number, as named by QEMU's gdbstub
+const GPRS: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+
+/// The number of vector registers, `zmm0` to `zmm31`
+const VECTORS: usize = 32;
+
+/// The size of the largest vector registers
+const VECTOR_SIZE: usize = 64;
+
+/// Where the bytes of a register are in the register file
+struct Location {
+    /// The index of the first byte of the full register holding it
+    base: usize,
+    /// The offset of the register in the full register, which is 1 for `ah` to `bh`
+    offset: usize,
+    /// The size of the register
+    size: usize,
+    /// The size of the full register
+    full: usize,
+    /// Whether writing the register zeroes the rest of the full register, like writing `eax`
+    /// zeroes the upper half of `rax`
+    zero_extends: bool,
+}
+
+/// Find a register in the register file. Returns `None` for registers that are not tracked,
+/// like segment, control and x87 registers
+///
+/// # Arguments
+///
+/// * `reg` - The register
+fn locate(reg: RegSpec) -> Option<Location> {
+    let gpr = |num: u8, offset: usize, size: usize, zero_extends: bool| Location {
+        base: num as usize * 8,
+        offset,
+        size,
+        full: 8,
+        zero_extends,
+    };
+    let vector = |num: u8, size: usize| Location {
+        base: GPRS.len() * 8 + num as usize * VECTOR_SIZE,
+        offset: 0,
+        size,
+        full: VECTOR_SIZE,
+        zero_extends: false,
+    };
+
+    match reg.class() {
+        register_class::Q => Some(gpr(reg.num(), 0, 8, false)),
+        register_class::D => Some(gpr(reg.num(), 0, 4, true)),
+        register_class::W => Some(gpr(reg.num(), 0, 2, false)),
+        register_class::RB => Some(gpr(reg.num(), 0, 1, false)),
+        // Without a REX prefix, byte registers 4 to 7 are `ah`, `ch`, `dh` and `bh`
+        register_class::B if reg.num() >= 4 => Some(gpr(reg.num() - 4, 1, 1, false)),
+        register_class::B => Some(gpr(reg.num(), 0, 1, false)),
+        register_class::X => Some(vector(reg.num(), 16)),
+        register_class::Y => Some(vector(reg.num(), 32)),
+        register_class::Z => Some(vector(reg.num(), 64)),
+        _ => None,
+    }
+}
+
+/// The name QEMU's gdbstub gives the full register holding a register, like `rax` for `ah`,
+/// to read its value. Returns `None` for registers that are not tracked
+///
+/// # Arguments
+///
+/// * `reg` - The register
+pub fn full_name(reg: RegSpec) -> Option<String> {
+    let location = locate(reg)?;
+
+    if location.base < GPRS.len() * 8 {
+        Some(GPRS[location.base / 8].to_string())
+    } else {
+        let num = (location.base - GPRS.len() * 8) / VECTOR_SIZE;
+        Some(match location.size {
+            16 => format!("xmm{}", num),
+            32 => format!("ymm{}", num),
+            _ => format!("zmm{}", num),
+        })
+    }
+}
+
+/// The taint of the registers and flags of a VCPU
+#[derive(Debug)]
+pub struct Registers {
+    bytes: Vec<Taint>,
+    /// The taint of the flags, which is the taint of whatever they were last computed from
+    pub flags: Taint,
+}
+
+impl Default for Registers {
+    fn default() -> Self {
+        Self {
+            bytes: vec![Taint::default(); GPRS.len() * 8 + VECTORS * VECTOR_SIZE],
+            flags: Taint::default(),
+        }
+    }
+}
+
+impl Registers {
+    /// The taint of each byte of a register, from least to most significant. Registers that are
+    /// not tracked have no bytes
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register
+    pub fn read(&self, reg: RegSpec) -> Vec<Taint> {
+        match locate(reg) {
+            Some(location) => {
+                let start = location.base + location.offset;
+                self.bytes[start..start + location.size].to_vec()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Set the taint of each byte of a register, from least to most significant. Bytes missing
+    /// from `taints` are untainted, and writes to registers that are not tracked are ignored
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register
+    /// * `taints` - The taint of each byte
+    pub fn write(&mut self, reg: RegSpec, taints: &[Taint]) {
+        let Some(location) = locate(reg) else {
+            return;
+        };
+
+        let start = location.base + location.offset;
+
+        for i in 0..location.size {
+            self.bytes[start + i] = taints.get(i).cloned().unwrap_or_default();
+        }
+
+        if location.zero_extends {
+            for i in location.size..location.full {
+                self.bytes[location.base + i] = Taint::default();
+            }
+        }
+    }
+}